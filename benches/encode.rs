@@ -0,0 +1,107 @@
+use chii::arena::Arena;
+use chii::comp::{
+  BooleanCompressor, Compressor, EnumCompressor, IdentityCompressor, Value,
+};
+use chii::schema::{CompositeType, List, Record, Schema, Type};
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+use std::collections::BTreeMap;
+
+const GRADES: [&str; 5] = ["A", "B", "C", "D", "F"];
+
+/// The type of a single student record.
+fn student() -> Record {
+  let mut student = BTreeMap::new();
+  student.insert("name".to_string(), Type::PassThrough);
+  student.insert("active".to_string(), Type::Name("bool".to_string()));
+  student.insert(
+    "grade".to_string(),
+    Type::Enum {
+      variants: GRADES.iter().map(|s| s.to_string()).collect(),
+      probabilities: None,
+    },
+  );
+  Record::new(student)
+}
+
+/// A list of 10,000 student records.
+fn students() -> (Schema, serde_json::Value) {
+  let schema = Schema::new(CompositeType::List(List(Box::new(Type::Nested(
+    CompositeType::Record(student()),
+  )))));
+
+  let students = (0..10_000)
+    .map(|i| {
+      let mut student = serde_json::Map::new();
+      student.insert("name".into(), format!("student {}", i).into());
+      student.insert("active".into(), (i % 3 != 0).into());
+      student.insert("grade".into(), GRADES[i % GRADES.len()].into());
+      serde_json::Value::Object(student)
+    })
+    .collect();
+  (schema, serde_json::Value::Array(students))
+}
+
+fn encoder(c: &mut Criterion) {
+  let (schema, value) = students();
+  c.bench_function("encode 10k records", |b| {
+    b.iter(|| chii::encode(&schema, black_box(&value)).unwrap())
+  });
+  c.bench_function("encode 10k records to bytes", |b| {
+    b.iter(|| chii::encode(&schema, black_box(&value)).unwrap().to_bytes())
+  });
+  c.bench_function("encode_to 10k records", |b| {
+    b.iter(|| {
+      let mut bytes = Vec::new();
+      chii::encode_to(&schema, black_box(&value), &mut bytes).unwrap();
+      bytes
+    })
+  });
+
+  let bytes = chii::encode(&schema, &value).unwrap().to_bytes();
+  c.bench_function("decode 10k records", |b| {
+    b.iter(|| chii::decode(&schema, black_box(&bytes)).unwrap())
+  });
+
+  // Each record encoded as an object of its own
+  let record = Schema::new(CompositeType::Record(student()));
+  let records = value.as_array().unwrap();
+  c.bench_function("encode 10k objects", |b| {
+    b.iter(|| {
+      records
+        .iter()
+        .map(|r| chii::encode(&record, black_box(r)).unwrap().to_bytes())
+        .collect::<Vec<_>>()
+    })
+  });
+  let mut arena = Arena::new();
+  c.bench_function("encode 10k objects into an arena", |b| {
+    b.iter(|| {
+      arena.clear();
+      for r in records {
+        arena.encode(&record, black_box(r)).unwrap();
+      }
+    })
+  });
+}
+
+fn compressors(c: &mut Criterion) {
+  c.bench_function("bool compressor", |b| {
+    b.iter(|| BooleanCompressor.compress(Value::Bool(black_box(true))))
+  });
+
+  let variants: Vec<_> = (0..64).map(|i| format!("variant {}", i)).collect();
+  let enumeration = EnumCompressor { variants };
+  c.bench_function("enum compressor", |b| {
+    b.iter(|| {
+      enumeration.compress(Value::Str(black_box("variant 42".to_string())))
+    })
+  });
+
+  let text = "the quick brown fox jumps over the lazy dog".repeat(4);
+  c.bench_function("identity compressor", |b| {
+    b.iter(|| IdentityCompressor.compress(Value::Str(black_box(text.clone()))))
+  });
+}
+
+criterion_group!(benches, encoder, compressors);
+criterion_main!(benches);
@@ -0,0 +1,26 @@
+use chii::vie::CodePoint;
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+fn encode(c: &mut Criterion) {
+  c.bench_function("code point from u64", |b| {
+    b.iter(|| CodePoint::from(black_box(0x0123_4567_89ab_cdefu64)))
+  });
+  c.bench_function("code point from u128", |b| {
+    b.iter(|| CodePoint::from(black_box(u128::MAX / 3)))
+  });
+}
+
+fn decode(c: &mut Criterion) {
+  let cp = CodePoint::from(0x0123_4567_89ab_cdefu64);
+  c.bench_function("code point decode u64", |b| {
+    b.iter(|| black_box(&cp).decode::<u64>())
+  });
+
+  let big = CodePoint::from_le_bytes(&[0xa5; 256]);
+  c.bench_function("code point to 256 bytes", |b| {
+    b.iter(|| black_box(&big).to_le_bytes())
+  });
+}
+
+criterion_group!(benches, encode, decode);
+criterion_main!(benches);
@@ -1,76 +1,47 @@
-// //! This example showcases encoding a simple record object into binary.
-//
-// use dcomp::core::math;
-// use dcomp::prelude::*;
-// use std::fmt::{Binary, Formatter, Result as FmtResult};
-//
-// /// Vector wrapper to allow us to print a vector of bytes as binary.
-// struct V<B: Binary>(Vec<B>);
-//
-// impl<B: Binary> Binary for V<B> {
-//   fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
-//     let vec = &self.0;
-//     for (i, x) in vec.iter().enumerate() {
-//       if i != 0 {
-//         write!(f, " ")?;
-//       }
-//       x.fmt(f)?;
-//     }
-//     Ok(())
-//   }
-// }
-//
-// fn main() {
-//   // Create a new compressed record object to add data to.
-//   let mut co = CompressedObject::new_record();
-//
-//   // Add some data to the record, here we are adding field #4 with 16 bits of
-//   // data. The field values 0 through 3 are reserved control markers so we
-//   // cannot use them as field numbers.
-//   co.push_data(Marker::Field(4), Glob::new(16, vec![0xff, 0xff]));
-//
-//   // Globs don't need to be whole bytes.
-//   co.push_data(Marker::Field(5), Glob::new(7, vec![0x71]));
-//
-//   // With this, we've constructed our record object with 2 fields now we need
-//   // to encode it.
-//
-//   // The width of encoded markers is variable and will usually be determined
-// by   // the schema used to comp the object. Here however, we simply set
-// it to   // be the required bit width for the largest field number which in
-// this case   // is #5. The marker width here is 3 bits.
-//   let marker_width = math::required_bit_width(5);
-//
-//   // The compressed object can be encoded using the `into_bytes` method.
-//   let bytes = co.into_bytes(marker_width);
-//
-//   println!("{:08b}", V(bytes));
-//   // 00000001 00100001 11111110 11111111 01111011 00010000 00000111
-//
-//   // Let's break apart this binary blob to see what it looks like. First off
-// it   // is important to note each byte is indexed from right to left and the
-// bytes   // themselves are indexed left to right; confusing yes I know. It
-// looks   // something like this:
-//   //
-//   //	 byte 0   byte 1   byte 2   byte 3   byte 4   byte 5   byte 6
-//   // 	00000001 00100001 11111110 11111111 01111011 00010000 00000111
-//   //         ^       ^
-//   //     bit 0   bit 1 and so on...
-//   //
-//   // The individual components ordered as they appear in the binary is like
-// so:   //
-//   // 	001			 - record marker
-//   //	000	 		 - null marker
-//   // 	100 		 - marker field (#4)
-//   //	00010000 - vie length (16)
-//   // 	11111111 - glob byte 0
-//   // 	11111111 - glob byte 1
-//   //  101 		 - marker field (#5)
-//   // 	00000111 - vie length (7)
-//   //  1110001  - glob byte 0
-//   // 	00000 	 - padding
-//   //
-//   // All these components are packed together with no spacing in between.
-// }
+//! This example showcases encoding a simple record object into binary and
+//! how its bits are laid out.
 
-fn main() {}
+use chii::schema::{CompositeType, Record, Schema, Type};
+use serde_json::json;
+use std::collections::BTreeMap;
+
+fn main() {
+  // A record with two fields. Fields are identified by their position in the
+  // sorted list of names, so `active` is field 0 and `name` is field 1.
+  let mut fields = BTreeMap::new();
+  fields.insert("active".to_string(), Type::Name("bool".to_string()));
+  fields.insert("name".to_string(), Type::PassThrough);
+  let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+
+  let value = json!({ "active": true, "name": "Hi" });
+  let co = chii::encode(&schema, &value).unwrap();
+  for block in &co.blocks {
+    println!("{}", block);
+  }
+
+  let bytes = co.to_bytes();
+  let binary: Vec<String> =
+    bytes.iter().map(|b| format!("{:08b}", b)).collect();
+  println!("{}", binary.join(" "));
+  // 10101000 10000010 01000011 01001000
+
+  // Bits are packed most significant bit first: bit 0 of the object is the
+  // highest bit of byte 0, and bytes follow each other left to right, so the
+  // binary above can be read straight through:
+  //
+  //   10       - marker of field 0 (active)
+  //   1        - true
+  //   01       - marker of field 1 (name)
+  //   00010000 - vie length (16 bits)
+  //   01001000 - 'H'
+  //   01101001 - 'i'
+  //   000      - padding
+  //
+  // Field markers hold the field's identifier plus one, which is 1 for
+  // `active` and 2 for `name` here. Unlike the rest of the object they are
+  // written least significant bit first, so 2 is written as 01. The marker
+  // width is the number of bits needed for the largest marker, which is 2
+  // bits for a record with 2 fields.
+  //
+  // The root record doesn't need a terminator as it ends with the object.
+}
@@ -0,0 +1,78 @@
+#ifndef CHII_H
+#define CHII_H
+
+/* Generated with cbindgen from src/ffi.rs, do not edit by hand. */
+
+#include <stddef.h>
+#include <stdint.h>
+
+// A parsed schema, opaque to C.
+typedef struct ChiiSchema ChiiSchema;
+
+// A buffer of bytes owned by the caller.
+//
+// Release it with [`chii_buffer_free`].
+typedef struct ChiiBuffer {
+  uint8_t *data;
+  size_t len;
+} ChiiBuffer;
+
+// Parses a YAML or JSON schema definition of `len` bytes.
+//
+// Returns null on failure.
+//
+// # Safety
+//
+// `text` must point to `len` readable bytes.
+ChiiSchema *chii_schema_parse(const char *text, size_t len);
+
+// Releases a schema returned by [`chii_schema_parse`]. Does nothing if
+// `schema` is null.
+//
+// # Safety
+//
+// `schema` must be null or a pointer returned by [`chii_schema_parse`] which
+// has not already been freed.
+void chii_schema_free(ChiiSchema *schema);
+
+// Compresses `len` bytes of JSON text at `json`, storing the compressed
+// bytes in `out`.
+//
+// # Safety
+//
+// `schema` must be a live schema, `json` must point to `len` readable bytes
+// and `out` must be valid for writes.
+int chii_compress(const ChiiSchema *schema,
+                  const uint8_t *json,
+                  size_t len,
+                  ChiiBuffer *out);
+
+// Decompresses `len` bytes at `data`, storing the decoded JSON text, which is
+// not nul terminated, in `out`.
+//
+// # Safety
+//
+// `schema` must be a live schema, `data` must point to `len` readable bytes
+// and `out` must be valid for writes.
+int chii_decompress(const ChiiSchema *schema,
+                    const uint8_t *data,
+                    size_t len,
+                    ChiiBuffer *out);
+
+// Releases a buffer filled in by the library. Does nothing if its data is
+// null.
+//
+// # Safety
+//
+// `buffer` must have been filled in by the library and not already been
+// freed.
+void chii_buffer_free(ChiiBuffer buffer);
+
+// The message of the last error raised on this thread, or null if the last
+// call succeeded.
+//
+// The string is owned by the library and stays valid until the next call
+// into the library on the same thread.
+const char *chii_last_error(void);
+
+#endif /* CHII_H */
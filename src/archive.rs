@@ -0,0 +1,597 @@
+//! The `archive` module implements a simple container format which packs many
+//! encoded [compressed objects] into a single file.
+//!
+//! An archive is laid out as follows:
+//!
+//! ```text
+//! MAGIC | entry 0 | entry 1 | ... | entry n | TOC | TOC offset | MAGIC
+//! ```
+//!
+//! Each entry is the byte representation of a single compressed object. The
+//! table of contents (TOC) is a VIE encoded entry count followed by the VIE
+//! encoded byte length of each entry. The TOC offset is stored as an 8 byte
+//! little endian integer so that it can be found by seeking backwards from the
+//! end of the file.
+//!
+//! Because the table of contents lives at the end of the archive, appending a
+//! new entry only requires overwriting the old table of contents. Existing
+//! entries are never rewritten.
+//!
+//! With the `fec` feature, archives for unreliable storage can be created
+//! with Reed-Solomon parity. They start and end with [`FEC_MAGIC`] instead,
+//! and the first magic bytes are followed by a single byte holding the number
+//! of parity bytes in each codeword:
+//!
+//! ```text
+//! FEC_MAGIC | parity | entry 0 | ... | entry n | TOC | TOC offset | FEC_MAGIC
+//! ```
+//!
+//! Each entry and the table of contents are stored as interleaved codewords
+//! of at most 255 bytes, and the lengths in the table of contents include
+//! their parity. Damaged entries are repaired as they are read, as long as
+//! no codeword has more than half as many damaged bytes as parity bytes. The
+//! magic bytes, the parity byte and the TOC offset are not protected.
+//!
+//! [compressed objects]: crate::data::CompressedObject
+
+use crate::schema::Schema;
+use crate::vie::CodePoint;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+use std::borrow::Cow;
+use std::io::{Read, Seek, SeekFrom, Write};
+
+/// Magic bytes found at the start and end of every archive.
+pub const MAGIC: &[u8; 4] = b"CHIA";
+
+/// Magic bytes found at the start and end of archives with Reed-Solomon
+/// parity.
+pub const FEC_MAGIC: &[u8; 4] = b"CHIR";
+
+/// Size of the trailer (TOC offset + magic) in bytes.
+const TRAILER_LEN: u64 = 8 + MAGIC.len() as u64;
+
+/// Size of the header (magic + parity) of an archive with parity in bytes.
+const HEADER_LEN: usize = MAGIC.len() + 1;
+
+/// The location of a single entry within an archive.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Entry {
+  /// Byte offset of the entry from the start of the archive.
+  pub offset: u64,
+  /// Length of the entry in bytes, including any parity.
+  pub len: u64,
+}
+
+/// An archive of compressed objects backed by some seekable stream, usually a
+/// file.
+#[derive(Debug)]
+pub struct Archive<F> {
+  inner: F,
+  entries: Vec<Entry>,
+  /// The number of parity bytes in each codeword, or zero if the archive
+  /// has no parity.
+  parity: usize,
+}
+
+impl<F> Archive<F>
+where
+  F: Read + Write + Seek,
+{
+  /// Initializes an empty archive in `inner` overwriting anything that was
+  /// there before.
+  pub fn create(mut inner: F) -> Result<Self> {
+    inner.seek(SeekFrom::Start(0))?;
+    inner.write_all(MAGIC)?;
+    let mut archive = Archive {
+      inner,
+      entries: Vec::new(),
+      parity: 0,
+    };
+    archive.write_toc()?;
+    Ok(archive)
+  }
+
+  /// Initializes an empty archive in `inner` whose entries are stored with
+  /// `percent` percent more bytes of Reed-Solomon parity than data,
+  /// overwriting anything that was there before.
+  ///
+  /// The parity must be between 1 and 100 percent.
+  #[cfg(feature = "fec")]
+  pub fn create_with_parity(mut inner: F, percent: u32) -> Result<Self> {
+    let parity = crate::fec::parity_bytes(percent)?;
+    inner.seek(SeekFrom::Start(0))?;
+    inner.write_all(FEC_MAGIC)?;
+    inner.write_all(&[parity as u8])?;
+    let mut archive = Archive {
+      inner,
+      entries: Vec::new(),
+      parity,
+    };
+    archive.write_toc()?;
+    Ok(archive)
+  }
+
+  /// Appends a new entry to the end of the archive returning its location.
+  ///
+  /// The table of contents is rewritten to include the new entry, but the
+  /// bytes of existing entries are left untouched.
+  pub fn append(&mut self, bytes: &[u8]) -> Result<Entry> {
+    let bytes = protect(bytes, self.parity);
+    let entry = Entry {
+      offset: self.toc_offset(),
+      len: bytes.len() as u64,
+    };
+
+    self.inner.seek(SeekFrom::Start(entry.offset))?;
+    self.inner.write_all(&bytes)?;
+    self.entries.push(entry);
+    self.write_toc()?;
+    Ok(entry)
+  }
+
+  /// Writes the table of contents and trailer after the last entry.
+  fn write_toc(&mut self) -> Result<()> {
+    let toc_offset = self.toc_offset();
+    let mut toc = CodePoint::from(self.entries.len() as u64).bytes().to_vec();
+    for entry in &self.entries {
+      toc.extend_from_slice(CodePoint::from(entry.len).bytes());
+    }
+    let mut toc = protect(&toc, self.parity).into_owned();
+    toc.extend_from_slice(&toc_offset.to_le_bytes());
+    toc.extend_from_slice(magic(self.parity));
+
+    self.inner.seek(SeekFrom::Start(toc_offset))?;
+    self.inner.write_all(&toc)?;
+    self.inner.flush()?;
+    Ok(())
+  }
+}
+
+impl<F> Archive<F>
+where
+  F: Read + Seek,
+{
+  /// Opens an existing archive by reading its table of contents.
+  pub fn open(mut inner: F) -> Result<Self> {
+    let mut header = [0u8; HEADER_LEN];
+    inner.seek(SeekFrom::Start(0))?;
+    let read = inner.read(&mut header)?;
+    let parity = parse_header(&header[..read])?;
+
+    let end = inner.seek(SeekFrom::End(0))?;
+    if end < start(parity) + TRAILER_LEN {
+      bail!("archive is truncated");
+    }
+
+    let mut trailer = [0u8; TRAILER_LEN as usize];
+    inner.seek(SeekFrom::Start(end - TRAILER_LEN))?;
+    inner.read_exact(&mut trailer)?;
+    if &trailer[8..] != magic(parity) {
+      bail!("archive is truncated: missing trailer");
+    }
+
+    let toc_offset = parse_trailer(&trailer, end, parity)?;
+
+    let mut toc = vec![0u8; (end - TRAILER_LEN - toc_offset) as usize];
+    inner.seek(SeekFrom::Start(toc_offset))?;
+    inner.read_exact(&mut toc)?;
+    let toc = repair(&toc, parity)
+      .context("when reading the archive table of contents")?;
+    let entries = parse_toc(&toc, toc_offset, parity)?;
+
+    Ok(Archive {
+      inner,
+      entries,
+      parity,
+    })
+  }
+
+  /// Reads the bytes of the `index`th entry in the archive, repairing them
+  /// if the archive has parity.
+  pub fn read(&mut self, index: usize) -> Result<Vec<u8>> {
+    let entry = *self
+      .entries
+      .get(index)
+      .ok_or_else(|| anyhow!("archive has no entry {}", index))?;
+    let mut bytes = vec![0u8; entry.len as usize];
+    self.inner.seek(SeekFrom::Start(entry.offset))?;
+    self.inner.read_exact(&mut bytes)?;
+    if self.parity > 0 {
+      bytes = repair(&bytes, self.parity)
+        .with_context(|| format!("when reading archive entry {}", index))?
+        .into_owned();
+    }
+    Ok(bytes)
+  }
+}
+
+impl<F> Archive<F> {
+  /// The locations of all entries in this archive.
+  #[inline]
+  pub fn entries(&self) -> &[Entry] {
+    &self.entries
+  }
+
+  /// The number of entries in this archive.
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Returns `true` if this archive has no entries.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// The number of parity bytes in each codeword of this archive's entries,
+  /// or zero if it has no parity.
+  #[inline]
+  pub fn parity(&self) -> usize {
+    self.parity
+  }
+
+  /// Consumes this archive returning the underlying stream.
+  pub fn into_inner(self) -> F {
+    self.inner
+  }
+
+  /// The offset of the table of contents, which is also where the next entry
+  /// will be written.
+  fn toc_offset(&self) -> u64 {
+    self
+      .entries
+      .last()
+      .map_or(start(self.parity), |e| e.offset + e.len)
+  }
+}
+
+/// A read-only archive over bytes which are already in memory, such as a
+/// memory mapped file.
+///
+/// Unlike [`Archive::read`], entries are borrowed from the underlying bytes
+/// instead of being copied out.
+#[derive(Clone, Debug)]
+pub struct ArchiveView<'a> {
+  bytes: &'a [u8],
+  entries: Vec<Entry>,
+  parity: usize,
+}
+
+impl<'a> ArchiveView<'a> {
+  /// Opens the archive held in `bytes` by reading its table of contents.
+  pub fn new(bytes: &'a [u8]) -> Result<Self> {
+    let parity = parse_header(&bytes[..bytes.len().min(HEADER_LEN)])?;
+
+    let end = bytes.len() as u64;
+    if end < start(parity) + TRAILER_LEN {
+      bail!("archive is truncated");
+    }
+    let trailer = &bytes[(end - TRAILER_LEN) as usize..];
+    if &trailer[8..] != magic(parity) {
+      bail!("archive is truncated: missing trailer");
+    }
+
+    let toc_offset = parse_trailer(trailer, end, parity)?;
+    let toc = &bytes[toc_offset as usize..(end - TRAILER_LEN) as usize];
+    let toc = repair(toc, parity)
+      .context("when reading the archive table of contents")?;
+    let entries = parse_toc(&toc, toc_offset, parity)?;
+    Ok(ArchiveView {
+      bytes,
+      entries,
+      parity,
+    })
+  }
+
+  /// Reads the bytes of the `index`th entry in the archive.
+  ///
+  /// The bytes are borrowed unless the archive has parity, in which case
+  /// they are repaired into a copy.
+  pub fn read(&self, index: usize) -> Result<Cow<'a, [u8]>> {
+    let entry = self
+      .entries
+      .get(index)
+      .ok_or_else(|| anyhow!("archive has no entry {}", index))?;
+    let start = entry.offset as usize;
+    let bytes = &self.bytes[start..start + entry.len as usize];
+    repair(bytes, self.parity)
+      .with_context(|| format!("when reading archive entry {}", index))
+  }
+
+  /// Decodes every entry in the archive using a given `schema`.
+  ///
+  /// Entries don't depend on each other, so with the `parallel` feature they
+  /// are decoded on the rayon thread pool.
+  pub fn decode_all(&self, schema: &Schema) -> Result<Vec<Value>> {
+    let decode = |i: usize| {
+      self
+        .read(i)
+        .and_then(|bytes| crate::decode(schema, &bytes))
+        .with_context(|| format!("when decoding archive entry {}", i))
+    };
+
+    #[cfg(feature = "parallel")]
+    {
+      use rayon::prelude::*;
+      (0..self.len()).into_par_iter().map(decode).collect()
+    }
+    #[cfg(not(feature = "parallel"))]
+    (0..self.len()).map(decode).collect()
+  }
+
+  /// The locations of all entries in this archive.
+  #[inline]
+  pub fn entries(&self) -> &[Entry] {
+    &self.entries
+  }
+
+  /// The number of entries in this archive.
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.entries.len()
+  }
+
+  /// Returns `true` if this archive has no entries.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.entries.is_empty()
+  }
+
+  /// The number of parity bytes in each codeword of this archive's entries,
+  /// or zero if it has no parity.
+  #[inline]
+  pub fn parity(&self) -> usize {
+    self.parity
+  }
+}
+
+/// The magic bytes of an archive with `parity` parity bytes per codeword.
+fn magic(parity: usize) -> &'static [u8; 4] {
+  match parity {
+    0 => MAGIC,
+    _ => FEC_MAGIC,
+  }
+}
+
+/// The offset of the first entry of an archive with `parity` parity bytes
+/// per codeword.
+fn start(parity: usize) -> u64 {
+  match parity {
+    0 => MAGIC.len() as u64,
+    _ => HEADER_LEN as u64,
+  }
+}
+
+/// Reads the number of parity bytes per codeword from the start of an
+/// archive, which is zero if it has no parity.
+fn parse_header(header: &[u8]) -> Result<usize> {
+  if header.starts_with(MAGIC) {
+    return Ok(0);
+  }
+  if !header.starts_with(FEC_MAGIC) {
+    bail!("not an archive: bad magic bytes");
+  }
+  if !cfg!(feature = "fec") {
+    bail!("archive has parity, which needs the `fec` feature to be read");
+  }
+  let parity = *header
+    .get(MAGIC.len())
+    .ok_or_else(|| anyhow!("archive is truncated"))? as usize;
+  #[cfg(feature = "fec")]
+  crate::fec::check_parity(parity)?;
+  Ok(parity)
+}
+
+/// Adds `parity` parity bytes to each codeword of the bytes of an entry or
+/// table of contents.
+fn protect(bytes: &[u8], parity: usize) -> Cow<'_, [u8]> {
+  match parity {
+    0 => Cow::Borrowed(bytes),
+    #[cfg(feature = "fec")]
+    parity => Cow::Owned(crate::fec::protect(bytes, parity)),
+    #[cfg(not(feature = "fec"))]
+    _ => unreachable!("archives only have parity with the `fec` feature"),
+  }
+}
+
+/// Repairs the bytes of an entry or table of contents which were stored with
+/// `parity` parity bytes per codeword, removing the parity.
+fn repair(bytes: &[u8], parity: usize) -> Result<Cow<'_, [u8]>> {
+  match parity {
+    0 => Ok(Cow::Borrowed(bytes)),
+    #[cfg(feature = "fec")]
+    parity => crate::fec::repair(bytes, parity).map(|(data, _)| data.into()),
+    #[cfg(not(feature = "fec"))]
+    _ => unreachable!("archives only have parity with the `fec` feature"),
+  }
+}
+
+/// Reads the offset of the table of contents from the `trailer` of an archive
+/// which is `end` bytes long and has `parity` parity bytes per codeword.
+fn parse_trailer(trailer: &[u8], end: u64, parity: usize) -> Result<u64> {
+  let mut offset_bytes = [0u8; 8];
+  offset_bytes.copy_from_slice(&trailer[..8]);
+  let toc_offset = u64::from_le_bytes(offset_bytes);
+  if toc_offset < start(parity) || toc_offset > end - TRAILER_LEN {
+    bail!("archive table of contents offset is out of bounds");
+  }
+  Ok(toc_offset)
+}
+
+/// Parses the entries listed in a table of contents found at `toc_offset` in
+/// an archive with `parity` parity bytes per codeword.
+fn parse_toc(toc: &[u8], toc_offset: u64, parity: usize) -> Result<Vec<Entry>> {
+  let mut rest = toc;
+  let mut next = || -> Result<u64> {
+    let cp = CodePoint::parse(rest)
+      .ok_or_else(|| anyhow!("archive table of contents is truncated"))?;
+    rest = &rest[cp.count()..];
+    cp.decode::<u64>()
+      .ok_or_else(|| anyhow!("archive table of contents value overflow"))
+  };
+
+  let count = next()?;
+  let mut entries = Vec::new();
+  let mut offset = start(parity);
+  for _ in 0..count {
+    let len = next()?;
+    entries.push(Entry { offset, len });
+    offset = offset
+      .checked_add(len)
+      .filter(|end| *end <= toc_offset)
+      .ok_or_else(|| anyhow!("archive entry extends past its data"))?;
+  }
+
+  if offset != toc_offset {
+    bail!("archive table of contents does not match entry data");
+  }
+  Ok(entries)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use std::io::Cursor;
+
+  #[test]
+  fn create_empty_archive() {
+    let archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    let archive = Archive::open(archive.into_inner()).unwrap();
+    assert!(archive.is_empty());
+  }
+
+  #[test]
+  fn append_and_read_entries() {
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    archive.append(&[1, 2, 3]).unwrap();
+    archive.append(&[4; 200]).unwrap();
+
+    let mut archive = Archive::open(archive.into_inner()).unwrap();
+    assert_eq!(2, archive.len());
+    assert_eq!(vec![1, 2, 3], archive.read(0).unwrap());
+    assert_eq!(vec![4; 200], archive.read(1).unwrap());
+  }
+
+  #[test]
+  fn append_to_reopened_archive_keeps_existing_entries() {
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    archive.append(&[1, 2, 3]).unwrap();
+    let before = archive.into_inner().into_inner();
+
+    let mut archive = Archive::open(Cursor::new(before.clone())).unwrap();
+    archive.append(&[5, 6]).unwrap();
+    let after = archive.into_inner().into_inner();
+
+    // Everything up to the old table of contents is left untouched.
+    assert_eq!(&before[..7], &after[..7]);
+
+    let mut archive = Archive::open(Cursor::new(after)).unwrap();
+    assert_eq!(vec![5, 6], archive.read(1).unwrap());
+  }
+
+  #[test]
+  fn open_rejects_bad_magic() {
+    let bytes = b"NOPE and some more bytes".to_vec();
+    assert!(Archive::open(Cursor::new(bytes)).is_err());
+  }
+
+  #[test]
+  fn open_rejects_overflowing_entry_lengths() {
+    let archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    let mut bytes = archive.into_inner().into_inner();
+    // Replace the empty table of contents with one whose entry lengths add
+    // up to more than a u64 can hold
+    let toc_offset = bytes.len() - TRAILER_LEN as usize - 1;
+    bytes.truncate(toc_offset);
+    for n in &[2, u64::MAX, 2] {
+      bytes.extend_from_slice(CodePoint::from(*n).bytes());
+    }
+    bytes.extend_from_slice(&(toc_offset as u64).to_le_bytes());
+    bytes.extend_from_slice(MAGIC);
+
+    let e = Archive::open(Cursor::new(bytes.clone())).unwrap_err();
+    assert_eq!("archive entry extends past its data", e.to_string());
+    assert!(ArchiveView::new(&bytes).is_err());
+  }
+
+  #[test]
+  fn view_borrows_entries() {
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    archive.append(&[1, 2, 3]).unwrap();
+    archive.append(&[4; 200]).unwrap();
+    let bytes = archive.into_inner().into_inner();
+
+    let view = ArchiveView::new(&bytes).unwrap();
+    assert_eq!(2, view.len());
+    assert_eq!(Cow::Borrowed(&[1, 2, 3][..]), view.read(0).unwrap());
+    assert!(
+      matches!(view.read(1).unwrap(), Cow::Borrowed(b) if b == &[4; 200][..])
+    );
+  }
+
+  #[test]
+  fn view_decodes_all_entries() {
+    use crate::schema::{CompositeType, List, Type};
+
+    let schema = Schema::new(CompositeType::List(List(Box::new(Type::Name(
+      "bool".to_string(),
+    )))));
+    let values = vec![json!([true]), json!([]), json!([false, true])];
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    for value in &values {
+      let bits: crate::bit::BitVec =
+        crate::encode(&schema, value).unwrap().into();
+      archive.append(&bits.to_bytes()).unwrap();
+    }
+    let bytes = archive.into_inner().into_inner();
+
+    let view = ArchiveView::new(&bytes).unwrap();
+    assert_eq!(values, view.decode_all(&schema).unwrap());
+  }
+
+  #[cfg(feature = "fec")]
+  #[test]
+  fn repair_damaged_entries() {
+    let entries = [vec![1, 2, 3], (0..=255).cycle().take(600).collect()];
+    let mut archive =
+      Archive::create_with_parity(Cursor::new(Vec::new()), 10).unwrap();
+    for entry in &entries {
+      archive.append(entry).unwrap();
+    }
+    let mut bytes = archive.into_inner().into_inner();
+    assert_eq!(FEC_MAGIC, &bytes[..4]);
+
+    // Damage a burst within the second entry and a byte of the TOC
+    let entry = ArchiveView::new(&bytes).unwrap().entries()[1];
+    for b in &mut bytes[entry.offset as usize + 10..][..30] {
+      *b ^= 0xff;
+    }
+    let toc = entry.offset + entry.len;
+    bytes[toc as usize] ^= 0xff;
+
+    let view = ArchiveView::new(&bytes).unwrap();
+    assert_eq!(24, view.parity());
+    assert_eq!(&entries[0][..], &*view.read(0).unwrap());
+    assert_eq!(&entries[1][..], &*view.read(1).unwrap());
+    let mut archive = Archive::open(Cursor::new(bytes.clone())).unwrap();
+    assert_eq!(entries[1], archive.read(1).unwrap());
+
+    // Too much damage to repair
+    for b in &mut bytes[entry.offset as usize..][..200] {
+      *b ^= 0xff;
+    }
+    let view = ArchiveView::new(&bytes).unwrap();
+    assert!(view.read(1).is_err());
+  }
+
+  #[test]
+  fn view_rejects_truncated_archive() {
+    let mut archive = Archive::create(Cursor::new(Vec::new())).unwrap();
+    archive.append(&[1, 2, 3]).unwrap();
+    let bytes = archive.into_inner().into_inner();
+    assert!(ArchiveView::new(&bytes[..bytes.len() - 1]).is_err());
+  }
+}
@@ -0,0 +1,190 @@
+//! The `arena` module implements a buffer which many compressed objects are
+//! encoded into back to back.
+//!
+//! Encoding with [`encode`](crate::encode) builds a [`CompressedObject`] with
+//! a separately allocated block for every field, which is then packed into
+//! yet another buffer. When encoding millions of small objects this puts a lot
+//! of pressure on the allocator. An [`Arena`] instead packs blocks into a
+//! reusable scratch buffer as they are produced and appends the bytes of each
+//! object to a single shared buffer, so once the arena has grown large enough
+//! encoding an object only allocates for the data of its individual values.
+//!
+//! [`CompressedObject`]: crate::data::CompressedObject
+
+use crate::bit::BitBuf;
+use crate::encode::{encode_to_bytes, EncodeOptions};
+use crate::prelude::*;
+use crate::schema::Schema;
+use anyhow::Result;
+use serde_json::Value;
+
+/// A single buffer holding the bytes of many compressed objects.
+#[derive(Clone, Debug, Default)]
+pub struct Arena {
+  bytes: Vec<u8>,
+  /// The offset one past the last byte of each object.
+  ends: Vec<usize>,
+  scratch: BitBuf,
+}
+
+impl Arena {
+  /// Constructs an empty arena.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Constructs an empty arena with space for at least `bytes` bytes of
+  /// objects.
+  pub fn with_capacity(bytes: usize) -> Self {
+    Arena {
+      bytes: Vec::with_capacity(bytes),
+      ..Self::default()
+    }
+  }
+
+  /// Encodes a JSON `value` using a given `schema`, returning the index of
+  /// the new object.
+  pub fn encode(&mut self, schema: &Schema, value: &Value) -> Result<usize> {
+    self.encode_with(schema, value, &EncodeOptions::default())
+  }
+
+  /// Encodes a JSON `value` using a given `schema` and `options`, returning
+  /// the index of the new object.
+  ///
+  /// The arena is left unchanged if encoding fails.
+  pub fn encode_with(
+    &mut self,
+    schema: &Schema,
+    value: &Value,
+    options: &EncodeOptions,
+  ) -> Result<usize> {
+    encode_to_bytes(
+      schema,
+      value,
+      options,
+      &mut self.scratch,
+      &mut self.bytes,
+    )?;
+    self.ends.push(self.bytes.len());
+    Ok(self.ends.len() - 1)
+  }
+
+  /// The bytes of the `index`th object, or `None` if there is no such object.
+  pub fn get(&self, index: usize) -> Option<&[u8]> {
+    let end = *self.ends.get(index)?;
+    let start = match index {
+      0 => 0,
+      i => self.ends[i - 1],
+    };
+    Some(&self.bytes[start..end])
+  }
+
+  /// Iterates over the bytes of each object in the order they were encoded.
+  pub fn iter(&self) -> impl Iterator<Item = &[u8]> + '_ {
+    (0..self.len()).map(move |i| self.get(i).unwrap())
+  }
+
+  /// The bytes of all objects concatenated together.
+  #[inline]
+  pub fn as_bytes(&self) -> &[u8] {
+    &self.bytes
+  }
+
+  /// The number of objects in this arena.
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.ends.len()
+  }
+
+  /// Returns `true` if this arena holds no objects.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.ends.is_empty()
+  }
+
+  /// Removes all objects, keeping the allocated space for reuse.
+  pub fn clear(&mut self) {
+    self.bytes.clear();
+    self.ends.clear();
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::encode::UnknownFieldPolicy;
+  use crate::schema::{CompositeType, Record, Type};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    Schema::new(CompositeType::Record(Record::new(fields)))
+  }
+
+  #[test]
+  fn objects_match_encode() {
+    let schema = schema();
+    let values = [
+      json!({ "name": "Alice", "active": true }),
+      json!({}),
+      json!({ "name": "Bob" }),
+    ];
+
+    let mut arena = Arena::new();
+    for (i, value) in values.iter().enumerate() {
+      assert_eq!(i, arena.encode(&schema, value).unwrap());
+    }
+
+    assert_eq!(values.len(), arena.len());
+    for (value, bytes) in values.iter().zip(arena.iter()) {
+      assert_eq!(crate::encode(&schema, value).unwrap().to_bytes(), bytes);
+      assert_eq!(*value, crate::decode(&schema, bytes).unwrap());
+    }
+    assert_eq!(None, arena.get(values.len()));
+  }
+
+  #[test]
+  fn failed_encode_leaves_arena_unchanged() {
+    let schema = schema();
+    let mut arena = Arena::new();
+    arena.encode(&schema, &json!({ "name": "Alice" })).unwrap();
+    let before = arena.as_bytes().to_vec();
+
+    assert!(arena.encode(&schema, &json!({ "active": "yes" })).is_err());
+    assert_eq!(1, arena.len());
+    assert_eq!(before, arena.as_bytes());
+  }
+
+  #[test]
+  fn clear_keeps_no_objects() {
+    let schema = schema();
+    let mut arena = Arena::with_capacity(64);
+    arena.encode(&schema, &json!({ "name": "Alice" })).unwrap();
+    arena.clear();
+    assert!(arena.is_empty());
+    assert!(arena.as_bytes().is_empty());
+
+    let index = arena.encode(&schema, &json!({ "active": false })).unwrap();
+    assert_eq!(0, index);
+  }
+
+  #[test]
+  fn unknown_fields_are_preserved() {
+    let schema = schema();
+    let value = json!({ "name": "Alice", "extra": [1, 2] });
+    let options = EncodeOptions {
+      unknown_fields: UnknownFieldPolicy::Preserve,
+      ..EncodeOptions::default()
+    };
+
+    let mut arena = Arena::new();
+    arena.encode_with(&schema, &value, &options).unwrap();
+
+    let mut expected = Vec::new();
+    crate::encode_to_with(&schema, &value, &mut expected, &options).unwrap();
+    assert_eq!(expected, arena.get(0).unwrap());
+  }
+}
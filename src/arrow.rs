@@ -0,0 +1,227 @@
+//! The `arrow` module converts between decoded lists of records and Apache
+//! Arrow [`RecordBatch`]es, so compressed data can be handed to analytics
+//! engines without writing it out as JSON text first.
+//!
+//! Each field of the record becomes a column. Types map onto Arrow as:
+//!
+//! | chii type              | Arrow type             |
+//! |------------------------|------------------------|
+//! | `bool`                 | `Boolean`              |
+//! | `bwt`                  | `Utf8`                 |
+//! | `color`                | `Utf8`                 |
+//! | `f16`                  | `Float32`              |
+//! | `float(precision=N)`   | `Float64`              |
+//! | `geo(precision=N)`     | `List` of `Float64`    |
+//! | `geo(..., form=record)`| `Struct` of `Float64`s |
+//! | `lzw`                  | `Utf8`                 |
+//! | `smaz`                 | `Utf8`                 |
+//! | `timestamp`            | `Int64`                |
+//! | pass-through           | `Utf8`                 |
+//! | enum                   | `Utf8`                 |
+//! | integer enum           | `Int64`                |
+//! | huffman                | `Utf8`                 |
+//! | dictionary             | `Utf8`                 |
+//! | scaled                 | `Float64`              |
+//! | transformed            | its underlying type    |
+//! | nested record          | `Struct`               |
+//! | nested list            | `List`                 |
+//!
+//! Fields which aren't required by the record are nullable, and a null value
+//! corresponds to the field being absent.
+
+use crate::comp::{GeoCompressor, GeoForm};
+use crate::data::CompressedObject;
+use crate::prelude::*;
+use crate::schema::{CompositeType, Record, Schema, Type};
+use ::arrow::datatypes::{self, DataType, Field, SchemaRef};
+use ::arrow::json::reader::Decoder;
+use ::arrow::json::writer::record_batches_to_json_rows;
+use ::arrow::record_batch::RecordBatch;
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+
+/// Converts a schema whose root is a list of records into the Arrow schema of
+/// the record batches it corresponds to.
+pub fn arrow_schema(schema: &Schema) -> Result<datatypes::Schema> {
+  let record = row_record(schema)?;
+  Ok(datatypes::Schema::new(fields(record)?))
+}
+
+/// Converts a decoded list of records into a single record batch with one row
+/// per record.
+pub fn to_record_batch(schema: &Schema, value: &Value) -> Result<RecordBatch> {
+  let rows = value
+    .as_array()
+    .ok_or_else(|| anyhow!("expected an array of records"))?;
+  let arrow_schema = SchemaRef::new(arrow_schema(schema)?);
+
+  // Arrow rejects a batch size of 0, even when there are no rows
+  let decoder = Decoder::new(arrow_schema.clone(), rows.len().max(1), None);
+  let mut rows = rows.iter().cloned().map(Ok);
+  let batch = decoder.next_batch(&mut rows)?;
+  Ok(batch.unwrap_or_else(|| RecordBatch::new_empty(arrow_schema)))
+}
+
+/// Converts a record batch back into a list of records, dropping null
+/// columns from each record.
+///
+/// The columns of the batch must be fields of the record in `schema`.
+pub fn from_record_batch(
+  schema: &Schema,
+  batch: &RecordBatch,
+) -> Result<Value> {
+  let record = row_record(schema)?;
+  for field in batch.schema().fields() {
+    if !record.fields.contains_key(field.name()) {
+      bail!("column '{}' is not a field of the record", field.name());
+    }
+  }
+
+  let rows = record_batches_to_json_rows(core::slice::from_ref(batch));
+  Ok(Value::Array(rows.into_iter().map(Value::Object).collect()))
+}
+
+/// Decodes a compressed list of records straight into a record batch.
+pub fn decode_record_batch(
+  schema: &Schema,
+  bytes: &[u8],
+) -> Result<RecordBatch> {
+  to_record_batch(schema, &crate::decode(schema, bytes)?)
+}
+
+/// Encodes the rows of a record batch as a compressed list of records.
+pub fn encode_record_batch(
+  schema: &Schema,
+  batch: &RecordBatch,
+) -> Result<CompressedObject> {
+  crate::encode(schema, &from_record_batch(schema, batch)?)
+}
+
+/// The record type of each element of the root list.
+fn row_record(schema: &Schema) -> Result<&Record> {
+  match schema.root() {
+    Type::Nested(CompositeType::List(list)) => match list.0.as_ref() {
+      Type::Nested(CompositeType::Record(record)) => Ok(record),
+      _ => {
+        bail!("record batches need a list of records, found a list of values")
+      }
+    },
+    _ => bail!("record batches need a list of records at the root"),
+  }
+}
+
+fn fields(record: &Record) -> Result<Vec<Field>> {
+  record
+    .fields
+    .iter()
+    .map(|(name, ty)| {
+      let nullable = !record.required.contains(name);
+      Ok(Field::new(name, data_type(ty)?, nullable))
+    })
+    .collect()
+}
+
+fn data_type(ty: &Type) -> Result<DataType> {
+  Ok(match ty {
+    Type::PassThrough
+    | Type::Enum { .. }
+    | Type::Huffman { .. }
+    | Type::Dictionary { .. } => DataType::Utf8,
+    Type::IntEnum { .. } => DataType::Int64,
+    Type::Scaled { .. } => DataType::Float64,
+    Type::Transformed { of, .. } => data_type(of)?,
+    Type::Name(name) if name == "bool" => DataType::Boolean,
+    Type::Name(name)
+      if ["bwt", "color", "lzw", "smaz"].contains(&name.as_str()) =>
+    {
+      DataType::Utf8
+    }
+    // Arrow's JSON reader can't read half floats, but every f16 is an f32
+    Type::Name(name) if name == "f16" => DataType::Float32,
+    Type::Name(name) if name == "timestamp" => DataType::Int64,
+    Type::Name(name) if crate::encode::is_float(name) => DataType::Float64,
+    Type::Name(name) => match GeoCompressor::parse(name) {
+      Some((_, GeoForm::Pair)) => {
+        DataType::List(Box::new(Field::new("item", DataType::Float64, false)))
+      }
+      Some((_, GeoForm::Record)) => DataType::Struct(vec![
+        Field::new("lat", DataType::Float64, false),
+        Field::new("lon", DataType::Float64, false),
+      ]),
+      None => bail!("no arrow type for '{}'", name),
+    },
+    Type::Nested(CompositeType::Record(record)) => {
+      DataType::Struct(fields(record)?)
+    }
+    Type::Nested(CompositeType::List(list)) => {
+      DataType::List(Box::new(Field::new("item", data_type(&list.0)?, true)))
+    }
+    Type::Union { .. } => bail!("no arrow type for unions"),
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::List;
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert(
+      "tags".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::PassThrough)))),
+    );
+    let mut record = Record::new(fields);
+    record.required.insert("name".to_string());
+    Schema::new(CompositeType::List(List(Box::new(Type::Nested(
+      CompositeType::Record(record),
+    )))))
+  }
+
+  #[test]
+  fn schema_conversion() {
+    let s = arrow_schema(&schema()).unwrap();
+    let tags =
+      DataType::List(Box::new(Field::new("item", DataType::Utf8, true)));
+    assert_eq!(
+      &vec![
+        Field::new("active", DataType::Boolean, true),
+        Field::new("name", DataType::Utf8, false),
+        Field::new("tags", tags, true),
+      ],
+      s.fields()
+    );
+
+    let scalar = Schema::new_scalar(Type::PassThrough);
+    assert!(arrow_schema(&scalar).is_err());
+  }
+
+  #[test]
+  fn round_trip_through_record_batch() {
+    let schema = schema();
+    let value = json!([
+      { "name": "a", "active": true, "tags": ["x", "y"] },
+      { "name": "b" }
+    ]);
+    let bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+
+    let batch = decode_record_batch(&schema, &bytes).unwrap();
+    assert_eq!(2, batch.num_rows());
+    assert_eq!(3, batch.num_columns());
+
+    let encoded = encode_record_batch(&schema, &batch).unwrap();
+    assert_eq!(value, crate::decode(&schema, &encoded.to_bytes()).unwrap());
+  }
+
+  #[test]
+  fn empty_list() {
+    let schema = schema();
+    let batch = to_record_batch(&schema, &json!([])).unwrap();
+    assert_eq!(0, batch.num_rows());
+    assert_eq!(json!([]), from_record_batch(&schema, &batch).unwrap());
+  }
+}
@@ -0,0 +1,254 @@
+//! The `async_io` module provides asynchronous versions of the stream based
+//! encoding, decoding and framing functions for use with tokio.
+//!
+//! Encoding and decoding are CPU bound and still happen in memory. Only the
+//! reading and writing of bytes is asynchronous, so executor threads are never
+//! blocked waiting on I/O.
+
+use crate::data::{BitOrder, Layout, Profile};
+use crate::frame::{self, Frame};
+use crate::schema::Schema;
+use crate::{DecodeOptions, EncodeOptions, Warning};
+use anyhow::{bail, Result};
+use serde_json::Value;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// Encodes a JSON `value` using a given `schema`, writing the encoded bytes
+/// to `writer`.
+pub async fn encode_to<W>(
+  schema: &Schema,
+  value: &Value,
+  writer: W,
+) -> Result<()>
+where
+  W: AsyncWrite + Unpin,
+{
+  encode_to_with(schema, value, writer, &EncodeOptions::default()).await?;
+  Ok(())
+}
+
+/// Encodes a JSON `value` using a given `schema` and `options`, writing the
+/// encoded bytes to `writer` and returning any warnings about the data.
+pub async fn encode_to_with<W>(
+  schema: &Schema,
+  value: &Value,
+  mut writer: W,
+  options: &EncodeOptions,
+) -> Result<Vec<Warning>>
+where
+  W: AsyncWrite + Unpin,
+{
+  let mut bytes = Vec::new();
+  let warnings = crate::encode_to_with(schema, value, &mut bytes, options)?;
+  writer.write_all(&bytes).await?;
+  writer.flush().await?;
+  Ok(warnings)
+}
+
+/// Reads `reader` to the end and decodes its contents using `schema`.
+pub async fn decode_from<R>(schema: &Schema, reader: R) -> Result<Value>
+where
+  R: AsyncRead + Unpin,
+{
+  decode_from_with(schema, reader, DecodeOptions::default()).await
+}
+
+/// Reads `reader` to the end and decodes its contents using `schema`,
+/// enforcing the limits in `options`.
+pub async fn decode_from_with<R>(
+  schema: &Schema,
+  mut reader: R,
+  options: DecodeOptions,
+) -> Result<Value>
+where
+  R: AsyncRead + Unpin,
+{
+  let mut bytes = Vec::new();
+  reader.read_to_end(&mut bytes).await?;
+  crate::decode_with(schema, &bytes, options)
+}
+
+/// Writes the bytes of a compressed `object` as a single frame, including the
+/// fingerprint of `schema` if given.
+///
+/// This is the asynchronous version of [`frame::write_frame`].
+pub async fn write_frame<W>(
+  mut writer: W,
+  object: &[u8],
+  schema: Option<&Schema>,
+) -> Result<()>
+where
+  W: AsyncWrite + Unpin,
+{
+  let fingerprint = schema.map(Schema::fingerprint);
+  writer
+    .write_all(&frame::prefix(
+      object.len(),
+      fingerprint,
+      Layout::Packed,
+      Profile::Standard,
+      false,
+      BitOrder::MsbFirst,
+    ))
+    .await?;
+  writer.write_all(object).await?;
+  Ok(())
+}
+
+/// Reads the next frame from `reader`.
+///
+/// This is the asynchronous version of [`frame::read_frame`].
+pub async fn read_frame<R>(mut reader: R) -> Result<Option<Frame>>
+where
+  R: AsyncRead + Unpin,
+{
+  let mut bytes = Vec::with_capacity(frame::MAX_HEADER_LEN);
+  let (len, has_fingerprint, layout, profile, checksums, bit_order) = loop {
+    let mut byte = [0u8];
+    if reader.read(&mut byte).await? == 0 {
+      return frame::end_of_header(&bytes);
+    }
+    if frame::push_header_byte(&mut bytes, byte[0])? {
+      break frame::parse_header(&bytes)?;
+    }
+  };
+
+  let fingerprint = if has_fingerprint {
+    let mut bytes = [0u8; 8];
+    reader
+      .read_exact(&mut bytes)
+      .await
+      .map_err(frame::truncated)?;
+    Some(u64::from_le_bytes(bytes))
+  } else {
+    None
+  };
+
+  let mut object = Vec::new();
+  (&mut reader).take(len).read_to_end(&mut object).await?;
+  if (object.len() as u64) < len {
+    bail!("frame is truncated");
+  }
+  Ok(Some(Frame {
+    fingerprint,
+    object,
+    layout,
+    profile,
+    checksums,
+    bit_order,
+  }))
+}
+
+/// A stream of frames over some asynchronous connection, such as a TCP
+/// socket.
+///
+/// Frames can be received if the connection is [`AsyncRead`] and sent if it
+/// is [`AsyncWrite`].
+#[derive(Debug)]
+pub struct FrameStream<S> {
+  inner: S,
+}
+
+impl<S> FrameStream<S> {
+  pub fn new(inner: S) -> Self {
+    FrameStream { inner }
+  }
+
+  /// Returns the underlying connection.
+  pub fn into_inner(self) -> S {
+    self.inner
+  }
+}
+
+impl<S: AsyncRead + Unpin> FrameStream<S> {
+  /// Receives the next frame, or `None` if the connection was closed.
+  pub async fn next(&mut self) -> Result<Option<Frame>> {
+    read_frame(&mut self.inner).await
+  }
+}
+
+impl<S: AsyncWrite + Unpin> FrameStream<S> {
+  /// Sends the bytes of a compressed `object` as a frame, including the
+  /// fingerprint of `schema` if given.
+  pub async fn send(
+    &mut self,
+    object: &[u8],
+    schema: Option<&Schema>,
+  ) -> Result<()> {
+    write_frame(&mut self.inner, object, schema).await?;
+    self.inner.flush().await?;
+    Ok(())
+  }
+
+  /// Encodes `value` and sends it as a frame with the fingerprint of
+  /// `schema`.
+  pub async fn send_value(
+    &mut self,
+    schema: &Schema,
+    value: &Value,
+  ) -> Result<()> {
+    let object = crate::encode(schema, value)?.to_bytes();
+    self.send(&object, Some(schema)).await
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{CompositeType, Record, Type};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+  use std::future::Future;
+
+  fn block_on<F: Future>(f: F) -> F::Output {
+    let rt = tokio::runtime::Builder::new_current_thread()
+      .build()
+      .unwrap();
+    rt.block_on(f)
+  }
+
+  fn schema() -> Schema {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    Schema::new(CompositeType::Record(Record::new(fields)))
+  }
+
+  #[test]
+  fn async_roundtrip() {
+    let schema = schema();
+    let value = json!({ "name": "Jeremy" });
+
+    let mut bytes = Vec::new();
+    block_on(encode_to(&schema, &value, &mut bytes)).unwrap();
+    assert_eq!(crate::encode(&schema, &value).unwrap().to_bytes(), bytes);
+    assert_eq!(value, block_on(decode_from(&schema, &bytes[..])).unwrap());
+  }
+
+  #[test]
+  fn async_frames() {
+    let schema = schema();
+    let values = vec![json!({ "name": "Jeremy" }), json!({ "name": "Ada" })];
+
+    let mut stream = FrameStream::new(Vec::new());
+    for value in &values {
+      block_on(stream.send_value(&schema, value)).unwrap();
+    }
+    let bytes = stream.into_inner();
+
+    // Frames are the same as those written synchronously
+    let mut expected = Vec::new();
+    for value in &values {
+      let object = crate::encode(&schema, value).unwrap().to_bytes();
+      frame::write_frame(&mut expected, &object, Some(&schema)).unwrap();
+    }
+    assert_eq!(expected, bytes);
+
+    let mut stream = FrameStream::new(&bytes[..]);
+    for value in &values {
+      let frame = block_on(stream.next()).unwrap().unwrap();
+      assert_eq!(*value, frame.decode(&schema).unwrap());
+    }
+    assert_eq!(None, block_on(stream.next()).unwrap());
+    assert!(block_on(read_frame(&bytes[..3])).is_err());
+  }
+}
@@ -1,10 +1,35 @@
-use anyhow::{anyhow, Result};
-use bit_vec::BitVec;
-use chii::schema::Schema;
+use anyhow::{anyhow, Context, Result};
+use chii::archive::Archive;
+use chii::bit::BitReader;
+use chii::comp::Codebook;
+use chii::data::{BitOrder, Layout, Profile};
+use chii::header::{self, Header};
+use chii::index::Index;
+use chii::inspect::{BlockKind, SectionKind};
+use chii::patch::Patch;
+use chii::path::Segment;
+use chii::schema::{dsl, Schema, Type};
+use chii::unknown::UnknownFields;
+use chii::{
+  DecodeOptions, DeprecatedFieldPolicy, EncodeOptions, UnknownFieldPolicy,
+  Warning,
+};
+#[cfg(feature = "bench")]
+use flate2::write::GzEncoder;
+#[cfg(feature = "parquet")]
+use parquet::arrow::ArrowWriter;
 use serde_json::Value;
-use std::fs::File;
-use std::io::Write;
-use std::path::PathBuf;
+use std::ffi::OsStr;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufWriter, Seek, SeekFrom, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::process;
+use std::str::FromStr;
+use std::sync::{mpsc, Arc, Mutex};
+use std::thread;
+#[cfg(feature = "bench")]
+use std::time::{Duration, Instant};
 use structopt::StructOpt;
 
 #[derive(Debug, StructOpt)]
@@ -12,15 +37,301 @@ use structopt::StructOpt;
   name = "chii",
   about = "A compression utility for domain specific data"
 )]
-struct Opt {
-  /// Uncompress file
-  #[structopt(short, long)]
-  decompress: bool,
+enum Opt {
+  /// Compress a file
+  Compress(CompressOpt),
 
+  /// Uncompress a file
+  Decompress(DecompressOpt),
+
+  /// Compress a file and append it to an archive
+  Append(AppendOpt),
+
+  /// Decode a single value from a compressed file
+  Get(GetOpt),
+
+  /// Overwrite a fixed-width value in a compressed file in place
+  Set(SetOpt),
+
+  /// Show field-level differences between two compressed files
+  Diff(DiffOpt),
+
+  /// Create a binary patch between two compressed files
+  Patch(PatchOpt),
+
+  /// Apply a binary patch to a compressed file
+  Apply(ApplyOpt),
+
+  /// Compare compressed size and time against general purpose compressors
+  #[cfg(feature = "bench")]
+  Bench(BenchOpt),
+
+  /// Print every block of a compressed file with its location and value
+  Inspect(InspectOpt),
+
+  /// Print the bytes of a compressed file next to what each of their bits
+  /// means
+  Explain(ExplainOpt),
+
+  /// Compare the declared probabilities of enum variants with how often they
+  /// occur in a file
+  Stats(StatsOpt),
+
+  /// Compare the size of a file compressed with each of several schemas,
+  /// field by field
+  Compare(CompareOpt),
+
+  /// Give the fields of a schema's records identifiers by how often they
+  /// appear in a set of files
+  Reorder(ReorderOpt),
+
+  /// Export a compressed list of records to a columnar format
+  #[cfg(feature = "parquet")]
+  Export(ExportOpt),
+
+  /// Work with schema files
+  Schema(SchemaOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct CompressOpt {
   /// Print compressed object blocks
   #[structopt(long)]
   blocks: bool,
 
+  /// Format of the blocks printed by --blocks: text, or json for one object
+  /// per line
+  #[structopt(long, value_name = "FORMAT", default_value = "text")]
+  format: BlockFormat,
+
+  /// Attach an index recording the offset of every Nth root list element
+  #[structopt(long, value_name = "N")]
+  index: Option<usize>,
+
+  /// What to do with fields not in the schema: error, ignore, or preserve
+  #[structopt(long, value_name = "POLICY", default_value = "error")]
+  unknown_fields: UnknownFieldPolicy,
+
+  /// What to do with fields the schema marks as deprecated: error or ignore
+  #[structopt(long, value_name = "POLICY", default_value = "error")]
+  deprecated_fields: DeprecatedFieldPolicy,
+
+  /// How blocks are laid out: packed, or byte-aligned to pad every block to a
+  /// whole number of bytes
+  #[structopt(long, value_name = "LAYOUT", default_value = "packed")]
+  layout: Layout,
+
+  /// Which encoding profile to use: standard, or dense to leave out the field
+  /// markers of records whose fields are all required
+  #[structopt(long, value_name = "PROFILE", default_value = "standard")]
+  profile: Profile,
+
+  /// How bits are packed into bytes: msb-first, or lsb-first for tools which
+  /// read the lowest bit of each byte first
+  #[structopt(long, value_name = "ORDER", default_value = "msb-first")]
+  bit_order: BitOrder,
+
+  /// Follow the data of every variable-width value with a checksum, so that
+  /// damage to it can be pinned to that value
+  #[structopt(long)]
+  checksums: bool,
+
+  /// Replace the compressor the schema chooses for the values at a path
+  /// (e.g., 'courses[].name=huffman'). CODEC is raw, bool, huffman:CODEBOOK
+  /// or huffman, which trains a codebook on the data being compressed
+  #[structopt(
+    long = "override",
+    value_name = "PATH=CODEC",
+    number_of_values = 1
+  )]
+  overrides: Vec<Override>,
+
+  /// Compress every JSON file in a directory, mirroring its structure
+  #[structopt(short, long, conflicts_with = "blocks")]
+  recursive: bool,
+
+  /// Print the projected size of the compressed file, broken down by
+  /// section, without writing anything
+  #[structopt(long, conflicts_with_all = &["blocks", "index", "recursive"])]
+  dry_run: bool,
+
+  /// Number of files to compress at once with --recursive, defaults to the
+  /// number of CPUs
+  #[structopt(short, long, value_name = "N")]
+  jobs: Option<usize>,
+
+  /// Output file, or output directory with --recursive
+  #[structopt(short)]
+  out_file: Option<PathBuf>,
+
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the data, or a directory of data with --recursive
+  file: PathBuf,
+}
+
+/// How blocks are printed by `compress --blocks`.
+#[derive(Copy, Clone, Debug)]
+enum BlockFormat {
+  Text,
+  Json,
+}
+
+impl FromStr for BlockFormat {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "text" => Ok(BlockFormat::Text),
+      "json" => Ok(BlockFormat::Json),
+      _ => Err(anyhow!("unknown block format: {}", s)),
+    }
+  }
+}
+
+/// A replacement for the compressor which the schema chooses for the values
+/// at some path, given as `PATH=CODEC`.
+#[derive(Debug)]
+struct Override {
+  path: chii::path::Path,
+  codec: Codec,
+}
+
+#[derive(Debug)]
+enum Codec {
+  Type(Box<Type>),
+  /// A Huffman code trained on the values being compressed.
+  TrainedHuffman,
+}
+
+impl FromStr for Override {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let (path, codec) = s
+      .split_once('=')
+      .ok_or_else(|| anyhow!("expected PATH=CODEC: {}", s))?;
+    // Every element of a list has the same type so `[]` stands for any index
+    let path = path.trim().replace("[]", "[0]");
+    let path = if path.starts_with(&['.', '['][..]) {
+      path.parse()?
+    } else {
+      format!(".{}", path).parse()?
+    };
+    let codec = match codec.trim() {
+      "raw" => Codec::Type(Box::new(Type::PassThrough)),
+      "bool" => Codec::Type(Box::new(Type::Name("bool".to_string()))),
+      "huffman" => Codec::TrainedHuffman,
+      codec => match codec.strip_prefix("huffman:") {
+        Some(book) => Codec::Type(Box::new(Type::Huffman {
+          huffman: book.parse()?,
+        })),
+        None => {
+          return Err(anyhow!(
+            "unknown codec '{}', expected raw, bool, huffman or \
+             huffman:CODEBOOK",
+            codec
+          ))
+        }
+      },
+    };
+    Ok(Override { path, codec })
+  }
+}
+
+/// Applies `overrides` to `schema`, training Huffman codes on `data`.
+fn apply_overrides(
+  schema: &mut Schema,
+  overrides: &[Override],
+  data: Option<&Value>,
+) -> Result<()> {
+  for o in overrides {
+    let ty = match &o.codec {
+      Codec::Type(ty) => ty.as_ref().clone(),
+      Codec::TrainedHuffman => {
+        let data = data.ok_or_else(|| {
+          anyhow!(
+            "{}=huffman needs a codebook here, use huffman:CODEBOOK",
+            o.path
+          )
+        })?;
+        let mut samples = Vec::new();
+        strings_at(data, o.path.segments(), &mut samples);
+        let huffman = Codebook::train(&samples);
+        eprintln!("trained {}=huffman:{}", o.path, huffman);
+        Type::Huffman { huffman }
+      }
+    };
+    schema
+      .override_type(&o.path, ty)
+      .with_context(|| format!("failed to override {}", o.path))?;
+  }
+  Ok(())
+}
+
+/// Collects the strings at `path` within `value`, where list indices stand
+/// for every element.
+fn strings_at<'v>(value: &'v Value, path: &[Segment], out: &mut Vec<&'v str>) {
+  match (path.split_first(), value) {
+    (None, Value::String(s)) => out.push(s),
+    (Some((Segment::Field(name), rest)), Value::Object(map)) => {
+      if let Some(v) = map.get(name) {
+        strings_at(v, rest, out);
+      }
+    }
+    (Some((Segment::Index(_), rest)), Value::Array(arr)) => {
+      for v in arr {
+        strings_at(v, rest, out);
+      }
+    }
+    _ => {}
+  }
+}
+
+impl CompressOpt {
+  fn output_file_path(&self) -> PathBuf {
+    if let Some(path) = &self.out_file {
+      path.clone()
+    } else {
+      let mut input_file = self.file.clone();
+      input_file.set_extension("co");
+      input_file
+    }
+  }
+}
+
+#[derive(Debug, StructOpt)]
+struct DecompressOpt {
+  /// Indent the output and sort record fields by name
+  #[structopt(long, conflicts_with = "compact")]
+  pretty: bool,
+
+  /// Write the output on a single line without whitespace (the default)
+  #[structopt(long, conflicts_with = "pretty")]
+  compact: bool,
+
+  /// Salvage what can be decoded from a damaged file, reporting each problem
+  /// found instead of failing
+  #[structopt(long)]
+  lenient: bool,
+
+  /// Fail instead of letting the decoded value take up more than BYTES of
+  /// memory
+  #[structopt(long, value_name = "BYTES")]
+  max_memory: Option<usize>,
+
+  /// Replace the compressor the schema chooses for the values at a path
+  /// (e.g., 'courses[].name=huffman'). CODEC is raw, bool, huffman:CODEBOOK
+  /// or huffman:CODEBOOK, using the
+  /// codebook printed when compressing with huffman
+  #[structopt(
+    long = "override",
+    value_name = "PATH=CODEC",
+    number_of_values = 1
+  )]
+  overrides: Vec<Override>,
+
   /// Output file
   #[structopt(short)]
   out_file: Option<PathBuf>,
@@ -28,54 +339,1391 @@ struct Opt {
   /// Path to the data schema
   schema: PathBuf,
 
+  /// Path to the compressed data
+  file: PathBuf,
+}
+
+impl DecompressOpt {
+  fn output_file_path(&self) -> PathBuf {
+    if let Some(path) = &self.out_file {
+      path.clone()
+    } else {
+      let mut input_file = self.file.clone();
+      input_file.set_extension("json");
+      input_file
+    }
+  }
+}
+
+#[derive(Debug, StructOpt)]
+struct AppendOpt {
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the archive, it is created if it doesn't exist
+  archive: PathBuf,
+
   /// Path to the data
   file: PathBuf,
+
+  /// Store a new archive's entries with this percentage of Reed-Solomon
+  /// parity, so that damaged entries can be repaired
+  #[cfg(feature = "fec")]
+  #[structopt(long, value_name = "PERCENT")]
+  parity: Option<u32>,
+}
+
+#[derive(Debug, StructOpt)]
+struct GetOpt {
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the compressed data
+  file: PathBuf,
+
+  /// Path of the value to decode (e.g., '.courses[2].grade')
+  path: chii::path::Path,
 }
 
-impl Opt {
+#[derive(Debug, StructOpt)]
+struct SetOpt {
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the compressed data
+  file: PathBuf,
+
+  /// Path of the value to overwrite (e.g., '.status')
+  path: chii::path::Path,
+
+  /// The new value as JSON (e.g., '"active"' or 'true')
+  value: String,
+}
+
+#[derive(Debug, StructOpt)]
+struct DiffOpt {
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the old compressed data
+  old: PathBuf,
+
+  /// Path to the new compressed data
+  new: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct PatchOpt {
+  /// Output file
+  #[structopt(short)]
+  out_file: PathBuf,
+
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the old compressed data
+  old: PathBuf,
+
+  /// Path to the new compressed data
+  new: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct ApplyOpt {
+  /// Output file, defaults to overwriting the compressed data
+  #[structopt(short)]
+  out_file: Option<PathBuf>,
+
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the compressed data
+  file: PathBuf,
+
+  /// Path to the patch
+  patch: PathBuf,
+}
+
+#[cfg(feature = "bench")]
+#[derive(Debug, StructOpt)]
+struct BenchOpt {
+  /// Number of times to run each compressor, the fastest run is reported
+  #[structopt(short = "n", long, default_value = "10")]
+  iterations: usize,
+
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the data
+  file: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct InspectOpt {
+  /// Maximum number of raw bits to print per block
+  #[structopt(long, value_name = "N", default_value = "32")]
+  max_bits: usize,
+
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the compressed data
+  file: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct ExplainOpt {
+  /// Maximum number of raw bits to print per section
+  #[structopt(long, value_name = "N", default_value = "32")]
+  max_bits: usize,
+
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the compressed data
+  file: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct StatsOpt {
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Path to the data
+  file: PathBuf,
+}
+
+#[derive(Debug, StructOpt)]
+struct CompareOpt {
+  /// Path to the data
+  file: PathBuf,
+
+  /// Paths to the candidate schemas, the first of which the others are
+  /// compared against
+  #[structopt(required = true, min_values = 2)]
+  schemas: Vec<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct ReorderOpt {
+  /// Write the reordered schema to this file, instead of only printing the
+  /// proposed identifiers
+  #[structopt(short)]
+  out_file: Option<PathBuf>,
+
+  /// Format of the reordered schema: yaml, json or toml, defaults to the
+  /// format implied by the output file's extension
+  #[structopt(long, value_name = "FORMAT")]
+  to: Option<SchemaFormat>,
+
+  /// Write the old and new identifiers of the reordered fields to this file
+  /// as JSON
+  #[structopt(long, value_name = "FILE")]
+  mapping: Option<PathBuf>,
+
+  /// Path to the data schema
+  schema: PathBuf,
+
+  /// Paths to the data
+  #[structopt(required = true)]
+  files: Vec<PathBuf>,
+}
+
+#[cfg(feature = "parquet")]
+#[derive(Debug, StructOpt)]
+struct ExportOpt {
+  /// Format to export to: parquet
+  #[structopt(long, value_name = "FORMAT")]
+  format: ExportFormat,
+
+  /// Output file, defaults to the input file with the format's extension
+  #[structopt(short)]
+  out_file: Option<PathBuf>,
+
+  /// Path to the data schema, its root must be a list of records
+  schema: PathBuf,
+
+  /// Path to the compressed data
+  file: PathBuf,
+}
+
+#[cfg(feature = "parquet")]
+impl ExportOpt {
   fn output_file_path(&self) -> PathBuf {
     if let Some(path) = &self.out_file {
       path.clone()
     } else {
       let mut input_file = self.file.clone();
-      input_file.set_extension("co");
+      input_file.set_extension(self.format.extension());
       input_file
     }
   }
 }
 
-fn compress(opt: &Opt) -> Result<()> {
-  // Load schema from file
-  let schema_file = File::open(&opt.schema)?;
-  let schema: Schema = serde_yaml::from_reader(schema_file)?;
+/// A file format which compressed lists of records can be exported to.
+#[cfg(feature = "parquet")]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum ExportFormat {
+  Parquet,
+}
+
+#[cfg(feature = "parquet")]
+impl ExportFormat {
+  fn extension(self) -> &'static str {
+    match self {
+      ExportFormat::Parquet => "parquet",
+    }
+  }
+}
+
+#[cfg(feature = "parquet")]
+impl FromStr for ExportFormat {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "parquet" => Ok(ExportFormat::Parquet),
+      _ => Err(anyhow!("unknown export format: {}", s)),
+    }
+  }
+}
+
+#[derive(Debug, StructOpt)]
+enum SchemaOpt {
+  /// Convert a schema between YAML, JSON, TOML and the compact DSL
+  Convert(ConvertOpt),
+  /// Bundle older versions of a schema with the current one
+  Bundle(BundleOpt),
+  /// Document the fields of a schema as a Markdown table
+  Doc(DocOpt),
+}
+
+#[derive(Debug, StructOpt)]
+struct DocOpt {
+  /// Output file, defaults to stdout
+  #[structopt(short)]
+  out_file: Option<PathBuf>,
+
+  /// Path to the schema
+  schema: PathBuf,
+}
 
-  // Load data from file
-  let data_file = File::open(&opt.file)?;
-  let data: Value = serde_json::from_reader(data_file)?;
+#[derive(Debug, StructOpt)]
+struct BundleOpt {
+  /// Format of the bundle: yaml, json or toml, defaults to the format implied
+  /// by the output file's extension
+  #[structopt(long, value_name = "FORMAT")]
+  to: Option<SchemaFormat>,
+
+  /// Output file
+  #[structopt(short)]
+  out_file: PathBuf,
+
+  /// Path to the current version of the schema
+  current: PathBuf,
+
+  /// Paths to older versions of the schema, which may be bundles themselves
+  older: Vec<PathBuf>,
+}
+
+#[derive(Debug, StructOpt)]
+struct ConvertOpt {
+  /// Format to convert to: yaml, json, toml or dsl, defaults to the format
+  /// implied by the output file's extension
+  #[structopt(long, value_name = "FORMAT")]
+  to: Option<SchemaFormat>,
+
+  /// Path to the schema
+  input: PathBuf,
+
+  /// Path to write the converted schema to
+  output: PathBuf,
+}
+
+/// A file format which schemas can be written in.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum SchemaFormat {
+  Yaml,
+  Json,
+  Toml,
+  /// The compact syntax of `chii::schema::dsl`.
+  Dsl,
+}
+
+impl SchemaFormat {
+  /// The format of a schema file based on its extension. Files without a
+  /// known extension are assumed to be YAML.
+  fn of(path: &Path) -> Self {
+    match path.extension().and_then(OsStr::to_str) {
+      Some("json") => SchemaFormat::Json,
+      Some("toml") => SchemaFormat::Toml,
+      Some("dsl") => SchemaFormat::Dsl,
+      _ => SchemaFormat::Yaml,
+    }
+  }
+
+  fn parse(self, s: &str) -> Result<Schema> {
+    let schema = match self {
+      SchemaFormat::Yaml => serde_yaml::from_str(s)?,
+      SchemaFormat::Json => serde_json::from_str(s)?,
+      SchemaFormat::Toml => toml::from_str(s)?,
+      SchemaFormat::Dsl => dsl::parse(s)?,
+    };
+    Ok(schema)
+  }
+
+  fn write(self, schema: &Schema) -> Result<String> {
+    let s = match self {
+      SchemaFormat::Yaml => serde_yaml::to_string(schema)?,
+      SchemaFormat::Json => serde_json::to_string_pretty(schema)? + "\n",
+      // Going through a `toml::Value` makes sure nested tables are written
+      // after plain values, which TOML requires
+      SchemaFormat::Toml => {
+        toml::to_string_pretty(&toml::Value::try_from(schema)?)?
+      }
+      SchemaFormat::Dsl => dsl::to_string(schema)?,
+    };
+    Ok(s)
+  }
+}
+
+impl FromStr for SchemaFormat {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "yaml" | "yml" => Ok(SchemaFormat::Yaml),
+      "json" => Ok(SchemaFormat::Json),
+      "toml" => Ok(SchemaFormat::Toml),
+      "dsl" => Ok(SchemaFormat::Dsl),
+      _ => Err(anyhow!("unknown schema format: {}", s)),
+    }
+  }
+}
+
+/// Loads a schema from a YAML, JSON, TOML or DSL file depending on its
+/// extension.
+fn load_schema(path: &Path) -> Result<Schema> {
+  let s = fs::read_to_string(path)?;
+  SchemaFormat::of(path)
+    .parse(&s)
+    .with_context(|| format!("failed to load schema {}", path.display()))
+}
+
+/// Loads a JSON value from a file.
+fn load_json(path: &Path) -> Result<Value> {
+  let data_file = File::open(path)?;
+  let data = serde_json::from_reader(data_file)?;
+  Ok(data)
+}
+
+/// Decodes a compressed file ignoring any attached index and restoring any
+/// preserved unknown fields.
+fn load_compressed(
+  schema: &Schema,
+  path: &Path,
+  options: DecodeOptions,
+) -> Result<Value> {
+  let bytes = fs::read(path)?;
+  let file = header::parse(&bytes)?;
+  let options = file.header.decode_options(options);
+  let mut value = chii::decode_with(schema, file.object, options)?;
+  if let Some(unknown) = file.unknown_fields {
+    unknown.restore(&mut value)?;
+  }
+  Ok(value)
+}
+
+/// Settings shared by every file being compressed.
+struct CompressJob {
+  schema: Schema,
+  options: EncodeOptions,
+  index: Option<usize>,
+  blocks: Option<BlockFormat>,
+}
+
+/// The sizes of a file before and after compression.
+struct CompressStats {
+  original: u64,
+  compressed: u64,
+}
+
+impl CompressJob {
+  /// Compresses the JSON file at `input` writing the result to `output`.
+  fn compress_file(
+    &self,
+    input: &Path,
+    output: &Path,
+  ) -> Result<CompressStats> {
+    let schema = &self.schema;
+    let data = load_json(input)?;
+    let unknown = Some(UnknownFields::collect(schema, &data)).filter(|u| {
+      self.options.unknown_fields == UnknownFieldPolicy::Preserve
+        && !u.is_empty()
+    });
+    let header = Header {
+      unknown_fields: unknown.is_some(),
+      index: self.index.is_some(),
+      layout: self.options.layout,
+      bit_order: self.options.bit_order,
+      profile: self.options.profile,
+      checksums: self.options.checksums,
+    };
+    let decode_options = header.decode_options(DecodeOptions::default());
+
+    // Without any extra output the encoding can be streamed straight to disk
+    if self.blocks.is_none() && self.index.is_none() {
+      let mut file = BufWriter::new(File::create(output)?);
+      file.write_all(&header.to_bytes())?;
+      let warnings =
+        chii::encode_to_with(schema, &data, &mut file, &self.options)?;
+      print_warnings(input, &warnings);
+      file.flush()?;
+      return CompressStats::of(input, output);
+    }
+
+    // Perform compression
+    let (co, warnings) =
+      chii::encode_with_warnings(schema, &data, &self.options)?;
+    print_warnings(input, &warnings);
+    let mut bytes = co.to_bytes();
+    match self.blocks {
+      Some(BlockFormat::Text) => {
+        for block in &co.blocks {
+          println!("{}", block);
+        }
+      }
+      Some(BlockFormat::Json) => {
+        let inspection =
+          chii::inspect::inspect_with(schema, &bytes, decode_options);
+        for block in &inspection.blocks {
+          println!("{}", block.to_json());
+        }
+        if let Some(e) = inspection.error {
+          return Err(e);
+        }
+      }
+      None => {}
+    }
+
+    co.validate(schema)?;
+
+    if let Some(unknown) = &unknown {
+      unknown.append_to(&mut bytes);
+    }
+
+    if let Some(stride) = self.index {
+      let index = Index::build_with(schema, &bytes, stride, decode_options)?;
+      index.append_to(&mut bytes);
+    }
+
+    // Write to output file
+    let mut file = File::create(output)?;
+    file.write_all(&header.to_bytes())?;
+    file.write_all(&bytes)?;
+
+    CompressStats::of(input, output)
+  }
+}
+
+/// Prints the problems found in the data of `file` while compressing it to
+/// stderr.
+fn print_warnings(file: &Path, warnings: &[Warning]) {
+  for w in warnings {
+    eprintln!("warning: {}: {}", file.display(), w);
+  }
+}
+
+impl CompressStats {
+  fn of(input: &Path, output: &Path) -> Result<Self> {
+    Ok(CompressStats {
+      original: fs::metadata(input)?.len(),
+      compressed: fs::metadata(output)?.len(),
+    })
+  }
+
+  /// The compressed size as a percentage of the original size.
+  fn ratio(&self) -> f64 {
+    if self.original == 0 {
+      return 100.0;
+    }
+    self.compressed as f64 / self.original as f64 * 100.0
+  }
+}
+
+fn compress(opt: &CompressOpt) -> Result<()> {
+  // Size estimates assume the default options
+  let defaults = EncodeOptions::default();
+  if opt.dry_run
+    && (opt.layout != defaults.layout
+      || opt.profile != defaults.profile
+      || opt.checksums
+      || opt.unknown_fields != defaults.unknown_fields
+      || opt.deprecated_fields != defaults.deprecated_fields)
+  {
+    return Err(anyhow!(
+      "--dry-run can only be used with the default layout, profile and field \
+       policies, and without --checksums"
+    ));
+  }
+  let mut schema = load_schema(&opt.schema)?;
+  let trains = opt
+    .overrides
+    .iter()
+    .any(|o| matches!(o.codec, Codec::TrainedHuffman));
+  // Every file compressed with --recursive must share the same codebook
+  let data = if (trains || opt.dry_run) && !opt.recursive {
+    Some(load_json(&opt.file)?)
+  } else {
+    None
+  };
+  apply_overrides(&mut schema, &opt.overrides, data.as_ref())?;
+  // Dry runs can't be recursive, so their data has been loaded
+  if let Some(data) = data.as_ref().filter(|_| opt.dry_run) {
+    return print_projection(&schema, data, &opt.file);
+  }
+  let job = CompressJob {
+    schema,
+    options: EncodeOptions {
+      unknown_fields: opt.unknown_fields,
+      deprecated_fields: opt.deprecated_fields,
+      layout: opt.layout,
+      profile: opt.profile,
+      checksums: opt.checksums,
+      bit_order: opt.bit_order,
+      ..Default::default()
+    },
+    index: opt.index,
+    blocks: if opt.blocks { Some(opt.format) } else { None },
+  };
+
+  if opt.recursive {
+    return compress_dir(opt, job);
+  }
+  job.compress_file(&opt.file, &opt.output_file_path())?;
+  Ok(())
+}
+
+/// Compresses every JSON file under the directory `opt.file` using a pool of
+/// worker threads, then prints a summary of the results.
+///
+/// Outputs mirror the structure of the input directory under the output
+/// directory, which defaults to the input directory itself.
+fn compress_dir(opt: &CompressOpt, job: CompressJob) -> Result<()> {
+  let mut inputs = Vec::new();
+  find_json_files(&opt.file, &mut inputs)?;
+  inputs.sort();
+  let total = inputs.len();
+
+  let default_jobs = thread::available_parallelism().map_or(1, |n| n.get());
+  let workers = opt.jobs.unwrap_or(default_jobs).max(1).min(total.max(1));
+  let root = Arc::new(opt.file.clone());
+  let out_dir =
+    Arc::new(opt.out_file.clone().unwrap_or_else(|| opt.file.clone()));
+  let job = Arc::new(job);
+  let queue = Arc::new(Mutex::new(inputs.into_iter()));
+  let (tx, rx) = mpsc::channel();
+
+  for _ in 0..workers {
+    let (job, queue, tx) = (job.clone(), queue.clone(), tx.clone());
+    let (root, out_dir) = (root.clone(), out_dir.clone());
+    thread::spawn(move || loop {
+      let next = queue.lock().unwrap().next();
+      let input = match next {
+        Some(input) => input,
+        None => break,
+      };
+
+      // Inputs were found by walking `root` so they all start with it
+      let relative = input.strip_prefix(root.as_path()).unwrap();
+      let output = out_dir.join(relative).with_extension("co");
+      let result = output
+        .parent()
+        .map_or(Ok(()), fs::create_dir_all)
+        .map_err(anyhow::Error::from)
+        .and_then(|_| job.compress_file(&input, &output));
+      if tx.send((relative.to_path_buf(), result)).is_err() {
+        break;
+      }
+    });
+  }
+  drop(tx);
+
+  let mut results: Vec<_> = rx.iter().collect();
+  results.sort_by(|a, b| a.0.cmp(&b.0));
+  print_summary(&results);
+
+  let failed = results.iter().filter(|(_, r)| r.is_err()).count();
+  if failed > 0 {
+    return Err(anyhow!("failed to compress {} of {} files", failed, total));
+  }
+  Ok(())
+}
+
+/// Recursively collects the paths of all `*.json` files under `dir`.
+fn find_json_files(dir: &Path, files: &mut Vec<PathBuf>) -> Result<()> {
+  for entry in fs::read_dir(dir)? {
+    let path = entry?.path();
+    if path.is_dir() {
+      find_json_files(&path, files)?;
+    } else if path.extension() == Some("json".as_ref()) {
+      files.push(path);
+    }
+  }
+  Ok(())
+}
+
+/// Prints the projected size of `data`, the contents of `file`, once
+/// compressed using `schema`, broken down by section.
+fn print_projection(schema: &Schema, data: &Value, file: &Path) -> Result<()> {
+  let estimate = chii::estimate_size(schema, data)?;
+  let total = estimate.total();
+  let sections = [
+    ("markers", estimate.markers),
+    ("lengths", estimate.lengths),
+    ("data", estimate.data),
+    ("total", total),
+  ];
+  println!("{:<8}  {:>12}  {:>7}", "section", "bits", "share");
+  for (name, bits) in &sections {
+    let share = *bits as f64 / total.max(1) as f64 * 100.0;
+    println!("{:<8}  {:>12}  {:>6.1}%", name, bits, share);
+  }
+
+  let stats = CompressStats {
+    original: fs::metadata(file)?.len(),
+    compressed: estimate.bytes() as u64,
+  };
+  println!(
+    "\nprojected {} bytes from {} bytes ({:.1}%)",
+    stats.compressed,
+    stats.original,
+    stats.ratio()
+  );
+  Ok(())
+}
+
+/// Prints a table of the size and compression ratio of each file.
+fn print_summary(results: &[(PathBuf, Result<CompressStats>)]) {
+  let width = results
+    .iter()
+    .map(|(path, _)| path.display().to_string().len())
+    .chain(std::iter::once("file".len()))
+    .max()
+    .unwrap_or_default();
+
+  println!(
+    "{:<w$}  {:>12}  {:>12}  {:>7}",
+    "file",
+    "original",
+    "compressed",
+    "ratio",
+    w = width
+  );
+
+  let (mut original, mut compressed) = (0, 0);
+  for (path, result) in results {
+    match result {
+      Ok(stats) => {
+        println!(
+          "{:<w$}  {:>12}  {:>12}  {:>6.1}%",
+          path.display(),
+          stats.original,
+          stats.compressed,
+          stats.ratio(),
+          w = width
+        );
+        original += stats.original;
+        compressed += stats.compressed;
+      }
+      Err(e) => println!("{:<w$}  error: {:#}", path.display(), e, w = width),
+    }
+  }
+
+  let total = CompressStats {
+    original,
+    compressed,
+  };
+  println!(
+    "{:<w$}  {:>12}  {:>12}  {:>6.1}%",
+    "total",
+    total.original,
+    total.compressed,
+    total.ratio(),
+    w = width
+  );
+}
+
+/// Decodes as much of a damaged compressed file as possible, printing the
+/// problems found to stderr.
+fn salvage_compressed(
+  schema: &Schema,
+  path: &Path,
+  options: DecodeOptions,
+) -> Result<Value> {
+  let bytes = fs::read(path)?;
+  let file = header::parse(&bytes)?;
+  let options = file.header.decode_options(options);
+  let salvaged = chii::decode_lenient_with(schema, file.object, options);
+  for diagnostic in &salvaged.diagnostics {
+    eprintln!("warning: {}", diagnostic);
+  }
+  let mut value = salvaged.value;
+  if let Some(unknown) = file.unknown_fields {
+    unknown.restore(&mut value)?;
+  }
+  Ok(value)
+}
+
+fn decompress(opt: &DecompressOpt) -> Result<()> {
+  let mut schema = load_schema(&opt.schema)?;
+  apply_overrides(&mut schema, &opt.overrides, None)?;
+  let options = DecodeOptions {
+    max_memory: opt.max_memory.unwrap_or(usize::MAX),
+    ..Default::default()
+  };
+  let value = if opt.lenient {
+    salvage_compressed(&schema, &opt.file, options)?
+  } else {
+    load_compressed(&schema, &opt.file, options)?
+  };
+  let mut file = BufWriter::new(File::create(opt.output_file_path())?);
+  if opt.pretty && !opt.compact {
+    serde_json::to_writer_pretty(&mut file, &sort_keys(value))?;
+    writeln!(file)?;
+  } else {
+    serde_json::to_writer(&mut file, &value)?;
+  }
+  file.flush()?;
+
+  Ok(())
+}
+
+/// Sorts the fields of every object in `value` by name.
+///
+/// Object fields are usually sorted already, but not if some other crate
+/// enables `serde_json`'s `preserve_order` feature.
+fn sort_keys(value: Value) -> Value {
+  match value {
+    Value::Object(map) => {
+      let mut fields: Vec<_> = map.into_iter().collect();
+      fields.sort_by(|a, b| a.0.cmp(&b.0));
+      let fields = fields.into_iter().map(|(k, v)| (k, sort_keys(v)));
+      Value::Object(fields.collect())
+    }
+    Value::Array(arr) => Value::Array(arr.into_iter().map(sort_keys).collect()),
+    value => value,
+  }
+}
+
+fn append(opt: &AppendOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let data = load_json(&opt.file)?;
 
-  // Perform compression
   let co = chii::encode(&schema, &data)?;
-  if opt.blocks {
-    for block in &co.blocks {
-      println!("{}", block);
+  let bytes = co.to_bytes();
+
+  let file = OpenOptions::new()
+    .read(true)
+    .write(true)
+    .create(true)
+    .truncate(false)
+    .open(&opt.archive)?;
+  let mut archive = if file.metadata()?.len() == 0 {
+    create_archive(file, opt)?
+  } else {
+    Archive::open(file)?
+  };
+
+  archive.append(&bytes)?;
+  Ok(())
+}
+
+/// Creates an empty archive in `file`, with parity if `opt` asks for it.
+#[cfg_attr(not(feature = "fec"), allow(unused_variables))]
+fn create_archive(file: File, opt: &AppendOpt) -> Result<Archive<File>> {
+  #[cfg(feature = "fec")]
+  if let Some(percent) = opt.parity {
+    return Archive::create_with_parity(file, percent);
+  }
+  Archive::create(file)
+}
+
+fn get(opt: &GetOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let bytes = fs::read(&opt.file)?;
+  let file = header::parse(&bytes)?;
+  let options = file.header.decode_options(DecodeOptions::default());
+
+  let value = chii::decode_path_with(&schema, file.object, &opt.path, options)?
+    .ok_or_else(|| anyhow!("no value found at {}", opt.path))?;
+  println!("{}", value);
+  Ok(())
+}
+
+fn set(opt: &SetOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let value: Value = serde_json::from_str(&opt.value)?;
+  let mut bytes = fs::read(&opt.file)?;
+  let file = header::parse(&bytes)?;
+  let options = file.header.decode_options(DecodeOptions::default());
+  let len = file.object.len();
+  let object = &mut bytes[header::LEN..header::LEN + len];
+
+  let loc =
+    chii::update::update_with(&schema, object, &opt.path, &value, options)?;
+
+  // Only write back the bytes which were actually touched
+  let range = loc.byte_range();
+  let mut file = OpenOptions::new().write(true).open(&opt.file)?;
+  file.seek(SeekFrom::Start((header::LEN + range.start) as u64))?;
+  file.write_all(&object[range])?;
+  Ok(())
+}
+
+fn diff(opt: &DiffOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let old = load_compressed(&schema, &opt.old, DecodeOptions::default())?;
+  let new = load_compressed(&schema, &opt.new, DecodeOptions::default())?;
+
+  let changes = chii::diff::diff(&old, &new);
+  for change in &changes {
+    println!("{}", change);
+  }
+
+  // Like diff(1), exit with a non-zero status if the files differ
+  if !changes.is_empty() {
+    process::exit(1);
+  }
+  Ok(())
+}
+
+fn patch(opt: &PatchOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let old = fs::read(&opt.old)?;
+  let new = fs::read(&opt.new)?;
+  let old = header::parse(&old)?;
+  let (header, body) = Header::split(&new)?;
+  check_same_layout(&old.header, &header)?;
+
+  // The patch starts with the new file's header and carries every footer
+  // following the new object, so applying it recreates the whole file
+  let options = old.header.decode_options(DecodeOptions::default());
+  let patch = Patch::diff_with(&schema, old.object, body, options)?;
+  let mut bytes = header.to_bytes().to_vec();
+  bytes.extend(patch.to_bytes());
+  fs::write(&opt.out_file, bytes)?;
+  Ok(())
+}
+
+fn apply(opt: &ApplyOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let bytes = fs::read(&opt.file)?;
+  let file = header::parse(&bytes)?;
+  let patch = fs::read(&opt.patch)?;
+  let (header, patch) = Header::split(&patch)?;
+  check_same_layout(&file.header, &header)?;
+  let patch = Patch::from_bytes(patch)?;
+
+  let options = file.header.decode_options(DecodeOptions::default());
+  let mut patched = header.to_bytes().to_vec();
+  patched.extend(patch.apply_with(&schema, file.object, options)?);
+  fs::write(opt.out_file.as_ref().unwrap_or(&opt.file), patched)?;
+  Ok(())
+}
+
+/// Patches are made of whole blocks, which can only be reused between objects
+/// laid out the same way.
+fn check_same_layout(old: &Header, new: &Header) -> Result<()> {
+  if old.layout != new.layout || old.bit_order != new.bit_order {
+    return Err(anyhow!("the files are laid out differently"));
+  }
+  Ok(())
+}
+
+/// Runs `f` `iterations` times returning its output along with the duration of
+/// the fastest run.
+#[cfg(feature = "bench")]
+fn time_fastest<F>(iterations: usize, mut f: F) -> Result<(Vec<u8>, Duration)>
+where
+  F: FnMut() -> Result<Vec<u8>>,
+{
+  let mut fastest = None;
+  let mut output = Vec::new();
+  for _ in 0..iterations.max(1) {
+    let start = Instant::now();
+    output = f()?;
+    let elapsed = start.elapsed();
+    fastest = Some(fastest.map_or(elapsed, |d: Duration| d.min(elapsed)));
+  }
+  Ok((output, fastest.unwrap_or_default()))
+}
+
+#[cfg(feature = "bench")]
+fn bench(opt: &BenchOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let raw = fs::read(&opt.file)?;
+  let data: Value = serde_json::from_slice(&raw)?;
+  let n = opt.iterations;
+
+  let encoded =
+    time_fastest(n, || Ok(chii::encode(&schema, &data)?.to_bytes()))?;
+
+  // Make sure chii's output is actually usable before reporting on it
+  if chii::decode(&schema, &encoded.0)? != data {
+    return Err(anyhow!("decoded value differs from the original"));
+  }
+
+  let mut results = vec![("chii", encoded)];
+  results.push((
+    "gzip",
+    time_fastest(n, || {
+      let mut e = GzEncoder::new(Vec::new(), flate2::Compression::default());
+      e.write_all(&raw)?;
+      Ok(e.finish()?)
+    })?,
+  ));
+  results.push((
+    "zstd",
+    time_fastest(n, || Ok(zstd::encode_all(&raw[..], 0)?))?,
+  ));
+  results.push((
+    "brotli",
+    time_fastest(n, || {
+      let mut w = brotli::CompressorWriter::new(Vec::new(), 4096, 11, 22);
+      w.write_all(&raw)?;
+      Ok(w.into_inner())
+    })?,
+  ));
+
+  println!(
+    "{:<8}  {:>12}  {:>7}  {:>12}",
+    "method", "size", "ratio", "time"
+  );
+  println!(
+    "{:<8}  {:>12}  {:>6.1}%  {:>12}",
+    "json",
+    raw.len(),
+    100.0,
+    "-"
+  );
+  for (name, (output, time)) in &results {
+    let ratio = output.len() as f64 / raw.len().max(1) as f64 * 100.0;
+    println!(
+      "{:<8}  {:>12}  {:>6.1}%  {:>10.3}ms",
+      name,
+      output.len(),
+      ratio,
+      time.as_secs_f64() * 1000.0
+    );
+  }
+  Ok(())
+}
+
+fn inspect(opt: &InspectOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let bytes = fs::read(&opt.file)?;
+  let file = header::parse(&bytes)?;
+  let options = file.header.decode_options(DecodeOptions::default());
+  let body = file.object;
+
+  let inspection = chii::inspect::inspect_with(&schema, body, options);
+  println!(
+    "{:>8}  {:>6}  {:<10}  {:<24}  {:<w$}  value",
+    "offset",
+    "width",
+    "block",
+    "path",
+    "bits",
+    w = opt.max_bits
+  );
+  for block in &inspection.blocks {
+    // Blocks were found within `body` so their bits are always there
+    let bits = block.bits_with(body, options.bit_order).unwrap_or_default();
+    let mut raw: String =
+      bits.iter().map(|b| if b { '1' } else { '0' }).collect();
+    if raw.len() > opt.max_bits {
+      raw.truncate(opt.max_bits.saturating_sub(3));
+      raw.push_str("...");
+    }
+
+    let value = block.value.as_ref().map(Value::to_string);
+    println!(
+      "{:>8}  {:>6}  {:<10}  {:<24}  {:<w$}  {}",
+      block.span.start,
+      block.span.len(),
+      block.kind.to_string(),
+      block.path.to_string(),
+      raw,
+      value.unwrap_or_default(),
+      w = opt.max_bits
+    );
+  }
+
+  if let Some(e) = inspection.error {
+    return Err(e);
+  }
+
+  let end = inspection.blocks.last().map_or(0, |b| b.span.end);
+  let padding = body.len() * 8 - end;
+  if padding >= 8 {
+    println!("{} unused bits after the last block", padding);
+  }
+  Ok(())
+}
+
+/// Prints each section of every block of a compressed file, such as a field
+/// marker or the data of a value, next to the bytes it lies in.
+fn explain(opt: &ExplainOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let bytes = fs::read(&opt.file)?;
+  let file = header::parse(&bytes)?;
+  let options = file.header.decode_options(DecodeOptions::default());
+  let body = file.object;
+
+  let inspection = chii::inspect::inspect_with(&schema, body, options);
+  println!(
+    "{:>8}  {:<13}  {:<w$}  meaning",
+    "offset",
+    "bytes",
+    "bits",
+    w = opt.max_bits
+  );
+  let line = |span: Range<usize>, meaning: String| {
+    let mut r = BitReader::new(body).with_bit_order(options.bit_order);
+    r.seek(span.start);
+    let bits = r.read_bits(span.len()).unwrap_or_default();
+    let mut raw: String =
+      bits.iter().map(|b| if b { '1' } else { '0' }).collect();
+    if raw.len() > opt.max_bits {
+      raw.truncate(opt.max_bits.saturating_sub(3));
+      raw.push_str("...");
+    }
+
+    // The bytes which the section's bits lie in
+    let touched = &body[span.start / 8..chii::math::div_ceil(span.end, 8)];
+    let mut hex: Vec<_> = touched
+      .iter()
+      .take(4)
+      .map(|b| format!("{:02x}", b))
+      .collect();
+    if touched.len() > 4 {
+      hex.push("..".to_string());
+    }
+    println!(
+      "{:>8}  {:<13}  {:<w$}  {}",
+      span.start,
+      hex.join(" "),
+      raw,
+      meaning,
+      w = opt.max_bits
+    );
+  };
+
+  for block in &inspection.blocks {
+    let path = block.path.to_string();
+    for section in &block.sections {
+      let meaning = match (section.kind, block.kind) {
+        (SectionKind::Marker, BlockKind::Terminator) => {
+          format!("end of {}", path)
+        }
+        (SectionKind::Marker, _) => {
+          format!("marker {} of {}", block.marker.unwrap_or_default(), path)
+        }
+        (SectionKind::Flag, BlockKind::Layout { sparse }) => format!(
+          "layout of {}: {}",
+          path,
+          if sparse { "deltas" } else { "markers" }
+        ),
+        (SectionKind::Flag, BlockKind::Sync { marker }) => {
+          format!("sync flag: {}", if marker { "marker" } else { "none" })
+        }
+        (SectionKind::Magic, _) => "sync magic".to_string(),
+        (SectionKind::Tag, BlockKind::Tag { tag }) => {
+          format!("tag of {}: type {}", path, tag)
+        }
+        (SectionKind::Length, BlockKind::ListHeader { len }) => {
+          format!("length of {}: {} elements", path, len)
+        }
+        (SectionKind::Length, BlockKind::ChunkHeader { bits }) => {
+          format!("chunk of {} bits", bits)
+        }
+        (SectionKind::Length, BlockKind::Sync { .. }) => {
+          "index of the next element".to_string()
+        }
+        (SectionKind::Length, _) => {
+          let data = block.sections.last().map_or(0, |s| s.span.len());
+          format!("length of {}: {} bits", path, data)
+        }
+        (SectionKind::Data, _) => {
+          let value = block.value.as_ref().map(Value::to_string);
+          format!("{} = {}", path, value.unwrap_or_default())
+        }
+        (kind, _) => format!("{} of {}", kind.name(), path),
+      };
+      line(section.span.clone(), meaning);
     }
   }
 
-  let bits: BitVec = co.into();
-  let bytes = bits.to_bytes();
+  if let Some(e) = inspection.error {
+    return Err(e);
+  }
 
-  // Write to output file
-  let mut file = File::create(opt.output_file_path())?;
-  file.write_all(&bytes)?;
+  let end = inspection.blocks.last().map_or(0, |b| b.span.end);
+  if end < body.len() * 8 {
+    line(end..body.len() * 8, "padding".to_string());
+  }
+  Ok(())
+}
 
+/// Prints how often each enum variant occurs in a file next to the
+/// probability the schema declares for it.
+fn stats(opt: &StatsOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let data = load_json(&opt.file)?;
+  let percent = |p: f64| format!("{:.1}%", p * 100.0);
+
+  for (i, stats) in chii::stats::enum_stats(&schema, &data).iter().enumerate() {
+    if i > 0 {
+      println!();
+    }
+    match stats.divergence() {
+      Some(d) => println!(
+        "{}: {} values, divergence {:.4} bits",
+        stats.path,
+        stats.total(),
+        d
+      ),
+      None => println!("{}: {} values", stats.path, stats.total()),
+    }
+    println!("  {:<24}  {:>8}  {:>8}", "variant", "declared", "observed");
+    for (i, v) in stats.variants.iter().enumerate() {
+      println!(
+        "  {:<24}  {:>8}  {:>8}",
+        v.variant,
+        v.declared.map(percent).unwrap_or_else(|| "-".to_string()),
+        percent(stats.frequency(i))
+      );
+    }
+  }
   Ok(())
 }
 
-fn main() -> Result<()> {
-  let opt = Opt::from_args();
-  if opt.decompress {
-    Err(anyhow!("decompression is not supported yet"))
+/// Compresses a file with each candidate schema, then prints the size and
+/// ratio of each followed by the bits of every field, with the difference
+/// from the first schema for the others.
+fn compare(opt: &CompareOpt) -> Result<()> {
+  let data = load_json(&opt.file)?;
+  let original = fs::metadata(&opt.file)?.len();
+  let mut candidates = Vec::new();
+  for path in &opt.schemas {
+    let schema = load_schema(path)?;
+    let co = chii::encode(&schema, &data)
+      .with_context(|| format!("failed to compress with {}", path.display()))?;
+    let stats = CompressStats {
+      original,
+      compressed: co.to_bytes().len() as u64,
+    };
+    let sizes = co.field_sizes(&schema)?;
+    candidates.push((path.display().to_string(), stats, sizes));
+  }
+
+  let width = candidates
+    .iter()
+    .map(|(name, _, _)| name.len())
+    .chain(std::iter::once("schema".len()))
+    .max()
+    .unwrap_or_default();
+  println!(
+    "{:>2}  {:<w$}  {:>12}  {:>7}",
+    "#",
+    "schema",
+    "bytes",
+    "ratio",
+    w = width
+  );
+  for (i, (name, stats, _)) in candidates.iter().enumerate() {
+    println!(
+      "{:>2}  {:<w$}  {:>12}  {:>6.1}%",
+      i + 1,
+      name,
+      stats.compressed,
+      stats.ratio(),
+      w = width
+    );
+  }
+
+  // Every field found with any of the schemas, in the order first found
+  let mut paths: Vec<&chii::path::Path> = Vec::new();
+  for (_, _, sizes) in &candidates {
+    for (path, _) in sizes {
+      if !paths.contains(&path) {
+        paths.push(path);
+      }
+    }
+  }
+  let field_width = paths
+    .iter()
+    .map(|p| p.to_string().len())
+    .chain(std::iter::once("field".len()))
+    .max()
+    .unwrap_or_default();
+  println!();
+  print!("{:<w$}", "field", w = field_width);
+  for i in 1..=candidates.len() {
+    print!("  {:>16}", format!("#{} bits", i));
+  }
+  println!();
+
+  let bits_at = |sizes: &[(chii::path::Path, usize)], path| {
+    sizes.iter().find(|(p, _)| p == path).map(|(_, bits)| *bits)
+  };
+  let (_, _, baseline) = &candidates[0];
+  for path in paths {
+    print!("{:<w$}", path.to_string(), w = field_width);
+    let base = bits_at(baseline, path);
+    for (i, (_, _, sizes)) in candidates.iter().enumerate() {
+      let cell = match (bits_at(sizes, path), base) {
+        (None, _) => "-".to_string(),
+        (Some(bits), Some(base)) if i > 0 => {
+          format!("{} ({:+})", bits, bits as i64 - base as i64)
+        }
+        (Some(bits), _) => bits.to_string(),
+      };
+      print!("  {:>16}", cell);
+    }
+    println!();
+  }
+  Ok(())
+}
+
+/// Exports a compressed list of records, with one column per record field
+/// typed according to the schema.
+#[cfg(feature = "parquet")]
+fn export(opt: &ExportOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let value = load_compressed(&schema, &opt.file, DecodeOptions::default())?;
+  let batch = chii::arrow::to_record_batch(&schema, &value)?;
+
+  match opt.format {
+    ExportFormat::Parquet => {
+      let file = File::create(opt.output_file_path())?;
+      let mut writer = ArrowWriter::try_new(file, batch.schema(), None)?;
+      writer.write(&batch)?;
+      writer.close()?;
+    }
+  }
+  Ok(())
+}
+
+fn reorder(opt: &ReorderOpt) -> Result<()> {
+  let schema = load_schema(&opt.schema)?;
+  let corpus = opt
+    .files
+    .iter()
+    .map(|path| load_json(path))
+    .collect::<Result<Vec<_>>>()?;
+  let reordering = chii::reorder::reorder(&schema, &corpus)?;
+  let percent = |n: usize, total: usize| match total {
+    0 => "-".to_string(),
+    total => format!("{:.1}%", n as f64 / total as f64 * 100.0),
+  };
+
+  let changed = reordering.records.iter().filter(|r| !r.is_unchanged());
+  for (i, record) in changed.enumerate() {
+    if i > 0 {
+      println!();
+    }
+    println!("{}: {} values", record.path, record.values);
+    println!(
+      "  {:<24}  {:>8}  {:>4}  {:>4}",
+      "field", "present", "old", "new"
+    );
+    for f in &record.fields {
+      println!(
+        "  {:<24}  {:>8}  {:>4}  {:>4}",
+        f.name,
+        percent(f.present, record.values),
+        f.old,
+        f.new
+      );
+    }
+  }
+
+  // Encoding the corpus with both schemas shows what the new identifiers
+  // actually save
+  let (mut before, mut after) = (0, 0);
+  for value in &corpus {
+    before += chii::encode(&schema, value)?.to_bytes().len();
+    after += chii::encode(&reordering.schema, value)?.to_bytes().len();
+  }
+  if before == after {
+    println!("reordering saves nothing: {} bytes", before);
   } else {
-    compress(&opt)
+    println!("{} bytes -> {} bytes", before, after);
+  }
+
+  if let Some(out_file) = &opt.out_file {
+    let format = opt.to.unwrap_or_else(|| SchemaFormat::of(out_file));
+    fs::write(out_file, format.write(&reordering.schema)?)?;
+  }
+  if let Some(mapping) = &opt.mapping {
+    let json = serde_json::to_string_pretty(&reordering.mapping())?;
+    fs::write(mapping, json + "\n")?;
+  }
+  Ok(())
+}
+
+fn convert_schema(opt: &ConvertOpt) -> Result<()> {
+  let schema = load_schema(&opt.input)?;
+  let format = opt.to.unwrap_or_else(|| SchemaFormat::of(&opt.output));
+  fs::write(&opt.output, format.write(&schema)?)?;
+  Ok(())
+}
+
+fn bundle_schema(opt: &BundleOpt) -> Result<()> {
+  let current = load_schema(&opt.current)?;
+  let older = opt
+    .older
+    .iter()
+    .map(|path| load_schema(path))
+    .collect::<Result<Vec<_>>>()?;
+  let bundle = current.with_history(older);
+  let format = opt.to.unwrap_or_else(|| SchemaFormat::of(&opt.out_file));
+  fs::write(&opt.out_file, format.write(&bundle)?)?;
+  Ok(())
+}
+
+fn doc_schema(opt: &DocOpt) -> Result<()> {
+  let markdown = load_schema(&opt.schema)?.to_markdown();
+  match &opt.out_file {
+    Some(path) => fs::write(path, markdown)?,
+    None => print!("{}", markdown),
+  }
+  Ok(())
+}
+
+fn main() -> Result<()> {
+  match Opt::from_args() {
+    Opt::Compress(opt) => compress(&opt),
+    Opt::Decompress(opt) => decompress(&opt),
+    Opt::Append(opt) => append(&opt),
+    Opt::Get(opt) => get(&opt),
+    Opt::Set(opt) => set(&opt),
+    Opt::Diff(opt) => diff(&opt),
+    Opt::Patch(opt) => patch(&opt),
+    Opt::Apply(opt) => apply(&opt),
+    #[cfg(feature = "bench")]
+    Opt::Bench(opt) => bench(&opt),
+    Opt::Inspect(opt) => inspect(&opt),
+    Opt::Explain(opt) => explain(&opt),
+    Opt::Stats(opt) => stats(&opt),
+    Opt::Compare(opt) => compare(&opt),
+    Opt::Reorder(opt) => reorder(&opt),
+    #[cfg(feature = "parquet")]
+    Opt::Export(opt) => export(&opt),
+    Opt::Schema(SchemaOpt::Convert(opt)) => convert_schema(&opt),
+    Opt::Schema(SchemaOpt::Bundle(opt)) => bundle_schema(&opt),
+    Opt::Schema(SchemaOpt::Doc(opt)) => doc_schema(&opt),
   }
 }
@@ -1,7 +1,12 @@
 //! Utility functions for dealing with bit vectors.
 
+use crate::data::{BitOrder, LengthEncoding, Profile};
 use crate::int::BigEndian;
+use crate::math;
+use crate::prelude::*;
 pub use bit_vec::BitVec;
+#[cfg(feature = "std")]
+use std::io::{self, Write};
 
 /// Extensions to `BitVec`.
 pub trait BitVecExt {
@@ -60,6 +65,560 @@ impl BitVecExt for BitVec {
   }
 }
 
+/// Overwrites the bits of `bytes` starting at bit position `pos` with `bits`.
+///
+/// Bits are addressed in the same order as they are laid out by [`BitVec`].
+/// Returns `None`, leaving `bytes` untouched, if `bits` doesn't fit.
+pub fn overwrite(bytes: &mut [u8], pos: usize, bits: &BitVec) -> Option<()> {
+  if pos + bits.len() > bytes.len() * 8 {
+    return None;
+  }
+
+  for (i, bit) in bits.iter().enumerate() {
+    let p = pos + i;
+    let mask = 1 << (7 - p % 8);
+    if bit {
+      bytes[p / 8] |= mask;
+    } else {
+      bytes[p / 8] &= !mask;
+    }
+  }
+  Some(())
+}
+
+/// The CRC-8 (polynomial 0x07) of `bits`, computed one bit at a time so that
+/// it doesn't depend on how the bits are packed into bytes.
+pub fn crc8(bits: &BitVec) -> u8 {
+  bits.iter().fold(0, |crc, bit| {
+    if (crc & 0x80 != 0) != bit {
+      (crc << 1) ^ 0x07
+    } else {
+      crc << 1
+    }
+  })
+}
+
+/// A growable sequence of bits backed by 64-bit words.
+///
+/// Bits are laid out in the same order as [`BitVec`] but are appended up to a
+/// whole word at a time instead of one by one, making it much faster to build
+/// up large sequences of bits.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct BitBuf {
+  words: Vec<u64>,
+  len: usize,
+}
+
+impl BitBuf {
+  /// Constructs an empty buffer.
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Constructs an empty buffer with space for at least `bits` bits.
+  pub fn with_capacity(bits: usize) -> Self {
+    BitBuf {
+      words: Vec::with_capacity(math::div_ceil(bits, 64)),
+      len: 0,
+    }
+  }
+
+  /// The number of bits in this buffer.
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Returns `true` if this buffer holds no bits.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// Removes all bits, keeping the allocated space for reuse.
+  pub fn clear(&mut self) {
+    self.words.clear();
+    self.len = 0;
+  }
+
+  /// Appends the lowest `n` bits of `value`, most significant bit first.
+  pub fn push_bits(&mut self, value: u64, n: usize) {
+    debug_assert!(n <= 64);
+    if n == 0 {
+      return;
+    }
+
+    let value = if n < 64 {
+      value & ((1 << n) - 1)
+    } else {
+      value
+    };
+    let offset = self.len % 64;
+    if offset == 0 {
+      self.words.push(0);
+    }
+
+    // This can't fail as there is always a partially filled word by now
+    let free = 64 - offset;
+    let last = self.words.last_mut().unwrap();
+    if n <= free {
+      *last |= value << (free - n);
+    } else {
+      *last |= value >> (n - free);
+      self.words.push(value << (64 - (n - free)));
+    }
+    self.len += n;
+  }
+
+  /// Appends a single bit.
+  #[inline]
+  pub fn push_bit(&mut self, bit: bool) {
+    self.push_bits(bit as u64, 1);
+  }
+
+  /// Appends `n` zero bits.
+  pub fn push_zeros(&mut self, mut n: usize) {
+    while n > 0 {
+      let chunk = n.min(64);
+      self.push_bits(0, chunk);
+      n -= chunk;
+    }
+  }
+
+  /// Appends all bits of `bytes`.
+  pub fn push_bytes(&mut self, bytes: &[u8]) {
+    let mut chunks = bytes.chunks_exact(8);
+    for chunk in &mut chunks {
+      let mut word = [0u8; 8];
+      word.copy_from_slice(chunk);
+      self.push_bits(u64::from_be_bytes(word), 64);
+    }
+    for b in chunks.remainder() {
+      self.push_bits(*b as u64, 8);
+    }
+  }
+
+  /// Appends all bits of `bits`.
+  pub fn push_bit_vec(&mut self, bits: &BitVec) {
+    let bytes = bits.to_bytes();
+    let whole = bits.len() / 8;
+    self.push_bytes(&bytes[..whole]);
+    if let Some(last) = bytes.get(whole) {
+      let n = bits.len() % 8;
+      self.push_bits((*last >> (8 - n)) as u64, n);
+    }
+  }
+
+  /// Appends all bits of `other`.
+  pub fn push_bit_buf(&mut self, other: &BitBuf) {
+    let whole = other.len / 64;
+    for word in &other.words[..whole] {
+      self.push_bits(*word, 64);
+    }
+    let n = other.len % 64;
+    if n > 0 {
+      self.push_bits(other.words[whole] >> (64 - n), n);
+    }
+  }
+
+  /// The bytes of this buffer with the last byte padded with zeros.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(self.words.len() * 8);
+    self.append_to(&mut bytes);
+    bytes
+  }
+
+  /// Appends the bytes of this buffer, with the last byte padded with zeros,
+  /// to `bytes`.
+  pub fn append_to(&self, bytes: &mut Vec<u8>) {
+    let end = bytes.len() + math::div_ceil(self.len, 8);
+    for word in &self.words {
+      bytes.extend_from_slice(&word.to_be_bytes());
+    }
+    bytes.truncate(end);
+  }
+}
+
+impl From<BitBuf> for BitVec {
+  fn from(buf: BitBuf) -> Self {
+    let mut bits = BitVec::from_bytes(&buf.to_bytes());
+    bits.truncate(buf.len);
+    bits
+  }
+}
+
+/// Reads individual bits and bit sequences from a slice of bytes.
+///
+/// By default, bits are read in the same order as they are laid out by
+/// [`BitVec`], that is, starting from the most significant bit of each byte.
+#[derive(Clone, Debug)]
+pub struct BitReader<'a> {
+  bytes: &'a [u8],
+  pos: usize,
+  /// Whether sections of the input are padded to byte boundaries.
+  byte_aligned: bool,
+  /// How lengths in the input are encoded.
+  lengths: LengthEncoding,
+  /// Which encoding profile produced the input.
+  profile: Profile,
+  /// The number of elements in each chunk of a list, if lists in the input
+  /// are chunked.
+  chunks: Option<usize>,
+  /// Whether the elements of a root list in the input are preceded by sync
+  /// blocks.
+  sync: bool,
+  /// Whether variable-width data in the input is followed by a checksum.
+  checksums: bool,
+  /// How the bits of the input are packed into bytes.
+  bit_order: BitOrder,
+}
+
+impl<'a> BitReader<'a> {
+  /// Constructs a reader positioned at the first bit of `bytes`.
+  pub fn new(bytes: &'a [u8]) -> Self {
+    BitReader {
+      bytes,
+      pos: 0,
+      byte_aligned: false,
+      lengths: LengthEncoding::Vie,
+      profile: Profile::Standard,
+      chunks: None,
+      sync: false,
+      checksums: false,
+      bit_order: BitOrder::MsbFirst,
+    }
+  }
+
+  /// Constructs a reader positioned at the first bit of `bytes` whose
+  /// sections are padded to byte boundaries.
+  pub fn byte_aligned(bytes: &'a [u8]) -> Self {
+    BitReader {
+      bytes,
+      pos: 0,
+      byte_aligned: true,
+      lengths: LengthEncoding::Vie,
+      profile: Profile::Standard,
+      chunks: None,
+      sync: false,
+      checksums: false,
+      bit_order: BitOrder::MsbFirst,
+    }
+  }
+
+  /// Sets how lengths in the input are encoded.
+  pub fn with_lengths(mut self, lengths: LengthEncoding) -> Self {
+    self.lengths = lengths;
+    self
+  }
+
+  /// How lengths in the input are encoded.
+  #[inline]
+  pub fn lengths(&self) -> LengthEncoding {
+    self.lengths
+  }
+
+  /// Sets which encoding profile produced the input.
+  pub fn with_profile(mut self, profile: Profile) -> Self {
+    self.profile = profile;
+    self
+  }
+
+  /// Which encoding profile produced the input.
+  #[inline]
+  pub fn profile(&self) -> Profile {
+    self.profile
+  }
+
+  /// Sets the number of elements in each chunk of a list in the input.
+  pub fn with_chunks(mut self, chunks: Option<usize>) -> Self {
+    self.chunks = chunks;
+    self
+  }
+
+  /// The number of elements in each chunk of a list, if lists in the input
+  /// are chunked.
+  #[inline]
+  pub fn chunks(&self) -> Option<usize> {
+    self.chunks
+  }
+
+  /// Sets whether the elements of a root list in the input are preceded by
+  /// sync blocks.
+  pub fn with_sync(mut self, sync: bool) -> Self {
+    self.sync = sync;
+    self
+  }
+
+  /// Whether the elements of a root list in the input are preceded by sync
+  /// blocks.
+  #[inline]
+  pub fn sync(&self) -> bool {
+    self.sync
+  }
+
+  /// Sets whether variable-width data in the input is followed by a
+  /// checksum.
+  pub fn with_checksums(mut self, checksums: bool) -> Self {
+    self.checksums = checksums;
+    self
+  }
+
+  /// Whether variable-width data in the input is followed by a checksum.
+  #[inline]
+  pub fn checksums(&self) -> bool {
+    self.checksums
+  }
+
+  /// Sets how the bits of the input are packed into bytes.
+  pub fn with_bit_order(mut self, bit_order: BitOrder) -> Self {
+    self.bit_order = bit_order;
+    self
+  }
+
+  /// How the bits of the input are packed into bytes.
+  #[inline]
+  pub fn bit_order(&self) -> BitOrder {
+    self.bit_order
+  }
+
+  /// Skips the padding at the end of a section, moving this reader to the
+  /// next byte boundary. Does nothing unless the reader is
+  /// [byte-aligned](BitReader::byte_aligned).
+  #[inline]
+  pub fn align(&mut self) {
+    if self.byte_aligned {
+      self.pos = crate::math::div_ceil(self.pos, 8) * 8;
+    }
+  }
+
+  /// The current bit position of this reader.
+  #[inline]
+  pub fn position(&self) -> usize {
+    self.pos
+  }
+
+  /// The total number of bits in the underlying byte slice.
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.bytes.len() * 8
+  }
+
+  /// Returns `true` if the underlying byte slice is empty.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.bytes.is_empty()
+  }
+
+  /// The number of bits left to read.
+  #[inline]
+  pub fn remaining(&self) -> usize {
+    self.len() - self.pos
+  }
+
+  /// Moves this reader to an absolute bit position.
+  ///
+  /// Returns `None` if `pos` is past the end of the input.
+  pub fn seek(&mut self, pos: usize) -> Option<()> {
+    if pos > self.len() {
+      return None;
+    }
+    self.pos = pos;
+    Some(())
+  }
+
+  /// Advances this reader by `n` bits without reading them.
+  pub fn skip(&mut self, n: usize) -> Option<()> {
+    if n > self.remaining() {
+      return None;
+    }
+    self.pos += n;
+    Some(())
+  }
+
+  /// Reads a single bit.
+  pub fn read_bit(&mut self) -> Option<bool> {
+    let byte = self.bytes.get(self.pos / 8)?;
+    let shift = match self.bit_order {
+      BitOrder::MsbFirst => 7 - self.pos % 8,
+      BitOrder::LsbFirst => self.pos % 8,
+    };
+    let bit = (byte >> shift) & 1 == 1;
+    self.pos += 1;
+    Some(bit)
+  }
+
+  /// Reads `n` bits into a new `BitVec`.
+  pub fn read_bits(&mut self, n: usize) -> Option<BitVec> {
+    if n > self.remaining() {
+      return None;
+    }
+    let mut bits = BitVec::with_capacity(n);
+    for _ in 0..n {
+      bits.push(self.read_bit()?);
+    }
+    Some(bits)
+  }
+
+  /// Reads a single byte, most significant bit first.
+  pub fn read_byte(&mut self) -> Option<u8> {
+    if self.remaining() < 8 {
+      return None;
+    }
+    let mut byte = 0u8;
+    for _ in 0..8 {
+      byte = (byte << 1) | self.read_bit()? as u8;
+    }
+    Some(byte)
+  }
+
+  /// Reads an `n` bit unsigned integer stored most significant bit first.
+  /// This is the inverse of [`BitBuf::push_bits`].
+  pub fn read_be(&mut self, n: usize) -> Option<u64> {
+    debug_assert!(n <= 64);
+    if n > self.remaining() {
+      return None;
+    }
+    let mut x = 0u64;
+    for _ in 0..n {
+      x = (x << 1) | self.read_bit()? as u64;
+    }
+    Some(x)
+  }
+
+  /// Reads an `n` bit unsigned integer stored least significant bit first.
+  /// This is the inverse of [`BitVecExt::from_rev_be`] truncated to `n` bits.
+  pub fn read_rev_be(&mut self, n: usize) -> Option<u64> {
+    debug_assert!(n <= 64);
+    if n > self.remaining() {
+      return None;
+    }
+    let mut x = 0u64;
+    for i in 0..n {
+      x |= (self.read_bit()? as u64) << i;
+    }
+    Some(x)
+  }
+}
+
+/// Writes individual bits and bit sequences to an underlying [`Write`].
+///
+/// Bits are packed in the same order as they are laid out by [`BitVec`],
+/// unless [another order](BitWriter::with_bit_order) is chosen, and whole
+/// bytes are handed to the underlying writer as soon as they are
+/// complete, so only a single partial byte is ever held in memory. Wrapping
+/// the writer in a `BufWriter` is recommended when writing to a file.
+///
+/// [`finish`] must be called once all bits have been written to flush the last
+/// partial byte, which is padded with zeros.
+///
+/// [`finish`]: BitWriter::finish
+#[cfg(feature = "std")]
+#[derive(Debug)]
+pub struct BitWriter<W: Write> {
+  inner: W,
+  byte: u8,
+  pos: usize,
+  /// How bits are packed into the bytes handed to `inner`.
+  bit_order: BitOrder,
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> BitWriter<W> {
+  /// Constructs a writer which writes bits to `inner`.
+  pub fn new(inner: W) -> Self {
+    BitWriter {
+      inner,
+      byte: 0,
+      pos: 0,
+      bit_order: BitOrder::MsbFirst,
+    }
+  }
+
+  /// Sets how bits are packed into the bytes handed to the underlying
+  /// writer.
+  pub fn with_bit_order(mut self, bit_order: BitOrder) -> Self {
+    self.bit_order = bit_order;
+    self
+  }
+
+  /// The number of bits written so far.
+  #[inline]
+  pub fn position(&self) -> usize {
+    self.pos
+  }
+
+  /// Writes a single bit.
+  pub fn write_bit(&mut self, bit: bool) -> io::Result<()> {
+    self.byte |= (bit as u8) << (7 - self.pos % 8);
+    self.pos += 1;
+    if self.pos % 8 == 0 {
+      self.emit(&[self.byte])?;
+      self.byte = 0;
+    }
+    Ok(())
+  }
+
+  /// Writes all bits in `bits`.
+  pub fn write_bits(&mut self, bits: &BitVec) -> io::Result<()> {
+    if self.pos % 8 == 0 && bits.len() % 8 == 0 {
+      // Fast path for byte aligned writes
+      self.pos += bits.len();
+      return self.emit(&bits.to_bytes());
+    }
+    for bit in bits {
+      self.write_bit(bit)?;
+    }
+    Ok(())
+  }
+
+  /// Writes all bits in `buf`.
+  pub fn write_buf(&mut self, buf: &BitBuf) -> io::Result<()> {
+    let bytes = buf.to_bytes();
+    let (whole, rest) = bytes.split_at(buf.len() / 8);
+
+    let shift = self.pos % 8;
+    if shift == 0 {
+      self.emit(whole)?;
+    } else {
+      let mut out = Vec::with_capacity(whole.len());
+      for b in whole {
+        out.push(self.byte | b >> shift);
+        self.byte = b << (8 - shift);
+      }
+      self.emit(&out)?;
+    }
+    self.pos += whole.len() * 8;
+
+    for i in 0..buf.len() % 8 {
+      self.write_bit(rest[0] >> (7 - i) & 1 == 1)?;
+    }
+    Ok(())
+  }
+
+  /// Writes any remaining partial byte, padded with zeros, and returns the
+  /// underlying writer.
+  pub fn finish(mut self) -> io::Result<W> {
+    if self.pos % 8 != 0 {
+      self.emit(&[self.byte])?;
+    }
+    self.inner.flush()?;
+    Ok(self.inner)
+  }
+
+  /// Hands whole bytes, packed most significant bit first, to the underlying
+  /// writer in this writer's bit order.
+  fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+    match self.bit_order {
+      BitOrder::MsbFirst => self.inner.write_all(bytes),
+      BitOrder::LsbFirst => {
+        let mut bytes = bytes.to_vec();
+        self.bit_order.repack(&mut bytes);
+        self.inner.write_all(&bytes)
+      }
+    }
+  }
+}
+
 #[cfg(test)]
 mod test {
   use super::*;
@@ -87,7 +646,102 @@ mod test {
     assert_eq!(b.to_bytes(), &[0b1100_0000]);
   }
 
+  #[test]
+  fn bit_reader_reads_msb_first() {
+    let mut r = BitReader::new(&[0b1010_0000, 0xff]);
+    assert_eq!(Some(true), r.read_bit());
+    assert_eq!(Some(false), r.read_bit());
+    assert_eq!(Some(0b1000_0011), r.read_byte());
+    assert_eq!(6, r.remaining());
+  }
+
+  #[test]
+  fn bit_reader_read_past_end() {
+    let mut r = BitReader::new(&[0xff]);
+    assert_eq!(None, r.read_bits(9));
+    assert_eq!(0, r.position());
+    assert_eq!(None, r.skip(9));
+  }
+
+  #[test]
+  fn overwrite_across_byte_boundary() {
+    let mut bytes = [0xffu8, 0x00];
+    let bits = BitVec::from_bytes(&[0b0101_0000]);
+    overwrite(&mut bytes, 6, &bits).unwrap();
+    assert_eq!([0b1111_1101, 0b0100_0000], bytes);
+    assert_eq!(None, overwrite(&mut bytes, 9, &bits));
+  }
+
+  #[test]
+  fn bit_writer_matches_bit_vec_layout() {
+    let mut expected = BitVec::new();
+    let mut w = BitWriter::new(Vec::new());
+    for chunk in &[3usize, 8, 1, 16, 5] {
+      let bits = BitVec::from_fn(*chunk, |i| i % 3 == 0);
+      w.write_bits(&bits).unwrap();
+      expected.append(&mut bits.clone());
+    }
+    assert_eq!(33, w.position());
+
+    let mut buf = BitBuf::new();
+    buf.push_bytes(&[0xa5, 0x5a]);
+    buf.push_bits(0b11, 2);
+    w.write_buf(&buf).unwrap();
+    expected.append(&mut BitVec::from(buf));
+
+    assert_eq!(51, w.position());
+    assert_eq!(expected.to_bytes(), w.finish().unwrap());
+  }
+
+  #[test]
+  fn bit_buf_matches_bit_vec_layout() {
+    let mut expected = BitVec::new();
+    let mut buf = BitBuf::new();
+    for chunk in &[3usize, 8, 1, 64, 16, 5, 70] {
+      let bits = BitVec::from_fn(*chunk, |i| i % 3 == 0);
+      buf.push_bit_vec(&bits);
+      expected.append(&mut bits.clone());
+    }
+    buf.push_bits(0b101, 3);
+    buf.push_zeros(2);
+    expected.append(&mut BitVec::from_fn(5, |i| i == 0 || i == 2));
+
+    let mut tail = BitBuf::new();
+    tail.push_bit_vec(&BitVec::from_fn(67, |i| i % 5 == 0));
+    buf.push_bit_buf(&tail);
+    expected.append(&mut BitVec::from(tail));
+
+    assert_eq!(expected.len(), buf.len());
+    assert_eq!(expected.to_bytes(), buf.to_bytes());
+    assert_eq!(expected, BitVec::from(buf));
+  }
+
   proptest! {
+    #[test]
+    fn prop_bit_buf_push_bits(x: u64, y: u64, n: u8) {
+      let n = n as usize % 65;
+      let mut buf = BitBuf::new();
+      buf.push_bits(x, n);
+      buf.push_bits(y, 64);
+
+      let bytes = buf.to_bytes();
+      let mut r = BitReader::new(&bytes);
+      for i in (0..n).rev() {
+        assert_eq!(Some(x >> i & 1 == 1), r.read_bit());
+      }
+      for i in (0..64).rev() {
+        assert_eq!(Some(y >> i & 1 == 1), r.read_bit());
+      }
+    }
+
+    #[test]
+    fn prop_read_rev_be_inverse_of_from_rev_be(x: u16) {
+      let b = BitVec::from_rev_be(x);
+      let bytes = b.to_bytes();
+      let mut r = BitReader::new(&bytes);
+      assert_eq!(Some(x as u64), r.read_rev_be(16));
+    }
+
     #[test]
     fn prop_to_rev_be_inverse_of_from_rev_be(x: u16) {
       let b = BitVec::from_rev_be(x);
@@ -3,53 +3,117 @@
 
 use crate::bit::BitVecExt;
 use crate::math;
+use crate::prelude::*;
+use alloc::collections::BTreeMap;
 use anyhow::{anyhow, bail, Error, Result};
 use bit_vec::BitVec;
-use std::convert::TryFrom;
+use core::convert::TryFrom;
 
+mod any;
 mod boolean;
+#[cfg(feature = "experimental-bwt")]
+mod bwt;
+mod color;
+mod dictionary;
 mod enumeration;
+mod fallback;
+mod geo;
+mod half;
+mod huffman;
 mod identity;
+mod lzw;
+mod quantized;
+mod scaled;
+mod smaz;
+mod timestamp;
+mod transform;
 
+pub use any::AnyCompressor;
 pub use boolean::BooleanCompressor;
-pub use enumeration::EnumCompressor;
+#[cfg(feature = "experimental-bwt")]
+pub use bwt::BwtCompressor;
+pub use color::ColorCompressor;
+pub use dictionary::DictionaryCompressor;
+pub use enumeration::{
+  EnumCompressor, IntEnumCompressor, WeightedEnumCompressor,
+};
+pub use fallback::RawFallback;
+pub use geo::{GeoCompressor, GeoForm, MAX_GEO_PRECISION};
+pub use half::HalfCompressor;
+pub use huffman::{Codebook, HuffmanCompressor, MAX_CODE_LENGTH};
 pub use identity::IdentityCompressor;
+pub use lzw::LzwCompressor;
+pub use quantized::{QuantizedCompressor, MAX_PRECISION};
+pub use scaled::{Rounding, ScaledCompressor};
+pub use smaz::SmazCompressor;
+pub use timestamp::TimestampCompressor;
+pub use transform::{Transform, TransformCompressor, TransformKind};
 
-/// Represents a primitive data value to be compressed.
+/// Represents a data value to be compressed.
+///
+/// Values are usually primitives, but some compressors take small composite
+/// values as a whole, like a `[lat, lon]` pair, so lists and records of
+/// values are values too. Only [`AnyCompressor`] accepts nulls.
 #[derive(Debug, PartialEq)]
 pub enum Value {
+  Null,
   Bool(bool),
   Int(i64),
   UInt(u64),
   Float(f64),
   Str(String),
+  List(Vec<Value>),
+  Record(BTreeMap<String, Value>),
 }
 
-impl Value {
-  /// A textual description of the variant type; used for error messages.
-  fn typename(&self) -> &'static str {
-    use Value::*;
+impl<'a> TryFrom<&'a serde_json::Value> for Value {
+  type Error = anyhow::Error;
+
+  fn try_from(v: &'a serde_json::Value) -> Result<Self> {
+    use serde_json::Value as Json;
 
-    match self {
-      Bool(_) => "bool",
-      Int(_) | UInt(_) => "int",
-      Float(_) => "float",
-      Str(_) => "string",
+    match v {
+      Json::Bool(b) => Ok(Value::Bool(*b)),
+      Json::Number(n) => n
+        .as_i64()
+        .map(Value::Int)
+        .or_else(|| n.as_u64().map(Value::UInt))
+        .or_else(|| n.as_f64().map(Value::Float))
+        .ok_or_else(|| anyhow!("unsupported number: {}", n)),
+      Json::String(s) => Ok(Value::Str(s.clone())),
+      Json::Array(arr) => arr
+        .iter()
+        .map(Value::try_from)
+        .collect::<Result<_>>()
+        .map(Value::List),
+      Json::Object(map) => map
+        .iter()
+        .map(|(k, v)| Ok((k.clone(), Value::try_from(v)?)))
+        .collect::<Result<_>>()
+        .map(Value::Record),
+      Json::Null => Ok(Value::Null),
     }
   }
 }
 
-impl<'a> TryFrom<&'a serde_json::Value> for Value {
-  type Error = anyhow::Error;
-
-  fn try_from(v: &'a serde_json::Value) -> Result<Self> {
+impl From<Value> for serde_json::Value {
+  fn from(v: Value) -> Self {
     match v {
-      _ if v.is_boolean() => Ok(Value::Bool(v.as_bool().unwrap())),
-      _ if v.is_i64() => Ok(Value::Int(v.as_i64().unwrap())),
-      _ if v.is_u64() => Ok(Value::UInt(v.as_u64().unwrap())),
-      _ if v.is_f64() => Ok(Value::Float(v.as_f64().unwrap())),
-      _ if v.is_string() => Ok(Value::Str(v.as_str().unwrap().to_owned())),
-      _ => Err(anyhow!("failed to convert JSON to primitive value")),
+      Value::Null => serde_json::Value::Null,
+      Value::Bool(b) => serde_json::Value::Bool(b),
+      Value::Int(i) => serde_json::Value::from(i),
+      Value::UInt(u) => serde_json::Value::from(u),
+      Value::Float(f) => serde_json::Value::from(f),
+      Value::Str(s) => serde_json::Value::String(s),
+      Value::List(list) => {
+        serde_json::Value::Array(list.into_iter().map(Self::from).collect())
+      }
+      Value::Record(record) => serde_json::Value::Object(
+        record
+          .into_iter()
+          .map(|(k, v)| (k, Self::from(v)))
+          .collect(),
+      ),
     }
   }
 }
@@ -66,6 +130,10 @@ impl<'a> TryFrom<&'a serde_json::Value> for Value {
 pub enum EncodedWidth {
   Fixed(usize),
   Variable,
+  /// Values are codes of a prefix code, so their ends can be found without a
+  /// length. They are stored in the same blocks as fixed width values and
+  /// their widths are found with [`Compressor::prefix_len`].
+  Prefix,
 }
 
 /// The trait implement by all compressors.
@@ -87,14 +155,55 @@ pub trait Compressor {
   /// to first encode data and then second time (in a different invocation of
   /// the program) to decode the data.
   fn encoded_width(&self) -> EncodedWidth;
+
+  /// The number of bits taken up by the value at the start of `bits`.
+  ///
+  /// Only called for compressors whose values are [prefix
+  /// codes](EncodedWidth::Prefix), which must override it. Fails if `bits`
+  /// ends before the value does.
+  fn prefix_len(&self, bits: &mut dyn Iterator<Item = bool>) -> Result<usize> {
+    let _ = bits;
+    bail!("values of this compressor are not prefix codes")
+  }
+
+  /// The number of bits `value` will take up once compressed.
+  ///
+  /// Fixed width compressors don't look at `value` at all. The default for
+  /// variable width compressors is to compress `value` and count the bits, so
+  /// they should provide a cheaper implementation if they can.
+  fn compressed_len(&self, value: Value) -> Result<usize> {
+    match self.encoded_width() {
+      EncodedWidth::Fixed(width) => Ok(width),
+      EncodedWidth::Variable | EncodedWidth::Prefix => {
+        Ok(self.compress(value)?.len())
+      }
+    }
+  }
 }
 
 /// Returns an error stating that a given value type cannot be handled by the
 /// compressor.
 fn unexpected_type(value: Value, hint: &str) -> Error {
-  anyhow!(
-    "unexpected value type: {}, expected {}",
-    value.typename(),
-    hint
-  )
+  Error::new(crate::Error::TypeMismatch {
+    path: crate::path::Path::root(),
+    expected: hint.to_string(),
+    value: value.into(),
+  })
+}
+
+/// Splits the name of a parameterized type, like `geo(precision=6)`, into its
+/// base name and `key=value` parameters.
+///
+/// Returns `None` if `name` isn't of that form.
+fn parse_params(name: &str) -> Option<(&str, Vec<(&str, &str)>)> {
+  let (base, rest) = name.split_once('(')?;
+  let params = rest.strip_suffix(')')?;
+  let params = params
+    .split(',')
+    .map(|param| {
+      let (key, value) = param.split_once('=')?;
+      Some((key.trim(), value.trim()))
+    })
+    .collect::<Option<_>>()?;
+  Some((base, params))
 }
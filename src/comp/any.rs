@@ -0,0 +1,301 @@
+use crate::comp::half::{from_f16_bits, to_f16_bits};
+use crate::comp::*;
+
+/// The deepest arrays and maps may be nested within a value, so that
+/// decoding a malicious value can't overflow the stack.
+const MAX_DEPTH: usize = 128;
+
+/// A compressor for values of any type, for the parts of a document which
+/// have no structure to speak of.
+///
+/// Values are serialized as CBOR ([RFC 8949]) in its deterministic form:
+/// integers and lengths take up as few bytes as possible, floats are stored
+/// in the narrowest of half, single and double precision which holds them
+/// exactly, and the entries of maps are sorted by the bytes of their keys.
+/// Equal values are therefore always compressed to the same bits.
+///
+/// [RFC 8949]: https://www.rfc-editor.org/rfc/rfc8949
+pub struct AnyCompressor;
+
+const UNSIGNED: u8 = 0;
+const NEGATIVE: u8 = 1;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const SIMPLE: u8 = 7;
+
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+const HALF: u8 = 0xf9;
+const SINGLE: u8 = 0xfa;
+const DOUBLE: u8 = 0xfb;
+
+/// Writes the head of an item, made up of its major type and an argument in
+/// as few bytes as possible.
+fn write_head(major: u8, arg: u64, out: &mut Vec<u8>) {
+  let major = major << 5;
+  if arg < 24 {
+    out.push(major | arg as u8);
+  } else if arg <= u8::MAX as u64 {
+    out.push(major | 24);
+    out.push(arg as u8);
+  } else if arg <= u16::MAX as u64 {
+    out.push(major | 25);
+    out.extend_from_slice(&(arg as u16).to_be_bytes());
+  } else if arg <= u32::MAX as u64 {
+    out.push(major | 26);
+    out.extend_from_slice(&(arg as u32).to_be_bytes());
+  } else {
+    out.push(major | 27);
+    out.extend_from_slice(&arg.to_be_bytes());
+  }
+}
+
+fn write_text(s: &str, out: &mut Vec<u8>) {
+  write_head(TEXT, s.len() as u64, out);
+  out.extend_from_slice(s.as_bytes());
+}
+
+fn write_float(x: f64, out: &mut Vec<u8>) {
+  let half = to_f16_bits(x);
+  if from_f16_bits(half).to_bits() == x.to_bits() {
+    out.push(HALF);
+    out.extend_from_slice(&half.to_be_bytes());
+  } else if (x as f32) as f64 == x {
+    out.push(SINGLE);
+    out.extend_from_slice(&(x as f32).to_bits().to_be_bytes());
+  } else {
+    out.push(DOUBLE);
+    out.extend_from_slice(&x.to_bits().to_be_bytes());
+  }
+}
+
+fn write_value(value: &Value, depth: usize, out: &mut Vec<u8>) -> Result<()> {
+  match value {
+    Value::Null => out.push(NULL),
+    Value::Bool(b) => out.push(if *b { TRUE } else { FALSE }),
+    Value::Int(i) if *i >= 0 => write_head(UNSIGNED, *i as u64, out),
+    // -1 - i, which is how CBOR stores negative integers
+    Value::Int(i) => write_head(NEGATIVE, !*i as u64, out),
+    Value::UInt(u) => write_head(UNSIGNED, *u, out),
+    Value::Float(f) => write_float(*f, out),
+    Value::Str(s) => write_text(s, out),
+    Value::List(_) | Value::Record(_) if depth == MAX_DEPTH => {
+      bail!("value is nested more than {} levels deep", MAX_DEPTH)
+    }
+    Value::List(list) => {
+      write_head(ARRAY, list.len() as u64, out);
+      for v in list {
+        write_value(v, depth + 1, out)?;
+      }
+    }
+    Value::Record(record) => {
+      let mut entries = Vec::with_capacity(record.len());
+      for (k, v) in record {
+        let mut key = Vec::new();
+        write_text(k, &mut key);
+        let mut value = Vec::new();
+        write_value(v, depth + 1, &mut value)?;
+        entries.push((key, value));
+      }
+      entries.sort();
+      write_head(MAP, entries.len() as u64, out);
+      for (k, v) in entries {
+        out.extend_from_slice(&k);
+        out.extend_from_slice(&v);
+      }
+    }
+  }
+  Ok(())
+}
+
+/// Reads CBOR items from a byte slice.
+struct Reader<'a> {
+  bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+  fn take(&mut self, n: usize) -> Result<&'a [u8]> {
+    if n > self.bytes.len() {
+      bail!("value ends within an item");
+    }
+    let (taken, rest) = self.bytes.split_at(n);
+    self.bytes = rest;
+    Ok(taken)
+  }
+
+  fn uint(&mut self, n: usize) -> Result<u64> {
+    Ok(
+      self
+        .take(n)?
+        .iter()
+        .fold(0, |acc, b| (acc << 8) | *b as u64),
+    )
+  }
+
+  /// Reads the head of an item, returning its major type, additional
+  /// information and argument.
+  fn head(&mut self) -> Result<(u8, u8, u64)> {
+    let initial = self.take(1)?[0];
+    let (major, info) = (initial >> 5, initial & 0x1f);
+    let arg = match info {
+      0..=23 => info as u64,
+      24 => self.uint(1)?,
+      25 => self.uint(2)?,
+      26 => self.uint(4)?,
+      27 => self.uint(8)?,
+      _ => bail!("unsupported item {:#04x}", initial),
+    };
+    Ok((major, info, arg))
+  }
+
+  /// Reads a length, which can't be larger than the number of bytes left as
+  /// every item takes up at least one.
+  fn len(&mut self, arg: u64) -> Result<usize> {
+    if arg > self.bytes.len() as u64 {
+      bail!("value ends within an item");
+    }
+    Ok(arg as usize)
+  }
+
+  fn text(&mut self, arg: u64) -> Result<String> {
+    let len = self.len(arg)?;
+    Ok(String::from_utf8(self.take(len)?.to_vec())?)
+  }
+
+  fn value(&mut self, depth: usize) -> Result<Value> {
+    let (major, info, arg) = self.head()?;
+    if (major == ARRAY || major == MAP) && depth == MAX_DEPTH {
+      bail!("value is nested more than {} levels deep", MAX_DEPTH);
+    }
+    match (major, info) {
+      (UNSIGNED, _) if arg <= i64::MAX as u64 => Ok(Value::Int(arg as i64)),
+      (UNSIGNED, _) => Ok(Value::UInt(arg)),
+      (NEGATIVE, _) if arg <= i64::MAX as u64 => Ok(Value::Int(!(arg as i64))),
+      (NEGATIVE, _) => bail!("integer -1-{} is out of range", arg),
+      (TEXT, _) => Ok(Value::Str(self.text(arg)?)),
+      (ARRAY, _) => {
+        let len = self.len(arg)?;
+        let mut list = Vec::with_capacity(len);
+        for _ in 0..len {
+          list.push(self.value(depth + 1)?);
+        }
+        Ok(Value::List(list))
+      }
+      (MAP, _) => {
+        let len = self.len(arg)?;
+        let mut record = BTreeMap::new();
+        for _ in 0..len {
+          let key = match self.head()? {
+            (TEXT, _, arg) => self.text(arg)?,
+            _ => bail!("map keys must be text"),
+          };
+          let value = self.value(depth + 1)?;
+          if record.insert(key, value).is_some() {
+            bail!("map has duplicate keys");
+          }
+        }
+        Ok(Value::Record(record))
+      }
+      (SIMPLE, 20) => Ok(Value::Bool(false)),
+      (SIMPLE, 21) => Ok(Value::Bool(true)),
+      (SIMPLE, 22) => Ok(Value::Null),
+      (SIMPLE, 25) => Ok(Value::Float(from_f16_bits(arg as u16))),
+      (SIMPLE, 26) => Ok(Value::Float(f32::from_bits(arg as u32) as f64)),
+      (SIMPLE, 27) => Ok(Value::Float(f64::from_bits(arg))),
+      _ => bail!("unsupported item of major type {}", major),
+    }
+  }
+}
+
+impl Compressor for AnyCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let mut bytes = Vec::new();
+    write_value(&value, 0, &mut bytes)?;
+    Ok(BitVec::from_bytes(&bytes))
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.len() % 8 != 0 {
+      bail!("unable to convert bit sequence to bytes");
+    }
+    let bytes = bits.to_bytes();
+    let mut reader = Reader { bytes: &bytes };
+    let value = reader.value(0)?;
+    if !reader.bytes.is_empty() {
+      bail!("{} bytes left over after the value", reader.bytes.len());
+    }
+    Ok(value)
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn cbor(value: Value) -> Vec<u8> {
+    let bits = AnyCompressor.compress(value).unwrap();
+    let bytes = bits.to_bytes();
+    let decoded = AnyCompressor.decompress(bits).unwrap();
+    assert_eq!(bytes, AnyCompressor.compress(decoded).unwrap().to_bytes());
+    bytes
+  }
+
+  #[test]
+  fn deterministic_encoding() {
+    // Examples from appendix A of RFC 8949
+    assert_eq!(vec![0x17], cbor(Value::Int(23)));
+    assert_eq!(vec![0x18, 0x18], cbor(Value::Int(24)));
+    assert_eq!(vec![0x19, 0x03, 0xe8], cbor(Value::Int(1000)));
+    assert_eq!(vec![0x38, 0x63], cbor(Value::Int(-100)));
+    assert_eq!(
+      vec![0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+      cbor(Value::UInt(u64::MAX))
+    );
+    assert_eq!(vec![0xf9, 0x3e, 0x00], cbor(Value::Float(1.5)));
+    assert_eq!(vec![0xfa, 0x47, 0xc3, 0x50, 0x00], cbor(Value::Float(1e5)));
+    assert_eq!(
+      vec![0xfb, 0x3f, 0xf1, 0x99, 0x99, 0x99, 0x99, 0x99, 0x9a],
+      cbor(Value::Float(1.1))
+    );
+    assert_eq!(vec![0xf6], cbor(Value::Null));
+    assert_eq!(vec![0x62, 0xc3, 0xbc], cbor(Value::Str("ü".to_string())));
+
+    // Shorter keys sort first as their heads are smaller
+    let mut record = BTreeMap::new();
+    record.insert("bb".to_string(), Value::List(vec![Value::Bool(true)]));
+    record.insert("c".to_string(), Value::Bool(false));
+    assert_eq!(
+      vec![0xa2, 0x61, 0x63, 0xf4, 0x62, 0x62, 0x62, 0x81, 0xf5],
+      cbor(Value::Record(record))
+    );
+  }
+
+  #[test]
+  fn invalid_values() {
+    let decode =
+      |bytes: &[u8]| AnyCompressor.decompress(BitVec::from_bytes(bytes));
+    // Truncated, left over bytes, a byte string and a huge array
+    assert!(decode(&[0x19, 0x03]).is_err());
+    assert!(decode(&[0x01, 0x02]).is_err());
+    assert!(decode(&[0x41, 0x00]).is_err());
+    assert!(
+      decode(&[0x9b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]).is_err()
+    );
+    assert!(decode(&[0xa1, 0x01, 0x01]).is_err());
+    assert!(
+      decode(&[[0x81; MAX_DEPTH + 1].as_ref(), &[0xf6]].concat()).is_err()
+    );
+
+    let mut deep = Value::Null;
+    for _ in 0..=MAX_DEPTH {
+      deep = Value::List(vec![deep]);
+    }
+    assert!(AnyCompressor.compress(deep).is_err());
+  }
+}
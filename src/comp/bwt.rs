@@ -0,0 +1,250 @@
+use crate::bit::BitBuf;
+use crate::comp::*;
+use crate::vie::CodePoint;
+
+/// An experimental compressor for long text which chains the
+/// Burrows-Wheeler transform, move-to-front coding, run-length coding of
+/// zeros and a Huffman code trained on the result, in the style of bzip2.
+///
+/// It usually compresses long text better than [`LzwCompressor`] at the cost
+/// of much slower encoding, so it is meant for archival. A value is laid out
+/// as the index of the original string among its sorted rotations and the
+/// serialized [`Codebook`], both preceded by VIE code points, followed by
+/// the codes.
+///
+/// This compressor is experimental and only enabled by the `experimental-bwt`
+/// feature. Its encoding may change between releases, so values should only
+/// be decoded by the version of chii which encoded them.
+pub struct BwtCompressor;
+
+/// The last column of the sorted rotations of `bytes`, along with the index
+/// of `bytes` itself among them.
+fn bwt(bytes: &[u8]) -> (Vec<u8>, usize) {
+  let n = bytes.len();
+  let mut rotations: Vec<usize> = (0..n).collect();
+  rotations.sort_by(|a, b| {
+    let a = bytes[*a..].iter().chain(&bytes[..*a]);
+    let b = bytes[*b..].iter().chain(&bytes[..*b]);
+    a.cmp(b)
+  });
+  let last = rotations.iter().map(|r| bytes[(r + n - 1) % n]).collect();
+  let index = rotations.iter().position(|r| *r == 0).unwrap();
+  (last, index)
+}
+
+/// Undoes [`bwt`].
+fn inverse_bwt(last: &[u8], index: usize) -> Result<Vec<u8>> {
+  if index >= last.len() {
+    bail!("rotation index {} is out of range", index);
+  }
+  // The first column is the last one sorted, and the k-th occurrence of a
+  // byte in one is the k-th occurrence in the other
+  let mut starts = [0; 256];
+  for b in last {
+    starts[*b as usize] += 1;
+  }
+  let mut total = 0;
+  for start in starts.iter_mut() {
+    total += *start;
+    *start = total - *start;
+  }
+  let mut next = vec![0; last.len()];
+  for (i, b) in last.iter().enumerate() {
+    next[starts[*b as usize]] = i;
+    starts[*b as usize] += 1;
+  }
+
+  let mut bytes = Vec::with_capacity(last.len());
+  let mut row = next[index];
+  for _ in 0..last.len() {
+    bytes.push(last[row]);
+    row = next[row];
+  }
+  Ok(bytes)
+}
+
+/// Replaces each byte with the number of distinct bytes seen since it last
+/// occurred, so that repeated bytes become zeros.
+fn move_to_front(bytes: &[u8]) -> Vec<u8> {
+  let mut order: Vec<u8> = (0..=255).collect();
+  bytes
+    .iter()
+    .map(|b| {
+      let i = order.iter().position(|o| o == b).unwrap();
+      order.remove(i);
+      order.insert(0, *b);
+      i as u8
+    })
+    .collect()
+}
+
+/// Undoes [`move_to_front`].
+fn inverse_move_to_front(indices: &[u8]) -> Vec<u8> {
+  let mut order: Vec<u8> = (0..=255).collect();
+  indices
+    .iter()
+    .map(|i| {
+      let b = order.remove(*i as usize);
+      order.insert(0, b);
+      b
+    })
+    .collect()
+}
+
+/// Replaces each run of up to 256 zeros with a zero followed by the length
+/// of the run minus one.
+fn run_length(bytes: &[u8]) -> Vec<u8> {
+  let mut out = Vec::new();
+  let mut zeros = 0;
+  for b in bytes.iter().chain(Some(&1)) {
+    if *b == 0 && zeros < 256 {
+      zeros += 1;
+      continue;
+    }
+    if zeros > 0 {
+      out.push(0);
+      out.push((zeros - 1) as u8);
+    }
+    if *b == 0 {
+      zeros = 1;
+    } else {
+      zeros = 0;
+      out.push(*b);
+    }
+  }
+  // The byte chained on to flush the last run
+  out.pop();
+  out
+}
+
+/// Undoes [`run_length`].
+fn inverse_run_length(bytes: &[u8]) -> Result<Vec<u8>> {
+  let mut out = Vec::new();
+  let mut bytes = bytes.iter();
+  while let Some(b) = bytes.next() {
+    if *b != 0 {
+      out.push(*b);
+      continue;
+    }
+    let zeros = bytes
+      .next()
+      .ok_or_else(|| anyhow!("run of zeros has no length"))?;
+    out.resize(out.len() + *zeros as usize + 1, 0);
+  }
+  Ok(out)
+}
+
+impl Compressor for BwtCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let s = match value {
+      Value::Str(s) => s,
+      _ => return Err(unexpected_type(value, "string")),
+    };
+    if s.is_empty() {
+      return Ok(BitVec::new());
+    }
+
+    let (last, index) = bwt(s.as_bytes());
+    let symbols = run_length(&move_to_front(&last));
+    let book = Codebook::train(Some(&symbols));
+    let book_bytes = book.to_bytes();
+
+    let mut buf = BitBuf::new();
+    buf.push_bytes(CodePoint::from(index as u64).bytes());
+    buf.push_bytes(CodePoint::from(book_bytes.len() as u64).bytes());
+    buf.push_bytes(&book_bytes);
+    book.write_codes(&symbols, &mut buf)?;
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.is_empty() {
+      return Ok(Value::Str(String::new()));
+    }
+
+    // The header is whole bytes, the codes after it might not be
+    let bytes = bits.to_bytes();
+    let mut rest = &bytes[..];
+    let mut read_code_point = || {
+      let cp = CodePoint::parse(rest)
+        .ok_or_else(|| anyhow!("invalid code point in bit sequence"))?;
+      rest = &rest[cp.count()..];
+      cp.decode::<u64>()
+        .map(|n| n as usize)
+        .ok_or_else(|| anyhow!("invalid code point in bit sequence"))
+    };
+    let index = read_code_point()?;
+    let book_len = read_code_point()?;
+    if book_len > rest.len() {
+      bail!("codebook is truncated");
+    }
+    let book = Codebook::from_bytes(&rest[..book_len])?;
+    let header = bytes.len() - rest.len() + book_len;
+
+    let symbols = book.read_codes(bits.iter().skip(header * 8))?;
+    let last = inverse_move_to_front(&inverse_run_length(&symbols)?);
+    let s = String::from_utf8(inverse_bwt(&last, index)?)?;
+    Ok(Value::Str(s))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn roundtrip(s: &str) -> usize {
+    let bits = BwtCompressor.compress(Value::Str(s.to_string())).unwrap();
+    let len = bits.len();
+    assert_eq!(
+      Value::Str(s.to_string()),
+      BwtCompressor.decompress(bits).unwrap()
+    );
+    len
+  }
+
+  #[test]
+  fn stages_roundtrip() {
+    let (last, index) = bwt(b"banana");
+    assert_eq!((b"nnbaaa".to_vec(), 3), (last.clone(), index));
+    assert_eq!(b"banana".to_vec(), inverse_bwt(&last, index).unwrap());
+
+    let mtf = move_to_front(b"nnbaaa");
+    assert_eq!(vec![b'n', 0, b'b' + 1, b'a' + 2, 0, 0], mtf);
+    assert_eq!(b"nnbaaa".to_vec(), inverse_move_to_front(&mtf));
+
+    let zeros = [vec![0; 300], vec![7, 0, 0, 9, 0]].concat();
+    let rle = run_length(&zeros);
+    assert_eq!(vec![0, 255, 0, 43, 7, 0, 1, 9, 0, 0], rle);
+    assert_eq!(zeros, inverse_run_length(&rle).unwrap());
+  }
+
+  #[test]
+  fn text_roundtrip() {
+    assert_eq!(0, roundtrip(""));
+    roundtrip("a");
+    roundtrip("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa");
+    roundtrip("naïve café ☕ naïve café ☕");
+
+    let text = "It was the best of times, it was the worst of times, it was \
+                the age of wisdom, it was the age of foolishness. "
+      .repeat(20);
+    let len = roundtrip(&text);
+    assert!(len < text.len() * 8 / 5);
+  }
+
+  #[test]
+  fn invalid_bits() {
+    let mut bits = BwtCompressor
+      .compress(Value::Str("banana".to_string()))
+      .unwrap();
+    bits.set(0, true);
+    assert!(BwtCompressor.decompress(bits).is_err());
+    assert!(BwtCompressor
+      .decompress(BitVec::from_bytes(&[9, 1]))
+      .is_err());
+  }
+}
@@ -0,0 +1,105 @@
+use crate::bit::BitBuf;
+use crate::comp::*;
+
+/// A compressor for hex colors, either `#rrggbb` or the short `#rgb` form.
+///
+/// A color is stored as a flag which is set for the short form followed by
+/// its 24 bits of red, green and blue, where short colors have each digit
+/// doubled as in CSS. Digits may be in either case but are always
+/// decompressed in lowercase.
+pub struct ColorCompressor;
+
+/// The value of a hex digit.
+fn hex_digit(c: u8) -> Option<u32> {
+  (c as char).to_digit(16)
+}
+
+impl Compressor for ColorCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let s = match value {
+      Value::Str(s) => s,
+      _ => return Err(unexpected_type(value, "a hex color")),
+    };
+    let digits = s
+      .strip_prefix('#')
+      .map(str::as_bytes)
+      .filter(|d| d.len() == 3 || d.len() == 6)
+      .and_then(|d| d.iter().map(|c| hex_digit(*c)).collect::<Option<Vec<_>>>())
+      .ok_or_else(|| anyhow!("{} is not a hex color", s))?;
+    let short = digits.len() == 3;
+    let rgb = digits.iter().fold(0, |rgb, d| {
+      if short {
+        (rgb << 8) | (d << 4) | d
+      } else {
+        (rgb << 4) | d
+      }
+    });
+
+    let mut buf = BitBuf::new();
+    buf.push_bits(short as u64, 1);
+    buf.push_bits(rgb as u64, 24);
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.len() != 25 {
+      bail!("invalid bit sequence length");
+    }
+    let rgb = bits
+      .iter()
+      .skip(1)
+      .fold(0u32, |acc, b| (acc << 1) | b as u32);
+    if !bits[0] {
+      return Ok(Value::Str(format!("#{:06x}", rgb)));
+    }
+    // Every other digit of a short color is a copy
+    if rgb & 0x0f0f0f != (rgb >> 4) & 0x0f0f0f {
+      bail!("short color {:06x} has digits which aren't doubled", rgb);
+    }
+    let rgb = ((rgb >> 8) & 0xf00) | ((rgb >> 4) & 0x0f0) | (rgb & 0x00f);
+    Ok(Value::Str(format!("#{:03x}", rgb)))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Fixed(25)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn roundtrip(color: &str) -> Value {
+    let bits = ColorCompressor
+      .compress(Value::Str(color.to_string()))
+      .unwrap();
+    assert_eq!(25, bits.len());
+    ColorCompressor.decompress(bits).unwrap()
+  }
+
+  #[test]
+  fn long_and_short_colors() {
+    assert_eq!(Value::Str("#1e90ff".to_string()), roundtrip("#1E90FF"));
+    assert_eq!(Value::Str("#000000".to_string()), roundtrip("#000000"));
+    assert_eq!(Value::Str("#f0a".to_string()), roundtrip("#f0A"));
+
+    // Short colors are stored expanded
+    let bits = ColorCompressor.compress(Value::Str("#f0a".to_string()));
+    let mut expected = BitVec::from_elem(1, true);
+    expected.extend(BitVec::from_bytes(&[0xff, 0x00, 0xaa]).iter());
+    assert_eq!(expected, bits.unwrap());
+  }
+
+  #[test]
+  fn invalid_colors() {
+    for color in &["1e90ff", "#1e90f", "#ggg", "#1e90ff00", "#"] {
+      let value = Value::Str(color.to_string());
+      assert!(ColorCompressor.compress(value).is_err(), "{}", color);
+    }
+    assert!(ColorCompressor.compress(Value::Int(0)).is_err());
+
+    let mut bits = BitVec::from_elem(1, true);
+    bits.extend(BitVec::from_bytes(&[0xf1, 0x00, 0xaa]).iter());
+    assert!(ColorCompressor.decompress(bits).is_err());
+  }
+}
@@ -0,0 +1,77 @@
+use crate::bit::BitBuf;
+use crate::comp::*;
+
+/// A compressor for strings which may have been replaced by an index into a
+/// [dictionary](crate::dictionary) of a field's most frequent values.
+///
+/// A value starts with a flag which is set for indices. Indices follow in
+/// the minimum number of bits needed for a dictionary of `size` entries and
+/// strings follow as is. Indices are decompressed as integers, which are
+/// looked up once the whole object has been decoded.
+pub struct DictionaryCompressor {
+  pub size: usize,
+}
+
+impl Compressor for DictionaryCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let mut buf = BitBuf::new();
+    let index = match value {
+      Value::Str(s) => {
+        buf.push_bit(false);
+        buf.push_bytes(s.as_bytes());
+        return Ok(buf.into());
+      }
+      Value::Int(i) if i >= 0 => i as u64,
+      Value::UInt(u) => u,
+      _ => return Err(unexpected_type(value, "string")),
+    };
+    if index >= self.size as u64 {
+      bail!("dictionary index {} is out of range", index);
+    }
+    buf.push_bit(true);
+    buf.push_bits(index, math::required_bit_width(self.size));
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.is_empty() {
+      bail!("invalid bit sequence length");
+    }
+    if bits[0] {
+      if bits.len() - 1 != math::required_bit_width(self.size) {
+        bail!("invalid bit sequence length");
+      }
+      let i = bits.iter().skip(1).fold(0, |acc, b| (acc << 1) | b as u64);
+      return Ok(Value::UInt(i));
+    }
+    let rest: BitVec = bits.iter().skip(1).collect();
+    if rest.len() % 8 != 0 {
+      bail!("unable to convert bit sequence to bytes");
+    }
+    Ok(Value::Str(String::from_utf8(rest.to_bytes())?))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn strings_and_indices() {
+    let c = DictionaryCompressor { size: 6 };
+    let bits = c.compress(Value::Str("GET".to_string())).unwrap();
+    assert_eq!(1 + 24, bits.len());
+    assert_eq!(Value::Str("GET".to_string()), c.decompress(bits).unwrap());
+
+    let bits = c.compress(Value::UInt(5)).unwrap();
+    assert_eq!(1 + 3, bits.len());
+    assert_eq!(Value::UInt(5), c.decompress(bits).unwrap());
+
+    assert!(c.compress(Value::UInt(6)).is_err());
+    assert!(c.compress(Value::Bool(true)).is_err());
+  }
+}
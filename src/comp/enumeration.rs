@@ -1,5 +1,10 @@
+use crate::bit::BitBuf;
 use crate::comp::*;
 
+/// The total weight which probabilities are scaled to before building a
+/// [`WeightedEnumCompressor`]'s code, which keeps codes well under 64 bits.
+const WEIGHT_SCALE: f64 = 4_294_967_296.0;
+
 /// Compressor for enumerations of string variants.
 ///
 /// Takes a fixed set of variants and compresses them into unique integer values
@@ -21,21 +26,14 @@ impl Compressor for EnumCompressor {
       .variants
       .iter()
       .position(|v| v.as_bytes() == bytes)
-      .ok_or_else(|| anyhow!("cannot convert {} to enum variant", s))?
-      as u64;
-    let width = math::required_bit_width(self.variants.len());
-    let mut bits = BitVec::from_rev_be(index);
-    bits.truncate(width);
-    Ok(bits)
+      .ok_or_else(|| anyhow!("cannot convert {} to enum variant", s))?;
+    Ok(ordinal_bits(index, self.variants.len()))
   }
 
-  fn decompress(&self, mut bits: BitVec) -> Result<Value> {
-    bits.zext_or_trunc(64);
-    // This can't fail as we just extended the vector to 64 bits
-    let index = bits.to_rev_be::<u64>().unwrap();
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
     let variant: &String = self
       .variants
-      .get(index as usize)
+      .get(read_ordinal(bits)?)
       .ok_or_else(|| anyhow!("cannot match encoded value to variant"))?;
     Ok(Value::Str(variant.clone()))
   }
@@ -45,3 +43,222 @@ impl Compressor for EnumCompressor {
     EncodedWidth::Fixed(width)
   }
 }
+
+/// Compressor for enumerations of string variants whose probabilities are
+/// known ahead of time.
+///
+/// Each variant is given a code from a Huffman code built on the
+/// probabilities, so likely variants take up fewer bits than they would with
+/// an [`EnumCompressor`] and unlikely ones take up more. Codes are canonical,
+/// handed out in order of their length and then of their variant, and form a
+/// prefix code so values don't need a length.
+pub struct WeightedEnumCompressor {
+  variants: Vec<String>,
+  /// The code of each variant along with its length.
+  codes: Vec<(u64, usize)>,
+}
+
+impl WeightedEnumCompressor {
+  /// Builds the code for `variants` which occur with the matching
+  /// `probabilities`. These are normalized, so they may be any positive
+  /// weights.
+  pub fn new(variants: Vec<String>, probabilities: &[f64]) -> Result<Self> {
+    if variants.len() != probabilities.len() {
+      bail!(
+        "{} variants but {} probabilities",
+        variants.len(),
+        probabilities.len()
+      );
+    }
+    for (v, p) in variants.iter().zip(probabilities) {
+      if !(*p > 0.0 && p.is_finite()) {
+        bail!(
+          "probability of '{}' must be a positive number, not {}",
+          v,
+          p
+        );
+      }
+    }
+
+    let total: f64 = probabilities.iter().sum();
+    let weights: Vec<u64> = probabilities
+      .iter()
+      .map(|p| ((p / total * WEIGHT_SCALE) as u64).max(1))
+      .collect();
+    let mut lengths = super::huffman::code_lengths(&weights);
+    if lengths.len() == 1 {
+      // A lone variant is always the value, so it takes up no bits at all
+      lengths[0] = 0;
+    }
+
+    let mut order: Vec<usize> = (0..variants.len()).collect();
+    order.sort_by_key(|i| lengths[*i]);
+    let mut codes = vec![(0, 0); variants.len()];
+    let (mut code, mut prev) = (0u64, 0);
+    for i in order {
+      let len = lengths[i] as usize;
+      code <<= len - prev;
+      codes[i] = (code, len);
+      code += 1;
+      prev = len;
+    }
+    Ok(WeightedEnumCompressor { variants, codes })
+  }
+
+  /// The code of each variant along with its length, in the order of the
+  /// variants.
+  pub fn codes(&self) -> &[(u64, usize)] {
+    &self.codes
+  }
+}
+
+impl Compressor for WeightedEnumCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let s = match value {
+      Value::Str(s) => s,
+      _ => return Err(unexpected_type(value, "string")),
+    };
+    let index = self
+      .variants
+      .iter()
+      .position(|v| *v == s)
+      .ok_or_else(|| anyhow!("cannot convert {} to enum variant", s))?;
+    let (code, len) = self.codes[index];
+    let mut buf = BitBuf::with_capacity(len);
+    buf.push_bits(code, len);
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.len() > 64 {
+      bail!("invalid bit sequence length");
+    }
+    let code = bits.iter().fold(0, |acc, b| (acc << 1) | b as u64);
+    let index = self
+      .codes
+      .iter()
+      .position(|c| *c == (code, bits.len()))
+      .ok_or_else(|| anyhow!("cannot match encoded value to variant"))?;
+    Ok(Value::Str(self.variants[index].clone()))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Prefix
+  }
+
+  fn prefix_len(&self, bits: &mut dyn Iterator<Item = bool>) -> Result<usize> {
+    let max = self.codes.iter().map(|(_, len)| *len).max().unwrap_or(0);
+    let (mut code, mut len) = (0u64, 0);
+    loop {
+      if self.codes.contains(&(code, len)) {
+        return Ok(len);
+      }
+      if len == max {
+        bail!("invalid code in bit sequence");
+      }
+      let bit = bits
+        .next()
+        .ok_or_else(|| anyhow!("bit sequence ends within a code"))?;
+      code = (code << 1) | bit as u64;
+      len += 1;
+    }
+  }
+}
+
+/// Compressor for enumerations of integer codes, e.g., HTTP status codes.
+///
+/// Like [`EnumCompressor`], codes are compressed into their ordinal values,
+/// so they take up the minimum required number of bits no matter how large
+/// they are.
+pub struct IntEnumCompressor {
+  pub codes: Vec<i64>,
+}
+
+impl Compressor for IntEnumCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let code = match value {
+      Value::Int(i) => i128::from(i),
+      Value::UInt(u) => i128::from(u),
+      _ => return Err(unexpected_type(value, "integer")),
+    };
+    let index = self
+      .codes
+      .iter()
+      .position(|c| i128::from(*c) == code)
+      .ok_or_else(|| anyhow!("cannot convert {} to enum code", code))?;
+    Ok(ordinal_bits(index, self.codes.len()))
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    let code = self
+      .codes
+      .get(read_ordinal(bits)?)
+      .ok_or_else(|| anyhow!("cannot match encoded value to code"))?;
+    Ok(Value::Int(*code))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    let width = math::required_bit_width(self.codes.len());
+    EncodedWidth::Fixed(width)
+  }
+}
+
+/// The bits of the ordinal `index` of one of `n` values.
+fn ordinal_bits(index: usize, n: usize) -> BitVec {
+  let mut bits = BitVec::from_rev_be(index as u64);
+  bits.truncate(math::required_bit_width(n));
+  bits
+}
+
+/// Reads an ordinal written by [`ordinal_bits`].
+fn read_ordinal(mut bits: BitVec) -> Result<usize> {
+  if bits.len() > 64 {
+    bail!("invalid bit sequence length");
+  }
+  bits.zext_or_trunc(64);
+  let index = bits
+    .to_rev_be::<u64>()
+    .ok_or_else(|| anyhow!("invalid bit sequence length"))?;
+  Ok(index as usize)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn levels() -> WeightedEnumCompressor {
+    let variants = ["debug", "error", "info", "warn"];
+    WeightedEnumCompressor::new(
+      variants.iter().map(|v| v.to_string()).collect(),
+      &[0.05, 0.05, 0.7, 0.2],
+    )
+    .unwrap()
+  }
+
+  #[test]
+  fn likely_variants_have_short_codes() {
+    let c = levels();
+    assert_eq!(&[(6, 3), (7, 3), (0, 1), (2, 2)], c.codes());
+
+    for v in &["debug", "error", "info", "warn"] {
+      let bits = c.compress(Value::Str(v.to_string())).unwrap();
+      let mut padded = bits.iter().chain(vec![true; 8]);
+      assert_eq!(bits.len(), c.prefix_len(&mut padded).unwrap());
+      assert_eq!(Value::Str(v.to_string()), c.decompress(bits).unwrap());
+    }
+    assert!(c.compress(Value::Str("trace".to_string())).is_err());
+    assert!(c.prefix_len(&mut vec![true, true].into_iter()).is_err());
+  }
+
+  #[test]
+  fn invalid_probabilities() {
+    let variants = vec!["a".to_string(), "b".to_string()];
+    assert!(WeightedEnumCompressor::new(variants.clone(), &[1.0]).is_err());
+    assert!(WeightedEnumCompressor::new(variants.clone(), &[1.0, 0.0]).is_err());
+    assert!(WeightedEnumCompressor::new(variants, &[1.0, f64::NAN]).is_err());
+
+    let lone = WeightedEnumCompressor::new(vec!["a".to_string()], &[1.0]);
+    let bits = lone.unwrap().compress(Value::Str("a".to_string())).unwrap();
+    assert!(bits.is_empty());
+  }
+}
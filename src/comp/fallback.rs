@@ -0,0 +1,101 @@
+use crate::comp::*;
+
+/// Wraps a compressor which may expand some inputs, falling back to storing
+/// strings as raw UTF-8 whenever that is smaller.
+///
+/// Encoded values start with a single flag bit which is 0 when the rest of the
+/// bits were produced by the wrapped compressor and 1 when they are the raw
+/// bytes of a string. Values which aren't strings are always compressed.
+///
+/// Values of `huffman`, `lzw` and `smaz` types are encoded this way, since
+/// their compressors expand some strings. Dictionary values need no wrapping
+/// as they already start with a flag which picks between an index and the
+/// raw string.
+pub struct RawFallback<C>(pub C);
+
+impl<C: Compressor> Compressor for RawFallback<C> {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let raw = match &value {
+      Value::Str(s) => Some(BitVec::from_bytes(s.as_bytes())),
+      _ => None,
+    };
+    let compressed = self.0.compress(value)?;
+
+    let (is_raw, mut data) = match raw {
+      Some(raw) if raw.len() < compressed.len() => (true, raw),
+      _ => (false, compressed),
+    };
+    let mut bits = BitVec::from_elem(1, is_raw);
+    bits.append(&mut data);
+    Ok(bits)
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.is_empty() {
+      bail!("missing raw fallback flag");
+    }
+
+    let data: BitVec = bits.iter().skip(1).collect();
+    if bits[0] {
+      IdentityCompressor.decompress(data)
+    } else {
+      self.0.decompress(data)
+    }
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+
+  fn compressed_len(&self, value: Value) -> Result<usize> {
+    let raw = match &value {
+      Value::Str(s) => Some(s.len() * 8),
+      _ => None,
+    };
+    let compressed = self.0.compressed_len(value)?;
+    Ok(1 + raw.map_or(compressed, |raw| raw.min(compressed)))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  /// Stores strings with every bit repeated, which is always larger than the
+  /// string itself.
+  struct Doubling;
+
+  impl Compressor for Doubling {
+    fn compress(&self, value: Value) -> Result<BitVec> {
+      let bits = IdentityCompressor.compress(value)?;
+      Ok(bits.iter().flat_map(|b| vec![b, b]).collect())
+    }
+
+    fn decompress(&self, bits: BitVec) -> Result<Value> {
+      IdentityCompressor.decompress(bits.iter().step_by(2).collect())
+    }
+
+    fn encoded_width(&self) -> EncodedWidth {
+      EncodedWidth::Variable
+    }
+  }
+
+  #[test]
+  fn falls_back_to_raw_when_smaller() {
+    let c = RawFallback(Doubling);
+    let bits = c.compress(Value::Str("abc".to_string())).unwrap();
+    assert_eq!(1 + 24, bits.len());
+    assert!(bits[0]);
+    assert_eq!(25, c.compressed_len(Value::Str("abc".to_string())).unwrap());
+    assert_eq!(Value::Str("abc".to_string()), c.decompress(bits).unwrap());
+
+    // Enum variants are always smaller than their strings
+    let c = RawFallback(EnumCompressor {
+      variants: vec!["abc".to_string(), "def".to_string()],
+    });
+    let bits = c.compress(Value::Str("def".to_string())).unwrap();
+    assert_eq!(2, bits.len());
+    assert!(!bits[0]);
+    assert_eq!(Value::Str("def".to_string()), c.decompress(bits).unwrap());
+  }
+}
@@ -0,0 +1,233 @@
+use crate::bit::BitBuf;
+use crate::comp::*;
+
+/// The most decimal places a [`GeoCompressor`] may keep. At 9 places
+/// coordinates are accurate to well under a millimetre.
+pub const MAX_GEO_PRECISION: u32 = 9;
+
+/// How a [`GeoCompressor`] decompresses coordinates.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum GeoForm {
+  /// As a `[lat, lon]` pair.
+  Pair,
+  /// As a `{lat, lon}` record.
+  Record,
+}
+
+/// A lossy compressor for geographic coordinates, given as either `[lat, lon]`
+/// pairs or `{lat, lon}` records, which rounds them to `precision` decimal
+/// places.
+///
+/// Rounded latitudes and longitudes are stored as scaled integers offset from
+/// the bottom of their ranges, -90 to 90 and -180 to 180 degrees, each in the
+/// fewest bits which hold every value in its range. At 6 decimal places, which
+/// is accurate to about 10cm, that's 28 bits for the latitude followed by 29
+/// for the longitude.
+pub struct GeoCompressor {
+  precision: u32,
+  form: GeoForm,
+  scaled: ScaledCompressor,
+}
+
+impl GeoCompressor {
+  /// Constructs a compressor which keeps `precision` decimal places and
+  /// decompresses coordinates in the given `form`.
+  ///
+  /// Fails if `precision` is more than [`MAX_GEO_PRECISION`].
+  pub fn new(precision: u32, form: GeoForm) -> Result<Self> {
+    if precision > MAX_GEO_PRECISION {
+      bail!(
+        "geo precision must be at most {}, not {}",
+        MAX_GEO_PRECISION,
+        precision
+      );
+    }
+    let scale = (0..precision).fold(1.0, |scale, _| scale * 10.0);
+    Ok(GeoCompressor {
+      precision,
+      form,
+      scaled: ScaledCompressor {
+        scale,
+        round: Rounding::Nearest,
+      },
+    })
+  }
+
+  /// The number of decimal places kept.
+  pub fn precision(&self) -> u32 {
+    self.precision
+  }
+
+  /// The form coordinates are decompressed in.
+  pub fn form(&self) -> GeoForm {
+    self.form
+  }
+
+  /// Parses the precision and form of a type named `geo(precision=N)`, for
+  /// pairs, or `geo(precision=N, form=record)`, for records, returning `None`
+  /// if `name` isn't of that form.
+  pub fn parse(name: &str) -> Option<(u32, GeoForm)> {
+    let (base, params) = parse_params(name)?;
+    if base != "geo" {
+      return None;
+    }
+    match params[..] {
+      [("precision", precision)] => {
+        Some((precision.parse().ok()?, GeoForm::Pair))
+      }
+      [("precision", precision), ("form", form)] => {
+        let form = match form {
+          "pair" => GeoForm::Pair,
+          "record" => GeoForm::Record,
+          _ => return None,
+        };
+        Some((precision.parse().ok()?, form))
+      }
+      _ => None,
+    }
+  }
+
+  /// The number of bits taken up by a coordinate within `-max..=max`.
+  fn width(&self, max: u64) -> usize {
+    let values = 2 * max * self.scaled.scale as u64 + 1;
+    64 - (values - 1).leading_zeros() as usize
+  }
+
+  /// Scales `value`, failing if it is outside of `-max..=max`.
+  fn offset(&self, value: Value, max: u64, what: &str) -> Result<u64> {
+    let limit = max as i64 * self.scaled.scale as i64;
+    let i = self.scaled.scaled(value)?;
+    if i < -limit || i > limit {
+      bail!(
+        "{} {} is not within ±{}",
+        what,
+        i as f64 / self.scaled.scale,
+        max
+      );
+    }
+    Ok((i + limit) as u64)
+  }
+}
+
+/// Takes the latitude and longitude out of a `[lat, lon]` pair or `{lat, lon}`
+/// record.
+fn coordinates(value: Value) -> Result<(Value, Value)> {
+  match value {
+    Value::List(list) if list.len() == 2 => {
+      let mut list = list.into_iter();
+      Ok((list.next().unwrap(), list.next().unwrap()))
+    }
+    Value::Record(mut record)
+      if record.len() == 2
+        && record.contains_key("lat")
+        && record.contains_key("lon") =>
+    {
+      Ok((record.remove("lat").unwrap(), record.remove("lon").unwrap()))
+    }
+    _ => Err(unexpected_type(
+      value,
+      "a [lat, lon] pair or {lat, lon} record",
+    )),
+  }
+}
+
+impl Compressor for GeoCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let (lat, lon) = coordinates(value)?;
+    let lat = self.offset(lat, 90, "latitude")?;
+    let lon = self.offset(lon, 180, "longitude")?;
+    let mut buf = BitBuf::new();
+    buf.push_bits(lat, self.width(90));
+    buf.push_bits(lon, self.width(180));
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    let (lat_width, lon_width) = (self.width(90), self.width(180));
+    if bits.len() != lat_width + lon_width {
+      bail!("invalid bit sequence length");
+    }
+    let read = |skip, take, max: u64| {
+      let u = bits
+        .iter()
+        .skip(skip)
+        .take(take)
+        .fold(0u64, |acc, b| (acc << 1) | b as u64);
+      let limit = max as i64 * self.scaled.scale as i64;
+      let i = u as i64 - limit;
+      if i > limit {
+        bail!("coordinate is out of range");
+      }
+      Ok(Value::Float(i as f64 / self.scaled.scale))
+    };
+    let lat = read(0, lat_width, 90)?;
+    let lon = read(lat_width, lon_width, 180)?;
+    Ok(match self.form {
+      GeoForm::Pair => Value::List(vec![lat, lon]),
+      GeoForm::Record => {
+        let mut record = BTreeMap::new();
+        record.insert("lat".to_string(), lat);
+        record.insert("lon".to_string(), lon);
+        Value::Record(record)
+      }
+    })
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Fixed(self.width(90) + self.width(180))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn pair(lat: f64, lon: f64) -> Value {
+    Value::List(vec![Value::Float(lat), Value::Float(lon)])
+  }
+
+  #[test]
+  fn coordinates_roundtrip() {
+    let c = GeoCompressor::new(6, GeoForm::Pair).unwrap();
+    assert_eq!(EncodedWidth::Fixed(28 + 29), c.encoded_width());
+    let bits = c.compress(pair(49.2827291, -123.1207378)).unwrap();
+    assert_eq!(57, bits.len());
+    assert_eq!(pair(49.282729, -123.120738), c.decompress(bits).unwrap());
+
+    let c = GeoCompressor::new(0, GeoForm::Record).unwrap();
+    assert_eq!(EncodedWidth::Fixed(8 + 9), c.encoded_width());
+    let mut record = BTreeMap::new();
+    record.insert("lat".to_string(), Value::Int(-90));
+    record.insert("lon".to_string(), Value::Float(179.6));
+    let bits = c.compress(Value::Record(record)).unwrap();
+    let mut expected = BTreeMap::new();
+    expected.insert("lat".to_string(), Value::Float(-90.0));
+    expected.insert("lon".to_string(), Value::Float(180.0));
+    assert_eq!(Value::Record(expected), c.decompress(bits).unwrap());
+  }
+
+  #[test]
+  fn invalid_coordinates() {
+    let c = GeoCompressor::new(2, GeoForm::Pair).unwrap();
+    let e = c.compress(pair(90.01, 0.0)).unwrap_err();
+    assert_eq!("latitude 90.01 is not within ±90", e.to_string());
+    assert!(c.compress(pair(0.0, -180.5)).is_err());
+    assert!(c.compress(Value::List(vec![Value::Float(0.0)])).is_err());
+    assert!(c.compress(Value::Str("0,0".to_string())).is_err());
+    assert!(c.decompress(BitVec::from_elem(15 + 16, true)).is_err());
+    assert!(GeoCompressor::new(MAX_GEO_PRECISION + 1, GeoForm::Pair).is_err());
+  }
+
+  #[test]
+  fn parse_names() {
+    let parse = GeoCompressor::parse;
+    assert_eq!(Some((6, GeoForm::Pair)), parse("geo(precision=6)"));
+    assert_eq!(
+      Some((4, GeoForm::Record)),
+      parse("geo(precision=4, form=record)")
+    );
+    assert_eq!(None, parse("geo(precision=4, form=tuple)"));
+    assert_eq!(None, parse("float(precision=4)"));
+    assert_eq!(None, parse("geo"));
+  }
+}
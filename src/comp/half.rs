@@ -0,0 +1,145 @@
+use crate::bit::BitBuf;
+use crate::comp::*;
+
+/// A lossy compressor for floating point numbers which stores them as IEEE
+/// 754 half-precision floats (f16), most significant bit first.
+///
+/// Numbers are rounded to the nearest f16, with ties going to the one with an
+/// even mantissa, which keeps about 3 significant decimal digits. Numbers too
+/// large for an f16, beyond ±65504, can't be compressed. Values are widened
+/// back to `f64` when they are decompressed.
+pub struct HalfCompressor;
+
+/// The bits of the f16 nearest to `x`.
+pub(super) fn to_f16_bits(x: f64) -> u16 {
+  let bits = x.to_bits();
+  let sign = ((bits >> 48) & 0x8000) as u16;
+  let exp = ((bits >> 52) & 0x7ff) as i32;
+  let man = bits & 0x000f_ffff_ffff_ffff;
+  if exp == 0x7ff {
+    let nan = if man != 0 { 0x200 } else { 0 };
+    return sign | 0x7c00 | nan;
+  }
+
+  // The exponent of `x` as an f16, which is 0 or less for subnormals
+  let e = exp - 1023 + 15;
+  if e >= 0x1f {
+    return sign | 0x7c00;
+  }
+  if e < -10 {
+    return sign;
+  }
+  let (exp_bits, man, shift) = if e > 0 {
+    ((e as u64) << 10, man, 42)
+  } else {
+    // Subnormals keep the implicit leading bit, shifted 1 - e places more
+    (0, man | (1 << 52), (43 - e) as u32)
+  };
+  let half = exp_bits | (man >> shift);
+
+  // Carrying out of the mantissa while rounding increments the exponent,
+  // which is exactly what is needed
+  let rem = man & ((1 << shift) - 1);
+  let halfway = 1u64 << (shift - 1);
+  let rounded = if rem > halfway || (rem == halfway && half & 1 == 1) {
+    half + 1
+  } else {
+    half
+  };
+  sign | rounded as u16
+}
+
+/// The value of the f16 with the bits `h`.
+pub(super) fn from_f16_bits(h: u16) -> f64 {
+  let sign = ((h & 0x8000) as u64) << 48;
+  let exp = ((h >> 10) & 0x1f) as u64;
+  let man = (h & 0x3ff) as u64;
+  let magnitude = match exp {
+    // Subnormals are the mantissa times 2^-24
+    0 => man as f64 / 16_777_216.0,
+    0x1f => f64::from_bits((0x7ff << 52) | (man << 42)),
+    _ => f64::from_bits(((exp + 1023 - 15) << 52) | (man << 42)),
+  };
+  f64::from_bits(sign | magnitude.to_bits())
+}
+
+impl Compressor for HalfCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let x = match value {
+      Value::Int(i) => i as f64,
+      Value::UInt(u) => u as f64,
+      Value::Float(f) => f,
+      _ => return Err(unexpected_type(value, "number")),
+    };
+    let h = to_f16_bits(x);
+    if h & 0x7c00 == 0x7c00 {
+      bail!("{} is too large for an f16", x);
+    }
+    let mut buf = BitBuf::new();
+    buf.push_bits(h as u64, 16);
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.len() != 16 {
+      bail!("invalid bit sequence length");
+    }
+    let h = bits.iter().fold(0u16, |acc, b| (acc << 1) | b as u16);
+    Ok(Value::Float(from_f16_bits(h)))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Fixed(16)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn conversions() {
+    let cases = [
+      (0.0, 0x0000),
+      (-0.0, 0x8000),
+      (1.0, 0x3c00),
+      (-2.0, 0xc000),
+      (0.5, 0x3800),
+      (65504.0, 0x7bff),
+      (0.333251953125, 0x3555),
+      // The smallest subnormal and normal numbers
+      (5.960464477539063e-8, 0x0001),
+      (6.103515625e-5, 0x0400),
+    ];
+    for (x, h) in cases.iter() {
+      assert_eq!(*h, to_f16_bits(*x), "{}", x);
+      assert_eq!(*x, from_f16_bits(*h));
+    }
+
+    // Ties go to the even mantissa, including when rounding carries
+    assert_eq!(0x3c00, to_f16_bits(1.0 + 1.0 / 2048.0));
+    assert_eq!(0x3c02, to_f16_bits(1.0 + 3.0 / 2048.0));
+    assert_eq!(0x3c00, to_f16_bits(0.99999));
+    assert_eq!(0x0000, to_f16_bits(2.9802322387695312e-8));
+    assert_eq!(0x0001, to_f16_bits(2.9802322387695313e-8 * 1.5));
+    assert_eq!(0x7c00, to_f16_bits(65520.0));
+    assert_eq!(0x0000, to_f16_bits(1e-300));
+  }
+
+  #[test]
+  fn compress_rounds_to_f16() {
+    let bits = HalfCompressor.compress(Value::Float(1.2345)).unwrap();
+    assert_eq!(16, bits.len());
+    assert_eq!(
+      Value::Float(1.234375),
+      HalfCompressor.decompress(bits).unwrap()
+    );
+    let bits = HalfCompressor.compress(Value::Int(-7)).unwrap();
+    assert_eq!(Value::Float(-7.0), HalfCompressor.decompress(bits).unwrap());
+
+    assert!(HalfCompressor.compress(Value::Float(1e5)).is_err());
+    assert!(HalfCompressor
+      .compress(Value::Str("1".to_string()))
+      .is_err());
+  }
+}
@@ -0,0 +1,424 @@
+use crate::bit::BitBuf;
+use crate::comp::*;
+use crate::vie::CodePoint;
+use alloc::collections::BinaryHeap;
+use core::cmp::Reverse;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+/// The longest code a [`Codebook`] may assign to a byte.
+pub const MAX_CODE_LENGTH: usize = 32;
+
+/// A canonical Huffman code over the bytes of strings.
+///
+/// A canonical code is fully determined by the length of the code assigned to
+/// each byte: codes are handed out in order of their length and then of the
+/// byte they stand for. Only the lengths need to be stored, which makes a
+/// codebook's serialized form both small and unique. It is written as the
+/// longest code length, the number of codes of each length up to it as VIE
+/// code points and then the bytes which have codes in canonical order. In a
+/// schema file these bytes are written in hex.
+#[derive(Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Codebook {
+  /// The number of codes of each length, indexed by length.
+  counts: Vec<usize>,
+  /// The bytes which have codes in canonical order.
+  symbols: Vec<u8>,
+  /// The code of each byte along with its length, which is zero for bytes
+  /// without a code.
+  codes: Vec<(u64, usize)>,
+}
+
+impl Codebook {
+  /// Constructs the canonical code which assigns each byte a code of the
+  /// length given by `lengths`, where zero means the byte has no code.
+  ///
+  /// Fails if any length is longer than [`MAX_CODE_LENGTH`] or if there are
+  /// too many short codes for them all to be unique.
+  pub fn from_lengths(lengths: &[u8; 256]) -> Result<Self> {
+    let mut counts = vec![0; MAX_CODE_LENGTH + 1];
+    for &len in lengths.iter().filter(|len| **len > 0) {
+      if len as usize > MAX_CODE_LENGTH {
+        bail!(
+          "code length {} is longer than the maximum of {}",
+          len,
+          MAX_CODE_LENGTH
+        );
+      }
+      counts[len as usize] += 1;
+    }
+    let max = counts.iter().rposition(|n| *n > 0).unwrap_or(0);
+    counts.truncate(max + 1);
+
+    // Each code of length `len` uses up 2^(max - len) of the 2^max codes of
+    // the longest length
+    let used: u64 = (1..counts.len())
+      .map(|len| (counts[len] as u64) << (max - len))
+      .sum();
+    if used > 1 << max {
+      bail!("code lengths assign more codes than there are");
+    }
+
+    let mut symbols: Vec<u8> =
+      (0..=255u8).filter(|b| lengths[*b as usize] > 0).collect();
+    symbols.sort_by_key(|b| lengths[*b as usize]);
+
+    let mut codes = vec![(0, 0); 256];
+    let (mut code, mut prev) = (0u64, 0);
+    for &b in &symbols {
+      let len = lengths[b as usize] as usize;
+      code <<= len - prev;
+      codes[b as usize] = (code, len);
+      code += 1;
+      prev = len;
+    }
+    Ok(Codebook {
+      counts,
+      symbols,
+      codes,
+    })
+  }
+
+  /// Trains a code on sample values, giving the bytes which occur most often
+  /// the shortest codes.
+  ///
+  /// Bytes which don't occur in any sample don't get a code, so values which
+  /// contain them can't be compressed with the trained code.
+  pub fn train<I>(samples: I) -> Self
+  where
+    I: IntoIterator,
+    I::Item: AsRef<[u8]>,
+  {
+    let mut weights = [0u64; 256];
+    for sample in samples {
+      for b in sample.as_ref() {
+        weights[*b as usize] += 1;
+      }
+    }
+    loop {
+      let mut lengths = [0u8; 256];
+      lengths.copy_from_slice(&code_lengths(&weights));
+      if let Ok(book) = Codebook::from_lengths(&lengths) {
+        return book;
+      }
+      // Flattening the distribution shortens the longest codes
+      for w in weights.iter_mut().filter(|w| **w > 0) {
+        *w = math::div_ceil(*w, 2);
+      }
+    }
+  }
+
+  /// The code of byte `b` and its length, or `None` if it has no code.
+  pub fn code(&self, b: u8) -> Option<(u64, usize)> {
+    Some(self.codes[b as usize]).filter(|(_, len)| *len > 0)
+  }
+
+  /// Writes the code of each of `bytes` to `buf`.
+  ///
+  /// Fails if any byte has no code.
+  pub(super) fn write_codes(
+    &self,
+    bytes: &[u8],
+    buf: &mut BitBuf,
+  ) -> Result<()> {
+    for b in bytes {
+      let (code, len) = self.code(*b).ok_or_else(|| {
+        anyhow!("byte {:#04x} has no code in the codebook", b)
+      })?;
+      buf.push_bits(code, len);
+    }
+    Ok(())
+  }
+
+  /// Reads the bytes of the codes in `bits` until it runs out.
+  pub(super) fn read_codes<I>(&self, bits: I) -> Result<Vec<u8>>
+  where
+    I: Iterator<Item = bool>,
+  {
+    let counts = &self.counts;
+    let mut bytes = Vec::new();
+    let mut bits = bits.peekable();
+    while bits.peek().is_some() {
+      // Canonical codes of each length are consecutive, starting at `first`,
+      // and their bytes are consecutive in `symbols` starting at `index`
+      let (mut code, mut first, mut index) = (0u64, 0u64, 0usize);
+      let mut len = 1;
+      loop {
+        if len >= counts.len() {
+          bail!("invalid code in bit sequence");
+        }
+        let bit = bits
+          .next()
+          .ok_or_else(|| anyhow!("bit sequence ends within a code"))?;
+        code |= bit as u64;
+        let count = counts[len] as u64;
+        if code < first + count {
+          bytes.push(self.symbols[index + (code - first) as usize]);
+          break;
+        }
+        index += count as usize;
+        first = (first + count) << 1;
+        code <<= 1;
+        len += 1;
+      }
+    }
+    Ok(bytes)
+  }
+
+  /// The serialized form of this codebook.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let max = self.counts.len() - 1;
+    let mut bytes = vec![max as u8];
+    for count in &self.counts[1..] {
+      bytes.extend_from_slice(CodePoint::from(*count as u64).bytes());
+    }
+    bytes.extend_from_slice(&self.symbols);
+    bytes
+  }
+
+  /// Parses the serialized form of a codebook.
+  ///
+  /// Fails if `bytes` isn't a valid codebook or doesn't hold exactly one.
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    let (&max, mut rest) = bytes
+      .split_first()
+      .ok_or_else(|| anyhow!("codebook is empty"))?;
+    if max as usize > MAX_CODE_LENGTH {
+      bail!("codebook has codes longer than {}", MAX_CODE_LENGTH);
+    }
+
+    let mut lengths = [0u8; 256];
+    let mut counts = Vec::new();
+    for _ in 0..max {
+      let cp = CodePoint::parse(rest)
+        .ok_or_else(|| anyhow!("codebook is truncated"))?;
+      rest = &rest[cp.count()..];
+      let count = cp
+        .decode::<u64>()
+        .filter(|n| *n <= 256)
+        .ok_or_else(|| anyhow!("codebook has too many codes"))?;
+      counts.push(count as usize);
+    }
+    let total: usize = counts.iter().sum();
+    if total != rest.len() {
+      bail!("codebook has {} codes but {} bytes", total, rest.len());
+    }
+
+    let mut symbols = rest.iter();
+    for (i, count) in counts.iter().enumerate() {
+      let start = symbols.as_slice();
+      for &b in symbols.by_ref().take(*count) {
+        if lengths[b as usize] != 0 {
+          bail!("byte {:#04x} has more than one code", b);
+        }
+        lengths[b as usize] = i as u8 + 1;
+      }
+      if !start[..*count].windows(2).all(|w| w[0] < w[1]) {
+        bail!("codebook bytes are not in canonical order");
+      }
+    }
+
+    let book = Codebook::from_lengths(&lengths)?;
+    if book.counts.len() != counts.len() + 1 {
+      bail!("codebook has no codes of its longest length");
+    }
+    Ok(book)
+  }
+}
+
+impl fmt::Display for Codebook {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    for b in self.to_bytes() {
+      write!(f, "{:02x}", b)?;
+    }
+    Ok(())
+  }
+}
+
+impl core::str::FromStr for Codebook {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    if s.len() % 2 != 0 || !s.is_ascii() {
+      bail!("codebook is not a hex string");
+    }
+    let bytes = (0..s.len())
+      .step_by(2)
+      .map(|i| u8::from_str_radix(&s[i..i + 2], 16))
+      .collect::<core::result::Result<Vec<_>, _>>()
+      .map_err(|_| anyhow!("codebook is not a hex string"))?;
+    Codebook::from_bytes(&bytes)
+  }
+}
+
+impl TryFrom<String> for Codebook {
+  type Error = anyhow::Error;
+
+  fn try_from(s: String) -> Result<Self> {
+    s.parse()
+  }
+}
+
+impl From<Codebook> for String {
+  fn from(book: Codebook) -> Self {
+    book.to_string()
+  }
+}
+
+/// Computes the lengths of a Huffman code for symbols with the given weights.
+///
+/// Symbols with a weight of zero don't get a code. Ties are broken by the
+/// order in which nodes are created, so the lengths are deterministic.
+pub(super) fn code_lengths(weights: &[u64]) -> Vec<u8> {
+  let mut lengths = vec![0u8; weights.len()];
+  let leaves: Vec<usize> =
+    (0..weights.len()).filter(|b| weights[*b] > 0).collect();
+  if leaves.len() == 1 {
+    // A lone symbol still needs a code of at least one bit
+    lengths[leaves[0]] = 1;
+    return lengths;
+  }
+
+  let mut parents = vec![usize::MAX; leaves.len()];
+  let mut heap: BinaryHeap<_> = leaves
+    .iter()
+    .enumerate()
+    .map(|(node, b)| Reverse((weights[*b], node)))
+    .collect();
+  while let (Some(Reverse((a, x))), Some(Reverse((b, y)))) =
+    (heap.pop(), heap.pop())
+  {
+    let node = parents.len();
+    parents.push(usize::MAX);
+    parents[x] = node;
+    parents[y] = node;
+    heap.push(Reverse((a + b, node)));
+  }
+
+  for (node, b) in leaves.iter().enumerate() {
+    let (mut depth, mut n) = (0usize, node);
+    while parents[n] != usize::MAX {
+      depth += 1;
+      n = parents[n];
+    }
+    lengths[*b] = depth.min(u8::MAX as usize) as u8;
+  }
+  lengths
+}
+
+/// Compressor for strings which replaces each byte with its code in a
+/// [`Codebook`].
+pub struct HuffmanCompressor {
+  pub book: Codebook,
+}
+
+impl Compressor for HuffmanCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let s = match value {
+      Value::Str(s) => s,
+      _ => return Err(unexpected_type(value, "string")),
+    };
+    let mut buf = BitBuf::new();
+    self.book.write_codes(s.as_bytes(), &mut buf)?;
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    let bytes = self.book.read_codes(bits.iter())?;
+    let s = String::from_utf8(bytes)?;
+    Ok(Value::Str(s))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+
+  fn compressed_len(&self, value: Value) -> Result<usize> {
+    let s = match value {
+      Value::Str(s) => s,
+      _ => return Err(unexpected_type(value, "string")),
+    };
+    s.bytes()
+      .map(|b| {
+        self
+          .book
+          .code(b)
+          .map(|(_, len)| len)
+          .ok_or_else(|| anyhow!("byte {:#04x} has no code in the codebook", b))
+      })
+      .sum()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn str(s: &str) -> Value {
+    Value::Str(s.to_string())
+  }
+
+  #[test]
+  fn trained_code_roundtrip() {
+    let book = Codebook::train(&["hello world", "help", "yellow"]);
+    // 'l' is the most common byte so it has the shortest code
+    let (_, l) = book.code(b'l').unwrap();
+    assert!((0..=255u8).filter_map(|b| book.code(b)).all(|c| c.1 >= l));
+    assert!(book.code(b'z').is_none());
+
+    let c = HuffmanCompressor { book };
+    for s in &["", "hello", "yellow world"] {
+      let bits = c.compress(str(s)).unwrap();
+      assert_eq!(bits.len(), c.compressed_len(str(s)).unwrap());
+      assert_eq!(str(s), c.decompress(bits).unwrap());
+    }
+    assert!(c.compress(str("zebra")).is_err());
+  }
+
+  #[test]
+  fn canonical_codes() {
+    let mut lengths = [0u8; 256];
+    lengths[b'a' as usize] = 1;
+    lengths[b'b' as usize] = 2;
+    lengths[b'c' as usize] = 3;
+    lengths[b'd' as usize] = 3;
+    let book = Codebook::from_lengths(&lengths).unwrap();
+    assert_eq!(Some((0b0, 1)), book.code(b'a'));
+    assert_eq!(Some((0b10, 2)), book.code(b'b'));
+    assert_eq!(Some((0b110, 3)), book.code(b'c'));
+    assert_eq!(Some((0b111, 3)), book.code(b'd'));
+    assert_eq!("0301010261626364", book.to_string());
+    assert_eq!(book, book.to_string().parse().unwrap());
+
+    // Three codes of length 1 can't all be unique
+    lengths[b'b' as usize] = 1;
+    lengths[b'c' as usize] = 1;
+    assert!(Codebook::from_lengths(&lengths).is_err());
+  }
+
+  #[test]
+  fn invalid_serialized_codebooks() {
+    let e = |s: &str| s.parse::<Codebook>().unwrap_err().to_string();
+    assert_eq!("codebook is empty", e(""));
+    assert_eq!("codebook is not a hex string", e("0g"));
+    assert_eq!("codebook has 2 codes but 1 bytes", e("02010161"));
+    assert_eq!("byte 0x61 has more than one code", e("0201016161"));
+    assert_eq!("codebook bytes are not in canonical order", e("01026261"));
+    assert_eq!("codebook has no codes of its longest length", e("02010061"));
+
+    // 'a' is 0 and 'b' is 10, leaving 11 unused
+    let c = HuffmanCompressor {
+      book: "0201016162".parse().unwrap(),
+    };
+    let bits = |s: &[bool]| s.iter().copied().collect::<BitVec>();
+    assert_eq!(
+      str("ab"),
+      c.decompress(bits(&[false, true, false])).unwrap()
+    );
+    let e = c.decompress(bits(&[true, true])).unwrap_err();
+    assert_eq!("invalid code in bit sequence", e.to_string());
+    let e = c.decompress(bits(&[true])).unwrap_err();
+    assert_eq!("bit sequence ends within a code", e.to_string());
+  }
+}
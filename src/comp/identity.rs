@@ -13,7 +13,7 @@ impl Compressor for IdentityCompressor {
       Value::Str(s) => {
         let b = BitVec::from_bytes(s.as_bytes());
         Ok(b)
-      },
+      }
       _ => Err(unexpected_type(value, "string")),
     }
   }
@@ -30,4 +30,11 @@ impl Compressor for IdentityCompressor {
   fn encoded_width(&self) -> EncodedWidth {
     EncodedWidth::Variable
   }
+
+  fn compressed_len(&self, value: Value) -> Result<usize> {
+    match value {
+      Value::Str(s) => Ok(s.len() * 8),
+      _ => Err(unexpected_type(value, "string")),
+    }
+  }
 }
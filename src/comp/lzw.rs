@@ -0,0 +1,150 @@
+use crate::bit::BitBuf;
+use crate::comp::*;
+
+/// The most codes an [`LzwCompressor`] dictionary holds, which caps codes at
+/// 12 bits.
+const MAX_CODES: usize = 4096;
+
+/// A compressor for long strings which replaces repeated substrings with
+/// codes using the Lempel-Ziv-Welch algorithm.
+///
+/// The dictionary starts out with every byte and gains one substring for
+/// each code written until it holds [`MAX_CODES`]. Codes take up the fewest
+/// bits which can hold every code in the dictionary at that point. Each value
+/// starts from a fresh dictionary so values can still be decoded on their
+/// own, which means short values are usually larger than they started out.
+pub struct LzwCompressor;
+
+/// The width of the code at `index` within a value.
+fn code_width(index: usize) -> usize {
+  math::required_bit_width((256 + index).min(MAX_CODES))
+}
+
+impl Compressor for LzwCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let s = match value {
+      Value::Str(s) => s,
+      _ => return Err(unexpected_type(value, "string")),
+    };
+
+    // Substrings are keyed by the code of their prefix and their last byte
+    let mut dict = BTreeMap::new();
+    let mut buf = BitBuf::new();
+    let mut index = 0;
+    let mut bytes = s.bytes();
+    let mut code = match bytes.next() {
+      Some(b) => b as usize,
+      None => return Ok(BitVec::new()),
+    };
+    for b in bytes {
+      if let Some(next) = dict.get(&(code, b)) {
+        code = *next;
+        continue;
+      }
+      buf.push_bits(code as u64, code_width(index));
+      if 256 + dict.len() < MAX_CODES {
+        dict.insert((code, b), 256 + dict.len());
+      }
+      index += 1;
+      code = b as usize;
+    }
+    buf.push_bits(code as u64, code_width(index));
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    let mut dict: Vec<Vec<u8>> = (0..=255u8).map(|b| vec![b]).collect();
+    let mut out = Vec::new();
+    let mut prev: Option<Vec<u8>> = None;
+    let mut pos = 0;
+    let mut index = 0;
+    while pos < bits.len() {
+      let width = code_width(index);
+      if pos + width > bits.len() {
+        bail!("invalid bit sequence length");
+      }
+      let code = bits
+        .iter()
+        .skip(pos)
+        .take(width)
+        .fold(0, |acc, b| (acc << 1) | b as usize);
+      pos += width;
+      index += 1;
+
+      let entry = match (dict.get(code), &prev) {
+        (Some(entry), _) => entry.clone(),
+        // The code being defined by this very step
+        (None, Some(prev)) if code == dict.len() => {
+          let mut entry = prev.clone();
+          entry.push(prev[0]);
+          entry
+        }
+        _ => bail!("invalid code {}", code),
+      };
+      if let Some(mut prev) = prev {
+        if dict.len() < MAX_CODES {
+          prev.push(entry[0]);
+          dict.push(prev);
+        }
+      }
+      out.extend_from_slice(&entry);
+      prev = Some(entry);
+    }
+    Ok(Value::Str(String::from_utf8(out)?))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn roundtrip(s: &str) -> usize {
+    let bits = LzwCompressor.compress(Value::Str(s.to_string())).unwrap();
+    let len = bits.len();
+    assert_eq!(
+      Value::Str(s.to_string()),
+      LzwCompressor.decompress(bits).unwrap()
+    );
+    len
+  }
+
+  #[test]
+  fn strings_roundtrip() {
+    assert_eq!(0, roundtrip(""));
+    assert_eq!(8, roundtrip("a"));
+    // The second "aa" refers to the code which is being defined
+    roundtrip("aaaaaaa");
+    roundtrip("TOBEORNOTTOBEORTOBEORNOT");
+    roundtrip("naïve café ☕ naïve café ☕");
+  }
+
+  #[test]
+  fn repetitive_text_shrinks() {
+    let text = "the quick brown fox jumps over the lazy dog. ".repeat(40);
+    assert!(roundtrip(&text) < text.len() * 8 / 3);
+  }
+
+  #[test]
+  fn full_dictionary() {
+    // Enough distinct substrings to fill the dictionary
+    let text: String = (0..20_000u32)
+      .map(|i| char::from(b'a' + ((i * i + i / 7) % 26) as u8))
+      .collect();
+    roundtrip(&text);
+  }
+
+  #[test]
+  fn invalid_codes() {
+    let mut buf = BitBuf::new();
+    buf.push_bits(b'a' as u64, 8);
+    buf.push_bits(300, 9);
+    assert!(LzwCompressor.decompress(buf.into()).is_err());
+    assert!(LzwCompressor
+      .decompress(BitVec::from_elem(5, false))
+      .is_err());
+  }
+}
@@ -0,0 +1,118 @@
+use crate::comp::*;
+use crate::vie::CodePoint;
+
+/// The most decimal places a [`QuantizedCompressor`] may keep, which is about
+/// as many as an `f64` can hold.
+pub const MAX_PRECISION: u32 = 15;
+
+/// A lossy compressor for floating point numbers which rounds them to
+/// `precision` decimal places, e.g., 2 for metrics where hundredths are all
+/// that matter.
+///
+/// Rounded numbers are multiplied by 10^`precision` and stored as a signed
+/// VIE [code point](CodePoint), so a number like 19.99 takes up 2 bytes
+/// instead of the 8 it would as an `f64`. Values are always decompressed as
+/// floating point numbers.
+pub struct QuantizedCompressor {
+  precision: u32,
+  scaled: ScaledCompressor,
+}
+
+impl QuantizedCompressor {
+  /// Constructs a compressor which keeps `precision` decimal places.
+  ///
+  /// Fails if `precision` is more than [`MAX_PRECISION`].
+  pub fn new(precision: u32) -> Result<Self> {
+    if precision > MAX_PRECISION {
+      bail!(
+        "float precision must be at most {}, not {}",
+        MAX_PRECISION,
+        precision
+      );
+    }
+    let scale = (0..precision).fold(1.0, |scale, _| scale * 10.0);
+    Ok(QuantizedCompressor {
+      precision,
+      scaled: ScaledCompressor {
+        scale,
+        round: Rounding::Nearest,
+      },
+    })
+  }
+
+  /// The number of decimal places kept.
+  pub fn precision(&self) -> u32 {
+    self.precision
+  }
+
+  /// Parses the precision of a type named `float(precision=N)`, returning
+  /// `None` if `name` isn't of that form.
+  pub fn parse_precision(name: &str) -> Option<u32> {
+    match parse_params(name)? {
+      ("float", params) => match params[..] {
+        [("precision", precision)] => precision.parse().ok(),
+        _ => None,
+      },
+      _ => None,
+    }
+  }
+}
+
+impl Compressor for QuantizedCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let i = self.scaled.scaled(value)?;
+    Ok(BitVec::from_bytes(CodePoint::from_signed(i).bytes()))
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.len() % 8 != 0 {
+      bail!("unable to convert bit sequence to bytes");
+    }
+    let i = CodePoint::parse(&bits.to_bytes())
+      .and_then(|cp| cp.decode_signed::<i64>())
+      .ok_or_else(|| anyhow!("invalid code point in bit sequence"))?;
+    Ok(Value::Float(i as f64 / self.scaled.scale))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn rounds_to_precision() {
+    let c = QuantizedCompressor::new(2).unwrap();
+    let bits = c.compress(Value::Float(19.987)).unwrap();
+    // 1999 zigzag encoded is 3998, which needs 2 bytes
+    assert_eq!(16, bits.len());
+    assert_eq!(Value::Float(19.99), c.decompress(bits).unwrap());
+
+    let bits = c.compress(Value::Int(-3)).unwrap();
+    assert_eq!(16, bits.len());
+    assert_eq!(Value::Float(-3.0), c.decompress(bits).unwrap());
+
+    let c = QuantizedCompressor::new(0).unwrap();
+    let bits = c.compress(Value::Float(0.4)).unwrap();
+    assert_eq!(8, bits.len());
+    assert_eq!(Value::Float(0.0), c.decompress(bits).unwrap());
+
+    assert!(QuantizedCompressor::new(MAX_PRECISION + 1).is_err());
+    assert!(c.decompress(BitVec::from_bytes(&[0x80])).is_err());
+  }
+
+  #[test]
+  fn parse_precision() {
+    let parse = QuantizedCompressor::parse_precision;
+    assert_eq!(Some(2), parse("float(precision=2)"));
+    assert_eq!(Some(16), parse("float(precision=16)"));
+    assert_eq!(None, parse("float(precision=-1)"));
+    assert_eq!(Some(3), parse("float( precision = 3 )"));
+    assert_eq!(None, parse("float(2)"));
+    assert_eq!(None, parse("float(precision=2,scale=3)"));
+    assert_eq!(None, parse("float"));
+  }
+}
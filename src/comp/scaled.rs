@@ -0,0 +1,171 @@
+use crate::bit::BitBuf;
+use crate::comp::*;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+
+/// How a [`ScaledCompressor`] turns scaled values which aren't whole into
+/// integers.
+///
+/// Scaling is done with floating point numbers, so values within a rounding
+/// error of a whole number are taken to be that number whatever the policy,
+/// e.g., 12.34 dollars are always 1234 cents even though `12.34 * 100.0` is a
+/// little less than 1234.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rounding {
+  /// Rounds to the nearest integer, with halves rounding up.
+  Nearest,
+  /// Rounds down.
+  Floor,
+  /// Rounds up.
+  Ceil,
+  /// Fails to compress values which aren't whole once scaled.
+  Exact,
+}
+
+impl Rounding {
+  /// The name of the policy in a schema file.
+  pub fn name(self) -> &'static str {
+    match self {
+      Rounding::Nearest => "nearest",
+      Rounding::Floor => "floor",
+      Rounding::Ceil => "ceil",
+      Rounding::Exact => "exact",
+    }
+  }
+}
+
+impl fmt::Display for Rounding {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+/// A compressor for numbers of a fixed granularity, which are multiplied by
+/// `scale` and stored as integers, e.g., dollars stored as cents with a scale
+/// of 100.
+///
+/// Integers are zigzag encoded so that small negative numbers are small too,
+/// and take up as few bits as they need, most significant bit first. Zero
+/// takes up no bits at all. Values are decompressed by dividing by `scale`,
+/// so they are always floating point numbers.
+pub struct ScaledCompressor {
+  pub scale: f64,
+  pub round: Rounding,
+}
+
+/// Scaled values must be smaller than this, 2^62, to be zigzag encoded as a
+/// `u64`.
+const LIMIT: f64 = 4_611_686_018_427_387_904.0;
+
+impl ScaledCompressor {
+  /// Scales `value` and rounds it to an integer.
+  pub(super) fn scaled(&self, value: Value) -> Result<i64> {
+    let x = match value {
+      Value::Int(i) => i as f64,
+      Value::UInt(u) => u as f64,
+      Value::Float(f) => f,
+      _ => return Err(unexpected_type(value, "number")),
+    } * self.scale;
+    // Written so that NaN fails the check too
+    if !(-LIMIT < x && x < LIMIT) {
+      bail!("{} is too large to scale by {}", x / self.scale, self.scale);
+    }
+
+    let nearest = floor(x + 0.5);
+    let error = x - nearest as f64;
+    let magnitude = if x < 0.0 { -x } else { x };
+    let tolerance = 1e-9 * if magnitude > 1.0 { magnitude } else { 1.0 };
+    if -tolerance <= error && error <= tolerance {
+      return Ok(nearest);
+    }
+    match self.round {
+      Rounding::Nearest => Ok(nearest),
+      Rounding::Floor => Ok(floor(x)),
+      Rounding::Ceil => Ok(floor(x) + 1),
+      Rounding::Exact => bail!(
+        "{} is not a multiple of {}",
+        x / self.scale,
+        1.0 / self.scale
+      ),
+    }
+  }
+}
+
+/// Rounds `x` down, which must be within [`LIMIT`].
+fn floor(x: f64) -> i64 {
+  // Casting rounds towards zero
+  let t = x as i64;
+  if (t as f64) > x {
+    t - 1
+  } else {
+    t
+  }
+}
+
+impl Compressor for ScaledCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let i = self.scaled(value)?;
+    let zigzag = ((i << 1) ^ (i >> 63)) as u64;
+    let width = 64 - zigzag.leading_zeros() as usize;
+    let mut buf = BitBuf::new();
+    buf.push_bits(zigzag, width);
+    Ok(buf.into())
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.len() > 64 {
+      bail!("invalid bit sequence length");
+    }
+    let zigzag = bits.iter().fold(0u64, |acc, b| (acc << 1) | b as u64);
+    let i = (zigzag >> 1) as i64 ^ -((zigzag & 1) as i64);
+    Ok(Value::Float(i as f64 / self.scale))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn compressor(scale: f64, round: Rounding) -> ScaledCompressor {
+    ScaledCompressor { scale, round }
+  }
+
+  #[test]
+  fn dollars_as_cents() {
+    let c = compressor(100.0, Rounding::Exact);
+    let bits = c.compress(Value::Float(12.34)).unwrap();
+    // 1234 zigzag encoded is 2468, which needs 12 bits
+    assert_eq!(12, bits.len());
+    assert_eq!(Value::Float(12.34), c.decompress(bits).unwrap());
+
+    let bits = c.compress(Value::Float(-0.01)).unwrap();
+    assert_eq!(1, bits.len());
+    assert_eq!(Value::Float(-0.01), c.decompress(bits).unwrap());
+
+    assert_eq!(0, c.compress(Value::Int(0)).unwrap().len());
+    assert!(c.compress(Value::Float(0.001)).is_err());
+    assert!(c.compress(Value::Float(f64::NAN)).is_err());
+    assert!(c.compress(Value::Float(1e18)).is_err());
+    assert!(c.compress(Value::Str("1".to_string())).is_err());
+  }
+
+  #[test]
+  fn rounding_policies() {
+    let scaled = |round, value| {
+      let c = compressor(1000.0, round);
+      c.decompress(c.compress(Value::Float(value)).unwrap())
+        .unwrap()
+    };
+    assert_eq!(Value::Float(1.5), scaled(Rounding::Nearest, 1.4996));
+    assert_eq!(Value::Float(1.499), scaled(Rounding::Floor, 1.4996));
+    assert_eq!(Value::Float(-1.5), scaled(Rounding::Floor, -1.4996));
+    assert_eq!(Value::Float(1.5), scaled(Rounding::Ceil, 1.4991));
+    assert_eq!(Value::Float(0.3), scaled(Rounding::Floor, 0.1 + 0.2));
+    assert_eq!(Value::Float(42.0), scaled(Rounding::Exact, 42.0));
+  }
+}
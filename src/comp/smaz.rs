@@ -0,0 +1,186 @@
+use crate::comp::*;
+
+/// Code of a single byte which isn't in the fragment table.
+const VERBATIM_BYTE: u8 = 254;
+
+/// Code of a run of up to 256 bytes which aren't in the fragment table,
+/// followed by the length of the run minus one.
+const VERBATIM_RUN: u8 = 255;
+
+/// The fragments which can be replaced with a single byte, tuned for names,
+/// places and other short labels. A fragment's code is its index.
+const FRAGMENTS: &[&str] = &[
+  // Single characters
+  " ", "e", "t", "a", "o", "i", "n", "s", "r", "h", "l", "d", "c", "u", "m",
+  "f", "p", "g", "w", "y", "b", "v", "k", "x", "j", "q", "z", "A", "B", "C",
+  "D", "E", "F", "G", "H", "I", "J", "K", "L", "M", "N", "O", "P", "Q", "R",
+  "S", "T", "U", "V", "W", "X", "Y", "Z", ".", ",", "-", "'", "(", ")", "/",
+  ":", "0", "1", "2", "3", "4", "5", "6", "7", "8", "9",
+  // Common pairs of letters
+  "th", "he", "in", "er", "an", "re", "on", "at", "en", "nd", "ti", "es", "or",
+  "te", "of", "ed", "is", "it", "al", "ar", "st", "to", "nt", "ng", "se", "ha",
+  "as", "ou", "io", "le", "ve", "co", "me", "de", "hi", "ri", "ro", "ic", "ne",
+  "ea", "ra", "ce", "li", "ch", "ll", "be", "ma", "si", "om", "ur", "el", "la",
+  "na", "ta", "ie", "ia", "ee", "oo", "ss",
+  // Common runs of three or more letters
+  "the", "and", "ing", "ion", "tio", "ent", "ati", "for", "her", "ter", "hat",
+  "tha", "ere", "ate", "his", "con", "res", "ver", "all", "ons", "nce", "men",
+  "ith", "ted", "ers", "pro", "thi", "wit", "are", "ess", "not", "ive", "was",
+  "ect", "rea", "com", "eve", "per", "int", "est", "sta", "cti", "ica", "ist",
+  "ear", "ain", "one", "our", "iti", "rat", "ell", "ant", "ill", "ort", "ton",
+  "son", "ber", "ley", "ville", "ford", "land", "burg", "berg", "field",
+  "port", "wood", "town", "ington", "ston",
+  // Words and the ends of words, with their spaces
+  "San ", "New ", "Saint ", "St. ", "an ", "er ", "the ", "of ", "in ", "ing ",
+  "ed ", "es ", "on ", "s ", "e ", "d ", "t ", "y ", ", ", ". ", "and ", "Mc",
+  "Mac", "Van ", "de ", "la ", "el ", "Los ", "Las ", "Le ", "La ",
+];
+
+/// A compressor for short strings, like names and labels, which replaces
+/// common fragments of text with single byte codes from a fixed table in the
+/// style of [smaz](https://github.com/antirez/smaz).
+///
+/// Strings are split into the longest fragments in the table, from left to
+/// right. Bytes which don't start a fragment are copied as is after a code
+/// marking them as verbatim. As the table is fixed there is nothing to store
+/// alongside the codes, so short strings made up of common fragments come
+/// out smaller, unlike with [`HuffmanCompressor`] or [`LzwCompressor`].
+pub struct SmazCompressor;
+
+/// The code and length of the longest fragment at the start of `bytes`.
+fn longest_fragment(bytes: &[u8]) -> Option<(u8, usize)> {
+  FRAGMENTS
+    .iter()
+    .enumerate()
+    .filter(|(_, f)| bytes.starts_with(f.as_bytes()))
+    .max_by_key(|(_, f)| f.len())
+    .map(|(code, f)| (code as u8, f.len()))
+}
+
+/// Writes a run of verbatim bytes to `out`.
+fn push_verbatim(run: &[u8], out: &mut Vec<u8>) {
+  for chunk in run.chunks(256) {
+    if chunk.len() == 1 {
+      out.push(VERBATIM_BYTE);
+    } else {
+      out.push(VERBATIM_RUN);
+      out.push((chunk.len() - 1) as u8);
+    }
+    out.extend_from_slice(chunk);
+  }
+}
+
+impl Compressor for SmazCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let s = match value {
+      Value::Str(s) => s,
+      _ => return Err(unexpected_type(value, "string")),
+    };
+
+    let bytes = s.as_bytes();
+    let mut out = Vec::new();
+    let mut run_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+      match longest_fragment(&bytes[i..]) {
+        Some((code, len)) => {
+          push_verbatim(&bytes[run_start..i], &mut out);
+          out.push(code);
+          i += len;
+          run_start = i;
+        }
+        None => i += 1,
+      }
+    }
+    push_verbatim(&bytes[run_start..], &mut out);
+    Ok(BitVec::from_bytes(&out))
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.len() % 8 != 0 {
+      bail!("unable to convert bit sequence to bytes");
+    }
+    let bytes = bits.to_bytes();
+    let mut out = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+      let code = bytes[i];
+      let (start, len) = match code {
+        VERBATIM_BYTE => (i + 1, 1),
+        VERBATIM_RUN => match bytes.get(i + 1) {
+          Some(len) => (i + 2, *len as usize + 1),
+          None => bail!("truncated verbatim run"),
+        },
+        _ => {
+          let fragment = FRAGMENTS
+            .get(code as usize)
+            .ok_or_else(|| anyhow!("invalid fragment code {}", code))?;
+          out.extend_from_slice(fragment.as_bytes());
+          i += 1;
+          continue;
+        }
+      };
+      let verbatim = bytes
+        .get(start..start + len)
+        .ok_or_else(|| anyhow!("truncated verbatim run"))?;
+      out.extend_from_slice(verbatim);
+      i = start + len;
+    }
+    Ok(Value::Str(String::from_utf8(out)?))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn roundtrip(s: &str) -> usize {
+    let bits = SmazCompressor.compress(Value::Str(s.to_string())).unwrap();
+    let len = bits.len() / 8;
+    assert_eq!(
+      Value::Str(s.to_string()),
+      SmazCompressor.decompress(bits).unwrap()
+    );
+    len
+  }
+
+  #[test]
+  fn fragment_table() {
+    assert!(FRAGMENTS.len() <= VERBATIM_BYTE as usize);
+    for (i, f) in FRAGMENTS.iter().enumerate() {
+      assert!(!f.is_empty());
+      assert!(!FRAGMENTS[i + 1..].contains(f), "{} is repeated", f);
+    }
+  }
+
+  #[test]
+  fn short_strings_shrink() {
+    assert_eq!(0, roundtrip(""));
+    assert!(roundtrip("San Francisco") < "San Francisco".len());
+    assert!(roundtrip("the station") < "the station".len());
+    assert!(roundtrip("Jeremy Schwartz") < "Jeremy Schwartz".len());
+  }
+
+  #[test]
+  fn verbatim_bytes() {
+    // "ü" is two bytes long, and "rich" is "ri" followed by "ch"
+    assert_eq!(1 + 4 + 2, roundtrip("Zürich"));
+    assert_eq!(2 + 9, roundtrip("東京都"));
+    let long = "€".repeat(100);
+    assert_eq!(2 + 256 + 2 + 44, roundtrip(&long));
+  }
+
+  #[test]
+  fn invalid_codes() {
+    let invalid: &[&[u8]] = &[&[250], &[VERBATIM_BYTE], &[VERBATIM_RUN, 2, 0]];
+    for bytes in invalid {
+      assert!(SmazCompressor
+        .decompress(BitVec::from_bytes(bytes))
+        .is_err());
+    }
+  }
+}
@@ -0,0 +1,53 @@
+use crate::comp::*;
+use crate::vie::CodePoint;
+
+/// A compressor for integer timestamps, in whatever unit they are given,
+/// which stores them as signed VIE [code points](CodePoint).
+///
+/// Absolute timestamps are large numbers which take up 5 bytes as seconds or
+/// 6 as milliseconds. A schema with an [epoch](crate::epoch) stores them
+/// relative to it instead, where they are small.
+pub struct TimestampCompressor;
+
+impl Compressor for TimestampCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    let i = match value {
+      Value::Int(i) => i,
+      Value::UInt(u) if u <= i64::MAX as u64 => u as i64,
+      _ => return Err(unexpected_type(value, "an integer timestamp")),
+    };
+    Ok(BitVec::from_bytes(CodePoint::from_signed(i).bytes()))
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    if bits.len() % 8 != 0 {
+      bail!("unable to convert bit sequence to bytes");
+    }
+    let i = CodePoint::parse(&bits.to_bytes())
+      .and_then(|cp| cp.decode_signed::<i64>())
+      .ok_or_else(|| anyhow!("invalid code point in bit sequence"))?;
+    Ok(Value::Int(i))
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    EncodedWidth::Variable
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn timestamps_roundtrip() {
+    for (t, bytes) in &[(1_600_000_000_000, 6), (-90, 2), (0, 1)] {
+      let bits = TimestampCompressor.compress(Value::Int(*t)).unwrap();
+      assert_eq!(bytes * 8, bits.len());
+      assert_eq!(
+        Value::Int(*t),
+        TimestampCompressor.decompress(bits).unwrap()
+      );
+    }
+    assert!(TimestampCompressor.compress(Value::Float(1.5)).is_err());
+  }
+}
@@ -0,0 +1,177 @@
+use crate::comp::*;
+use core::fmt;
+use serde::{Deserialize, Serialize};
+use unicode_normalization::UnicodeNormalization;
+
+/// A change made to values before they are compressed.
+///
+/// Transforms let a compressor see values in a canonical form, which often
+/// makes them cheaper to encode, e.g., a Huffman code trained on lowercase
+/// strings needs half as many codes for letters. They are applied in order
+/// before compression and reversed in the opposite order after
+/// decompression.
+pub trait Transform {
+  /// Transforms a value before it is compressed.
+  fn apply(&self, value: Value) -> Result<Value>;
+
+  /// Undoes [`apply`](Transform::apply) after a value is decompressed.
+  ///
+  /// Transforms which throw information away, like all of the ones in
+  /// [`TransformKind`], can't be undone and leave values in their transformed
+  /// form, which is what the default does.
+  fn reverse(&self, value: Value) -> Result<Value> {
+    Ok(value)
+  }
+}
+
+/// The transforms which can be declared in a schema.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum TransformKind {
+  /// Removes leading and trailing whitespace.
+  Trim,
+  /// Converts letters to lowercase.
+  Lowercase,
+  /// Puts strings into Unicode normalization form C, so that characters
+  /// with several representations are always encoded the same way.
+  NfcNormalize,
+}
+
+impl TransformKind {
+  /// The name of the transform in a schema file.
+  pub fn name(self) -> &'static str {
+    match self {
+      TransformKind::Trim => "trim",
+      TransformKind::Lowercase => "lowercase",
+      TransformKind::NfcNormalize => "nfc-normalize",
+    }
+  }
+}
+
+impl fmt::Display for TransformKind {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl Transform for TransformKind {
+  fn apply(&self, value: Value) -> Result<Value> {
+    let s = match value {
+      Value::Str(s) => s,
+      _ => return Err(unexpected_type(value, "string")),
+    };
+    let s = match self {
+      TransformKind::Trim => s.trim().to_string(),
+      TransformKind::Lowercase => s.to_lowercase(),
+      TransformKind::NfcNormalize => s.nfc().collect(),
+    };
+    Ok(Value::Str(s))
+  }
+}
+
+/// A compressor which transforms values before passing them on to another
+/// compressor.
+pub struct TransformCompressor {
+  pub transforms: Vec<Box<dyn Transform>>,
+  pub inner: Box<dyn Compressor>,
+}
+
+impl TransformCompressor {
+  fn apply(&self, mut value: Value) -> Result<Value> {
+    for t in &self.transforms {
+      value = t.apply(value)?;
+    }
+    Ok(value)
+  }
+}
+
+impl Compressor for TransformCompressor {
+  fn compress(&self, value: Value) -> Result<BitVec> {
+    self.inner.compress(self.apply(value)?)
+  }
+
+  fn decompress(&self, bits: BitVec) -> Result<Value> {
+    let mut value = self.inner.decompress(bits)?;
+    for t in self.transforms.iter().rev() {
+      value = t.reverse(value)?;
+    }
+    Ok(value)
+  }
+
+  fn encoded_width(&self) -> EncodedWidth {
+    self.inner.encoded_width()
+  }
+
+  fn prefix_len(&self, bits: &mut dyn Iterator<Item = bool>) -> Result<usize> {
+    self.inner.prefix_len(bits)
+  }
+
+  fn compressed_len(&self, value: Value) -> Result<usize> {
+    self.inner.compressed_len(self.apply(value)?)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn compressor(transforms: &[TransformKind]) -> TransformCompressor {
+    TransformCompressor {
+      transforms: transforms
+        .iter()
+        .map(|t| Box::new(*t) as Box<dyn Transform>)
+        .collect(),
+      inner: Box::new(IdentityCompressor),
+    }
+  }
+
+  fn roundtrip(c: &TransformCompressor, s: &str) -> Value {
+    let bits = c.compress(Value::Str(s.to_string())).unwrap();
+    c.decompress(bits).unwrap()
+  }
+
+  #[test]
+  fn transforms_are_applied_in_order() {
+    use TransformKind::*;
+    let c = compressor(&[Trim, Lowercase]);
+    assert_eq!(Value::Str("hello".to_string()), roundtrip(&c, "  HeLLo \n"));
+    assert_eq!(
+      8 * "hello".len(),
+      c.compressed_len(Value::Str(" Hello ".to_string())).unwrap()
+    );
+
+    let c = compressor(&[NfcNormalize]);
+    assert_eq!(Value::Str("\u{e9}".to_string()), roundtrip(&c, "e\u{301}"));
+
+    assert!(c.compress(Value::Bool(true)).is_err());
+  }
+
+  #[test]
+  fn reversible_transforms() {
+    struct Offset;
+
+    impl Transform for Offset {
+      fn apply(&self, value: Value) -> Result<Value> {
+        match value {
+          Value::Int(i) => Ok(Value::Str(format!("{}", i - 1000))),
+          _ => Err(unexpected_type(value, "int")),
+        }
+      }
+
+      fn reverse(&self, value: Value) -> Result<Value> {
+        match value {
+          Value::Str(s) => Ok(Value::Int(s.parse::<i64>()? + 1000)),
+          _ => Err(unexpected_type(value, "string")),
+        }
+      }
+    }
+
+    let c = TransformCompressor {
+      transforms: vec![Box::new(Offset)],
+      inner: Box::new(IdentityCompressor),
+    };
+    let bits = c.compress(Value::Int(1042)).unwrap();
+    assert_eq!(8 * 2, bits.len());
+    assert_eq!(Value::Int(1042), c.decompress(bits).unwrap());
+  }
+}
@@ -1,7 +1,29 @@
 //! The `data` module defines the data layout of compressed objects.
+//!
+//! Blocks are packed into bytes most significant bit first by default, so the
+//! first bit of an object is the highest bit of its first byte. Objects may
+//! instead be packed [least significant bit first](BitOrder::LsbFirst), in
+//! which case the bits of each byte are simply reversed. Independently of
+//! how bits are packed, the only sections stored least significant bit first
+//! are field markers, see
+//! [`Field::write_to`], and the tags in front of the elements of lists whose
+//! element type is a [union](Type::Union), which are written the same way.
+//!
+//! The values of [sparse](Record::sparse) records start with a single bit
+//! flag block, which is set if their fields are marked with the differences
+//! between consecutive markers instead, see [`Field::sparse`].
 
-use crate::bit::{BitVec, BitVecExt};
+use crate::bit::{self, BitBuf, BitVec};
+use crate::comp::EncodedWidth;
+use crate::dictionary::Dictionaries;
+use crate::encode::get_compressor_for_type;
+use crate::math;
+use crate::prelude::*;
+use crate::schema::{CompositeType, List, Record, Schema, Type};
 use crate::vie::CodePoint;
+use anyhow::{anyhow, bail, Context, Result};
+use core::slice;
+use serde::{Deserialize, Serialize};
 
 /// An interned identifier which can be mapped back to a named record field in
 /// some schema.
@@ -12,6 +34,11 @@ impl FieldId {
   pub fn new(i: u32) -> Self {
     FieldId(i)
   }
+
+  /// The position of the field among the fields of its record.
+  pub fn index(self) -> usize {
+    self.0 as usize
+  }
 }
 
 /// A section of a [Block] which denotes what field some piece of data belongs
@@ -26,6 +53,9 @@ pub struct Field {
   /// The id of this field, or `None` if there is no associated id such as in
   /// the case of the root object.
   pub id: Option<FieldId>,
+  /// The difference between the marker of this field and that of the
+  /// previous one, if this field is marked that way.
+  pub delta: Option<u32>,
 }
 
 impl Field {
@@ -34,49 +64,305 @@ impl Field {
     Field {
       width,
       id: Some(id),
+      delta: None,
     }
   }
 
   /// Constructs a field with no `id` and a given width.
   pub fn null(width: usize) -> Self {
-    Field { width, id: None }
+    Field {
+      width,
+      id: None,
+      delta: None,
+    }
+  }
+
+  /// Constructs a field of a sparse record, which is marked with the
+  /// difference between `id + 1` and the marker `prev` of the previous field,
+  /// or zero for the first field.
+  pub fn sparse(id: FieldId, prev: u32) -> Self {
+    let delta = id.0 + 1 - prev;
+    Field {
+      width: LengthEncoding::Vie.bit_len(delta as usize),
+      id: Some(id),
+      delta: Some(delta),
+    }
+  }
+
+  /// Appends the bits of this field to `buf`.
+  ///
+  /// The id is stored as the bit-reversed big endian representation of
+  /// `id + 1`, zero extended or truncated to the width of the field. The
+  /// fields of sparse records store their delta as a VIE code point instead.
+  pub fn write_to(&self, buf: &mut BitBuf) {
+    if let Some(delta) = self.delta {
+      return buf.push_bytes(CodePoint::from(delta as u64).bytes());
+    }
+    let id = match self.id {
+      Some(id) if self.width > 0 => id,
+      _ => return buf.push_zeros(self.width),
+    };
+
+    let rev = (id.0 as u64 + 1).reverse_bits();
+    let n = self.width.min(64);
+    buf.push_bits(rev >> (64 - n), n);
+    buf.push_zeros(self.width - n);
   }
 }
 
 impl Into<BitVec> for Field {
   fn into(self) -> BitVec<u32> {
-    let mut b = match self.id {
-      None => BitVec::from_elem(self.width, false),
-      Some(id) => BitVec::from_rev_be(id.0 + 1),
-    };
-
-    b.zext_or_trunc(self.width);
-    b
+    let mut buf = BitBuf::new();
+    self.write_to(&mut buf);
+    buf.into()
   }
 }
 
 /// A section of a [Block] which denotes the length of a data section or list
-/// object. Lengths are encoded using a variable width integer encoding similar
-/// to UTF-8. See [CodePoint] for more information on their implementation.
+/// object. Lengths are encoded using the [LengthEncoding] chosen by the schema,
+/// which by default is a variable width integer encoding similar to UTF-8. See
+/// [CodePoint] for more information on its implementation.
 ///
 /// [Block]: enum.Block.html
 /// [CodePoint]: ../core/struct.CodePoint.html
 #[derive(Clone, Debug, Eq, PartialEq)]
-pub struct Length(usize);
+pub struct Length {
+  len: usize,
+  encoding: LengthEncoding,
+}
 
 impl Length {
   pub fn new(len: usize) -> Self {
-    Length(len)
+    Length {
+      len,
+      encoding: LengthEncoding::Vie,
+    }
+  }
+
+  /// Constructs a length which is encoded using `encoding`.
+  ///
+  /// Fails if `len` is too large for `encoding`.
+  pub fn encoded(len: usize, encoding: LengthEncoding) -> Result<Self> {
+    if len as u64 > encoding.max() {
+      bail!("length {} is too large for {} lengths", len, encoding);
+    }
+    Ok(Length { len, encoding })
+  }
+
+  /// The length value held by this section.
+  #[inline]
+  pub fn get(&self) -> usize {
+    self.len
+  }
+
+  /// How this length is encoded.
+  #[inline]
+  pub fn encoding(&self) -> LengthEncoding {
+    self.encoding
+  }
+
+  /// The number of bits this length takes up once encoded.
+  pub fn bit_len(&self) -> usize {
+    self.encoding.bit_len(self.len)
+  }
+
+  /// Appends the bits of this length to `buf`.
+  pub fn write_to(&self, buf: &mut BitBuf) {
+    let len = self.len as u64;
+    match self.encoding {
+      LengthEncoding::Vie => buf.push_bytes(CodePoint::from(len).bytes()),
+      LengthEncoding::Fixed16 => buf.push_bits(len, 16),
+      LengthEncoding::Fixed32 => buf.push_bits(len, 32),
+      LengthEncoding::Gamma => {
+        let bits = gamma_bits(len);
+        buf.push_zeros(bits - 1);
+        buf.push_bits(len + 1, bits);
+      }
+    }
+  }
+}
+
+/// The number of significant bits in `len + 1`, which is the value an Elias
+/// gamma code holds as it can't encode zero.
+fn gamma_bits(len: u64) -> usize {
+  (u64::BITS - (len + 1).leading_zeros()) as usize
+}
+
+/// How the [Length] sections of a compressed object are encoded.
+///
+/// Different workloads have very different lengths. Short strings and lists
+/// suit Elias gamma codes, while fixed widths make lengths cheap to read. The
+/// encoding is part of the [Schema], so objects are always decoded with the
+/// encoding they were encoded with.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum LengthEncoding {
+  /// A variable width integer of one or more bytes, 7 bits of which hold the
+  /// value.
+  Vie,
+  /// A 16 bit unsigned integer.
+  Fixed16,
+  /// A 32 bit unsigned integer.
+  Fixed32,
+  /// An Elias gamma code of `len + 1`: as many zeros as the code has bits
+  /// after the first, followed by the bits of `len + 1`. A length `n` takes
+  /// `2 * floor(log2(n + 1)) + 1` bits.
+  Gamma,
+}
+
+impl LengthEncoding {
+  /// The number of bits a length of `len` takes up using this encoding.
+  pub fn bit_len(self, len: usize) -> usize {
+    match self {
+      LengthEncoding::Vie => {
+        let bits = usize::BITS - len.leading_zeros();
+        math::div_ceil(bits as usize, 7).max(1) * 8
+      }
+      LengthEncoding::Fixed16 => 16,
+      LengthEncoding::Fixed32 => 32,
+      LengthEncoding::Gamma => gamma_bits(len as u64) * 2 - 1,
+    }
+  }
+
+  /// The largest length which can be encoded using this encoding.
+  pub fn max(self) -> u64 {
+    match self {
+      LengthEncoding::Fixed16 => u16::MAX as u64,
+      LengthEncoding::Fixed32 => u32::MAX as u64,
+      // The gamma code of `len + 1` can't hold `u64::MAX + 1`
+      LengthEncoding::Gamma => u64::MAX - 1,
+      LengthEncoding::Vie => u64::MAX,
+    }
+  }
+
+  /// A short name for this encoding, as used in schema files.
+  pub fn name(self) -> &'static str {
+    match self {
+      LengthEncoding::Vie => "vie",
+      LengthEncoding::Fixed16 => "fixed16",
+      LengthEncoding::Fixed32 => "fixed32",
+      LengthEncoding::Gamma => "gamma",
+    }
+  }
+}
+
+impl core::fmt::Display for LengthEncoding {
+  fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+    f.write_str(self.name())
   }
 }
 
 impl Into<BitVec> for Length {
   fn into(self) -> BitVec<u32> {
-    let codepoint = CodePoint::from(self.0 as u64);
-    BitVec::from_bytes(codepoint.bytes())
+    let mut buf = BitBuf::new();
+    self.write_to(&mut buf);
+    buf.into()
+  }
+}
+
+/// How the sections of blocks are laid out in the bits of a compressed
+/// object.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Layout {
+  /// Sections are packed together without any padding.
+  Packed,
+  /// Field markers, lengths and data are padded with zeros to a whole number
+  /// of bytes, so that every section, and therefore every block, starts on a
+  /// byte boundary. Objects are a little larger but much faster to decode,
+  /// and the offsets of their values can be found by tools which work in
+  /// bytes.
+  ByteAligned,
+}
+
+impl Layout {
+  /// The number of bits a section of `n` bits takes up once padded.
+  pub fn padded(self, n: usize) -> usize {
+    match self {
+      Layout::Packed => n,
+      Layout::ByteAligned => math::div_ceil(n, 8) * 8,
+    }
+  }
+}
+
+impl core::str::FromStr for Layout {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "packed" => Ok(Layout::Packed),
+      "byte-aligned" => Ok(Layout::ByteAligned),
+      _ => bail!("layout must be one of: packed, byte-aligned"),
+    }
+  }
+}
+
+/// The order in which the bits of a compressed object are packed into each of
+/// its bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BitOrder {
+  /// The first bit of each byte is its most significant bit.
+  MsbFirst,
+  /// The first bit of each byte is its least significant bit, which is the
+  /// order expected by tools that read bit streams from the bottom of each
+  /// byte up.
+  LsbFirst,
+}
+
+impl BitOrder {
+  /// Repacks `bytes` between most significant bit first and this order.
+  ///
+  /// Repacking is its own inverse, so this converts in either direction.
+  pub fn repack(self, bytes: &mut [u8]) {
+    if self == BitOrder::LsbFirst {
+      for byte in bytes {
+        *byte = byte.reverse_bits();
+      }
+    }
+  }
+}
+
+impl core::str::FromStr for BitOrder {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "msb-first" => Ok(BitOrder::MsbFirst),
+      "lsb-first" => Ok(BitOrder::LsbFirst),
+      _ => bail!("bit order must be one of: msb-first, lsb-first"),
+    }
   }
 }
 
+/// How the fields of records are marked in a compressed object.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Profile {
+  /// Every field starts with a field marker, and nested records end with a
+  /// terminator.
+  Standard,
+  /// Records whose fields are all [required](Record::required) and none
+  /// deprecated have no field markers or terminators. Their fields are always
+  /// present, so they are just the values of their fields one after another
+  /// in schema order. Other records are encoded as usual. Objects are as
+  /// small as they can be but every field of such records must be present,
+  /// and adding a field to one changes the encoding of the record.
+  Dense,
+}
+
+impl core::str::FromStr for Profile {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "standard" => Ok(Profile::Standard),
+      "dense" => Ok(Profile::Dense),
+      _ => bail!("profile must be one of: standard, dense"),
+    }
+  }
+}
+
+/// The 64 bits which start every sync marker (see [`Block::Sync`]).
+pub const SYNC_MAGIC: u64 = u64::from_be_bytes(*b"CHIISYNC");
+
 /// Blocks are the fundamental building block of compressed objects. Each
 /// compressed object is just a sequence of blocks packed together in memory.
 #[derive(Clone, Debug, Eq, PartialEq)]
@@ -92,16 +378,35 @@ pub enum Block {
   /// A block which denotes the start of a list data object. It has two
   /// components, a [Field] and a [Length]. The field component determines what
   /// field this list is nested under if nested within a record object. The
-  /// length component holds the number of elements in the list.
+  /// length component holds the number of elements in the list, or one more
+  /// than that if the list is a nullable field, in which case a length of
+  /// zero means the field is null.
   ///
   /// [Field]: struct.Field.html
   /// [Length]: struct.Length.html
   ListHeader(Field, Length),
 
+  /// A block which starts each chunk of a list which is split into chunks.
+  /// Its [Length] component holds the number of bits taken up by the
+  /// elements of the chunk, letting decoders skip over them.
+  ///
+  /// [Length]: struct.Length.html
+  ChunkHeader(Length),
+
+  /// A block which precedes each element of a root list, or each chunk of
+  /// one which is split into chunks, when the schema asks for sync markers.
+  /// It starts with a single bit which is set if a marker follows: the 64
+  /// bits of [SYNC_MAGIC] and a [Length] holding the index of the element.
+  /// Decoders scan for markers to find their place again after damaged data.
+  ///
+  /// [SYNC_MAGIC]: constant.SYNC_MAGIC.html
+  /// [Length]: struct.Length.html
+  Sync(Option<Length>),
+
   /// A data block which contains encoded data for a single record field.
   ///
   /// Data held in this block has a fixed width which is determined from the
-  /// schema.
+  /// schema, or is a code of a prefix code which determines its own width.
   FixedWidthField(Field, BitVec),
 
   /// A data block which contains encoded data for a single record field.
@@ -114,7 +419,8 @@ pub enum Block {
   ///
   /// Data held in this type of block has a fixed width determined from the
   /// schema. Since lists must be homogeneous no length component is required
-  /// when the element width can be statically determined.
+  /// when the element width can be statically determined. Codes of a prefix
+  /// code don't need one either.
   FixedWidthElement(BitVec),
 
   /// A data block which contains encoded data for a single list element.
@@ -123,12 +429,18 @@ pub enum Block {
   /// the schema so a length component is required.
   VariableWidthElement(Length, BitVec),
 
+  /// A block which follows the data of every variable-width field and
+  /// element of an object encoded with checksums. It holds the 8 bit CRC of
+  /// the data, so that damage to the data is detected at that value instead
+  /// of only once the whole object fails to decode.
+  Checksum(u8),
+
   /// The terminator block is used to mark the end of record objects.
   Terminator { width: usize },
 }
 
-impl std::fmt::Display for Block {
-  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+impl core::fmt::Display for Block {
+  fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
     use Block::*;
 
     let fmt_id = |m: &Field| {
@@ -137,19 +449,19 @@ impl std::fmt::Display for Block {
     };
 
     match self {
-      RecordHeader(m) => write!(
-        f,
-        "HR  {{ width: {}, id: {} }}",
-        m.width,
-        fmt_id(m)
-      ),
+      RecordHeader(m) => {
+        write!(f, "HR  {{ width: {}, id: {} }}", m.width, fmt_id(m))
+      }
       ListHeader(m, l) => write!(
         f,
         "HL  {{ width: {}, id: {}, length: {} }}",
         m.width,
         fmt_id(m),
-        l.0
+        l.get()
       ),
+      ChunkHeader(l) => write!(f, "HC  {{ length: {} }}", l.get()),
+      Sync(None) => write!(f, "SY  {{ }}"),
+      Sync(Some(l)) => write!(f, "SY  {{ element: {} }}", l.get()),
       FixedWidthField(m, data) => write!(
         f,
         "FWF {{ width: {}, id: {}, data: {:?} }}",
@@ -162,62 +474,127 @@ impl std::fmt::Display for Block {
         "VWF {{ width: {}, id: {}, length: {}, data: {:?} }}",
         m.width,
         fmt_id(m),
-        l.0,
+        l.get(),
         data
       ),
       FixedWidthElement(data) => {
         write!(f, "FixedWidthElement {{ data: {:?} }}", data.len())
       }
-      VariableWidthElement(l, data) => write!(
-        f,
-        "VWE {{ length: {}, data: {:?} }}",
-        l.0,
-        data
-      ),
+      VariableWidthElement(l, data) => {
+        write!(f, "VWE {{ length: {}, data: {:?} }}", l.get(), data)
+      }
+      Checksum(crc) => write!(f, "CRC {{ value: {:#04x} }}", crc),
       Terminator { width } => write!(f, "TER {{ width: {} }}", width),
     }
   }
 }
 
-impl Into<BitVec> for Block {
-  fn into(self) -> BitVec<u32> {
+impl Block {
+  /// The number of bits this block takes up once encoded.
+  pub fn bit_len(&self) -> usize {
+    self.bit_len_with(Layout::Packed)
+  }
+
+  /// The number of bits this block takes up once encoded with a given
+  /// `layout`.
+  pub fn bit_len_with(&self, layout: Layout) -> usize {
     use Block::*;
 
+    let pad = |n| layout.padded(n);
     match self {
-      RecordHeader(m) => m.into(),
+      RecordHeader(m) => pad(m.width),
+      ListHeader(m, l) => pad(m.width) + pad(l.bit_len()),
+      ChunkHeader(l) => pad(l.bit_len()),
+      Sync(None) => pad(1),
+      Sync(Some(l)) => pad(1) + pad(64) + pad(l.bit_len()),
+      FixedWidthField(m, data) => pad(m.width) + pad(data.len()),
+      VariableWidthField(m, l, data) => {
+        pad(m.width) + pad(l.bit_len()) + pad(data.len())
+      }
+      FixedWidthElement(data) => pad(data.len()),
+      VariableWidthElement(l, data) => pad(l.bit_len()) + pad(data.len()),
+      Checksum(_) => pad(8),
+      Terminator { width } => pad(*width),
+    }
+  }
+
+  /// Appends the bits of this block to `buf`.
+  pub fn write_to(&self, buf: &mut BitBuf) {
+    self.write_with(buf, Layout::Packed)
+  }
+
+  /// Appends the bits of this block to `buf` using a given `layout`.
+  ///
+  /// Byte-aligned blocks must be written at a byte boundary of `buf` for
+  /// their sections to be aligned.
+  pub fn write_with(&self, buf: &mut BitBuf, layout: Layout) {
+    use Block::*;
+
+    let field = |m: &Field, buf: &mut BitBuf| {
+      m.write_to(buf);
+      buf.push_zeros(layout.padded(m.width) - m.width);
+    };
+    let length = |l: &Length, buf: &mut BitBuf| {
+      l.write_to(buf);
+      buf.push_zeros(layout.padded(l.bit_len()) - l.bit_len());
+    };
+    let data = |data: &BitVec, buf: &mut BitBuf| {
+      buf.push_bit_vec(data);
+      buf.push_zeros(layout.padded(data.len()) - data.len());
+    };
+
+    match self {
+      RecordHeader(m) => field(m, buf),
 
       ListHeader(m, l) => {
-        let mut b: BitVec = m.into();
-        b.append(&mut l.into());
-        b
+        field(m, buf);
+        length(l, buf);
+      }
+
+      ChunkHeader(l) => length(l, buf),
+
+      Sync(marker) => {
+        buf.push_bit(marker.is_some());
+        buf.push_zeros(layout.padded(1) - 1);
+        if let Some(l) = marker {
+          buf.push_bits(SYNC_MAGIC, 64);
+          length(l, buf);
+        }
       }
 
-      FixedWidthField(m, mut data) => {
-        let mut b: BitVec = m.into();
-        b.append(&mut data);
-        b
+      FixedWidthField(m, d) => {
+        field(m, buf);
+        data(d, buf);
       }
 
-      VariableWidthField(m, l, mut data) => {
-        let mut b: BitVec = m.into();
-        b.append(&mut l.into());
-        b.append(&mut data);
-        b
+      VariableWidthField(m, l, d) => {
+        field(m, buf);
+        length(l, buf);
+        data(d, buf);
       }
 
-      FixedWidthElement(data) => data,
+      FixedWidthElement(d) => data(d, buf),
 
-      VariableWidthElement(l, mut data) => {
-        let mut b: BitVec = l.into();
-        b.append(&mut data);
-        b
+      VariableWidthElement(l, d) => {
+        length(l, buf);
+        data(d, buf);
       }
 
-      Terminator { width } => Field::null(width).into(),
+      Checksum(crc) => buf.push_bits(*crc as u64, 8),
+
+      Terminator { width } => field(&Field::null(*width), buf),
     }
   }
 }
 
+impl Into<BitVec> for Block {
+  fn into(self) -> BitVec<u32> {
+    let mut buf = BitBuf::new();
+    self.write_to(&mut buf);
+    buf.into()
+  }
+}
+
 /// A compressed object is simply a sequence of [Blocks] which represents some
 /// structured data. When paired with a Schema it can be converted into a
 /// human-readable representation like JSON.
@@ -226,18 +603,586 @@ impl Into<BitVec> for Block {
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct CompressedObject {
   pub blocks: Vec<Block>,
+  /// How the blocks are laid out once encoded.
+  pub layout: Layout,
+  /// How the fields of the object's records are marked.
+  pub profile: Profile,
+  /// Whether the variable-width data blocks are followed by checksums.
+  pub checksums: bool,
+  /// How the bits of the object are packed into bytes.
+  pub bit_order: BitOrder,
+  /// Whether the object's schema has [dictionary](crate::dictionary) fields,
+  /// in which case its bytes end with an empty dictionaries footer.
+  pub dictionaries: bool,
 }
 
 impl CompressedObject {
   /// Constructs an empty compressed object with no blocks.
   pub fn new() -> Self {
-    CompressedObject { blocks: Vec::new() }
+    CompressedObject::with_layout(Layout::Packed)
+  }
+
+  /// Constructs an empty compressed object whose blocks are laid out using
+  /// `layout` once encoded.
+  pub fn with_layout(layout: Layout) -> Self {
+    CompressedObject {
+      blocks: Vec::new(),
+      layout,
+      profile: Profile::Standard,
+      checksums: false,
+      bit_order: BitOrder::MsbFirst,
+      dictionaries: false,
+    }
   }
 
   /// Pushes a new block onto the end of this compressed object.
   pub fn push(&mut self, block: Block) {
     self.blocks.push(block);
   }
+
+  /// The number of bits this object takes up once encoded.
+  pub fn bit_len(&self) -> usize {
+    self
+      .blocks
+      .iter()
+      .map(|block| block.bit_len_with(self.layout))
+      .sum()
+  }
+
+  /// Packs the blocks of this object into a bit buffer.
+  ///
+  /// The buffer is sized up front so packing is linear in the size of the
+  /// object.
+  pub fn to_bit_buf(&self) -> BitBuf {
+    let mut buf = BitBuf::with_capacity(self.bit_len());
+    for block in &self.blocks {
+      block.write_with(&mut buf, self.layout);
+    }
+    buf
+  }
+
+  /// The byte representation of this object with the last byte padded with
+  /// zeros, packed in the object's [bit order](CompressedObject::bit_order)
+  /// and followed by an empty dictionaries footer if its schema has
+  /// dictionary fields.
+  ///
+  /// When packed most significant bit first, this is the same as converting
+  /// the object into a [`BitVec`] and then into bytes, only faster.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = self.to_bit_buf().to_bytes();
+    self.bit_order.repack(&mut bytes);
+    if self.dictionaries {
+      let footer = Dictionaries::default().footer(bytes.len());
+      bytes.extend_from_slice(&footer);
+    }
+    bytes
+  }
+
+  /// Walks the blocks of this object in order, calling the methods of
+  /// `visitor` with field names and types resolved using `schema`.
+  ///
+  /// Returns an error if the blocks don't match the structure of `schema`. See
+  /// [`validate`] for the checks which are performed.
+  ///
+  /// [`validate`]: CompressedObject::validate
+  pub fn accept<V: Visitor>(
+    &self,
+    schema: &Schema,
+    visitor: &mut V,
+  ) -> Result<()> {
+    let mut blocks = self.blocks.iter();
+    let encoding = Encoding {
+      profile: self.profile,
+      layout: self.layout,
+      chunks: schema.chunks(),
+      sync: false,
+      checksums: self.checksums,
+    };
+    match schema.root() {
+      Type::Nested(CompositeType::Record(rec)) => {
+        walk_record(rec, None, true, None, encoding, &mut blocks, visitor)?
+      }
+      Type::Nested(CompositeType::List(l)) => {
+        match next_block(&mut blocks, visitor) {
+          Some(Block::ListHeader(f, len)) => {
+            check_width(f, 0)?;
+            let encoding = Encoding {
+              sync: schema.sync().is_some(),
+              ..encoding
+            };
+            walk_list(l, None, len.get(), encoding, &mut blocks, visitor)?
+          }
+          _ => bail!("expected list header"),
+        }
+      }
+      ty => match next_block(&mut blocks, visitor) {
+        Some(Block::FixedWidthElement(data)) => {
+          check_data(ty, None, data)?;
+          visitor.visit_data(None, ty, data)
+        }
+        Some(Block::VariableWidthElement(len, data)) => {
+          check_data(ty, Some(len), data)?;
+          if encoding.checksums {
+            walk_checksum(data, &mut blocks, visitor)?;
+          }
+          visitor.visit_data(None, ty, data)
+        }
+        _ => bail!("expected a single element"),
+      },
+    }
+
+    if blocks.next().is_some() {
+      bail!("unexpected block after end of object");
+    }
+    Ok(())
+  }
+
+  /// Checks that this object is structurally valid for `schema`.
+  ///
+  /// Every nested record header must be balanced by a terminator, every list
+  /// must be followed by exactly as many elements as its header states, the
+  /// chunks of a chunked list must hold as many bits as their headers state,
+  /// field
+  /// markers and terminators must be as wide as their record's
+  /// [`field_width`], and data blocks must have the width required by their
+  /// type with any length section matching the length of the data. The
+  /// variable-width data blocks of an object with
+  /// [checksums](CompressedObject::checksums) must each be followed by a
+  /// checksum which matches their data.
+  ///
+  /// [`field_width`]: Record::field_width
+  pub fn validate(&self, schema: &Schema) -> Result<()> {
+    struct Noop;
+    impl Visitor for Noop {}
+
+    self.accept(schema, &mut Noop)
+  }
+}
+
+/// A visitor over the blocks of a [CompressedObject].
+///
+/// Field names and types are resolved from the schema so implementors only
+/// need to handle the parts of the structure they are interested in. All
+/// methods do nothing by default. `field` is `None` for the root object and
+/// for list elements.
+///
+/// [CompressedObject]: struct.CompressedObject.html
+pub trait Visitor {
+  /// Called at the start of a record.
+  fn visit_record(&mut self, _field: Option<&str>) {}
+
+  /// Called after the last field of a record.
+  fn visit_record_end(&mut self) {}
+
+  /// Called at the start of a list with `len` elements.
+  fn visit_list(&mut self, _field: Option<&str>, _len: usize) {}
+
+  /// Called after the last element of a list.
+  fn visit_list_end(&mut self) {}
+
+  /// Called for a nullable list field which is null.
+  fn visit_null(&mut self, _field: Option<&str>) {}
+
+  /// Called for each non-nested field or element along with its type and
+  /// encoded data.
+  fn visit_data(&mut self, _field: Option<&str>, _ty: &Type, _data: &BitVec) {}
+
+  /// Called for every block as it is walked, before any of the other methods
+  /// called for the value it belongs to. A layout flag of a sparse record
+  /// comes before the start of the record, and a terminator before its end.
+  fn visit_block(&mut self, _block: &Block) {}
+}
+
+/// How the blocks of an object were encoded, which walking them depends on.
+#[derive(Copy, Clone)]
+struct Encoding {
+  profile: Profile,
+  layout: Layout,
+  /// The number of elements in each chunk of a list, if lists are chunked.
+  chunks: Option<usize>,
+  /// Whether the elements or chunks of the list being walked are preceded
+  /// by sync blocks, which only those of a root list are.
+  sync: bool,
+  /// Whether variable-width data blocks are followed by checksums.
+  checksums: bool,
+}
+
+/// Takes the next block, showing it to `visitor`.
+fn next_block<'b, V: Visitor>(
+  blocks: &mut slice::Iter<'b, Block>,
+  visitor: &mut V,
+) -> Option<&'b Block> {
+  let block = blocks.next()?;
+  visitor.visit_block(block);
+  Some(block)
+}
+
+/// Walks the fields of a record up to and including its terminator.
+///
+/// An inline record, whose `parent`'s marker width and the identifier of its
+/// first field among them are given, has no terminator and ends before the
+/// first block which isn't one of its fields.
+fn walk_record<V: Visitor>(
+  record: &Record,
+  name: Option<&str>,
+  root: bool,
+  parent: Option<(usize, u32)>,
+  encoding: Encoding,
+  blocks: &mut slice::Iter<Block>,
+  visitor: &mut V,
+) -> Result<()> {
+  // Dense records are just their fields in schema order
+  let dense =
+    encoding.profile == Profile::Dense && record.is_dense() && parent.is_none();
+  let sparse = record.sparse
+    && parent.is_none()
+    && !dense
+    && match next_block(blocks, visitor) {
+      Some(Block::FixedWidthElement(flag)) if flag.len() == 1 => flag[0],
+      Some(block) => bail!("expected a layout flag, found {}", block),
+      None => bail!("sparse record is missing its layout flag"),
+    };
+  visitor.visit_record(name);
+  // Sparse layouts end with a zero VIE code point, even in the root record
+  let (width, root) = match (sparse, parent) {
+    _ if dense => (0, root),
+    (true, _) => (LengthEncoding::Vie.bit_len(0), false),
+    (false, Some((width, _))) => (width, root),
+    (false, None) => (record.field_width(), root),
+  };
+  let first = parent.map_or(0, |p| p.1);
+  let name_of = |field: &Field| {
+    field
+      .id
+      .and_then(|id| id.0.checked_sub(first))
+      .and_then(|id| record.field_by_id(FieldId(id)))
+      .map(|(name, _)| name.as_str())
+  };
+  let mut prev = 0u32;
+  let mut lookup = |field: &Field| {
+    match (sparse, field.delta) {
+      (false, None) => check_width(field, width)?,
+      (true, Some(delta)) if delta > 0 => {
+        check_width(field, LengthEncoding::Vie.bit_len(delta as usize))?;
+        prev = prev.saturating_add(delta);
+        if field.id != Some(FieldId(prev - 1)) {
+          bail!("field delta {} does not match {:?}", delta, field.id);
+        }
+      }
+      _ => bail!("unexpected field marker in record: {:?}", field),
+    }
+    name_of(field).ok_or_else(|| anyhow!("unexpected field: {:?}", field.id))
+  };
+
+  let mut order = record.ordered_fields().map(|(name, _)| name);
+  loop {
+    let next = blocks.clone().next().and_then(marked_field);
+    if dense {
+      let expected = match order.next() {
+        Some(expected) => expected,
+        None => break,
+      };
+      if next.and_then(name_of) != Some(expected.as_str()) {
+        bail!("dense record is missing field {}", expected);
+      }
+    }
+    let within = |field: &Field| {
+      let id = field.id.and_then(|id| id.0.checked_sub(first));
+      id.is_some_and(|id| (id as usize) < record.id_count())
+    };
+    if parent.is_some() && !next.is_some_and(within) {
+      break;
+    }
+    // The fields of an inline record are walked as if it were nested
+    let inline = next.and_then(name_of).filter(|_| !dense).and_then(|name| {
+      let rec = record.inline_record(name)?;
+      Some((name, rec, record.field_map()[name].index() as u32))
+    });
+    if let Some((name, rec, id)) = inline {
+      let parent = Some((width, first + id));
+      walk_record(rec, Some(name), root, parent, encoding, blocks, visitor)?;
+      continue;
+    }
+
+    let block = match next_block(blocks, visitor) {
+      Some(b) => b,
+      None if root => break,
+      None => bail!("record is missing a terminator"),
+    };
+
+    match block {
+      Block::Terminator { width: w } if root => {
+        bail!("unexpected terminator (width {}) in root record", w)
+      }
+      Block::Terminator { width: w } => {
+        check_width(&Field::null(*w), width)?;
+        break;
+      }
+      Block::RecordHeader(f) => {
+        let name = lookup(f)?;
+        match &record.fields[name] {
+          Type::Nested(CompositeType::Record(rec)) => walk_record(
+            rec,
+            Some(name),
+            false,
+            None,
+            encoding,
+            blocks,
+            visitor,
+          )?,
+          _ => bail!("{} is not a record", name),
+        }
+      }
+      Block::ListHeader(f, len) => {
+        let name = lookup(f)?;
+        match &record.fields[name] {
+          Type::Nested(CompositeType::List(l)) if record.is_nullable(name) => {
+            match len.get() {
+              0 => visitor.visit_null(Some(name)),
+              len => {
+                walk_list(l, Some(name), len - 1, encoding, blocks, visitor)?
+              }
+            }
+          }
+          Type::Nested(CompositeType::List(l)) => {
+            walk_list(l, Some(name), len.get(), encoding, blocks, visitor)?
+          }
+          _ => bail!("{} is not a list", name),
+        }
+      }
+      Block::FixedWidthField(f, data) => {
+        let name = lookup(f)?;
+        let ty = &record.fields[name];
+        check_data(ty, None, data).with_context(|| format!("in {}", name))?;
+        visitor.visit_data(Some(name), ty, data);
+      }
+      Block::VariableWidthField(f, len, data) => {
+        let name = lookup(f)?;
+        let ty = &record.fields[name];
+        check_data(ty, Some(len), data)
+          .with_context(|| format!("in {}", name))?;
+        if encoding.checksums {
+          walk_checksum(data, blocks, visitor)
+            .with_context(|| format!("in {}", name))?;
+        }
+        visitor.visit_data(Some(name), ty, data);
+      }
+      _ => bail!("unexpected list element in record"),
+    }
+  }
+
+  visitor.visit_record_end();
+  Ok(())
+}
+
+/// The field marked by a block, if it has a marker.
+fn marked_field(block: &Block) -> Option<&Field> {
+  match block {
+    Block::RecordHeader(f)
+    | Block::ListHeader(f, _)
+    | Block::FixedWidthField(f, _)
+    | Block::VariableWidthField(f, _, _) => Some(f),
+    _ => None,
+  }
+}
+
+/// Walks the `len` elements of a list whose header has already been consumed.
+fn walk_list<'b, V: Visitor>(
+  list: &List,
+  name: Option<&str>,
+  len: usize,
+  encoding: Encoding,
+  blocks: &mut slice::Iter<'b, Block>,
+  visitor: &mut V,
+) -> Result<()> {
+  visitor.visit_list(name, len);
+  let chunks = encoding.chunks.filter(|size| len > *size);
+  let size = chunks.unwrap_or(len).max(1);
+  // Sync blocks precede every chunk, or every element if there are none
+  let sync = encoding.sync;
+  let encoding = Encoding {
+    sync: false,
+    ..encoding
+  };
+  for start in (0..len).step_by(size) {
+    if sync && chunks.is_some() {
+      walk_sync(start, blocks, visitor)?;
+    }
+    let header = match chunks.map(|_| next_block(blocks, visitor)) {
+      None => None,
+      Some(Some(Block::ChunkHeader(bits))) => Some(bits.get()),
+      Some(Some(block)) => bail!("expected a chunk header, found {}", block),
+      Some(None) => bail!("list has fewer elements than its length"),
+    };
+    let rest = blocks.as_slice();
+    for i in start..len.min(start + size) {
+      if sync && chunks.is_none() {
+        walk_sync(i, blocks, visitor)?;
+      }
+      walk_element(list, encoding, blocks, visitor)?;
+    }
+    if let Some(bits) = header {
+      let chunk = &rest[..rest.len() - blocks.as_slice().len()];
+      let actual: usize = chunk
+        .iter()
+        .map(|block| block.bit_len_with(encoding.layout))
+        .sum();
+      if bits != actual {
+        bail!("chunk length {} does not match its {} bits", bits, actual);
+      }
+    }
+  }
+  visitor.visit_list_end();
+  Ok(())
+}
+
+/// Walks the sync block before element `i` of a root list.
+fn walk_sync<V: Visitor>(
+  i: usize,
+  blocks: &mut slice::Iter<Block>,
+  visitor: &mut V,
+) -> Result<()> {
+  match next_block(blocks, visitor) {
+    Some(Block::Sync(None)) => Ok(()),
+    Some(Block::Sync(Some(l))) if l.get() == i => Ok(()),
+    Some(Block::Sync(Some(l))) => {
+      bail!("sync marker for element {} found at element {}", l.get(), i)
+    }
+    Some(block) => bail!("expected a sync block, found {}", block),
+    None => bail!("list has fewer elements than its length"),
+  }
+}
+
+/// Walks a single list element.
+fn walk_element<'b, V: Visitor>(
+  list: &List,
+  encoding: Encoding,
+  blocks: &mut slice::Iter<'b, Block>,
+  visitor: &mut V,
+) -> Result<()> {
+  let next = |blocks: &mut slice::Iter<'b, Block>, visitor: &mut V| {
+    next_block(blocks, visitor)
+      .ok_or_else(|| anyhow!("list has fewer elements than its length"))
+  };
+  let mut block = next(blocks, visitor)?;
+  let ty = match list.0.as_ref() {
+    Type::Union { alternatives } => {
+      let ty = match block {
+        Block::FixedWidthElement(tag) => check_tag(alternatives, tag)?,
+        _ => bail!("expected a union tag, found {}", block),
+      };
+      block = next(blocks, visitor)?;
+      ty
+    }
+    ty => ty,
+  };
+  match (ty, block) {
+    (Type::Nested(CompositeType::Record(rec)), Block::RecordHeader(f)) => {
+      check_width(f, 0)?;
+      walk_record(rec, None, false, None, encoding, blocks, visitor)
+    }
+    (Type::Nested(CompositeType::List(l)), Block::ListHeader(f, len)) => {
+      check_width(f, 0)?;
+      walk_list(l, None, len.get(), encoding, blocks, visitor)
+    }
+    (Type::Nested(_), _) => bail!("unexpected block in list: {}", block),
+    (_, Block::FixedWidthElement(data)) => {
+      check_data(ty, None, data)?;
+      visitor.visit_data(None, ty, data);
+      Ok(())
+    }
+    (_, Block::VariableWidthElement(len, data)) => {
+      check_data(ty, Some(len), data)?;
+      if encoding.checksums {
+        walk_checksum(data, blocks, visitor)?;
+      }
+      visitor.visit_data(None, ty, data);
+      Ok(())
+    }
+    _ => bail!("unexpected block in list: {}", block),
+  }
+}
+
+/// Walks the checksum block after variable-width `data`, checking that it
+/// matches the data.
+fn walk_checksum<V: Visitor>(
+  data: &BitVec,
+  blocks: &mut slice::Iter<Block>,
+  visitor: &mut V,
+) -> Result<()> {
+  match next_block(blocks, visitor) {
+    Some(Block::Checksum(crc)) if *crc == bit::crc8(data) => Ok(()),
+    Some(Block::Checksum(crc)) => {
+      bail!("checksum {:#04x} does not match the data", crc)
+    }
+    Some(block) => bail!("expected a checksum, found {}", block),
+    None => bail!("variable width data is missing its checksum"),
+  }
+}
+
+/// Checks that the tag of a union element has the expected width, returning
+/// the type it selects.
+fn check_tag<'t>(alternatives: &'t [Type], tag: &BitVec) -> Result<&'t Type> {
+  let width = math::required_bit_width(alternatives.len());
+  if tag.len() != width {
+    bail!(
+      "union tag width {} does not match width {}",
+      tag.len(),
+      width
+    );
+  }
+  let index = tag.iter().rev().fold(0, |acc, b| (acc << 1) | b as usize);
+  alternatives
+    .get(index)
+    .ok_or_else(|| anyhow!("union has no type with tag {}", index))
+}
+
+/// Checks that a field marker has the expected width.
+fn check_width(field: &Field, expected: usize) -> Result<()> {
+  if field.width != expected {
+    bail!(
+      "field width {} does not match expected width {}",
+      field.width,
+      expected
+    );
+  }
+  Ok(())
+}
+
+/// Checks that the data of a field or element is consistent with its type and
+/// length section, if it has one.
+fn check_data(ty: &Type, len: Option<&Length>, data: &BitVec) -> Result<()> {
+  let compressor = get_compressor_for_type(ty)?;
+  match (compressor.encoded_width(), len) {
+    (EncodedWidth::Fixed(n), None) if data.len() != n => {
+      bail!("expected {} bits of data but found {}", n, data.len())
+    }
+    (EncodedWidth::Fixed(_), None) => Ok(()),
+    (EncodedWidth::Fixed(_), Some(_)) => {
+      bail!("unexpected length for fixed width data")
+    }
+    (EncodedWidth::Variable, Some(l)) if l.get() != data.len() => {
+      bail!(
+        "length {} does not match {} bits of data",
+        l.get(),
+        data.len()
+      )
+    }
+    (EncodedWidth::Variable, Some(_)) => Ok(()),
+    (EncodedWidth::Variable, None) => {
+      bail!("missing length for variable width data")
+    }
+    (EncodedWidth::Prefix, None) => {
+      let len = compressor.prefix_len(&mut data.iter())?;
+      if len != data.len() {
+        bail!("expected {} bits of data but found {}", len, data.len())
+      }
+      Ok(())
+    }
+    (EncodedWidth::Prefix, Some(_)) => {
+      bail!("unexpected length for prefix coded data")
+    }
+  }
 }
 
 impl Default for CompressedObject {
@@ -248,11 +1193,166 @@ impl Default for CompressedObject {
 
 impl Into<BitVec> for CompressedObject {
   fn into(self) -> BitVec<u32> {
-    let mut b = BitVec::new();
-    for block in self.blocks {
-      let mut bits: BitVec = block.into();
-      b.append(&mut bits);
+    self.to_bit_buf().into()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  /// Records every visited event as a line of text.
+  #[derive(Default)]
+  struct Trace(Vec<String>);
+
+  impl Visitor for Trace {
+    fn visit_record(&mut self, field: Option<&str>) {
+      self.0.push(format!("record {:?}", field));
+    }
+
+    fn visit_record_end(&mut self) {
+      self.0.push("end record".to_string());
+    }
+
+    fn visit_list(&mut self, field: Option<&str>, len: usize) {
+      self.0.push(format!("list {:?} {}", field, len));
     }
-    b
+
+    fn visit_list_end(&mut self) {
+      self.0.push("end list".to_string());
+    }
+
+    fn visit_data(&mut self, field: Option<&str>, _: &Type, data: &BitVec) {
+      self.0.push(format!("data {:?} {}", field, data.len()));
+    }
+  }
+
+  #[test]
+  fn accept_visits_blocks_with_field_names() {
+    let mut student = BTreeMap::new();
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "tags".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::PassThrough)))),
+    );
+    let schema = Schema::new(CompositeType::Record(Record::new(student)));
+
+    let value = json!({ "active": true, "tags": ["a", "bc"] });
+    let co = crate::encode(&schema, &value).unwrap();
+    let mut trace = Trace::default();
+    co.accept(&schema, &mut trace).unwrap();
+
+    assert_eq!(
+      vec![
+        "record None",
+        "data Some(\"active\") 1",
+        "list Some(\"tags\") 2",
+        "data None 8",
+        "data None 16",
+        "end list",
+        "end record",
+      ],
+      trace.0
+    );
+  }
+
+  #[test]
+  fn field_bits_are_reversed_id() {
+    use crate::bit::BitVecExt;
+
+    for &(id, width) in &[(0, 1), (2, 2), (5, 3), (6, 70), (u32::MAX - 1, 40)] {
+      let mut expected = BitVec::from_rev_be(id + 1);
+      expected.zext_or_trunc(width);
+      let bits: BitVec = Field::new(width, FieldId::new(id)).into();
+      assert_eq!(expected, bits);
+    }
+    let bits: BitVec = Field::null(5).into();
+    assert_eq!(BitVec::from_elem(5, false), bits);
+  }
+
+  #[test]
+  fn bit_len_matches_packed_blocks() {
+    let mut student = BTreeMap::new();
+    student.insert("name".to_string(), Type::PassThrough);
+    student.insert(
+      "flags".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Name(
+        "bool".to_string(),
+      ))))),
+    );
+    let schema = Schema::new(CompositeType::Record(Record::new(student)));
+
+    let flags = (0..200).map(|i| serde_json::Value::from(i % 3 == 0));
+    let flags = serde_json::Value::Array(flags.collect());
+    let mut value = json!({ "name": "Jeremy" });
+    value
+      .as_object_mut()
+      .unwrap()
+      .insert("flags".to_string(), flags);
+    let co = crate::encode(&schema, &value).unwrap();
+    for block in &co.blocks {
+      let bits: BitVec = block.clone().into();
+      assert_eq!(bits.len(), block.bit_len());
+    }
+    assert_eq!(co.to_bit_buf().len(), co.bit_len());
+  }
+
+  #[test]
+  fn validate_encoded_object() {
+    let mut course = BTreeMap::new();
+    course.insert("done".to_string(), Type::Name("bool".to_string()));
+    let schema = Schema::new(CompositeType::List(List(Box::new(
+      Type::Nested(CompositeType::Record(Record::new(course))),
+    ))));
+
+    let value = json!([{ "done": true }, {}]);
+    let co = crate::encode(&schema, &value).unwrap();
+    co.validate(&schema).unwrap();
+
+    // Dropping a terminator unbalances the object
+    let mut missing = co.clone();
+    missing.blocks.pop();
+    assert!(missing.validate(&schema).is_err());
+
+    // Field markers must match the record's field width
+    let mut wide = co.clone();
+    wide.blocks[2] = Block::FixedWidthField(
+      Field::new(5, FieldId::new(0)),
+      BitVec::from_elem(1, true),
+    );
+    assert!(wide.validate(&schema).is_err());
+
+    // Fixed width data must have the width of its type
+    let mut short = co;
+    short.blocks[2] =
+      Block::FixedWidthField(Field::new(1, FieldId::new(0)), BitVec::new());
+    assert!(short.validate(&schema).is_err());
+  }
+
+  #[test]
+  fn validate_data_block_for_nested_field() {
+    let mut inner = BTreeMap::new();
+    inner.insert("x".to_string(), Type::PassThrough);
+    let mut outer = BTreeMap::new();
+    outer.insert(
+      "inner".to_string(),
+      Type::Nested(CompositeType::Record(Record::new(inner))),
+    );
+    let schema = Schema::new(CompositeType::Record(Record::new(outer)));
+
+    let co = CompressedObject {
+      blocks: vec![Block::FixedWidthField(
+        Field::new(1, FieldId::new(0)),
+        BitVec::new(),
+      )],
+      layout: Layout::Packed,
+      profile: Profile::Standard,
+      checksums: false,
+      bit_order: BitOrder::MsbFirst,
+      dictionaries: false,
+    };
+    assert!(co.validate(&schema).is_err());
   }
 }
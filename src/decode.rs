@@ -0,0 +1,1926 @@
+use anyhow::{anyhow, bail, Result};
+use core::convert::TryFrom;
+use core::ops::Range;
+use serde_json::{Map, Value};
+
+use crate::bit::{self, BitReader};
+use crate::comp::{Compressor, EncodedWidth};
+use crate::data::{
+  BitOrder, FieldId, Layout, LengthEncoding, Profile, SYNC_MAGIC,
+};
+use crate::dictionary::{self, Dictionaries, Resolver};
+use crate::encode::{get_compressor_for_type, DEFAULT_MAX_DEPTH};
+use crate::epoch;
+use crate::error::{within, within_path, Error};
+use crate::event::{Event, Events};
+use crate::index::Index;
+use crate::math;
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use crate::schema::{CompositeType, List, Record, Schema, Type};
+use crate::vie::CodePoint;
+
+/// Limits which are enforced while decoding so that a small malicious input
+/// can't exhaust memory.
+#[derive(Copy, Clone, Debug)]
+pub struct DecodeOptions {
+  /// The maximum number of nested records and lists, including the root.
+  pub max_depth: usize,
+  /// The maximum total number of fields and list elements, including nested
+  /// records and lists.
+  pub max_elements: usize,
+  /// The maximum total size in bytes of decoded values. Strings count as
+  /// their length and all other values as 8 bytes.
+  pub max_size: usize,
+  /// The maximum number of bytes of memory the decoded value may take up,
+  /// counting every value, field name and string. Decoding fails with
+  /// [`Error::BudgetExceeded`] instead of allocating past it. Unlimited by
+  /// default.
+  pub max_memory: usize,
+  /// How the object being decoded was laid out when it was encoded.
+  pub layout: Layout,
+  /// Which profile the object being decoded was encoded with.
+  pub profile: Profile,
+  /// Whether the object being decoded was encoded with checksums. A value
+  /// whose data doesn't match its checksum is reported as
+  /// [`Error::Malformed`].
+  pub checksums: bool,
+  /// How the bits of the object being decoded are packed into bytes.
+  pub bit_order: BitOrder,
+}
+
+impl Default for DecodeOptions {
+  fn default() -> Self {
+    DecodeOptions {
+      max_depth: DEFAULT_MAX_DEPTH,
+      max_elements: 1 << 24,
+      max_size: 1 << 30,
+      max_memory: usize::MAX,
+      layout: Layout::Packed,
+      profile: Profile::Standard,
+      checksums: false,
+      bit_order: BitOrder::MsbFirst,
+    }
+  }
+}
+
+/// Decodes the byte representation of a compressed object using a given
+/// `schema`.
+pub fn decode(schema: &Schema, bytes: &[u8]) -> Result<Value> {
+  decode_with(schema, bytes, DecodeOptions::default())
+}
+
+/// Decodes the byte representation of a compressed object using a given
+/// `schema`, enforcing the limits in `options`.
+pub fn decode_with(
+  schema: &Schema,
+  bytes: &[u8],
+  options: DecodeOptions,
+) -> Result<Value> {
+  #[cfg(feature = "tracing")]
+  let _span = tracing::debug_span!("decode", bytes = bytes.len()).entered();
+  let (bytes, dictionaries) = split_dictionaries(schema, bytes)?;
+  let mut r = reader(schema, bytes, &options);
+  let mut value = match schema.root() {
+    Type::Nested(ct) => decode_composite_type(ct, true, r, options),
+    ty => decode_value(ty, &mut r),
+  }?;
+  Dictionaries::resolve(schema, dictionaries.as_ref(), &mut value)?;
+  epoch::resolve(schema, &mut value)?;
+  Ok(value)
+}
+
+/// Splits the bytes of an object encoded with `schema` from its
+/// [dictionaries](crate::dictionary), if its schema has dictionary fields.
+pub(crate) fn split_dictionaries<'b>(
+  schema: &Schema,
+  bytes: &'b [u8],
+) -> Result<(&'b [u8], Option<Dictionaries>)> {
+  if !dictionary::has_dictionaries(schema.root()) {
+    return Ok((bytes, None));
+  }
+  let (bytes, dictionaries) = Dictionaries::split(bytes)?;
+  Ok((bytes, Some(dictionaries)))
+}
+
+/// The result of decoding a possibly damaged object with [`decode_lenient`].
+#[derive(Clone, Debug, PartialEq)]
+pub struct Salvaged {
+  /// As much of the object as could be decoded.
+  pub value: Value,
+  /// The problems found while decoding, in the order they were found.
+  pub diagnostics: Vec<Diagnostic>,
+}
+
+impl Salvaged {
+  /// Returns `true` if the object was decoded without any problems.
+  pub fn is_complete(&self) -> bool {
+    self.diagnostics.is_empty()
+  }
+}
+
+/// A problem found while decoding leniently.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Diagnostic {
+  /// The path of the value which couldn't be decoded.
+  pub path: Path,
+  /// The bit offset at which the problem was found.
+  pub offset: usize,
+  /// A description of the problem.
+  pub message: String,
+}
+
+impl core::fmt::Display for Diagnostic {
+  fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+    f.write_str(&self.message)
+  }
+}
+
+/// Decodes a compressed object which may be damaged, salvaging as much of it
+/// as possible.
+///
+/// A non-nested value whose data can't be decompressed is replaced with
+/// `null` and decoding continues after it, as its width or length tells where
+/// the next value starts. Damage to the structure of the object, such as an
+/// invalid field marker or list length, leaves no way to find the next value
+/// so decoding stops there and the records and lists decoded so far are
+/// returned. Each problem is recorded as a [`Diagnostic`].
+///
+/// Damaged data which still decompresses goes unnoticed, unless the object
+/// was encoded with [checksums](DecodeOptions::checksums), in which case a
+/// variable-width value whose data doesn't match its checksum is replaced
+/// with `null` as well.
+///
+/// If the schema has [sync markers](Schema::with_sync) and the damage is
+/// within an element of a root list, decoding instead scans forward to the
+/// next sync marker and continues from the element it is for. The elements
+/// in between are replaced with `null` and their range is recorded as
+/// another diagnostic.
+pub fn decode_lenient(schema: &Schema, bytes: &[u8]) -> Salvaged {
+  decode_lenient_with(schema, bytes, DecodeOptions::default())
+}
+
+/// Decodes a compressed object which may be damaged, enforcing the limits in
+/// `options`.
+///
+/// See [`decode_lenient`] for how damage is handled. Exceeding a limit stops
+/// decoding in the same way as damage to the structure of the object.
+pub fn decode_lenient_with(
+  schema: &Schema,
+  bytes: &[u8],
+  options: DecodeOptions,
+) -> Salvaged {
+  let mut diagnostics = Vec::new();
+  let (bytes, dictionaries) =
+    split_dictionaries(schema, bytes).unwrap_or_else(|e| {
+      diagnostics.push(diagnostic(e, &Path::root(), bytes.len() * 8));
+      (bytes, None)
+    });
+  let mut r = reader(schema, bytes, &options);
+  let value = match schema.root() {
+    Type::Nested(ct) => {
+      let mut events = Events::new(r.clone(), ct, true, options);
+      events.lenient();
+      build_value(&mut events, Some(&mut diagnostics))
+    }
+    ty => read_value(ty, &mut r).and_then(|value| value),
+  };
+  let mut value = value.unwrap_or_else(|e| {
+    diagnostics.push(diagnostic(e, &Path::root(), r.position()));
+    Value::Null
+  });
+  let resolved =
+    Dictionaries::resolve(schema, dictionaries.as_ref(), &mut value)
+      .and_then(|_| epoch::resolve(schema, &mut value));
+  if let Err(e) = resolved {
+    diagnostics.push(diagnostic(e, &Path::root(), r.position()));
+  }
+  Salvaged { value, diagnostics }
+}
+
+/// Constructs a reader for the bits of an object encoded with `schema` in
+/// the way described by `options`.
+pub(crate) fn reader<'a>(
+  schema: &Schema,
+  bytes: &'a [u8],
+  options: &DecodeOptions,
+) -> BitReader<'a> {
+  let r = match options.layout {
+    Layout::Packed => BitReader::new(bytes),
+    Layout::ByteAligned => BitReader::byte_aligned(bytes),
+  };
+  r.with_lengths(schema.lengths())
+    .with_chunks(schema.chunks())
+    .with_sync(schema.sync().is_some())
+    .with_profile(options.profile)
+    .with_checksums(options.checksums)
+    .with_bit_order(options.bit_order)
+}
+
+/// Decodes the `n`th element of a compressed object whose root is a list.
+///
+/// Instead of scanning the whole object, the decoder seeks to the closest
+/// indexed element before `n` and only skips over the elements in between.
+pub fn decode_element(
+  schema: &Schema,
+  bytes: &[u8],
+  index: &Index,
+  n: usize,
+) -> Result<Value> {
+  decode_element_with(schema, bytes, index, n, DecodeOptions::default())
+}
+
+/// Decodes the `n`th element of a compressed object whose root is a list,
+/// enforcing the limits in `options`.
+pub fn decode_element_with(
+  schema: &Schema,
+  bytes: &[u8],
+  index: &Index,
+  n: usize,
+  options: DecodeOptions,
+) -> Result<Value> {
+  let list = match schema.root() {
+    Type::Nested(CompositeType::List(l)) => l,
+    _ => bail!("root type is not a list"),
+  };
+
+  let (bytes, dictionaries) = split_dictionaries(schema, bytes)?;
+  let mut r = reader(schema, bytes, &options);
+  let offset = index.seek_offset(n)?;
+  r.seek(offset as usize)
+    .ok_or_else(|| anyhow!("index offset is out of bounds"))?;
+
+  // Offsets are those of the chunk header of elements which start a chunk
+  let len = index.len();
+  skip_elements(list, n - n % index.stride()..n, len, true, &mut r)?;
+  read_sync(n, len, &mut r)?;
+  read_chunk_header(n, len, &mut r)?;
+  let mut value = decode_element_at(list, &mut r, options)
+    .map_err(|e| within(e, Segment::Index(n), "decoding"))?;
+  let path = Path(vec![Segment::Index(n)]);
+  Resolver::new(schema, dictionaries).resolve_at(&path, &mut value)?;
+  Ok(value)
+}
+
+/// Decodes a compressed object whose root is a list, decoding runs of its
+/// elements on the rayon thread pool and joining them back together.
+///
+/// A run starts at every chunk if the schema chunks lists, otherwise at every
+/// offset recorded in `index`. Without either, the list is decoded as a
+/// single run. The limits in `options` apply to each run separately.
+#[cfg(feature = "parallel")]
+pub fn decode_parallel(
+  schema: &Schema,
+  bytes: &[u8],
+  index: Option<&Index>,
+  options: DecodeOptions,
+) -> Result<Value> {
+  use rayon::prelude::*;
+
+  let list = match schema.root() {
+    Type::Nested(CompositeType::List(l)) => l,
+    _ => bail!("root type is not a list"),
+  };
+  let (bytes, dictionaries) = split_dictionaries(schema, bytes)?;
+  let mut r = reader(schema, bytes, &options);
+  let len = read_length(&mut r)?;
+
+  let mut runs = Vec::new();
+  match (r.chunks(), index) {
+    (Some(size), _) if len > size => {
+      for start in (0..len).step_by(size) {
+        let offset = r.position();
+        read_sync(start, len, &mut r)?;
+        if let Some(bits) = read_chunk_header(start, len, &mut r)? {
+          r.skip(bits).ok_or_else(|| truncated(&r, bits))?;
+        }
+        runs.push((offset, start..len.min(start + size)));
+      }
+    }
+    (_, Some(index)) => {
+      if index.len() != len {
+        bail!("index does not match the list's length");
+      }
+      let starts = (0..len).step_by(index.stride());
+      for (&offset, start) in index.offsets().iter().zip(starts) {
+        runs.push((offset as usize, start..len.min(start + index.stride())));
+      }
+    }
+    _ => runs.push((r.position(), 0..len)),
+  }
+
+  let parts: Vec<Result<Vec<Value>>> = runs
+    .par_iter()
+    .map(|(offset, range)| {
+      let mut r = r.clone();
+      r.seek(*offset)
+        .ok_or_else(|| anyhow!("element offset is out of bounds"))?;
+      range
+        .clone()
+        .map(|i| {
+          read_sync(i, len, &mut r)
+            .and_then(|_| read_chunk_header(i, len, &mut r))
+            .and_then(|_| decode_element_at(list, &mut r, options))
+            .map_err(|e| within(e, Segment::Index(i), "decoding"))
+        })
+        .collect()
+    })
+    .collect();
+
+  let mut arr = Vec::new();
+  for part in parts {
+    arr.extend(part?);
+  }
+  let mut value = Value::Array(arr);
+  Dictionaries::resolve(schema, dictionaries.as_ref(), &mut value)?;
+  epoch::resolve(schema, &mut value)?;
+  Ok(value)
+}
+
+/// Decodes only the value found at `path` within a compressed object.
+///
+/// Fields and elements which are not on the path are skipped over using their
+/// widths and lengths without being decompressed. Returns `None` if the path
+/// refers to a field which is not present in the object or to an element past
+/// the end of a list.
+pub fn decode_path(
+  schema: &Schema,
+  bytes: &[u8],
+  path: &Path,
+) -> Result<Option<Value>> {
+  decode_path_with(schema, bytes, path, DecodeOptions::default())
+}
+
+/// Decodes only the value found at `path` within a compressed object,
+/// enforcing the limits in `options`.
+pub fn decode_path_with(
+  schema: &Schema,
+  bytes: &[u8],
+  path: &Path,
+  options: DecodeOptions,
+) -> Result<Option<Value>> {
+  let (bytes, dictionaries) = split_dictionaries(schema, bytes)?;
+  let mut r = reader(schema, bytes, &options);
+  let target = seek_root(schema, &mut r, path.segments())
+    .map_err(|e| within_path(e, path, "decoding"))?;
+
+  let value = match target {
+    None => Ok(None),
+    Some(Target::Composite(ct, root)) => {
+      decode_composite_type(ct, root, r, options).map(Some)
+    }
+    Some(Target::List(list, len)) => (0..len)
+      .map(|i| {
+        read_chunk_header(i, len, &mut r)
+          .and_then(|_| decode_element_at(list, &mut r, options))
+          .map_err(|e| within(e, Segment::Index(i), "decoding"))
+      })
+      .collect::<Result<_>>()
+      .map(|arr| Some(Value::Array(arr))),
+    Some(Target::Inline(fields)) => {
+      build_value(&mut Events::inline(r, fields, options), None).map(Some)
+    }
+    Some(Target::Null) => Ok(Some(Value::Null)),
+    Some(Target::Value(ty)) => decode_value(ty, &mut r).map(Some),
+  }
+  .map_err(|e| within_path(e, path, "decoding"))?;
+
+  match value {
+    Some(mut value) => {
+      Resolver::new(schema, dictionaries).resolve_at(path, &mut value)?;
+      Ok(Some(value))
+    }
+    None => Ok(None),
+  }
+}
+
+/// The type of value found at the end of a path.
+pub(crate) enum Target<'s> {
+  /// A record or list, along with whether it is the root object.
+  Composite(&'s CompositeType, bool),
+  /// A nullable list whose header holding `len` elements has been read.
+  List(&'s List, usize),
+  /// An inline record whose fields are read by the cursor.
+  Inline(Fields<'s>),
+  /// A nullable list which is null.
+  Null,
+  /// A non-nested field or element.
+  Value(&'s Type),
+}
+
+/// Walks the root type of `schema` following `segments`, leaving the reader
+/// positioned at the start of the value at the end of the path.
+pub(crate) fn seek_root<'s>(
+  schema: &'s Schema,
+  r: &mut BitReader,
+  segments: &[Segment],
+) -> Result<Option<Target<'s>>> {
+  match (schema.root(), segments) {
+    (Type::Nested(ct), _) => seek_path(ct, true, r, segments),
+    (ty, []) => Ok(Some(Target::Value(ty))),
+    (_, _) => bail!("cannot index into a scalar value"),
+  }
+}
+
+/// Walks a composite type following `segments`, leaving the reader positioned
+/// at the start of the value at the end of the path.
+///
+/// Returns `None` if the path refers to a field which is not present or to an
+/// element past the end of a list.
+pub(crate) fn seek_path<'s>(
+  ct: &'s CompositeType,
+  root: bool,
+  r: &mut BitReader,
+  segments: &[Segment],
+) -> Result<Option<Target<'s>>> {
+  let (segment, rest) = match segments.split_first() {
+    Some(s) => s,
+    None => return Ok(Some(Target::Composite(ct, root))),
+  };
+
+  match (ct, segment) {
+    (CompositeType::Record(rec), Segment::Field(name)) => {
+      seek_field(Fields::new(rec, root), name, r, rest)
+    }
+
+    (CompositeType::List(l), _) => {
+      let len = read_length(r)?;
+      seek_element(l, len, root, r, segments)
+    }
+
+    (CompositeType::Record(_), Segment::Index(_)) => {
+      bail!("cannot index into a record")
+    }
+  }
+}
+
+/// Follows a path into the field `name` of a record whose fields are read by
+/// `fields`.
+fn seek_field<'s>(
+  mut fields: Fields<'s>,
+  name: &str,
+  r: &mut BitReader,
+  rest: &[Segment],
+) -> Result<Option<Target<'s>>> {
+  while let Some((field, ty)) = fields.next(r)? {
+    if let Some(inline) = fields.inline(field) {
+      if field != name {
+        skip_fields(inline, r)?;
+        continue;
+      }
+      return match rest.split_first() {
+        None => Ok(Some(Target::Inline(inline))),
+        Some((Segment::Field(name), rest)) => seek_field(inline, name, r, rest),
+        Some((Segment::Index(_), _)) => bail!("cannot index into a record"),
+      };
+    }
+    let nullable = fields.record.is_nullable(field);
+    if field == name {
+      return seek_value(ty, nullable, r, rest);
+    }
+    skip_field(ty, nullable, r)?;
+  }
+  Ok(None)
+}
+
+/// Follows a path into a list of `len` elements whose header has already
+/// been read, which is the root object if `root` is `true`.
+fn seek_element<'s>(
+  list: &'s List,
+  len: usize,
+  root: bool,
+  r: &mut BitReader,
+  segments: &[Segment],
+) -> Result<Option<Target<'s>>> {
+  match segments.split_first() {
+    Some((Segment::Index(i), _)) if *i >= len => Ok(None),
+    Some((Segment::Index(i), rest)) => {
+      skip_elements(list, 0..*i, len, root, r)?;
+      if root {
+        read_sync(*i, len, r)?;
+      }
+      read_chunk_header(*i, len, r)?;
+      let ty = read_tag(list.0.as_ref(), r)?;
+      seek_value(ty, false, r, rest)
+    }
+    Some((Segment::Field(_), _)) => bail!("cannot select a field from a list"),
+    None => Ok(Some(Target::List(list, len))),
+  }
+}
+
+/// Follows the remainder of a path starting at a field or element of type
+/// `ty`, which may be null if it is a `nullable` list.
+fn seek_value<'s>(
+  ty: &'s Type,
+  nullable: bool,
+  r: &mut BitReader,
+  rest: &[Segment],
+) -> Result<Option<Target<'s>>> {
+  match ty {
+    Type::Nested(CompositeType::List(l)) if nullable => {
+      match read_list_length(r, true)? {
+        Some(len) => seek_element(l, len, false, r, rest),
+        None if rest.is_empty() => Ok(Some(Target::Null)),
+        None => Ok(None),
+      }
+    }
+    Type::Nested(ct) => seek_path(ct, false, r, rest),
+    _ if rest.is_empty() => Ok(Some(Target::Value(ty))),
+    _ => bail!("cannot follow path into a non-nested value"),
+  }
+}
+
+/// Decodes a composite type starting at the current position of `r`.
+fn decode_composite_type(
+  ct: &CompositeType,
+  root: bool,
+  r: BitReader,
+  options: DecodeOptions,
+) -> Result<Value> {
+  build_value(&mut Events::new(r, ct, root, options), None)
+}
+
+/// Builds the value of a composite type from its [`Event`]s using an explicit
+/// stack instead of recursion.
+///
+/// If `diagnostics` is given, problems are recorded there instead of being
+/// returned and the partially built value is returned after an error.
+fn build_value(
+  events: &mut Events,
+  mut diagnostics: Option<&mut Vec<Diagnostic>>,
+) -> Result<Value> {
+  let mut stack = Vec::new();
+  while let Some(event) = events.next() {
+    let event = match event {
+      Ok(event) => event,
+      Err(e) => {
+        let path = partial_path(&stack);
+        let diagnostics = match diagnostics.as_mut() {
+          Some(diagnostics) => diagnostics,
+          None => return Err(within_path(e, &path, "decoding")),
+        };
+        let limited = matches!(
+          e.downcast_ref::<Error>(),
+          Some(Error::LimitExceeded { .. })
+            | Some(Error::BudgetExceeded { .. })
+        );
+        diagnostics.push(diagnostic(e, &path, Events::position(events)));
+
+        // Damage within a root list is skipped up to its next sync marker
+        let from = match stack.first() {
+          Some(Partial::List(arr)) if !limited => arr.len(),
+          _ => return Ok(close_partials(stack)),
+        };
+        let next = match events.resync(from) {
+          Some(next) => next,
+          None => return Ok(close_partials(stack)),
+        };
+        let offset = Events::position(events);
+        diagnostics.push(Diagnostic {
+          path: Path::root(),
+          offset,
+          message: format!(
+            "lost elements {}..{} before the sync marker at bit {}",
+            from, next, offset
+          ),
+        });
+        stack.truncate(1);
+        if let Some(Partial::List(arr)) = stack.first_mut() {
+          arr.resize(next, Value::Null);
+        }
+        continue;
+      }
+    };
+
+    if let (Some(diagnostics), Some(e)) =
+      (diagnostics.as_mut(), events.take_damaged())
+    {
+      let path = partial_path(&stack);
+      diagnostics.push(diagnostic(e, &path, Events::position(events)));
+    }
+
+    let value = match event {
+      Event::StartRecord => {
+        stack.push(Partial::Record(Map::new(), None));
+        continue;
+      }
+      Event::StartList(_) => {
+        stack.push(Partial::List(Vec::new()));
+        continue;
+      }
+      Event::Field(name) => {
+        if let Some(Partial::Record(_, key)) = stack.last_mut() {
+          *key = Some(name.to_string());
+        }
+        continue;
+      }
+      Event::Value(v) => v,
+      Event::EndRecord | Event::EndList => match stack.pop() {
+        Some(Partial::Record(map, _)) => Value::Object(map),
+        Some(Partial::List(arr)) => Value::Array(arr),
+        None => bail!("unbalanced end of composite type"),
+      },
+    };
+
+    match stack.last_mut() {
+      None => return Ok(value),
+      Some(Partial::Record(map, key)) => {
+        if let Some(k) = key.take() {
+          map.insert(k, value);
+        }
+      }
+      Some(Partial::List(arr)) => arr.push(value),
+    }
+  }
+
+  bail!("unbalanced start of composite type")
+}
+
+/// Completes the records and lists on the stack with the values decoded so
+/// far, returning the outermost one.
+///
+/// A field whose value was being decoded is left out of its record.
+fn close_partials(stack: Vec<Partial>) -> Value {
+  let mut value = None;
+  for partial in stack.into_iter().rev() {
+    value = Some(match partial {
+      Partial::Record(mut map, key) => {
+        if let (Some(k), Some(v)) = (key, value) {
+          map.insert(k, v);
+        }
+        Value::Object(map)
+      }
+      Partial::List(mut arr) => {
+        arr.extend(value);
+        Value::Array(arr)
+      }
+    });
+  }
+  value.unwrap_or(Value::Null)
+}
+
+/// Converts an error raised while decoding the value at `path` into a
+/// diagnostic.
+///
+/// The offset of an [`Error`] is used if it has one, otherwise `offset` is.
+fn diagnostic(e: anyhow::Error, path: &Path, offset: usize) -> Diagnostic {
+  let e = within_path(e, path, "decoding");
+  let (path, offset) = match e.downcast_ref::<Error>() {
+    Some(err) => (err.path().clone(), err.offset().unwrap_or(offset)),
+    None => (path.clone(), offset),
+  };
+  Diagnostic {
+    path,
+    offset,
+    message: format!("{:#}", e),
+  }
+}
+
+/// A record or list whose value is being decoded.
+enum Partial {
+  /// A record along with the name of the field currently being decoded.
+  Record(Map<String, Value>, Option<String>),
+  List(Vec<Value>),
+}
+
+/// The path of the value currently being decoded.
+fn partial_path(stack: &[Partial]) -> Path {
+  let segments = stack.iter().filter_map(|p| match p {
+    Partial::Record(_, key) => key.clone().map(Segment::Field),
+    Partial::List(arr) => Some(Segment::Index(arr.len())),
+  });
+  Path(segments.collect())
+}
+
+/// Decodes a single list element at the reader's current position.
+fn decode_element_at(
+  list: &List,
+  r: &mut BitReader,
+  options: DecodeOptions,
+) -> Result<Value> {
+  match read_tag(list.0.as_ref(), r)? {
+    Type::Nested(ct) => {
+      let mut events = Events::new(r.clone(), ct, false, options);
+      let value = build_value(&mut events, None)?;
+      r.seek(events.position())
+        .expect("decoding stays within the input");
+      Ok(value)
+    }
+    ty => decode_value(ty, r),
+  }
+}
+
+/// Reads the tag of a list element if the list's element type `ty` is a
+/// union, returning the type of the element.
+pub(crate) fn read_tag<'s>(
+  ty: &'s Type,
+  r: &mut BitReader,
+) -> Result<&'s Type> {
+  let alternatives = match ty {
+    Type::Union { alternatives } => alternatives,
+    ty => return Ok(ty),
+  };
+  let width = math::required_bit_width(alternatives.len());
+  let start = r.position();
+  let tag = r.read_rev_be(width).ok_or_else(|| truncated(r, width))?;
+  r.align();
+  alternatives.get(tag as usize).ok_or_else(|| {
+    Error::malformed(start, anyhow!("union has no type with tag {}", tag))
+  })
+}
+
+/// Reads the field markers of a record one at a time.
+///
+/// The fields of an inline record are marked among its parent's, so reading
+/// a marker of one of them yields the inline field and rewinds to the marker,
+/// which is then read again by the cursor returned from
+/// [`inline`](Fields::inline).
+#[derive(Clone, Debug)]
+pub(crate) struct Fields<'s> {
+  pub(crate) record: &'s Record,
+  root: bool,
+  layout: FieldLayout,
+  /// The width of the parent's markers and the identifier of the first field
+  /// of an inline record among them.
+  parent: Option<(usize, u32)>,
+  /// The inline fields which haven't been read yet.
+  pending: Vec<&'s str>,
+}
+
+/// The state of a [`Fields`] cursor apart from its record, which outlives
+/// the schema it was read with.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub(crate) struct FieldsState {
+  pub(crate) root: bool,
+  pub(crate) layout: FieldLayout,
+  pub(crate) parent: Option<(usize, u32)>,
+  pub(crate) pending: Vec<String>,
+}
+
+/// How the fields of a record are marked.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum FieldLayout {
+  /// The layout flag of a sparse record hasn't been read yet, or it isn't
+  /// known yet whether a fully required record is dense.
+  Unknown,
+  /// Each field starts with its marker.
+  Markers,
+  /// Each field starts with the difference between its marker and the
+  /// previous one, which is held here.
+  Deltas(u64),
+  /// There are no markers, every field is present in schema order and the
+  /// index of the next one is held here.
+  Dense(usize),
+}
+
+impl<'s> Fields<'s> {
+  pub(crate) fn new(record: &'s Record, root: bool) -> Self {
+    let layout = match record.sparse || record.is_dense() {
+      true => FieldLayout::Unknown,
+      false => FieldLayout::Markers,
+    };
+    Fields {
+      record,
+      root,
+      layout,
+      parent: None,
+      pending: Self::inline_fields(record),
+    }
+  }
+
+  /// Restores a cursor over the fields of `record` from a saved `state`.
+  pub(crate) fn restore(
+    record: &'s Record,
+    state: &FieldsState,
+  ) -> Result<Self> {
+    let pending = state
+      .pending
+      .iter()
+      .map(|name| match record.fields.get_key_value(name.as_str()) {
+        Some((name, _)) => Ok(name.as_str()),
+        None => bail!("record has no field named {}", name),
+      })
+      .collect::<Result<_>>()?;
+    Ok(Fields {
+      record,
+      root: state.root,
+      layout: state.layout,
+      parent: state.parent,
+      pending,
+    })
+  }
+
+  /// Saves the state of this cursor apart from its record.
+  pub(crate) fn save(&self) -> FieldsState {
+    FieldsState {
+      root: self.root,
+      layout: self.layout,
+      parent: self.parent,
+      pending: self.pending.iter().map(|name| name.to_string()).collect(),
+    }
+  }
+
+  fn inline_fields(record: &'s Record) -> Vec<&'s str> {
+    record
+      .fields
+      .keys()
+      .map(String::as_str)
+      .filter(|k| record.inline_record(k).is_some())
+      .collect()
+  }
+
+  /// Returns a cursor over the fields of the inline field `name`, or `None`
+  /// if the field isn't inline.
+  pub(crate) fn inline(&self, name: &str) -> Option<Fields<'s>> {
+    if let FieldLayout::Dense(_) = self.layout {
+      return None;
+    }
+    let record = self.record.inline_record(name)?;
+    let first = self.record.field_map()[name].index() as u32;
+    let (width, offset) = self.parent.unwrap_or((self.record.field_width(), 0));
+    Some(Fields {
+      record,
+      root: self.root,
+      layout: FieldLayout::Markers,
+      parent: Some((width, offset + first)),
+      pending: Self::inline_fields(record),
+    })
+  }
+
+  /// The marker of the field `name` among the markers read by this cursor.
+  pub(crate) fn marker(&self, name: &str) -> Option<u64> {
+    let id = self.record.field_map().get(name)?.index() as u64;
+    Some(self.parent.map_or(0, |p| p.1 as u64) + id + 1)
+  }
+
+  /// Reads the layout flag of a sparse record if it hasn't been read yet,
+  /// returning whether the fields are marked with the differences between
+  /// their markers.
+  pub(crate) fn read_layout(
+    &mut self,
+    r: &mut BitReader,
+  ) -> Result<Option<bool>> {
+    if let FieldLayout::Unknown = self.layout {
+      if r.profile() == Profile::Dense && self.record.is_dense() {
+        self.layout = FieldLayout::Dense(0);
+        self.pending.clear();
+        return Ok(None);
+      }
+      if !self.record.sparse {
+        self.layout = FieldLayout::Markers;
+        return Ok(None);
+      }
+      let sparse = r.read_bit().ok_or_else(|| truncated(r, 1))?;
+      r.align();
+      self.layout = match sparse {
+        true => FieldLayout::Deltas(0),
+        false => FieldLayout::Markers,
+      };
+      return Ok(Some(sparse));
+    }
+    Ok(None)
+  }
+
+  /// Returns `false` if the record ends at the end of the input instead of
+  /// with a terminator, which is the case for the root record unless its
+  /// fields are marked with the differences between their markers, and for
+  /// inline and dense records.
+  pub(crate) fn has_terminator(&self) -> bool {
+    self.parent.is_none()
+      && !matches!(self.layout, FieldLayout::Dense(_))
+      && (!self.root || matches!(self.layout, FieldLayout::Deltas(_)))
+  }
+
+  /// Reads the next field marker returning the name and type of the field it
+  /// refers to, or `None` at the end of the record.
+  ///
+  /// Inline fields are always returned, even if none of their record's
+  /// fields are present. The end of an inline record is found by reading the
+  /// marker after it, which is left to be read again.
+  pub(crate) fn next(
+    &mut self,
+    r: &mut BitReader,
+  ) -> Result<Option<(&'s String, &'s Type)>> {
+    self.read_layout(r)?;
+    let start = r.position();
+    let marker = match self.layout {
+      FieldLayout::Dense(i) => {
+        self.layout = FieldLayout::Dense(i + 1);
+        return Ok(self.record.ordered_fields().nth(i));
+      }
+      FieldLayout::Deltas(prev) => {
+        let delta = read_vie_length(r)?;
+        r.align();
+        match delta.and_then(|delta| prev.checked_add(delta)) {
+          Some(marker) if marker == prev => return self.end(start, r),
+          Some(marker) => marker,
+          None => {
+            let e = anyhow!("field delta is too large");
+            return Err(Error::malformed(start, e));
+          }
+        }
+      }
+      _ => {
+        let width = self.parent.map_or(self.record.field_width(), |p| p.0);
+        if self.root && r.remaining() < width {
+          return self.end(start, r);
+        }
+        let marker = r.read_rev_be(width).ok_or_else(|| truncated(r, width))?;
+        r.align();
+        if marker == 0 {
+          return self.end(start, r);
+        }
+        marker
+      }
+    };
+
+    let id = match self.parent {
+      Some((_, first)) => match (marker - 1).checked_sub(first as u64) {
+        Some(id) if id < self.record.id_count() as u64 => id,
+        _ => return self.end(start, r),
+      },
+      None => marker - 1,
+    };
+    let field = u32::try_from(id)
+      .ok()
+      .and_then(|id| self.record.field_by_id(FieldId::new(id)))
+      .ok_or_else(|| {
+        Error::malformed(start, anyhow!("unexpected field id: {}", marker))
+      })?;
+    if let FieldLayout::Deltas(prev) = &mut self.layout {
+      *prev = marker;
+    }
+    if self.record.inline_record(field.0).is_some() {
+      let i = self.pending.iter().position(|k| *k == field.0);
+      let i = i.ok_or_else(|| {
+        let e = anyhow!("fields of inline record {} are split up", field.0);
+        Error::malformed(start, e)
+      })?;
+      self.pending.remove(i);
+      r.seek(start);
+    }
+    Ok(Some(field))
+  }
+
+  /// Ends the record at the marker read from `start`, unless an inline field
+  /// hasn't been read yet in which case it is returned instead. Either way,
+  /// the marker is left to be read again unless it is a terminator which
+  /// ends the record.
+  fn end(
+    &mut self,
+    start: usize,
+    r: &mut BitReader,
+  ) -> Result<Option<(&'s String, &'s Type)>> {
+    if self.parent.is_some() || !self.pending.is_empty() {
+      r.seek(start);
+    }
+    match self.pending.pop() {
+      Some(name) => Ok(self.record.fields.get_key_value(name)),
+      None => Ok(None),
+    }
+  }
+}
+
+/// Decodes a non-nested field or element.
+pub(crate) fn decode_value(ty: &Type, r: &mut BitReader) -> Result<Value> {
+  read_value(ty, r)?
+}
+
+/// Reads and decompresses a non-nested field or element.
+///
+/// The outer result fails if the extent of the value can't be determined. The
+/// inner result fails if the value's data can't be decompressed, in which
+/// case the reader is left at the end of the value.
+pub(crate) fn read_value(
+  ty: &Type,
+  r: &mut BitReader,
+) -> Result<Result<Value>> {
+  let compressor = get_compressor_for_type(ty)?;
+  let len = read_width(compressor.as_ref(), r)?;
+
+  // Check the length against the remaining input before allocating space for
+  // the data as a corrupted length may be arbitrarily large
+  if len > r.remaining() {
+    return Err(truncated(r, len));
+  }
+  let start = r.position();
+  let bits = r.read_bits(len).ok_or_else(|| truncated(r, len))?;
+  r.align();
+  if let Some(crc) = read_checksum(compressor.as_ref(), r)? {
+    if crc != bit::crc8(&bits) {
+      let e = anyhow!("data does not match its checksum {:#04x}", crc);
+      return Ok(Err(Error::malformed(start, e)));
+    }
+  }
+  let value = compressor
+    .decompress(bits)
+    .map(Into::into)
+    .map_err(|e| Error::malformed(start, e));
+  #[cfg(feature = "tracing")]
+  tracing::trace!(offset = start, bits = len, "decompressed");
+  Ok(value)
+}
+
+/// Reads the width of the data of a value compressed by `compressor`, which
+/// is the value's length if it has one.
+fn read_width(compressor: &dyn Compressor, r: &mut BitReader) -> Result<usize> {
+  match compressor.encoded_width() {
+    EncodedWidth::Fixed(n) => Ok(n),
+    EncodedWidth::Variable => read_length(r),
+    EncodedWidth::Prefix => {
+      let mut peek = r.clone();
+      compressor
+        .prefix_len(&mut core::iter::from_fn(|| peek.read_bit()))
+        .map_err(|e| Error::malformed(r.position(), e))
+    }
+  }
+}
+
+/// Reads the checksum after the data of a value compressed by `compressor`,
+/// if it has one.
+fn read_checksum(
+  compressor: &dyn Compressor,
+  r: &mut BitReader,
+) -> Result<Option<u8>> {
+  if !r.checksums() || compressor.encoded_width() != EncodedWidth::Variable {
+    return Ok(None);
+  }
+  let crc = r.read_be(8).ok_or_else(|| truncated(r, 8))?;
+  r.align();
+  Ok(Some(crc as u8))
+}
+
+/// Skips over a single list element.
+pub(crate) fn skip_element(list: &List, r: &mut BitReader) -> Result<()> {
+  match read_tag(list.0.as_ref(), r)? {
+    Type::Nested(ct) => skip_composite_type(ct, r),
+    ty => skip_value(ty, r),
+  }
+}
+
+/// Skips over a nested composite type without decoding any of its data.
+pub(crate) fn skip_composite_type(
+  ct: &CompositeType,
+  r: &mut BitReader,
+) -> Result<()> {
+  match ct {
+    CompositeType::Record(rec) => skip_fields(Fields::new(rec, false), r)?,
+    CompositeType::List(l) => skip_list(l, false, r)?,
+  }
+  Ok(())
+}
+
+/// Skips over the fields read by `fields`.
+pub(crate) fn skip_fields(mut fields: Fields, r: &mut BitReader) -> Result<()> {
+  while let Some((name, ty)) = fields.next(r)? {
+    match fields.inline(name) {
+      Some(inline) => skip_fields(inline, r)?,
+      None => skip_field(ty, fields.record.is_nullable(name), r)?,
+    }
+  }
+  Ok(())
+}
+
+/// Skips over a record field of type `ty`, which may be null if it is a
+/// `nullable` list.
+pub(crate) fn skip_field(
+  ty: &Type,
+  nullable: bool,
+  r: &mut BitReader,
+) -> Result<()> {
+  match ty {
+    Type::Nested(CompositeType::List(l)) => skip_list(l, nullable, r),
+    Type::Nested(ct) => skip_composite_type(ct, r),
+    ty => skip_value(ty, r),
+  }
+}
+
+/// Skips over a list, which may be null if it is `nullable`.
+fn skip_list(list: &List, nullable: bool, r: &mut BitReader) -> Result<()> {
+  let len = read_list_length(r, nullable)?.unwrap_or(0);
+  skip_elements(list, 0..len, len, false, r)
+}
+
+/// Skips over the elements in `range` of a list of `len` elements, which is
+/// the root object if `root` is `true`, skipping whole chunks at a time where
+/// `range` covers them.
+pub(crate) fn skip_elements(
+  list: &List,
+  range: Range<usize>,
+  len: usize,
+  root: bool,
+  r: &mut BitReader,
+) -> Result<()> {
+  let mut i = range.start;
+  while i < range.end {
+    if root {
+      read_sync(i, len, r)?;
+    }
+    if let Some(bits) = read_chunk_header(i, len, r)? {
+      let end = len.min(i + r.chunks().unwrap_or(len));
+      if end <= range.end {
+        r.skip(bits).ok_or_else(|| truncated(r, bits))?;
+        i = end;
+        continue;
+      }
+    }
+    skip_element(list, r)?;
+    i += 1;
+  }
+  Ok(())
+}
+
+/// Reads the header of the chunk which starts at element `i` of a list of
+/// `len` elements, if lists are chunked and a chunk starts there. Returns the
+/// number of bits the elements of the chunk take up.
+pub(crate) fn read_chunk_header(
+  i: usize,
+  len: usize,
+  r: &mut BitReader,
+) -> Result<Option<usize>> {
+  match r.chunks() {
+    Some(size) if len > size && i % size == 0 => read_length(r).map(Some),
+    _ => Ok(None),
+  }
+}
+
+/// Reads the sync block before element `i` of a root list of `len` elements,
+/// if the input has sync blocks and one precedes that element. Returns
+/// whether the block holds a sync marker.
+pub(crate) fn read_sync(
+  i: usize,
+  len: usize,
+  r: &mut BitReader,
+) -> Result<bool> {
+  let synced = match r.chunks() {
+    _ if !r.sync() => false,
+    Some(size) if len > size => i % size == 0,
+    _ => true,
+  };
+  if !synced {
+    return Ok(false);
+  }
+
+  let start = r.position();
+  let marker = r.read_bit().ok_or_else(|| truncated(r, 1))?;
+  r.align();
+  if !marker {
+    return Ok(false);
+  }
+  if r.read_be(64).ok_or_else(|| truncated(r, 64))? != SYNC_MAGIC {
+    return Err(Error::malformed(start, anyhow!("invalid sync marker")));
+  }
+  match read_length(r)? {
+    n if n == i => Ok(true),
+    n => Err(Error::malformed(
+      start,
+      anyhow!("sync marker for element {} found at element {}", n, i),
+    )),
+  }
+}
+
+/// Scans forward from bit `start` for the first sync marker of a root list
+/// of `len` elements which is for element `from` or later, leaving the reader
+/// at the start of its sync block. Returns the element the marker is for, or
+/// `None` if there is no such marker.
+pub(crate) fn find_sync(
+  from: usize,
+  len: usize,
+  start: usize,
+  r: &mut BitReader,
+) -> Option<usize> {
+  for pos in start..r.len() {
+    r.seek(pos)?;
+    let i = match read_marker(r) {
+      Some(i) if i >= from && i < len => i,
+      _ => continue,
+    };
+    let mut at = r.clone();
+    at.seek(pos)?;
+    if let Ok(true) = read_sync(i, len, &mut at) {
+      r.seek(pos)?;
+      return Some(i);
+    }
+  }
+  None
+}
+
+/// Reads a sync marker, returning the element it is for, or `None` if the
+/// reader isn't at one.
+fn read_marker(r: &mut BitReader) -> Option<usize> {
+  if !r.read_bit()? {
+    return None;
+  }
+  r.align();
+  match r.read_be(64)? {
+    SYNC_MAGIC => read_length(r).ok(),
+    _ => None,
+  }
+}
+
+/// Skips over a non-nested field or element.
+pub(crate) fn skip_value(ty: &Type, r: &mut BitReader) -> Result<()> {
+  let compressor = get_compressor_for_type(ty)?;
+  let len = read_width(compressor.as_ref(), r)?;
+  r.skip(len).ok_or_else(|| truncated(r, len))?;
+  r.align();
+  read_checksum(compressor.as_ref(), r)?;
+  Ok(())
+}
+
+/// Splits the byte representation of a compressed object into the bit ranges
+/// of its individual blocks.
+///
+/// Blocks which don't take up any bits (e.g., the header of a record nested
+/// directly within a list) are omitted, as is any padding after the last
+/// block.
+pub(crate) fn block_spans(
+  schema: &Schema,
+  bytes: &[u8],
+  options: &DecodeOptions,
+) -> Result<Vec<Range<usize>>> {
+  let mut r = reader(schema, bytes, options);
+  let mut spans = Vec::new();
+  match schema.root() {
+    Type::Nested(CompositeType::Record(rec)) => {
+      spans_record(Fields::new(rec, true), &mut r, &mut spans)?
+    }
+    Type::Nested(CompositeType::List(l)) => {
+      spans_list(l, false, true, 0, &mut r, &mut spans)?
+    }
+    ty => {
+      skip_value(ty, &mut r)?;
+      spans.push(0..r.position());
+    }
+  }
+  Ok(spans)
+}
+
+/// Collects the spans of the fields read by `fields` and the record's
+/// terminator.
+fn spans_record(
+  mut fields: Fields,
+  r: &mut BitReader,
+  spans: &mut Vec<Range<usize>>,
+) -> Result<()> {
+  loop {
+    let start = r.position();
+    let next = fields.next(r)?;
+    if let Some(inline) = next.and_then(|(name, _)| fields.inline(name)) {
+      spans_record(inline, r, spans)?;
+      continue;
+    }
+    match next {
+      Some((name, Type::Nested(CompositeType::List(l)))) => {
+        let nullable = fields.record.is_nullable(name);
+        spans_list(l, nullable, false, start, r, spans)?
+      }
+      Some((_, ty)) => spans_value(ty, start, r, spans)?,
+      None => {
+        if fields.has_terminator() {
+          push_span(spans, start..r.position());
+        }
+        return Ok(());
+      }
+    }
+  }
+}
+
+/// Collects the spans of a list's header and elements, where the list may be
+/// null if it is `nullable` and is the root object if `root` is `true`.
+fn spans_list(
+  list: &List,
+  nullable: bool,
+  root: bool,
+  start: usize,
+  r: &mut BitReader,
+  spans: &mut Vec<Range<usize>>,
+) -> Result<()> {
+  let len = read_list_length(r, nullable)?.unwrap_or(0);
+  push_span(spans, start..r.position());
+  for i in 0..len {
+    if root {
+      let start = r.position();
+      read_sync(i, len, r)?;
+      push_span(spans, start..r.position());
+    }
+    let start = r.position();
+    read_chunk_header(i, len, r)?;
+    push_span(spans, start..r.position());
+    let start = r.position();
+    let ty = read_tag(list.0.as_ref(), r)?;
+    push_span(spans, start..r.position());
+    spans_value(ty, r.position(), r, spans)?;
+  }
+  Ok(())
+}
+
+/// Collects the spans of a field or element whose header (if any) started at
+/// bit position `start`.
+fn spans_value(
+  ty: &Type,
+  start: usize,
+  r: &mut BitReader,
+  spans: &mut Vec<Range<usize>>,
+) -> Result<()> {
+  match ty {
+    Type::Nested(CompositeType::Record(rec)) => {
+      push_span(spans, start..r.position());
+      spans_record(Fields::new(rec, false), r, spans)
+    }
+    Type::Nested(CompositeType::List(l)) => {
+      spans_list(l, false, false, start, r, spans)
+    }
+    _ => {
+      skip_value(ty, r)?;
+      push_span(spans, start..r.position());
+      Ok(())
+    }
+  }
+}
+
+fn push_span(spans: &mut Vec<Range<usize>>, span: Range<usize>) {
+  if !span.is_empty() {
+    spans.push(span);
+  }
+}
+
+/// Reads a [`Length`] section.
+///
+/// [`Length`]: crate::data::Length
+pub(crate) fn read_length(r: &mut BitReader) -> Result<usize> {
+  let start = r.position();
+  let overflow = || Error::LengthOverflow {
+    path: Path::root(),
+    offset: start,
+  };
+
+  let len = match r.lengths() {
+    LengthEncoding::Vie => read_vie_length(r)?.ok_or_else(overflow)?,
+    LengthEncoding::Fixed16 => r.read_be(16).ok_or_else(|| truncated(r, 16))?,
+    LengthEncoding::Fixed32 => r.read_be(32).ok_or_else(|| truncated(r, 32))?,
+    LengthEncoding::Gamma => {
+      let mut zeros = 0;
+      while !r.read_bit().ok_or_else(|| truncated(r, 1))? {
+        zeros += 1;
+        // `len + 1` has at most 64 bits, so any more zeros must overflow
+        if zeros == 64 {
+          return Err(overflow().into());
+        }
+      }
+      let low = r.read_be(zeros).ok_or_else(|| truncated(r, zeros))?;
+      ((1u64 << zeros) | low) - 1
+    }
+  };
+  r.align();
+  usize::try_from(len).map_err(|_| overflow().into())
+}
+
+/// Reads the length of a list, which is one more than its number of elements
+/// if the list is `nullable`. Returns `None` for a null list.
+pub(crate) fn read_list_length(
+  r: &mut BitReader,
+  nullable: bool,
+) -> Result<Option<usize>> {
+  let len = read_length(r)?;
+  Ok(match len {
+    _ if !nullable => Some(len),
+    0 => None,
+    len => Some(len - 1),
+  })
+}
+
+/// Reads a VIE code point, returning `None` if its value doesn't fit in 64
+/// bits.
+fn read_vie_length(r: &mut BitReader) -> Result<Option<u64>> {
+  // A u64 needs at most 10 bytes so any longer code point must overflow
+  const MAX_BYTES: usize = 10;
+
+  let mut bytes = Vec::with_capacity(MAX_BYTES);
+  loop {
+    if bytes.len() == MAX_BYTES {
+      return Ok(None);
+    }
+    let byte = r.read_byte().ok_or_else(|| truncated(r, 8))?;
+    bytes.push(byte);
+    if byte & 0x80 == 0 {
+      break;
+    }
+  }
+
+  // `bytes` is always a complete code point so this can't fail
+  let cp = CodePoint::parse(&bytes).unwrap();
+  Ok(cp.decode::<u64>())
+}
+
+/// Returns an error for input which ends before the next `needed` bits could
+/// be read.
+pub(crate) fn truncated(r: &BitReader, needed: usize) -> anyhow::Error {
+  Error::TruncatedInput {
+    path: Path::root(),
+    offset: r.position(),
+    needed,
+    remaining: r.remaining(),
+  }
+  .into()
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::bit::BitVec;
+  use crate::data::{Block, Length};
+  use crate::error::Limit;
+  use crate::schema::CompositeType;
+  use core::mem::size_of;
+  use serde_json::json;
+  use std::collections::{BTreeMap, BTreeSet};
+
+  fn enum_type(variants: &[&str]) -> Type {
+    Type::Enum {
+      variants: variants
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<BTreeSet<_>>(),
+      probabilities: None,
+    }
+  }
+
+  fn student_schema() -> Schema {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    course.insert("grade".to_string(), enum_type(&["A", "B", "C"]));
+
+    let mut student = BTreeMap::new();
+    student.insert("name".to_string(), Type::PassThrough);
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    Schema::new(CompositeType::Record(Record::new(student)))
+  }
+
+  fn roundtrip(schema: &Schema, value: &Value) -> Value {
+    let co = crate::encode(schema, value).unwrap();
+    let bits: BitVec = co.into();
+    decode(schema, &bits.to_bytes()).unwrap()
+  }
+
+  #[test]
+  fn decode_nested_record() {
+    let value = json!({
+      "name": "Jeremy",
+      "active": true,
+      "courses": [
+        { "name": "Math", "grade": "A" },
+        { "name": "Physics", "grade": "C" },
+        {}
+      ]
+    });
+    assert_eq!(value, roundtrip(&student_schema(), &value));
+  }
+
+  #[test]
+  fn decode_streamed_encoding() {
+    let schema = student_schema();
+    let value = json!({
+      "name": "Jeremy",
+      "active": false,
+      "courses": [{ "name": "Math", "grade": "B" }]
+    });
+    let mut bytes = Vec::new();
+    crate::encode_to(&schema, &value, &mut bytes).unwrap();
+
+    let bits: BitVec = crate::encode(&schema, &value).unwrap().into();
+    assert_eq!(bits.to_bytes(), bytes);
+    assert_eq!(value, decode(&schema, &bytes).unwrap());
+  }
+
+  #[test]
+  fn decode_limits_compression_bomb() {
+    // Records without any fields take up no bits at all, so a short length
+    // can claim an enormous number of elements
+    let schema = Schema::new(CompositeType::List(List(Box::new(
+      Type::Nested(CompositeType::Record(Record::new(BTreeMap::new()))),
+    ))));
+    let bytes = [0xff, 0xff, 0xff, 0x7f];
+
+    let options = DecodeOptions {
+      max_elements: 1000,
+      ..DecodeOptions::default()
+    };
+    let e = decode_with(&schema, &bytes, options).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert!(matches!(
+      e,
+      Error::LimitExceeded {
+        limit: Limit::Elements(_),
+        ..
+      }
+    ));
+  }
+
+  #[test]
+  fn decode_limits_size() {
+    let schema = student_schema();
+    let value = json!({ "name": "Jeremy", "active": true });
+    let bits: BitVec = crate::encode(&schema, &value).unwrap().into();
+    let bytes = bits.to_bytes();
+
+    let options = DecodeOptions {
+      max_size: 10,
+      ..DecodeOptions::default()
+    };
+    let e = decode_with(&schema, &bytes, options).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert_eq!("/name", e.pointer());
+
+    // Booleans count as 8 bytes and the name as its 6 bytes
+    let options = DecodeOptions {
+      max_size: 8 + 6,
+      ..DecodeOptions::default()
+    };
+    assert_eq!(value, decode_with(&schema, &bytes, options).unwrap());
+  }
+
+  #[test]
+  fn decode_memory_budget() {
+    let schema = student_schema();
+    let value = json!({ "name": "Jeremy", "active": true });
+    let bits: BitVec = crate::encode(&schema, &value).unwrap().into();
+    let bytes = bits.to_bytes();
+
+    // The record, both values, both field names and the name's characters
+    let needed = 3 * size_of::<Value>()
+      + 2 * size_of::<String>()
+      + "name".len()
+      + "active".len()
+      + "Jeremy".len();
+    let options = DecodeOptions {
+      max_memory: needed - 1,
+      ..DecodeOptions::default()
+    };
+    let e = decode_with(&schema, &bytes, options).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert!(
+      matches!(e, Error::BudgetExceeded { budget, .. } if budget == needed - 1)
+    );
+
+    let options = DecodeOptions {
+      max_memory: needed,
+      ..DecodeOptions::default()
+    };
+    assert_eq!(value, decode_with(&schema, &bytes, options).unwrap());
+  }
+
+  #[test]
+  fn decode_root_list() {
+    let schema = Schema::new(CompositeType::List(List(Box::new(Type::Name(
+      "bool".to_string(),
+    )))));
+    let value = json!([true, false, true]);
+    assert_eq!(value, roundtrip(&schema, &value));
+  }
+
+  #[test]
+  fn decode_scalar_root() {
+    let schema = Schema::new_scalar(Type::PassThrough);
+    let value = json!("token");
+    assert_eq!(value, roundtrip(&schema, &value));
+
+    // A single element block holding the length and data
+    let co = crate::encode(&schema, &value).unwrap();
+    assert_eq!(1, co.blocks.len());
+    co.validate(&schema).unwrap();
+
+    let bits: BitVec = co.into();
+    let bytes = bits.to_bytes();
+    assert_eq!(6, bytes.len());
+    let path = Path::root();
+    assert_eq!(Some(value), decode_path(&schema, &bytes, &path).unwrap());
+
+    let schema = Schema::new_scalar(Type::Name("bool".to_string()));
+    assert_eq!(json!(true), roundtrip(&schema, &json!(true)));
+  }
+
+  #[test]
+  fn decode_path_to_nested_field() {
+    let schema = student_schema();
+    let value = json!({
+      "name": "Jeremy",
+      "active": true,
+      "courses": [
+        { "name": "Math", "grade": "A" },
+        { "name": "Physics", "grade": "C" }
+      ]
+    });
+    let co = crate::encode(&schema, &value).unwrap();
+    let bits: BitVec = co.into();
+    let bytes = bits.to_bytes();
+
+    let get = |p: &str| decode_path(&schema, &bytes, &p.parse().unwrap());
+    assert_eq!(Some(json!("C")), get(".courses[1].grade").unwrap());
+    assert_eq!(Some(json!(true)), get(".active").unwrap());
+    assert_eq!(Some(value.clone()), get(".").unwrap());
+    assert_eq!(None, get(".courses[2]").unwrap());
+    assert!(get(".name.first").is_err());
+
+    // Byte-aligned objects must be read byte-aligned all the way to the path
+    let options = crate::EncodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let bytes = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+    let options = DecodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let get =
+      |p: &str| decode_path_with(&schema, &bytes, &p.parse().unwrap(), options);
+    assert_eq!(Some(json!("C")), get(".courses[1].grade").unwrap());
+    assert_eq!(Some(json!("Jeremy")), get(".name").unwrap());
+    assert_eq!(Some(value), get(".").unwrap());
+  }
+
+  #[test]
+  fn decode_chunked_lists() {
+    let schema = student_schema().with_chunks(2);
+    let value = json!({
+      "name": "Jeremy",
+      "courses": [
+        { "name": "Algebra", "grade": "A" },
+        { "name": "Biology", "grade": "B" },
+        { "name": "Chemistry", "grade": "C" },
+        { "name": "Drama", "grade": "B" },
+        { "name": "English", "grade": "A" }
+      ]
+    });
+    let co = crate::encode(&schema, &value).unwrap();
+    co.validate(&schema).unwrap();
+    let is_chunk = |b: &&Block| matches!(b, Block::ChunkHeader(_));
+    assert_eq!(3, co.blocks.iter().filter(is_chunk).count());
+    let estimate = crate::estimate_size(&schema, &value).unwrap();
+    assert_eq!(co.bit_len(), estimate.total());
+
+    let bytes = co.to_bytes();
+    assert_eq!(value, decode(&schema, &bytes).unwrap());
+    crate::spec::verify_bytes(&schema, &bytes).unwrap();
+    let mut streamed = Vec::new();
+    crate::encode_to(&schema, &value, &mut streamed).unwrap();
+    assert_eq!(bytes, streamed);
+
+    // Whole chunks are skipped to reach later elements and fields
+    let get = |p: &str| decode_path(&schema, &bytes, &p.parse().unwrap());
+    assert_eq!(Some(json!("English")), get(".courses[4].name").unwrap());
+    assert_eq!(Some(json!("B")), get(".courses[3].grade").unwrap());
+    assert_eq!(Some(json!("Jeremy")), get(".name").unwrap());
+    assert_eq!(Some(value["courses"].clone()), get(".courses").unwrap());
+
+    // Lists which fit in a single chunk aren't split
+    let short = json!({ "courses": [{ "grade": "A" }, { "grade": "B" }] });
+    assert_eq!(
+      crate::encode(&student_schema(), &short).unwrap(),
+      crate::encode(&schema, &short).unwrap()
+    );
+
+    // Chunk headers must hold the length of their chunk
+    let mut broken = co;
+    let i = broken.blocks.iter().position(|b| is_chunk(&b)).unwrap();
+    broken.blocks[i] = Block::ChunkHeader(Length::new(1));
+    assert!(broken.validate(&schema).is_err());
+    let e = crate::spec::verify_bytes(&schema, &broken.to_bytes()).unwrap_err();
+    let violation = e.downcast_ref::<crate::spec::Violation>().unwrap();
+    assert_eq!(crate::spec::CHUNK_LENGTH, violation.rule);
+  }
+
+  #[cfg(feature = "parallel")]
+  #[test]
+  fn decode_parallel_runs() {
+    let value = json!([
+      { "name": "Algebra", "grade": "A" },
+      { "name": "Biology" },
+      { "name": "Chemistry", "grade": "C" },
+      { "grade": "B" },
+      { "name": "English", "grade": "A" }
+    ]);
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    course.insert("grade".to_string(), enum_type(&["A", "B", "C"]));
+    let courses = CompositeType::List(List(Box::new(Type::Nested(
+      CompositeType::Record(Record::new(course)),
+    ))));
+
+    // Runs are chunks, strides of an index or the whole list
+    for chunks in &[None, Some(2), Some(3)] {
+      let schema = match chunks {
+        Some(size) => Schema::new(courses.clone()).with_chunks(*size),
+        None => Schema::new(courses.clone()),
+      };
+      let bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+      let index = Index::build(&schema, &bytes, 2).unwrap();
+      for index in &[None, Some(&index)] {
+        let options = DecodeOptions::default();
+        let decoded = decode_parallel(&schema, &bytes, *index, options);
+        assert_eq!(value, decoded.unwrap());
+      }
+    }
+
+    let e =
+      decode_parallel(&student_schema(), &[], None, DecodeOptions::default());
+    assert!(e.is_err());
+  }
+
+  #[test]
+  fn decode_truncated_input() {
+    let value = json!({ "name": "Jeremy" });
+    let schema = student_schema();
+    let co = crate::encode(&schema, &value).unwrap();
+    let bits: BitVec = co.into();
+    let bytes = bits.to_bytes();
+    assert!(decode(&schema, &bytes[..bytes.len() - 2]).is_err());
+  }
+
+  #[test]
+  fn decode_corrupted_length() {
+    let schema =
+      Schema::new(CompositeType::List(List(Box::new(Type::PassThrough))));
+
+    // A single element whose length is far larger than the input
+    let bytes = [0x01, 0xff, 0xff, 0xff, 0x7f];
+    let e = decode(&schema, &bytes).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert_eq!("/0", e.pointer());
+    assert!(matches!(
+      e,
+      Error::TruncatedInput {
+        offset: 40,
+        needed: 0x0fff_ffff,
+        remaining: 0,
+        ..
+      }
+    ));
+
+    // A length which doesn't fit in a u64
+    let mut bytes = vec![0x01];
+    bytes.extend_from_slice(&[0xff; 12]);
+    let e = decode(&schema, &bytes).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert!(matches!(e, Error::LengthOverflow { offset: 8, .. }));
+  }
+
+  #[test]
+  fn decode_lenient_skips_damaged_values() {
+    let schema =
+      Schema::new(CompositeType::List(List(Box::new(enum_type(&[
+        "A", "B", "C",
+      ])))));
+
+    // Four 2-bit elements where the second holds an index with no variant
+    let salvaged = decode_lenient(&schema, &[0x04, 0x34]);
+    assert_eq!(json!(["A", null, "C", "A"]), salvaged.value);
+    assert_eq!(1, salvaged.diagnostics.len());
+    let diagnostic = &salvaged.diagnostics[0];
+    assert_eq!("/1", diagnostic.path.to_pointer());
+    assert_eq!(10, diagnostic.offset);
+  }
+
+  #[test]
+  fn decode_lenient_stops_at_damaged_structure() {
+    let value = json!({
+      "name": "Jeremy",
+      "courses": [{ "name": "Math", "grade": "A" }, { "name": "Art" }]
+    });
+    let schema = student_schema();
+    let bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+
+    let salvaged = decode_lenient(&schema, &bytes);
+    assert!(salvaged.is_complete());
+    assert_eq!(value, salvaged.value);
+
+    // Truncating the input cuts off the name which is the last field
+    let salvaged = decode_lenient(&schema, &bytes[..bytes.len() - 2]);
+    assert_eq!(1, salvaged.diagnostics.len());
+    assert_eq!("/name", salvaged.diagnostics[0].path.to_pointer());
+    let mut expected = value;
+    expected.as_object_mut().unwrap().remove("name");
+    assert_eq!(expected, salvaged.value);
+  }
+
+  #[test]
+  fn decode_lenient_resyncs_at_sync_markers() {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    course.insert("grade".to_string(), enum_type(&["A", "B", "C"]));
+    let value = json!([
+      { "name": "Math", "grade": "A" },
+      { "name": "Art", "grade": "B" },
+      { "name": "Music", "grade": "C" },
+      { "name": "History", "grade": "A" },
+      { "name": "Biology", "grade": "B" }
+    ]);
+
+    let list = Schema::new(CompositeType::List(List(Box::new(Type::Nested(
+      CompositeType::Record(Record::new(course)),
+    )))))
+    .with_sync(1);
+    for schema in &[list.clone(), list.clone().with_chunks(2)] {
+      let co = crate::encode(schema, &value).unwrap();
+      let markers = co
+        .blocks
+        .iter()
+        .filter(|b| matches!(b, Block::Sync(Some(_))))
+        .count();
+      assert_eq!(if schema.chunks().is_some() { 3 } else { 5 }, markers);
+      let bytes = co.to_bytes();
+      assert_eq!(value, decode(schema, &bytes).unwrap());
+      assert_eq!(
+        json!("Music"),
+        decode_path(schema, &bytes, &"[2].name".parse().unwrap())
+          .unwrap()
+          .unwrap()
+      );
+    }
+
+    // Damaging the first field marker of the third element loses it, but
+    // decoding picks up again at the marker for the fourth one
+    let schema = list;
+    let mut bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+    let block = crate::inspect::inspect(&schema, &bytes)
+      .blocks
+      .into_iter()
+      .find(|b| b.path.to_pointer().starts_with("/2/"))
+      .unwrap();
+    let damage = BitVec::from_elem(2, true);
+    crate::bit::overwrite(&mut bytes, block.span.start, &damage).unwrap();
+    assert!(decode(&schema, &bytes).is_err());
+
+    let salvaged = decode_lenient(&schema, &bytes);
+    let mut expected = value;
+    expected[2] = Value::Null;
+    assert_eq!(expected, salvaged.value);
+    assert_eq!(2, salvaged.diagnostics.len());
+    assert_eq!("/2", salvaged.diagnostics[0].path.to_pointer());
+    assert!(salvaged.diagnostics[1]
+      .message
+      .starts_with("lost elements 2..3"));
+  }
+
+  #[test]
+  fn decode_checksums() {
+    let value = json!({
+      "name": "Jeremy",
+      "courses": [{ "name": "Math", "grade": "A" }, { "name": "Art" }]
+    });
+    let schema = student_schema();
+    let options = crate::EncodeOptions {
+      checksums: true,
+      ..Default::default()
+    };
+    let co = crate::encode_with(&schema, &value, &options).unwrap();
+    co.validate(&schema).unwrap();
+    // One checksum for each of the three names
+    let plain = crate::encode(&schema, &value).unwrap();
+    assert_eq!(plain.bit_len() + 24, co.bit_len());
+
+    let options = DecodeOptions {
+      checksums: true,
+      ..Default::default()
+    };
+    let mut bytes = co.to_bytes();
+    assert_eq!(value, decode_with(&schema, &bytes, options).unwrap());
+
+    // Flipping the first bit of "Art" is caught by its checksum, and only the
+    // name is lost
+    let i = co
+      .blocks
+      .iter()
+      .enumerate()
+      .filter(|(_, b)| matches!(b, Block::VariableWidthField(..)))
+      .nth(1)
+      .unwrap()
+      .0;
+    let offset = match &co.blocks[i] {
+      Block::VariableWidthField(f, l, _) => f.width + l.bit_len(),
+      _ => unreachable!(),
+    };
+    let offset =
+      offset + co.blocks[..i].iter().map(Block::bit_len).sum::<usize>();
+    bytes[offset / 8] ^= 0x80 >> (offset % 8);
+
+    let e = decode_with(&schema, &bytes, options).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert_eq!("/courses/1/name", e.pointer());
+    assert!(matches!(e, Error::Malformed { offset: o, .. } if o == offset));
+
+    let salvaged = decode_lenient_with(&schema, &bytes, options);
+    let mut expected = value;
+    expected["courses"][1]["name"] = Value::Null;
+    assert_eq!(expected, salvaged.value);
+    assert_eq!(1, salvaged.diagnostics.len());
+  }
+}
@@ -0,0 +1,538 @@
+//! The `dictionary` module implements a footer holding the most frequent
+//! values of the dictionary fields of an object, for fields whose values
+//! repeat but aren't known when the schema is written.
+//!
+//! A dictionary field is declared with the number of entries its dictionary
+//! may hold:
+//!
+//! ```yaml
+//! record:
+//!   requests:
+//!     list:
+//!       record:
+//!         method: { dictionary: 8 }
+//!         path: ~
+//! ```
+//!
+//! When an object is encoded to bytes with [`encode_to`], the values which
+//! occur more than once in each dictionary field are counted and the most
+//! frequent of them are put in that field's dictionary. Those values are
+//! encoded as indices into the dictionary and the rest as they are. The
+//! dictionaries are laid out after the object's bytes like so:
+//!
+//! ```text
+//! object | dictionaries | dictionaries offset | MAGIC
+//! ```
+//!
+//! The dictionaries are a JSON array holding the entries of each dictionary
+//! field in the order of the schema: fields by identifier with the element
+//! type of a list in place of the list. The dictionaries offset is an 8 byte
+//! little endian integer holding the byte position of the start of the
+//! dictionaries. Every object of a schema with dictionary fields has the
+//! footer, even if all of its dictionaries are empty, so that whether there
+//! is one never has to be guessed from the last bytes of the object. The
+//! footer of a [`CompressedObject`], which only ever holds values as they
+//! are, is always empty.
+//!
+//! [`decode`] looks up indices once the whole object is decoded. Functions
+//! which decode part of an object, such as [`decode_path`] and
+//! [`decode_events`], look up the indices of the values they decode as they
+//! go, using the position of each value's field in the schema to find its
+//! dictionary.
+//!
+//! [`CompressedObject`]: crate::data::CompressedObject
+//! [`decode`]: crate::decode
+//! [`decode_events`]: crate::decode_events
+//! [`decode_path`]: crate::decode_path
+//! [`encode_to`]: crate::encode_to
+
+use crate::error::Error;
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use crate::schema::{CompositeType, Schema, Type};
+use alloc::collections::BTreeMap;
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+
+/// Magic bytes found at the very end of an object with dictionaries.
+pub const MAGIC: &[u8; 4] = b"CHID";
+
+/// Size of the trailer (dictionaries offset + magic) in bytes.
+const TRAILER_LEN: usize = 8 + MAGIC.len();
+
+/// The dictionaries of an object, one for each dictionary field of its schema
+/// in schema order.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Dictionaries {
+  entries: Vec<Vec<String>>,
+}
+
+/// Whether `ty` is, or contains, a dictionary field.
+pub(crate) fn has_dictionaries(ty: &Type) -> bool {
+  count(ty) > 0
+}
+
+/// The number of dictionary fields in `ty`.
+fn count(ty: &Type) -> usize {
+  match ty {
+    Type::Dictionary { .. } => 1,
+    Type::Nested(CompositeType::Record(record)) => {
+      record.fields.values().map(count).sum()
+    }
+    Type::Nested(CompositeType::List(list)) => count(&list.0),
+    _ => 0,
+  }
+}
+
+/// Appends the types of the dictionary fields in `ty` to `fields`, in schema
+/// order.
+fn collect<'s>(ty: &'s Type, fields: &mut Vec<&'s Type>) {
+  match ty {
+    Type::Dictionary { .. } => fields.push(ty),
+    Type::Nested(CompositeType::Record(record)) => {
+      for ty in record.fields.values() {
+        collect(ty, fields);
+      }
+    }
+    Type::Nested(CompositeType::List(list)) => collect(&list.0, fields),
+    _ => {}
+  }
+}
+
+/// Finds the type of the values at `path` within a value of type `ty`.
+fn type_at<'s>(ty: &'s Type, path: &[Segment]) -> Option<&'s Type> {
+  match (ty, path.split_first()) {
+    (ty, None) => Some(ty),
+    (
+      Type::Nested(CompositeType::Record(record)),
+      Some((Segment::Field(name), rest)),
+    ) => type_at(record.fields.get(name)?, rest),
+    (
+      Type::Nested(CompositeType::List(list)),
+      Some((Segment::Index(_), rest)),
+    ) => type_at(&list.0, rest),
+    _ => None,
+  }
+}
+
+/// Calls `f` with the index, size and path of every dictionary field in
+/// `value`, along with the field's value.
+fn for_each_field<F>(
+  ty: &Type,
+  id: usize,
+  value: &mut Value,
+  path: &mut Vec<Segment>,
+  f: &mut F,
+) -> Result<()>
+where
+  F: FnMut(usize, usize, &[Segment], &mut Value) -> Result<()>,
+{
+  match (ty, value) {
+    (Type::Dictionary { size }, value) => f(id, *size, path, value),
+    (Type::Nested(CompositeType::Record(record)), Value::Object(map)) => {
+      let mut id = id;
+      for (name, ty) in &record.fields {
+        if let Some(value) = map.get_mut(name) {
+          path.push(Segment::Field(name.clone()));
+          for_each_field(ty, id, value, path, f)?;
+          path.pop();
+        }
+        id += count(ty);
+      }
+      Ok(())
+    }
+    (Type::Nested(CompositeType::List(list)), Value::Array(arr)) => {
+      for (i, value) in arr.iter_mut().enumerate() {
+        path.push(Segment::Index(i));
+        for_each_field(&list.0, id, value, path, f)?;
+        path.pop();
+      }
+      Ok(())
+    }
+    _ => Ok(()),
+  }
+}
+
+/// Checks that the values of the dictionary fields of `value` are strings,
+/// so that they can't be mistaken for indices.
+pub(crate) fn check(schema: &Schema, value: &mut Value) -> Result<()> {
+  for_each_field(
+    schema.root(),
+    0,
+    value,
+    &mut Vec::new(),
+    &mut |_, _, path, value| match value {
+      Value::String(_) | Value::Null => Ok(()),
+      _ => Err(anyhow::Error::new(Error::TypeMismatch {
+        path: Path(path.to_vec()),
+        expected: "string".to_string(),
+        value: value.clone(),
+      })),
+    },
+  )
+}
+
+impl Dictionaries {
+  /// Builds the dictionaries of `value` and replaces the values found in them
+  /// with their indices.
+  ///
+  /// `value` must have been [checked](check) first.
+  pub(crate) fn build(schema: &Schema, value: &mut Value) -> Result<Self> {
+    let n = count(schema.root());
+    let mut counts = vec![BTreeMap::new(); n];
+    let mut sizes = vec![0; n];
+    for_each_field(
+      schema.root(),
+      0,
+      value,
+      &mut Vec::new(),
+      &mut |id, size, _, value| {
+        if let Value::String(s) = value {
+          *counts[id].entry(s.clone()).or_insert(0usize) += 1;
+        }
+        sizes[id] = size;
+        Ok(())
+      },
+    )?;
+
+    let entries: Vec<Vec<String>> = counts
+      .into_iter()
+      .zip(sizes)
+      .map(|(counts, size)| {
+        let mut frequent: Vec<_> =
+          counts.into_iter().filter(|(_, n)| *n > 1).collect();
+        // Most frequent first, ties in the order of the values
+        frequent.sort_by(|(a, x), (b, y)| y.cmp(x).then_with(|| a.cmp(b)));
+        frequent.into_iter().take(size).map(|(s, _)| s).collect()
+      })
+      .collect();
+
+    for_each_field(
+      schema.root(),
+      0,
+      value,
+      &mut Vec::new(),
+      &mut |id, _, _, value| {
+        let index = match value {
+          Value::String(s) => entries[id].iter().position(|e| e == s),
+          _ => None,
+        };
+        if let Some(index) = index {
+          *value = Value::from(index);
+        }
+        Ok(())
+      },
+    )?;
+    Ok(Dictionaries { entries })
+  }
+
+  /// Returns `true` if every dictionary is empty.
+  pub fn is_empty(&self) -> bool {
+    self.entries.iter().all(Vec::is_empty)
+  }
+
+  /// The entries of the dictionary of the dictionary field with index `id`,
+  /// counting dictionary fields in schema order.
+  pub fn entries(&self, id: usize) -> &[String] {
+    self.entries.get(id).map_or(&[], Vec::as_slice)
+  }
+
+  /// Replaces the indices in a decoded `value` with the entries they refer
+  /// to.
+  pub(crate) fn resolve(
+    schema: &Schema,
+    dictionaries: Option<&Dictionaries>,
+    value: &mut Value,
+  ) -> Result<()> {
+    if !has_dictionaries(schema.root()) {
+      return Ok(());
+    }
+    resolve_fields(schema.root(), 0, dictionaries, &mut Vec::new(), value)
+  }
+
+  /// Serializes these dictionaries to bytes.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let entries = self
+      .entries
+      .iter()
+      .map(|entries| entries.iter().map(|e| Value::from(e.as_str())).collect())
+      .collect();
+    // Serializing a `Value` can't fail
+    serde_json::to_vec(&Value::Array(entries)).unwrap()
+  }
+
+  /// Deserializes dictionaries from bytes produced by [`to_bytes`].
+  ///
+  /// [`to_bytes`]: Dictionaries::to_bytes
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    let dictionaries = match serde_json::from_slice(bytes)? {
+      Value::Array(dictionaries) => dictionaries,
+      _ => bail!("dictionaries must be an array"),
+    };
+
+    let mut entries = Vec::new();
+    for dictionary in dictionaries {
+      let dictionary = match dictionary {
+        Value::Array(dictionary) => dictionary,
+        _ => bail!("a dictionary must be an array"),
+      };
+      let dictionary = dictionary
+        .into_iter()
+        .map(|entry| match entry {
+          Value::String(s) => Ok(s),
+          _ => bail!("dictionary entries must be strings"),
+        })
+        .collect::<Result<_>>()?;
+      entries.push(dictionary);
+    }
+    Ok(Dictionaries { entries })
+  }
+
+  /// The footer for an object which is `len` bytes long.
+  pub(crate) fn footer(&self, len: usize) -> Vec<u8> {
+    let mut footer = self.to_bytes();
+    footer.extend_from_slice(&(len as u64).to_le_bytes());
+    footer.extend_from_slice(MAGIC);
+    footer
+  }
+
+  /// Splits the bytes of a compressed object from its dictionaries footer,
+  /// which objects of schemas with dictionary fields always end with.
+  pub fn split(bytes: &[u8]) -> Result<(&[u8], Dictionaries)> {
+    if bytes.len() < TRAILER_LEN || !bytes.ends_with(MAGIC) {
+      bail!("missing dictionaries footer");
+    }
+
+    let trailer = &bytes[bytes.len() - TRAILER_LEN..];
+    let mut start = [0u8; 8];
+    start.copy_from_slice(&trailer[..8]);
+    let start = u64::from_le_bytes(start) as usize;
+    if start > bytes.len() - TRAILER_LEN {
+      bail!("dictionaries offset is out of bounds");
+    }
+
+    let dictionaries = &bytes[start..bytes.len() - TRAILER_LEN];
+    let dictionaries = Dictionaries::from_bytes(dictionaries)?;
+    Ok((&bytes[..start], dictionaries))
+  }
+}
+
+/// Replaces the indices in a `value` of type `ty`, whose first dictionary
+/// field has index `id`, with the entries they refer to.
+fn resolve_fields(
+  ty: &Type,
+  id: usize,
+  dictionaries: Option<&Dictionaries>,
+  path: &mut Vec<Segment>,
+  value: &mut Value,
+) -> Result<()> {
+  for_each_field(ty, id, value, path, &mut |id, _, path, value| {
+    let index = match value.as_u64() {
+      Some(index) => index,
+      None => return Ok(()),
+    };
+    let entry = dictionaries
+      .and_then(|d| d.entries(id).get(index as usize))
+      .ok_or_else(|| match path {
+        [] => anyhow!("dictionary index {} is out of range", index),
+        _ => anyhow!(
+          "dictionary index {} at {} is out of range",
+          index,
+          Path(path.to_vec())
+        ),
+      })?;
+    *value = Value::String(entry.clone());
+    Ok(())
+  })
+}
+
+/// Looks up the dictionary indices in values decoded from part of an object,
+/// such as a single field.
+#[derive(Clone, Debug)]
+pub(crate) struct Resolver<'s> {
+  root: &'s Type,
+  /// The types of the dictionary fields of the schema, in schema order.
+  fields: Vec<&'s Type>,
+  dictionaries: Option<Dictionaries>,
+}
+
+impl<'s> Resolver<'s> {
+  /// Constructs a resolver for an object encoded with `schema` whose
+  /// dictionaries footer held `dictionaries`.
+  pub(crate) fn new(
+    schema: &'s Schema,
+    dictionaries: Option<Dictionaries>,
+  ) -> Self {
+    let mut fields = Vec::new();
+    collect(schema.root(), &mut fields);
+    Resolver {
+      root: schema.root(),
+      fields,
+      dictionaries,
+    }
+  }
+
+  /// Replaces the indices in `value`, a decoded value of type `ty`, with the
+  /// entries they refer to.
+  ///
+  /// `ty` must be part of the schema this resolver was constructed with.
+  pub(crate) fn resolve(&self, ty: &Type, value: &mut Value) -> Result<()> {
+    self.resolve_within(ty, &mut Vec::new(), value)
+  }
+
+  /// Replaces the indices in `value`, the decoded value found at `path`, with
+  /// the entries they refer to.
+  pub(crate) fn resolve_at(
+    &self,
+    path: &Path,
+    value: &mut Value,
+  ) -> Result<()> {
+    match type_at(self.root, path.segments()) {
+      Some(ty) => self.resolve_within(ty, &mut path.segments().to_vec(), value),
+      None => Ok(()),
+    }
+  }
+
+  fn resolve_within(
+    &self,
+    ty: &Type,
+    path: &mut Vec<Segment>,
+    value: &mut Value,
+  ) -> Result<()> {
+    let mut within = Vec::new();
+    collect(ty, &mut within);
+    // Dictionary fields are numbered in schema order, so those within `ty`
+    // are numbered from the first of them on
+    let id = within.first().and_then(|first| {
+      self.fields.iter().position(|ty| core::ptr::eq(*ty, *first))
+    });
+    match id {
+      Some(id) => {
+        resolve_fields(ty, id, self.dictionaries.as_ref(), path, value)
+      }
+      None => Ok(()),
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{List, Record};
+  use serde_json::json;
+
+  fn schema() -> Schema {
+    let mut request = BTreeMap::new();
+    request.insert("method".to_string(), Type::Dictionary { size: 2 });
+    request.insert("path".to_string(), Type::PassThrough);
+    let list = List(Box::new(Type::Nested(CompositeType::Record(
+      Record::new(request),
+    ))));
+    Schema::new(CompositeType::List(list))
+  }
+
+  fn requests() -> Value {
+    json!([
+      { "method": "GET", "path": "/" },
+      { "method": "POST", "path": "/login" },
+      { "method": "GET", "path": "/home" },
+      { "method": "DELETE", "path": "/session" },
+      { "method": "POST", "path": "/logout" },
+      { "method": "GET", "path": "/" }
+    ])
+  }
+
+  #[test]
+  fn frequent_values_are_replaced() {
+    let schema = schema();
+    let mut value = requests();
+    let dictionaries = Dictionaries::build(&schema, &mut value).unwrap();
+    assert_eq!(["GET", "POST"], dictionaries.entries(0));
+    assert_eq!(json!(0), value[0]["method"]);
+    assert_eq!(json!(1), value[1]["method"]);
+    assert_eq!(json!("DELETE"), value[3]["method"]);
+
+    let bytes = dictionaries.to_bytes();
+    assert_eq!(dictionaries, Dictionaries::from_bytes(&bytes).unwrap());
+  }
+
+  #[test]
+  fn dictionary_roundtrip() {
+    let schema = schema();
+    let mut bytes = Vec::new();
+    crate::encode_to(&schema, &requests(), &mut bytes).unwrap();
+    let plain = crate::encode(&schema, &requests()).unwrap().to_bytes();
+    let (body, dictionaries) = Dictionaries::split(&bytes).unwrap();
+    assert!(!dictionaries.is_empty());
+    assert!(body.len() < plain.len());
+    let (_, empty) = Dictionaries::split(&plain).unwrap();
+    assert!(empty.is_empty());
+
+    assert_eq!(requests(), crate::decode(&schema, &bytes).unwrap());
+    assert_eq!(requests(), crate::decode(&schema, &plain).unwrap());
+
+    let e = crate::encode(&schema, &json!([{ "method": 0 }])).unwrap_err();
+    assert_eq!("/0/method", e.downcast::<Error>().unwrap().pointer());
+  }
+
+  #[test]
+  fn footer_magic_in_object_is_not_a_footer() {
+    // The dictionary is empty and the byte aligned object ends in the
+    // footer's magic
+    let mut fields = BTreeMap::new();
+    fields.insert("a".to_string(), Type::Dictionary { size: 4 });
+    fields.insert("p".to_string(), Type::Name("bool".to_string()));
+    fields.insert("z".to_string(), Type::PassThrough);
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+    let mut map = serde_json::Map::new();
+    map.insert("a".to_string(), Value::from("unique"));
+    map.insert("p".to_string(), Value::Bool(true));
+    let z = format!("{}CHID", "\0".repeat(8));
+    map.insert("z".to_string(), Value::from(z));
+    let value = Value::Object(map);
+    let mut bytes = Vec::new();
+    crate::encode_to(&schema, &value, &mut bytes).unwrap();
+
+    let (object, dictionaries) = Dictionaries::split(&bytes).unwrap();
+    assert!(object.ends_with(MAGIC));
+    assert!(dictionaries.is_empty());
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+    assert!(crate::decode(&schema, object).is_err());
+  }
+
+  #[test]
+  fn partial_decoders_resolve_dictionaries() {
+    use crate::event::Event;
+    use crate::index::Index;
+
+    let schema = schema();
+    let mut bytes = Vec::new();
+    crate::encode_to(&schema, &requests(), &mut bytes).unwrap();
+
+    let path = "[1].method".parse().unwrap();
+    let method = crate::decode_path(&schema, &bytes, &path).unwrap();
+    assert_eq!(Some(json!("POST")), method);
+    let path = "[2]".parse().unwrap();
+    let request = crate::decode_path(&schema, &bytes, &path).unwrap();
+    assert_eq!(Some(requests()[2].clone()), request);
+
+    let index = Index::build(&schema, &bytes, 2).unwrap();
+    let request = crate::decode_element(&schema, &bytes, &index, 4).unwrap();
+    assert_eq!(requests()[4], request);
+
+    let methods = crate::decode_events(&schema, &bytes)
+      .filter_map(|event| match event.unwrap() {
+        Event::Value(Value::String(s)) if s.starts_with('/') => None,
+        Event::Value(value) => Some(value),
+        _ => None,
+      })
+      .collect::<Vec<_>>();
+    let expected = requests()
+      .as_array()
+      .unwrap()
+      .iter()
+      .map(|request| request["method"].clone())
+      .collect::<Vec<_>>();
+    assert_eq!(expected, methods);
+  }
+}
@@ -0,0 +1,139 @@
+//! The `diff` module computes field-level differences between two decoded
+//! documents.
+
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use alloc::collections::BTreeSet;
+use core::fmt;
+use serde_json::Value;
+
+/// A single difference between two documents.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+  /// A value is present in the new document but not the old one.
+  Added(Path, Value),
+  /// A value is present in the old document but not the new one.
+  Removed(Path, Value),
+  /// A value is present in both documents but differs.
+  Changed(Path, Value, Value),
+}
+
+impl Change {
+  /// The path of the value which changed.
+  pub fn path(&self) -> &Path {
+    match self {
+      Change::Added(p, _)
+      | Change::Removed(p, _)
+      | Change::Changed(p, _, _) => p,
+    }
+  }
+}
+
+impl fmt::Display for Change {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Change::Added(p, v) => write!(f, "+ {}: {}", p, v),
+      Change::Removed(p, v) => write!(f, "- {}: {}", p, v),
+      Change::Changed(p, old, new) => write!(f, "~ {}: {} -> {}", p, old, new),
+    }
+  }
+}
+
+/// Computes the differences between an `old` and `new` document.
+///
+/// Records are compared field by field and lists element by element, so a
+/// change deep within a document is reported at the path of the value which
+/// actually changed instead of at the root. Changes are returned in path
+/// order.
+pub fn diff(old: &Value, new: &Value) -> Vec<Change> {
+  let mut changes = Vec::new();
+  let mut path = Vec::new();
+  diff_at(&mut path, old, new, &mut changes);
+  changes
+}
+
+fn diff_at(
+  path: &mut Vec<Segment>,
+  old: &Value,
+  new: &Value,
+  changes: &mut Vec<Change>,
+) {
+  match (old, new) {
+    (Value::Object(a), Value::Object(b)) => {
+      let keys: BTreeSet<&String> = a.keys().chain(b.keys()).collect();
+      for k in keys {
+        path.push(Segment::Field(k.clone()));
+        match (a.get(k.as_str()), b.get(k.as_str())) {
+          (Some(x), Some(y)) => diff_at(path, x, y, changes),
+          (Some(x), None) => {
+            changes.push(Change::Removed(Path(path.clone()), x.clone()))
+          }
+          (None, Some(y)) => {
+            changes.push(Change::Added(Path(path.clone()), y.clone()))
+          }
+          (None, None) => unreachable!(),
+        }
+        path.pop();
+      }
+    }
+
+    (Value::Array(a), Value::Array(b)) => {
+      for i in 0..a.len().max(b.len()) {
+        path.push(Segment::Index(i));
+        match (a.get(i), b.get(i)) {
+          (Some(x), Some(y)) => diff_at(path, x, y, changes),
+          (Some(x), None) => {
+            changes.push(Change::Removed(Path(path.clone()), x.clone()))
+          }
+          (None, Some(y)) => {
+            changes.push(Change::Added(Path(path.clone()), y.clone()))
+          }
+          (None, None) => unreachable!(),
+        }
+        path.pop();
+      }
+    }
+
+    _ if old != new => changes.push(Change::Changed(
+      Path(path.clone()),
+      old.clone(),
+      new.clone(),
+    )),
+
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  #[test]
+  fn diff_identical() {
+    let v = json!({ "a": [1, 2, { "b": true }] });
+    assert!(diff(&v, &v).is_empty());
+  }
+
+  #[test]
+  fn diff_nested_changes() {
+    let old = json!({ "name": "x", "courses": [{ "grade": "A" }], "age": 3 });
+    let new = json!({
+      "name": "x",
+      "courses": [{ "grade": "C" }, { "grade": "B" }],
+      "id": 7
+    });
+
+    let changes: Vec<String> =
+      diff(&old, &new).iter().map(|c| c.to_string()).collect();
+    assert_eq!(
+      vec![
+        "- .age: 3",
+        "~ .courses[0].grade: \"A\" -> \"C\"",
+        "+ .courses[1]: {\"grade\":\"B\"}",
+        "+ .id: 7",
+      ],
+      changes
+    );
+  }
+}
@@ -1,178 +1,2332 @@
-use std::convert::TryFrom;
+use alloc::borrow::Cow;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use core::convert::TryFrom;
+use core::ops::RangeFrom;
+use core::str::FromStr;
+use core::{iter, slice};
+#[cfg(feature = "std")]
+use std::io::Write;
 
-use anyhow::{anyhow, bail, Context, Result};
+use anyhow::{anyhow, bail, Result};
 use serde_json::Value;
 
+#[cfg(feature = "std")]
+use crate::bit::BitWriter;
+use crate::bit::{self, BitBuf, BitVec, BitVecExt};
 use crate::comp::{self, Compressor, EncodedWidth};
-use crate::data::{Block, CompressedObject, Field, Length};
+use crate::data::{
+  BitOrder, Block, CompressedObject, Field, FieldId, Layout, Length,
+  LengthEncoding, Profile,
+};
+use crate::dictionary::{self, Dictionaries};
+use crate::epoch;
+use crate::error::{within_path, Error, Limit};
+use crate::math;
+use crate::path::{Path, Segment};
+use crate::prelude::*;
 use crate::schema::{CompositeType, List, Record, Schema, Type};
+use crate::unknown::UnknownFields;
+
+/// A destination for encoded blocks.
+trait Sink {
+  /// An independent sink which list elements can be encoded into in parallel
+  /// before being appended to this one.
+  #[cfg(feature = "parallel")]
+  type Part: Sink + Default + Send;
+
+  /// Pushes a `block` which is laid out using `layout` once encoded.
+  fn push(&mut self, block: Block, layout: Layout) -> Result<()>;
+
+  /// Appends everything pushed to `part`.
+  #[cfg(feature = "parallel")]
+  fn append(&mut self, part: Self::Part) -> Result<()>;
+}
+
+impl Sink for CompressedObject {
+  #[cfg(feature = "parallel")]
+  type Part = CompressedObject;
+
+  /// Objects are laid out as a whole once they are packed, using the layout
+  /// they were constructed with.
+  fn push(&mut self, block: Block, _: Layout) -> Result<()> {
+    CompressedObject::push(self, block);
+    Ok(())
+  }
+
+  #[cfg(feature = "parallel")]
+  fn append(&mut self, mut part: CompressedObject) -> Result<()> {
+    self.blocks.append(&mut part.blocks);
+    Ok(())
+  }
+}
+
+impl Sink for BitBuf {
+  #[cfg(feature = "parallel")]
+  type Part = BitBuf;
+
+  fn push(&mut self, block: Block, layout: Layout) -> Result<()> {
+    block.write_with(self, layout);
+    Ok(())
+  }
+
+  #[cfg(feature = "parallel")]
+  fn append(&mut self, part: BitBuf) -> Result<()> {
+    self.push_bit_buf(&part);
+    Ok(())
+  }
+}
+
+#[cfg(feature = "std")]
+impl<W: Write> Sink for BitWriter<W> {
+  #[cfg(feature = "parallel")]
+  type Part = BitBuf;
+
+  fn push(&mut self, block: Block, layout: Layout) -> Result<()> {
+    let mut buf = BitBuf::new();
+    block.write_with(&mut buf, layout);
+    self.write_buf(&buf)?;
+    Ok(())
+  }
+
+  /// Parts start at bit 0 so they are shifted to the writer's position as
+  /// they are written.
+  #[cfg(feature = "parallel")]
+  fn append(&mut self, part: BitBuf) -> Result<()> {
+    self.write_buf(&part)?;
+    Ok(())
+  }
+}
+
+/// The default maximum nesting depth used by [`EncodeOptions`].
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 128;
+
+/// The minimum number of elements a list of composite values must have for
+/// its elements to be encoded in parallel.
+#[cfg(feature = "parallel")]
+pub(crate) const PARALLEL_THRESHOLD: usize = 4096;
+
+/// The order in which the fields of a record are encoded.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FieldOrder {
+  /// Fields are encoded in the order they are defined in the schema. The
+  /// output for semantically identical values is always the same.
+  Schema,
+  /// Fields are encoded in the order they appear in the input value.
+  Input,
+}
+
+/// What to do when a required field is missing from a record.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum MissingFieldPolicy {
+  /// Fail with an [`Error::MissingField`].
+  Error,
+  /// Encode the record without the field, reporting a warning which
+  /// [`encode_with_warnings`] and [`encode_to_with`] return.
+  Warn,
+  /// Encode the field with a default value for its type: `false`, an empty
+  /// string, the first enum variant, an empty record or an empty list.
+  FillDefault,
+}
+
+/// What to do when a record contains a field which is not in the schema.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum UnknownFieldPolicy {
+  /// Fail with an [`Error::UnknownField`].
+  Error,
+  /// Leave the field out of the encoded object.
+  Ignore,
+  /// Leave the field out of the encoded object but store it in an
+  /// [`UnknownFields`] footer so it can be restored after decoding.
+  ///
+  /// Only [`encode_to`] and [`encode_to_with`] write the footer. A
+  /// [`CompressedObject`] only ever holds the known fields; the rest can be
+  /// found using [`UnknownFields::collect`].
+  Preserve,
+}
+
+impl FromStr for UnknownFieldPolicy {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "error" => Ok(UnknownFieldPolicy::Error),
+      "ignore" => Ok(UnknownFieldPolicy::Ignore),
+      "preserve" => Ok(UnknownFieldPolicy::Preserve),
+      _ => {
+        bail!("unknown field policy must be one of: error, ignore, preserve")
+      }
+    }
+  }
+}
+
+/// What to do when a record contains a field which the schema marks as
+/// deprecated.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum DeprecatedFieldPolicy {
+  /// Fail with an [`Error::DeprecatedField`].
+  Error,
+  /// Leave the field out of the encoded object.
+  Ignore,
+}
+
+impl FromStr for DeprecatedFieldPolicy {
+  type Err = anyhow::Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    match s {
+      "error" => Ok(DeprecatedFieldPolicy::Error),
+      "ignore" => Ok(DeprecatedFieldPolicy::Ignore),
+      _ => bail!("deprecated field policy must be one of: error, ignore"),
+    }
+  }
+}
+
+/// Options which control how values are encoded.
+#[derive(Clone, Debug)]
+pub struct EncodeOptions {
+  /// The maximum number of nested records and lists, including the root,
+  /// which may be encoded.
+  pub max_depth: usize,
+  /// The order in which record fields are encoded.
+  pub field_order: FieldOrder,
+  /// What to do when a required field is missing.
+  pub missing_fields: MissingFieldPolicy,
+  /// What to do with fields which are not in the schema.
+  pub unknown_fields: UnknownFieldPolicy,
+  /// What to do with fields which the schema marks as deprecated.
+  pub deprecated_fields: DeprecatedFieldPolicy,
+  /// How the blocks of the encoded object are laid out.
+  pub layout: Layout,
+  /// Which encoding profile is used.
+  pub profile: Profile,
+  /// Whether the data of every variable-width field and element is followed
+  /// by a checksum, so that damage to it can be pinned to that value.
+  pub checksums: bool,
+  /// How the bits of the encoded object are packed into bytes.
+  pub bit_order: BitOrder,
+}
+
+impl Default for EncodeOptions {
+  fn default() -> Self {
+    EncodeOptions {
+      max_depth: DEFAULT_MAX_DEPTH,
+      field_order: FieldOrder::Schema,
+      missing_fields: MissingFieldPolicy::Error,
+      unknown_fields: UnknownFieldPolicy::Error,
+      deprecated_fields: DeprecatedFieldPolicy::Error,
+      layout: Layout::Packed,
+      profile: Profile::Standard,
+      checksums: false,
+      bit_order: BitOrder::MsbFirst,
+    }
+  }
+}
+
+/// A problem with the data of a value which was encoded anyway.
+///
+/// Warnings are found by [`encode_with_warnings`], so that pipelines can log
+/// issues with the quality of their data without rejecting it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Warning {
+  /// The path of the value with the problem.
+  pub path: Path,
+  /// What the problem is.
+  pub kind: WarningKind,
+}
+
+/// The kinds of [`Warning`].
+#[derive(Clone, Debug, PartialEq)]
+pub enum WarningKind {
+  /// A required field was missing and the record was encoded without it,
+  /// see [`MissingFieldPolicy::Warn`].
+  MissingField,
+  /// A field which is not in the schema was left out, see
+  /// [`UnknownFieldPolicy::Ignore`].
+  UnknownField,
+  /// A deprecated field was left out, see [`DeprecatedFieldPolicy::Ignore`].
+  DeprecatedField,
+  /// A number which isn't a multiple of the granularity of its
+  /// [scaled](Type::Scaled) type was rounded.
+  Rounded { value: f64, scale: f64 },
+  /// A string was changed by the [transforms](Type::Transformed) of its
+  /// type, e.g., an enum variant which only matched once it was lowercased.
+  Transformed { from: String, to: String },
+}
+
+impl core::fmt::Display for Warning {
+  fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+    let path = self.path.clone();
+    match &self.kind {
+      WarningKind::MissingField => Error::MissingField { path }.fmt(f),
+      WarningKind::UnknownField => Error::UnknownField { path }.fmt(f),
+      WarningKind::DeprecatedField => Error::DeprecatedField { path }.fmt(f),
+      WarningKind::Rounded { value, scale } => write!(
+        f,
+        "rounded {} to a multiple of {} (at {})",
+        value,
+        1.0 / scale,
+        path.to_pointer()
+      ),
+      WarningKind::Transformed { from, to } => write!(
+        f,
+        "transformed {:?} into {:?} (at {})",
+        from,
+        to,
+        path.to_pointer()
+      ),
+    }
+  }
+}
 
 /// Encodes a JSON `value` using a given `schema`.
 pub fn encode(schema: &Schema, value: &Value) -> Result<CompressedObject> {
-  let mut co = CompressedObject::new();
-  encode_composite_type(schema.root(), None, &mut co, value)?;
-  Ok(co)
+  encode_with(schema, value, &EncodeOptions::default())
+}
+
+/// Encodes a JSON `value` using a given `schema` and `options`.
+///
+/// Any warnings about the data are discarded, use [`encode_with_warnings`] to
+/// get them.
+pub fn encode_with(
+  schema: &Schema,
+  value: &Value,
+  options: &EncodeOptions,
+) -> Result<CompressedObject> {
+  encode_with_warnings(schema, value, options).map(|(co, _)| co)
+}
+
+/// Encodes a JSON `value` using a given `schema` and `options`, returning
+/// the object along with any problems with the data which didn't stop it
+/// from being encoded.
+///
+/// Besides the fields which are left out under the field policies of
+/// `options`, numbers rounded by [scaled](Type::Scaled) types and strings
+/// changed by [transforms](Type::Transformed) are reported, in the order
+/// they were found.
+pub fn encode_with_warnings(
+  schema: &Schema,
+  value: &Value,
+  options: &EncodeOptions,
+) -> Result<(CompressedObject, Vec<Warning>)> {
+  #[cfg(feature = "tracing")]
+  let _span = tracing::debug_span!("encode").entered();
+  let value = prepare(schema, value, options)?;
+  let mut co = CompressedObject::with_layout(options.layout);
+  co.profile = options.profile;
+  co.checksums = options.checksums;
+  co.bit_order = options.bit_order;
+  co.dictionaries = dictionary::has_dictionaries(schema.root());
+  let warnings = Encoder::new(options, schema.lengths(), &mut co)
+    .with_chunks(schema.chunks())
+    .with_sync(schema.sync())
+    .run(schema.root(), &value)?;
+  #[cfg(feature = "tracing")]
+  tracing::debug!(bits = co.bit_len(), "encoded");
+  Ok((co, warnings))
 }
 
-/// Encodes a composite type.
-fn encode_composite_type(
-  ct: &CompositeType,
-  field: Option<Field>,
-  co: &mut CompressedObject,
+/// Encodes a JSON `value` using a given `schema`, writing the encoded bytes
+/// directly to `writer`.
+///
+/// Unlike [`encode`], blocks are written out as soon as they are produced
+/// instead of being collected into a [`CompressedObject`] first. The bytes
+/// written are identical to those of the object returned by [`encode`],
+/// except that the most frequent values of [dictionary](crate::dictionary)
+/// fields are replaced by indices into a footer.
+#[cfg(feature = "std")]
+pub fn encode_to<W: Write>(
+  schema: &Schema,
   value: &Value,
+  writer: W,
 ) -> Result<()> {
-  match ct {
-    CompositeType::Record(r) => encode_record(&r, field, co, value),
-    CompositeType::List(l) => encode_list(&l, field, co, value),
+  encode_to_with(schema, value, writer, &EncodeOptions::default())?;
+  Ok(())
+}
+
+/// Encodes a JSON `value` using a given `schema` and `options`, writing the
+/// encoded bytes directly to `writer` and returning any warnings about the
+/// data, like [`encode_with_warnings`].
+#[cfg(feature = "std")]
+pub fn encode_to_with<W: Write>(
+  schema: &Schema,
+  value: &Value,
+  writer: W,
+  options: &EncodeOptions,
+) -> Result<Vec<Warning>> {
+  #[cfg(feature = "tracing")]
+  let _span = tracing::debug_span!("encode").entered();
+  let mut value = prepare(schema, value, options)?;
+  let dictionaries = build_dictionaries(schema, &mut value)?;
+  let mut w = BitWriter::new(writer).with_bit_order(options.bit_order);
+  let warnings = Encoder::new(options, schema.lengths(), &mut w)
+    .with_chunks(schema.chunks())
+    .with_sync(schema.sync())
+    .run(schema.root(), &value)?;
+  #[cfg(feature = "tracing")]
+  tracing::debug!(bits = w.position(), "encoded");
+  let mut len = crate::math::div_ceil(w.position(), 8);
+  let mut writer = w.finish()?;
+
+  if let Some(dictionaries) = dictionaries {
+    let footer = dictionaries.footer(len);
+    writer.write_all(&footer)?;
+    len += footer.len();
   }
+  if options.unknown_fields == UnknownFieldPolicy::Preserve {
+    let unknown = UnknownFields::collect(schema, &value);
+    if !unknown.is_empty() {
+      writer.write_all(&unknown.footer(len))?;
+    }
+  }
+  Ok(warnings)
 }
 
-/// Encodes a list type.
-fn encode_list(
-  list: &List,
-  field: Option<Field>,
-  co: &mut CompressedObject,
+/// Encodes a JSON `value` using a given `schema` and `options`, appending the
+/// encoded bytes to `bytes`.
+///
+/// The bits are built up in `scratch`, which is cleared first, so that its
+/// space can be reused between objects. Nothing is appended to `bytes` if
+/// encoding fails. Like [`encode_with`], any warnings are discarded.
+pub(crate) fn encode_to_bytes(
+  schema: &Schema,
   value: &Value,
+  options: &EncodeOptions,
+  scratch: &mut BitBuf,
+  bytes: &mut Vec<u8>,
 ) -> Result<()> {
-  // Cast `value` into an array first as we need its length for the header
-  let arr = value.as_array().ok_or_else(|| anyhow!("expected array"))?;
+  #[cfg(feature = "tracing")]
+  let _span = tracing::debug_span!("encode").entered();
+  let mut value = prepare(schema, value, options)?;
+  let dictionaries = build_dictionaries(schema, &mut value)?;
+  scratch.clear();
+  Encoder::new(options, schema.lengths(), scratch)
+    .with_chunks(schema.chunks())
+    .with_sync(schema.sync())
+    .run(schema.root(), &value)?;
+  #[cfg(feature = "tracing")]
+  tracing::debug!(bits = scratch.len(), "encoded");
 
-  // If this list is nested push its header on first
-  if let Some(f) = field {
-    let len = Length::new(arr.len());
-    let header = Block::ListHeader(f, len);
-    co.push(header);
+  let start = bytes.len();
+  scratch.append_to(bytes);
+  options.bit_order.repack(&mut bytes[start..]);
+  if let Some(dictionaries) = dictionaries {
+    let len = bytes.len() - start;
+    bytes.extend_from_slice(&dictionaries.footer(len));
   }
+  if options.unknown_fields == UnknownFieldPolicy::Preserve {
+    let unknown = UnknownFields::collect(schema, &value);
+    if !unknown.is_empty() {
+      let len = bytes.len() - start;
+      bytes.extend_from_slice(&unknown.footer(len));
+    }
+  }
+  Ok(())
+}
 
-  // Encode each element in the list
-  for v in arr {
-    if let Type::Nested(ct) = list.0.as_ref() {
-      encode_composite_type(ct, None, co, v)
-    } else {
-      encode_element(list.0.as_ref(), co, v)
+/// Applies any changes `options` or the epoch of `schema` require to be made
+/// to `value` before it can be encoded.
+fn prepare<'v>(
+  schema: &Schema,
+  value: &'v Value,
+  options: &EncodeOptions,
+) -> Result<Cow<'v, Value>> {
+  let fill = match schema.composite_root() {
+    Some(ct) if options.missing_fields == MissingFieldPolicy::FillDefault => {
+      Some(ct)
     }
-    .with_context(|| "when encoding list element")?;
+    _ => None,
+  };
+  let dictionaries = dictionary::has_dictionaries(schema.root());
+  if fill.is_none() && schema.epoch().is_none() && !dictionaries {
+    return Ok(Cow::Borrowed(value));
   }
 
-  Ok(())
+  let mut value = value.clone();
+  if let Some(ct) = fill {
+    fill_defaults(ct, &mut value)?;
+  }
+  epoch::relativize(schema, &mut value)?;
+  if dictionaries {
+    dictionary::check(schema, &mut value)?;
+  }
+  Ok(Cow::Owned(value))
 }
 
-/// Encodes a record type.
-fn encode_record(
-  record: &Record,
-  field: Option<Field>,
-  co: &mut CompressedObject,
-  value: &Value,
-) -> Result<()> {
-  // If this record is nested, push its header on first
-  if let Some(f) = field {
-    let header = Block::RecordHeader(f);
-    co.push(header);
-  }
-
-  // Cast `value` into an object
-  let value_map = value
-    .as_object()
-    .ok_or_else(|| anyhow!("expected object"))?;
-
-  // Compute the mapping of field names to identifiers and figure out the field
-  // width for this record's elements
-  let field_map = record.field_map();
-  let field_width = record.field_width();
-
-  // Encode each field as they appear in the value object
-  for (k, v) in value_map {
-    let id = field_map
-      .get(k.as_str())
-      .ok_or_else(|| anyhow!("unexpected field: {}", k))?;
-    let field = Field::new(field_width, *id);
-    let ty = &record.0[k];
-
-    // If the expected type for a field is a nested type (i.e., record or list)
-    // recurse and try an encode the composite type. Note that we switch based
-    // on the expected type as defined in the schema and not what the value
-    // actually is. The schema is what drives the encoding process, not the
-    // value.
-    //
-    // If not, then we just encode the value normally.
-    if let Type::Nested(ct) = ty {
-      encode_composite_type(ct, Some(field), co, v)
-    } else {
-      encode_field(field, ty, co, v)
-    }
-    .with_context(|| format!("when encoding {}", k))?;
-  }
-
-  // Push the terminator block if this is a nested record
-  if field.is_some() {
-    // Terminator uses the same field width as the rest of this record's fields
-    co.push(Block::Terminator { width: field_width });
+/// Builds the [dictionaries](crate::dictionary) of a prepared `value`,
+/// replacing the values found in them with their indices, or returns `None`
+/// if `schema` has no dictionary fields.
+fn build_dictionaries(
+  schema: &Schema,
+  value: &mut Cow<Value>,
+) -> Result<Option<Dictionaries>> {
+  if !dictionary::has_dictionaries(schema.root()) {
+    return Ok(None);
   }
+  Dictionaries::build(schema, value.to_mut()).map(Some)
+}
 
+/// Inserts default values for all missing required fields in `value`.
+///
+/// Values which don't match their type are left alone for the encoder to
+/// report.
+fn fill_defaults(ct: &CompositeType, value: &mut Value) -> Result<()> {
+  match (ct, value) {
+    (CompositeType::Record(record), Value::Object(map)) => {
+      for name in &record.required {
+        if !map.contains_key(name) {
+          let default = default_value(&record.fields[name]).map_err(|e| {
+            e.context(format!("when filling required field {}", name))
+          })?;
+          map.insert(name.clone(), default);
+        }
+      }
+      for (k, v) in map.iter_mut() {
+        if let Some(Type::Nested(ct)) = record.fields.get(k) {
+          fill_defaults(ct, v)?;
+        }
+      }
+    }
+    (CompositeType::List(list), Value::Array(arr)) => {
+      if let Type::Nested(ct) = list.0.as_ref() {
+        for v in arr {
+          fill_defaults(ct, v)?;
+        }
+      }
+    }
+    _ => {}
+  }
   Ok(())
 }
 
+/// The value used in place of a missing required field of type `ty`.
+fn default_value(ty: &Type) -> Result<Value> {
+  match ty {
+    Type::PassThrough => Ok(Value::String(String::new())),
+    Type::Name(name) if name == "bool" => Ok(Value::Bool(false)),
+    Type::Name(name) if name == "color" => Ok(Value::from("#000000")),
+    Type::Name(name) if name == "any" => Ok(Value::Null),
+    Type::Name(name) if ["bwt", "lzw", "smaz"].contains(&name.as_str()) => {
+      Ok(Value::String(String::new()))
+    }
+    Type::Name(name) if name == "f16" || is_float(name) => Ok(Value::from(0.0)),
+    Type::Name(name) if name == "timestamp" => Ok(Value::from(0)),
+    Type::Name(name) => match comp::GeoCompressor::parse(name) {
+      Some((_, comp::GeoForm::Pair)) => Ok(serde_json::json!([0.0, 0.0])),
+      Some((_, comp::GeoForm::Record)) => {
+        Ok(serde_json::json!({ "lat": 0.0, "lon": 0.0 }))
+      }
+      None => bail!("no default value for '{}'", name),
+    },
+    Type::Enum { variants, .. } => match variants.iter().next() {
+      Some(v) => Ok(Value::String(v.clone())),
+      None => bail!("no default value for an empty enum"),
+    },
+    Type::IntEnum { codes } => match codes.iter().next() {
+      Some(c) => Ok(Value::from(*c)),
+      None => bail!("no default value for an empty enum"),
+    },
+    // The empty string has no bytes so it never needs a code
+    Type::Huffman { .. } | Type::Dictionary { .. } => {
+      Ok(Value::String(String::new()))
+    }
+    Type::Scaled { .. } => Ok(Value::from(0.0)),
+    Type::Transformed { of, .. } => default_value(of),
+    Type::Nested(CompositeType::Record(_)) => {
+      Ok(Value::Object(serde_json::Map::new()))
+    }
+    Type::Nested(CompositeType::List(_)) => Ok(Value::Array(Vec::new())),
+    Type::Union { .. } => bail!("no default value for a union"),
+  }
+}
+
+/// A record or list which is currently being encoded.
+enum Frame<'a> {
+  Record {
+    record: &'a Record,
+    field_map: BTreeMap<&'a str, FieldId>,
+    field_width: usize,
+    nested: bool,
+    /// The marker of the previous field if the fields are marked with the
+    /// differences between their markers.
+    sparse: Option<u32>,
+    /// Whether the fields are written in schema order without markers.
+    dense: bool,
+    fields: vec::IntoIter<(&'a String, &'a Value)>,
+  },
+  List {
+    list: &'a List,
+    elements: iter::Zip<RangeFrom<usize>, slice::Iter<'a, Value>>,
+  },
+}
+
+/// Encodes composite values using an explicit stack of frames instead of
+/// recursion, so deeply nested values can't overflow the call stack.
+struct Encoder<'a, 'o, S> {
+  options: &'o EncodeOptions,
+  lengths: LengthEncoding,
+  /// The number of elements in each chunk of a list, if lists are chunked.
+  chunks: Option<usize>,
+  /// The number of blocks after which a sync marker is written between the
+  /// elements of a root list, if it has sync markers.
+  sync: Option<usize>,
+  /// The number of blocks pushed so far, and how many had been when the
+  /// last sync marker was written.
+  blocks: usize,
+  synced: usize,
+  sink: &'o mut S,
+  stack: Vec<Frame<'a>>,
+  /// The path to the value currently being encoded.
+  path: Vec<Segment>,
+  /// The number of composite values enclosing the value being encoded which
+  /// are not on `stack`.
+  depth: usize,
+  /// The problems with the data found so far.
+  warnings: Vec<Warning>,
+  /// The spans of the composite values being encoded, along with the number
+  /// of frames on `stack` when their frame was opened.
+  #[cfg(feature = "tracing")]
+  spans: Vec<(usize, tracing::span::EnteredSpan)>,
+}
+
+impl<'a, 'o, S: Sink> Encoder<'a, 'o, S> {
+  fn new(
+    options: &'o EncodeOptions,
+    lengths: LengthEncoding,
+    sink: &'o mut S,
+  ) -> Self {
+    Encoder {
+      options,
+      lengths,
+      chunks: None,
+      sync: None,
+      blocks: 0,
+      synced: 0,
+      sink,
+      stack: Vec::new(),
+      path: Vec::new(),
+      depth: 0,
+      warnings: Vec::new(),
+      #[cfg(feature = "tracing")]
+      spans: Vec::new(),
+    }
+  }
+
+  /// Splits lists of more than `chunks` elements into chunks.
+  fn with_chunks(mut self, chunks: Option<usize>) -> Self {
+    self.chunks = chunks;
+    self
+  }
+
+  /// Writes sync markers between the elements of a root list after every
+  /// `sync` blocks.
+  fn with_sync(mut self, sync: Option<usize>) -> Self {
+    self.sync = sync;
+    self
+  }
+
+  /// Encodes a root `value` of type `root`, returning the warnings found.
+  fn run(mut self, root: &'a Type, value: &'a Value) -> Result<Vec<Warning>> {
+    match root {
+      Type::Nested(ct) => self.open(ct, None, value, false)?,
+      // Scalar roots consist of a single element
+      ty => {
+        let options = self.options;
+        encode_element(ty, self.sink, options, self.lengths, value)?;
+        self.check_lossy(ty, value);
+        return Ok(self.warnings);
+      }
+    }
+    self.drain()?;
+    Ok(self.warnings)
+  }
+
+  /// Encodes a composite list element at `path` which is nested `depth`
+  /// composite values deep, returning the number of blocks pushed and the
+  /// warnings found.
+  #[cfg(feature = "parallel")]
+  fn run_element(
+    mut self,
+    ct: &'a CompositeType,
+    value: &'a Value,
+    path: Vec<Segment>,
+    depth: usize,
+  ) -> Result<(usize, Vec<Warning>)> {
+    self.path = path;
+    self.depth = depth;
+    if let Err(e) = self.open(ct, Some(Field::null(0)), value, false) {
+      return Err(self.error(e));
+    }
+    self.drain()?;
+    Ok((self.blocks, self.warnings))
+  }
+
+  /// Encodes a chunk of the elements of `list` starting at index `start`,
+  /// where the list is nested `depth` composite values deep, returning the
+  /// warnings found with paths relative to the list.
+  fn run_chunk(
+    mut self,
+    list: &'a List,
+    chunk: &'a [Value],
+    start: usize,
+    depth: usize,
+  ) -> Result<Vec<Warning>> {
+    self.depth = depth;
+    let elements = (start..).zip(chunk);
+    self.stack.push(Frame::List { list, elements });
+    self.drain()?;
+    Ok(self.warnings)
+  }
+
+  /// Encodes the contents of all open frames.
+  fn drain(&mut self) -> Result<()> {
+    loop {
+      // Find the next field or element of the innermost record or list,
+      // closing it if it has none left.
+      let (segment, field, ty, value, nullable) = match self.stack.last_mut() {
+        None => return Ok(()),
+
+        Some(Frame::Record {
+          record,
+          field_map,
+          field_width,
+          nested,
+          sparse,
+          dense,
+          fields,
+        }) => match fields.next() {
+          Some((k, v)) => {
+            let segment = Segment::Field(k.clone());
+            let id = match field_map.get(k.as_str()) {
+              Some(id) => *id,
+              None
+                if self.options.unknown_fields == UnknownFieldPolicy::Error =>
+              {
+                self.path.push(segment);
+                return Err(
+                  self.error(Error::UnknownField { path: Path::root() }),
+                );
+              }
+              // Preserved fields are written out separately once the whole
+              // value has been encoded
+              None => {
+                if self.options.unknown_fields == UnknownFieldPolicy::Ignore {
+                  self.warn(Some(segment), WarningKind::UnknownField);
+                }
+                continue;
+              }
+            };
+            if record.is_deprecated(k) {
+              match self.options.deprecated_fields {
+                DeprecatedFieldPolicy::Error => {
+                  self.path.push(segment);
+                  let e = Error::DeprecatedField { path: Path::root() };
+                  return Err(self.error(e));
+                }
+                DeprecatedFieldPolicy::Ignore => {
+                  self.warn(Some(segment), WarningKind::DeprecatedField);
+                  continue;
+                }
+              }
+            }
+            let inline = record.inline_record(k).filter(|_| !*dense);
+            if let Some(inline) = inline {
+              let (width, first) = (*field_width, id);
+              self.path.push(segment);
+              if let Err(e) = self.open_inline(inline, width, first, v) {
+                return Err(self.error(e));
+              }
+              continue;
+            }
+            let field = match sparse {
+              Some(prev) => {
+                let field = Field::sparse(id, *prev);
+                *prev = id.index() as u32 + 1;
+                field
+              }
+              None => Field::new(*field_width, id),
+            };
+            let nullable = record.is_nullable(k);
+            (segment, Some(field), &record.fields[k], v, nullable)
+          }
+          None => {
+            // Terminator uses the same field width as the rest of this
+            // record's fields, or is a zero code point for sparse layouts
+            // which have one even at the root
+            let terminator = Block::Terminator {
+              width: match sparse {
+                Some(_) => LengthEncoding::Vie.bit_len(0),
+                None => *field_width,
+              },
+            };
+            let nested = *nested || sparse.is_some();
+            self.close();
+            if nested {
+              self.push(terminator)?;
+            }
+            continue;
+          }
+        },
+
+        Some(Frame::List { list, elements }) => match elements.next() {
+          Some((i, v)) => (Segment::Index(i), None, list.0.as_ref(), v, false),
+          None => {
+            self.close();
+            continue;
+          }
+        },
+      };
+
+      // Only the elements of a root list are preceded by sync blocks, which
+      // the encoders of parts of an object don't write
+      if let (Segment::Index(i), 1) = (&segment, self.stack.len()) {
+        let i = *i;
+        if let Err(e) = self.push_sync(i) {
+          self.path.push(segment);
+          return Err(self.error(e));
+        }
+      }
+
+      // Note that we switch based on the expected type as defined in the
+      // schema and not what the value actually is. The schema is what drives
+      // the encoding process, not the value.
+      self.path.push(segment);
+      // Covers encoding the whole value, so the time spent on each field can
+      // be told apart
+      #[cfg(feature = "tracing")]
+      let span = tracing::trace_span!("value", path = %Path(self.path.clone()))
+        .entered();
+      let ty = match (ty, field) {
+        (Type::Union { alternatives }, None) => {
+          match self.union_tag(alternatives, value) {
+            Ok(ty) => ty,
+            Err(e) => return Err(self.error(e)),
+          }
+        }
+        (ty, _) => ty,
+      };
+      let null = nullable
+        && value.is_null()
+        && matches!(ty, Type::Nested(CompositeType::List(_)));
+      let result = match (ty, field) {
+        // Nullable lists store one more than their length, leaving a length
+        // of zero for null
+        (_, Some(f)) if null => Length::encoded(0, self.lengths)
+          .and_then(|len| self.push(Block::ListHeader(f, len))),
+        // Composite list elements are treated as nested objects with a zero
+        // width field so that records still get a terminator.
+        (Type::Nested(ct), f) => {
+          let f = f.unwrap_or_else(|| Field::null(0));
+          self.open(ct, Some(f), value, nullable)
+        }
+        (_, Some(f)) => {
+          let options = self.options;
+          self.blocks += 1;
+          encode_field(f, ty, self.sink, options, self.lengths, value)
+        }
+        (_, None) => {
+          let options = self.options;
+          self.blocks += 1;
+          encode_element(ty, self.sink, options, self.lengths, value)
+        }
+      };
+      if let Err(e) = result {
+        return Err(self.error(e));
+      }
+      if null || !matches!(ty, Type::Nested(_)) {
+        self.check_lossy(ty, value);
+        self.path.pop();
+      } else {
+        // The span of a composite value stays entered until its frame is
+        // closed, covering the values within it
+        #[cfg(feature = "tracing")]
+        self.spans.push((self.stack.len(), span));
+      }
+    }
+  }
+
+  /// Pushes the header of a composite `value` and a frame for encoding its
+  /// contents. The length of a `nullable` list is stored as one more than
+  /// its number of elements.
+  fn open(
+    &mut self,
+    ct: &'a CompositeType,
+    field: Option<Field>,
+    value: &'a Value,
+    nullable: bool,
+  ) -> Result<()> {
+    self.check_depth()?;
+    let frame = match ct {
+      CompositeType::Record(record) => {
+        // Cast `value` into an object
+        let value_map = value
+          .as_object()
+          .ok_or_else(|| type_mismatch("object", value))?;
+
+        // If this record is nested, push its header on first
+        if let Some(f) = field {
+          self.push(Block::RecordHeader(f))?;
+        }
+
+        self.check_required(record, value_map)?;
+
+        let field_map = record.field_map();
+        let mut fields: Vec<_> = value_map.iter().collect();
+        let dense = self.options.profile == Profile::Dense && record.is_dense();
+        if dense {
+          if let Some(name) =
+            record.fields.keys().find(|k| !value_map.contains_key(*k))
+          {
+            bail!("dense record is missing field {}", name);
+          }
+        }
+        let sparse = record.sparse && !dense && {
+          let ignored = |k: &str| {
+            record.is_deprecated(k)
+              && self.options.deprecated_fields == DeprecatedFieldPolicy::Ignore
+          };
+          let ids = fields
+            .iter()
+            .filter(|(k, _)| !ignored(k))
+            .filter_map(|(k, _)| field_map.get(k.as_str()).copied());
+          let layout = self.options.layout;
+          prefers_sparse(record, ids, field.is_some(), layout)
+        };
+        if record.sparse && !dense {
+          let flag = BitVec::from_elem(1, sparse);
+          self.push(Block::FixedWidthElement(flag))?;
+        }
+
+        // Schema order is the order of the fields' identifiers. Sparse
+        // layouts need their markers in order and dense ones have no markers
+        // at all.
+        if sparse || dense || self.options.field_order == FieldOrder::Schema {
+          fields.sort_by_key(|(k, _)| field_map.get(k.as_str()).copied());
+        }
+
+        Frame::Record {
+          record,
+          field_map,
+          field_width: if dense { 0 } else { record.field_width() },
+          nested: field.is_some() && !dense,
+          sparse: if sparse { Some(0) } else { None },
+          dense,
+          fields: fields.into_iter(),
+        }
+      }
+
+      CompositeType::List(list) => {
+        // Cast `value` into an array first as we need its length for the
+        // header
+        let arr = value
+          .as_array()
+          .ok_or_else(|| type_mismatch("array", value))?;
+
+        // Lists always push a header as the decoder needs to know how many
+        // elements to expect. Root lists and lists nested directly in other
+        // lists don't have a field id so they use a zero width field.
+        let len = Length::encoded(arr.len() + nullable as usize, self.lengths)?;
+        let field = field.unwrap_or_else(|| Field::null(0));
+        let header = Block::ListHeader(field, len);
+        self.push(header)?;
+
+        if let Some(size) = self.chunks.filter(|size| arr.len() > *size) {
+          self.encode_chunks(list, arr, size)?;
+          // All elements have been encoded, leaving an empty frame which is
+          // closed as usual
+          let elements = (arr.len()..).zip(&arr[arr.len()..]);
+          self.stack.push(Frame::List { list, elements });
+          return Ok(());
+        }
+
+        #[cfg(feature = "parallel")]
+        if let Type::Nested(ct) = list.0.as_ref() {
+          if arr.len() >= PARALLEL_THRESHOLD {
+            self.encode_parallel(ct, arr)?;
+            let elements = (arr.len()..).zip(&arr[arr.len()..]);
+            self.stack.push(Frame::List { list, elements });
+            return Ok(());
+          }
+        }
+
+        Frame::List {
+          list,
+          elements: (0..).zip(arr),
+        }
+      }
+    };
+
+    self.stack.push(frame);
+    Ok(())
+  }
+
+  /// Pushes a frame for encoding the fields of an inline `record` among
+  /// its parent's, whose markers are `field_width` bits wide, starting at
+  /// the identifier `first`. Inline records have no header or terminator.
+  fn open_inline(
+    &mut self,
+    record: &'a Record,
+    field_width: usize,
+    first: FieldId,
+    value: &'a Value,
+  ) -> Result<()> {
+    self.check_depth()?;
+    let value_map = value
+      .as_object()
+      .ok_or_else(|| type_mismatch("object", value))?;
+    self.check_required(record, value_map)?;
+
+    let first = first.index() as u32;
+    let field_map: BTreeMap<_, _> = record
+      .field_map()
+      .into_iter()
+      .map(|(k, id)| (k, FieldId::new(first + id.index() as u32)))
+      .collect();
+    let mut fields: Vec<_> = value_map.iter().collect();
+    if self.options.field_order == FieldOrder::Schema {
+      fields.sort_by_key(|(k, _)| field_map.get(k.as_str()).copied());
+    }
+    self.stack.push(Frame::Record {
+      record,
+      field_map,
+      field_width,
+      nested: false,
+      sparse: None,
+      dense: false,
+      fields: fields.into_iter(),
+    });
+    Ok(())
+  }
+
+  fn check_depth(&self) -> Result<()> {
+    if self.depth + self.stack.len() >= self.options.max_depth {
+      return Err(
+        Error::LimitExceeded {
+          path: Path::root(),
+          limit: Limit::Depth(self.options.max_depth),
+        }
+        .into(),
+      );
+    }
+    Ok(())
+  }
+
+  /// Pushes the tag of the first of a union's `alternatives` which accepts
+  /// `value`, returning its type.
+  fn union_tag(
+    &mut self,
+    alternatives: &'a [Type],
+    value: &Value,
+  ) -> Result<&'a Type> {
+    let (tag, ty) = union_alternative(alternatives, value)?;
+    let mut bits = BitVec::from_rev_be(tag as u64);
+    bits.truncate(math::required_bit_width(alternatives.len()));
+    self.push(Block::FixedWidthElement(bits))?;
+    Ok(ty)
+  }
+
+  /// Applies the missing field policy to any required fields of `record`
+  /// which are not in `value_map`.
+  fn check_required(
+    &mut self,
+    record: &Record,
+    value_map: &serde_json::Map<String, Value>,
+  ) -> Result<()> {
+    let missing = record
+      .required
+      .iter()
+      .filter(|name| !value_map.contains_key(name.as_str()));
+    for name in missing {
+      let e = Error::MissingField {
+        path: Path(vec![Segment::Field(name.clone())]),
+      };
+      match self.options.missing_fields {
+        MissingFieldPolicy::Error => return Err(e.into()),
+        MissingFieldPolicy::Warn => {
+          let segment = Segment::Field(name.clone());
+          self.warn(Some(segment), WarningKind::MissingField);
+        }
+        // Defaults have already been filled in by `prepare`
+        MissingFieldPolicy::FillDefault => {}
+      }
+    }
+    Ok(())
+  }
+
+  /// Encodes the composite elements of a list into independent parts in
+  /// parallel, then appends them to the sink in order.
+  ///
+  /// Errors are attributed to their elements within the parts, so the error
+  /// reported is always that of the first element which failed.
+  #[cfg(feature = "parallel")]
+  fn encode_parallel(
+    &mut self,
+    ct: &'a CompositeType,
+    arr: &'a [Value],
+  ) -> Result<()> {
+    use rayon::prelude::*;
+
+    // The list's own frame is pushed once its elements are encoded
+    let depth = self.depth + self.stack.len() + 1;
+    let (options, lengths, base) = (self.options, self.lengths, &self.path);
+    let chunks = self.chunks;
+    let parts: Vec<Result<_>> = arr
+      .par_iter()
+      .enumerate()
+      .map(|(i, value)| {
+        let mut path = base.clone();
+        path.push(Segment::Index(i));
+        let mut part = S::Part::default();
+        let (blocks, warnings) = Encoder::new(options, lengths, &mut part)
+          .with_chunks(chunks)
+          .run_element(ct, value, path, depth)?;
+        Ok((part, blocks, warnings))
+      })
+      .collect();
+
+    let root = self.stack.is_empty();
+    for (i, part) in parts.into_iter().enumerate() {
+      let (part, blocks, warnings) = part?;
+      if root {
+        self.push_sync(i)?;
+      }
+      self.sink.append(part)?;
+      self.blocks += blocks;
+      self.warnings.extend(warnings);
+    }
+    Ok(())
+  }
+
+  /// Encodes the elements of a list in chunks of `size` elements, each
+  /// starting with a header holding the number of bits its elements take up.
+  ///
+  /// Chunks are encoded separately so that their lengths are known before
+  /// they are pushed, which happens as soon as each one is complete. Large
+  /// lists have their chunks encoded in parallel.
+  fn encode_chunks(
+    &mut self,
+    list: &'a List,
+    arr: &'a [Value],
+    size: usize,
+  ) -> Result<()> {
+    // The list's own frame is pushed by each chunk's encoder
+    let depth = self.depth + self.stack.len();
+    let (options, lengths, chunks) = (self.options, self.lengths, self.chunks);
+    let encode = |(i, chunk): (usize, &'a [Value])| {
+      let mut part = CompressedObject::with_layout(options.layout);
+      Encoder::new(options, lengths, &mut part)
+        .with_chunks(chunks)
+        .run_chunk(list, chunk, i * size, depth)
+        .map(|warnings| (part, warnings))
+    };
+
+    // Only the chunks of a root list are preceded by sync blocks
+    let root = self.stack.is_empty();
+
+    #[cfg(feature = "parallel")]
+    if arr.len() >= PARALLEL_THRESHOLD {
+      use rayon::prelude::*;
+      let parts: Vec<Result<(CompressedObject, Vec<Warning>)>> =
+        arr.par_chunks(size).enumerate().map(&encode).collect();
+      for (i, part) in parts.into_iter().enumerate() {
+        let (part, warnings) = part?;
+        self.push_chunk(part, root.then(|| i * size))?;
+        self.extend_warnings(warnings);
+      }
+      return Ok(());
+    }
+
+    for chunk in arr.chunks(size).enumerate() {
+      let start = root.then(|| chunk.0 * size);
+      let (part, warnings) = encode(chunk)?;
+      self.push_chunk(part, start)?;
+      self.extend_warnings(warnings);
+    }
+    Ok(())
+  }
+
+  /// Pushes the header of a chunk followed by its blocks, after the sync
+  /// block of the chunk if it starts at element `start` of a root list.
+  fn push_chunk(
+    &mut self,
+    part: CompressedObject,
+    start: Option<usize>,
+  ) -> Result<()> {
+    if let Some(start) = start {
+      self.push_sync(start)?;
+    }
+    let len = Length::encoded(part.bit_len(), self.lengths)?;
+    self.push(Block::ChunkHeader(len))?;
+    let layout = self.options.layout;
+    self.blocks += part.blocks.len();
+    for block in part.blocks {
+      self.sink.push(block, layout)?;
+    }
+    Ok(())
+  }
+
+  /// Pushes a `block`.
+  fn push(&mut self, block: Block) -> Result<()> {
+    self.blocks += 1;
+    self.sink.push(block, self.options.layout)
+  }
+
+  /// Pushes the sync block before element `i` of a root list, holding a
+  /// marker if enough blocks have been pushed since the last one. Does
+  /// nothing if there are no sync markers.
+  fn push_sync(&mut self, i: usize) -> Result<()> {
+    let every = match self.sync {
+      Some(every) => every,
+      None => return Ok(()),
+    };
+    let marker = match self.blocks - self.synced >= every {
+      true => {
+        self.synced = self.blocks;
+        Some(Length::encoded(i, self.lengths)?)
+      }
+      false => None,
+    };
+    self.sink.push(Block::Sync(marker), self.options.layout)
+  }
+
+  /// Pops the innermost frame, exiting the span of its value.
+  fn close(&mut self) {
+    self.stack.pop();
+    self.path.pop();
+    #[cfg(feature = "tracing")]
+    if matches!(self.spans.last(), Some((n, _)) if *n > self.stack.len()) {
+      self.spans.pop();
+    }
+  }
+
+  /// Records a warning about the value currently being encoded, or its
+  /// field at `segment`.
+  fn warn(&mut self, segment: Option<Segment>, kind: WarningKind) {
+    let mut path = Path(self.path.clone());
+    path.0.extend(segment);
+    self.warnings.push(Warning { path, kind });
+  }
+
+  /// Records `warnings` about values within the one currently being encoded.
+  fn extend_warnings(&mut self, warnings: Vec<Warning>) {
+    for mut w in warnings {
+      w.path.0.splice(0..0, self.path.iter().cloned());
+      self.warnings.push(w);
+    }
+  }
+
+  /// Records a warning if the non-nested `value` of type `ty` which has just
+  /// been encoded was changed to fit its type.
+  fn check_lossy(&mut self, ty: &Type, value: &Value) {
+    if let Some(kind) = lossy(ty, value) {
+      self.warn(None, kind);
+    }
+  }
+
+  /// Attributes an error to the value currently being encoded.
+  fn error(&mut self, e: impl Into<anyhow::Error>) -> anyhow::Error {
+    let path = Path(core::mem::take(&mut self.path));
+    within_path(e.into(), &path, "encoding")
+  }
+}
+
+/// Returns the kind of warning for a non-nested `value` of type `ty` which
+/// is changed when compressed, if it is.
+fn lossy(ty: &Type, value: &Value) -> Option<WarningKind> {
+  match ty {
+    Type::Scaled {
+      round: Some(comp::Rounding::Exact),
+      ..
+    } => None,
+    Type::Scaled { scale, .. } => {
+      let x = value.as_f64()?;
+      let exact = comp::ScaledCompressor {
+        scale: *scale,
+        round: comp::Rounding::Exact,
+      };
+      if exact.compress(comp::Value::Float(x)).is_ok() {
+        return None;
+      }
+      Some(WarningKind::Rounded {
+        value: x,
+        scale: *scale,
+      })
+    }
+    Type::Transformed { transform, .. } => {
+      let from = value.as_str()?;
+      let mut to = comp::Value::Str(from.to_string());
+      for t in transform {
+        to = comp::Transform::apply(t, to).ok()?;
+      }
+      match to {
+        comp::Value::Str(to) if to != from => Some(WarningKind::Transformed {
+          from: from.to_string(),
+          to,
+        }),
+        _ => None,
+      }
+    }
+    _ => None,
+  }
+}
+
+/// Returns `true` if the fields of a sparse `record` with identifiers `ids`
+/// take up fewer bits marked with the differences between their markers than
+/// with the markers themselves.
+pub(crate) fn prefers_sparse(
+  record: &Record,
+  ids: impl Iterator<Item = FieldId>,
+  nested: bool,
+  layout: Layout,
+) -> bool {
+  let mut markers: Vec<usize> = ids.map(|id| id.index() + 1).collect();
+  markers.sort_unstable();
+  let marker = layout.padded(record.field_width());
+  let dense = marker * (markers.len() + nested as usize);
+  let mut prev = 0;
+  let mut sparse = LengthEncoding::Vie.bit_len(0);
+  for m in markers {
+    sparse += LengthEncoding::Vie.bit_len(m - prev);
+    prev = m;
+  }
+  sparse < dense
+}
+
 /// Encodes a non-nested element.
-fn encode_element(
+fn encode_element<S: Sink>(
   ty: &Type,
-  co: &mut CompressedObject,
+  sink: &mut S,
+  options: &EncodeOptions,
+  lengths: LengthEncoding,
   value: &Value,
 ) -> Result<()> {
   let compressor = get_compressor_for_type(ty)?;
-  let value = comp::Value::try_from(value)?;
-  let bits = compressor.compress(value)?;
+  let bits = compress(compressor.as_ref(), value)?;
 
+  let mut checksum = None;
   let block = if compressor.encoded_width() == EncodedWidth::Variable {
-    let len = Length::new(bits.len());
+    let len = Length::encoded(bits.len(), lengths)?;
+    checksum = options.checksums.then(|| bit::crc8(&bits));
     Block::VariableWidthElement(len, bits)
   } else {
     Block::FixedWidthElement(bits)
   };
 
-  co.push(block);
+  sink.push(block, options.layout)?;
+  if let Some(crc) = checksum {
+    sink.push(Block::Checksum(crc), options.layout)?;
+  }
   Ok(())
 }
 
 /// Encodes a non-nested field.
-fn encode_field(
+fn encode_field<S: Sink>(
   field: Field,
   ty: &Type,
-  co: &mut CompressedObject,
+  sink: &mut S,
+  options: &EncodeOptions,
+  lengths: LengthEncoding,
   value: &Value,
 ) -> Result<()> {
   let compressor = get_compressor_for_type(ty)?;
-  let value = comp::Value::try_from(value)?;
-  let bits = compressor.compress(value)?;
+  let bits = compress(compressor.as_ref(), value)?;
 
+  let mut checksum = None;
   let block = if compressor.encoded_width() == EncodedWidth::Variable {
-    let len = Length::new(bits.len());
+    let len = Length::encoded(bits.len(), lengths)?;
+    checksum = options.checksums.then(|| bit::crc8(&bits));
     Block::VariableWidthField(field, len, bits)
   } else {
     Block::FixedWidthField(field, bits)
   };
 
-  co.push(block);
+  sink.push(block, options.layout)?;
+  if let Some(crc) = checksum {
+    sink.push(Block::Checksum(crc), options.layout)?;
+  }
   Ok(())
 }
 
-fn get_compressor_for_type(ty: &Type) -> Result<Box<dyn Compressor>> {
+/// Compresses a non-nested value reporting any failure as an [`Error`].
+fn compress(compressor: &dyn Compressor, value: &Value) -> Result<BitVec> {
+  let v = comp::Value::try_from(value)
+    .map_err(|_| type_mismatch("a primitive value", value))?;
+  let bits = compressor
+    .compress(v)
+    .map_err(|e| Error::invalid(value, e))?;
+  #[cfg(feature = "tracing")]
+  tracing::trace!(bits = bits.len(), "compressed");
+  Ok(bits)
+}
+
+/// Returns the index and type of the first of a union's `alternatives` which
+/// accepts `value`.
+pub(crate) fn union_alternative<'t>(
+  alternatives: &'t [Type],
+  value: &Value,
+) -> Result<(usize, &'t Type)> {
+  if alternatives.is_empty() {
+    bail!("a union needs at least one type");
+  }
+  alternatives
+    .iter()
+    .enumerate()
+    .find(|(_, ty)| accepts(ty, value))
+    .ok_or_else(|| {
+      type_mismatch("a value of one of the union's types", value).into()
+    })
+}
+
+/// Whether `value` can be encoded as an alternative of type `ty`.
+fn accepts(ty: &Type, value: &Value) -> bool {
+  match (ty, value) {
+    (Type::Nested(CompositeType::Record(record)), Value::Object(map)) => {
+      map.keys().all(|k| record.fields.contains_key(k))
+        && record.required.iter().all(|name| map.contains_key(name))
+    }
+    (Type::Nested(CompositeType::List(_)), Value::Array(_)) => true,
+    (Type::Nested(_), _) | (Type::Union { .. }, _) => false,
+    (ty, value) => get_compressor_for_type(ty)
+      .and_then(|c| compress(c.as_ref(), value))
+      .is_ok(),
+  }
+}
+
+pub(crate) fn type_mismatch(expected: &str, value: &Value) -> Error {
+  Error::TypeMismatch {
+    path: Path::root(),
+    expected: expected.to_string(),
+    value: value.clone(),
+  }
+}
+
+/// Returns the compressor used to encode and decode values of a given
+/// non-nested type.
+pub(crate) fn get_compressor_for_type(
+  ty: &Type,
+) -> Result<Box<dyn Compressor>> {
   use Type::*;
 
   match ty {
     PassThrough => Ok(Box::new(comp::IdentityCompressor)),
     Name(name) => lookup_named_compressor(name),
-    Enum { variants } => Ok(Box::new(comp::EnumCompressor {
+    Enum {
+      variants,
+      probabilities: None,
+    } => Ok(Box::new(comp::EnumCompressor {
       variants: variants.iter().cloned().collect(),
     })),
-    Nested(_) => panic!("cannot get compressor for composite type"),
+    Enum {
+      variants,
+      probabilities: Some(probabilities),
+    } => {
+      if let Some(v) = probabilities.keys().find(|v| !variants.contains(*v)) {
+        bail!("'{}' has a probability but is not a variant", v);
+      }
+      let probabilities = variants
+        .iter()
+        .map(|v| {
+          probabilities
+            .get(v)
+            .copied()
+            .ok_or_else(|| anyhow!("variant '{}' has no probability", v))
+        })
+        .collect::<Result<Vec<_>>>()?;
+      Ok(Box::new(comp::WeightedEnumCompressor::new(
+        variants.iter().cloned().collect(),
+        &probabilities,
+      )?))
+    }
+    IntEnum { codes } => Ok(Box::new(comp::IntEnumCompressor {
+      codes: codes.iter().cloned().collect(),
+    })),
+    Dictionary { size } => {
+      if *size == 0 {
+        bail!("dictionary size must be at least 1");
+      }
+      Ok(Box::new(comp::DictionaryCompressor { size: *size }))
+    }
+    Huffman { huffman } => {
+      Ok(Box::new(comp::RawFallback(comp::HuffmanCompressor {
+        book: huffman.clone(),
+      })))
+    }
+    Scaled { scale, round } => {
+      if !(*scale > 0.0 && scale.is_finite()) {
+        bail!("scale must be a positive number, not {}", scale);
+      }
+      Ok(Box::new(comp::ScaledCompressor {
+        scale: *scale,
+        round: round.unwrap_or(comp::Rounding::Nearest),
+      }))
+    }
+    Transformed { transform, of } => Ok(Box::new(comp::TransformCompressor {
+      transforms: transform
+        .iter()
+        .map(|t| Box::new(*t) as Box<dyn comp::Transform>)
+        .collect(),
+      inner: get_compressor_for_type(of)?,
+    })),
+    Nested(_) => bail!("cannot get compressor for composite type"),
+    Union { .. } => bail!("a union can only be the element type of a list"),
   }
 }
 
+/// Whether `name` is the name of a `float(precision=N)` type.
+pub(crate) fn is_float(name: &str) -> bool {
+  comp::QuantizedCompressor::parse_precision(name).is_some()
+}
+
 /// Attempts to find the compressor for a given name. Returns `None` if unable
 /// to find a compressor.
 fn lookup_named_compressor(name: &str) -> Result<Box<dyn Compressor>> {
   match name {
+    "any" => Ok(Box::new(comp::AnyCompressor)),
     "bool" => Ok(Box::new(comp::BooleanCompressor)),
-    _ => bail!("cannot determine compressor for '{}'", name),
+    "f16" => Ok(Box::new(comp::HalfCompressor)),
+    "color" => Ok(Box::new(comp::ColorCompressor)),
+    "lzw" => Ok(Box::new(comp::RawFallback(comp::LzwCompressor))),
+    "smaz" => Ok(Box::new(comp::RawFallback(comp::SmazCompressor))),
+    #[cfg(feature = "experimental-bwt")]
+    "bwt" => Ok(Box::new(comp::BwtCompressor)),
+    "timestamp" => Ok(Box::new(comp::TimestampCompressor)),
+    _ => {
+      if let Some(precision) = comp::QuantizedCompressor::parse_precision(name)
+      {
+        return Ok(Box::new(comp::QuantizedCompressor::new(precision)?));
+      }
+      if let Some((precision, form)) = comp::GeoCompressor::parse(name) {
+        return Ok(Box::new(comp::GeoCompressor::new(precision, form)?));
+      }
+      bail!("cannot determine compressor for '{}'", name)
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::Record;
+  use std::collections::{BTreeMap, BTreeSet};
+
+  #[test]
+  fn fields_are_encoded_in_schema_order() {
+    let mut fields = BTreeMap::new();
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert("name".to_string(), Type::PassThrough);
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+
+    let mut value = serde_json::Map::new();
+    value.insert("name".to_string(), Value::from("Jeremy"));
+    value.insert("active".to_string(), Value::from(true));
+    let co = encode(&schema, &Value::Object(value)).unwrap();
+
+    let ids: Vec<_> = co
+      .blocks
+      .iter()
+      .map(|block| match block {
+        Block::FixedWidthField(f, _) | Block::VariableWidthField(f, _, _) => *f,
+        _ => unreachable!(),
+      })
+      .collect();
+    assert_eq!(
+      vec![
+        Field::new(2, FieldId::new(0)),
+        Field::new(2, FieldId::new(1))
+      ],
+      ids
+    );
+  }
+
+  #[test]
+  fn missing_field_policies() {
+    let mut course = BTreeMap::new();
+    course.insert("grade".to_string(), Type::PassThrough);
+    let mut course = Record::new(course);
+    course.required.insert("grade".to_string());
+
+    let mut fields = BTreeMap::new();
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(course),
+      ))))),
+    );
+    let mut student = Record::new(fields);
+    student.required.insert("active".to_string());
+    let schema = Schema::new(CompositeType::Record(student));
+
+    let value = serde_json::json!({ "active": true, "courses": [{}] });
+    let e = encode(&schema, &value).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert!(matches!(e, Error::MissingField { .. }));
+    assert_eq!("/courses/0/grade", e.pointer());
+
+    let options = EncodeOptions {
+      missing_fields: MissingFieldPolicy::Warn,
+      ..Default::default()
+    };
+    let co = encode_with(&schema, &value, &options).unwrap();
+    let bits: BitVec = co.into();
+    assert_eq!(value, crate::decode(&schema, &bits.to_bytes()).unwrap());
+
+    let options = EncodeOptions {
+      missing_fields: MissingFieldPolicy::FillDefault,
+      ..Default::default()
+    };
+    let value = serde_json::json!({ "courses": [{}] });
+    let co = encode_with(&schema, &value, &options).unwrap();
+    let bits: BitVec = co.into();
+    assert_eq!(
+      serde_json::json!({ "active": false, "courses": [{ "grade": "" }] }),
+      crate::decode(&schema, &bits.to_bytes()).unwrap()
+    );
+  }
+
+  #[test]
+  fn large_lists_of_records() {
+    let mut point = BTreeMap::new();
+    point.insert("on".to_string(), Type::Name("bool".to_string()));
+    point.insert("label".to_string(), Type::PassThrough);
+    let schema = Schema::new(CompositeType::List(List(Box::new(
+      Type::Nested(CompositeType::Record(Record::new(point))),
+    ))));
+
+    let points = (0..5000).map(|i| {
+      let mut point = serde_json::Map::new();
+      point.insert("on".to_string(), Value::from(i % 2 == 0));
+      point.insert("label".to_string(), Value::from(i.to_string()));
+      Value::Object(point)
+    });
+    let mut points: Vec<_> = points.collect();
+    let value = Value::Array(points.clone());
+
+    let co = encode(&schema, &value).unwrap();
+    let mut bytes = Vec::new();
+    encode_to(&schema, &value, &mut bytes).unwrap();
+    assert_eq!(co.to_bytes(), bytes);
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+
+    points[4321] = Value::from(1);
+    points[4567] = Value::from(2);
+    let e = encode(&schema, &Value::Array(points)).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert_eq!("/4321", e.pointer());
+  }
+
+  #[test]
+  fn deprecated_and_reserved_fields() {
+    let mut fields = BTreeMap::new();
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert("middle".to_string(), Type::PassThrough);
+    fields.insert("name".to_string(), Type::PassThrough);
+    let old = Schema::new(CompositeType::Record(Record::new(fields.clone())));
+
+    // Reserving a removed field keeps the identifiers of the others
+    fields.remove("middle");
+    let mut record = Record::new(fields);
+    record.reserved.insert("middle".to_string());
+    record.deprecated.insert("active".to_string());
+    assert_eq!(Some(&FieldId::new(2)), record.field_map().get("name"));
+    assert!(record.field_by_id(FieldId::new(1)).is_none());
+    let schema = Schema::new(CompositeType::Record(record));
+
+    // Deprecated fields are still decoded
+    let value = serde_json::json!({ "active": true, "name": "Jeremy" });
+    let bytes = encode(&old, &value).unwrap().to_bytes();
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+
+    let e = encode(&schema, &value).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert!(matches!(e, Error::DeprecatedField { .. }));
+    assert_eq!("/active", e.pointer());
+
+    let options = EncodeOptions {
+      deprecated_fields: DeprecatedFieldPolicy::Ignore,
+      ..Default::default()
+    };
+    let bytes = encode_with(&schema, &value, &options).unwrap().to_bytes();
+    let value = serde_json::json!({ "name": "Jeremy" });
+    assert_eq!(bytes, encode(&old, &value).unwrap().to_bytes());
+  }
+
+  #[test]
+  fn warnings() {
+    let color = Type::Transformed {
+      transform: vec![comp::TransformKind::Lowercase],
+      of: Box::new(Type::Enum {
+        variants: ["green", "red"].iter().map(|v| v.to_string()).collect(),
+        probabilities: None,
+      }),
+    };
+    let mut fields = BTreeMap::new();
+    fields.insert("color".to_string(), color);
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("old".to_string(), Type::PassThrough);
+    fields.insert(
+      "price".to_string(),
+      Type::Scaled {
+        scale: 100.0,
+        round: None,
+      },
+    );
+    let mut record = Record::new(fields);
+    record.required.insert("name".to_string());
+    record.deprecated.insert("old".to_string());
+    let list = List(Box::new(Type::Nested(CompositeType::Record(record))));
+    let schema = Schema::new(CompositeType::List(list));
+
+    let options = EncodeOptions {
+      missing_fields: MissingFieldPolicy::Warn,
+      unknown_fields: UnknownFieldPolicy::Ignore,
+      deprecated_fields: DeprecatedFieldPolicy::Ignore,
+      ..Default::default()
+    };
+    let value = serde_json::json!([
+      { "name": "a", "color": "red", "price": 1.25 },
+      { "color": "Red", "price": 1.255, "old": "x", "extra": 1 }
+    ]);
+    let (co, warnings) =
+      encode_with_warnings(&schema, &value, &options).unwrap();
+    assert_eq!(co, encode_with(&schema, &value, &options).unwrap());
+    let warnings: Vec<_> = warnings.iter().map(|w| w.to_string()).collect();
+    assert_eq!(
+      vec![
+        "missing required field: name (at /1/name)",
+        "unexpected field: extra (at /1/extra)",
+        "transformed \"Red\" into \"red\" (at /1/color)",
+        "deprecated field: old (at /1/old)",
+        "rounded 1.255 to a multiple of 0.01 (at /1/price)",
+      ],
+      warnings
+    );
+
+    // Streaming returns the same warnings instead of printing them
+    let streamed =
+      encode_to_with(&schema, &value, &mut Vec::new(), &options).unwrap();
+    let streamed: Vec<_> = streamed.iter().map(|w| w.to_string()).collect();
+    assert_eq!(warnings, streamed);
+
+    // Chunks are encoded separately but their warnings have the same paths
+    let chunked = schema.clone().with_chunks(1);
+    let (_, chunked) =
+      encode_with_warnings(&chunked, &value, &options).unwrap();
+    let chunked: Vec<_> = chunked.iter().map(|w| w.to_string()).collect();
+    assert_eq!(warnings, chunked);
+  }
+
+  #[test]
+  fn length_encodings() {
+    use crate::data::LengthEncoding;
+
+    let list = List(Box::new(Type::PassThrough));
+    let strings =
+      vec![String::new(), "a".into(), "Jeremy".into(), "x".repeat(300)];
+    let value = Value::Array(strings.into_iter().map(Value::from).collect());
+    for lengths in &[
+      LengthEncoding::Vie,
+      LengthEncoding::Fixed16,
+      LengthEncoding::Fixed32,
+      LengthEncoding::Gamma,
+    ] {
+      let schema =
+        Schema::new(CompositeType::List(list.clone())).with_lengths(*lengths);
+      for layout in &[Layout::Packed, Layout::ByteAligned] {
+        let options = EncodeOptions {
+          layout: *layout,
+          ..Default::default()
+        };
+        let co = encode_with(&schema, &value, &options).unwrap();
+        let decode_options = crate::DecodeOptions {
+          layout: *layout,
+          ..Default::default()
+        };
+        let bytes = co.to_bytes();
+        let decoded = crate::decode_with(&schema, &bytes, decode_options);
+        assert_eq!(value, decoded.unwrap(), "{} {:?}", lengths, layout);
+      }
+      let estimate = crate::estimate::estimate_size(&schema, &value).unwrap();
+      let co = encode(&schema, &value).unwrap();
+      assert_eq!(co.bit_len(), estimate.total());
+    }
+
+    // 0, 8, 48 and 2400 bits of data along with the list's length of 4
+    let lengths = |l: LengthEncoding| {
+      [4, 0, 8, 48, 2400]
+        .iter()
+        .map(|n| l.bit_len(*n))
+        .sum::<usize>()
+    };
+    assert_eq!(5 + 1 + 7 + 11 + 23, lengths(LengthEncoding::Gamma));
+    assert_eq!(8 + 8 + 8 + 8 + 16, lengths(LengthEncoding::Vie));
+
+    let schema = Schema::new(CompositeType::List(list))
+      .with_lengths(LengthEncoding::Fixed16);
+    let long = Value::Array(vec![Value::from("x".repeat(8192))]);
+    let e = encode(&schema, &long).unwrap_err();
+    assert_eq!(
+      "when encoding [0]: length 65536 is too large for fixed16 lengths",
+      format!("{:#}", e)
+    );
+  }
+
+  #[test]
+  fn byte_aligned_layout() {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    let mut fields = BTreeMap::new();
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+    let value = serde_json::json!({
+      "active": true,
+      "courses": [{ "name": "Math" }, {}]
+    });
+
+    let options = EncodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let co = encode_with(&schema, &value, &options).unwrap();
+    for block in &co.blocks {
+      assert_eq!(0, block.bit_len_with(Layout::ByteAligned) % 8);
+    }
+    let bytes = co.to_bytes();
+    assert_eq!(co.bit_len(), bytes.len() * 8);
+    assert!(bytes.len() > encode(&schema, &value).unwrap().to_bytes().len());
+
+    let mut written = Vec::new();
+    encode_to_with(&schema, &value, &mut written, &options).unwrap();
+    assert_eq!(bytes, written);
+
+    let decode_options = crate::DecodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    assert_eq!(
+      value,
+      crate::decode_with(&schema, &bytes, decode_options).unwrap()
+    );
+  }
+
+  #[test]
+  fn lsb_first_bit_order() {
+    let mut fields = BTreeMap::new();
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert("name".to_string(), Type::PassThrough);
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+    let value = serde_json::json!({ "active": true, "name": "Jeremy" });
+
+    let options = EncodeOptions {
+      bit_order: BitOrder::LsbFirst,
+      ..Default::default()
+    };
+    let bytes = encode_with(&schema, &value, &options).unwrap().to_bytes();
+    let msb_first = encode(&schema, &value).unwrap().to_bytes();
+    let reversed: Vec<_> = msb_first.iter().map(|b| b.reverse_bits()).collect();
+    assert_eq!(reversed, bytes);
+
+    let mut written = Vec::new();
+    encode_to_with(&schema, &value, &mut written, &options).unwrap();
+    assert_eq!(bytes, written);
+
+    let decode_options = crate::DecodeOptions {
+      bit_order: BitOrder::LsbFirst,
+      ..Default::default()
+    };
+    assert_eq!(
+      value,
+      crate::decode_with(&schema, &bytes, decode_options).unwrap()
+    );
+  }
+
+  #[test]
+  fn dense_profile() {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    course.insert("room".to_string(), Type::PassThrough);
+    let mut course = Record::new(course);
+    course.required.insert("name".to_string());
+    let mut fields = BTreeMap::new();
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(course),
+      ))))),
+    );
+    let mut record = Record::new(fields);
+    record.required.insert("active".to_string());
+    record.required.insert("courses".to_string());
+    let schema = Schema::new(CompositeType::Record(record));
+    let value = serde_json::json!({
+      "active": true,
+      "courses": [{ "name": "Math", "room": "B12" }, { "name": "Art" }]
+    });
+
+    let options = EncodeOptions {
+      profile: Profile::Dense,
+      ..Default::default()
+    };
+    let co = encode_with(&schema, &value, &options).unwrap();
+    co.validate(&schema).unwrap();
+    // The root record loses its two field markers, while the courses which
+    // have an optional field keep theirs
+    assert_eq!(encode(&schema, &value).unwrap().bit_len() - 4, co.bit_len());
+    let bytes = co.to_bytes();
+    let mut written = Vec::new();
+    encode_to_with(&schema, &value, &mut written, &options).unwrap();
+    assert_eq!(bytes, written);
+
+    let decode_options = crate::DecodeOptions {
+      profile: Profile::Dense,
+      ..Default::default()
+    };
+    assert_eq!(
+      value,
+      crate::decode_with(&schema, &bytes, decode_options).unwrap()
+    );
+    crate::spec::verify_bytes_with(
+      &schema,
+      &bytes,
+      Layout::Packed,
+      Profile::Dense,
+      false,
+    )
+    .unwrap();
+
+    let options = EncodeOptions {
+      missing_fields: MissingFieldPolicy::Warn,
+      ..options
+    };
+    let e =
+      encode_with(&schema, &serde_json::json!({ "active": true }), &options)
+        .unwrap_err();
+    assert_eq!("dense record is missing field courses", format!("{:#}", e));
+  }
+
+  #[test]
+  fn geo_coordinates() {
+    let geo = Type::Name("geo(precision=6)".to_string());
+    let schema = Schema::new(CompositeType::List(List(Box::new(geo))));
+    let value = serde_json::json!([[49.282729, -123.120738], [-90.0, 180.0]]);
+    let co = encode(&schema, &value).unwrap();
+    assert_eq!(value, crate::decode(&schema, &co.to_bytes()).unwrap());
+
+    let e = encode(&schema, &serde_json::json!([[91.0, 0.0]])).unwrap_err();
+    assert_eq!(
+      "invalid value [91.0,0.0]: latitude 91 is not within ±90 (at /0)",
+      format!("{:#}", e)
+    );
+  }
+
+  #[test]
+  fn integer_enums() {
+    let status = Type::IntEnum {
+      codes: [200, 301, 404, 500].iter().cloned().collect(),
+    };
+    let compressor = get_compressor_for_type(&status).unwrap();
+    assert_eq!(EncodedWidth::Fixed(2), compressor.encoded_width());
+
+    let schema = Schema::new(CompositeType::List(List(Box::new(status))));
+    let value = serde_json::json!([404, 200, 500]);
+    let co = encode(&schema, &value).unwrap();
+    assert_eq!(value, crate::decode(&schema, &co.to_bytes()).unwrap());
+
+    let e = encode(&schema, &serde_json::json!([302])).unwrap_err();
+    assert_eq!(
+      "invalid value 302: cannot convert 302 to enum code (at /0)",
+      format!("{:#}", e)
+    );
+  }
+
+  #[test]
+  fn weighted_enums() {
+    let variants: BTreeSet<String> = ["debug", "error", "info", "warn"]
+      .iter()
+      .map(|v| v.to_string())
+      .collect();
+    let probabilities = [("debug", 0.05), ("error", 0.05), ("info", 0.7)]
+      .iter()
+      .map(|(v, p)| (v.to_string(), *p))
+      .collect::<BTreeMap<_, _>>();
+    let weighted = |probabilities| {
+      let level = Type::Enum {
+        variants: variants.clone(),
+        probabilities: Some(probabilities),
+      };
+      Schema::new(CompositeType::List(List(Box::new(level))))
+    };
+
+    let schema = weighted(probabilities.clone());
+    let e = encode(&schema, &serde_json::json!(["info"])).unwrap_err();
+    assert_eq!(
+      "when encoding [0]: variant 'warn' has no probability",
+      format!("{:#}", e)
+    );
+
+    let mut complete = probabilities;
+    complete.insert("warn".to_string(), 0.2);
+    let schema = weighted(complete);
+    let value = serde_json::json!(["info", "info", "warn", "info", "debug"]);
+    let bytes = encode(&schema, &value).unwrap().to_bytes();
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+    crate::spec::verify_bytes(&schema, &bytes).unwrap();
+
+    let plain = Type::Enum {
+      variants,
+      probabilities: None,
+    };
+    let plain = Schema::new(CompositeType::List(List(Box::new(plain))));
+    assert!(bytes.len() < encode(&plain, &value).unwrap().to_bytes().len());
+  }
+
+  #[test]
+  fn any_values() {
+    let mut fields = BTreeMap::new();
+    fields.insert("extra".to_string(), Type::Name("any".to_string()));
+    fields.insert("name".to_string(), Type::PassThrough);
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+
+    for extra in [
+      serde_json::json!(null),
+      serde_json::json!(-7),
+      serde_json::json!({ "tags": ["a", null, 2.5], "ok": true }),
+    ] {
+      let mut value = serde_json::json!({ "name": "x" });
+      value
+        .as_object_mut()
+        .unwrap()
+        .insert("extra".to_string(), extra);
+      let co = encode(&schema, &value).unwrap();
+      assert_eq!(value, crate::decode(&schema, &co.to_bytes()).unwrap());
+    }
+
+    let value = serde_json::json!({ "name": null });
+    let e = encode(&schema, &value).unwrap_err();
+    assert_eq!("/name", e.downcast::<Error>().unwrap().pointer());
+  }
+
+  #[test]
+  fn expanding_codecs_fall_back_to_raw() {
+    let lzw = Type::Name("lzw".to_string());
+    let schema = Schema::new(CompositeType::List(List(Box::new(lzw.clone()))));
+    let long = "ab".repeat(50);
+    let value = Value::Array(vec![Value::from("abcd"), Value::from(&*long)]);
+
+    // Four 9 bit codes take up more than the 4 bytes of "abcd"
+    let compressor = get_compressor_for_type(&lzw).unwrap();
+    let bits = compressor
+      .compress(comp::Value::Str("abcd".to_string()))
+      .unwrap();
+    assert_eq!(1 + 32, bits.len());
+    assert!(bits[0]);
+    let bits = compressor.compress(comp::Value::Str(long.clone())).unwrap();
+    assert!(!bits[0]);
+    assert!(bits.len() < long.len() * 8);
+
+    let bytes = encode(&schema, &value).unwrap().to_bytes();
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+  }
+
+  #[test]
+  fn union_elements() {
+    let mut view = BTreeMap::new();
+    view.insert("page".to_string(), Type::PassThrough);
+    let mut view = Record::new(view);
+    view.required.insert("page".to_string());
+    let mut click = BTreeMap::new();
+    click.insert("x".to_string(), Type::Name("f16".to_string()));
+    click.insert("y".to_string(), Type::Name("f16".to_string()));
+    let union = Type::Union {
+      alternatives: vec![
+        Type::Nested(CompositeType::Record(view)),
+        Type::Nested(CompositeType::Record(Record::new(click))),
+        Type::Name("timestamp".to_string()),
+        Type::PassThrough,
+      ],
+    };
+    let schema = Schema::new(CompositeType::List(List(Box::new(union))));
+
+    let value = serde_json::json!([
+      { "page": "/home" },
+      1700000000,
+      { "x": 1.5, "y": 2.0 },
+      "logout",
+      {}
+    ]);
+    let co = encode(&schema, &value).unwrap();
+    co.validate(&schema).unwrap();
+    let bytes = co.to_bytes();
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+    crate::spec::verify_bytes(&schema, &bytes).unwrap();
+    let path = "[2].x".parse().unwrap();
+    assert_eq!(
+      Some(serde_json::json!(1.5)),
+      crate::decode_path(&schema, &bytes, &path).unwrap()
+    );
+
+    let e = encode(&schema, &serde_json::json!([true])).unwrap_err();
+    assert_eq!("/0", e.downcast::<Error>().unwrap().pointer());
+  }
+
+  #[test]
+  fn null_empty_and_absent_lists() {
+    let list =
+      || Type::Nested(CompositeType::List(List(Box::new(Type::PassThrough))));
+    let mut fields = BTreeMap::new();
+    fields.insert("ids".to_string(), list());
+    fields.insert("tags".to_string(), list());
+    let mut record = Record::new(fields);
+    record.nullable.insert("tags".to_string());
+    let inner = Type::Nested(CompositeType::Record(record.clone()));
+    record.fields.insert("inner".to_string(), inner);
+    let schema = Schema::new(CompositeType::Record(record));
+
+    let values = [
+      serde_json::json!({}),
+      serde_json::json!({ "ids": [], "tags": null }),
+      serde_json::json!({ "tags": [] }),
+      serde_json::json!({ "tags": ["a"], "inner": { "tags": null } }),
+      serde_json::json!({ "inner": { "tags": [], "ids": [] } }),
+    ];
+    let tags = ".tags".parse().unwrap();
+    for value in &values {
+      let co = encode(&schema, value).unwrap();
+      co.validate(&schema).unwrap();
+      let bytes = co.to_bytes();
+      assert_eq!(*value, crate::decode(&schema, &bytes).unwrap());
+      crate::spec::verify_bytes(&schema, &bytes).unwrap();
+      assert_eq!(
+        value.get("tags").cloned(),
+        crate::decode_path(&schema, &bytes, &tags).unwrap()
+      );
+    }
+
+    let e = encode(&schema, &serde_json::json!({ "ids": null })).unwrap_err();
+    assert_eq!("/ids", e.downcast::<Error>().unwrap().pointer());
+  }
+
+  #[test]
+  fn sparse_records() {
+    let fields = (0..300)
+      .map(|i| (format!("f{:03}", i), Type::Name("bool".to_string())))
+      .collect();
+    let mut record = Record::new(fields);
+    record.width = Some(16);
+    let dense = Schema::new(CompositeType::Record(record.clone()));
+    record.sparse = true;
+    let schema = Schema::new(CompositeType::Record(record));
+
+    let value =
+      serde_json::json!({ "f007": true, "f010": false, "f250": true });
+    let co = encode(&schema, &value).unwrap();
+    co.validate(&schema).unwrap();
+    let bytes = co.to_bytes();
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+    crate::spec::verify_bytes(&schema, &bytes).unwrap();
+    let path = ".f250".parse().unwrap();
+    assert_eq!(
+      Some(serde_json::json!(true)),
+      crate::decode_path(&schema, &bytes, &path).unwrap()
+    );
+    let estimate = crate::estimate_size(&schema, &value).unwrap();
+    assert_eq!(co.bit_len(), estimate.total());
+    // A flag, deltas of 8, 3 and 240, a terminator and the data
+    assert_eq!(1 + 8 + 8 + 16 + 8 + 3, co.bit_len());
+    assert!(co.bit_len() < encode(&dense, &value).unwrap().bit_len());
+
+    // An empty root record is smaller with markers, as there is no
+    // terminator
+    let empty = serde_json::json!({});
+    let co = encode(&schema, &empty).unwrap();
+    assert_eq!(1, co.bit_len());
+    assert_eq!(empty, crate::decode(&schema, &co.to_bytes()).unwrap());
+  }
+
+  #[test]
+  fn inline_records() {
+    let bool_type = || Type::Name("bool".to_string());
+    let point: BTreeMap<_, _> = vec![
+      ("x".to_string(), bool_type()),
+      ("y".to_string(), bool_type()),
+    ]
+    .into_iter()
+    .collect();
+    let point = Type::Nested(CompositeType::Record(Record::new(point)));
+    let fields = vec![
+      ("name".to_string(), bool_type()),
+      ("pos".to_string(), point),
+    ];
+    let mut record = Record::new(fields.into_iter().collect());
+    record.required.insert("pos".to_string());
+    let nested = Schema::new(CompositeType::Record(record.clone()));
+    record.inline.insert("pos".to_string());
+    let schema = Schema::new(CompositeType::Record(record));
+    assert_ne!(nested.fingerprint(), schema.fingerprint());
+
+    let value =
+      serde_json::json!({ "name": true, "pos": { "x": true, "y": false } });
+    let co = encode(&schema, &value).unwrap();
+    co.validate(&schema).unwrap();
+    let bytes = co.to_bytes();
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+    crate::spec::verify_bytes(&schema, &bytes).unwrap();
+    assert!(crate::inspect::inspect(&schema, &bytes).error.is_none());
+    let estimate = crate::estimate_size(&schema, &value).unwrap();
+    assert_eq!(co.bit_len(), estimate.total());
+    // Three 2-bit markers with their data, saving the header and terminator
+    assert_eq!(3 * (2 + 1), co.bit_len());
+    assert_eq!(
+      co.bit_len() + 2 * 2,
+      encode(&nested, &value).unwrap().bit_len()
+    );
+
+    let path = ".pos.y".parse().unwrap();
+    assert_eq!(
+      Some(serde_json::json!(false)),
+      crate::decode_path(&schema, &bytes, &path).unwrap()
+    );
+    let path = ".pos".parse().unwrap();
+    assert_eq!(
+      Some(value["pos"].clone()),
+      crate::decode_path(&schema, &bytes, &path).unwrap()
+    );
+    let mut lazy = crate::LazyRecord::new(&schema, &bytes).unwrap();
+    let mut pos = lazy.record("pos").unwrap().unwrap();
+    assert_eq!(Some(serde_json::json!(true)), pos.get("x").unwrap());
+    assert_eq!(Some(serde_json::json!(true)), lazy.get("name").unwrap());
+
+    // An inline record with no fields present still decodes
+    let empty = serde_json::json!({ "pos": {} });
+    let co = encode(&schema, &empty).unwrap();
+    assert_eq!(0, co.bit_len());
+    assert_eq!(empty, crate::decode(&schema, &co.to_bytes()).unwrap());
+  }
+
+  /// A subscriber which logs the spans entered and exited, and the events
+  /// recorded within them.
+  #[cfg(feature = "tracing")]
+  struct Capture(std::sync::Arc<std::sync::Mutex<CaptureLog>>);
+
+  #[cfg(feature = "tracing")]
+  #[derive(Default)]
+  struct CaptureLog {
+    /// The label of each span by its id, less one.
+    spans: Vec<String>,
+    entered: Vec<u64>,
+    lines: Vec<String>,
+  }
+
+  /// Collects the fields of a span or event by name.
+  #[cfg(feature = "tracing")]
+  #[derive(Default)]
+  struct Fields(BTreeMap<&'static str, String>);
+
+  #[cfg(feature = "tracing")]
+  impl tracing::field::Visit for Fields {
+    fn record_debug(
+      &mut self,
+      field: &tracing::field::Field,
+      value: &dyn core::fmt::Debug,
+    ) {
+      self.0.insert(field.name(), format!("{:?}", value));
+    }
+  }
+
+  #[cfg(feature = "tracing")]
+  impl tracing::Subscriber for Capture {
+    fn enabled(&self, _: &tracing::Metadata) -> bool {
+      true
+    }
+
+    fn new_span(&self, span: &tracing::span::Attributes) -> tracing::span::Id {
+      let mut fields = Fields::default();
+      span.record(&mut fields);
+      let name = span.metadata().name().to_string();
+      let mut log = self.0.lock().unwrap();
+      log.spans.push(fields.0.remove("path").unwrap_or(name));
+      tracing::span::Id::from_u64(log.spans.len() as u64)
+    }
+
+    fn record(&self, _: &tracing::span::Id, _: &tracing::span::Record) {}
+
+    fn record_follows_from(
+      &self,
+      _: &tracing::span::Id,
+      _: &tracing::span::Id,
+    ) {
+    }
+
+    fn event(&self, event: &tracing::Event) {
+      let mut fields = Fields::default();
+      event.record(&mut fields);
+      let mut log = self.0.lock().unwrap();
+      let span = match log.entered.last() {
+        Some(id) => log.spans[*id as usize - 1].clone(),
+        None => String::new(),
+      };
+      let message = fields.0.remove("message").unwrap_or_default();
+      log.lines.push(format!("{} in {}", message, span));
+    }
+
+    fn enter(&self, span: &tracing::span::Id) {
+      let mut log = self.0.lock().unwrap();
+      log.entered.push(span.into_u64());
+      let line = format!("enter {}", log.spans[span.into_u64() as usize - 1]);
+      log.lines.push(line);
+    }
+
+    fn exit(&self, span: &tracing::span::Id) {
+      let mut log = self.0.lock().unwrap();
+      assert_eq!(Some(span.into_u64()), log.entered.pop());
+      let line = format!("exit {}", log.spans[span.into_u64() as usize - 1]);
+      log.lines.push(line);
+    }
+  }
+
+  #[test]
+  #[cfg(feature = "tracing")]
+  fn field_spans_cover_their_values() {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert(
+      "tags".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::PassThrough)))),
+    );
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+    let value = serde_json::json!({ "name": "a", "tags": ["b"] });
+
+    let log = std::sync::Arc::default();
+    let capture = Capture(std::sync::Arc::clone(&log));
+    tracing::subscriber::with_default(capture, || {
+      encode(&schema, &value).unwrap();
+    });
+
+    // The span of the list stays entered while its elements are encoded, so
+    // its duration includes theirs
+    let log = log.lock().unwrap();
+    assert_eq!(
+      vec![
+        "enter encode",
+        "enter .name",
+        "compressed in .name",
+        "exit .name",
+        "enter .tags",
+        "enter .tags[0]",
+        "compressed in .tags[0]",
+        "exit .tags[0]",
+        "exit .tags",
+        "encoded in encode",
+        "exit encode",
+      ],
+      log.lines
+    );
   }
 }
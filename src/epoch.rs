@@ -0,0 +1,223 @@
+//! The `epoch` module stores the `timestamp` values of a document relative to
+//! an epoch, so that documents whose times all fall within a narrow window
+//! only need a few bytes for each one.
+//!
+//! A schema's epoch is either a fixed timestamp declared in the schema file,
+//! or the first timestamp of each document, which is stored as is:
+//!
+//! ```yaml
+//! epoch: document
+//! schema:
+//!   record:
+//!     created: timestamp
+//!     updated: timestamp
+//! ```
+//!
+//! The first timestamp of a document is the first one found walking the value
+//! in the order of the schema: fields by identifier and list elements by
+//! index. Timestamps are made relative to the epoch before they are encoded
+//! and absolute again once the whole document is decoded, so only [`decode`]
+//! and the functions built on it, not those which decode part of an object,
+//! return absolute timestamps.
+//!
+//! [`decode`]: crate::decode
+
+use crate::prelude::*;
+use crate::schema::{CompositeType, Schema, Type};
+use anyhow::{anyhow, bail, Result};
+use core::convert::TryFrom;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+/// What the timestamps of documents are stored relative to.
+#[derive(Copy, Clone, Debug, Eq, PartialEq, Deserialize, Serialize)]
+#[serde(try_from = "EpochDef", into = "EpochDef")]
+pub enum Epoch {
+  /// The first timestamp of each document.
+  Document,
+  /// A fixed timestamp.
+  Fixed(i64),
+}
+
+/// An epoch as written in a schema file.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum EpochDef {
+  Fixed(i64),
+  Keyword(String),
+}
+
+impl TryFrom<EpochDef> for Epoch {
+  type Error = anyhow::Error;
+
+  fn try_from(def: EpochDef) -> Result<Self> {
+    match def {
+      EpochDef::Fixed(t) => Ok(Epoch::Fixed(t)),
+      EpochDef::Keyword(k) if k == "document" => Ok(Epoch::Document),
+      EpochDef::Keyword(k) => bail!("unknown epoch '{}'", k),
+    }
+  }
+}
+
+impl From<Epoch> for EpochDef {
+  fn from(epoch: Epoch) -> Self {
+    match epoch {
+      Epoch::Document => EpochDef::Keyword("document".to_string()),
+      Epoch::Fixed(t) => EpochDef::Fixed(t),
+    }
+  }
+}
+
+/// Whether `ty` is the `timestamp` type.
+fn is_timestamp(ty: &Type) -> bool {
+  matches!(ty, Type::Name(name) if name == "timestamp")
+}
+
+/// Calls `f` on every timestamp in `value` in the order of the schema.
+///
+/// Values which don't match their type are skipped, for the encoder to
+/// report.
+fn for_each_timestamp<F>(ty: &Type, value: &mut Value, f: &mut F) -> Result<()>
+where
+  F: FnMut(&mut Value) -> Result<()>,
+{
+  match (ty, value) {
+    (ty, value @ Value::Number(_)) if is_timestamp(ty) => f(value),
+    (Type::Nested(CompositeType::Record(record)), Value::Object(map)) => {
+      for (name, ty) in &record.fields {
+        if let Some(value) = map.get_mut(name) {
+          for_each_timestamp(ty, value, f)?;
+        }
+      }
+      Ok(())
+    }
+    (Type::Nested(CompositeType::List(list)), Value::Array(arr)) => {
+      for value in arr {
+        for_each_timestamp(&list.0, value, f)?;
+      }
+      Ok(())
+    }
+    _ => Ok(()),
+  }
+}
+
+/// Replaces a timestamp with `op` applied to it and the epoch.
+fn shift(
+  value: &mut Value,
+  epoch: i64,
+  op: fn(i64, i64) -> Option<i64>,
+) -> Result<()> {
+  if let Some(t) = value.as_i64() {
+    let shifted = op(t, epoch).ok_or_else(|| {
+      anyhow!("timestamp {} is too far from the epoch {}", t, epoch)
+    })?;
+    *value = Value::from(shifted);
+  }
+  Ok(())
+}
+
+/// Makes the timestamps of `value` relative to the epoch of `schema`.
+pub(crate) fn relativize(schema: &Schema, value: &mut Value) -> Result<()> {
+  shift_all(schema, value, i64::checked_sub)
+}
+
+/// Makes the timestamps of a decoded `value` absolute again.
+pub(crate) fn resolve(schema: &Schema, value: &mut Value) -> Result<()> {
+  shift_all(schema, value, i64::checked_add)
+}
+
+fn shift_all(
+  schema: &Schema,
+  value: &mut Value,
+  op: fn(i64, i64) -> Option<i64>,
+) -> Result<()> {
+  let mut epoch = match schema.epoch() {
+    Some(Epoch::Fixed(t)) => Some(t),
+    Some(Epoch::Document) => None,
+    None => return Ok(()),
+  };
+  for_each_timestamp(schema.root(), value, &mut |t| match epoch {
+    Some(epoch) => shift(t, epoch, op),
+    // The first timestamp is the epoch and is stored as is
+    None => {
+      epoch = t.as_i64();
+      Ok(())
+    }
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{List, Record};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema(epoch: Option<Epoch>) -> Schema {
+    let timestamp = || Type::Name("timestamp".to_string());
+    let mut fields = BTreeMap::new();
+    fields.insert("created".to_string(), timestamp());
+    fields.insert(
+      "edits".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(timestamp())))),
+    );
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+    match epoch {
+      Some(epoch) => schema.with_epoch(epoch),
+      None => schema,
+    }
+  }
+
+  fn document() -> Value {
+    json!({
+      "created": 1600000000000i64,
+      "edits": [1600000000250i64, 1600000001000i64, 1599999999990i64]
+    })
+  }
+
+  #[test]
+  fn document_epoch() {
+    let relative = schema(Some(Epoch::Document));
+    let mut value = document();
+    relativize(&relative, &mut value).unwrap();
+    assert_eq!(
+      json!({ "created": 1600000000000i64, "edits": [250, 1000, -10] }),
+      value
+    );
+
+    let bytes = crate::encode(&relative, &document()).unwrap().to_bytes();
+    let absolute = crate::encode(&schema(None), &document()).unwrap();
+    assert!(bytes.len() < absolute.to_bytes().len());
+    assert_eq!(document(), crate::decode(&relative, &bytes).unwrap());
+  }
+
+  #[test]
+  fn fixed_epoch() {
+    let fixed = schema(Some(Epoch::Fixed(1600000000000)));
+    let bytes = crate::encode(&fixed, &document()).unwrap().to_bytes();
+    assert_eq!(document(), crate::decode(&fixed, &bytes).unwrap());
+
+    let value = json!({ "created": -9223372036854775808i64 });
+    let e = crate::encode(&fixed, &value).unwrap_err();
+    assert_eq!(
+      format!(
+        "timestamp {} is too far from the epoch 1600000000000",
+        i64::MIN
+      ),
+      e.to_string()
+    );
+  }
+
+  #[test]
+  fn epochs_are_part_of_the_fingerprint() {
+    let fingerprints = [
+      schema(None).fingerprint(),
+      schema(Some(Epoch::Document)).fingerprint(),
+      schema(Some(Epoch::Fixed(0))).fingerprint(),
+      schema(Some(Epoch::Fixed(1))).fingerprint(),
+    ];
+    for (i, a) in fingerprints.iter().enumerate() {
+      assert!(!fingerprints[i + 1..].contains(a));
+    }
+  }
+}
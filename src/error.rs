@@ -0,0 +1,363 @@
+//! The `error` module defines the structured error type reported when a value
+//! cannot be encoded or an object cannot be decoded.
+//!
+//! Functions in this crate return `anyhow` errors. When the failure is caused
+//! by the data being encoded or decoded, the root cause of that error is an
+//! [`Error`] which can be recovered with `downcast_ref` and inspected:
+//!
+//! ```
+//! # use chii::schema::{CompositeType, Record, Schema, Type};
+//! # use std::collections::BTreeMap;
+//! # let mut fields = BTreeMap::new();
+//! # fields.insert("active".to_string(), Type::Name("bool".to_string()));
+//! # let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+//! let value = serde_json::json!({ "active": "yes" });
+//! let err = chii::encode(&schema, &value).unwrap_err();
+//! let err = err.downcast_ref::<chii::Error>().unwrap();
+//! assert_eq!("/active", err.pointer());
+//! ```
+
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use core::fmt;
+use serde_json::Value;
+
+/// An error caused by the data being encoded or decoded.
+#[derive(Debug)]
+pub enum Error {
+  /// A value doesn't have the type expected by the schema.
+  TypeMismatch {
+    path: Path,
+    expected: String,
+    value: Value,
+  },
+
+  /// A record contains a field which is not defined by the schema.
+  UnknownField { path: Path },
+
+  /// A record contains a field which the schema marks as deprecated.
+  DeprecatedField { path: Path },
+
+  /// A record is missing a field which the schema marks as required.
+  MissingField { path: Path },
+
+  /// A value has the right type but can't be encoded, such as a string which
+  /// is not one of an enum's variants.
+  InvalidValue {
+    path: Path,
+    value: Value,
+    reason: String,
+  },
+
+  /// The bytes being decoded are malformed or truncated.
+  Malformed {
+    path: Path,
+    offset: usize,
+    reason: String,
+  },
+
+  /// The input ends before the end of a value.
+  TruncatedInput {
+    path: Path,
+    offset: usize,
+    needed: usize,
+    remaining: usize,
+  },
+
+  /// A length section holds a value which is too large to be represented.
+  LengthOverflow { path: Path, offset: usize },
+
+  /// A configured resource limit was exceeded.
+  LimitExceeded { path: Path, limit: Limit },
+
+  /// Decoding would need more memory than the budget of `budget` bytes.
+  BudgetExceeded { path: Path, budget: usize },
+}
+
+/// A resource limit enforced while encoding or decoding.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Limit {
+  /// The maximum number of nested records and lists, including the root.
+  Depth(usize),
+  /// The maximum number of fields and list elements.
+  Elements(usize),
+  /// The maximum size in bytes of decoded values.
+  Size(usize),
+}
+
+impl fmt::Display for Limit {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Limit::Depth(n) => write!(f, "nesting depth exceeds the limit of {}", n),
+      Limit::Elements(n) => {
+        write!(f, "number of elements exceeds the limit of {}", n)
+      }
+      Limit::Size(n) => {
+        write!(f, "decoded size exceeds the limit of {} bytes", n)
+      }
+    }
+  }
+}
+
+impl Error {
+  /// The path of the value which caused this error.
+  pub fn path(&self) -> &Path {
+    match self {
+      Error::TypeMismatch { path, .. }
+      | Error::UnknownField { path }
+      | Error::DeprecatedField { path }
+      | Error::MissingField { path }
+      | Error::InvalidValue { path, .. }
+      | Error::Malformed { path, .. }
+      | Error::TruncatedInput { path, .. }
+      | Error::LengthOverflow { path, .. }
+      | Error::LimitExceeded { path, .. }
+      | Error::BudgetExceeded { path, .. } => path,
+    }
+  }
+
+  /// The path of the value which caused this error as a JSON pointer.
+  pub fn pointer(&self) -> String {
+    self.path().to_pointer()
+  }
+
+  /// The bit offset at which decoding failed, if this is a decode error.
+  pub fn offset(&self) -> Option<usize> {
+    match self {
+      Error::Malformed { offset, .. }
+      | Error::TruncatedInput { offset, .. }
+      | Error::LengthOverflow { offset, .. } => Some(*offset),
+      _ => None,
+    }
+  }
+
+  fn path_mut(&mut self) -> &mut Path {
+    match self {
+      Error::TypeMismatch { path, .. }
+      | Error::UnknownField { path }
+      | Error::DeprecatedField { path }
+      | Error::MissingField { path }
+      | Error::InvalidValue { path, .. }
+      | Error::Malformed { path, .. }
+      | Error::TruncatedInput { path, .. }
+      | Error::LengthOverflow { path, .. }
+      | Error::LimitExceeded { path, .. }
+      | Error::BudgetExceeded { path, .. } => path,
+    }
+  }
+
+  /// Converts an error raised while decoding data at bit `offset` into a
+  /// [`Error::Malformed`], unless it already is an [`Error`].
+  pub(crate) fn malformed(offset: usize, e: anyhow::Error) -> anyhow::Error {
+    if e.downcast_ref::<Error>().is_some() {
+      return e;
+    }
+    anyhow::Error::new(Error::Malformed {
+      path: Path::root(),
+      offset,
+      reason: format!("{:#}", e),
+    })
+  }
+
+  /// Converts an error raised while encoding `value` into an
+  /// [`Error::InvalidValue`], unless it already is an [`Error`].
+  pub(crate) fn invalid(value: &Value, e: anyhow::Error) -> anyhow::Error {
+    if e.downcast_ref::<Error>().is_some() {
+      return e;
+    }
+    anyhow::Error::new(Error::InvalidValue {
+      path: Path::root(),
+      value: value.clone(),
+      reason: format!("{:#}", e),
+    })
+  }
+}
+
+impl fmt::Display for Error {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      Error::TypeMismatch {
+        expected, value, ..
+      } => write!(f, "expected {} but found {}", expected, value)?,
+      Error::UnknownField { path } => match path.segments().last() {
+        Some(Segment::Field(name)) => write!(f, "unexpected field: {}", name)?,
+        _ => write!(f, "unexpected field")?,
+      },
+      Error::DeprecatedField { path } => match path.segments().last() {
+        Some(Segment::Field(name)) => write!(f, "deprecated field: {}", name)?,
+        _ => write!(f, "deprecated field")?,
+      },
+      Error::MissingField { path } => match path.segments().last() {
+        Some(Segment::Field(name)) => {
+          write!(f, "missing required field: {}", name)?
+        }
+        _ => write!(f, "missing required field")?,
+      },
+      Error::InvalidValue { value, reason, .. } => {
+        write!(f, "invalid value {}: {}", value, reason)?
+      }
+      Error::Malformed { offset, reason, .. } => {
+        write!(f, "{} at bit {}", reason, offset)?
+      }
+      Error::TruncatedInput {
+        offset,
+        needed,
+        remaining,
+        ..
+      } => write!(
+        f,
+        "unexpected end of input at bit {}: needed {} bits but only {} remain",
+        offset, needed, remaining
+      )?,
+      Error::LengthOverflow { offset, .. } => {
+        write!(f, "length overflow at bit {}", offset)?
+      }
+      Error::LimitExceeded { limit, .. } => write!(f, "{}", limit)?,
+      Error::BudgetExceeded { budget, .. } => write!(
+        f,
+        "decoded value exceeds the memory budget of {} bytes",
+        budget
+      )?,
+    }
+
+    if !self.path().segments().is_empty() {
+      write!(f, " (at {})", self.pointer())?;
+    }
+    Ok(())
+  }
+}
+
+impl core::error::Error for Error {}
+
+/// Attributes an error raised within the value at `segment` to that value.
+///
+/// If `e` is an [`Error`], `segment` is prepended to its path. Any other error
+/// is given a context message built from `action` instead.
+pub(crate) fn within(
+  e: anyhow::Error,
+  segment: Segment,
+  action: &str,
+) -> anyhow::Error {
+  match e.downcast::<Error>() {
+    Ok(mut err) => {
+      err.path_mut().0.insert(0, segment);
+      anyhow::Error::new(err)
+    }
+    Err(e) => match segment {
+      Segment::Field(name) => e.context(format!("when {} {}", action, name)),
+      Segment::Index(i) => {
+        e.context(format!("when {} list element {}", action, i))
+      }
+    },
+  }
+}
+
+/// Attributes an error raised within the value at `path` to that value.
+///
+/// This is the same as [`within`] but for a whole path of segments.
+pub(crate) fn within_path(
+  e: anyhow::Error,
+  path: &Path,
+  action: &str,
+) -> anyhow::Error {
+  match e.downcast::<Error>() {
+    Ok(mut err) => {
+      let inner = &mut err.path_mut().0;
+      inner.splice(0..0, path.segments().iter().cloned());
+      anyhow::Error::new(err)
+    }
+    Err(e) => e.context(format!("when {} {}", action, path)),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{CompositeType, List, Record, Schema, Type};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut course = BTreeMap::new();
+    course.insert(
+      "grade".to_string(),
+      Type::Enum {
+        variants: ["A", "B"].iter().map(|s| s.to_string()).collect(),
+        probabilities: None,
+      },
+    );
+    let mut student = BTreeMap::new();
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    Schema::new(CompositeType::Record(Record::new(student)))
+  }
+
+  fn encode_err(value: Value) -> Error {
+    let e = crate::encode(&schema(), &value).unwrap_err();
+    e.downcast::<Error>().unwrap()
+  }
+
+  #[test]
+  fn encode_error_paths() {
+    let e =
+      encode_err(json!({ "courses": [{ "grade": "A" }, { "grade": 3 }] }));
+    assert_eq!("/courses/1/grade", e.pointer());
+    assert!(matches!(e, Error::TypeMismatch { .. }));
+
+    let e = encode_err(json!({ "courses": [{ "grade": "D" }] }));
+    assert_eq!("/courses/0/grade", e.pointer());
+    assert!(matches!(e, Error::InvalidValue { .. }));
+
+    let e = encode_err(json!({ "courses": [{ "name": "Math" }] }));
+    assert_eq!("/courses/0/name", e.pointer());
+    assert!(matches!(e, Error::UnknownField { .. }));
+
+    let e = encode_err(json!({ "courses": {} }));
+    assert_eq!("/courses", e.pointer());
+    assert_eq!("expected array but found {} (at /courses)", e.to_string());
+  }
+
+  #[test]
+  fn encode_depth_limit() {
+    let value = json!({ "courses": [{ "grade": "A" }] });
+    let options = crate::EncodeOptions {
+      max_depth: 2,
+      ..Default::default()
+    };
+    let e = crate::encode_with(&schema(), &value, &options).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert_eq!("/courses/0", e.pointer());
+    assert!(matches!(
+      e,
+      Error::LimitExceeded {
+        limit: Limit::Depth(2),
+        ..
+      }
+    ));
+
+    let options = crate::EncodeOptions {
+      max_depth: 3,
+      ..Default::default()
+    };
+    assert!(crate::encode_with(&schema(), &value, &options).is_ok());
+  }
+
+  #[test]
+  fn decode_error_offset() {
+    let schema = schema();
+    let value = json!({ "courses": [{ "grade": "A" }, { "grade": "B" }] });
+    let bits: crate::bit::BitVec =
+      crate::encode(&schema, &value).unwrap().into();
+    let bytes = bits.to_bytes();
+
+    // The list's length starts right after the 1 bit field marker
+    let e = crate::decode(&schema, &bytes[..1]).unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert_eq!(Some(1), e.offset());
+    assert_eq!("/courses", e.pointer());
+  }
+}
@@ -0,0 +1,328 @@
+//! The `estimate` module computes how large a value will be once encoded
+//! without actually encoding it.
+
+use alloc::borrow::Cow;
+use core::convert::TryFrom;
+
+use anyhow::Result;
+use serde_json::{Map, Value};
+
+use crate::comp::{self, EncodedWidth};
+use crate::data::{Layout::Packed, LengthEncoding};
+use crate::encode::{
+  get_compressor_for_type, prefers_sparse, type_mismatch, union_alternative,
+  DEFAULT_MAX_DEPTH,
+};
+use crate::error::{within, Error, Limit};
+use crate::math;
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use crate::schema::{CompositeType, Record, Schema, Type};
+
+/// The number of bits a value takes up once encoded, broken down by the kind
+/// of section they belong to.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BitEstimate {
+  /// Bits used by the field markers of headers, data blocks and terminators,
+  /// and by the tags of union elements.
+  pub markers: usize,
+  /// Bits used by the length sections of lists and variable width data.
+  pub lengths: usize,
+  /// Bits used by the encoded data itself.
+  pub data: usize,
+}
+
+impl BitEstimate {
+  /// The total number of bits.
+  pub fn total(&self) -> usize {
+    self.markers + self.lengths + self.data
+  }
+
+  /// The total number of bytes, as written out with the last byte padded.
+  pub fn bytes(&self) -> usize {
+    math::div_ceil(self.total(), 8)
+  }
+
+  /// Adds a composite value which is nested under a field marker `marker`
+  /// bits wide, or is the root if `marker` is `None`. The length of a
+  /// `nullable` list is one more than its number of elements.
+  fn composite(
+    &mut self,
+    ct: &CompositeType,
+    marker: Option<usize>,
+    value: &Value,
+    nullable: bool,
+    depth: usize,
+    schema: &Schema,
+  ) -> Result<()> {
+    if depth >= DEFAULT_MAX_DEPTH {
+      return Err(
+        Error::LimitExceeded {
+          path: Path::root(),
+          limit: Limit::Depth(DEFAULT_MAX_DEPTH),
+        }
+        .into(),
+      );
+    }
+
+    match ct {
+      CompositeType::Record(record) => {
+        let map = value
+          .as_object()
+          .ok_or_else(|| type_mismatch("object", value))?;
+
+        // Sparse records have a layout flag, and may mark their fields with
+        // the differences between their markers ending with a terminator,
+        // which are counted up front
+        let field_map = record.field_map();
+        let ids = || map.keys().filter_map(|k| field_map.get(k.as_str()));
+        let sparse = record.sparse
+          && prefers_sparse(record, ids().copied(), marker.is_some(), Packed);
+        self.markers += record.sparse as usize;
+        let width = if sparse { 0 } else { record.field_width() };
+        if sparse {
+          let mut markers: Vec<_> = ids().map(|id| id.index() + 1).collect();
+          markers.sort_unstable();
+          let mut prev = 0;
+          for m in markers {
+            self.markers += LengthEncoding::Vie.bit_len(m - prev);
+            prev = m;
+          }
+          self.markers += LengthEncoding::Vie.bit_len(0);
+        }
+
+        // Nested records have a header and a terminator
+        if let Some(marker) = marker {
+          self.markers += marker + width;
+        }
+
+        self.fields(record, width, map, depth, schema)?;
+      }
+
+      CompositeType::List(list) => {
+        let arr = value
+          .as_array()
+          .ok_or_else(|| type_mismatch("array", value))?;
+
+        // Lists always have a header
+        self.markers += marker.unwrap_or(0);
+        self.lengths += schema.lengths().bit_len(arr.len() + nullable as usize);
+
+        // Chunks start with the number of bits their elements take up
+        let chunked = schema.chunks().filter(|size| arr.len() > *size);
+        let size = chunked.unwrap_or(arr.len()).max(1);
+        // Sync flags precede the chunks of a root list, or its elements if it
+        // isn't chunked, though the markers they may hold aren't counted
+        if marker.is_none() && schema.sync().is_some() {
+          self.markers += match chunked {
+            Some(_) => math::div_ceil(arr.len(), size),
+            None => arr.len(),
+          };
+        }
+        for (c, chunk) in arr.chunks(size).enumerate() {
+          let start = self.total();
+          for (j, v) in chunk.iter().enumerate() {
+            self.element_of(&list.0, v, depth, schema).map_err(|e| {
+              within(e, Segment::Index(c * size + j), "estimating")
+            })?;
+          }
+          if chunked.is_some() {
+            self.lengths += schema.lengths().bit_len(self.total() - start);
+          }
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Adds the fields of a record whose markers are `width` bits wide. The
+  /// fields of inline records are added with the same markers and no header
+  /// or terminator.
+  fn fields(
+    &mut self,
+    record: &Record,
+    width: usize,
+    map: &Map<String, Value>,
+    depth: usize,
+    schema: &Schema,
+  ) -> Result<()> {
+    for (k, v) in map {
+      let segment = || Segment::Field(k.clone());
+      let ty = record.fields.get(k).ok_or_else(|| {
+        let e = Error::UnknownField { path: Path::root() };
+        within(e.into(), segment(), "estimating")
+      })?;
+      let result = match (record.inline_record(k), v.as_object()) {
+        (Some(inline), Some(map)) => {
+          self.fields(inline, width, map, depth, schema)
+        }
+        (Some(_), None) => Err(type_mismatch("object", v).into()),
+        (None, _) => {
+          let nullable = record.is_nullable(k);
+          self.value(ty, Some(width), v, nullable, depth, schema)
+        }
+      };
+      result.map_err(|e| within(e, segment(), "estimating"))?;
+    }
+    Ok(())
+  }
+
+  /// Adds a list element, along with its tag if the list's element type `ty`
+  /// is a union.
+  fn element_of(
+    &mut self,
+    ty: &Type,
+    value: &Value,
+    depth: usize,
+    schema: &Schema,
+  ) -> Result<()> {
+    let ty = match ty {
+      Type::Union { alternatives } => {
+        self.markers += math::required_bit_width(alternatives.len());
+        union_alternative(alternatives, value)?.1
+      }
+      ty => ty,
+    };
+    self.value(ty, None, value, false, depth, schema)
+  }
+
+  /// Adds a record field with a marker `marker` bits wide, or a list element
+  /// if `marker` is `None`.
+  fn value(
+    &mut self,
+    ty: &Type,
+    marker: Option<usize>,
+    value: &Value,
+    nullable: bool,
+    depth: usize,
+    schema: &Schema,
+  ) -> Result<()> {
+    let marker = marker.unwrap_or(0);
+    match (ty, value) {
+      // A null list is just a header with a length of zero
+      (Type::Nested(CompositeType::List(_)), Value::Null) if nullable => {
+        self.markers += marker;
+        self.lengths += schema.lengths().bit_len(0);
+        Ok(())
+      }
+      (Type::Nested(ct), _) => {
+        self.composite(ct, Some(marker), value, nullable, depth + 1, schema)
+      }
+      (ty, _) => {
+        self.markers += marker;
+        self.element(ty, value, schema)
+      }
+    }
+  }
+
+  /// Adds the data, and length if it has one, of a non-nested value.
+  fn element(
+    &mut self,
+    ty: &Type,
+    value: &Value,
+    schema: &Schema,
+  ) -> Result<()> {
+    let compressor = get_compressor_for_type(ty)?;
+    let v = comp::Value::try_from(value)
+      .map_err(|_| type_mismatch("a primitive value", value))?;
+    let bits = compressor
+      .compressed_len(v)
+      .map_err(|e| Error::invalid(value, e))?;
+
+    if compressor.encoded_width() == EncodedWidth::Variable {
+      self.lengths += schema.lengths().bit_len(bits);
+    }
+    self.data += bits;
+    Ok(())
+  }
+}
+
+/// Computes the size of `value` once encoded using `schema`, without
+/// allocating any buffers for the encoded data.
+///
+/// For any value which can be encoded with the default [`EncodeOptions`] the
+/// estimate is exact, except that the sync markers of a schema with them
+/// aren't counted. Values which can't be encoded may still produce an
+/// estimate as fixed width data isn't validated.
+///
+/// [`EncodeOptions`]: crate::EncodeOptions
+pub fn estimate_size(schema: &Schema, value: &Value) -> Result<BitEstimate> {
+  let mut value = Cow::Borrowed(value);
+  if schema.epoch().is_some() {
+    crate::epoch::relativize(schema, value.to_mut())?;
+  }
+  let value = &*value;
+  let mut estimate = BitEstimate::default();
+  match schema.root() {
+    Type::Nested(ct) => {
+      estimate.composite(ct, None, value, false, 0, schema)?
+    }
+    ty => estimate.element(ty, value, schema)?,
+  }
+  Ok(estimate)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{List, Record};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    course.insert(
+      "grade".to_string(),
+      Type::Enum {
+        variants: ["A", "B", "C"].iter().map(|s| s.to_string()).collect(),
+        probabilities: None,
+      },
+    );
+
+    let mut student = BTreeMap::new();
+    student.insert("name".to_string(), Type::PassThrough);
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    Schema::new(CompositeType::Record(Record::new(student)))
+  }
+
+  #[test]
+  fn estimate_matches_encoded_size() {
+    let schema = schema();
+    let value = json!({
+      "name": "Jeremy",
+      "active": true,
+      "courses": [{ "name": "Math", "grade": "A" }, { "grade": "C" }, {}]
+    });
+
+    let estimate = estimate_size(&schema, &value).unwrap();
+    let co = crate::encode(&schema, &value).unwrap();
+    assert_eq!(co.bit_len(), estimate.total());
+    assert_eq!(co.to_bytes().len(), estimate.bytes());
+    // One bool, the bytes of two strings and two 2 bit enum variants
+    assert_eq!(1 + (6 + 4) * 8 + 2 * 2, estimate.data);
+
+    let scalar = Schema::new_scalar(Type::PassThrough);
+    let estimate = estimate_size(&scalar, &json!("hello")).unwrap();
+    assert_eq!(
+      crate::encode(&scalar, &json!("hello")).unwrap().bit_len(),
+      48
+    );
+    assert_eq!(48, estimate.total());
+  }
+
+  #[test]
+  fn estimate_reports_errors() {
+    let e = estimate_size(&schema(), &json!({ "courses": [{ "room": 1 }] }))
+      .unwrap_err();
+    let e = e.downcast::<Error>().unwrap();
+    assert_eq!("/courses/0/room", e.pointer());
+    assert!(matches!(e, Error::UnknownField { .. }));
+  }
+}
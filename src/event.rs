@@ -0,0 +1,1072 @@
+//! The `event` module implements an event-based (SAX-style) decoder for
+//! compressed objects.
+//!
+//! Instead of building a complete JSON tree, [`decode_events`] returns an
+//! iterator which yields an [`Event`] for each structural element of the
+//! object as it is read. Only the path from the root to the current value is
+//! kept in memory, making it possible to process very large objects.
+//!
+//! Decoding can also be paused and picked up again later, even by another
+//! process. A [`Checkpoint`] taken from [`Events`] holds the bit offset which
+//! was reached along with the state of every record and list which is still
+//! open, and [`resume_events`] continues decoding from it.
+
+use crate::bit::BitReader;
+use crate::decode::{
+  find_sync, read_chunk_header, read_length, read_list_length, read_sync,
+  read_tag, read_value, reader, split_dictionaries, DecodeOptions, FieldLayout,
+  Fields, FieldsState,
+};
+use crate::dictionary::Resolver;
+use crate::error::{Error, Limit};
+use crate::path::Path;
+use crate::prelude::*;
+use crate::schema::{CompositeType, List, Schema, Type};
+use crate::vie::CodePoint;
+use anyhow::{anyhow, bail, Result};
+use core::mem::size_of;
+use serde_json::Value;
+
+/// A structural element of a compressed object.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event<'s> {
+  /// The start of a record.
+  StartRecord,
+  /// The name of the next field in the current record. It is followed by
+  /// either a `Value` event or the events of a nested record or list.
+  Field(&'s str),
+  /// A non-nested field or list element value.
+  Value(Value),
+  /// The end of a record.
+  EndRecord,
+  /// The start of a list with a given number of elements.
+  StartList(usize),
+  /// The end of a list.
+  EndList,
+}
+
+/// Decodes a compressed object into a stream of events.
+///
+/// The values of [dictionary](crate::dictionary) fields are looked up in the
+/// object's dictionaries before they are yielded. The returned iterator stops
+/// after the first error.
+pub fn decode_events<'s, 'b>(
+  schema: &'s Schema,
+  bytes: &'b [u8],
+) -> Events<'s, 'b> {
+  decode_events_with(schema, bytes, DecodeOptions::default())
+}
+
+/// Decodes a compressed object into a stream of events, enforcing the limits
+/// in `options`.
+pub fn decode_events_with<'s, 'b>(
+  schema: &'s Schema,
+  bytes: &'b [u8],
+  options: DecodeOptions,
+) -> Events<'s, 'b> {
+  let mut decoder = EventDecoder::for_schema(schema, options);
+  let (bytes, error) = match split_dictionaries(schema, bytes) {
+    Ok((bytes, dictionaries)) => {
+      decoder.set_resolver(Resolver::new(schema, dictionaries));
+      (bytes, None)
+    }
+    Err(e) => (bytes, Some(e)),
+  };
+  Events {
+    r: reader(schema, bytes, &options),
+    decoder,
+    schema: Some(schema),
+    error,
+    done: false,
+  }
+}
+
+/// Resumes decoding a compressed object into a stream of events from a
+/// `checkpoint` taken while decoding the same object.
+///
+/// Fails if the checkpoint was taken with a different schema or doesn't
+/// match its structure.
+pub fn resume_events<'s, 'b>(
+  schema: &'s Schema,
+  bytes: &'b [u8],
+  checkpoint: &Checkpoint,
+) -> Result<Events<'s, 'b>> {
+  resume_events_with(schema, bytes, checkpoint, DecodeOptions::default())
+}
+
+/// Resumes decoding a compressed object into a stream of events from a
+/// `checkpoint`, enforcing the limits in `options`.
+///
+/// `options` must describe the object in the same way as when the
+/// checkpoint was taken. The elements and bytes decoded before the
+/// checkpoint count towards the limits.
+pub fn resume_events_with<'s, 'b>(
+  schema: &'s Schema,
+  bytes: &'b [u8],
+  checkpoint: &Checkpoint,
+  options: DecodeOptions,
+) -> Result<Events<'s, 'b>> {
+  if checkpoint.fingerprint != schema.fingerprint() {
+    bail!("checkpoint was taken with a different schema");
+  }
+  let (bytes, dictionaries) = split_dictionaries(schema, bytes)?;
+  let mut r = reader(schema, bytes, &options);
+  r.seek(checkpoint.offset)
+    .ok_or_else(|| anyhow!("checkpoint offset is out of bounds"))?;
+  let mut decoder = EventDecoder::restore(schema, checkpoint, options)?;
+  decoder.set_resolver(Resolver::new(schema, dictionaries));
+  Ok(Events {
+    r,
+    decoder,
+    schema: Some(schema),
+    error: None,
+    done: false,
+  })
+}
+
+/// A composite type which is currently being decoded.
+enum Frame<'s> {
+  Record(Fields<'s>),
+  /// A list of `len` elements, of which `next` is read next.
+  List {
+    list: &'s List,
+    len: usize,
+    next: usize,
+  },
+}
+
+/// What the event after a field's name is read from.
+enum Pending<'s> {
+  /// A value of a type, along with whether it is nullable.
+  Value(&'s Type, bool),
+  /// The fields of an inline record.
+  Inline(Fields<'s>),
+}
+
+/// An iterator over the events of a compressed object.
+///
+/// This `struct` is created by [`decode_events`].
+pub struct Events<'s, 'b> {
+  r: BitReader<'b>,
+  decoder: EventDecoder<'s>,
+  /// The schema of the object if the events are those of its root.
+  schema: Option<&'s Schema>,
+  /// An error found before decoding started, which is yielded first.
+  error: Option<anyhow::Error>,
+  done: bool,
+}
+
+impl<'s, 'b> Events<'s, 'b> {
+  /// Constructs an iterator over the events of a value of type `ct` starting
+  /// at the current position of `r`.
+  pub(crate) fn new(
+    r: BitReader<'b>,
+    ct: &'s CompositeType,
+    root: bool,
+    options: DecodeOptions,
+  ) -> Self {
+    Events {
+      r,
+      decoder: EventDecoder::new(ct, root, options),
+      schema: None,
+      error: None,
+      done: false,
+    }
+  }
+
+  /// Constructs an iterator over the events of an inline record whose fields
+  /// are read by `fields` starting at the current position of `r`.
+  pub(crate) fn inline(
+    r: BitReader<'b>,
+    fields: Fields<'s>,
+    options: DecodeOptions,
+  ) -> Self {
+    Events {
+      r,
+      decoder: EventDecoder::inline(fields, options),
+      schema: None,
+      error: None,
+      done: false,
+    }
+  }
+
+  /// Continues past values whose data is damaged, yielding `null` in their
+  /// place. The error for each such value is available from
+  /// [`take_damaged`](Events::take_damaged) right after it is yielded.
+  pub(crate) fn lenient(&mut self) {
+    self.decoder.lenient = true;
+  }
+
+  /// Takes the error for the value just yielded if its data was damaged.
+  pub(crate) fn take_damaged(&mut self) -> Option<anyhow::Error> {
+    self.decoder.damaged.take()
+  }
+
+  /// The current bit position in the input.
+  pub(crate) fn position(&self) -> usize {
+    self.r.position()
+  }
+
+  /// Continues after damage to the structure of a root list by moving to
+  /// the next sync marker which is for element `from` or later. Returns the
+  /// element decoding continues at, or `None` if there is no such marker.
+  pub(crate) fn resync(&mut self, from: usize) -> Option<usize> {
+    let next = self.decoder.resync(from, &mut self.r)?;
+    self.done = false;
+    Some(next)
+  }
+
+  /// Saves the state of decoding, from which the events after the last one
+  /// yielded can be decoded with [`resume_events`].
+  pub fn checkpoint(&self) -> Checkpoint {
+    let fingerprint = self.schema.map_or(0, Schema::fingerprint);
+    self.decoder.save(fingerprint, self.r.position())
+  }
+}
+
+/// The decoding state behind [`Events`].
+///
+/// The state does not borrow the input, instead the reader is passed to each
+/// call of [`next_event`](EventDecoder::next_event). This allows decoding to
+/// be suspended and resumed from a saved bit position.
+pub(crate) struct EventDecoder<'s> {
+  /// The outermost type and whether it is the root object, until decoding
+  /// has started.
+  outer: Option<(&'s CompositeType, bool)>,
+  stack: Vec<Frame<'s>>,
+  /// What to read after the name of a field was just yielded.
+  pending: Option<Pending<'s>>,
+  options: DecodeOptions,
+  /// The number of values decoded so far.
+  elements: usize,
+  /// The approximate size in bytes of the values decoded so far.
+  size: usize,
+  /// The number of bytes the values decoded so far take up in memory.
+  memory: usize,
+  /// Whether to yield `null` for values whose data is damaged instead of
+  /// failing.
+  lenient: bool,
+  /// The error for the last value yielded in place of a damaged one.
+  damaged: Option<anyhow::Error>,
+  /// Whether the outermost frame on the stack is a root list, whose elements
+  /// may be preceded by sync blocks.
+  root: bool,
+  /// The bit position at which the root list's last element started.
+  mark: usize,
+  /// Looks up the values of dictionary fields, if they are to be looked up
+  /// as they are decoded.
+  resolver: Option<Resolver<'s>>,
+}
+
+impl<'s> EventDecoder<'s> {
+  /// Constructs a decoder for the events of a value of type `ct`.
+  pub(crate) fn new(
+    ct: &'s CompositeType,
+    root: bool,
+    options: DecodeOptions,
+  ) -> Self {
+    EventDecoder {
+      outer: Some((ct, root)),
+      ..EventDecoder::empty(options)
+    }
+  }
+
+  /// Constructs a decoder for the single event of a non-nested value of type
+  /// `ty`.
+  pub(crate) fn scalar(ty: &'s Type, options: DecodeOptions) -> Self {
+    EventDecoder {
+      pending: Some(Pending::Value(ty, false)),
+      ..EventDecoder::empty(options)
+    }
+  }
+
+  /// Constructs a decoder for the events of an inline record whose fields
+  /// are read by `fields`.
+  fn inline(fields: Fields<'s>, options: DecodeOptions) -> Self {
+    EventDecoder {
+      pending: Some(Pending::Inline(fields)),
+      ..EventDecoder::empty(options)
+    }
+  }
+
+  fn empty(options: DecodeOptions) -> Self {
+    EventDecoder {
+      outer: None,
+      stack: Vec::new(),
+      pending: None,
+      options,
+      elements: 0,
+      size: 0,
+      memory: 0,
+      lenient: false,
+      damaged: None,
+      root: false,
+      mark: 0,
+      resolver: None,
+    }
+  }
+
+  /// Looks up the values of dictionary fields using `resolver` as they are
+  /// decoded.
+  pub(crate) fn set_resolver(&mut self, resolver: Resolver<'s>) {
+    self.resolver = Some(resolver);
+  }
+
+  /// Constructs a decoder for the root object of `schema`.
+  pub(crate) fn for_schema(schema: &'s Schema, options: DecodeOptions) -> Self {
+    match schema.root() {
+      Type::Nested(ct) => EventDecoder::new(ct, true, options),
+      ty => EventDecoder::scalar(ty, options),
+    }
+  }
+
+  /// Reads the next event from `r`, returning `None` once the object has
+  /// been fully decoded.
+  pub(crate) fn next_event(
+    &mut self,
+    r: &mut BitReader,
+  ) -> Result<Option<Event<'s>>> {
+    if let Some((ct, root)) = self.outer.take() {
+      self.charge(size_of::<Value>())?;
+      return self.start(ct, root, r).map(Some);
+    }
+
+    match self.pending.take() {
+      Some(Pending::Value(ty, nullable)) => {
+        return self.value(ty, nullable, r).map(Some);
+      }
+      Some(Pending::Inline(fields)) => {
+        self.count_element()?;
+        self.charge(size_of::<Value>())?;
+        self.check_depth()?;
+        self.stack.push(Frame::Record(fields));
+        return Ok(Some(Event::StartRecord));
+      }
+      None => {}
+    }
+
+    if let (true, [Frame::List { len, next, .. }]) =
+      (self.root, self.stack.as_slice())
+    {
+      if next < len {
+        let (len, next) = (*len, *next);
+        self.mark = r.position();
+        read_sync(next, len, r)?;
+      }
+    }
+
+    let event = match self.stack.last_mut() {
+      None => None,
+      Some(Frame::Record(fields)) => match fields.next(r)? {
+        Some((name, ty)) => {
+          // The name is checked against the budget along with the value
+          self.memory = self.memory.saturating_add(key_memory(name));
+          self.pending = Some(match fields.inline(name) {
+            Some(inline) => Pending::Inline(inline),
+            None => Pending::Value(ty, fields.record.is_nullable(name)),
+          });
+          Some(Event::Field(name))
+        }
+        None => {
+          self.stack.pop();
+          Some(Event::EndRecord)
+        }
+      },
+      Some(Frame::List { len, next, .. }) if next == len => {
+        self.stack.pop();
+        Some(Event::EndList)
+      }
+      Some(Frame::List { list, len, next }) => {
+        read_chunk_header(*next, *len, r)?;
+        *next += 1;
+        let ty = read_tag(list.0.as_ref(), r)?;
+        Some(self.value(ty, false, r)?)
+      }
+    };
+    Ok(event)
+  }
+
+  /// Yields the event for the start of a field or element of type `ty`,
+  /// which is `null` if it is a `nullable` list which is null.
+  fn value(
+    &mut self,
+    ty: &'s Type,
+    nullable: bool,
+    r: &mut BitReader,
+  ) -> Result<Event<'s>> {
+    self.count_element()?;
+    self.charge(size_of::<Value>())?;
+    match ty {
+      Type::Nested(CompositeType::List(list)) if nullable => {
+        match read_list_length(r, true)? {
+          Some(len) => self.start_list(list, len),
+          None => Ok(Event::Value(Value::Null)),
+        }
+      }
+      Type::Nested(ct) => self.start(ct, false, r),
+      _ => {
+        let mut value = match read_value(ty, r)? {
+          Err(e) if self.lenient => {
+            self.damaged = Some(e);
+            Value::Null
+          }
+          value => value?,
+        };
+        if let Some(resolver) = &self.resolver {
+          resolver.resolve(ty, &mut value)?;
+        }
+        self.size += approximate_size(&value);
+        if self.size > self.options.max_size {
+          return Err(limit_exceeded(Limit::Size(self.options.max_size)));
+        }
+        if let Value::String(s) = &value {
+          self.charge(s.len())?;
+        }
+        Ok(Event::Value(value))
+      }
+    }
+  }
+
+  /// Pushes a new composite type onto the stack.
+  fn start(
+    &mut self,
+    ct: &'s CompositeType,
+    root: bool,
+    r: &mut BitReader,
+  ) -> Result<Event<'s>> {
+    match ct {
+      CompositeType::Record(record) => {
+        self.check_depth()?;
+        self.stack.push(Frame::Record(Fields::new(record, root)));
+        Ok(Event::StartRecord)
+      }
+      CompositeType::List(list) => {
+        let len = read_length(r)?;
+        self.root = root;
+        self.start_list(list, len)
+      }
+    }
+  }
+
+  /// Pushes a list whose header holding `len` elements has been read onto the
+  /// stack.
+  fn start_list(&mut self, list: &'s List, len: usize) -> Result<Event<'s>> {
+    self.check_depth()?;
+    self.stack.push(Frame::List { list, len, next: 0 });
+    Ok(Event::StartList(len))
+  }
+
+  /// Saves the state of this decoder, which has read up to bit `offset` of
+  /// an object encoded with a schema with a given `fingerprint`.
+  fn save(&self, fingerprint: u64, offset: usize) -> Checkpoint {
+    let mut frames: Vec<(Step, SavedFrame)> = Vec::new();
+    for (i, frame) in self.stack.iter().enumerate() {
+      let step = match i.checked_sub(1).map(|i| &self.stack[i]) {
+        None => Step::Root,
+        Some(Frame::Record(fields)) => {
+          let (name, _) = fields
+            .record
+            .fields
+            .iter()
+            .find(|(_, ty)| holds(ty, frame))
+            .expect("a nested record or list is held by a field");
+          Step::Field(name.clone())
+        }
+        Some(Frame::List { list, .. }) => match list.0.as_ref() {
+          Type::Union { alternatives } => Step::Element(
+            alternatives
+              .iter()
+              .position(|ty| holds(ty, frame))
+              .expect("a nested record or list is one of the union's types"),
+          ),
+          _ => Step::Element(0),
+        },
+      };
+      let saved = match frame {
+        Frame::Record(fields) => SavedFrame::Record(fields.save()),
+        Frame::List { len, next, .. } => SavedFrame::List {
+          len: *len,
+          next: *next,
+        },
+      };
+      frames.push((step, saved));
+    }
+
+    // A field's value is pending once its name has been yielded
+    let pending = match (&self.pending, self.stack.last()) {
+      (Some(pending), Some(Frame::Record(fields))) => {
+        let (name, _) = fields
+          .record
+          .fields
+          .iter()
+          .find(|(_, ty)| match pending {
+            Pending::Value(pending, _) => core::ptr::eq(*ty, *pending),
+            Pending::Inline(inline) => match ty {
+              Type::Nested(CompositeType::Record(record)) => {
+                core::ptr::eq(record, inline.record)
+              }
+              _ => false,
+            },
+          })
+          .expect("a pending value is held by a field");
+        Some(name.clone())
+      }
+      _ => None,
+    };
+
+    // Decoding a scalar root starts with its value pending
+    let started = self.outer.is_none()
+      && !(self.stack.is_empty() && self.pending.is_some());
+    Checkpoint {
+      fingerprint,
+      offset,
+      started,
+      elements: self.elements,
+      size: self.size,
+      memory: self.memory,
+      frames,
+      pending,
+    }
+  }
+
+  /// Restores a decoder for the root object of `schema` from a
+  /// `checkpoint`.
+  fn restore(
+    schema: &'s Schema,
+    checkpoint: &Checkpoint,
+    options: DecodeOptions,
+  ) -> Result<Self> {
+    if !checkpoint.started {
+      return Ok(EventDecoder::for_schema(schema, options));
+    }
+    let mismatch = || anyhow!("checkpoint does not match the schema");
+
+    let mut decoder = EventDecoder::empty(options);
+    decoder.elements = checkpoint.elements;
+    decoder.size = checkpoint.size;
+    decoder.memory = checkpoint.memory;
+    for (step, saved) in &checkpoint.frames {
+      let ty = match (decoder.stack.last(), step) {
+        (None, Step::Root) => schema.root(),
+        (Some(Frame::Record(fields)), Step::Field(name)) => {
+          fields.record.fields.get(name).ok_or_else(mismatch)?
+        }
+        (Some(Frame::List { list, .. }), Step::Element(tag)) => {
+          match list.0.as_ref() {
+            Type::Union { alternatives } => {
+              alternatives.get(*tag).ok_or_else(mismatch)?
+            }
+            ty if *tag == 0 => ty,
+            _ => return Err(mismatch()),
+          }
+        }
+        _ => return Err(mismatch()),
+      };
+      let frame = match (ty, saved) {
+        (
+          Type::Nested(CompositeType::Record(record)),
+          SavedFrame::Record(state),
+        ) => Frame::Record(Fields::restore(record, state)?),
+        (
+          Type::Nested(CompositeType::List(list)),
+          SavedFrame::List { len, next },
+        ) if next <= len => Frame::List {
+          list,
+          len: *len,
+          next: *next,
+        },
+        _ => return Err(mismatch()),
+      };
+      decoder.stack.push(frame);
+    }
+    decoder.root = matches!(decoder.stack.first(), Some(Frame::List { .. }));
+
+    decoder.pending = match (&checkpoint.pending, decoder.stack.last()) {
+      (None, _) => None,
+      (Some(name), Some(Frame::Record(fields))) => {
+        let (name, ty) = fields
+          .record
+          .fields
+          .get_key_value(name.as_str())
+          .ok_or_else(mismatch)?;
+        Some(match fields.inline(name) {
+          Some(inline) => Pending::Inline(inline),
+          None => Pending::Value(ty, fields.record.is_nullable(name)),
+        })
+      }
+      (Some(_), _) => return Err(mismatch()),
+    };
+    Ok(decoder)
+  }
+
+  /// Moves `r` to the first sync marker after the start of the root list's
+  /// last element which is for element `from` or later, leaving only the
+  /// root list on the stack. Returns the element the marker is for, or
+  /// `None` if there is no such marker.
+  fn resync(&mut self, from: usize, r: &mut BitReader) -> Option<usize> {
+    let len = match self.stack.first() {
+      Some(Frame::List { len, .. }) if self.root && r.sync() => *len,
+      _ => return None,
+    };
+    let next = find_sync(from, len, self.mark + 1, r)?;
+    self.stack.truncate(1);
+    if let Some(Frame::List { next: n, .. }) = self.stack.first_mut() {
+      *n = next;
+    }
+    self.pending = None;
+    self.damaged = None;
+    Some(next)
+  }
+
+  fn count_element(&mut self) -> Result<()> {
+    self.elements += 1;
+    if self.elements > self.options.max_elements {
+      return Err(limit_exceeded(Limit::Elements(self.options.max_elements)));
+    }
+    Ok(())
+  }
+
+  /// Accounts for `bytes` more of memory taken up by the decoded value.
+  fn charge(&mut self, bytes: usize) -> Result<()> {
+    self.memory = self.memory.saturating_add(bytes);
+    if self.memory > self.options.max_memory {
+      return Err(
+        Error::BudgetExceeded {
+          path: Path::root(),
+          budget: self.options.max_memory,
+        }
+        .into(),
+      );
+    }
+    Ok(())
+  }
+
+  fn check_depth(&self) -> Result<()> {
+    if self.stack.len() >= self.options.max_depth {
+      return Err(limit_exceeded(Limit::Depth(self.options.max_depth)));
+    }
+    Ok(())
+  }
+}
+
+/// Returns `true` if `ty` is the very record or list type of `frame`, rather
+/// than just an equal one.
+fn holds(ty: &Type, frame: &Frame) -> bool {
+  match (ty, frame) {
+    (Type::Nested(CompositeType::Record(record)), Frame::Record(fields)) => {
+      core::ptr::eq(record, fields.record)
+    }
+    (Type::Nested(CompositeType::List(l)), Frame::List { list, .. }) => {
+      core::ptr::eq(l, *list)
+    }
+    _ => false,
+  }
+}
+
+/// The state of decoding a compressed object into events, from which
+/// decoding can be resumed later with [`resume_events`].
+///
+/// A checkpoint doesn't borrow the schema or the input, and can be saved
+/// with [`to_bytes`](Checkpoint::to_bytes) to pause a long-running job and
+/// resume it in another process.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Checkpoint {
+  /// The fingerprint of the schema the object is decoded with.
+  fingerprint: u64,
+  offset: usize,
+  /// Whether the first event has been decoded.
+  started: bool,
+  /// The number of values, the approximate number of bytes and the memory
+  /// decoded so far, which count towards the limits.
+  elements: usize,
+  size: usize,
+  memory: usize,
+  /// The records and lists which are open, from the outermost, along with
+  /// how each is reached from the one enclosing it.
+  frames: Vec<(Step, SavedFrame)>,
+  /// The name of the field whose value is decoded next.
+  pending: Option<String>,
+}
+
+/// How a record or list is reached from the one enclosing it.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum Step {
+  /// The root object.
+  Root,
+  /// The value of the field with a given name, or its fields if it is
+  /// inline.
+  Field(String),
+  /// A list element, with the index of its type if the elements are a union.
+  Element(usize),
+}
+
+/// The state of an open record or list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+enum SavedFrame {
+  Record(FieldsState),
+  List { len: usize, next: usize },
+}
+
+impl Checkpoint {
+  /// The bit offset in the input from which decoding resumes.
+  #[inline]
+  pub fn offset(&self) -> usize {
+    self.offset
+  }
+
+  /// The number of records and lists which are open.
+  #[inline]
+  pub fn depth(&self) -> usize {
+    self.frames.len()
+  }
+
+  /// Serializes this checkpoint to bytes, which can be read back with
+  /// [`from_bytes`].
+  ///
+  /// [`from_bytes`]: Checkpoint::from_bytes
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = self.fingerprint.to_le_bytes().to_vec();
+    let put = |bytes: &mut Vec<u8>, n: u64| {
+      bytes.extend_from_slice(CodePoint::from(n).bytes())
+    };
+    let put_str = |bytes: &mut Vec<u8>, s: &str| {
+      put(bytes, s.len() as u64);
+      bytes.extend_from_slice(s.as_bytes());
+    };
+
+    put(&mut bytes, self.offset as u64);
+    put(&mut bytes, self.elements as u64);
+    put(&mut bytes, self.size as u64);
+    put(&mut bytes, self.memory as u64);
+    match (&self.pending, self.started) {
+      (_, false) => put(&mut bytes, 0),
+      (None, true) => put(&mut bytes, 1),
+      (Some(name), true) => {
+        put(&mut bytes, 2);
+        put_str(&mut bytes, name);
+      }
+    }
+
+    put(&mut bytes, self.frames.len() as u64);
+    for (step, frame) in &self.frames {
+      match step {
+        Step::Root => put(&mut bytes, 0),
+        Step::Field(name) => {
+          put(&mut bytes, 1);
+          put_str(&mut bytes, name);
+        }
+        Step::Element(tag) => {
+          put(&mut bytes, 2);
+          put(&mut bytes, *tag as u64);
+        }
+      }
+      match frame {
+        SavedFrame::Record(state) => {
+          put(&mut bytes, 0);
+          put(&mut bytes, state.root as u64);
+          match state.layout {
+            FieldLayout::Unknown => put(&mut bytes, 0),
+            FieldLayout::Markers => put(&mut bytes, 1),
+            FieldLayout::Deltas(prev) => {
+              put(&mut bytes, 2);
+              put(&mut bytes, prev);
+            }
+            FieldLayout::Dense(next) => {
+              put(&mut bytes, 3);
+              put(&mut bytes, next as u64);
+            }
+          }
+          match state.parent {
+            None => put(&mut bytes, 0),
+            Some((width, first)) => {
+              put(&mut bytes, 1);
+              put(&mut bytes, width as u64);
+              put(&mut bytes, first as u64);
+            }
+          }
+          put(&mut bytes, state.pending.len() as u64);
+          for name in &state.pending {
+            put_str(&mut bytes, name);
+          }
+        }
+        SavedFrame::List { len, next } => {
+          put(&mut bytes, 1);
+          put(&mut bytes, *len as u64);
+          put(&mut bytes, *next as u64);
+        }
+      }
+    }
+    bytes
+  }
+
+  /// Deserializes a checkpoint from bytes produced by [`to_bytes`].
+  ///
+  /// [`to_bytes`]: Checkpoint::to_bytes
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    if bytes.len() < 8 {
+      bail!("checkpoint is truncated");
+    }
+    let mut fingerprint = [0; 8];
+    fingerprint.copy_from_slice(&bytes[..8]);
+    let mut c = Cursor(&bytes[8..]);
+
+    let offset = c.next()? as usize;
+    let elements = c.next()? as usize;
+    let size = c.next()? as usize;
+    let memory = c.next()? as usize;
+    let (started, pending) = match c.next()? {
+      0 => (false, None),
+      1 => (true, None),
+      2 => (true, Some(c.string()?)),
+      n => bail!("invalid checkpoint state {}", n),
+    };
+
+    let count = c.next()? as usize;
+    let mut frames = Vec::with_capacity(count.min(1 << 8));
+    for _ in 0..count {
+      let step = match c.next()? {
+        0 => Step::Root,
+        1 => Step::Field(c.string()?),
+        2 => Step::Element(c.next()? as usize),
+        n => bail!("invalid checkpoint step {}", n),
+      };
+      let frame = match c.next()? {
+        0 => {
+          let root = c.next()? != 0;
+          let layout = match c.next()? {
+            0 => FieldLayout::Unknown,
+            1 => FieldLayout::Markers,
+            2 => FieldLayout::Deltas(c.next()?),
+            3 => FieldLayout::Dense(c.next()? as usize),
+            n => bail!("invalid checkpoint field layout {}", n),
+          };
+          let parent = match c.next()? {
+            0 => None,
+            _ => Some((c.next()? as usize, c.next()? as u32)),
+          };
+          let names = c.next()? as usize;
+          let mut pending = Vec::with_capacity(names.min(1 << 8));
+          for _ in 0..names {
+            pending.push(c.string()?);
+          }
+          SavedFrame::Record(FieldsState {
+            root,
+            layout,
+            parent,
+            pending,
+          })
+        }
+        1 => SavedFrame::List {
+          len: c.next()? as usize,
+          next: c.next()? as usize,
+        },
+        n => bail!("invalid checkpoint frame {}", n),
+      };
+      frames.push((step, frame));
+    }
+    if !c.0.is_empty() {
+      bail!("unexpected bytes after checkpoint");
+    }
+
+    Ok(Checkpoint {
+      fingerprint: u64::from_le_bytes(fingerprint),
+      offset,
+      started,
+      elements,
+      size,
+      memory,
+      frames,
+      pending,
+    })
+  }
+}
+
+/// Reads the code points and strings of a serialized [`Checkpoint`].
+struct Cursor<'a>(&'a [u8]);
+
+impl Cursor<'_> {
+  fn next(&mut self) -> Result<u64> {
+    let cp = CodePoint::parse(self.0)
+      .ok_or_else(|| anyhow!("checkpoint is truncated"))?;
+    self.0 = &self.0[cp.count()..];
+    cp.decode::<u64>()
+      .ok_or_else(|| anyhow!("checkpoint value overflow"))
+  }
+
+  fn string(&mut self) -> Result<String> {
+    let len = self.next()? as usize;
+    if self.0.len() < len {
+      bail!("checkpoint is truncated");
+    }
+    let (s, rest) = self.0.split_at(len);
+    self.0 = rest;
+    Ok(String::from(core::str::from_utf8(s)?))
+  }
+}
+
+/// The approximate number of bytes needed to hold a decoded value.
+fn approximate_size(value: &Value) -> usize {
+  match value {
+    Value::String(s) => s.len(),
+    _ => core::mem::size_of::<u64>(),
+  }
+}
+
+/// The number of bytes taken up in memory by the key of a field `name`.
+fn key_memory(name: &str) -> usize {
+  size_of::<String>() + name.len()
+}
+
+fn limit_exceeded(limit: Limit) -> anyhow::Error {
+  Error::LimitExceeded {
+    path: Path::root(),
+    limit,
+  }
+  .into()
+}
+
+impl<'s, 'b> Iterator for Events<'s, 'b> {
+  type Item = Result<Event<'s>>;
+
+  fn next(&mut self) -> Option<Self::Item> {
+    if self.done {
+      return None;
+    }
+    if let Some(e) = self.error.take() {
+      self.done = true;
+      return Some(Err(e));
+    }
+
+    let event = self.decoder.next_event(&mut self.r).transpose();
+    if !matches!(event, Some(Ok(_))) {
+      self.done = true;
+    }
+    event
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::Record;
+  use crate::test_support::schema_and_value;
+  use proptest::prelude::*;
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+
+    let mut student = BTreeMap::new();
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    Schema::new(CompositeType::Record(Record::new(student)))
+  }
+
+  fn encode_bytes(schema: &Schema, value: &Value) -> Vec<u8> {
+    crate::encode(schema, value).unwrap().to_bytes()
+  }
+
+  #[test]
+  fn events_for_nested_object() {
+    let schema = schema();
+    let bytes = encode_bytes(
+      &schema,
+      &json!({ "active": true, "courses": [{ "name": "Math" }, {}] }),
+    );
+
+    let events: Vec<Event> = decode_events(&schema, &bytes)
+      .collect::<Result<_>>()
+      .unwrap();
+    assert_eq!(
+      vec![
+        Event::StartRecord,
+        Event::Field("active"),
+        Event::Value(json!(true)),
+        Event::Field("courses"),
+        Event::StartList(2),
+        Event::StartRecord,
+        Event::Field("name"),
+        Event::Value(json!("Math")),
+        Event::EndRecord,
+        Event::StartRecord,
+        Event::EndRecord,
+        Event::EndList,
+        Event::EndRecord,
+      ],
+      events
+    );
+  }
+
+  #[test]
+  fn events_stop_after_error() {
+    let schema = schema();
+    let bytes = encode_bytes(
+      &schema,
+      &json!({ "courses": [{ "name": "Math" }, { "name": "Physics" }] }),
+    );
+
+    let events: Vec<_> = decode_events(&schema, &bytes[..3]).collect();
+    assert!(events.last().unwrap().is_err());
+    assert!(events[..events.len() - 1].iter().all(Result::is_ok));
+  }
+
+  /// Checks that decoding can be resumed after every event.
+  fn check_resume(schema: &Schema, bytes: &[u8]) {
+    let all: Vec<Event> =
+      decode_events(schema, bytes).collect::<Result<_>>().unwrap();
+    for taken in 0..=all.len() {
+      let mut events = decode_events(schema, bytes);
+      for _ in 0..taken {
+        events.next().unwrap().unwrap();
+      }
+      let saved = events.checkpoint().to_bytes();
+      let checkpoint = Checkpoint::from_bytes(&saved).unwrap();
+      assert_eq!(events.checkpoint(), checkpoint);
+
+      let rest: Vec<Event> = resume_events(schema, bytes, &checkpoint)
+        .unwrap()
+        .collect::<Result<_>>()
+        .unwrap();
+      assert_eq!(all[taken..], rest[..]);
+    }
+  }
+
+  #[test]
+  fn resume_from_checkpoints() {
+    let schema = schema();
+    let bytes = encode_bytes(
+      &schema,
+      &json!({ "active": true, "courses": [{ "name": "Math" }, {}] }),
+    );
+    check_resume(&schema, &bytes);
+
+    let mut events = decode_events(&schema, &bytes);
+    for _ in 0..6 {
+      events.next().unwrap().unwrap();
+    }
+    let checkpoint = events.checkpoint();
+    assert_eq!(3, checkpoint.depth());
+    assert!(checkpoint.offset() > 0);
+
+    let other =
+      Schema::new(CompositeType::List(List(Box::new(Type::PassThrough))));
+    assert!(resume_events(&other, &bytes, &checkpoint).is_err());
+    assert!(Checkpoint::from_bytes(&checkpoint.to_bytes()[..9]).is_err());
+  }
+
+  proptest! {
+    #[test]
+    fn resume_any_object((schema, value) in schema_and_value()) {
+      for schema in &[schema.clone(), schema.with_chunks(2)] {
+        check_resume(schema, &encode_bytes(schema, &value));
+      }
+    }
+  }
+}
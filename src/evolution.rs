@@ -0,0 +1,348 @@
+//! The `evolution` module lets objects encoded with an older version of a
+//! schema be decoded with a newer one, so that stored data survives the
+//! schema growing.
+//!
+//! A newer schema is compatible with an older one if it only differs by:
+//!
+//! * records gaining new fields which are not required,
+//! * enums gaining new variants,
+//! * unions gaining new types after their existing ones,
+//! * required fields becoming optional,
+//! * list fields becoming nullable,
+//! * record fields becoming inline or no longer inline, and
+//! * records giving their fields identifiers in a different order.
+//!
+//! Field markers and enum values are assigned in sorted order, so adding a
+//! field or variant changes how other values are encoded. Old objects are
+//! therefore decoded with the exact version of the schema they were encoded
+//! with, found in a [`SchemaHistory`] by its [fingerprint], and then upgraded
+//! to the newest version by filling in the [defaults] of fields which were
+//! added since.
+//!
+//! [fingerprint]: Schema::fingerprint
+//! [defaults]: crate::schema::Record::defaults
+
+use crate::decode::DecodeOptions;
+use crate::encode::union_alternative;
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use crate::schema::{CompositeType, Record, Schema, Type};
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+
+/// Checks that objects encoded with the `old` schema can be upgraded to the
+/// `new` one, failing with the first difference which breaks the rules.
+pub fn check_compatible(old: &Schema, new: &Schema) -> Result<()> {
+  check_type(old.root(), new.root(), &mut Path::root())
+}
+
+fn check_type(old: &Type, new: &Type, path: &mut Path) -> Result<()> {
+  match (old, new) {
+    (Type::PassThrough, Type::PassThrough) => Ok(()),
+    (Type::Name(a), Type::Name(b)) if a == b => Ok(()),
+    (Type::Huffman { huffman: a }, Type::Huffman { huffman: b }) if a == b => {
+      Ok(())
+    }
+    (Type::Dictionary { size: a }, Type::Dictionary { size: b }) if a == b => {
+      Ok(())
+    }
+    (
+      Type::Scaled { scale: a, round: x },
+      Type::Scaled { scale: b, round: y },
+    ) if a == b && x == y => Ok(()),
+    (
+      Type::Transformed {
+        transform: a,
+        of: old,
+      },
+      Type::Transformed {
+        transform: b,
+        of: new,
+      },
+    ) if a == b => check_type(old, new, path),
+    (Type::Enum { variants: old, .. }, Type::Enum { variants: new, .. }) => {
+      match old.difference(new).next() {
+        Some(v) => bail!("variant '{}' was removed from {}", v, path),
+        None => Ok(()),
+      }
+    }
+    (Type::IntEnum { codes: old }, Type::IntEnum { codes: new }) => {
+      match old.difference(new).next() {
+        Some(c) => bail!("code {} was removed from {}", c, path),
+        None => Ok(()),
+      }
+    }
+    (
+      Type::Nested(CompositeType::Record(old)),
+      Type::Nested(CompositeType::Record(new)),
+    ) => check_record(old, new, path),
+    (
+      Type::Nested(CompositeType::List(old)),
+      Type::Nested(CompositeType::List(new)),
+    ) => {
+      path.0.push(Segment::Index(0));
+      check_type(&old.0, &new.0, path)?;
+      path.0.pop();
+      Ok(())
+    }
+    (Type::Union { alternatives: old }, Type::Union { alternatives: new }) => {
+      if new.len() < old.len() {
+        bail!("a type was removed from the union at {}", path);
+      }
+      for (old, new) in old.iter().zip(new) {
+        check_type(old, new, path)?;
+      }
+      Ok(())
+    }
+    _ => bail!("type of {} was changed", path),
+  }
+}
+
+fn check_record(old: &Record, new: &Record, path: &mut Path) -> Result<()> {
+  for (name, old_ty) in &old.fields {
+    path.0.push(Segment::Field(name.clone()));
+    let new_ty = new
+      .fields
+      .get(name)
+      .ok_or_else(|| anyhow!("field {} was removed", path))?;
+    check_type(old_ty, new_ty, path)?;
+    if new.is_required(name) && !old.is_required(name) {
+      bail!("field {} was made required", path);
+    }
+    if old.is_nullable(name) && !new.is_nullable(name) {
+      bail!("field {} was made non-nullable", path);
+    }
+    path.0.pop();
+  }
+
+  for (name, ty) in &new.fields {
+    if old.fields.contains_key(name) {
+      continue;
+    }
+    path.0.push(Segment::Field(name.clone()));
+    if new.is_required(name) {
+      bail!("new field {} is required", path);
+    }
+    if let Some(default) = new.defaults.get(name) {
+      let schema = Schema::new_scalar(ty.clone());
+      crate::encode(&schema, default)
+        .with_context(|| format!("invalid default for {}", path))?;
+    }
+    path.0.pop();
+  }
+  Ok(())
+}
+
+/// Fills in the defaults of fields which are in the `new` schema but not the
+/// `old` one, turning a value of the old schema into one of the new schema.
+///
+/// The schemas should be [compatible](check_compatible).
+pub fn upgrade(old: &Schema, new: &Schema, value: &mut Value) {
+  upgrade_type(old.root(), new.root(), value);
+}
+
+fn upgrade_type(old: &Type, new: &Type, value: &mut Value) {
+  match (old, new, value) {
+    (
+      Type::Nested(CompositeType::Record(old)),
+      Type::Nested(CompositeType::Record(new)),
+      Value::Object(map),
+    ) => {
+      for (name, default) in &new.defaults {
+        if !old.fields.contains_key(name) && !map.contains_key(name) {
+          map.insert(name.clone(), default.clone());
+        }
+      }
+      for (name, v) in map.iter_mut() {
+        if let (Some(old), Some(new)) =
+          (old.fields.get(name), new.fields.get(name))
+        {
+          upgrade_type(old, new, v);
+        }
+      }
+    }
+    (
+      Type::Nested(CompositeType::List(old)),
+      Type::Nested(CompositeType::List(new)),
+      Value::Array(arr),
+    ) => {
+      for v in arr {
+        upgrade_type(&old.0, &new.0, v);
+      }
+    }
+    // Values are upgraded as the type at the same position in the new union
+    // as the one they were encoded as
+    (
+      Type::Union { alternatives: old },
+      Type::Union { alternatives: new },
+      value,
+    ) => {
+      if let Ok((i, old)) = union_alternative(old, value) {
+        if let Some(new) = new.get(i) {
+          upgrade_type(old, new, value);
+        }
+      }
+    }
+    _ => {}
+  }
+}
+
+/// Every version of a schema, which decodes objects encoded with any of them
+/// as values of the newest version.
+#[derive(Clone, Debug)]
+pub struct SchemaHistory {
+  /// The versions, oldest first, along with their fingerprints.
+  versions: Vec<(u64, Schema)>,
+}
+
+impl SchemaHistory {
+  /// Constructs the history of a schema from its versions, oldest first.
+  ///
+  /// Fails if there are no versions or if a version isn't compatible with
+  /// the one before it.
+  pub fn new(versions: Vec<Schema>) -> Result<Self> {
+    if versions.is_empty() {
+      bail!("a schema history needs at least one version");
+    }
+    for (i, pair) in versions.windows(2).enumerate() {
+      check_compatible(&pair[0], &pair[1]).with_context(|| {
+        format!("version {} is incompatible with version {}", i + 1, i)
+      })?;
+    }
+    let versions = versions
+      .into_iter()
+      .map(|schema| (schema.fingerprint(), schema))
+      .collect();
+    Ok(SchemaHistory { versions })
+  }
+
+  /// The newest version of the schema.
+  pub fn latest(&self) -> &Schema {
+    &self.versions.last().expect("history is not empty").1
+  }
+
+  /// The version of the schema with a given fingerprint.
+  pub fn version(&self, fingerprint: u64) -> Option<&Schema> {
+    // Search from the newest as most objects use a recent version
+    self
+      .versions
+      .iter()
+      .rev()
+      .find(|(fp, _)| *fp == fingerprint)
+      .map(|(_, schema)| schema)
+  }
+
+  /// Decodes an object encoded with the version of the schema with a given
+  /// fingerprint, upgrading it to the newest version.
+  pub fn decode(&self, fingerprint: u64, bytes: &[u8]) -> Result<Value> {
+    self.decode_with(fingerprint, bytes, DecodeOptions::default())
+  }
+
+  /// Decodes an object encoded with the version of the schema with a given
+  /// fingerprint using `options`, upgrading it to the newest version.
+  pub fn decode_with(
+    &self,
+    fingerprint: u64,
+    bytes: &[u8],
+    options: DecodeOptions,
+  ) -> Result<Value> {
+    let schema = self.version(fingerprint).ok_or_else(|| {
+      anyhow!(
+        "no version of the schema has fingerprint {:016x}",
+        fingerprint
+      )
+    })?;
+    let mut value = crate::decode_with(schema, bytes, options)?;
+    upgrade(schema, self.latest(), &mut value);
+    Ok(value)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::List;
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn enum_type(variants: &[&str]) -> Type {
+    Type::Enum {
+      variants: variants.iter().map(|v| v.to_string()).collect(),
+      probabilities: None,
+    }
+  }
+
+  fn v1() -> Schema {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("grade".to_string(), enum_type(&["A", "B"]));
+    let mut record = Record::new(fields);
+    record.required.insert("name".to_string());
+    Schema::new(CompositeType::Record(record))
+  }
+
+  fn v2_record() -> Record {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("grade".to_string(), enum_type(&["A", "B", "C"]));
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert("email".to_string(), Type::PassThrough);
+    let mut record = Record::new(fields);
+    record.required.insert("name".to_string());
+    record.defaults.insert("active".to_string(), json!(true));
+    record
+  }
+
+  fn v2() -> Schema {
+    Schema::new(CompositeType::Record(v2_record()))
+  }
+
+  #[test]
+  fn compatible_versions() {
+    check_compatible(&v1(), &v2()).unwrap();
+    check_compatible(&v2(), &v2()).unwrap();
+    assert!(check_compatible(&v2(), &v1()).is_err());
+  }
+
+  #[test]
+  fn incompatible_changes() {
+    let mut record = v2_record();
+    record.required.insert("email".to_string());
+    let schema = Schema::new(CompositeType::Record(record));
+    let e = check_compatible(&v1(), &schema).unwrap_err();
+    assert_eq!("new field .email is required", e.to_string());
+
+    let list = |ty| Schema::new(CompositeType::List(List(Box::new(ty))));
+    let e = check_compatible(
+      &list(enum_type(&["A", "B"])),
+      &list(enum_type(&["A", "C"])),
+    )
+    .unwrap_err();
+    assert_eq!("variant 'B' was removed from [0]", e.to_string());
+
+    let e =
+      check_compatible(&list(Type::PassThrough), &list(enum_type(&["A"])))
+        .unwrap_err();
+    assert_eq!("type of [0] was changed", e.to_string());
+  }
+
+  #[test]
+  fn decode_old_version() {
+    let history = SchemaHistory::new(vec![v1(), v2()]).unwrap();
+    let old = json!({ "name": "Jeremy", "grade": "B" });
+    let bytes = crate::encode(&v1(), &old).unwrap().to_bytes();
+
+    let value = history.decode(v1().fingerprint(), &bytes).unwrap();
+    assert_eq!(
+      json!({ "name": "Jeremy", "grade": "B", "active": true }),
+      value
+    );
+
+    let new = json!({ "name": "Jeremy", "grade": "C", "active": false });
+    let bytes = crate::encode(&v2(), &new).unwrap().to_bytes();
+    assert_eq!(new, history.decode(v2().fingerprint(), &bytes).unwrap());
+
+    assert!(history.decode(0, &bytes).is_err());
+    assert!(SchemaHistory::new(vec![v2(), v1()]).is_err());
+  }
+}
@@ -0,0 +1,377 @@
+//! The `fec` module implements the Reed-Solomon code which archives use to
+//! repair entries damaged by unreliable storage.
+//!
+//! Bytes are split into codewords over GF(2^8) of at most 255 bytes. Each is
+//! made up of up to `255 - parity` data bytes followed by `parity` parity
+//! bytes, and any `parity / 2` of its bytes may be damaged and still be
+//! repaired. The bytes of the codewords are interleaved, one byte of each in
+//! turn, so that a burst of damage is spread out over all of them:
+//!
+//! ```text
+//! codeword 0 byte 0 | codeword 1 byte 0 | ... | codeword 0 byte 1 | ...
+//! ```
+//!
+//! Only the last codeword may be shorter than 255 bytes, so the number of
+//! data bytes can be worked out from the number of stored bytes alone.
+
+use anyhow::{bail, Result};
+
+/// The number of bytes in a full codeword.
+const N: usize = 255;
+
+/// The reduction polynomial x^8 + x^4 + x^3 + x^2 + 1 of the field.
+const POLY: u16 = 0x11d;
+
+/// Powers of the generator 2 of the field, repeated so that the sum of two
+/// logarithms can be looked up directly.
+static EXP: [u8; 2 * N] = TABLES.0;
+
+/// Logarithms base 2 of the non-zero elements of the field.
+static LOG: [u8; N + 1] = TABLES.1;
+
+const TABLES: ([u8; 2 * N], [u8; N + 1]) = tables();
+
+const fn tables() -> ([u8; 2 * N], [u8; N + 1]) {
+  let mut exp = [0u8; 2 * N];
+  let mut log = [0u8; N + 1];
+  let mut x: u16 = 1;
+  let mut i = 0;
+  while i < N {
+    exp[i] = x as u8;
+    exp[i + N] = x as u8;
+    log[x as usize] = i as u8;
+    x <<= 1;
+    if x & 0x100 != 0 {
+      x ^= POLY;
+    }
+    i += 1;
+  }
+  (exp, log)
+}
+
+fn mul(a: u8, b: u8) -> u8 {
+  match (a, b) {
+    (0, _) | (_, 0) => 0,
+    (a, b) => EXP[LOG[a as usize] as usize + LOG[b as usize] as usize],
+  }
+}
+
+fn div(a: u8, b: u8) -> u8 {
+  debug_assert_ne!(0, b, "division by zero");
+  match a {
+    0 => 0,
+    a => EXP[LOG[a as usize] as usize + N - LOG[b as usize] as usize],
+  }
+}
+
+fn inverse(a: u8) -> u8 {
+  div(1, a)
+}
+
+/// Raises the generator of the field to the power `e`.
+fn alpha(e: usize) -> u8 {
+  EXP[e % N]
+}
+
+// Polynomials are stored with their highest degree coefficient first.
+
+fn poly_scale(p: &[u8], x: u8) -> Vec<u8> {
+  p.iter().map(|c| mul(*c, x)).collect()
+}
+
+fn poly_add(p: &[u8], q: &[u8]) -> Vec<u8> {
+  let len = p.len().max(q.len());
+  let mut r = vec![0; len];
+  for (i, c) in p.iter().enumerate() {
+    r[i + len - p.len()] = *c;
+  }
+  for (i, c) in q.iter().enumerate() {
+    r[i + len - q.len()] ^= *c;
+  }
+  r
+}
+
+fn poly_mul(p: &[u8], q: &[u8]) -> Vec<u8> {
+  let mut r = vec![0; p.len() + q.len() - 1];
+  for (i, a) in p.iter().enumerate() {
+    for (j, b) in q.iter().enumerate() {
+      r[i + j] ^= mul(*a, *b);
+    }
+  }
+  r
+}
+
+fn poly_eval(p: &[u8], x: u8) -> u8 {
+  p.iter().fold(0, |y, c| mul(y, x) ^ c)
+}
+
+/// The remainder of dividing `p` by the monic polynomial `divisor`.
+fn poly_rem(p: &[u8], divisor: &[u8]) -> Vec<u8> {
+  let mut r = p.to_vec();
+  let degree = divisor.len() - 1;
+  for i in 0..p.len().saturating_sub(degree) {
+    let coef = r[i];
+    if coef != 0 {
+      for (j, d) in divisor.iter().enumerate().skip(1) {
+        r[i + j] ^= mul(*d, coef);
+      }
+    }
+  }
+  r.split_off(r.len().saturating_sub(degree))
+}
+
+/// The number of parity bytes in each codeword for `percent` percent more
+/// bytes of parity than data.
+pub(crate) fn parity_bytes(percent: u32) -> Result<usize> {
+  if !(1..=100).contains(&percent) {
+    bail!("parity must be between 1 and 100 percent");
+  }
+  let percent = percent as usize;
+  Ok(crate::math::div_ceil(N * percent, 100 + percent))
+}
+
+/// Checks that `parity` is a valid number of parity bytes per codeword.
+pub(crate) fn check_parity(parity: usize) -> Result<()> {
+  if !(2..N).contains(&parity) {
+    bail!("invalid number of parity bytes per codeword: {}", parity);
+  }
+  Ok(())
+}
+
+/// The number of bytes `len` bytes of data take up once `parity` parity
+/// bytes are added to each codeword.
+pub(crate) fn protected_len(len: usize, parity: usize) -> usize {
+  len + crate::math::div_ceil(len, N - parity) * parity
+}
+
+/// Adds `parity` parity bytes to each codeword of `data`, interleaving the
+/// codewords.
+pub(crate) fn protect(data: &[u8], parity: usize) -> Vec<u8> {
+  let rs = ReedSolomon::new(parity);
+  let codewords: Vec<Vec<u8>> = data
+    .chunks(N - parity)
+    .map(|chunk| {
+      let mut codeword = chunk.to_vec();
+      codeword.extend(rs.encode(chunk));
+      codeword
+    })
+    .collect();
+
+  let mut out = Vec::with_capacity(protected_len(data.len(), parity));
+  for i in 0..N {
+    out.extend(codewords.iter().filter_map(|c| c.get(i)));
+  }
+  out
+}
+
+/// Repairs the interleaved codewords of bytes protected with `parity` parity
+/// bytes each, returning the data along with the number of bytes which were
+/// repaired.
+pub(crate) fn repair(stored: &[u8], parity: usize) -> Result<(Vec<u8>, usize)> {
+  let count = crate::math::div_ceil(stored.len(), N);
+  let last = stored.len() - count.saturating_sub(1) * N;
+  if count > 0 && last <= parity {
+    bail!("protected bytes end within the parity of a codeword");
+  }
+
+  let len = |i: usize| if i + 1 == count { last } else { N };
+  let mut codewords: Vec<Vec<u8>> =
+    (0..count).map(|i| Vec::with_capacity(len(i))).collect();
+  let mut bytes = stored.iter();
+  for j in 0..N {
+    for (i, codeword) in codewords.iter_mut().enumerate() {
+      if j < len(i) {
+        codeword.extend(bytes.next());
+      }
+    }
+  }
+
+  let rs = ReedSolomon::new(parity);
+  let mut data = Vec::with_capacity(stored.len() - count * parity);
+  let mut repaired = 0;
+  for (i, mut codeword) in codewords.into_iter().enumerate() {
+    repaired += match rs.correct(&mut codeword) {
+      Ok(n) => n,
+      Err(e) => bail!("codeword {} is damaged beyond repair: {}", i, e),
+    };
+    codeword.truncate(codeword.len() - parity);
+    data.extend(codeword);
+  }
+  Ok((data, repaired))
+}
+
+/// A Reed-Solomon code with a given number of parity bytes per codeword.
+pub(crate) struct ReedSolomon {
+  /// The generator polynomial, whose roots are the first `parity` powers of
+  /// the generator of the field.
+  generator: Vec<u8>,
+}
+
+impl ReedSolomon {
+  pub(crate) fn new(parity: usize) -> Self {
+    let generator =
+      (0..parity).fold(vec![1], |g, i| poly_mul(&g, &[1, alpha(i)]));
+    ReedSolomon { generator }
+  }
+
+  fn parity(&self) -> usize {
+    self.generator.len() - 1
+  }
+
+  /// The parity bytes to append to `data` to form a codeword.
+  pub(crate) fn encode(&self, data: &[u8]) -> Vec<u8> {
+    let mut shifted = data.to_vec();
+    shifted.resize(data.len() + self.parity(), 0);
+    poly_rem(&shifted, &self.generator)
+  }
+
+  /// The syndromes of `codeword`, preceded by a zero. They are all zero if
+  /// the codeword is undamaged.
+  fn syndromes(&self, codeword: &[u8]) -> Vec<u8> {
+    let mut synd = vec![0];
+    synd.extend((0..self.parity()).map(|i| poly_eval(codeword, alpha(i))));
+    synd
+  }
+
+  /// Repairs the damaged bytes of `codeword` in place, returning how many
+  /// there were. Fails if more bytes are damaged than can be repaired.
+  pub(crate) fn correct(&self, codeword: &mut [u8]) -> Result<usize> {
+    let synd = self.syndromes(codeword);
+    if synd.iter().all(|s| *s == 0) {
+      return Ok(0);
+    }
+
+    let locator = self.error_locator(&synd)?;
+    let positions = error_positions(&locator, codeword.len())?;
+    correct_errors(codeword, &synd, &positions);
+
+    if self.syndromes(codeword).iter().any(|s| *s != 0) {
+      bail!("too many damaged bytes");
+    }
+    Ok(positions.len())
+  }
+
+  /// Finds the error locator polynomial using the Berlekamp-Massey
+  /// algorithm.
+  fn error_locator(&self, synd: &[u8]) -> Result<Vec<u8>> {
+    let mut locator = vec![1];
+    let mut old = vec![1];
+    for k in 1..synd.len() {
+      let mut delta = synd[k];
+      for j in 1..locator.len() {
+        delta ^= mul(locator[locator.len() - 1 - j], synd[k - j]);
+      }
+      old.push(0);
+      if delta != 0 {
+        if old.len() > locator.len() {
+          let new = poly_scale(&old, delta);
+          old = poly_scale(&locator, inverse(delta));
+          locator = new;
+        }
+        locator = poly_add(&locator, &poly_scale(&old, delta));
+      }
+    }
+
+    let leading = locator.iter().take_while(|c| **c == 0).count();
+    locator.drain(..leading);
+    if (locator.len() - 1) * 2 > self.parity() {
+      bail!("too many damaged bytes");
+    }
+    Ok(locator)
+  }
+}
+
+/// Finds the positions of the damaged bytes of a codeword of `len` bytes
+/// from the roots of its error `locator`.
+fn error_positions(locator: &[u8], len: usize) -> Result<Vec<usize>> {
+  let reversed: Vec<u8> = locator.iter().rev().copied().collect();
+  let positions: Vec<usize> = (0..len)
+    .filter(|i| poly_eval(&reversed, alpha(*i)) == 0)
+    .map(|i| len - 1 - i)
+    .collect();
+  if positions.len() != locator.len() - 1 {
+    bail!("too many damaged bytes");
+  }
+  Ok(positions)
+}
+
+/// Corrects the bytes of `codeword` at `positions` using the Forney
+/// algorithm.
+fn correct_errors(codeword: &mut [u8], synd: &[u8], positions: &[usize]) {
+  let len = codeword.len();
+  let coefs: Vec<usize> = positions.iter().map(|p| len - 1 - p).collect();
+  let locator = coefs.iter().fold(vec![1], |l, c| {
+    poly_mul(&l, &poly_add(&[1], &[alpha(*c), 0]))
+  });
+
+  let reversed: Vec<u8> = synd.iter().rev().copied().collect();
+  let mut divisor = vec![0; locator.len() + 1];
+  divisor[0] = 1;
+  let evaluator = poly_rem(&poly_mul(&reversed, &locator), &divisor);
+
+  let xs: Vec<u8> = coefs.iter().map(|c| alpha(*c)).collect();
+  for (i, x) in xs.iter().enumerate() {
+    let x_inv = inverse(*x);
+    let derivative = xs
+      .iter()
+      .enumerate()
+      .filter(|(j, _)| *j != i)
+      .fold(1, |d, (_, xj)| mul(d, 1 ^ mul(x_inv, *xj)));
+    let y = mul(*x, poly_eval(&evaluator, x_inv));
+    codeword[positions[i]] ^= div(y, derivative);
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn damage(bytes: &mut [u8], positions: &[usize]) {
+    for (i, p) in positions.iter().enumerate() {
+      bytes[*p] ^= 0x5a + i as u8;
+    }
+  }
+
+  #[test]
+  fn correct_up_to_half_the_parity() {
+    let rs = ReedSolomon::new(8);
+    let data: Vec<u8> = (0..40).map(|i| (i * 7 + 3) as u8).collect();
+    let mut codeword = data.clone();
+    codeword.extend(rs.encode(&data));
+    assert_eq!(0, rs.correct(&mut codeword.clone()).unwrap());
+
+    for positions in &[vec![0], vec![47], vec![3, 20], vec![1, 2, 30, 45]] {
+      let mut damaged = codeword.clone();
+      damage(&mut damaged, positions);
+      assert_eq!(positions.len(), rs.correct(&mut damaged).unwrap());
+      assert_eq!(codeword, damaged);
+    }
+
+    let mut damaged = codeword.clone();
+    damage(&mut damaged, &[0, 5, 10, 15, 20]);
+    assert!(rs.correct(&mut damaged).is_err());
+  }
+
+  #[test]
+  fn repair_interleaved_bursts() {
+    let parity = parity_bytes(10).unwrap();
+    assert_eq!(24, parity);
+    let data: Vec<u8> = (0..1000).map(|i| (i % 251) as u8).collect();
+    let mut stored = protect(&data, parity);
+    assert_eq!(protected_len(data.len(), parity), stored.len());
+    assert_eq!((data.clone(), 0), repair(&stored, parity).unwrap());
+
+    // A burst of 40 bytes is spread over the 5 codewords
+    for b in &mut stored[100..140] {
+      *b = 0;
+    }
+    let (repaired, n) = repair(&stored, parity).unwrap();
+    assert_eq!(data, repaired);
+    assert!(n > 0 && n <= 40);
+
+    assert_eq!(
+      (Vec::new(), 0),
+      repair(&protect(&[], parity), parity).unwrap()
+    );
+  }
+}
@@ -0,0 +1,308 @@
+//! The `ffi` module exposes the codec to C and other languages with a C ABI.
+//!
+//! The declarations are mirrored in `include/chii.h`, which is generated with
+//! [cbindgen] using the `cbindgen.toml` at the root of the repository.
+//!
+//! The crate is only built as a Rust library by default. A shared or static
+//! library for linking from C is built with the `ffi` feature and the crate
+//! type chosen on the command line:
+//!
+//! ```text
+//! cargo rustc --release --lib --features ffi --crate-type cdylib
+//! cargo rustc --release --lib --features ffi --crate-type staticlib
+//! ```
+//!
+//! # Ownership
+//!
+//! * Schemas are created with [`chii_schema_parse`] and must be released with
+//!   [`chii_schema_free`].
+//! * Buffers returned through a [`ChiiBuffer`] out parameter are owned by the
+//!   caller and must be released with [`chii_buffer_free`]. They must not be
+//!   passed to `free`.
+//! * The string returned by [`chii_last_error`] is owned by the library. It
+//!   stays valid until the next call into the library on the same thread.
+//!
+//! Every function which can fail returns `0` on success and `-1` on failure,
+//! in which case the reason is available from [`chii_last_error`]. Panics are
+//! caught at the boundary and reported as errors.
+//!
+//! [cbindgen]: https://github.com/eqrion/cbindgen
+
+use crate::schema::Schema;
+use anyhow::{anyhow, Result};
+use serde_json::Value;
+use std::cell::RefCell;
+use std::ffi::CString;
+use std::os::raw::{c_char, c_int};
+use std::panic::{self, AssertUnwindSafe};
+use std::{ptr, slice};
+
+/// A parsed schema, opaque to C.
+pub struct ChiiSchema(Schema);
+
+/// A buffer of bytes owned by the caller.
+///
+/// Release it with [`chii_buffer_free`].
+#[repr(C)]
+#[derive(Debug)]
+pub struct ChiiBuffer {
+  pub data: *mut u8,
+  pub len: usize,
+}
+
+impl ChiiBuffer {
+  fn empty() -> Self {
+    ChiiBuffer {
+      data: ptr::null_mut(),
+      len: 0,
+    }
+  }
+
+  fn from_vec(bytes: Vec<u8>) -> Self {
+    let bytes = bytes.into_boxed_slice();
+    let len = bytes.len();
+    let data = Box::into_raw(bytes) as *mut u8;
+    ChiiBuffer { data, len }
+  }
+}
+
+thread_local! {
+  static LAST_ERROR: RefCell<Option<CString>> = const { RefCell::new(None) };
+}
+
+fn set_last_error(message: String) {
+  // Interior nul bytes can't be represented in a C string
+  let message = CString::new(message.replace('\0', "\\0")).unwrap();
+  LAST_ERROR.with(|e| *e.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, recording any error or panic, and returns the matching status
+/// code.
+fn status(f: impl FnOnce() -> Result<()>) -> c_int {
+  LAST_ERROR.with(|e| *e.borrow_mut() = None);
+  match panic::catch_unwind(AssertUnwindSafe(f)) {
+    Ok(Ok(())) => 0,
+    Ok(Err(e)) => {
+      set_last_error(format!("{:#}", e));
+      -1
+    }
+    Err(_) => {
+      set_last_error("panicked".to_string());
+      -1
+    }
+  }
+}
+
+/// Borrows `len` bytes at `data`, allowing a null pointer for an empty slice.
+unsafe fn bytes<'a>(data: *const u8, len: usize) -> Result<&'a [u8]> {
+  if data.is_null() {
+    if len == 0 {
+      return Ok(&[]);
+    }
+    return Err(anyhow!("null data pointer"));
+  }
+  Ok(slice::from_raw_parts(data, len))
+}
+
+unsafe fn schema_ref<'a>(schema: *const ChiiSchema) -> Result<&'a Schema> {
+  schema
+    .as_ref()
+    .map(|s| &s.0)
+    .ok_or_else(|| anyhow!("null schema pointer"))
+}
+
+/// Parses a YAML or JSON schema definition of `len` bytes.
+///
+/// Returns null on failure.
+///
+/// # Safety
+///
+/// `text` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn chii_schema_parse(
+  text: *const c_char,
+  len: usize,
+) -> *mut ChiiSchema {
+  let mut parsed = None;
+  status(|| {
+    let text = bytes(text as *const u8, len)?;
+    parsed = Some(serde_yaml::from_slice::<Schema>(text)?);
+    Ok(())
+  });
+  match parsed {
+    Some(schema) => Box::into_raw(Box::new(ChiiSchema(schema))),
+    None => ptr::null_mut(),
+  }
+}
+
+/// Releases a schema returned by [`chii_schema_parse`]. Does nothing if
+/// `schema` is null.
+///
+/// # Safety
+///
+/// `schema` must be null or a pointer returned by [`chii_schema_parse`] which
+/// has not already been freed.
+#[no_mangle]
+pub unsafe extern "C" fn chii_schema_free(schema: *mut ChiiSchema) {
+  if !schema.is_null() {
+    drop(Box::from_raw(schema));
+  }
+}
+
+/// Compresses `len` bytes of JSON text at `json`, storing the compressed
+/// bytes in `out`.
+///
+/// # Safety
+///
+/// `schema` must be a live schema, `json` must point to `len` readable bytes
+/// and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn chii_compress(
+  schema: *const ChiiSchema,
+  json: *const u8,
+  len: usize,
+  out: *mut ChiiBuffer,
+) -> c_int {
+  status(|| {
+    let out = out.as_mut().ok_or_else(|| anyhow!("null out pointer"))?;
+    *out = ChiiBuffer::empty();
+    let schema = schema_ref(schema)?;
+    let value: Value = serde_json::from_slice(bytes(json, len)?)?;
+    *out = ChiiBuffer::from_vec(crate::encode(schema, &value)?.to_bytes());
+    Ok(())
+  })
+}
+
+/// Decompresses `len` bytes at `data`, storing the decoded JSON text, which is
+/// not nul terminated, in `out`.
+///
+/// # Safety
+///
+/// `schema` must be a live schema, `data` must point to `len` readable bytes
+/// and `out` must be valid for writes.
+#[no_mangle]
+pub unsafe extern "C" fn chii_decompress(
+  schema: *const ChiiSchema,
+  data: *const u8,
+  len: usize,
+  out: *mut ChiiBuffer,
+) -> c_int {
+  status(|| {
+    let out = out.as_mut().ok_or_else(|| anyhow!("null out pointer"))?;
+    *out = ChiiBuffer::empty();
+    let schema = schema_ref(schema)?;
+    let value = crate::decode(schema, bytes(data, len)?)?;
+    *out = ChiiBuffer::from_vec(serde_json::to_vec(&value)?);
+    Ok(())
+  })
+}
+
+/// Releases a buffer filled in by the library. Does nothing if its data is
+/// null.
+///
+/// # Safety
+///
+/// `buffer` must have been filled in by the library and not already been
+/// freed.
+#[no_mangle]
+pub unsafe extern "C" fn chii_buffer_free(buffer: ChiiBuffer) {
+  if !buffer.data.is_null() {
+    let slice = ptr::slice_from_raw_parts_mut(buffer.data, buffer.len);
+    drop(Box::from_raw(slice));
+  }
+}
+
+/// The message of the last error raised on this thread, or null if the last
+/// call succeeded.
+///
+/// The string is owned by the library and stays valid until the next call
+/// into the library on the same thread.
+#[no_mangle]
+pub extern "C" fn chii_last_error() -> *const c_char {
+  LAST_ERROR.with(|e| match &*e.borrow() {
+    Some(message) => message.as_ptr(),
+    None => ptr::null(),
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{CompositeType, Record, Type};
+  use std::collections::BTreeMap;
+  use std::ffi::CStr;
+
+  fn schema() -> *mut ChiiSchema {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+    Box::into_raw(Box::new(ChiiSchema(schema)))
+  }
+
+  fn last_error() -> Option<String> {
+    let message = chii_last_error();
+    if message.is_null() {
+      return None;
+    }
+    let message = unsafe { CStr::from_ptr(message) };
+    Some(message.to_str().unwrap().to_string())
+  }
+
+  #[test]
+  fn compress_and_decompress() {
+    let schema = schema();
+    let json = br#"{"active":true,"name":"Alice"}"#;
+
+    unsafe {
+      let mut compressed = ChiiBuffer::empty();
+      let status =
+        chii_compress(schema, json.as_ptr(), json.len(), &mut compressed);
+      assert_eq!(0, status);
+      assert_eq!(None, last_error());
+
+      let mut decompressed = ChiiBuffer::empty();
+      let status = chii_decompress(
+        schema,
+        compressed.data,
+        compressed.len,
+        &mut decompressed,
+      );
+      assert_eq!(0, status);
+      let text = slice::from_raw_parts(decompressed.data, decompressed.len);
+      assert_eq!(&json[..], text);
+
+      chii_buffer_free(compressed);
+      chii_buffer_free(decompressed);
+      chii_schema_free(schema);
+    }
+  }
+
+  #[test]
+  fn errors_are_reported() {
+    let schema = schema();
+    let json = br#"{"active":"yes"}"#;
+
+    unsafe {
+      let mut out = ChiiBuffer::empty();
+      let status = chii_compress(schema, json.as_ptr(), json.len(), &mut out);
+      assert_eq!(-1, status);
+      assert!(out.data.is_null());
+      assert!(last_error().is_some());
+
+      let status = chii_compress(ptr::null(), json.as_ptr(), 1, &mut out);
+      assert_eq!(-1, status);
+      assert_eq!(Some("null schema pointer".to_string()), last_error());
+
+      chii_schema_free(schema);
+    }
+  }
+
+  #[test]
+  fn freeing_null_does_nothing() {
+    unsafe {
+      chii_schema_free(ptr::null_mut());
+      chii_buffer_free(ChiiBuffer::empty());
+    }
+  }
+}
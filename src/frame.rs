@@ -0,0 +1,503 @@
+//! The `frame` module implements length-prefixed framing of compressed
+//! objects, so that a stream of them can be sent over a socket or through a
+//! message queue and split apart again by the reader.
+//!
+//! Each frame is laid out as follows:
+//!
+//! ```text
+//! header | fingerprint? | object
+//! ```
+//!
+//! The header is a VIE encoded integer holding the byte length of the object
+//! shifted left by five. The lowest bit is set if the frame carries a schema
+//! fingerprint, the next bit is set if the object's blocks are
+//! [byte-aligned](Layout::ByteAligned), the one after that is set if it was
+//! encoded with the [dense](Profile::Dense) profile, the next if it was
+//! encoded with [checksums](crate::EncodeOptions::checksums) and the highest
+//! is set if it's packed [least significant bit first](BitOrder::LsbFirst),
+//! so that readers know how to decode it. The fingerprint, if present, is the 8
+//! byte little endian [`Schema::fingerprint`] of the schema the object was
+//! encoded with. It lets readers detect objects encoded with a different schema
+//! before decoding them, or pick the version of the schema they were encoded
+//! with from a [`SchemaHistory`] or [`Migrator`].
+
+use crate::data::{BitOrder, Layout, Profile};
+use crate::decode::DecodeOptions;
+use crate::evolution::SchemaHistory;
+use crate::migrate::Migrator;
+use crate::schema::Schema;
+use crate::vie::CodePoint;
+use anyhow::{anyhow, bail, Result};
+use serde_json::Value;
+use std::io::{self, Read, Write};
+
+/// A VIE encoded u64 needs at most 10 bytes.
+pub(crate) const MAX_HEADER_LEN: usize = 10;
+
+/// A compressed object read from a stream of frames.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Frame {
+  /// The fingerprint of the schema the object was encoded with, if it was
+  /// included in the frame.
+  pub fingerprint: Option<u64>,
+  /// The bytes of the compressed object.
+  pub object: Vec<u8>,
+  /// How the blocks of the object are laid out.
+  pub layout: Layout,
+  /// Which profile the object was encoded with.
+  pub profile: Profile,
+  /// Whether the object was encoded with checksums.
+  pub checksums: bool,
+  /// How the bits of the object are packed into bytes.
+  pub bit_order: BitOrder,
+}
+
+impl Frame {
+  /// Decodes the object in this frame using `schema`, or the older version
+  /// bundled with it that the frame was encoded with.
+  ///
+  /// Fails without decoding anything if the frame has a fingerprint which
+  /// doesn't match any version of `schema`.
+  pub fn decode(&self, schema: &Schema) -> Result<Value> {
+    let schema = match self.fingerprint {
+      Some(fingerprint) => schema.resolve(fingerprint).ok_or_else(|| {
+        anyhow!(
+          "frame was encoded with a different schema (fingerprint {:016x})",
+          fingerprint
+        )
+      })?,
+      None => schema,
+    };
+    crate::decode_with(schema, &self.object, self.options())
+  }
+
+  /// Decodes the object in this frame using the version of the schema in
+  /// `history` it was encoded with, upgrading it to the newest version.
+  ///
+  /// A frame without a fingerprint is assumed to use the newest version.
+  pub fn decode_with_history(&self, history: &SchemaHistory) -> Result<Value> {
+    match self.fingerprint {
+      Some(fingerprint) => {
+        history.decode_with(fingerprint, &self.object, self.options())
+      }
+      None => {
+        crate::decode_with(history.latest(), &self.object, self.options())
+      }
+    }
+  }
+
+  /// Decodes the object in this frame using the version of the schema in
+  /// `migrator` it was encoded with, migrating it to the current version.
+  ///
+  /// A frame without a fingerprint is assumed to use the current version.
+  pub fn decode_with_migrator(&self, migrator: &Migrator) -> Result<Value> {
+    match self.fingerprint {
+      Some(fingerprint) => {
+        migrator.decode_with(fingerprint, &self.object, self.options())
+      }
+      None => {
+        crate::decode_with(migrator.current(), &self.object, self.options())
+      }
+    }
+  }
+
+  /// The options for decoding the object in this frame.
+  fn options(&self) -> DecodeOptions {
+    DecodeOptions {
+      layout: self.layout,
+      profile: self.profile,
+      checksums: self.checksums,
+      bit_order: self.bit_order,
+      ..DecodeOptions::default()
+    }
+  }
+}
+
+/// Writes the bytes of a compressed `object` as a single frame, including the
+/// fingerprint of `schema` if given.
+pub fn write_frame<W: Write>(
+  writer: W,
+  object: &[u8],
+  schema: Option<&Schema>,
+) -> Result<()> {
+  let (layout, profile) = (Layout::Packed, Profile::Standard);
+  let bit_order = BitOrder::MsbFirst;
+  write_frame_with(writer, object, schema, layout, profile, false, bit_order)
+}
+
+/// Writes the bytes of a compressed `object` whose blocks are laid out using
+/// `layout` and which was encoded with `profile`, with checksums if
+/// `checksums` is set and packed in `bit_order`, as a single frame, including
+/// the fingerprint of `schema` if given.
+pub fn write_frame_with<W: Write>(
+  mut writer: W,
+  object: &[u8],
+  schema: Option<&Schema>,
+  layout: Layout,
+  profile: Profile,
+  checksums: bool,
+  bit_order: BitOrder,
+) -> Result<()> {
+  let fingerprint = schema.map(Schema::fingerprint);
+  let prefix = prefix(
+    object.len(),
+    fingerprint,
+    layout,
+    profile,
+    checksums,
+    bit_order,
+  );
+  writer.write_all(&prefix)?;
+  writer.write_all(object)?;
+  Ok(())
+}
+
+/// The bytes which come before an object `len` bytes long in a frame.
+pub(crate) fn prefix(
+  len: usize,
+  fingerprint: Option<u64>,
+  layout: Layout,
+  profile: Profile,
+  checksums: bool,
+  bit_order: BitOrder,
+) -> Vec<u8> {
+  let aligned = layout == Layout::ByteAligned;
+  let dense = profile == Profile::Dense;
+  let lsb_first = bit_order == BitOrder::LsbFirst;
+  let header = (len as u64) << 5
+    | (lsb_first as u64) << 4
+    | (checksums as u64) << 3
+    | (dense as u64) << 2
+    | (aligned as u64) << 1
+    | fingerprint.is_some() as u64;
+  let mut bytes = CodePoint::from(header).bytes().to_vec();
+  if let Some(fingerprint) = fingerprint {
+    bytes.extend_from_slice(&fingerprint.to_le_bytes());
+  }
+  bytes
+}
+
+/// Reads the next frame from `reader`.
+///
+/// Returns `None` if the stream ends cleanly before the start of a frame. A
+/// stream which ends part way through a frame is an error.
+pub fn read_frame<R: Read>(mut reader: R) -> Result<Option<Frame>> {
+  let header = match read_header(&mut reader)? {
+    Some(header) => header,
+    None => return Ok(None),
+  };
+
+  let (len, has_fingerprint, layout, profile, checksums, bit_order) = header;
+  let fingerprint = if has_fingerprint {
+    let mut bytes = [0u8; 8];
+    reader.read_exact(&mut bytes).map_err(truncated)?;
+    Some(u64::from_le_bytes(bytes))
+  } else {
+    None
+  };
+
+  // Read through `take` instead of allocating the whole object up front as a
+  // corrupted length may be arbitrarily large
+  let mut object = Vec::new();
+  reader.take(len).read_to_end(&mut object)?;
+  if (object.len() as u64) < len {
+    bail!("frame is truncated");
+  }
+  Ok(Some(Frame {
+    fingerprint,
+    object,
+    layout,
+    profile,
+    checksums,
+    bit_order,
+  }))
+}
+
+/// Reads the VIE encoded header of a frame, or `None` at the end of the
+/// stream.
+fn read_header<R: Read>(reader: &mut R) -> Result<Option<Header>> {
+  let mut bytes = Vec::with_capacity(MAX_HEADER_LEN);
+  loop {
+    let mut byte = [0u8];
+    if reader.read(&mut byte)? == 0 {
+      return end_of_header(&bytes);
+    }
+    if push_header_byte(&mut bytes, byte[0])? {
+      return parse_header(&bytes).map(Some);
+    }
+  }
+}
+
+/// Adds the next `byte` of a header to `bytes`, returning `true` if it was
+/// the last one.
+pub(crate) fn push_header_byte(bytes: &mut Vec<u8>, byte: u8) -> Result<bool> {
+  bytes.push(byte);
+  if byte & 0x80 == 0 {
+    return Ok(true);
+  }
+  if bytes.len() == MAX_HEADER_LEN {
+    bail!("frame header is too long");
+  }
+  Ok(false)
+}
+
+/// Handles the end of the stream after reading the partial header `bytes`.
+pub(crate) fn end_of_header<T>(bytes: &[u8]) -> Result<Option<T>> {
+  if bytes.is_empty() {
+    Ok(None)
+  } else {
+    bail!("frame header is truncated")
+  }
+}
+
+/// The object's length, whether the frame has a fingerprint, the layout and
+/// profile of the object, whether it has checksums and its bit order.
+pub(crate) type Header = (u64, bool, Layout, Profile, bool, BitOrder);
+
+/// Splits a complete header into its parts.
+pub(crate) fn parse_header(bytes: &[u8]) -> Result<Header> {
+  let header = CodePoint::parse(bytes)
+    .and_then(|cp| cp.decode::<u64>())
+    .ok_or_else(|| anyhow!("frame length overflows"))?;
+  let layout = if header & 2 == 2 {
+    Layout::ByteAligned
+  } else {
+    Layout::Packed
+  };
+  let profile = if header & 4 == 4 {
+    Profile::Dense
+  } else {
+    Profile::Standard
+  };
+  let checksums = header & 8 == 8;
+  let bit_order = if header & 16 == 16 {
+    BitOrder::LsbFirst
+  } else {
+    BitOrder::MsbFirst
+  };
+  let fingerprint = header & 1 == 1;
+  Ok((
+    header >> 5,
+    fingerprint,
+    layout,
+    profile,
+    checksums,
+    bit_order,
+  ))
+}
+
+pub(crate) fn truncated(e: io::Error) -> anyhow::Error {
+  if e.kind() == io::ErrorKind::UnexpectedEof {
+    anyhow!("frame is truncated")
+  } else {
+    e.into()
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{CompositeType, Record, Type};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema(field: &str) -> Schema {
+    let mut fields = BTreeMap::new();
+    fields.insert(field.to_string(), Type::PassThrough);
+    Schema::new(CompositeType::Record(Record::new(fields)))
+  }
+
+  #[test]
+  fn frame_roundtrip() {
+    let schema = schema("name");
+    let a = crate::encode(&schema, &json!({ "name": "Jeremy" }))
+      .unwrap()
+      .to_bytes();
+    let b = vec![0x5a; 200];
+
+    let mut stream = Vec::new();
+    write_frame(&mut stream, &a, Some(&schema)).unwrap();
+    write_frame(&mut stream, &b, None).unwrap();
+
+    let mut r = &stream[..];
+    let frame = read_frame(&mut r).unwrap().unwrap();
+    assert_eq!(Some(schema.fingerprint()), frame.fingerprint);
+    assert_eq!(json!({ "name": "Jeremy" }), frame.decode(&schema).unwrap());
+    assert!(frame.decode(&self::schema("title")).is_err());
+
+    let frame = read_frame(&mut r).unwrap().unwrap();
+    assert_eq!(
+      Frame {
+        fingerprint: None,
+        object: b,
+        layout: Layout::Packed,
+        profile: Profile::Standard,
+        checksums: false,
+        bit_order: BitOrder::MsbFirst,
+      },
+      frame
+    );
+    assert_eq!(None, read_frame(&mut r).unwrap());
+  }
+
+  #[test]
+  fn frame_from_older_schema() {
+    let old = schema("name");
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("title".to_string(), Type::PassThrough);
+    let mut record = Record::new(fields);
+    record.defaults.insert("title".to_string(), json!("Dr."));
+    let new = Schema::new(CompositeType::Record(record));
+    let history = SchemaHistory::new(vec![old.clone(), new.clone()]).unwrap();
+
+    let bytes = crate::encode(&old, &json!({ "name": "Jeremy" }))
+      .unwrap()
+      .to_bytes();
+    let mut stream = Vec::new();
+    write_frame(&mut stream, &bytes, Some(&old)).unwrap();
+
+    let frame = read_frame(&stream[..]).unwrap().unwrap();
+    assert!(frame.decode(&new).is_err());
+    let bundle = new.clone().with_history(vec![old]);
+    assert_eq!(json!({ "name": "Jeremy" }), frame.decode(&bundle).unwrap());
+    assert_eq!(
+      json!({ "name": "Jeremy", "title": "Dr." }),
+      frame.decode_with_history(&history).unwrap()
+    );
+  }
+
+  #[test]
+  fn truncated_frame() {
+    let mut stream = Vec::new();
+    write_frame(&mut stream, &[1, 2, 3], None).unwrap();
+    for end in 1..stream.len() {
+      assert!(read_frame(&stream[..end]).is_err());
+    }
+    assert!(read_frame(&[0x80][..]).is_err());
+  }
+
+  #[test]
+  fn frame_records_layout() {
+    let schema = schema("name");
+    let value = json!({ "name": "Jeremy" });
+    let options = crate::EncodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let bytes = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+
+    let mut stream = Vec::new();
+    let layout = Layout::ByteAligned;
+    write_frame_with(
+      &mut stream,
+      &bytes,
+      Some(&schema),
+      layout,
+      Profile::Standard,
+      false,
+      BitOrder::MsbFirst,
+    )
+    .unwrap();
+    let frame = read_frame(&stream[..]).unwrap().unwrap();
+    assert_eq!(Layout::ByteAligned, frame.layout);
+    assert_eq!(bytes, frame.object);
+    assert_eq!(value, frame.decode(&schema).unwrap());
+  }
+
+  #[test]
+  fn frame_records_profile() {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    let mut record = Record::new(fields);
+    record.required.insert("name".to_string());
+    let schema = Schema::new(CompositeType::Record(record));
+    let value = json!({ "name": "Jeremy" });
+    let options = crate::EncodeOptions {
+      profile: Profile::Dense,
+      ..Default::default()
+    };
+    let bytes = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+
+    let mut stream = Vec::new();
+    let (layout, profile) = (Layout::Packed, Profile::Dense);
+    let bit_order = BitOrder::MsbFirst;
+    write_frame_with(
+      &mut stream,
+      &bytes,
+      None,
+      layout,
+      profile,
+      false,
+      bit_order,
+    )
+    .unwrap();
+    let frame = read_frame(&stream[..]).unwrap().unwrap();
+    assert_eq!(Profile::Dense, frame.profile);
+    assert_eq!(value, frame.decode(&schema).unwrap());
+  }
+
+  #[test]
+  fn frame_records_checksums() {
+    let schema = schema("name");
+    let value = json!({ "name": "Jeremy" });
+    let options = crate::EncodeOptions {
+      checksums: true,
+      ..Default::default()
+    };
+    let bytes = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+
+    let mut stream = Vec::new();
+    let (layout, profile) = (Layout::Packed, Profile::Standard);
+    let bit_order = BitOrder::MsbFirst;
+    write_frame_with(
+      &mut stream,
+      &bytes,
+      None,
+      layout,
+      profile,
+      true,
+      bit_order,
+    )
+    .unwrap();
+    let frame = read_frame(&stream[..]).unwrap().unwrap();
+    assert!(frame.checksums);
+    assert_eq!(value, frame.decode(&schema).unwrap());
+  }
+
+  #[test]
+  fn frame_records_bit_order() {
+    let schema = schema("name");
+    let value = json!({ "name": "Jeremy" });
+    let options = crate::EncodeOptions {
+      bit_order: BitOrder::LsbFirst,
+      ..Default::default()
+    };
+    let bytes = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+
+    let mut stream = Vec::new();
+    let (layout, profile) = (Layout::Packed, Profile::Standard);
+    let bit_order = BitOrder::LsbFirst;
+    write_frame_with(
+      &mut stream,
+      &bytes,
+      None,
+      layout,
+      profile,
+      false,
+      bit_order,
+    )
+    .unwrap();
+    let frame = read_frame(&stream[..]).unwrap().unwrap();
+    assert_eq!(BitOrder::LsbFirst, frame.bit_order);
+    assert_eq!(value, frame.decode(&schema).unwrap());
+  }
+}
@@ -0,0 +1,413 @@
+//! The `header` module implements the header found at the start of compressed
+//! files, such as those written by `chii compress`.
+//!
+//! The object in a compressed file may be followed by footers holding its
+//! [unknown fields](crate::unknown) and an [index](crate::index). Rather than
+//! guessing whether they are there from the last few bytes of the file, which
+//! could just as well be part of the object, the header records which of them
+//! follow the object. It also records the [layout](Layout),
+//! [bit order](BitOrder) and [profile](Profile) of the object and whether it
+//! was encoded with [checksums](crate::EncodeOptions::checksums), so that
+//! every reader of the file decodes it the way it was encoded. A compressed
+//! file is laid out like so:
+//!
+//! ```text
+//! MAGIC | flags | object | unknown fields? | index?
+//! ```
+//!
+//! The object includes its [dictionaries](crate::dictionary) footer, whose
+//! presence is determined by the schema. The flags are a single byte, the
+//! lowest bit of which is set if the object is followed by unknown fields, the
+//! next bit if it's followed by an index, the bit after that if the object is
+//! byte-aligned, the fourth bit if it's packed least significant bit first,
+//! the fifth bit if it's encoded with the dense profile and the sixth bit if it
+//! has checksums. Headers with any other bit set are rejected.
+
+use crate::data::{BitOrder, Layout, Profile};
+use crate::decode::DecodeOptions;
+use crate::index::Index;
+use crate::unknown::UnknownFields;
+use anyhow::{bail, Result};
+
+/// Magic bytes found at the very start of a compressed file.
+pub const MAGIC: &[u8; 4] = b"CHIF";
+
+/// Size of the header in bytes.
+pub const LEN: usize = MAGIC.len() + 1;
+
+const UNKNOWN_FIELDS: u8 = 1;
+const INDEX: u8 = 1 << 1;
+const BYTE_ALIGNED: u8 = 1 << 2;
+const LSB_FIRST: u8 = 1 << 3;
+const DENSE: u8 = 1 << 4;
+const CHECKSUMS: u8 = 1 << 5;
+
+/// Describes how the object of a compressed file was encoded and what follows
+/// it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Header {
+  /// Whether the object is followed by an unknown fields footer.
+  pub unknown_fields: bool,
+  /// Whether the object is followed by an index footer.
+  pub index: bool,
+  /// How the object is laid out.
+  pub layout: Layout,
+  /// How the bits of the object are packed into bytes.
+  pub bit_order: BitOrder,
+  /// Which encoding profile the object was encoded with.
+  pub profile: Profile,
+  /// Whether the object was encoded with checksums.
+  pub checksums: bool,
+}
+
+impl Default for Header {
+  fn default() -> Self {
+    Header {
+      unknown_fields: false,
+      index: false,
+      layout: Layout::Packed,
+      bit_order: BitOrder::MsbFirst,
+      profile: Profile::Standard,
+      checksums: false,
+    }
+  }
+}
+
+impl Header {
+  /// Serializes this header to bytes.
+  pub fn to_bytes(&self) -> [u8; LEN] {
+    let mut flags = 0;
+    if self.unknown_fields {
+      flags |= UNKNOWN_FIELDS;
+    }
+    if self.index {
+      flags |= INDEX;
+    }
+    if self.layout == Layout::ByteAligned {
+      flags |= BYTE_ALIGNED;
+    }
+    if self.bit_order == BitOrder::LsbFirst {
+      flags |= LSB_FIRST;
+    }
+    if self.profile == Profile::Dense {
+      flags |= DENSE;
+    }
+    if self.checksums {
+      flags |= CHECKSUMS;
+    }
+
+    let mut bytes = [0; LEN];
+    bytes[..MAGIC.len()].copy_from_slice(MAGIC);
+    bytes[MAGIC.len()] = flags;
+    bytes
+  }
+
+  /// Splits the header from the start of a compressed file.
+  pub fn split(bytes: &[u8]) -> Result<(Header, &[u8])> {
+    if bytes.len() < LEN || !bytes.starts_with(MAGIC) {
+      bail!("missing compressed file header");
+    }
+
+    let flags = bytes[MAGIC.len()];
+    let known =
+      UNKNOWN_FIELDS | INDEX | BYTE_ALIGNED | LSB_FIRST | DENSE | CHECKSUMS;
+    if flags & !known != 0 {
+      bail!("unsupported compressed file header flags {:#04x}", flags);
+    }
+    let header = Header {
+      unknown_fields: flags & UNKNOWN_FIELDS != 0,
+      index: flags & INDEX != 0,
+      layout: if flags & BYTE_ALIGNED != 0 {
+        Layout::ByteAligned
+      } else {
+        Layout::Packed
+      },
+      bit_order: if flags & LSB_FIRST != 0 {
+        BitOrder::LsbFirst
+      } else {
+        BitOrder::MsbFirst
+      },
+      profile: if flags & DENSE != 0 {
+        Profile::Dense
+      } else {
+        Profile::Standard
+      },
+      checksums: flags & CHECKSUMS != 0,
+    };
+    Ok((header, &bytes[LEN..]))
+  }
+
+  /// Sets how the object is to be decoded in `options` to the way this
+  /// header says it was encoded.
+  pub fn decode_options(&self, options: DecodeOptions) -> DecodeOptions {
+    DecodeOptions {
+      layout: self.layout,
+      bit_order: self.bit_order,
+      profile: self.profile,
+      checksums: self.checksums,
+      ..options
+    }
+  }
+}
+
+/// The parts of a compressed file.
+#[derive(Clone, Debug, PartialEq)]
+pub struct File<'b> {
+  /// The header of the file.
+  pub header: Header,
+  /// The bytes of the compressed object.
+  pub object: &'b [u8],
+  /// The unknown fields of the object, if they were preserved.
+  pub unknown_fields: Option<UnknownFields>,
+  /// The index of the object, if one was attached.
+  pub index: Option<Index>,
+}
+
+/// Splits a compressed file into its header, object and footers.
+pub fn parse(bytes: &[u8]) -> Result<File<'_>> {
+  let (header, mut object) = Header::split(bytes)?;
+  let mut index = None;
+  if header.index {
+    let (rest, found) = Index::split(object)?;
+    object = rest;
+    index = Some(found);
+  }
+  let mut unknown_fields = None;
+  if header.unknown_fields {
+    let (rest, found) = UnknownFields::split(object)?;
+    object = rest;
+    unknown_fields = Some(found);
+  }
+
+  Ok(File {
+    header,
+    object,
+    unknown_fields,
+    index,
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::bit::BitVec;
+  use crate::encode::EncodeOptions;
+  use crate::schema::{CompositeType, List, Record, Schema, Type};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn string_list_schema() -> Schema {
+    Schema::new(CompositeType::List(List(Box::new(Type::PassThrough))))
+  }
+
+  #[test]
+  fn header_roundtrip() {
+    for flags in 0..64 {
+      let header = Header {
+        unknown_fields: flags & 1 != 0,
+        index: flags & 2 != 0,
+        layout: if flags & 4 != 0 {
+          Layout::ByteAligned
+        } else {
+          Layout::Packed
+        },
+        bit_order: if flags & 8 != 0 {
+          BitOrder::LsbFirst
+        } else {
+          BitOrder::MsbFirst
+        },
+        profile: if flags & 16 != 0 {
+          Profile::Dense
+        } else {
+          Profile::Standard
+        },
+        checksums: flags & 32 != 0,
+      };
+      let mut bytes = header.to_bytes().to_vec();
+      bytes.push(42);
+      assert_eq!((header, &[42][..]), Header::split(&bytes).unwrap());
+    }
+  }
+
+  #[test]
+  fn header_errors() {
+    assert!(Header::split(b"CHI").is_err());
+    assert!(Header::split(b"CHIX\x00").is_err());
+    assert!(Header::split(b"CHIF\x40").is_err());
+  }
+
+  #[test]
+  fn byte_aligned_object_is_decoded_byte_aligned() {
+    let schema = string_list_schema();
+    let value = json!(["a", "bb", "ccc"]);
+    let options = EncodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let co = crate::encode_with(&schema, &value, &options).unwrap();
+    let header = Header {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let mut bytes = header.to_bytes().to_vec();
+    bytes.extend_from_slice(&co.to_bytes());
+
+    let file = parse(&bytes).unwrap();
+    let options = file.header.decode_options(DecodeOptions::default());
+    assert_eq!(Layout::ByteAligned, options.layout);
+    let decoded = crate::decode_with(&schema, file.object, options);
+    assert_eq!(value, decoded.unwrap());
+  }
+
+  #[test]
+  fn footer_magic_in_object_is_not_a_footer() {
+    // The object ends in the index's magic but has no index
+    let schema = string_list_schema();
+    let value = json!(["hello", "ABCDEFGHCHIX"]);
+    let bits: BitVec = crate::encode(&schema, &value).unwrap().into();
+    let object = bits.to_bytes();
+    assert!(object.ends_with(crate::index::MAGIC));
+
+    let mut bytes = Header::default().to_bytes().to_vec();
+    bytes.extend_from_slice(&object);
+    let file = parse(&bytes).unwrap();
+    assert_eq!(&object[..], file.object);
+    assert_eq!(None, file.index);
+    assert_eq!(value, crate::decode(&schema, file.object).unwrap());
+  }
+
+  #[test]
+  fn lsb_first_object_is_decoded_lsb_first() {
+    let schema = string_list_schema();
+    let value = json!(["a", "bb", "ccc"]);
+    let options = EncodeOptions {
+      bit_order: BitOrder::LsbFirst,
+      ..Default::default()
+    };
+    let co = crate::encode_with(&schema, &value, &options).unwrap();
+    let object = co.to_bytes();
+    let mut msb_first = object.clone();
+    BitOrder::LsbFirst.repack(&mut msb_first);
+    assert_eq!(
+      crate::encode(&schema, &value).unwrap().to_bytes(),
+      msb_first
+    );
+
+    let header = Header {
+      bit_order: BitOrder::LsbFirst,
+      ..Default::default()
+    };
+    let mut bytes = header.to_bytes().to_vec();
+    bytes.extend_from_slice(&object);
+    let file = parse(&bytes).unwrap();
+    let options = file.header.decode_options(DecodeOptions::default());
+    let decoded = crate::decode_with(&schema, file.object, options);
+    assert_eq!(value, decoded.unwrap());
+  }
+
+  #[test]
+  fn dense_object_is_decoded_dense() {
+    let mut flags = BTreeMap::new();
+    flags.insert("read".to_string(), Type::Name("bool".to_string()));
+    flags.insert("write".to_string(), Type::Name("bool".to_string()));
+    let mut flags = Record::new(flags);
+    flags.required.insert("read".to_string());
+    flags.required.insert("write".to_string());
+    let schema = Schema::new(CompositeType::List(List(Box::new(
+      Type::Nested(CompositeType::Record(flags)),
+    ))));
+    let value = json!([
+      { "read": true, "write": false },
+      { "read": false, "write": true }
+    ]);
+    let options = EncodeOptions {
+      profile: Profile::Dense,
+      ..Default::default()
+    };
+    let object = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+
+    let header = Header {
+      profile: Profile::Dense,
+      ..Default::default()
+    };
+    let mut bytes = header.to_bytes().to_vec();
+    bytes.extend_from_slice(&object);
+    let file = parse(&bytes).unwrap();
+    let options = file.header.decode_options(DecodeOptions::default());
+    let decoded = crate::decode_with(&schema, file.object, options);
+    assert_eq!(value, decoded.unwrap());
+
+    // Indexes and inspections follow the profile too
+    let index = Index::build_with(&schema, file.object, 1, options).unwrap();
+    let element =
+      crate::decode_element_with(&schema, file.object, &index, 1, options);
+    assert_eq!(value[1], element.unwrap());
+    let inspection =
+      crate::inspect::inspect_with(&schema, file.object, options);
+    assert!(inspection.error.is_none());
+  }
+
+  #[test]
+  fn checksummed_object_is_decoded_with_checksums() {
+    let schema = string_list_schema();
+    let value = json!(["a", "bb", "ccc"]);
+    let options = EncodeOptions {
+      checksums: true,
+      ..Default::default()
+    };
+    let object = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+
+    let header = Header {
+      checksums: true,
+      ..Default::default()
+    };
+    let mut bytes = header.to_bytes().to_vec();
+    bytes.extend_from_slice(&object);
+    let file = parse(&bytes).unwrap();
+    let options = file.header.decode_options(DecodeOptions::default());
+    assert!(options.checksums);
+    let decoded = crate::decode_with(&schema, file.object, options);
+    assert_eq!(value, decoded.unwrap());
+
+    // Indexes and inspections read the checksums too
+    let index = Index::build_with(&schema, file.object, 1, options).unwrap();
+    let element =
+      crate::decode_element_with(&schema, file.object, &index, 2, options);
+    assert_eq!(value[2], element.unwrap());
+    let inspection =
+      crate::inspect::inspect_with(&schema, file.object, options);
+    assert!(inspection.error.is_none());
+  }
+
+  #[test]
+  fn parse_footers() {
+    let schema = string_list_schema();
+    let value = json!(["a", "bb", "ccc"]);
+    let bits: BitVec = crate::encode(&schema, &value).unwrap().into();
+    let object = bits.to_bytes();
+    let index = Index::build(&schema, &object, 2).unwrap();
+    let unknown = UnknownFields::from_bytes(br#"{"/0":{"x":1}}"#).unwrap();
+
+    // Footers hold offsets from the start of the object
+    let mut body = object.clone();
+    unknown.append_to(&mut body);
+    index.append_to(&mut body);
+    let header = Header {
+      unknown_fields: true,
+      index: true,
+      ..Default::default()
+    };
+    let mut bytes = header.to_bytes().to_vec();
+    bytes.extend_from_slice(&body);
+
+    let file = parse(&bytes).unwrap();
+    assert_eq!(header, file.header);
+    assert_eq!(&object[..], file.object);
+    assert_eq!(Some(unknown), file.unknown_fields);
+    assert_eq!(Some(index), file.index);
+  }
+}
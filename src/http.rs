@@ -0,0 +1,484 @@
+//! The `http` module uses chii as an HTTP content-coding, so internal services
+//! which share a schema can exchange compressed bodies instead of JSON text.
+//!
+//! Two tower layers are provided, which makes them usable with axum, hyper or
+//! anything else built on tower:
+//!
+//! * [`CompressResponseLayer`] encodes JSON response bodies when the client
+//!   lists [`CONTENT_CODING`] in its `Accept-Encoding` header and the handler
+//!   attaches a [`ResponseSchema`] to the response. Every other response is
+//!   passed through untouched.
+//! * [`DecompressRequestLayer`] decodes request bodies sent with
+//!   `Content-Encoding: chii` back into JSON text before they reach the
+//!   handler, rejecting bodies which can't be decoded with `400 Bad Request`.
+//!
+//! With axum, a handler supplies the schema as a response extension:
+//!
+//! ```ignore
+//! async fn student(State(schema): State<Arc<Schema>>) -> impl IntoResponse {
+//!   (Extension(ResponseSchema(schema)), Json(load_student()))
+//! }
+//! ```
+//!
+//! Clients can use [`encode_body`] and [`decode_body`] directly.
+
+use crate::schema::Schema;
+use ::http::header::{self, HeaderMap, HeaderValue};
+use ::http::{Request, Response, StatusCode};
+use anyhow::{anyhow, Result};
+use bytes::Bytes;
+use http_body::{Body, SizeHint};
+use serde_json::Value;
+use std::fmt;
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower_layer::Layer;
+use tower_service::Service;
+
+/// The content-coding token used in `Accept-Encoding` and `Content-Encoding`
+/// headers.
+pub const CONTENT_CODING: &str = "chii";
+
+type BoxFuture<T> = Pin<Box<dyn Future<Output = T> + Send>>;
+
+/// Encodes a JSON text body with `schema`.
+pub fn encode_body(schema: &Schema, json: &[u8]) -> Result<Vec<u8>> {
+  let value: Value = serde_json::from_slice(json)?;
+  Ok(crate::encode(schema, &value)?.to_bytes())
+}
+
+/// Decodes a body encoded with `schema` back into JSON text.
+pub fn decode_body(schema: &Schema, bytes: &[u8]) -> Result<Vec<u8>> {
+  let value = crate::decode(schema, bytes)?;
+  Ok(serde_json::to_vec(&value)?)
+}
+
+/// Whether the `Accept-Encoding` headers list chii with a non-zero quality.
+pub fn accepts_chii(headers: &HeaderMap) -> bool {
+  headers
+    .get_all(header::ACCEPT_ENCODING)
+    .iter()
+    .filter_map(|v| v.to_str().ok())
+    .flat_map(|v| v.split(','))
+    .any(|coding| {
+      let mut params = coding.split(';');
+      let name = params.next().unwrap_or_default().trim();
+      name.eq_ignore_ascii_case(CONTENT_CODING)
+        && params.all(|p| !is_zero_quality(p))
+    })
+}
+
+fn is_zero_quality(param: &str) -> bool {
+  let mut kv = param.splitn(2, '=');
+  match (kv.next(), kv.next()) {
+    (Some(k), Some(v)) if k.trim().eq_ignore_ascii_case("q") => {
+      matches!(v.trim().parse::<f32>(), Ok(q) if q == 0.0)
+    }
+    _ => false,
+  }
+}
+
+/// Whether the body is encoded with chii, and only chii.
+fn is_chii_coded(headers: &HeaderMap) -> bool {
+  headers
+    .get(header::CONTENT_ENCODING)
+    .and_then(|v| v.to_str().ok())
+    .is_some_and(|v| v.trim().eq_ignore_ascii_case(CONTENT_CODING))
+}
+
+/// Reads the whole of `body` into memory.
+async fn collect<B>(mut body: B) -> Result<Vec<u8>, B::Error>
+where
+  B: Body<Data = Bytes> + Unpin,
+{
+  let mut bytes = Vec::new();
+  while let Some(chunk) = body.data().await {
+    bytes.extend_from_slice(&chunk?);
+  }
+  Ok(bytes)
+}
+
+/// A response extension holding the schema to encode the response body with.
+#[derive(Clone, Debug)]
+pub struct ResponseSchema(pub Arc<Schema>);
+
+/// The body of a request or response which passed through one of the layers.
+#[derive(Debug)]
+pub enum ChiiBody<B> {
+  /// The whole body after being encoded or decoded.
+  Coded(Option<Bytes>),
+  /// The original body, which was left alone.
+  Original(B),
+}
+
+impl<B> ChiiBody<B> {
+  fn coded(bytes: Vec<u8>) -> Self {
+    ChiiBody::Coded(Some(Bytes::from(bytes)))
+  }
+}
+
+/// An empty body.
+impl<B> Default for ChiiBody<B> {
+  fn default() -> Self {
+    ChiiBody::Coded(None)
+  }
+}
+
+impl<B> Body for ChiiBody<B>
+where
+  B: Body<Data = Bytes> + Unpin,
+{
+  type Data = Bytes;
+  type Error = B::Error;
+
+  fn poll_data(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Option<Result<Bytes, B::Error>>> {
+    match self.get_mut() {
+      ChiiBody::Coded(bytes) => Poll::Ready(bytes.take().map(Ok)),
+      ChiiBody::Original(body) => Pin::new(body).poll_data(cx),
+    }
+  }
+
+  fn poll_trailers(
+    self: Pin<&mut Self>,
+    cx: &mut Context<'_>,
+  ) -> Poll<Result<Option<HeaderMap>, B::Error>> {
+    match self.get_mut() {
+      ChiiBody::Coded(_) => Poll::Ready(Ok(None)),
+      ChiiBody::Original(body) => Pin::new(body).poll_trailers(cx),
+    }
+  }
+
+  fn is_end_stream(&self) -> bool {
+    match self {
+      ChiiBody::Coded(bytes) => bytes.is_none(),
+      ChiiBody::Original(body) => body.is_end_stream(),
+    }
+  }
+
+  fn size_hint(&self) -> SizeHint {
+    match self {
+      ChiiBody::Coded(bytes) => {
+        SizeHint::with_exact(bytes.as_ref().map_or(0, |b| b.len() as u64))
+      }
+      ChiiBody::Original(body) => body.size_hint(),
+    }
+  }
+}
+
+/// Applies [`CompressResponse`] to a service.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct CompressResponseLayer;
+
+impl<S> Layer<S> for CompressResponseLayer {
+  type Service = CompressResponse<S>;
+
+  fn layer(&self, inner: S) -> CompressResponse<S> {
+    CompressResponse { inner }
+  }
+}
+
+/// Encodes the JSON bodies of responses which carry a [`ResponseSchema`] when
+/// the client accepts chii.
+///
+/// The whole body is buffered before encoding. If it can't be encoded, for
+/// example because it doesn't match the schema, a
+/// `500 Internal Server Error` is returned in its place.
+#[derive(Clone, Debug)]
+pub struct CompressResponse<S> {
+  inner: S,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for CompressResponse<S>
+where
+  S: Service<Request<ReqBody>, Response = Response<ResBody>>,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+  ResBody: Body<Data = Bytes> + Unpin + Send + 'static,
+  ResBody::Error: fmt::Display,
+{
+  type Response = Response<ChiiBody<ResBody>>;
+  type Error = S::Error;
+  type Future = BoxFuture<Result<Self::Response, S::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+    let accepted = accepts_chii(req.headers());
+    let response = self.inner.call(req);
+    Box::pin(async move {
+      let mut response = response.await?;
+      let schema = match response.extensions_mut().remove::<ResponseSchema>() {
+        Some(ResponseSchema(schema)) => schema,
+        None => return Ok(response.map(ChiiBody::Original)),
+      };
+
+      // The body depends on the client's codings from here on
+      let headers = response.headers_mut();
+      headers.append(header::VARY, HeaderValue::from_static("accept-encoding"));
+      if !accepted || headers.contains_key(header::CONTENT_ENCODING) {
+        return Ok(response.map(ChiiBody::Original));
+      }
+
+      let (mut parts, body) = response.into_parts();
+      let encoded = collect(body)
+        .await
+        .map_err(|e| anyhow!("unable to read body: {}", e))
+        .and_then(|json| encode_body(&schema, &json));
+      let encoded = match encoded {
+        Ok(encoded) => encoded,
+        Err(e) => {
+          let message = format!("unable to encode response: {:#}", e);
+          let mut response = Response::new(ChiiBody::coded(message.into()));
+          *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+          return Ok(response);
+        }
+      };
+
+      parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(CONTENT_CODING),
+      );
+      parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(encoded.len()));
+      Ok(Response::from_parts(parts, ChiiBody::coded(encoded)))
+    })
+  }
+}
+
+/// Applies [`DecompressRequest`] to a service.
+#[derive(Clone, Debug)]
+pub struct DecompressRequestLayer {
+  schema: Arc<Schema>,
+}
+
+impl DecompressRequestLayer {
+  /// Constructs a layer which decodes request bodies using `schema`.
+  pub fn new(schema: Arc<Schema>) -> Self {
+    DecompressRequestLayer { schema }
+  }
+}
+
+impl<S> Layer<S> for DecompressRequestLayer {
+  type Service = DecompressRequest<S>;
+
+  fn layer(&self, inner: S) -> DecompressRequest<S> {
+    DecompressRequest {
+      inner,
+      schema: self.schema.clone(),
+    }
+  }
+}
+
+/// Decodes the bodies of requests sent with `Content-Encoding: chii` into
+/// JSON text.
+///
+/// Requests whose bodies can't be decoded are answered with
+/// `400 Bad Request` without calling the inner service.
+#[derive(Clone, Debug)]
+pub struct DecompressRequest<S> {
+  inner: S,
+  schema: Arc<Schema>,
+}
+
+impl<S, ReqBody, ResBody> Service<Request<ReqBody>> for DecompressRequest<S>
+where
+  S: Service<Request<ChiiBody<ReqBody>>, Response = Response<ResBody>>
+    + Clone
+    + Send
+    + 'static,
+  S::Future: Send + 'static,
+  S::Error: Send + 'static,
+  ReqBody: Body<Data = Bytes> + Unpin + Send + 'static,
+  ResBody: Default + Send + 'static,
+{
+  type Response = Response<ResBody>;
+  type Error = S::Error;
+  type Future = BoxFuture<Result<Self::Response, S::Error>>;
+
+  fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), S::Error>> {
+    self.inner.poll_ready(cx)
+  }
+
+  fn call(&mut self, req: Request<ReqBody>) -> Self::Future {
+    if !is_chii_coded(req.headers()) {
+      return Box::pin(self.inner.call(req.map(ChiiBody::Original)));
+    }
+
+    // The body has to be read before the inner service is called, so call
+    // the instance which was driven to readiness and leave a clone behind
+    let clone = self.inner.clone();
+    let mut inner = std::mem::replace(&mut self.inner, clone);
+    let schema = self.schema.clone();
+    Box::pin(async move {
+      let (mut parts, body) = req.into_parts();
+      let json = match collect(body).await {
+        Ok(bytes) => decode_body(&schema, &bytes),
+        Err(_) => Err(anyhow!("unable to read body")),
+      };
+      let json = match json {
+        Ok(json) => json,
+        Err(_) => {
+          let mut response = Response::new(ResBody::default());
+          *response.status_mut() = StatusCode::BAD_REQUEST;
+          return Ok(response);
+        }
+      };
+
+      parts.headers.remove(header::CONTENT_ENCODING);
+      parts
+        .headers
+        .insert(header::CONTENT_LENGTH, HeaderValue::from(json.len()));
+      inner
+        .call(Request::from_parts(parts, ChiiBody::coded(json)))
+        .await
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{CompositeType, Record, Type};
+  use http_body::Full;
+  use serde_json::json;
+  use std::collections::BTreeMap;
+  use std::convert::Infallible;
+
+  fn block_on<F: Future>(f: F) -> F::Output {
+    let rt = tokio::runtime::Builder::new_current_thread()
+      .build()
+      .unwrap();
+    rt.block_on(f)
+  }
+
+  fn schema() -> Arc<Schema> {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    Arc::new(Schema::new(CompositeType::Record(Record::new(fields))))
+  }
+
+  /// Responds with the request body, encoded with the schema if
+  /// `with_schema` is set.
+  #[derive(Clone)]
+  struct Echo {
+    with_schema: bool,
+  }
+
+  impl<B> Service<Request<B>> for Echo
+  where
+    B: Body<Data = Bytes> + Unpin + Send + 'static,
+    B::Error: fmt::Debug,
+  {
+    type Response = Response<Full<Bytes>>;
+    type Error = Infallible;
+    type Future = BoxFuture<Result<Self::Response, Infallible>>;
+
+    fn poll_ready(
+      &mut self,
+      _: &mut Context<'_>,
+    ) -> Poll<Result<(), Infallible>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+      let with_schema = self.with_schema;
+      Box::pin(async move {
+        let body = collect(req.into_body()).await.unwrap();
+        let mut response = Response::new(Full::new(Bytes::from(body)));
+        if with_schema {
+          response.extensions_mut().insert(ResponseSchema(schema()));
+        }
+        Ok(response)
+      })
+    }
+  }
+
+  fn request(body: Vec<u8>, headers: &[(&str, &str)]) -> Request<Full<Bytes>> {
+    let mut request = Request::new(Full::new(Bytes::from(body)));
+    for (name, value) in headers {
+      let name = match *name {
+        "accept-encoding" => header::ACCEPT_ENCODING,
+        "content-encoding" => header::CONTENT_ENCODING,
+        _ => unreachable!(),
+      };
+      request.headers_mut().append(name, value.parse().unwrap());
+    }
+    request
+  }
+
+  fn read<B>(response: Response<B>) -> Vec<u8>
+  where
+    B: Body<Data = Bytes> + Unpin,
+    B::Error: fmt::Debug,
+  {
+    block_on(collect(response.into_body())).unwrap()
+  }
+
+  #[test]
+  fn round_trip_through_both_layers() {
+    let json = serde_json::to_vec(&json!({ "active": true, "name": "x" }));
+    let body = encode_body(&schema(), &json.unwrap()).unwrap();
+
+    let mut service = DecompressRequestLayer::new(schema())
+      .layer(CompressResponseLayer.layer(Echo { with_schema: true }));
+    let request = request(
+      body.clone(),
+      &[
+        ("content-encoding", "chii"),
+        ("accept-encoding", "gzip, chii"),
+      ],
+    );
+    let response = block_on(service.call(request)).unwrap();
+
+    assert_eq!(StatusCode::OK, response.status());
+    let coding = response.headers().get(header::CONTENT_ENCODING).unwrap();
+    assert_eq!(CONTENT_CODING, coding.to_str().unwrap());
+    assert_eq!(body, read(response));
+  }
+
+  #[test]
+  fn responses_are_left_alone_unless_accepted() {
+    let json = br#"{"name":"x"}"#.to_vec();
+    let mut service = CompressResponseLayer.layer(Echo { with_schema: true });
+    let response = block_on(service.call(request(json.clone(), &[]))).unwrap();
+    assert!(response.headers().get(header::CONTENT_ENCODING).is_none());
+    assert_eq!(json, read(response));
+
+    let mut service = CompressResponseLayer.layer(Echo { with_schema: false });
+    let request = request(json.clone(), &[("accept-encoding", "chii")]);
+    let response = block_on(service.call(request)).unwrap();
+    assert_eq!(json, read(response));
+  }
+
+  #[test]
+  fn undecodable_requests_are_rejected() {
+    let mut service =
+      DecompressRequestLayer::new(schema()).layer(Echo { with_schema: false });
+    let request = request(vec![0xff; 4], &[("content-encoding", "chii")]);
+    let response = block_on(service.call(request)).unwrap();
+    assert_eq!(StatusCode::BAD_REQUEST, response.status());
+  }
+
+  #[test]
+  fn accept_encoding_parsing() {
+    let accepts = |value: &str| {
+      let mut headers = HeaderMap::new();
+      headers.insert(header::ACCEPT_ENCODING, value.parse().unwrap());
+      accepts_chii(&headers)
+    };
+    assert!(accepts("chii"));
+    assert!(accepts("gzip;q=1.0, CHII;q=0.5"));
+    assert!(!accepts("gzip, br"));
+    assert!(!accepts("chii;q=0"));
+    assert!(!accepts("chiikawa"));
+  }
+}
@@ -0,0 +1,272 @@
+//! The `index` module implements an optional footer index for compressed
+//! objects whose root is a list.
+//!
+//! The index records the bit offset of every `stride`th element allowing the
+//! decoder to seek close to an arbitrary element instead of scanning the whole
+//! object. When attached to a compressed object, the index is laid out after
+//! the object's bytes like so:
+//!
+//! ```text
+//! object | stride | length | offsets... | index offset | MAGIC
+//! ```
+//!
+//! The stride, list length, and offsets are VIE encoded with each offset
+//! stored as the difference from the previous one. The index offset is an 8
+//! byte little endian integer holding the byte position of the start of the
+//! index.
+
+use crate::decode::{read_length, reader, skip_elements, DecodeOptions};
+use crate::math;
+use crate::prelude::*;
+use crate::schema::{CompositeType, Schema, Type};
+use crate::vie::CodePoint;
+use anyhow::{anyhow, bail, Result};
+
+/// Magic bytes found at the very end of an object with an attached index.
+pub const MAGIC: &[u8; 4] = b"CHIX";
+
+/// Size of the trailer (index offset + magic) in bytes.
+const TRAILER_LEN: usize = 8 + MAGIC.len();
+
+/// Bit offsets of the elements of a root list.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Index {
+  stride: usize,
+  len: usize,
+  offsets: Vec<u64>,
+}
+
+impl Index {
+  /// Builds an index for an encoded compressed object by scanning over its
+  /// elements, recording the offset of every `stride`th one.
+  pub fn build(schema: &Schema, bytes: &[u8], stride: usize) -> Result<Self> {
+    Index::build_with(schema, bytes, stride, DecodeOptions::default())
+  }
+
+  /// Builds an index for a compressed object which was encoded in the way
+  /// described by `options`.
+  pub fn build_with(
+    schema: &Schema,
+    bytes: &[u8],
+    stride: usize,
+    options: DecodeOptions,
+  ) -> Result<Self> {
+    let list = match schema.root() {
+      Type::Nested(CompositeType::List(l)) => l,
+      _ => bail!("root type is not a list"),
+    };
+    if stride == 0 {
+      bail!("index stride must be greater than 0");
+    }
+
+    let mut r = reader(schema, bytes, &options);
+    let len = read_length(&mut r)?;
+    let mut offsets = Vec::new();
+    for start in (0..len).step_by(stride) {
+      offsets.push(r.position() as u64);
+      skip_elements(list, start..len.min(start + stride), len, true, &mut r)?;
+    }
+
+    Ok(Index {
+      stride,
+      len,
+      offsets,
+    })
+  }
+
+  /// The number of elements between each indexed offset.
+  #[inline]
+  pub fn stride(&self) -> usize {
+    self.stride
+  }
+
+  /// The number of elements in the indexed list.
+  #[inline]
+  pub fn len(&self) -> usize {
+    self.len
+  }
+
+  /// Returns `true` if the indexed list has no elements.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.len == 0
+  }
+
+  /// The bit offsets of every `stride`th element.
+  #[inline]
+  pub fn offsets(&self) -> &[u64] {
+    &self.offsets
+  }
+
+  /// The offset of the closest indexed element at or before element `n`.
+  pub(crate) fn seek_offset(&self, n: usize) -> Result<u64> {
+    if n >= self.len {
+      bail!(
+        "element {} is out of bounds for list of length {}",
+        n,
+        self.len
+      );
+    }
+    self
+      .offsets
+      .get(n / self.stride)
+      .copied()
+      .ok_or_else(|| anyhow!("index is missing an offset for element {}", n))
+  }
+
+  /// Serializes this index to bytes.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend_from_slice(CodePoint::from(self.stride as u64).bytes());
+    bytes.extend_from_slice(CodePoint::from(self.len as u64).bytes());
+    let mut prev = 0;
+    for &offset in &self.offsets {
+      bytes.extend_from_slice(CodePoint::from(offset - prev).bytes());
+      prev = offset;
+    }
+    bytes
+  }
+
+  /// Deserializes an index from bytes produced by [`to_bytes`].
+  ///
+  /// [`to_bytes`]: Index::to_bytes
+  pub fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+    let mut next = || -> Result<u64> {
+      let cp =
+        CodePoint::parse(bytes).ok_or_else(|| anyhow!("index is truncated"))?;
+      bytes = &bytes[cp.count()..];
+      cp.decode::<u64>()
+        .ok_or_else(|| anyhow!("index value overflow"))
+    };
+
+    let stride = next()? as usize;
+    let len = next()? as usize;
+    if stride == 0 {
+      bail!("index stride must be greater than 0");
+    }
+
+    let count = math::div_ceil(len, stride);
+    let mut offsets = Vec::with_capacity(count.min(1 << 16));
+    let mut prev = 0u64;
+    for _ in 0..count {
+      prev += next()?;
+      offsets.push(prev);
+    }
+
+    Ok(Index {
+      stride,
+      len,
+      offsets,
+    })
+  }
+
+  /// Appends this index as a footer to the bytes of a compressed object.
+  pub fn append_to(&self, object: &mut Vec<u8>) {
+    let start = object.len() as u64;
+    object.extend_from_slice(&self.to_bytes());
+    object.extend_from_slice(&start.to_le_bytes());
+    object.extend_from_slice(MAGIC);
+  }
+
+  /// Splits the bytes of a compressed object from the index footer at their
+  /// end.
+  ///
+  /// Whether an object has an index can't be told from its bytes, which may
+  /// happen to end in [`MAGIC`], so this is only called for objects known to
+  /// have one, such as those in a [file](crate::header) whose header says so.
+  pub fn split(bytes: &[u8]) -> Result<(&[u8], Index)> {
+    if bytes.len() < TRAILER_LEN || !bytes.ends_with(MAGIC) {
+      bail!("missing index footer");
+    }
+
+    let trailer = &bytes[bytes.len() - TRAILER_LEN..];
+    let mut start = [0u8; 8];
+    start.copy_from_slice(&trailer[..8]);
+    let start = u64::from_le_bytes(start) as usize;
+    if start > bytes.len() - TRAILER_LEN {
+      bail!("index offset is out of bounds");
+    }
+
+    let index = Index::from_bytes(&bytes[start..bytes.len() - TRAILER_LEN])?;
+    Ok((&bytes[..start], index))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::bit::BitVec;
+  use crate::data::Layout;
+  use crate::schema::List;
+  use serde_json::{json, Value};
+
+  fn string_list_schema() -> Schema {
+    Schema::new(CompositeType::List(List(Box::new(Type::PassThrough))))
+  }
+
+  fn encode_bytes(schema: &Schema, value: &Value) -> Vec<u8> {
+    let bits: BitVec = crate::encode(schema, value).unwrap().into();
+    bits.to_bytes()
+  }
+
+  #[test]
+  fn index_roundtrip_through_footer() {
+    let schema = string_list_schema();
+    let value = json!(["a", "bb", "ccc", "dddd", "eeeee"]);
+    let mut bytes = encode_bytes(&schema, &value);
+    let index = Index::build(&schema, &bytes, 2).unwrap();
+    assert_eq!(3, index.offsets().len());
+
+    index.append_to(&mut bytes);
+    let (body, found) = Index::split(&bytes).unwrap();
+    assert_eq!(index, found);
+    assert_eq!(value, crate::decode(&schema, body).unwrap());
+  }
+
+  #[test]
+  fn decode_element_with_index() {
+    let value = json!(["a", "bb", "ccc", "dddd", "eeeee"]);
+    // Indexed elements may fall at the start of a chunk or within one
+    for chunks in &[None, Some(2), Some(3)] {
+      let schema = match chunks {
+        Some(size) => string_list_schema().with_chunks(*size),
+        None => string_list_schema(),
+      };
+      let bytes = encode_bytes(&schema, &value);
+      let index = Index::build(&schema, &bytes, 2).unwrap();
+
+      for (i, expected) in value.as_array().unwrap().iter().enumerate() {
+        let v = crate::decode_element(&schema, &bytes, &index, i).unwrap();
+        assert_eq!(expected, &v);
+      }
+      assert!(crate::decode_element(&schema, &bytes, &index, 5).is_err());
+    }
+  }
+
+  #[test]
+  fn decode_byte_aligned_element_with_index() {
+    let schema = string_list_schema().with_chunks(2);
+    let value = json!(["a", "bb", "ccc", "dddd", "eeeee"]);
+    let options = crate::EncodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let co = crate::encode_with(&schema, &value, &options).unwrap();
+    let bytes = co.to_bytes();
+    let options = DecodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let index = Index::build_with(&schema, &bytes, 2, options).unwrap();
+
+    for (i, expected) in value.as_array().unwrap().iter().enumerate() {
+      let v = crate::decode_element_with(&schema, &bytes, &index, i, options);
+      assert_eq!(expected, &v.unwrap());
+    }
+  }
+
+  #[test]
+  fn split_without_index() {
+    assert!(Index::split(&[1, 2, 3]).is_err());
+  }
+}
@@ -0,0 +1,555 @@
+//! The `inspect` module walks the blocks of a compressed object, reporting
+//! where each one is and what it means. It is intended for debugging encoders
+//! and corrupted files.
+
+use crate::bit::{BitReader, BitVec};
+use crate::comp::EncodedWidth;
+use crate::data::BitOrder;
+use crate::decode::{
+  decode_value, read_chunk_header, read_length, read_list_length, read_sync,
+  read_tag, reader, DecodeOptions, Fields,
+};
+use crate::encode::get_compressor_for_type;
+use crate::error::within_path;
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use crate::schema::{CompositeType, List, Schema, Type};
+use anyhow::Result;
+use core::fmt;
+use core::ops::Range;
+use serde_json::{Map, Value};
+
+/// The kind of a block found by [`inspect`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlockKind {
+  /// The header of a nested record.
+  RecordHeader,
+  /// The header of a list holding `len` elements.
+  ListHeader { len: usize },
+  /// The header of a chunk of a list whose elements take up `bits` bits.
+  ChunkHeader { bits: usize },
+  /// The sync block before an element or chunk of a root list, which is set
+  /// if it holds a sync `marker`.
+  Sync { marker: bool },
+  /// A non-nested record field.
+  Field,
+  /// A non-nested list element or scalar root value.
+  Element,
+  /// The tag in front of an element of a union, holding the index of the
+  /// element's type.
+  Tag { tag: usize },
+  /// The flag at the start of a sparse record, which is set if its fields
+  /// are marked with the differences between their markers.
+  Layout { sparse: bool },
+  /// The end of a nested record.
+  Terminator,
+}
+
+impl BlockKind {
+  /// A machine-readable name for this kind of block.
+  pub fn name(&self) -> &'static str {
+    match self {
+      BlockKind::RecordHeader => "record_header",
+      BlockKind::ListHeader { .. } => "list_header",
+      BlockKind::ChunkHeader { .. } => "chunk_header",
+      BlockKind::Sync { .. } => "sync",
+      BlockKind::Field => "field",
+      BlockKind::Element => "element",
+      BlockKind::Tag { .. } => "tag",
+      BlockKind::Layout { .. } => "layout",
+      BlockKind::Terminator => "terminator",
+    }
+  }
+}
+
+impl fmt::Display for BlockKind {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      BlockKind::RecordHeader => write!(f, "record"),
+      BlockKind::ListHeader { len } => write!(f, "list({})", len),
+      BlockKind::ChunkHeader { bits } => write!(f, "chunk({})", bits),
+      BlockKind::Sync { marker: true } => write!(f, "sync(marker)"),
+      BlockKind::Sync { marker: false } => write!(f, "sync"),
+      BlockKind::Field => write!(f, "field"),
+      BlockKind::Element => write!(f, "element"),
+      BlockKind::Tag { tag } => write!(f, "tag({})", tag),
+      BlockKind::Layout { sparse: true } => write!(f, "sparse"),
+      BlockKind::Layout { sparse: false } => write!(f, "dense"),
+      BlockKind::Terminator => write!(f, "end"),
+    }
+  }
+}
+
+/// The kind of a section of a block found by [`inspect`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SectionKind {
+  /// The layout flag of a sparse record, or the flag of a sync block.
+  Flag,
+  /// A field marker, or the zero marker which ends a record.
+  Marker,
+  /// The tag in front of an element of a union.
+  Tag,
+  /// The length of a list, chunk or variable-width value, or the index held
+  /// by a sync marker.
+  Length,
+  /// The magic bits which start a sync marker.
+  Magic,
+  /// The data of a non-nested value.
+  Data,
+}
+
+impl SectionKind {
+  /// A machine-readable name for this kind of section.
+  pub fn name(&self) -> &'static str {
+    match self {
+      SectionKind::Flag => "flag",
+      SectionKind::Marker => "marker",
+      SectionKind::Tag => "tag",
+      SectionKind::Length => "length",
+      SectionKind::Magic => "magic",
+      SectionKind::Data => "data",
+    }
+  }
+}
+
+/// One of the sections a block is made up of, such as its field marker or
+/// its data.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Section {
+  pub kind: SectionKind,
+  /// The range of bits this section takes up.
+  pub span: Range<usize>,
+}
+
+/// A block of a compressed object along with its location and meaning.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BlockInfo {
+  pub kind: BlockKind,
+  /// The path of the value this block belongs to.
+  pub path: Path,
+  /// The value of the block's field marker, which is the field's id plus
+  /// one, if it belongs to a record field.
+  pub marker: Option<u64>,
+  /// The range of bits this block takes up.
+  pub span: Range<usize>,
+  /// The decoded value of field and element blocks.
+  pub value: Option<Value>,
+  /// The sections which make up the block, in order. Blocks which take up
+  /// no bits have none.
+  pub sections: Vec<Section>,
+}
+
+impl BlockInfo {
+  /// The raw bits of this block within `bytes`, which are packed most
+  /// significant bit first.
+  pub fn bits(&self, bytes: &[u8]) -> Option<BitVec> {
+    self.bits_with(bytes, BitOrder::MsbFirst)
+  }
+
+  /// The raw bits of this block within `bytes`, which are packed in
+  /// `bit_order`.
+  pub fn bits_with(&self, bytes: &[u8], bit_order: BitOrder) -> Option<BitVec> {
+    let mut r = BitReader::new(bytes).with_bit_order(bit_order);
+    r.seek(self.span.start)?;
+    r.read_bits(self.span.len())
+  }
+
+  /// A JSON representation of this block.
+  ///
+  /// ```text
+  /// {"kind":"field","offset":0,"width":3,"path":"/active","field":"active",
+  ///  "id":1,"value":true}
+  /// ```
+  ///
+  /// `field` and `id` are `null` for blocks which don't belong to a record
+  /// field, `length` is only present for list headers, `bits` only for chunk
+  /// headers, `marker` only for sync blocks, `tag` only for union tags,
+  /// `sparse` only for the layout flags of sparse records and `value` only
+  /// for fields and elements.
+  pub fn to_json(&self) -> Value {
+    let field = match self.path.segments().last() {
+      Some(Segment::Field(name)) if self.marker.is_some() => {
+        Value::from(name.as_str())
+      }
+      _ => Value::Null,
+    };
+
+    let mut map = Map::new();
+    map.insert("kind".to_string(), Value::from(self.kind.name()));
+    map.insert("offset".to_string(), Value::from(self.span.start));
+    map.insert("width".to_string(), Value::from(self.span.len()));
+    map.insert("path".to_string(), Value::from(self.path.to_pointer()));
+    map.insert("field".to_string(), field);
+    map.insert(
+      "id".to_string(),
+      self.marker.map_or(Value::Null, Value::from),
+    );
+    if let BlockKind::ListHeader { len } = self.kind {
+      map.insert("length".to_string(), Value::from(len));
+    }
+    if let BlockKind::ChunkHeader { bits } = self.kind {
+      map.insert("bits".to_string(), Value::from(bits));
+    }
+    if let BlockKind::Sync { marker } = self.kind {
+      map.insert("marker".to_string(), Value::from(marker));
+    }
+    if let BlockKind::Tag { tag } = self.kind {
+      map.insert("tag".to_string(), Value::from(tag));
+    }
+    if let BlockKind::Layout { sparse } = self.kind {
+      map.insert("sparse".to_string(), Value::from(sparse));
+    }
+    if let Some(value) = &self.value {
+      map.insert("value".to_string(), value.clone());
+    }
+    Value::Object(map)
+  }
+}
+
+/// The result of inspecting a compressed object.
+#[derive(Debug)]
+pub struct Inspection {
+  /// Every block which was read successfully, in order.
+  pub blocks: Vec<BlockInfo>,
+  /// The error which stopped the inspection, if any.
+  pub error: Option<anyhow::Error>,
+}
+
+/// Walks the blocks of a compressed object.
+///
+/// Unlike decoding, inspecting a corrupted object doesn't just fail. All of
+/// the blocks up until the point of corruption are returned along with the
+/// error.
+pub fn inspect(schema: &Schema, bytes: &[u8]) -> Inspection {
+  inspect_with(schema, bytes, DecodeOptions::default())
+}
+
+/// Walks the blocks of a compressed object which was encoded in the way
+/// described by `options`.
+pub fn inspect_with(
+  schema: &Schema,
+  bytes: &[u8],
+  options: DecodeOptions,
+) -> Inspection {
+  let mut inspector = Inspector {
+    r: reader(schema, bytes, &options),
+    path: Vec::new(),
+    marker: None,
+    sections: Vec::new(),
+    blocks: Vec::new(),
+  };
+
+  let result = match schema.root() {
+    Type::Nested(CompositeType::Record(record)) => {
+      inspector.record(Fields::new(record, true))
+    }
+    ty => inspector.value(ty, false, 0, BlockKind::Element),
+  };
+
+  let path = Path(inspector.path);
+  Inspection {
+    blocks: inspector.blocks,
+    error: result.err().map(|e| within_path(e, &path, "inspecting")),
+  }
+}
+
+struct Inspector<'b> {
+  r: BitReader<'b>,
+  /// The path to the value currently being inspected.
+  path: Vec<Segment>,
+  /// The marker of the field currently being inspected, until its first
+  /// block is pushed.
+  marker: Option<u64>,
+  /// The sections of the next block to be pushed which have been read.
+  sections: Vec<Section>,
+  blocks: Vec<BlockInfo>,
+}
+
+impl<'b> Inspector<'b> {
+  fn push(&mut self, kind: BlockKind, start: usize, value: Option<Value>) {
+    self.blocks.push(BlockInfo {
+      kind,
+      path: Path(self.path.clone()),
+      marker: self.marker.take(),
+      span: start..self.r.position(),
+      value,
+      sections: core::mem::take(&mut self.sections),
+    });
+  }
+
+  /// Adds a section of the next block, unless it is empty.
+  fn section(&mut self, kind: SectionKind, span: Range<usize>) {
+    if !span.is_empty() {
+      self.sections.push(Section { kind, span });
+    }
+  }
+
+  fn record(&mut self, mut fields: Fields) -> Result<()> {
+    let start = self.r.position();
+    if let Some(sparse) = fields.read_layout(&mut self.r)? {
+      self.section(SectionKind::Flag, start..self.r.position());
+      self.push(BlockKind::Layout { sparse }, start, None);
+    }
+    loop {
+      let start = self.r.position();
+      match fields.next(&mut self.r)? {
+        Some((name, ty)) => {
+          self.path.push(Segment::Field(name.clone()));
+          // The fields of inline records are inspected as if they were
+          // nested, though they have no header or terminator
+          match fields.inline(name) {
+            Some(inline) => self.record(inline)?,
+            None => {
+              self.section(SectionKind::Marker, start..self.r.position());
+              self.marker = fields.marker(name);
+              let nullable = fields.record.is_nullable(name);
+              self.value(ty, nullable, start, BlockKind::Field)?;
+            }
+          }
+          self.path.pop();
+        }
+        None => {
+          // The root record has no terminator block unless it is sparse
+          if fields.has_terminator() {
+            self.section(SectionKind::Marker, start..self.r.position());
+            self.push(BlockKind::Terminator, start, None);
+          }
+          return Ok(());
+        }
+      }
+    }
+  }
+
+  /// Inspects a list whose header holding `len` elements started at bit
+  /// position `start`.
+  fn list(&mut self, list: &List, len: usize, start: usize) -> Result<()> {
+    self.push(BlockKind::ListHeader { len }, start, None);
+    // Only the elements of a root list are preceded by sync blocks
+    let root = self.path.is_empty();
+    for i in 0..len {
+      let start = self.r.position();
+      if root {
+        let marker = read_sync(i, len, &mut self.r)?;
+        let end = self.r.position();
+        if marker {
+          // The flag is followed by the magic bits and the element's index
+          self.section(SectionKind::Flag, start..start + 1);
+          self.section(SectionKind::Magic, start + 1..start + 65);
+          self.section(SectionKind::Length, start + 65..end);
+        } else {
+          self.section(SectionKind::Flag, start..end);
+        }
+        if end > start {
+          self.push(BlockKind::Sync { marker }, start, None);
+        }
+      }
+      let start = self.r.position();
+      if let Some(bits) = read_chunk_header(i, len, &mut self.r)? {
+        self.section(SectionKind::Length, start..self.r.position());
+        self.push(BlockKind::ChunkHeader { bits }, start, None);
+      }
+      self.path.push(Segment::Index(i));
+      let start = self.r.position();
+      let ty = read_tag(&list.0, &mut self.r)?;
+      if let Type::Union { alternatives } = list.0.as_ref() {
+        let tag = alternatives.iter().position(|alt| core::ptr::eq(alt, ty));
+        self.section(SectionKind::Tag, start..self.r.position());
+        self.push(BlockKind::Tag { tag: tag.unwrap() }, start, None);
+      }
+      let start = self.r.position();
+      self.value(ty, false, start, BlockKind::Element)?;
+      self.path.pop();
+    }
+    Ok(())
+  }
+
+  /// Inspects a value whose header (if any) started at bit position `start`.
+  /// Non-nested values, and lists which are null because they are `nullable`,
+  /// are reported as blocks of a given `kind`.
+  fn value(
+    &mut self,
+    ty: &Type,
+    nullable: bool,
+    start: usize,
+    kind: BlockKind,
+  ) -> Result<()> {
+    match ty {
+      Type::Nested(CompositeType::Record(record)) => {
+        self.push(BlockKind::RecordHeader, start, None);
+        self.record(Fields::new(record, false))
+      }
+      Type::Nested(CompositeType::List(list)) => {
+        let length = self.r.position();
+        let len = read_list_length(&mut self.r, nullable)?;
+        self.section(SectionKind::Length, length..self.r.position());
+        match len {
+          Some(len) => self.list(list, len, start),
+          None => {
+            self.push(kind, start, Some(Value::Null));
+            Ok(())
+          }
+        }
+      }
+      ty => {
+        // The data of a variable-width value follows its length
+        let length = self.r.position();
+        let mut data = self.r.clone();
+        let compressor = get_compressor_for_type(ty)?;
+        if compressor.encoded_width() == EncodedWidth::Variable {
+          read_length(&mut data)?;
+        }
+        let value = decode_value(ty, &mut self.r)?;
+        self.section(SectionKind::Length, length..data.position());
+        self.section(SectionKind::Data, data.position()..self.r.position());
+        self.push(kind, start, Some(value));
+        Ok(())
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::data::Layout;
+  use crate::schema::Record;
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    let mut student = BTreeMap::new();
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    Schema::new(CompositeType::Record(Record::new(student)))
+  }
+
+  #[test]
+  fn inspect_blocks() {
+    let schema = schema();
+    let value = json!({ "active": true, "courses": [{ "name": "Art" }] });
+    let bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+
+    let inspection = inspect(&schema, &bytes);
+    assert!(inspection.error.is_none());
+    let blocks: Vec<_> = inspection
+      .blocks
+      .iter()
+      .map(|b| (b.kind, b.path.to_string(), b.span.clone()))
+      .collect();
+    assert_eq!(
+      vec![
+        (BlockKind::Field, ".active".to_string(), 0..3),
+        (
+          BlockKind::ListHeader { len: 1 },
+          ".courses".to_string(),
+          3..13
+        ),
+        (BlockKind::RecordHeader, ".courses[0]".to_string(), 13..13),
+        (BlockKind::Field, ".courses[0].name".to_string(), 13..46),
+        (BlockKind::Terminator, ".courses[0]".to_string(), 46..47),
+      ],
+      blocks
+    );
+    assert_eq!(Some(json!("Art")), inspection.blocks[3].value);
+
+    let bits = inspection.blocks[0].bits(&bytes).unwrap();
+    assert_eq!(BitVec::from_fn(3, |i| i != 1), bits);
+
+    let json: Vec<_> =
+      inspection.blocks.iter().map(BlockInfo::to_json).collect();
+    assert_eq!(
+      vec![
+        json!({ "kind": "field", "offset": 0, "width": 3, "path": "/active",
+                "field": "active", "id": 1, "value": true }),
+        json!({ "kind": "list_header", "offset": 3, "width": 10,
+                "path": "/courses", "field": "courses", "id": 2,
+                "length": 1 }),
+        json!({ "kind": "record_header", "offset": 13, "width": 0,
+                "path": "/courses/0", "field": null, "id": null }),
+        json!({ "kind": "field", "offset": 13, "width": 33,
+                "path": "/courses/0/name", "field": "name", "id": 1,
+                "value": "Art" }),
+        json!({ "kind": "terminator", "offset": 46, "width": 1,
+                "path": "/courses/0", "field": null, "id": null }),
+      ],
+      json
+    );
+  }
+
+  #[test]
+  fn inspect_byte_aligned_blocks() {
+    let schema = schema();
+    let value = json!({ "active": true, "courses": [{ "name": "Art" }] });
+    let options = crate::EncodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let bytes = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+    let options = DecodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+
+    let inspection = inspect_with(&schema, &bytes, options);
+    assert!(inspection.error.is_none());
+    let values: Vec<_> = inspection
+      .blocks
+      .iter()
+      .filter_map(|b| b.value.clone())
+      .collect();
+    assert_eq!(vec![json!(true), json!("Art")], values);
+    for block in &inspection.blocks {
+      assert_eq!(0, block.span.start % 8, "{}", block.kind);
+    }
+  }
+
+  #[test]
+  fn inspect_sections() {
+    let schema = schema();
+    let value = json!({ "active": true, "courses": [{ "name": "Art" }] });
+    let bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+
+    let sections: Vec<Vec<_>> = inspect(&schema, &bytes)
+      .blocks
+      .iter()
+      .map(|b| {
+        b.sections
+          .iter()
+          .map(|s| (s.kind.name(), s.span.clone()))
+          .collect()
+      })
+      .collect();
+    assert_eq!(
+      vec![
+        vec![("marker", 0..2), ("data", 2..3)],
+        vec![("marker", 3..5), ("length", 5..13)],
+        vec![],
+        vec![("marker", 13..14), ("length", 14..22), ("data", 22..46)],
+        vec![("marker", 46..47)],
+      ],
+      sections
+    );
+  }
+
+  #[test]
+  fn inspect_truncated() {
+    let schema = schema();
+    let value = json!({ "active": true, "courses": [{ "name": "Art" }] });
+    let bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+
+    let inspection = inspect(&schema, &bytes[..3]);
+    assert_eq!(3, inspection.blocks.len());
+    let e = inspection.error.unwrap();
+    let e = e.downcast::<crate::Error>().unwrap();
+    assert_eq!("/courses/0/name", e.pointer());
+  }
+}
@@ -1,6 +1,7 @@
 //! Various integer related traits.
 
-use std::convert::TryInto;
+use crate::prelude::*;
+use core::convert::TryInto;
 
 /// Trait for integers with a fixed width.
 pub trait FixedWidthInteger {
@@ -33,7 +34,9 @@ impl_fixed_width_integer! {
   u32 => 32,
   i32 => 32,
   u64 => 64,
-  i64 => 64
+  i64 => 64,
+  u128 => 128,
+  i128 => 128
 }
 
 /// Trait for integer types which expose a big endian byte representation.
@@ -65,7 +68,7 @@ macro_rules! impl_big_endian {
   }
 }
 
-impl_big_endian!(u8, u16, u32, u64, i8, i16, i32, i64);
+impl_big_endian!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
 
 /// Trait for integer types which expose a little endian byte representation.
 pub trait LittleEndian: Sized + FixedWidthInteger {
@@ -92,4 +95,4 @@ macro_rules! impl_little_endian {
   };
 }
 
-impl_little_endian!(u8, u16, u32, u64, i8, i16, i32, i64);
+impl_little_endian!(u8, u16, u32, u64, u128, i8, i16, i32, i64, i128);
@@ -0,0 +1,468 @@
+//! The `io` module adapts compressed objects to the standard `Read` and
+//! `Write` traits.
+//!
+//! [`DecompressReader`] wraps a reader of compressed bytes and yields the
+//! decoded object as JSON text, producing the text event by event instead of
+//! building the whole JSON tree first.
+//!
+//! [`CompressWriter`] goes the other way: JSON text written into it is parsed
+//! as complete values arrive and each value is written to the underlying
+//! writer as a [frame](crate::frame).
+
+use crate::decode::{reader, split_dictionaries, DecodeOptions};
+use crate::dictionary::Resolver;
+use crate::encode::EncodeOptions;
+use crate::event::{Event, EventDecoder};
+use crate::frame;
+use crate::schema::Schema;
+use serde_json::{Deserializer, Value};
+use std::io::{self, Read, Write};
+
+/// A reader which decompresses an object on the fly, yielding its JSON text.
+///
+/// The compressed bytes are read from the inner reader in full on the first
+/// call to `read`. Only the JSON text for a single event is buffered at a
+/// time, so the decoded document is never held in memory as a whole.
+///
+/// Decoding errors are reported as `io::ErrorKind::InvalidData`.
+pub struct DecompressReader<'s, R> {
+  inner: R,
+  /// The compressed bytes, once they've been read.
+  bytes: Option<Vec<u8>>,
+  /// The bit position within `bytes` of the next event.
+  pos: usize,
+  schema: &'s Schema,
+  /// How the compressed bytes were encoded.
+  options: DecodeOptions,
+  decoder: EventDecoder<'s>,
+  /// Whether the next item in each enclosing record or list is its first.
+  first: Vec<bool>,
+  /// Whether a field name was just written, so no separator is needed.
+  after_key: bool,
+  /// The JSON text which has been produced but not yet read.
+  out: Vec<u8>,
+  /// The number of bytes of `out` which have already been read.
+  consumed: usize,
+  done: bool,
+}
+
+impl<'s, R: Read> DecompressReader<'s, R> {
+  /// Constructs a reader which decompresses the bytes of `inner` using a
+  /// given `schema`.
+  pub fn new(schema: &'s Schema, inner: R) -> Self {
+    DecompressReader::with_options(schema, inner, DecodeOptions::default())
+  }
+
+  /// Constructs a reader which decompresses the bytes of `inner` using a
+  /// given `schema`, enforcing the limits in `options`.
+  pub fn with_options(
+    schema: &'s Schema,
+    inner: R,
+    options: DecodeOptions,
+  ) -> Self {
+    DecompressReader {
+      inner,
+      bytes: None,
+      pos: 0,
+      schema,
+      options,
+      decoder: EventDecoder::for_schema(schema, options),
+      first: Vec::new(),
+      after_key: false,
+      out: Vec::new(),
+      consumed: 0,
+      done: false,
+    }
+  }
+
+  /// Unwraps this reader, returning the inner reader.
+  pub fn into_inner(self) -> R {
+    self.inner
+  }
+
+  /// Decodes the next event into `out`, returning `false` once the object
+  /// has been fully decoded.
+  fn fill(&mut self) -> io::Result<bool> {
+    if self.done {
+      return Ok(false);
+    }
+
+    let bytes = match &mut self.bytes {
+      Some(bytes) => bytes,
+      None => {
+        let mut bytes = Vec::new();
+        self.inner.read_to_end(&mut bytes)?;
+        let (object, dictionaries) =
+          split_dictionaries(self.schema, &bytes).map_err(invalid_data)?;
+        let len = object.len();
+        let resolver = Resolver::new(self.schema, dictionaries);
+        self.decoder.set_resolver(resolver);
+        bytes.truncate(len);
+        self.bytes.get_or_insert(bytes)
+      }
+    };
+
+    let mut r = reader(self.schema, bytes, &self.options);
+    r.seek(self.pos).expect("position is within the input");
+    let event = match self.decoder.next_event(&mut r) {
+      Ok(event) => event,
+      Err(e) => {
+        self.done = true;
+        return Err(invalid_data(e));
+      }
+    };
+    self.pos = r.position();
+
+    match event {
+      Some(event) => {
+        self.write_event(event);
+        Ok(true)
+      }
+      None => {
+        self.done = true;
+        Ok(false)
+      }
+    }
+  }
+
+  /// Appends the JSON text for `event` to `out`.
+  fn write_event(&mut self, event: Event) {
+    match event {
+      Event::StartRecord => {
+        self.separator();
+        self.out.push(b'{');
+        self.first.push(true);
+      }
+      Event::Field(name) => {
+        self.separator();
+        let key = Value::from(name).to_string();
+        self.out.extend_from_slice(key.as_bytes());
+        self.out.push(b':');
+        self.after_key = true;
+      }
+      Event::Value(value) => {
+        self.separator();
+        self.out.extend_from_slice(value.to_string().as_bytes());
+      }
+      Event::EndRecord => {
+        self.first.pop();
+        self.out.push(b'}');
+      }
+      Event::StartList(_) => {
+        self.separator();
+        self.out.push(b'[');
+        self.first.push(true);
+      }
+      Event::EndList => {
+        self.first.pop();
+        self.out.push(b']');
+      }
+    }
+  }
+
+  /// Writes a comma if the next item is not the first in its record or list.
+  fn separator(&mut self) {
+    if self.after_key {
+      self.after_key = false;
+      return;
+    }
+    if let Some(first) = self.first.last_mut() {
+      if !*first {
+        self.out.push(b',');
+      }
+      *first = false;
+    }
+  }
+}
+
+impl<'s, R: Read> Read for DecompressReader<'s, R> {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    while self.consumed == self.out.len() {
+      self.out.clear();
+      self.consumed = 0;
+      if !self.fill()? {
+        return Ok(0);
+      }
+    }
+
+    let pending = &self.out[self.consumed..];
+    let n = pending.len().min(buf.len());
+    buf[..n].copy_from_slice(&pending[..n]);
+    self.consumed += n;
+    Ok(n)
+  }
+}
+
+/// A writer which compresses a stream of JSON values.
+///
+/// Bytes written into it are buffered until they hold a complete JSON value,
+/// which is then encoded and written to the inner writer as a frame carrying
+/// the fingerprint of the schema. Values may be separated by newlines (as in
+/// NDJSON) or any other whitespace, and a single document may be split across
+/// any number of writes.
+///
+/// Call [`finish`](CompressWriter::finish) once all values have been written
+/// so that a trailing number, which can't be told apart from a partially
+/// written one, is flushed as well. Invalid JSON and values which can't be
+/// encoded are reported as `io::ErrorKind::InvalidData`.
+pub struct CompressWriter<'s, W> {
+  inner: W,
+  schema: &'s Schema,
+  options: EncodeOptions,
+  /// The JSON text which doesn't yet form a complete value.
+  buf: Vec<u8>,
+}
+
+impl<'s, W: Write> CompressWriter<'s, W> {
+  /// Constructs a writer which compresses values using a given `schema` and
+  /// writes the frames to `inner`.
+  pub fn new(schema: &'s Schema, inner: W) -> Self {
+    CompressWriter::with_options(schema, inner, EncodeOptions::default())
+  }
+
+  /// Constructs a writer which compresses values using a given `schema` and
+  /// `options` and writes the frames to `inner`.
+  pub fn with_options(
+    schema: &'s Schema,
+    inner: W,
+    options: EncodeOptions,
+  ) -> Self {
+    CompressWriter {
+      inner,
+      schema,
+      options,
+      buf: Vec::new(),
+    }
+  }
+
+  /// Compresses any remaining value and returns the inner writer.
+  ///
+  /// Fails if the text written so far ends part way through a value.
+  pub fn finish(mut self) -> io::Result<W> {
+    self.drain(true)?;
+    if self.buf.iter().any(|b| !b.is_ascii_whitespace()) {
+      return Err(invalid_data("stream ends part way through a JSON value"));
+    }
+    self.inner.flush()?;
+    Ok(self.inner)
+  }
+
+  /// Compresses and writes every complete value at the front of `buf`.
+  fn drain(&mut self, last: bool) -> io::Result<()> {
+    let (values, consumed, err) = parse_values(&self.buf, last);
+    self.buf.drain(..consumed);
+    for value in values {
+      self.write_value(&value)?;
+    }
+    match err {
+      Some(e) => Err(e),
+      None => Ok(()),
+    }
+  }
+
+  fn write_value(&mut self, value: &Value) -> io::Result<()> {
+    let object = crate::encode_with(self.schema, value, &self.options)
+      .map_err(invalid_data)?;
+    let object = object.to_bytes();
+    let fingerprint = Some(self.schema.fingerprint());
+    let (layout, profile) = (self.options.layout, self.options.profile);
+    self.inner.write_all(&frame::prefix(
+      object.len(),
+      fingerprint,
+      layout,
+      profile,
+      self.options.checksums,
+      self.options.bit_order,
+    ))?;
+    self.inner.write_all(&object)
+  }
+}
+
+impl<'s, W: Write> Write for CompressWriter<'s, W> {
+  fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+    self.buf.extend_from_slice(buf);
+    self.drain(false)?;
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> io::Result<()> {
+    self.inner.flush()
+  }
+}
+
+/// Parses the complete JSON values at the start of `bytes`, returning them
+/// along with the number of bytes they span and the error which stopped
+/// parsing, if any.
+///
+/// Unless this is the `last` of the input, a number which runs up to the end
+/// of `bytes` is left unparsed as more of its digits may still be written.
+fn parse_values(
+  bytes: &[u8],
+  last: bool,
+) -> (Vec<Value>, usize, Option<io::Error>) {
+  // A multi-byte character may be split between writes
+  let text = match std::str::from_utf8(bytes) {
+    Ok(text) => text,
+    Err(e) if e.error_len().is_none() && !last => {
+      std::str::from_utf8(&bytes[..e.valid_up_to()]).unwrap()
+    }
+    Err(e) => {
+      return (Vec::new(), 0, Some(invalid_data(e)));
+    }
+  };
+
+  let mut values = Vec::new();
+  let mut consumed = 0;
+  let mut stream = Deserializer::from_str(text).into_iter::<Value>();
+  let err = loop {
+    match stream.next() {
+      None => break None,
+      Some(Ok(Value::Number(_)))
+        if !last && stream.byte_offset() == text.len() =>
+      {
+        break None;
+      }
+      Some(Ok(value)) => {
+        values.push(value);
+        consumed = stream.byte_offset();
+      }
+      Some(Err(e)) if e.is_eof() && !last => break None,
+      Some(Err(e)) => break Some(invalid_data(e)),
+    }
+  };
+  (values, consumed, err)
+}
+
+fn invalid_data<E>(e: E) -> io::Error
+where
+  E: Into<Box<dyn std::error::Error + Send + Sync>>,
+{
+  io::Error::new(io::ErrorKind::InvalidData, e)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::bit::BitVec;
+  use crate::schema::{CompositeType, List, Record, Type};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+
+    let mut student = BTreeMap::new();
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    Schema::new(CompositeType::Record(Record::new(student)))
+  }
+
+  fn encode_bytes(schema: &Schema, value: &Value) -> Vec<u8> {
+    let bits: BitVec = crate::encode(schema, value).unwrap().into();
+    bits.to_bytes()
+  }
+
+  #[test]
+  fn reads_decoded_json_text() {
+    let schema = schema();
+    let value = json!({
+      "active": true,
+      "courses": [{ "name": "Math \"101\"" }, {}, { "name": "Physics" }]
+    });
+    let bytes = encode_bytes(&schema, &value);
+
+    let mut text = String::new();
+    DecompressReader::new(&schema, bytes.as_slice())
+      .read_to_string(&mut text)
+      .unwrap();
+    assert_eq!(serde_json::to_string(&value).unwrap(), text);
+  }
+
+  #[test]
+  fn reads_into_small_buffers() {
+    let schema = schema();
+    let value = json!({ "courses": [{ "name": "Math" }, { "name": "Art" }] });
+    let bytes = encode_bytes(&schema, &value);
+
+    let mut reader = DecompressReader::new(&schema, bytes.as_slice());
+    let mut text = Vec::new();
+    let mut buf = [0; 3];
+    loop {
+      let n = reader.read(&mut buf).unwrap();
+      if n == 0 {
+        break;
+      }
+      text.extend_from_slice(&buf[..n]);
+    }
+    assert_eq!(value, serde_json::from_slice::<Value>(&text).unwrap());
+  }
+
+  #[test]
+  fn truncated_input_is_invalid_data() {
+    let schema = schema();
+    let value = json!({ "courses": [{ "name": "Math" }, { "name": "Art" }] });
+    let bytes = encode_bytes(&schema, &value);
+
+    let mut text = String::new();
+    let err = DecompressReader::new(&schema, &bytes[..3])
+      .read_to_string(&mut text)
+      .unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+  }
+
+  fn read_values(schema: &Schema, mut bytes: &[u8]) -> Vec<Value> {
+    let mut values = Vec::new();
+    while let Some(frame) = crate::frame::read_frame(&mut bytes).unwrap() {
+      values.push(frame.decode(schema).unwrap());
+    }
+    values
+  }
+
+  #[test]
+  fn writes_a_frame_per_ndjson_line() {
+    let schema = schema();
+    let text = "{\"active\":true}\n{\"courses\":[{\"name\":\"Math\"}]}\n";
+
+    // Write the text a few bytes at a time to split values between writes
+    let mut writer = CompressWriter::new(&schema, Vec::new());
+    for chunk in text.as_bytes().chunks(5) {
+      writer.write_all(chunk).unwrap();
+    }
+    let bytes = writer.finish().unwrap();
+
+    assert_eq!(
+      vec![
+        json!({ "active": true }),
+        json!({ "courses": [{ "name": "Math" }] })
+      ],
+      read_values(&schema, &bytes)
+    );
+  }
+
+  #[test]
+  fn writes_a_single_pretty_document() {
+    let schema = schema();
+    let value = json!({ "active": false, "courses": [{ "name": "Art" }] });
+    let text = serde_json::to_string_pretty(&value).unwrap();
+
+    let mut writer = CompressWriter::new(&schema, Vec::new());
+    writer.write_all(text.as_bytes()).unwrap();
+    let bytes = writer.finish().unwrap();
+    assert_eq!(vec![value], read_values(&schema, &bytes));
+  }
+
+  #[test]
+  fn unfinished_value_is_an_error() {
+    let schema = schema();
+    let mut writer = CompressWriter::new(&schema, Vec::new());
+    writer.write_all(b"{\"active\": tr").unwrap();
+    let err = writer.finish().unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidData, err.kind());
+  }
+}
@@ -0,0 +1,316 @@
+//! The `lazy` module implements on-demand access to the fields of a
+//! compressed record.
+//!
+//! A [`LazyRecord`] borrows the compressed bytes and only reads as far as it
+//! needs to find the requested field. Fields before it are skipped over using
+//! their widths and lengths without being decompressed, and fields after it
+//! aren't read at all. The values of [dictionary](crate::dictionary) fields
+//! are looked up in the object's dictionaries as they are decoded.
+
+use crate::bit::BitReader;
+use crate::decode::{
+  decode_value, reader, skip_field, skip_fields, split_dictionaries,
+  DecodeOptions, Fields,
+};
+use crate::dictionary::Resolver;
+use crate::error::within;
+use crate::path::Segment;
+use crate::prelude::*;
+use crate::schema::{CompositeType, Record, Schema, Type};
+use alloc::sync::Arc;
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// A view over a compressed record which decodes individual fields on
+/// demand.
+///
+/// The bit offset of each field is computed the first time the field, or one
+/// after it, is looked up and is remembered for later lookups. Looking up a
+/// field never decodes any other field, making this well suited to reading a
+/// couple of fields from each of many records.
+#[derive(Clone, Debug)]
+pub struct LazyRecord<'s, 'a> {
+  record: &'s Record,
+  /// The state of reading the field markers, which is needed to read those
+  /// of sparse records.
+  fields: Fields<'s>,
+  /// A reader over the whole object from which the reader for each lookup is
+  /// cloned.
+  r: BitReader<'a>,
+  /// Looks up the values of dictionary fields, shared with the views over
+  /// nested records.
+  resolver: Arc<Resolver<'s>>,
+  /// The fields which have been found so far along with the bit offsets of
+  /// their data.
+  seen: Vec<(&'s str, &'s Type, usize)>,
+  /// The bit offset of the next field marker to read, or `None` once the end
+  /// of the record has been reached.
+  next: Option<usize>,
+}
+
+impl<'s, 'a> LazyRecord<'s, 'a> {
+  /// Constructs a view over a compressed object whose root is a record.
+  pub fn new(schema: &'s Schema, bytes: &'a [u8]) -> Result<Self> {
+    LazyRecord::with_options(schema, bytes, DecodeOptions::default())
+  }
+
+  /// Constructs a view over a compressed object whose root is a record and
+  /// which was encoded in the way described by `options`.
+  pub fn with_options(
+    schema: &'s Schema,
+    bytes: &'a [u8],
+    options: DecodeOptions,
+  ) -> Result<Self> {
+    match schema.root() {
+      Type::Nested(CompositeType::Record(record)) => {
+        let fields = Fields::new(record, true);
+        let (bytes, dictionaries) = split_dictionaries(schema, bytes)?;
+        let r = reader(schema, bytes, &options);
+        let resolver = Arc::new(Resolver::new(schema, dictionaries));
+        Ok(LazyRecord::at(fields, r, resolver, 0))
+      }
+      _ => bail!("root type is not a record"),
+    }
+  }
+
+  fn at(
+    fields: Fields<'s>,
+    r: BitReader<'a>,
+    resolver: Arc<Resolver<'s>>,
+    pos: usize,
+  ) -> Self {
+    LazyRecord {
+      record: fields.record,
+      fields,
+      r,
+      resolver,
+      seen: Vec::new(),
+      next: Some(pos),
+    }
+  }
+
+  /// Decodes the value of the non-nested field `name`.
+  ///
+  /// Returns `None` if the field is not present in this record.
+  pub fn get(&mut self, name: &str) -> Result<Option<Value>> {
+    let (ty, offset) = match self.find(name)? {
+      Some(field) => field,
+      None => return Ok(None),
+    };
+    if let Type::Nested(_) = ty {
+      bail!("field {} is nested, use `record` to access it", name);
+    }
+
+    let mut r = self.reader(offset);
+    decode_value(ty, &mut r)
+      .and_then(|mut value| {
+        self.resolver.resolve(ty, &mut value)?;
+        Ok(Some(value))
+      })
+      .map_err(|e| within(e, Segment::Field(name.to_string()), "decoding"))
+  }
+
+  /// Returns a view over the nested record held in field `name`.
+  ///
+  /// Returns `None` if the field is not present in this record.
+  pub fn record(&mut self, name: &str) -> Result<Option<LazyRecord<'s, 'a>>> {
+    match self.find(name)? {
+      None => Ok(None),
+      Some((Type::Nested(CompositeType::Record(record)), offset)) => {
+        // The fields of an inline record are read from among this record's
+        let fields = self
+          .fields
+          .inline(name)
+          .unwrap_or_else(|| Fields::new(record, false));
+        let (r, resolver) = (self.r.clone(), Arc::clone(&self.resolver));
+        Ok(Some(LazyRecord::at(fields, r, resolver, offset)))
+      }
+      Some(_) => bail!("field {} is not a record", name),
+    }
+  }
+
+  /// The bit offset at which the data of field `name` starts, or `None` if
+  /// the field is not present in this record.
+  pub fn offset(&mut self, name: &str) -> Result<Option<usize>> {
+    Ok(self.find(name)?.map(|(_, offset)| offset))
+  }
+
+  /// Finds the type and data offset of field `name`, reading field markers
+  /// until it's found.
+  fn find(&mut self, name: &str) -> Result<Option<(&'s Type, usize)>> {
+    if let Some((_, ty, offset)) = self.seen.iter().find(|f| f.0 == name) {
+      return Ok(Some((ty, *offset)));
+    }
+    if !self.record.fields.contains_key(name) {
+      bail!("record has no field named {}", name);
+    }
+
+    let mut r = match self.next {
+      Some(pos) => self.reader(pos),
+      None => return Ok(None),
+    };
+    loop {
+      let (field, ty) = match self.fields.next(&mut r)? {
+        Some(field) => field,
+        None => {
+          self.next = None;
+          return Ok(None);
+        }
+      };
+
+      let offset = r.position();
+      match self.fields.inline(field) {
+        Some(inline) => skip_fields(inline, &mut r),
+        None => skip_field(ty, self.record.is_nullable(field), &mut r),
+      }
+      .map_err(|e| within(e, Segment::Field(field.clone()), "decoding"))?;
+
+      self.seen.push((field, ty, offset));
+      self.next = Some(r.position());
+      if field == name {
+        return Ok(Some((ty, offset)));
+      }
+    }
+  }
+
+  fn reader(&self, pos: usize) -> BitReader<'a> {
+    let mut r = self.r.clone();
+    r.seek(pos).expect("offset is within the input");
+    r
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::data::Layout;
+  use crate::schema::List;
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut address = BTreeMap::new();
+    address.insert("city".to_string(), Type::PassThrough);
+    address.insert("verified".to_string(), Type::Name("bool".to_string()));
+
+    let mut person = BTreeMap::new();
+    person.insert("active".to_string(), Type::Name("bool".to_string()));
+    person.insert(
+      "address".to_string(),
+      Type::Nested(CompositeType::Record(Record::new(address))),
+    );
+    person.insert(
+      "tags".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::PassThrough)))),
+    );
+    person.insert("name".to_string(), Type::PassThrough);
+    person.insert("admin".to_string(), Type::Name("bool".to_string()));
+    Schema::new(CompositeType::Record(Record::new(person)))
+  }
+
+  fn encode_bytes(schema: &Schema, value: &Value) -> Vec<u8> {
+    crate::encode(schema, value).unwrap().to_bytes()
+  }
+
+  #[test]
+  fn get_fields_in_any_order() {
+    let schema = schema();
+    let value = json!({
+      "active": true,
+      "address": { "city": "Paris", "verified": false },
+      "tags": ["a", "b"],
+      "name": "Alice",
+      "admin": false
+    });
+    let bytes = encode_bytes(&schema, &value);
+
+    let mut record = LazyRecord::new(&schema, &bytes).unwrap();
+    assert_eq!(Some(json!(false)), record.get("admin").unwrap());
+    assert_eq!(Some(json!(true)), record.get("active").unwrap());
+    assert_eq!(Some(json!("Alice")), record.get("name").unwrap());
+
+    let mut address = record.record("address").unwrap().unwrap();
+    assert_eq!(Some(json!("Paris")), address.get("city").unwrap());
+    assert_eq!(Some(json!(false)), address.get("verified").unwrap());
+  }
+
+  #[test]
+  fn get_byte_aligned_fields() {
+    let schema = schema();
+    let value = json!({
+      "address": { "city": "Paris", "verified": true },
+      "name": "Alice",
+      "admin": true
+    });
+    let options = crate::EncodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let bytes = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+    let options = DecodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+
+    let record = LazyRecord::with_options(&schema, &bytes, options);
+    let mut record = record.unwrap();
+    assert_eq!(Some(json!(true)), record.get("admin").unwrap());
+    assert_eq!(Some(json!("Alice")), record.get("name").unwrap());
+    let mut address = record.record("address").unwrap().unwrap();
+    assert_eq!(Some(json!(true)), address.get("verified").unwrap());
+  }
+
+  #[test]
+  fn missing_fields_are_none() {
+    let schema = schema();
+    let bytes = encode_bytes(&schema, &json!({ "name": "Bob" }));
+
+    let mut record = LazyRecord::new(&schema, &bytes).unwrap();
+    assert_eq!(None, record.get("admin").unwrap());
+    assert!(record.record("address").unwrap().is_none());
+    assert_eq!(Some(json!("Bob")), record.get("name").unwrap());
+  }
+
+  #[test]
+  fn fields_after_the_one_requested_are_not_read() {
+    let schema = schema();
+    let bytes = encode_bytes(&schema, &json!({ "active": true, "name": "C" }));
+
+    // Truncating the bytes after the first field doesn't affect it
+    let mut record = LazyRecord::new(&schema, &bytes[..1]).unwrap();
+    assert_eq!(Some(json!(true)), record.get("active").unwrap());
+    assert!(record.get("name").is_err());
+  }
+
+  #[test]
+  fn unknown_and_nested_fields_are_errors() {
+    let schema = schema();
+    let bytes = encode_bytes(&schema, &json!({ "tags": [] }));
+
+    let mut record = LazyRecord::new(&schema, &bytes).unwrap();
+    assert!(record.get("nope").is_err());
+    assert!(record.get("tags").is_err());
+    assert!(record.record("tags").is_err());
+  }
+
+  #[test]
+  fn get_fields_of_objects_with_dictionaries() {
+    let mut fields = BTreeMap::new();
+    fields.insert(
+      "methods".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Dictionary {
+        size: 2,
+      })))),
+    );
+    fields.insert("method".to_string(), Type::Dictionary { size: 2 });
+    let schema = Schema::new(CompositeType::Record(Record::new(fields)));
+    let value = json!({ "methods": ["GET", "GET", "POST"], "method": "PUT" });
+    let mut bytes = Vec::new();
+    crate::encode_to(&schema, &value, &mut bytes).unwrap();
+
+    let mut record = LazyRecord::new(&schema, &bytes).unwrap();
+    assert_eq!(Some(json!("PUT")), record.get("method").unwrap());
+  }
+}
@@ -1,13 +1,94 @@
 #![feature(bindings_after_at)]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+extern crate alloc;
+
+#[cfg(feature = "std")]
+pub mod archive;
+pub mod arena;
+#[cfg(feature = "arrow")]
+pub mod arrow;
+#[cfg(feature = "async")]
+pub mod async_io;
 pub mod bit;
 pub mod comp;
 pub mod data;
+pub mod dictionary;
+pub mod diff;
+pub mod epoch;
+pub mod error;
+pub mod event;
+pub mod evolution;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+#[cfg(feature = "std")]
+pub mod frame;
+pub mod header;
+#[cfg(feature = "http")]
+pub mod http;
+pub mod index;
+pub mod inspect;
 pub mod int;
+#[cfg(feature = "std")]
+pub mod io;
+pub mod lazy;
 pub mod math;
+pub mod migrate;
+#[cfg(feature = "mmap")]
+pub mod mmap;
+pub mod patch;
+pub mod path;
+#[cfg(feature = "std")]
+pub mod reorder;
 pub mod schema;
+pub mod spec;
+#[cfg(feature = "std")]
+pub mod stats;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod unknown;
+pub mod update;
 pub mod vie;
 
+mod decode;
+mod dot;
 mod encode;
+mod estimate;
+#[cfg(feature = "fec")]
+mod fec;
+mod markdown;
+mod sizes;
+mod tree;
+
+/// The items of the standard prelude which come from `alloc`, so that modules
+/// work the same with and without `std`.
+mod prelude {
+  pub use alloc::boxed::Box;
+  pub use alloc::string::{String, ToString};
+  pub use alloc::vec::Vec;
+  pub use alloc::{format, vec};
+}
 
-pub use encode::encode;
+#[cfg(feature = "parallel")]
+pub use decode::decode_parallel;
+pub use decode::{
+  decode, decode_element, decode_element_with, decode_lenient,
+  decode_lenient_with, decode_path, decode_path_with, decode_with,
+  DecodeOptions, Diagnostic, Salvaged,
+};
+pub use encode::{
+  encode, encode_with, encode_with_warnings, DeprecatedFieldPolicy,
+  EncodeOptions, FieldOrder, MissingFieldPolicy, UnknownFieldPolicy, Warning,
+  WarningKind,
+};
+#[cfg(feature = "std")]
+pub use encode::{encode_to, encode_to_with};
+pub use error::Error;
+pub use estimate::{estimate_size, BitEstimate};
+pub use event::{
+  decode_events, decode_events_with, resume_events, resume_events_with,
+  Checkpoint,
+};
+#[cfg(feature = "std")]
+pub use io::{CompressWriter, DecompressReader};
+pub use lazy::LazyRecord;
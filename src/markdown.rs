@@ -0,0 +1,245 @@
+//! Documents a schema as a Markdown table of its fields.
+
+use crate::comp::EncodedWidth;
+use crate::data::LengthEncoding;
+use crate::encode::get_compressor_for_type;
+use crate::prelude::*;
+use crate::schema::{CompositeType, Record, Schema, Type};
+use crate::tree::type_name;
+use core::fmt::Write;
+
+impl Schema {
+  /// Documents this schema as Markdown, for the consumers of the data it
+  /// describes.
+  ///
+  /// A list of the settings which affect the whole schema, such as its
+  /// fingerprint, is followed by a table with a row for every field and list
+  /// element giving its type, the number of bits its data takes up if that
+  /// is fixed, its constraints and its `description`:
+  ///
+  /// ```text
+  /// | Field | Type | Width | Constraints | Description |
+  /// | --- | --- | --- | --- | --- |
+  /// | `active` | bool | 1 bits | required | Whether the student is enrolled. |
+  /// | `courses` | list | - |  |  |
+  /// | `courses[]` | record | - |  |  |
+  /// | `courses[].grade` | enum | 2 bits | one of `A`, `B`, `C` |  |
+  /// ```
+  ///
+  /// Fields are listed in the order of their identifiers, with the fields
+  /// of a record right after it. The elements of a list are written as
+  /// `[]`.
+  pub fn to_markdown(&self) -> String {
+    let mut out = String::new();
+    // Writing to a string can't fail
+    let _ = writeln!(out, "# Schema\n");
+    let _ = writeln!(out, "- Fingerprint: `{:016x}`", self.fingerprint());
+    if let Some(version) = self.version() {
+      let _ = writeln!(out, "- Version: {}", version);
+    }
+    if self.lengths() != LengthEncoding::Vie {
+      let _ = writeln!(out, "- Lengths: {}", self.lengths().name());
+    }
+    if let Some(size) = self.chunks() {
+      let _ = writeln!(out, "- Lists are chunked every {} elements", size);
+    }
+    if let Some(blocks) = self.sync() {
+      let _ = writeln!(out, "- Sync markers roughly every {} blocks", blocks);
+    }
+
+    out.push_str("\n| Field | Type | Width | Constraints | Description |\n");
+    out.push_str("| --- | --- | --- | --- | --- |\n");
+    match self.root() {
+      Type::Nested(ct) => composite(ct, "", &mut out),
+      ty => row(&mut out, "(root)", ty, &constraints(ty), None),
+    }
+    out
+  }
+}
+
+/// Writes the rows of the fields or elements of `ct`, found at `path`.
+fn composite(ct: &CompositeType, path: &str, out: &mut String) {
+  match ct {
+    CompositeType::Record(record) => {
+      for (name, _) in record.names() {
+        let path = match path {
+          "" => name.to_string(),
+          path => format!("{}.{}", path, name),
+        };
+        match record.fields.get(name) {
+          Some(ty) => {
+            let description = record.descriptions.get(name);
+            let constraints = field_constraints(record, name, ty);
+            row(
+              out,
+              &path,
+              ty,
+              &constraints,
+              description.map(String::as_str),
+            );
+            if let Type::Nested(ct) = ty {
+              composite(ct, &path, out);
+            }
+          }
+          None => {
+            let _ = writeln!(out, "| `{}` | reserved | - |  |  |", path);
+          }
+        }
+      }
+    }
+    CompositeType::List(list) => {
+      let path = format!("{}[]", path);
+      row(out, &path, &list.0, &constraints(&list.0), None);
+      if let Type::Nested(ct) = list.0.as_ref() {
+        composite(ct, &path, out);
+      }
+    }
+  }
+}
+
+/// Writes the row of a value of type `ty` at `path`.
+fn row(
+  out: &mut String,
+  path: &str,
+  ty: &Type,
+  constraints: &[String],
+  description: Option<&str>,
+) {
+  let width = match ty {
+    Type::Nested(_) => None,
+    ty => get_compressor_for_type(ty).ok().map(|c| c.encoded_width()),
+  };
+  let width = match width {
+    Some(EncodedWidth::Fixed(bits)) => format!("{} bits", bits),
+    Some(EncodedWidth::Variable) => "variable".to_string(),
+    Some(EncodedWidth::Prefix) => "prefix code".to_string(),
+    None => "-".to_string(),
+  };
+  let _ = writeln!(
+    out,
+    "| `{}` | {} | {} | {} | {} |",
+    path,
+    escape(&type_name(ty)),
+    width,
+    escape(&constraints.join(", ")),
+    escape(description.unwrap_or_default())
+  );
+}
+
+/// The constraints on the field `name` of `record`, which has type `ty`.
+fn field_constraints(record: &Record, name: &str, ty: &Type) -> Vec<String> {
+  let mut out = Vec::new();
+  if record.is_required(name) {
+    out.push("required".to_string());
+  }
+  if record.is_nullable(name) {
+    out.push("nullable".to_string());
+  }
+  if record.is_deprecated(name) {
+    out.push("deprecated".to_string());
+  }
+  if record.inline_record(name).is_some() {
+    out.push("inline".to_string());
+  }
+  if let Some(default) = record.defaults.get(name) {
+    out.push(format!("default `{}`", default));
+  }
+  out.extend(constraints(ty));
+  out
+}
+
+/// The constraints on the values of type `ty`.
+fn constraints(ty: &Type) -> Vec<String> {
+  let quoted = |values: Vec<String>| {
+    let values: Vec<_> = values.iter().map(|v| format!("`{}`", v)).collect();
+    format!("one of {}", values.join(", "))
+  };
+  match ty {
+    Type::Enum { variants, .. } => {
+      vec![quoted(variants.iter().cloned().collect())]
+    }
+    Type::IntEnum { codes } => {
+      vec![quoted(codes.iter().map(|c| c.to_string()).collect())]
+    }
+    Type::Dictionary { size } => {
+      vec![format!("{} most frequent values in a footer", size)]
+    }
+    Type::Scaled { scale, round } => {
+      let mut out = vec![format!("multiple of {}", 1.0 / scale)];
+      if let Some(round) = round {
+        out.push(format!("rounded {}", round));
+      }
+      out
+    }
+    Type::Transformed { transform, of } => {
+      let names: Vec<_> = transform.iter().map(|t| t.name()).collect();
+      let mut out = vec![names.join(" then ")];
+      out.extend(constraints(of));
+      out
+    }
+    Type::Union { alternatives } => {
+      let names: Vec<_> = alternatives.iter().map(type_name).collect();
+      vec![format!("any of {}", names.join(" or "))]
+    }
+    _ => Vec::new(),
+  }
+}
+
+/// Escapes the text of a table cell, which must fit on one line.
+fn escape(text: &str) -> String {
+  text.replace('|', "\\|").replace('\n', " ")
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::List;
+  use std::collections::BTreeMap;
+
+  #[test]
+  fn markdown_table_of_fields() {
+    let mut course = BTreeMap::new();
+    course.insert(
+      "grade".to_string(),
+      Type::Enum {
+        variants: ["A", "B", "C"].iter().map(|v| v.to_string()).collect(),
+        probabilities: None,
+      },
+    );
+    let mut student = BTreeMap::new();
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    let mut student = Record::new(student);
+    student.required.insert("active".to_string());
+    student.descriptions.insert(
+      "active".to_string(),
+      "Whether the student is | enrolled.".to_string(),
+    );
+    student.reserved.insert("ssn".to_string());
+    let schema = Schema::new(CompositeType::Record(student)).with_version(2);
+
+    let markdown = schema.to_markdown();
+    let expected = format!(
+      concat!(
+        "# Schema\n\n",
+        "- Fingerprint: `{:016x}`\n",
+        "- Version: 2\n\n",
+        "| Field | Type | Width | Constraints | Description |\n",
+        "| --- | --- | --- | --- | --- |\n",
+        "| `active` | bool | 1 bits | required ",
+        "| Whether the student is \\| enrolled. |\n",
+        "| `courses` | list | - |  |  |\n",
+        "| `courses[]` | record | - |  |  |\n",
+        "| `courses[].grade` | enum | 2 bits | one of `A`, `B`, `C` |  |\n",
+        "| `ssn` | reserved | - |  |  |\n",
+      ),
+      schema.fingerprint()
+    );
+    assert_eq!(expected, markdown);
+  }
+}
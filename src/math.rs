@@ -1,6 +1,7 @@
 //! Math utilities.
 
 use crate::int::FixedWidthInteger;
+use crate::prelude::*;
 use num_traits::{PrimInt, Unsigned};
 
 /// Unsigned integer division rounding away from zero.
@@ -13,6 +14,13 @@ pub fn div_ceil<I: PrimInt + Unsigned>(lhs: I, rhs: I) -> I {
   }
 }
 
+/// A 64-bit FNV-1a hash.
+pub fn fnv1a(bytes: &[u8]) -> u64 {
+  bytes.iter().fold(0xcbf2_9ce4_8422_2325, |h, b| {
+    (h ^ *b as u64).wrapping_mul(0x0000_0100_0000_01b3)
+  })
+}
+
 /// Shifts `lhs` to the left by `rhs` bits returning the result of the shift
 /// along with the bits that were shifted out, which are shifted from the
 /// high part of the byte to the low part.
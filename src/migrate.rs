@@ -0,0 +1,233 @@
+//! The `migrate` module upgrades values encoded with an older version of a
+//! schema by applying migration functions registered by the user.
+//!
+//! Unlike [`evolution`](crate::evolution), which only handles a schema
+//! growing, migrations can make any change, such as renaming a field or
+//! splitting it in two. Every version of the schema must have a
+//! [version number](Schema::version), which is part of its fingerprint, and
+//! each older version is registered along with a function turning its values
+//! into values of the next version:
+//!
+//! ```ignore
+//! let mut migrator = Migrator::new(v3)?;
+//! migrator.register(v1, |value| Ok(split_name(value)))?;
+//! migrator.register(v2, |value| Ok(add_email(value)))?;
+//! let value = migrator.decode(frame.fingerprint.unwrap(), &frame.object)?;
+//! ```
+
+use crate::decode::DecodeOptions;
+use crate::prelude::*;
+use crate::schema::Schema;
+use alloc::collections::BTreeMap;
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+
+/// A function which turns a value of one version of a schema into a value of
+/// the next version.
+pub type Migration = Box<dyn Fn(Value) -> Result<Value> + Send + Sync>;
+
+/// Decodes objects encoded with any registered version of a schema as values
+/// of the current version.
+pub struct Migrator {
+  current: Schema,
+  /// The older versions of the schema by version number, along with the
+  /// migration from each to the next version.
+  versions: BTreeMap<u32, (Schema, Migration)>,
+}
+
+impl Migrator {
+  /// Constructs a migrator which produces values of the `current` version of
+  /// a schema.
+  ///
+  /// Fails if `current` doesn't have a version number.
+  pub fn new(current: Schema) -> Result<Self> {
+    if current.version().is_none() {
+      bail!("the current schema has no version");
+    }
+    Ok(Migrator {
+      current,
+      versions: BTreeMap::new(),
+    })
+  }
+
+  /// Registers an older version of the schema along with the function which
+  /// migrates its values to the next version.
+  ///
+  /// Fails if `schema` doesn't have a version number, isn't older than the
+  /// current version or if its version is already registered.
+  pub fn register<F>(&mut self, schema: Schema, migration: F) -> Result<()>
+  where
+    F: Fn(Value) -> Result<Value> + Send + Sync + 'static,
+  {
+    let version = schema
+      .version()
+      .ok_or_else(|| anyhow!("a registered schema has no version"))?;
+    if version >= self.current_version() {
+      bail!(
+        "version {} is not older than the current version {}",
+        version,
+        self.current_version()
+      );
+    }
+    if self.versions.contains_key(&version) {
+      bail!("version {} is already registered", version);
+    }
+    self.versions.insert(version, (schema, Box::new(migration)));
+    Ok(())
+  }
+
+  /// The current version of the schema.
+  pub fn current(&self) -> &Schema {
+    &self.current
+  }
+
+  fn current_version(&self) -> u32 {
+    self
+      .current
+      .version()
+      .expect("current schema has a version")
+  }
+
+  /// The current or registered version of the schema with a given
+  /// fingerprint.
+  pub fn schema(&self, fingerprint: u64) -> Option<&Schema> {
+    if self.current.fingerprint() == fingerprint {
+      return Some(&self.current);
+    }
+    self
+      .versions
+      .values()
+      .map(|(schema, _)| schema)
+      .find(|schema| schema.fingerprint() == fingerprint)
+  }
+
+  /// Migrates a value of version `version` of the schema to the current
+  /// version, applying the migration of each version in between.
+  pub fn migrate(&self, version: u32, mut value: Value) -> Result<Value> {
+    for v in version..self.current_version() {
+      let (_, migration) = self
+        .versions
+        .get(&v)
+        .ok_or_else(|| anyhow!("no migration from version {}", v))?;
+      value = migration(value)
+        .with_context(|| format!("when migrating from version {}", v))?;
+    }
+    Ok(value)
+  }
+
+  /// Decodes an object encoded with the version of the schema with a given
+  /// fingerprint, migrating it to the current version.
+  pub fn decode(&self, fingerprint: u64, bytes: &[u8]) -> Result<Value> {
+    self.decode_with(fingerprint, bytes, DecodeOptions::default())
+  }
+
+  /// Decodes an object encoded with the version of the schema with a given
+  /// fingerprint using `options`, migrating it to the current version.
+  pub fn decode_with(
+    &self,
+    fingerprint: u64,
+    bytes: &[u8],
+    options: DecodeOptions,
+  ) -> Result<Value> {
+    let schema = self.schema(fingerprint).ok_or_else(|| {
+      anyhow!(
+        "no version of the schema has fingerprint {:016x}",
+        fingerprint
+      )
+    })?;
+    let value = crate::decode_with(schema, bytes, options)?;
+    let version = schema.version().expect("registered schemas have versions");
+    self.migrate(version, value)
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{CompositeType, Record, Type};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema(version: u32, names: &[&str]) -> Schema {
+    let fields: BTreeMap<String, Type> = names
+      .iter()
+      .map(|name| (name.to_string(), Type::PassThrough))
+      .collect();
+    Schema::new(CompositeType::Record(Record::new(fields)))
+      .with_version(version)
+  }
+
+  fn migrator() -> Migrator {
+    let current = schema(3, &["first", "last", "email"]);
+    let mut migrator = Migrator::new(current).unwrap();
+    migrator
+      .register(schema(1, &["name"]), |mut value| {
+        let name = value["name"].as_str().unwrap_or_default().to_string();
+        let (first, last) = name.split_once(' ').unwrap_or((&name, ""));
+        let record = value.as_object_mut().unwrap();
+        record.remove("name");
+        record.insert("first".to_string(), Value::from(first));
+        record.insert("last".to_string(), Value::from(last));
+        Ok(value)
+      })
+      .unwrap();
+    migrator
+      .register(schema(2, &["first", "last"]), |mut value| {
+        let first = value["first"].as_str().unwrap_or_default();
+        let email = Value::from(format!("{}@example.com", first));
+        value
+          .as_object_mut()
+          .unwrap()
+          .insert("email".to_string(), email);
+        Ok(value)
+      })
+      .unwrap();
+    migrator
+  }
+
+  #[test]
+  fn decode_and_migrate_old_versions() {
+    let migrator = migrator();
+    let v1 = schema(1, &["name"]);
+    let bytes = crate::encode(&v1, &json!({ "name": "Jeremy Schwartz" }))
+      .unwrap()
+      .to_bytes();
+    assert_eq!(
+      json!({
+        "first": "Jeremy",
+        "last": "Schwartz",
+        "email": "Jeremy@example.com"
+      }),
+      migrator.decode(v1.fingerprint(), &bytes).unwrap()
+    );
+
+    let v3 = migrator.current().clone();
+    let value = json!({ "first": "A", "last": "B", "email": "c" });
+    let bytes = crate::encode(&v3, &value).unwrap().to_bytes();
+    assert_eq!(value, migrator.decode(v3.fingerprint(), &bytes).unwrap());
+  }
+
+  #[test]
+  fn versions_are_part_of_the_fingerprint() {
+    let a = schema(1, &["name"]);
+    let b = schema(2, &["name"]);
+    assert_ne!(a.fingerprint(), b.fingerprint());
+    assert!(migrator().schema(b.fingerprint()).is_none());
+  }
+
+  #[test]
+  fn invalid_registrations() {
+    let mut migrator = migrator();
+    let identity = |value| Ok(value);
+    assert!(migrator.register(schema(1, &["name"]), identity).is_err());
+    assert!(migrator.register(schema(3, &["name"]), identity).is_err());
+    let unversioned = Schema::new_scalar(Type::PassThrough);
+    assert!(migrator.register(unversioned.clone(), identity).is_err());
+    assert!(Migrator::new(unversioned).is_err());
+
+    let mut migrator = Migrator::new(schema(3, &["name"])).unwrap();
+    migrator.register(schema(1, &["name"]), identity).unwrap();
+    let e = migrator.migrate(1, json!({})).unwrap_err();
+    assert_eq!("no migration from version 2", e.to_string());
+  }
+}
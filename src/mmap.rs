@@ -0,0 +1,215 @@
+//! The `mmap` module decodes compressed objects and archives straight out of
+//! memory mapped files.
+//!
+//! The decoders only ever borrow their input, so decoding over a mapping
+//! doesn't copy anything until a value is materialized. Combined with
+//! [`decode_path`](crate::decode_path) and
+//! [`decode_element`](crate::decode_element), which skip over everything not
+//! on the way to the requested value, this allows querying very large files
+//! while only the pages which are actually read become resident.
+//!
+//! Compressed objects are expected in the [file format](crate::header) which
+//! `chii compress` writes, so each one is decoded the way its header says it
+//! was encoded, and its index and unknown fields are read from its footers.
+
+use crate::archive::ArchiveView;
+use crate::decode::DecodeOptions;
+use crate::header::{self, File};
+use crate::path::Path;
+use crate::schema::Schema;
+use anyhow::{anyhow, Context, Result};
+use memmap2::Mmap;
+use serde_json::Value;
+
+/// A read-only memory mapped file holding a compressed object or archive.
+pub struct MappedFile {
+  map: Mmap,
+}
+
+impl MappedFile {
+  /// Maps the file at `path` into memory.
+  ///
+  /// # Safety
+  ///
+  /// The file must not be modified or truncated, by this or any other
+  /// process, while it is mapped. Doing so changes the contents of memory
+  /// which is borrowed as an immutable slice.
+  pub unsafe fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+    let path = path.as_ref();
+    let file = std::fs::File::open(path)
+      .with_context(|| format!("unable to open {}", path.display()))?;
+    let map = Mmap::map(&file)
+      .with_context(|| format!("unable to map {}", path.display()))?;
+    Ok(MappedFile { map })
+  }
+
+  /// The mapped bytes of the file.
+  #[inline]
+  pub fn bytes(&self) -> &[u8] {
+    &self.map
+  }
+
+  /// Splits the compressed file into its header, object and footers. The
+  /// object is borrowed from the mapping, while the footers are parsed.
+  pub fn file(&self) -> Result<File<'_>> {
+    header::parse(self.bytes())
+  }
+
+  /// Decodes the whole compressed object held in the file, restoring its
+  /// unknown fields if they were preserved.
+  pub fn decode(&self, schema: &Schema) -> Result<Value> {
+    let file = self.file()?;
+    let options = file.header.decode_options(DecodeOptions::default());
+    let mut value = crate::decode_with(schema, file.object, options)?;
+    if let Some(unknown) = file.unknown_fields {
+      unknown.restore(&mut value)?;
+    }
+    Ok(value)
+  }
+
+  /// Decodes only the value found at `path` within the compressed object
+  /// held in the file.
+  pub fn decode_path(
+    &self,
+    schema: &Schema,
+    path: &Path,
+  ) -> Result<Option<Value>> {
+    let file = self.file()?;
+    let options = file.header.decode_options(DecodeOptions::default());
+    crate::decode_path_with(schema, file.object, path, options)
+  }
+
+  /// Decodes the `n`th element of the compressed list held in the file using
+  /// the index attached to it.
+  pub fn decode_element(&self, schema: &Schema, n: usize) -> Result<Value> {
+    let file = self.file()?;
+    let index = file
+      .index
+      .ok_or_else(|| anyhow!("compressed file has no index"))?;
+    let options = file.header.decode_options(DecodeOptions::default());
+    crate::decode_element_with(schema, file.object, &index, n, options)
+  }
+
+  /// Opens the archive held in the file, borrowing its entries from the
+  /// mapping.
+  pub fn archive(&self) -> Result<ArchiveView<'_>> {
+    ArchiveView::new(self.bytes())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::archive::Archive;
+  use crate::data::Layout;
+  use crate::header::Header;
+  use crate::index::Index;
+  use crate::schema::{CompositeType, List, Record, Type};
+  use crate::unknown::UnknownFields;
+  use crate::{EncodeOptions, UnknownFieldPolicy};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+  use std::path::PathBuf;
+
+  fn record() -> CompositeType {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    CompositeType::Record(Record::new(fields))
+  }
+
+  fn schema() -> Schema {
+    Schema::new(record())
+  }
+
+  fn temp_path(name: &str) -> PathBuf {
+    std::env::temp_dir().join(format!(
+      "chii-mmap-{}-{}",
+      std::process::id(),
+      name
+    ))
+  }
+
+  #[test]
+  fn decode_mapped_archive_entries() {
+    let schema = schema();
+    let values = vec![
+      json!({ "name": "Alice", "active": true }),
+      json!({ "name": "Bob" }),
+    ];
+
+    let path = temp_path("archive");
+    let file = std::fs::OpenOptions::new()
+      .read(true)
+      .write(true)
+      .create(true)
+      .truncate(true)
+      .open(&path)
+      .unwrap();
+    let mut archive = Archive::create(file).unwrap();
+    for value in &values {
+      let object = crate::encode(&schema, value).unwrap();
+      archive.append(&object.to_bytes()).unwrap();
+    }
+    drop(archive);
+
+    let mapped = unsafe { MappedFile::open(&path) }.unwrap();
+    let view = mapped.archive().unwrap();
+    let decoded: Vec<Value> = (0..view.len())
+      .map(|i| crate::decode(&schema, &view.read(i).unwrap()).unwrap())
+      .collect();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(values, decoded);
+  }
+
+  #[test]
+  fn decode_path_of_mapped_object() {
+    let schema =
+      Schema::new(CompositeType::List(List(Box::new(Type::Nested(record())))));
+    let value = json!([
+      { "name": "Alice", "active": true, "team": "red" },
+      { "name": "Bob" }
+    ]);
+
+    // Written the way `chii compress --index` writes it: the header, then the
+    // object, then its footers
+    let options = EncodeOptions {
+      layout: Layout::ByteAligned,
+      unknown_fields: UnknownFieldPolicy::Preserve,
+      ..Default::default()
+    };
+    let header = Header {
+      unknown_fields: true,
+      index: true,
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let decode_options = header.decode_options(DecodeOptions::default());
+    let mut object = crate::encode_with(&schema, &value, &options)
+      .unwrap()
+      .to_bytes();
+    UnknownFields::collect(&schema, &value).append_to(&mut object);
+    let index = Index::build_with(&schema, &object, 1, decode_options).unwrap();
+    index.append_to(&mut object);
+    let mut bytes = header.to_bytes().to_vec();
+    bytes.extend_from_slice(&object);
+
+    let path = temp_path("object");
+    std::fs::write(&path, &bytes).unwrap();
+    let mapped = unsafe { MappedFile::open(&path) }.unwrap();
+    let name = mapped
+      .decode_path(&schema, &"[1].name".parse().unwrap())
+      .unwrap();
+    let element = mapped.decode_element(&schema, 1).unwrap();
+    let file = mapped.file().unwrap();
+    let whole = mapped.decode(&schema).unwrap();
+    std::fs::remove_file(&path).unwrap();
+
+    assert_eq!(Some(json!("Bob")), name);
+    assert_eq!(json!({ "name": "Bob" }), element);
+    assert_eq!(header, file.header);
+    assert_eq!(Some(index), file.index);
+    assert_eq!(value, whole);
+  }
+}
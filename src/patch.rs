@@ -0,0 +1,609 @@
+//! The `patch` module implements a compact binary patch format between two
+//! compressed objects encoded with the same schema.
+//!
+//! A patch is computed by splitting both objects into their individual blocks
+//! and finding the shortest edit script between the two block sequences. Only
+//! blocks which appear in the new object but not the old one are stored in the
+//! patch; unchanged runs of blocks are stored as references into the old
+//! object. This makes patches for slowly-changing documents very small. The
+//! bytes which follow the blocks of the new object, such as its
+//! [dictionaries](crate::dictionary) footer, are stored in the patch as they
+//! are, so that the patched object is followed by them too.
+//!
+//! A serialized patch is laid out as follows:
+//!
+//! ```text
+//! base hash | op count | op... | footer length | footer
+//! ```
+//!
+//! The base hash is an 8 byte little endian hash of the old object which is
+//! used to make sure that a patch is only ever applied to the object it was
+//! created from. Each op starts with a tag byte: 0 for a copy of a run of old
+//! blocks (followed by the VIE encoded index of the first block and the number
+//! of blocks) or 1 for an insertion of new bits (followed by the VIE encoded
+//! number of bits and the bits themselves padded to a whole byte). The footer
+//! holds the bytes following the new object's blocks, preceded by their VIE
+//! encoded length.
+
+use crate::bit::{BitReader, BitVec};
+use crate::decode::{block_spans, DecodeOptions};
+use crate::math;
+use crate::prelude::*;
+use crate::schema::Schema;
+use crate::vie::CodePoint;
+use anyhow::{anyhow, bail, Result};
+use core::ops::Range;
+
+/// A single patch operation.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum Op {
+  /// Copies a run of `len` blocks from the old object starting at block
+  /// `start`.
+  Copy { start: usize, len: usize },
+  /// Inserts new bits which make up one or more blocks.
+  Insert(BitVec),
+}
+
+/// A patch which transforms one compressed object into another.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct Patch {
+  base: u64,
+  ops: Vec<Op>,
+  /// The bytes which follow the blocks of the new object.
+  footer: Vec<u8>,
+}
+
+impl Patch {
+  /// Computes a patch which transforms the `old` object into the `new` one.
+  ///
+  /// Both objects must be encoded with `schema`. Any footers following the
+  /// blocks of the `new` object are carried over to the patched object, while
+  /// those following the `old` one are dropped.
+  pub fn diff(schema: &Schema, old: &[u8], new: &[u8]) -> Result<Self> {
+    Patch::diff_with(schema, old, new, DecodeOptions::default())
+  }
+
+  /// Computes a patch which transforms the `old` object into the `new` one,
+  /// both of which were encoded in the way described by `options`.
+  pub fn diff_with(
+    schema: &Schema,
+    old: &[u8],
+    new: &[u8],
+    options: DecodeOptions,
+  ) -> Result<Self> {
+    let (a, _) = split_blocks(schema, old, &options)?;
+    let (b, end) = split_blocks(schema, new, &options)?;
+
+    let mut ops: Vec<Op> = Vec::new();
+    for edit in shortest_edit(&a, &b) {
+      match edit {
+        Edit::Keep(i) => match ops.last_mut() {
+          Some(Op::Copy { start, len }) if *start + *len == i => *len += 1,
+          _ => ops.push(Op::Copy { start: i, len: 1 }),
+        },
+        Edit::Insert(j) => {
+          let mut bits = b[j].clone();
+          match ops.last_mut() {
+            Some(Op::Insert(prev)) => prev.append(&mut bits),
+            _ => ops.push(Op::Insert(bits)),
+          }
+        }
+        Edit::Delete(_) => {}
+      }
+    }
+
+    Ok(Patch {
+      base: math::fnv1a(old),
+      ops,
+      footer: new[math::div_ceil(end, 8)..].to_vec(),
+    })
+  }
+
+  /// Applies this patch to the `old` object producing the bytes of the new
+  /// object.
+  pub fn apply(&self, schema: &Schema, old: &[u8]) -> Result<Vec<u8>> {
+    self.apply_with(schema, old, DecodeOptions::default())
+  }
+
+  /// Applies this patch to the `old` object, which was encoded in the way
+  /// described by `options`, producing the bytes of the new object.
+  pub fn apply_with(
+    &self,
+    schema: &Schema,
+    old: &[u8],
+    options: DecodeOptions,
+  ) -> Result<Vec<u8>> {
+    if math::fnv1a(old) != self.base {
+      bail!("patch does not apply to this object");
+    }
+
+    let spans = block_spans(schema, old, &options)?;
+    let mut r = BitReader::new(old).with_bit_order(options.bit_order);
+    let mut bits = BitVec::new();
+    for op in &self.ops {
+      match op {
+        Op::Copy { start, len } => {
+          let missing = || anyhow!("patch refers to a missing block");
+          let end = start.checked_add(*len).ok_or_else(missing)?;
+          let run = spans.get(*start..end).ok_or_else(missing)?;
+          for span in run {
+            bits.append(&mut read_span(&mut r, span.clone())?);
+          }
+        }
+        Op::Insert(data) => bits.append(&mut data.clone()),
+      }
+    }
+
+    let mut bytes = bits.to_bytes();
+    options.bit_order.repack(&mut bytes);
+    bytes.extend_from_slice(&self.footer);
+    Ok(bytes)
+  }
+
+  /// The operations which make up this patch.
+  #[inline]
+  pub fn ops(&self) -> &[Op] {
+    &self.ops
+  }
+
+  /// Serializes this patch to bytes.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let mut bytes = self.base.to_le_bytes().to_vec();
+    bytes.extend_from_slice(CodePoint::from(self.ops.len() as u64).bytes());
+    for op in &self.ops {
+      match op {
+        Op::Copy { start, len } => {
+          bytes.push(0);
+          bytes.extend_from_slice(CodePoint::from(*start as u64).bytes());
+          bytes.extend_from_slice(CodePoint::from(*len as u64).bytes());
+        }
+        Op::Insert(data) => {
+          bytes.push(1);
+          bytes.extend_from_slice(CodePoint::from(data.len() as u64).bytes());
+          bytes.extend_from_slice(&data.to_bytes());
+        }
+      }
+    }
+    bytes.extend_from_slice(CodePoint::from(self.footer.len() as u64).bytes());
+    bytes.extend_from_slice(&self.footer);
+    bytes
+  }
+
+  /// Deserializes a patch from bytes produced by [`to_bytes`].
+  ///
+  /// [`to_bytes`]: Patch::to_bytes
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    let truncated = || anyhow!("patch is truncated");
+    if bytes.len() < 8 {
+      return Err(truncated());
+    }
+
+    let mut base = [0u8; 8];
+    base.copy_from_slice(&bytes[..8]);
+    let mut rest = &bytes[8..];
+
+    let next = |rest: &mut &[u8]| -> Result<usize> {
+      let cp = CodePoint::parse(rest).ok_or_else(truncated)?;
+      *rest = &rest[cp.count()..];
+      cp.decode::<u64>()
+        .map(|x| x as usize)
+        .ok_or_else(|| anyhow!("patch value overflow"))
+    };
+
+    let count = next(&mut rest)?;
+    let mut ops = Vec::new();
+    for _ in 0..count {
+      let (&tag, r) = rest.split_first().ok_or_else(truncated)?;
+      rest = r;
+      let op = match tag {
+        0 => {
+          let start = next(&mut rest)?;
+          let len = next(&mut rest)?;
+          Op::Copy { start, len }
+        }
+        1 => {
+          let n = next(&mut rest)?;
+          let byte_len = math::div_ceil(n, 8);
+          if rest.len() < byte_len {
+            return Err(truncated());
+          }
+          let mut data = BitVec::from_bytes(&rest[..byte_len]);
+          data.truncate(n);
+          rest = &rest[byte_len..];
+          Op::Insert(data)
+        }
+        _ => bail!("unknown patch operation: {}", tag),
+      };
+      ops.push(op);
+    }
+
+    let len = next(&mut rest)?;
+    if rest.len() < len {
+      return Err(truncated());
+    } else if rest.len() > len {
+      bail!("unexpected trailing bytes after patch");
+    }
+
+    Ok(Patch {
+      base: u64::from_le_bytes(base),
+      ops,
+      footer: rest.to_vec(),
+    })
+  }
+}
+
+/// Splits a compressed object into the bits of its individual blocks,
+/// returning them along with the bit position at which the last one ends.
+fn split_blocks(
+  schema: &Schema,
+  bytes: &[u8],
+  options: &DecodeOptions,
+) -> Result<(Vec<BitVec>, usize)> {
+  let mut r = BitReader::new(bytes).with_bit_order(options.bit_order);
+  let spans = block_spans(schema, bytes, options)?;
+  let end = spans.last().map_or(0, |span| span.end);
+  let blocks = spans
+    .into_iter()
+    .map(|span| read_span(&mut r, span))
+    .collect::<Result<_>>()?;
+  Ok((blocks, end))
+}
+
+fn read_span(r: &mut BitReader, span: Range<usize>) -> Result<BitVec> {
+  let err = || anyhow!("block is out of bounds");
+  r.seek(span.start).ok_or_else(err)?;
+  r.read_bits(span.end - span.start).ok_or_else(err)
+}
+
+/// A single step in an edit script.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Edit {
+  /// Keep the `i`th old element.
+  Keep(usize),
+  /// Delete the `i`th old element.
+  Delete(usize),
+  /// Insert the `j`th new element.
+  Insert(usize),
+}
+
+/// Computes the shortest edit script transforming `a` into `b` using the
+/// linear space variant of Myers' difference algorithm.
+///
+/// Rather than keeping the furthest reaching paths of every edit distance
+/// around to backtrack through, the middle of the shortest path is found by
+/// searching from both ends at once and the halves on either side of it are
+/// solved recursively. This needs space linear in the length of the inputs.
+fn shortest_edit<T: PartialEq>(a: &[T], b: &[T]) -> Vec<Edit> {
+  let mut edits = Vec::with_capacity(a.len() + b.len());
+  push_edits(a, b, 0, 0, &mut edits);
+  edits
+}
+
+/// Pushes the edits transforming `a` into `b`, which start at the `i`th old
+/// and `j`th new element, onto `edits`.
+fn push_edits<T: PartialEq>(
+  a: &[T],
+  b: &[T],
+  i: usize,
+  j: usize,
+  edits: &mut Vec<Edit>,
+) {
+  let prefix = a.iter().zip(b).take_while(|(x, y)| x == y).count();
+  edits.extend((i..i + prefix).map(Edit::Keep));
+  let (a, b, i, j) = (&a[prefix..], &b[prefix..], i + prefix, j + prefix);
+  let suffix = a
+    .iter()
+    .rev()
+    .zip(b.iter().rev())
+    .take_while(|(x, y)| x == y)
+    .count();
+  let (a, b) = (&a[..a.len() - suffix], &b[..b.len() - suffix]);
+
+  match middle_snake(a, b) {
+    Some((x, y)) => {
+      push_edits(&a[..x], &b[..y], i, j, edits);
+      push_edits(&a[x..], &b[y..], i + x, j + y, edits);
+    }
+    None => {
+      edits.extend((i..i + a.len()).map(Edit::Delete));
+      edits.extend((j..j + b.len()).map(Edit::Insert));
+    }
+  }
+  edits.extend((i + a.len()..i + a.len() + suffix).map(Edit::Keep));
+}
+
+/// Finds the start of the middle snake of a shortest path through the edit
+/// graph of `a` and `b`, which must neither start nor end with the same
+/// element.
+///
+/// Returns `None` if either is empty, in which case there is nothing to split.
+fn middle_snake<T: PartialEq>(a: &[T], b: &[T]) -> Option<(usize, usize)> {
+  if a.is_empty() || b.is_empty() {
+    return None;
+  }
+
+  let n = a.len() as isize;
+  let m = b.len() as isize;
+  let delta = n - m;
+  let odd = delta % 2 != 0;
+  let max = (n + m + 1) / 2 + 1;
+  let idx = |k: isize| (k + max) as usize;
+
+  // The furthest reaching x for each diagonal k, searching forward from the
+  // start and backward from the end
+  let mut forward = vec![0isize; 2 * max as usize + 1];
+  let mut backward = vec![0isize; 2 * max as usize + 1];
+  for d in 0..max {
+    for k in (-d..=d).rev().step_by(2) {
+      let mut x =
+        if k == -d || (k != d && forward[idx(k - 1)] < forward[idx(k + 1)]) {
+          forward[idx(k + 1)]
+        } else {
+          forward[idx(k - 1)] + 1
+        };
+      let (x0, y0) = (x, x - k);
+      let mut y = y0;
+      while x < n && y < m && y >= 0 && a[x as usize] == b[y as usize] {
+        x += 1;
+        y += 1;
+      }
+      forward[idx(k)] = x;
+      if odd && (k - delta).abs() < d && x + backward[idx(delta - k)] >= n {
+        return Some((x0 as usize, y0 as usize));
+      }
+    }
+
+    for k in (-d..=d).rev().step_by(2) {
+      let mut x =
+        if k == -d || (k != d && backward[idx(k - 1)] < backward[idx(k + 1)]) {
+          backward[idx(k + 1)]
+        } else {
+          backward[idx(k - 1)] + 1
+        };
+      let mut y = x - k;
+      while x < n
+        && y < m
+        && y >= 0
+        && a[(n - x - 1) as usize] == b[(m - y - 1) as usize]
+      {
+        x += 1;
+        y += 1;
+      }
+      backward[idx(k)] = x;
+      if !odd && (k - delta).abs() <= d && x + forward[idx(delta - k)] >= n {
+        return Some(((n - x) as usize, (m - y) as usize));
+      }
+    }
+  }
+  None
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::data::{BitOrder, Layout};
+  use crate::schema::{CompositeType, List, Record, Type};
+  use serde_json::{json, Value};
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut item = BTreeMap::new();
+    item.insert("done".to_string(), Type::Name("bool".to_string()));
+    item.insert("title".to_string(), Type::PassThrough);
+    Schema::new(CompositeType::List(List(Box::new(Type::Nested(
+      CompositeType::Record(Record::new(item)),
+    )))))
+  }
+
+  fn encode(schema: &Schema, value: &Value) -> Vec<u8> {
+    let bits: BitVec = crate::encode(schema, value).unwrap().into();
+    bits.to_bytes()
+  }
+
+  #[test]
+  fn shortest_edit_script() {
+    let a = ['a', 'b', 'c', 'a', 'b', 'b', 'a'];
+    let b = ['c', 'b', 'a', 'b', 'a', 'c'];
+    let edits = shortest_edit(&a, &b);
+    let changes = edits.iter().filter(|e| !matches!(e, Edit::Keep(_)));
+    assert_eq!(5, changes.count());
+
+    let rebuilt: Vec<char> = edits
+      .iter()
+      .filter_map(|e| match e {
+        Edit::Keep(i) => Some(a[*i]),
+        Edit::Insert(j) => Some(b[*j]),
+        Edit::Delete(_) => None,
+      })
+      .collect();
+    assert_eq!(&b[..], &rebuilt[..]);
+  }
+
+  #[test]
+  fn shortest_edit_is_minimal() {
+    // Sequences over a small alphabet share many elements in many places
+    let mut state = 0x2545_f491_u32;
+    let mut next = |len: u32| {
+      state ^= state << 13;
+      state ^= state >> 17;
+      state ^= state << 5;
+      state % len
+    };
+    for _ in 0..500 {
+      let a: Vec<u32> = (0..next(12)).map(|_| next(3)).collect();
+      let b: Vec<u32> = (0..next(12)).map(|_| next(3)).collect();
+
+      // The length of the longest common subsequence, by dynamic programming
+      let mut lcs = vec![vec![0; b.len() + 1]; a.len() + 1];
+      for i in 0..a.len() {
+        for j in 0..b.len() {
+          lcs[i + 1][j + 1] = if a[i] == b[j] {
+            lcs[i][j] + 1
+          } else {
+            lcs[i][j + 1].max(lcs[i + 1][j])
+          };
+        }
+      }
+
+      let edits = shortest_edit(&a, &b);
+      let changes = edits.iter().filter(|e| !matches!(e, Edit::Keep(_)));
+      let expected = a.len() + b.len() - 2 * lcs[a.len()][b.len()];
+      assert_eq!(expected, changes.count(), "{:?} -> {:?}", a, b);
+
+      let mut old = 0;
+      let mut rebuilt = Vec::new();
+      for edit in &edits {
+        match *edit {
+          Edit::Keep(i) | Edit::Delete(i) => {
+            assert_eq!(old, i);
+            old += 1;
+            if let Edit::Keep(_) = edit {
+              rebuilt.push(a[i]);
+            }
+          }
+          Edit::Insert(j) => rebuilt.push(b[j]),
+        }
+      }
+      assert_eq!(a.len(), old);
+      assert_eq!(b, rebuilt);
+    }
+  }
+
+  #[test]
+  fn patch_roundtrip() {
+    let schema = schema();
+    let old = encode(
+      &schema,
+      &json!([
+        { "title": "write code", "done": true },
+        { "title": "test code", "done": false },
+        { "title": "ship it", "done": false }
+      ]),
+    );
+    let new_value = json!([
+      { "title": "write code", "done": true },
+      { "title": "test code", "done": true },
+      { "title": "ship it", "done": false },
+      { "title": "celebrate", "done": false }
+    ]);
+    let new = encode(&schema, &new_value);
+
+    let patch = Patch::diff(&schema, &old, &new).unwrap();
+    let patch = Patch::from_bytes(&patch.to_bytes()).unwrap();
+    let patched = patch.apply(&schema, &old).unwrap();
+    assert_eq!(new, patched);
+    assert_eq!(new_value, crate::decode(&schema, &patched).unwrap());
+
+    // Only the changed blocks should be stored as insertions
+    let inserted: usize = patch
+      .ops()
+      .iter()
+      .map(|op| match op {
+        Op::Insert(bits) => bits.len(),
+        _ => 0,
+      })
+      .sum();
+    assert!(inserted < new.len() * 8 / 2);
+  }
+
+  #[test]
+  fn patch_byte_aligned_roundtrip() {
+    let schema = schema();
+    let encode = |value: &Value| {
+      let options = crate::EncodeOptions {
+        layout: Layout::ByteAligned,
+        ..Default::default()
+      };
+      crate::encode_with(&schema, value, &options)
+        .unwrap()
+        .to_bytes()
+    };
+    let options = DecodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let old = encode(&json!([{ "title": "a" }, { "title": "b" }]));
+    let new_value = json!([{ "title": "a", "done": true }, { "title": "b" }]);
+    let new = encode(&new_value);
+
+    let patch = Patch::diff_with(&schema, &old, &new, options).unwrap();
+    let patched = patch.apply_with(&schema, &old, options).unwrap();
+    assert_eq!(new, patched);
+    let decoded = crate::decode_with(&schema, &patched, options).unwrap();
+    assert_eq!(new_value, decoded);
+  }
+
+  #[test]
+  fn patch_lsb_first_roundtrip() {
+    let schema = schema();
+    let encode = |value: &Value| {
+      let options = crate::EncodeOptions {
+        bit_order: BitOrder::LsbFirst,
+        ..Default::default()
+      };
+      crate::encode_with(&schema, value, &options)
+        .unwrap()
+        .to_bytes()
+    };
+    let options = DecodeOptions {
+      bit_order: BitOrder::LsbFirst,
+      ..Default::default()
+    };
+    let old = encode(&json!([{ "title": "a" }, { "title": "b" }]));
+    let new = encode(&json!([{ "title": "a" }, { "title": "c" }]));
+
+    let patch = Patch::diff_with(&schema, &old, &new, options).unwrap();
+    assert_eq!(new, patch.apply_with(&schema, &old, options).unwrap());
+  }
+
+  #[test]
+  fn patch_carries_footers() {
+    let mut item = BTreeMap::new();
+    item.insert("status".to_string(), Type::Dictionary { size: 2 });
+    let schema = Schema::new(CompositeType::List(List(Box::new(
+      Type::Nested(CompositeType::Record(Record::new(item))),
+    ))));
+    let encode = |value: &Value| {
+      let mut bytes = Vec::new();
+      crate::encode_to(&schema, value, &mut bytes).unwrap();
+      bytes
+    };
+    let old = encode(&json!([{ "status": "open" }, { "status": "open" }]));
+    let new_value = json!([
+      { "status": "done" },
+      { "status": "done" },
+      { "status": "open" }
+    ]);
+    let new = encode(&new_value);
+
+    let patch = Patch::diff(&schema, &old, &new).unwrap();
+    let patch = Patch::from_bytes(&patch.to_bytes()).unwrap();
+    let patched = patch.apply(&schema, &old).unwrap();
+    assert_eq!(new, patched);
+    assert_eq!(new_value, crate::decode(&schema, &patched).unwrap());
+  }
+
+  #[test]
+  fn patch_rejects_overflowing_copy() {
+    let schema = schema();
+    let old = encode(&schema, &json!([{ "title": "a" }]));
+    let patch = Patch {
+      base: math::fnv1a(&old),
+      ops: vec![Op::Copy {
+        start: 1,
+        len: usize::MAX,
+      }],
+      footer: Vec::new(),
+    };
+    assert!(patch.apply(&schema, &old).is_err());
+  }
+
+  #[test]
+  fn patch_rejects_wrong_base() {
+    let schema = schema();
+    let old = encode(&schema, &json!([{ "title": "a" }]));
+    let new = encode(&schema, &json!([{ "title": "b" }]));
+    let patch = Patch::diff(&schema, &old, &new).unwrap();
+    assert!(patch.apply(&schema, &new).is_err());
+  }
+}
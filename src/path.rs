@@ -0,0 +1,154 @@
+//! The `path` module implements a small path syntax for addressing values
+//! within a structured document.
+//!
+//! Paths are written as a sequence of segments in a syntax similar to `jq`:
+//! `.name` selects a record field and `[n]` selects a list element. For
+//! example, `.courses[2].grade` selects the `grade` field of the third element
+//! of the `courses` list. The path `.` refers to the root of the document.
+
+use crate::prelude::*;
+use anyhow::{anyhow, bail, Error, Result};
+use core::fmt;
+use core::str::FromStr;
+
+/// A single step in a [`Path`].
+#[derive(Clone, Debug, Eq, PartialEq, Hash)]
+pub enum Segment {
+  /// Selects a named field of a record.
+  Field(String),
+  /// Selects an element of a list by its index.
+  Index(usize),
+}
+
+/// A sequence of segments which address a value within a document.
+#[derive(Clone, Debug, Default, Eq, PartialEq, Hash)]
+pub struct Path(pub Vec<Segment>);
+
+impl Path {
+  /// The path referring to the root of a document.
+  pub fn root() -> Self {
+    Path(Vec::new())
+  }
+
+  /// The segments which make up this path.
+  #[inline]
+  pub fn segments(&self) -> &[Segment] {
+    &self.0
+  }
+
+  /// Formats this path as a JSON pointer as defined by RFC 6901 (e.g.,
+  /// `/courses/2/grade`). The root path is the empty string.
+  pub fn to_pointer(&self) -> String {
+    let mut pointer = String::new();
+    for segment in &self.0 {
+      pointer.push('/');
+      match segment {
+        Segment::Field(name) => {
+          pointer.push_str(&name.replace('~', "~0").replace('/', "~1"))
+        }
+        Segment::Index(i) => pointer.push_str(&i.to_string()),
+      }
+    }
+    pointer
+  }
+}
+
+impl FromStr for Path {
+  type Err = Error;
+
+  fn from_str(s: &str) -> Result<Self> {
+    let mut segments = Vec::new();
+    let mut rest = s.trim();
+    if rest == "." {
+      return Ok(Path::root());
+    }
+
+    while !rest.is_empty() {
+      if let Some(r) = rest.strip_prefix('.') {
+        let end = r.find(&['.', '['][..]).unwrap_or(r.len());
+        if end == 0 {
+          bail!("expected field name in path: {}", s);
+        }
+        segments.push(Segment::Field(r[..end].to_string()));
+        rest = &r[end..];
+      } else if let Some(r) = rest.strip_prefix('[') {
+        let end = r
+          .find(']')
+          .ok_or_else(|| anyhow!("unterminated '[' in path: {}", s))?;
+        let index = r[..end]
+          .trim()
+          .parse()
+          .map_err(|_| anyhow!("invalid list index in path: {}", s))?;
+        segments.push(Segment::Index(index));
+        rest = &r[end + 1..];
+      } else {
+        bail!("expected '.' or '[' in path: {}", s);
+      }
+    }
+
+    Ok(Path(segments))
+  }
+}
+
+impl fmt::Display for Path {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    if self.0.is_empty() {
+      return write!(f, ".");
+    }
+    for segment in &self.0 {
+      match segment {
+        Segment::Field(name) => write!(f, ".{}", name)?,
+        Segment::Index(i) => write!(f, "[{}]", i)?,
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn parse_root() {
+    assert_eq!(Path::root(), ".".parse().unwrap());
+  }
+
+  #[test]
+  fn parse_fields_and_indices() {
+    let path: Path = ".courses[2].grade".parse().unwrap();
+    assert_eq!(
+      vec![
+        Segment::Field("courses".to_string()),
+        Segment::Index(2),
+        Segment::Field("grade".to_string()),
+      ],
+      path.0
+    );
+    assert_eq!(".courses[2].grade", path.to_string());
+  }
+
+  #[test]
+  fn parse_leading_index() {
+    let path: Path = "[10].name".parse().unwrap();
+    assert_eq!(
+      vec![Segment::Index(10), Segment::Field("name".to_string())],
+      path.0
+    );
+  }
+
+  #[test]
+  fn json_pointer() {
+    let path: Path = ".courses[2].a/b~c".parse().unwrap();
+    assert_eq!("/courses/2/a~1b~0c", path.to_pointer());
+    assert_eq!("", Path::root().to_pointer());
+  }
+
+  #[test]
+  fn parse_invalid() {
+    assert!("courses".parse::<Path>().is_err());
+    assert!(".courses[x]".parse::<Path>().is_err());
+    assert!(".courses[1".parse::<Path>().is_err());
+    assert!("..name".parse::<Path>().is_err());
+  }
+}
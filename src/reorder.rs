@@ -0,0 +1,344 @@
+//! The `reorder` module proposes identifiers for the fields of a schema's
+//! records based on which fields actually appear in a corpus of data.
+//!
+//! Fields are given identifiers from the most to the least often present,
+//! with reserved fields, which are never present, last. Field markers are
+//! already as narrow as the number of identifiers allows, which reordering
+//! doesn't change, but the markers of sparse records are differences between
+//! identifiers: the closer together the fields which appear are, the smaller
+//! these differences and the more of them fit in a single byte. Fields which
+//! appear equally often keep their relative order so that reordering an
+//! already reordered schema changes nothing.
+//!
+//! The reordered schema encodes values differently from the original one, so
+//! its [version](Schema::version) is incremented if it has one and the
+//! original is bundled with it (see [`Schema::with_history`]) to decode
+//! existing objects. Field names don't change, so values need no migration,
+//! but the [mapping](Reordering::mapping) from old to new identifiers is
+//! given for implementations which work with identifiers directly.
+
+use crate::encode::union_alternative;
+use crate::path::{Path, Segment};
+use crate::schema::{CompositeType, Record, Schema, Type};
+use anyhow::{bail, Result};
+use serde_json::{Map, Value};
+use std::collections::BTreeMap;
+
+/// How often a field is present and its identifiers before and after
+/// reordering.
+#[derive(Clone, Debug, PartialEq)]
+pub struct FieldOrder {
+  /// The name of the field.
+  pub name: String,
+  /// The number of values of the record in which the field is present.
+  pub present: usize,
+  /// The first identifier of the field in the original schema.
+  pub old: u32,
+  /// The first identifier of the field in the reordered schema.
+  pub new: u32,
+}
+
+/// The new order of the fields of a record.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RecordOrder {
+  /// The path of the record within the schema, where list indices are always
+  /// zero and stand for every element.
+  pub path: Path,
+  /// The number of values of the record in the corpus.
+  pub values: usize,
+  /// The fields and reserved fields of the record in their new order.
+  pub fields: Vec<FieldOrder>,
+}
+
+impl RecordOrder {
+  /// Returns `true` if no field's identifier changes.
+  pub fn is_unchanged(&self) -> bool {
+    self.fields.iter().all(|f| f.old == f.new)
+  }
+}
+
+/// A schema whose fields have been reordered, along with the new order of
+/// each of its records.
+#[derive(Clone, Debug)]
+pub struct Reordering {
+  /// The reordered schema.
+  pub schema: Schema,
+  /// The records of the schema in schema order.
+  pub records: Vec<RecordOrder>,
+}
+
+impl Reordering {
+  /// The identifiers of the fields of every record whose fields were
+  /// reordered, as a JSON array of records of the form
+  /// `{"path": ..., "fields": {name: {"old": id, "new": id}}}`.
+  pub fn mapping(&self) -> Value {
+    let records = self
+      .records
+      .iter()
+      .filter(|record| !record.is_unchanged())
+      .map(|record| {
+        let fields: Map<String, Value> = record
+          .fields
+          .iter()
+          .map(|f| {
+            let mut ids = Map::new();
+            ids.insert("old".to_string(), Value::from(f.old));
+            ids.insert("new".to_string(), Value::from(f.new));
+            (f.name.clone(), Value::Object(ids))
+          })
+          .collect();
+        let mut map = Map::new();
+        map.insert("path".to_string(), Value::from(record.path.to_string()));
+        map.insert("fields".to_string(), Value::Object(fields));
+        Value::Object(map)
+      })
+      .collect();
+    Value::Array(records)
+  }
+}
+
+/// Reorders the fields of every record in `schema` by how often they are
+/// present in the values of `corpus`, which must match `schema`.
+pub fn reorder(schema: &Schema, corpus: &[Value]) -> Result<Reordering> {
+  let mut counts = Vec::new();
+  collect(schema.root(), &mut counts);
+  for value in corpus {
+    tally(schema.root(), value, 0, &mut counts)?;
+  }
+
+  let mut reordered = schema.clone();
+  let mut records = Vec::new();
+  let mut counts = counts.into_iter();
+  apply(
+    reordered.root_mut(),
+    &mut Vec::new(),
+    &mut counts,
+    &mut records,
+  );
+  let reordered = match schema.version() {
+    Some(version) => reordered.with_version(version + 1),
+    None => reordered,
+  };
+  let reordered = match reordered.fingerprint() == schema.fingerprint() {
+    true => reordered,
+    false => reordered.with_history(Some(schema.clone())),
+  };
+  Ok(Reordering {
+    schema: reordered,
+    records,
+  })
+}
+
+/// How often the fields of a record are present, by name.
+#[derive(Default)]
+struct Counts {
+  values: usize,
+  present: BTreeMap<String, usize>,
+}
+
+/// Adds empty counts for every record in `ty` to `out`.
+fn collect(ty: &Type, out: &mut Vec<Counts>) {
+  match ty {
+    Type::Nested(CompositeType::Record(record)) => {
+      out.push(Counts::default());
+      for ty in record.fields.values() {
+        collect(ty, out);
+      }
+    }
+    Type::Nested(CompositeType::List(list)) => collect(&list.0, out),
+    Type::Union { alternatives } => {
+      for ty in alternatives {
+        collect(ty, out);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// Counts the fields present in `value`, whose first record has the counts
+/// at index `id`.
+fn tally(
+  ty: &Type,
+  value: &Value,
+  id: usize,
+  counts: &mut [Counts],
+) -> Result<()> {
+  match (ty, value) {
+    (Type::Nested(CompositeType::Record(record)), Value::Object(map)) => {
+      counts[id].values += 1;
+      let mut next = id + 1;
+      for (name, ty) in &record.fields {
+        if let Some(value) = map.get(name) {
+          *counts[id].present.entry(name.clone()).or_default() += 1;
+          tally(ty, value, next, counts)?;
+        }
+        next += records(ty);
+      }
+    }
+    // Every element shares the counts of the element type
+    (Type::Nested(CompositeType::List(list)), Value::Array(arr)) => {
+      for value in arr {
+        tally(&list.0, value, id, counts)?;
+      }
+    }
+    (Type::Union { alternatives }, value) => {
+      let (index, _) = union_alternative(alternatives, value)?;
+      let id = id + alternatives[..index].iter().map(records).sum::<usize>();
+      tally(&alternatives[index], value, id, counts)?;
+    }
+    (Type::Nested(CompositeType::Record(_)), value) => {
+      bail!("expected an object, found {}", value)
+    }
+    (Type::Nested(CompositeType::List(_)), Value::Null) => {}
+    (Type::Nested(CompositeType::List(_)), value) => {
+      bail!("expected an array, found {}", value)
+    }
+    _ => {}
+  }
+  Ok(())
+}
+
+/// The number of records in `ty`.
+fn records(ty: &Type) -> usize {
+  match ty {
+    Type::Nested(CompositeType::Record(record)) => {
+      1 + record.fields.values().map(records).sum::<usize>()
+    }
+    Type::Nested(CompositeType::List(list)) => records(&list.0),
+    Type::Union { alternatives } => alternatives.iter().map(records).sum(),
+    _ => 0,
+  }
+}
+
+/// Reorders the fields of the records in `ty` using the next of `counts`,
+/// adding their new orders to `out`.
+fn apply(
+  ty: &mut Type,
+  path: &mut Vec<Segment>,
+  counts: &mut impl Iterator<Item = Counts>,
+  out: &mut Vec<RecordOrder>,
+) {
+  match ty {
+    Type::Nested(CompositeType::Record(record)) => {
+      let c = counts.next().expect("a record has counts");
+      out.push(order(record, Path(path.clone()), c));
+      for (name, ty) in record.fields.iter_mut() {
+        path.push(Segment::Field(name.clone()));
+        apply(ty, path, counts, out);
+        path.pop();
+      }
+    }
+    Type::Nested(CompositeType::List(list)) => {
+      path.push(Segment::Index(0));
+      apply(&mut list.0, path, counts, out);
+      path.pop();
+    }
+    Type::Union { alternatives } => {
+      for ty in alternatives {
+        apply(ty, path, counts, out);
+      }
+    }
+    _ => {}
+  }
+}
+
+/// Gives the fields of `record` at `path` identifiers from the most to the
+/// least often present according to `counts`.
+fn order(record: &mut Record, path: Path, counts: Counts) -> RecordOrder {
+  let mut fields: Vec<FieldOrder> = record
+    .names()
+    .map(|(name, old)| FieldOrder {
+      name: name.to_string(),
+      present: counts.present.get(name).copied().unwrap_or_default(),
+      old,
+      new: 0,
+    })
+    .collect();
+  fields.sort_by_key(|f| (core::cmp::Reverse(f.present), f.old));
+
+  let names = fields.iter().map(|f| f.name.clone()).collect();
+  record
+    .set_order(names)
+    .expect("every field and reserved field is ordered");
+  let new: BTreeMap<_, _> = record.names().collect();
+  for f in &mut fields {
+    f.new = new[f.name.as_str()];
+  }
+  RecordOrder {
+    path,
+    values: counts.values,
+    fields,
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::List;
+  use serde_json::json;
+
+  #[test]
+  fn hot_fields_first() {
+    let mut element = BTreeMap::new();
+    element.insert("x".to_string(), Type::PassThrough);
+    element.insert("y".to_string(), Type::PassThrough);
+    let element = Type::Nested(CompositeType::Record(Record::new(element)));
+    let mut fields = BTreeMap::new();
+    fields.insert("a".to_string(), Type::PassThrough);
+    fields.insert("b".to_string(), Type::PassThrough);
+    fields.insert(
+      "c".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(element)))),
+    );
+    fields.insert("z".to_string(), Type::PassThrough);
+    let mut record = Record::new(fields);
+    record.reserved.insert("d".to_string());
+    record.sparse = true;
+    let schema = Schema::new(CompositeType::Record(record));
+    let corpus = [
+      json!({ "z": "1", "c": [{ "y": "1" }, { "x": "2", "y": "3" }] }),
+      json!({ "z": "2", "b": "3" }),
+      json!({ "z": "3" }),
+    ];
+
+    let reordering = reorder(&schema, &corpus).unwrap();
+    let root = &reordering.records[0];
+    assert_eq!(3, root.values);
+    let names: Vec<_> = root.fields.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(vec!["z", "b", "c", "a", "d"], names);
+    let ids: Vec<_> = root.fields.iter().map(|f| (f.old, f.new)).collect();
+    assert_eq!(vec![(4, 0), (1, 1), (2, 2), (0, 3), (3, 4)], ids);
+
+    // Elements of a list share the counts of their record
+    let element = &reordering.records[1];
+    assert_eq!(".c[0]", element.path.to_string());
+    assert_eq!(2, element.values);
+    assert_eq!(vec![("y", 2), ("x", 1)], {
+      let f = &element.fields;
+      f.iter()
+        .map(|f| (f.name.as_str(), f.present))
+        .collect::<Vec<_>>()
+    });
+
+    // Values encode the same way with the reordered schema's identifiers
+    // and objects encoded with the original schema can still be decoded
+    let new = &reordering.schema;
+    assert_ne!(schema.fingerprint(), new.fingerprint());
+    for value in &corpus {
+      let bytes = crate::encode(new, value).unwrap().to_bytes();
+      assert_eq!(*value, crate::decode(new, &bytes).unwrap());
+      let old = crate::encode(&schema, value).unwrap().to_bytes();
+      let original = new.resolve(schema.fingerprint()).unwrap();
+      assert_eq!(*value, crate::decode(original, &old).unwrap());
+    }
+
+    let mapping = reordering.mapping();
+    assert_eq!(2, mapping.as_array().unwrap().len());
+    assert_eq!(json!({ "old": 4, "new": 0 }), mapping[0]["fields"]["z"]);
+
+    // Reordering again changes nothing
+    let again = reorder(new, &corpus).unwrap();
+    assert!(again.records.iter().all(RecordOrder::is_unchanged));
+    assert_eq!(json!([]), again.mapping());
+  }
+}
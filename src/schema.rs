@@ -1,13 +1,22 @@
 //! The `schema` module implements the schema which is used to encode/decode
 //! compressed objects.
 
-use crate::data::FieldId;
+use crate::comp::{Codebook, Rounding, TransformKind};
+use crate::data::{FieldId, LengthEncoding};
+use crate::epoch::Epoch;
 use crate::math;
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use alloc::collections::{BTreeMap, BTreeSet};
+use anyhow::{anyhow, bail, Result};
+use core::convert::TryFrom;
 use serde::{Deserialize, Serialize};
-use std::collections::{BTreeMap, BTreeSet, HashMap};
+use serde_json::Value;
+
+pub mod dsl;
 
 /// The base type for a record field or list element.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 #[serde(untagged)]
 pub enum Type {
@@ -20,7 +29,42 @@ pub enum Type {
   PassThrough,
 
   /// A named type. The schema will parse and lookup this name and try and
-  /// match it to a compression or encoding format that it knows about.
+  /// match it to a compression or encoding format that it knows about:
+  ///
+  /// * `any` for values of any type, including nulls, which are stored as CBOR
+  ///   (see [`AnyCompressor`]),
+  /// * `bool` for booleans, which take up a single bit,
+  /// * `bwt` for long text which is compressed as much as possible, however
+  ///   slowly, with the experimental [`BwtCompressor`] enabled by the
+  ///   `experimental-bwt` feature,
+  /// * `color` for `#rrggbb` and `#rgb` hex colors, which take up 25 bits (see
+  ///   [`ColorCompressor`]),
+  /// * `f16` for numbers which are stored as half-precision floats, losing all
+  ///   but about 3 significant digits (see [`HalfCompressor`]),
+  /// * `float(precision=N)` for numbers which are rounded to `N` decimal
+  ///   places, losing the rest (see [`QuantizedCompressor`]),
+  /// * `geo(precision=N)` for `[lat, lon]` pairs which are rounded to `N`
+  ///   decimal places, or `geo(precision=N, form=record)` for `{lat, lon}`
+  ///   records (see [`GeoCompressor`]),
+  /// * `lzw` for long free-text strings with repeated substrings (see
+  ///   [`LzwCompressor`]),
+  /// * `smaz` for short strings like names and labels, which are made up of
+  ///   common fragments of text (see [`SmazCompressor`]), and
+  /// * `timestamp` for integer timestamps, which may be stored relative to the
+  ///   schema's [epoch](crate::epoch).
+  ///
+  /// Strings which `lzw` or `smaz` would expand are stored raw instead (see
+  /// [`RawFallback`]).
+  ///
+  /// [`AnyCompressor`]: crate::comp::AnyCompressor
+  /// [`BwtCompressor`]: crate::comp::BwtCompressor
+  /// [`ColorCompressor`]: crate::comp::ColorCompressor
+  /// [`GeoCompressor`]: crate::comp::GeoCompressor
+  /// [`HalfCompressor`]: crate::comp::HalfCompressor
+  /// [`LzwCompressor`]: crate::comp::LzwCompressor
+  /// [`QuantizedCompressor`]: crate::comp::QuantizedCompressor
+  /// [`RawFallback`]: crate::comp::RawFallback
+  /// [`SmazCompressor`]: crate::comp::SmazCompressor
   Name(String),
 
   /// A nested record or list type.
@@ -35,16 +79,134 @@ pub enum Type {
   /// A `BTreeSet` is used here as a deterministic ordering on the variants is
   /// required. The schema uses the ordinal values of each variant when
   /// encoding.
+  ///
+  /// If the probability of every variant is known, variants are instead
+  /// encoded with a Huffman code built on those probabilities (see
+  /// [`WeightedEnumCompressor`]), so that common variants take up fewer bits:
+  ///
+  /// ```yaml
+  /// level:
+  ///   enum: [debug, info, warn, error]
+  ///   probabilities: { debug: 0.05, info: 0.7, warn: 0.2, error: 0.05 }
+  /// ```
+  ///
+  /// `chii stats` compares these probabilities with the frequencies of the
+  /// variants in actual data.
+  ///
+  /// [`WeightedEnumCompressor`]: crate::comp::WeightedEnumCompressor
   Enum {
     #[serde(rename = "enum")]
     variants: BTreeSet<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    probabilities: Option<BTreeMap<String, f64>>,
+  },
+
+  /// An enumeration of possible integer codes for this field/element, such
+  /// as HTTP status codes:
+  ///
+  /// ```yaml
+  /// status: { enum-int: [200, 301, 404, 500] }
+  /// ```
+  ///
+  /// Like [`Enum`](Type::Enum), codes are encoded as their ordinal values, so
+  /// they take up the minimum necessary number of bits however large they are.
+  IntEnum {
+    #[serde(rename = "enum-int")]
+    codes: BTreeSet<i64>,
+  },
+
+  /// A string whose most frequent values in each object are stored in a
+  /// [dictionary](crate::dictionary) of up to `size` entries and replaced
+  /// with their indices:
+  ///
+  /// ```yaml
+  /// method: { dictionary: 8 }
+  /// ```
+  Dictionary {
+    #[serde(rename = "dictionary")]
+    size: usize,
+  },
+
+  /// A string compressed with a Huffman code, usually one which was trained
+  /// on the values of this field/element (see [`Codebook::train`]). Strings
+  /// whose codes would take up more bits than the string itself are stored
+  /// raw instead (see [`RawFallback`](crate::comp::RawFallback)).
+  ///
+  /// The decoder must use exactly the codes the encoder did, so the codebook
+  /// is written out in its canonical form:
+  ///
+  /// ```yaml
+  /// name: { huffman: 0301010261626364 }
+  /// ```
+  Huffman { huffman: Codebook },
+
+  /// A number of fixed granularity which is multiplied by `scale` and stored
+  /// as a small integer, e.g., dollars as cents or seconds as milliseconds.
+  /// Scaled values which aren't whole are rounded to the nearest integer
+  /// unless another [rounding policy](Rounding) is given:
+  ///
+  /// ```yaml
+  /// price: { scale: 100 }
+  /// duration: { scale: 1000, round: floor }
+  /// ```
+  ///
+  /// Decoded values are divided by `scale` again, so they are always
+  /// floating point numbers.
+  Scaled {
+    scale: f64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    round: Option<Rounding>,
+  },
+
+  /// Values which are [transformed](crate::comp::Transform) before they are
+  /// compressed as another non-nested type, e.g., to ignore surrounding
+  /// whitespace and letter case:
+  ///
+  /// ```yaml
+  /// email: { transform: [trim, lowercase], of: ~ }
+  /// ```
+  ///
+  /// None of the transforms which can be declared can be undone, so values
+  /// are decoded in their transformed form.
+  Transformed {
+    transform: Vec<TransformKind>,
+    of: Box<Type>,
+  },
+
+  /// The element type of a list whose elements may be any one of several
+  /// types, e.g., an event stream mixing several shapes of record:
+  ///
+  /// ```yaml
+  /// events:
+  ///   list:
+  ///     union:
+  ///       - record: { page: ~, referrer: ~ }
+  ///       - record: { button: { enum: [left, right] }, x: f16, y: f16 }
+  ///       - f16
+  /// ```
+  ///
+  /// Each element is preceded by a tag holding the index of its type, which
+  /// takes up the minimum necessary number of bits. Elements are encoded as
+  /// the first type which accepts them, so types which accept the same values
+  /// should be ordered from most to least specific. Records accept objects
+  /// whose keys are all fields of the record and which have all of its
+  /// required fields.
+  ///
+  /// Unions can only be the element type of a list and can't be nested
+  /// directly within one another.
+  Union {
+    #[serde(rename = "union")]
+    alternatives: Vec<Type>,
   },
 }
 
 /// A composite type is either a record or list which is composed of other types
 /// some of which may be other records or lists.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
+// Records are much larger than lists, but schemas are built once and then
+// only read, so boxing them would gain nothing
+#[allow(clippy::large_enum_variant)]
 pub enum CompositeType {
   Record(Record),
   List(List),
@@ -56,40 +218,559 @@ pub enum CompositeType {
 /// a deterministic ordering. When encoding a [compressed object], a field's
 /// ordinal value is used to uniquely identify the field in the record.
 ///
+/// In a schema file each field is either given as its type or as a mapping
+/// with a `type` and extra attributes:
+///
+/// ```yaml
+/// record:
+///   name: ~
+///   active:
+///     type: bool
+///     required: true
+///     description: Whether the student is enrolled this term.
+///   role:
+///     type: { enum: [student, teacher] }
+///     default: student
+///   nickname:
+///     type: ~
+///     deprecated: true
+///   tags:
+///     type: { list: ~ }
+///     nullable: true
+///   ssn: { reserved: true }
+/// ```
+///
+/// A list field can be missing from an object, empty or hold elements, which
+/// decode back to exactly what was encoded. A `nullable` list field may also
+/// be `null`. Its length is stored as one more than its number of elements so
+/// that a length of zero can stand for `null`. Only lists can be nullable.
+///
+/// A field's `default` is filled in when an object encoded with an older
+/// version of the schema, from before the field was added, is upgraded (see
+/// [`evolution`](crate::evolution)). It doesn't change the encoding.
+///
+/// A field's `description` says what it holds for the consumers of the data,
+/// e.g., in the documentation written by [`Schema::to_markdown`]. It doesn't
+/// change the encoding either.
+///
+/// A `deprecated` field may no longer be encoded but is still decoded from
+/// existing data. Removing a field would change the identifiers of the fields
+/// which come after it, so a field which is no longer needed at all can be
+/// `reserved` instead: its name keeps its identifier without having a type,
+/// so neither it nor its identifier can be used again.
+///
+/// Field markers are normally as narrow as possible, so adding fields can
+/// make them wider. A record may declare a wider `width` to leave room for
+/// fields added later, in which case the fields are given under `fields`:
+///
+/// ```yaml
+/// record:
+///   width: 6
+///   fields:
+///     name: ~
+/// ```
+///
+/// Wide records where only a few fields are present in each value can be
+/// declared `sparse` the same way. Each value of a sparse record starts with
+/// a flag bit which picks one of two layouts, whichever is smaller: the usual
+/// field markers, or the differences between the markers of consecutive
+/// fields as VIE code points ending with a zero. Fields are always encoded in
+/// schema order in the second layout.
+///
+/// ```yaml
+/// record:
+///   sparse: true
+///   fields:
+///     name: ~
+/// ```
+///
+/// A required field holding a record can be declared `inline`, in which case
+/// the fields of its record are encoded among the parent's as if they were
+/// the parent's own. They take up identifiers in place of the inline field,
+/// saving its record's header and terminator, and share the parent's field
+/// markers. Records which are inline within sparse records are encoded as
+/// usual.
+///
+/// ```yaml
+/// record:
+///   point:
+///     type: { record: { x: i32, y: i32 } }
+///     required: true
+///     inline: true
+/// ```
+///
+/// Identifiers are normally given to fields in alphabetical order. A record
+/// may instead list its fields and reserved fields in the `order` their
+/// identifiers should follow, e.g., so that the fields which are present most
+/// often come first (see [`reorder`](crate::reorder)):
+///
+/// ```yaml
+/// record:
+///   order: [name, age]
+///   fields:
+///     age: i32
+///     name: ~
+/// ```
+///
 /// [compressed object]: ../data/struct.CompressedObject.html
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(rename_all = "kebab-case")]
-pub struct Record(pub BTreeMap<String, Type>);
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+#[serde(try_from = "RecordDef", into = "RecordDef")]
+pub struct Record {
+  /// The types of this record's fields.
+  pub fields: BTreeMap<String, Type>,
+  /// The names of the fields which must be present in encoded values.
+  pub required: BTreeSet<String>,
+  /// The values of fields which are filled in when upgrading objects encoded
+  /// before the fields were added.
+  pub defaults: BTreeMap<String, Value>,
+  /// The descriptions of fields, which only document them.
+  pub descriptions: BTreeMap<String, String>,
+  /// The names of the fields which may no longer be encoded.
+  pub deprecated: BTreeSet<String>,
+  /// The names of the list fields which may be `null`.
+  pub nullable: BTreeSet<String>,
+  /// The names of removed fields whose identifiers are reserved. They must
+  /// not also be in `fields`.
+  pub reserved: BTreeSet<String>,
+  /// The width of field markers, if it is wider than needed. A width which
+  /// is too narrow for the record's fields is ignored.
+  pub width: Option<usize>,
+  /// Whether each value picks between field markers and differences between
+  /// them, whichever is smaller.
+  pub sparse: bool,
+  /// The names of the required record fields whose fields are encoded among
+  /// this record's.
+  pub inline: BTreeSet<String>,
+  /// The names of this record's fields and reserved fields in the order of
+  /// their identifiers, or empty if they are in alphabetical order.
+  pub order: Vec<String>,
+}
 
 impl Record {
+  /// Constructs a new record type where no fields are required.
+  pub fn new(fields: BTreeMap<String, Type>) -> Self {
+    Record {
+      fields,
+      ..Record::default()
+    }
+  }
+
   /// The width of field markers for this record type.
   pub fn field_width(&self) -> usize {
-    math::required_bit_width(self.0.len() + 1)
+    let min = self.min_field_width();
+    self.width.map_or(min, |width| width.max(min))
+  }
+
+  /// The narrowest width field markers for this record type can have.
+  pub fn min_field_width(&self) -> usize {
+    math::required_bit_width(self.id_count() + 1)
   }
 
-  /// A mapping of this record's field names to identifiers.
-  pub fn field_map(&self) -> HashMap<&str, FieldId> {
+  /// The number of identifiers this record's fields take up, which counts
+  /// each field of an inline record in place of the inline field itself.
+  pub fn id_count(&self) -> usize {
+    let fields: usize = self
+      .fields
+      .keys()
+      .map(|k| self.inline_record(k).map_or(1, Record::id_count))
+      .sum();
+    fields + self.reserved.len()
+  }
+
+  /// The names of this record's fields and reserved fields in the order of
+  /// their identifiers, along with the first identifier of each.
+  pub(crate) fn names(&self) -> impl Iterator<Item = (&str, u32)> {
+    let names: Vec<&str> = if self.order.is_empty() {
+      let mut names: Vec<&str> = self
+        .fields
+        .keys()
+        .chain(&self.reserved)
+        .map(String::as_str)
+        .collect();
+      names.sort_unstable();
+      names
+    } else {
+      self.order.iter().map(String::as_str).collect()
+    };
+    let mut next = 0;
+    names.into_iter().map(move |name| {
+      let first = next;
+      next += self.inline_record(name).map_or(1, Record::id_count) as u32;
+      (name, first)
+    })
+  }
+
+  /// A mapping of this record's field names to identifiers. Inline fields
+  /// are mapped to the identifier of the first field of their record.
+  pub fn field_map(&self) -> BTreeMap<&str, FieldId> {
     self
-      .0
-      .iter()
-      .enumerate()
-      .map(|(i, (k, _))| (k.as_str(), FieldId::new(i as u32)))
+      .names()
+      .filter(|(k, _)| self.fields.contains_key(*k))
+      .map(|(k, id)| (k, FieldId::new(id)))
       .collect()
   }
 
+  /// This record's fields in the order of their identifiers.
+  pub fn ordered_fields(&self) -> impl Iterator<Item = (&String, &Type)> {
+    self
+      .names()
+      .filter_map(move |(k, _)| self.fields.get_key_value(k))
+  }
+
   /// A mapping of identifiers to this record's field names.
-  pub fn inverse_field_map(&self) -> HashMap<FieldId, &str> {
+  pub fn inverse_field_map(&self) -> BTreeMap<FieldId, &str> {
     self
-      .0
-      .iter()
-      .enumerate()
-      .map(|(i, (k, _))| (FieldId::new(i as u32), k.as_str()))
+      .field_map()
+      .into_iter()
+      .map(|(k, id)| (id, k))
+      .collect()
+  }
+
+  /// The name and type of the field with a given identifier, or `None` if
+  /// there is no such field or it is reserved. The identifiers of the fields
+  /// of an inline record refer to the inline field.
+  pub fn field_by_id(&self, id: FieldId) -> Option<(&String, &Type)> {
+    if self.reserved.is_empty()
+      && self.inline.is_empty()
+      && self.order.is_empty()
+    {
+      return self.fields.iter().nth(id.index());
+    }
+    let id = id.index() as u32;
+    let (name, first) = self.names().take_while(|(_, f)| *f <= id).last()?;
+    let count = self.inline_record(name).map_or(1, Record::id_count);
+    if (id - first) as usize >= count {
+      return None;
+    }
+    self.fields.get_key_value(name)
+  }
+
+  /// Returns `true` if the field `name` must be present in encoded values.
+  #[inline]
+  pub fn is_required(&self, name: &str) -> bool {
+    self.required.contains(name)
+  }
+
+  /// Returns `true` if every field must be present and none are deprecated,
+  /// allowing the record to be encoded without field markers under
+  /// [`Profile::Dense`].
+  ///
+  /// [`Profile::Dense`]: crate::data::Profile::Dense
+  #[inline]
+  pub fn is_dense(&self) -> bool {
+    self.deprecated.is_empty()
+      && self.fields.keys().all(|name| self.is_required(name))
+  }
+
+  /// Returns `true` if the field `name` may no longer be encoded.
+  #[inline]
+  pub fn is_deprecated(&self, name: &str) -> bool {
+    self.deprecated.contains(name)
+  }
+
+  /// Returns `true` if the list field `name` may be `null`.
+  #[inline]
+  pub fn is_nullable(&self, name: &str) -> bool {
+    self.nullable.contains(name)
+  }
+
+  /// Gives this record's fields and reserved fields identifiers in `order`,
+  /// which must list each of them exactly once.
+  pub fn set_order(&mut self, order: Vec<String>) -> Result<()> {
+    let mut names: BTreeSet<&String> =
+      self.fields.keys().chain(&self.reserved).collect();
+    for name in &order {
+      if !names.remove(name) {
+        match self.fields.contains_key(name) || self.reserved.contains(name) {
+          true => bail!("field {} appears more than once in the order", name),
+          false => bail!("ordered field {} is not a field", name),
+        }
+      }
+    }
+    if let Some(name) = names.into_iter().next() {
+      bail!("field {} is missing from the order", name);
+    }
+    self.order = order;
+    Ok(())
+  }
+
+  /// The record of the field `name` if its fields are encoded among this
+  /// record's, which is the case for required inline record fields unless
+  /// this record is sparse.
+  pub fn inline_record(&self, name: &str) -> Option<&Record> {
+    if self.sparse || !self.inline.contains(name) || !self.is_required(name) {
+      return None;
+    }
+    match self.fields.get(name) {
+      Some(Type::Nested(CompositeType::Record(record))) => Some(record),
+      _ => None,
+    }
+  }
+}
+
+/// The maximum declared width of field markers, as field identifiers are
+/// 32 bits.
+const MAX_FIELD_WIDTH: usize = 32;
+
+/// The largest number of integers in a range written in the [schema
+/// language](dsl) or a [`schema!`](crate::schema!).
+pub const MAX_RANGE_LEN: i128 = 1 << 16;
+
+/// A record as written in a schema file.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum RecordDef {
+  Ordered(OrderedRecord),
+  Sized(SizedRecord),
+  Sparse(SparseRecord),
+  Fields(BTreeMap<String, FieldDef>),
+}
+
+/// A record with a declared field width.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SizedRecord {
+  width: usize,
+  #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+  sparse: bool,
+  fields: BTreeMap<String, FieldDef>,
+}
+
+/// A record with a declared order of field identifiers.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct OrderedRecord {
+  order: Vec<String>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  width: Option<usize>,
+  #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+  sparse: bool,
+  fields: BTreeMap<String, FieldDef>,
+}
+
+/// A sparse record without a declared field width.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct SparseRecord {
+  sparse: bool,
+  fields: BTreeMap<String, FieldDef>,
+}
+
+impl TryFrom<RecordDef> for Record {
+  type Error = anyhow::Error;
+
+  fn try_from(def: RecordDef) -> Result<Self> {
+    let sized = match def {
+      RecordDef::Ordered(def) => {
+        let sized = SizedRecord {
+          width: def.width.unwrap_or(0),
+          sparse: def.sparse,
+          fields: def.fields,
+        };
+        let mut record = match def.width {
+          Some(_) => Record::try_from(RecordDef::Sized(sized))?,
+          None => {
+            let mut record = Record::try_from(sized.fields)?;
+            record.sparse = sized.sparse;
+            record
+          }
+        };
+        record.set_order(def.order)?;
+        return Ok(record);
+      }
+      RecordDef::Sized(sized) => sized,
+      RecordDef::Sparse(def) => {
+        let mut record = Record::try_from(def.fields)?;
+        record.sparse = def.sparse;
+        return Ok(record);
+      }
+      RecordDef::Fields(defs) => return Record::try_from(defs),
+    };
+    let mut record = Record::try_from(sized.fields)?;
+    record.sparse = sized.sparse;
+    if sized.width < record.min_field_width() {
+      bail!(
+        "width {} is too narrow for {} fields, which need {}",
+        sized.width,
+        record.id_count(),
+        record.min_field_width()
+      );
+    }
+    if sized.width > MAX_FIELD_WIDTH {
+      bail!(
+        "width {} is wider than the maximum of {}",
+        sized.width,
+        MAX_FIELD_WIDTH
+      );
+    }
+    record.width = Some(sized.width);
+    Ok(record)
+  }
+}
+
+impl From<Record> for RecordDef {
+  fn from(mut record: Record) -> Self {
+    let sparse = record.sparse;
+    if !record.order.is_empty() {
+      return RecordDef::Ordered(OrderedRecord {
+        order: core::mem::take(&mut record.order),
+        width: record.width.take(),
+        sparse,
+        fields: record.into(),
+      });
+    }
+    match record.width.take() {
+      Some(width) => RecordDef::Sized(SizedRecord {
+        width,
+        sparse,
+        fields: record.into(),
+      }),
+      None if sparse => RecordDef::Sparse(SparseRecord {
+        sparse,
+        fields: record.into(),
+      }),
+      None => RecordDef::Fields(record.into()),
+    }
+  }
+}
+
+/// The definition of a single record field as written in a schema file.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum FieldDef {
+  Annotated(AnnotatedField),
+  Reserved(ReservedField),
+  Plain(Type),
+}
+
+/// A record field with attributes.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct AnnotatedField {
+  #[serde(rename = "type")]
+  ty: Type,
+  #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+  required: bool,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  default: Option<Value>,
+  #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+  deprecated: bool,
+  #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+  nullable: bool,
+  #[serde(default, skip_serializing_if = "core::ops::Not::not")]
+  inline: bool,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  description: Option<String>,
+}
+
+/// A reserved field, which has no type.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ReservedField {
+  reserved: bool,
+}
+
+impl TryFrom<BTreeMap<String, FieldDef>> for Record {
+  type Error = anyhow::Error;
+
+  fn try_from(defs: BTreeMap<String, FieldDef>) -> Result<Self> {
+    let mut record = Record::default();
+    for (name, def) in defs {
+      let ty = match def {
+        FieldDef::Annotated(field) => {
+          if field.required && field.deprecated {
+            bail!("field {} is both required and deprecated", name);
+          }
+          if field.required {
+            record.required.insert(name.clone());
+          }
+          if field.deprecated {
+            record.deprecated.insert(name.clone());
+          }
+          if let Some(default) = field.default {
+            record.defaults.insert(name.clone(), default);
+          }
+          if let Some(description) = field.description {
+            record.descriptions.insert(name.clone(), description);
+          }
+          if field.nullable {
+            if !matches!(field.ty, Type::Nested(CompositeType::List(_))) {
+              bail!("field {} is nullable but isn't a list", name);
+            }
+            record.nullable.insert(name.clone());
+          }
+          if field.inline {
+            if !matches!(field.ty, Type::Nested(CompositeType::Record(_))) {
+              bail!("field {} is inline but isn't a record", name);
+            }
+            if !field.required {
+              bail!("field {} is inline but isn't required", name);
+            }
+            record.inline.insert(name.clone());
+          }
+          field.ty
+        }
+        FieldDef::Reserved(ReservedField { reserved: true }) => {
+          record.reserved.insert(name);
+          continue;
+        }
+        FieldDef::Reserved(_) => {
+          bail!("field {} must either have a type or be reserved", name)
+        }
+        FieldDef::Plain(ty) => ty,
+      };
+      record.fields.insert(name, ty);
+    }
+    Ok(record)
+  }
+}
+
+impl From<Record> for BTreeMap<String, FieldDef> {
+  fn from(record: Record) -> Self {
+    let required = record.required;
+    let deprecated = record.deprecated;
+    let nullable = record.nullable;
+    let inline = record.inline;
+    let mut defaults = record.defaults;
+    let mut descriptions = record.descriptions;
+    let reserved = record
+      .reserved
+      .into_iter()
+      .map(|name| (name, FieldDef::Reserved(ReservedField { reserved: true })));
+    record
+      .fields
+      .into_iter()
+      .map(|(name, ty)| {
+        let default = defaults.remove(&name);
+        let description = descriptions.remove(&name);
+        let annotated = required.contains(&name)
+          || deprecated.contains(&name)
+          || nullable.contains(&name)
+          || inline.contains(&name)
+          || default.is_some()
+          || description.is_some();
+        let def = if annotated {
+          FieldDef::Annotated(AnnotatedField {
+            ty,
+            required: required.contains(&name),
+            default,
+            deprecated: deprecated.contains(&name),
+            nullable: nullable.contains(&name),
+            inline: inline.contains(&name),
+            description,
+          })
+        } else {
+          FieldDef::Plain(ty)
+        };
+        (name, def)
+      })
+      .chain(reserved)
       .collect()
   }
 }
 
 /// Lists are a repetition of many values with a single type.
-#[derive(Debug, Deserialize, Serialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 #[serde(rename_all = "kebab-case")]
 pub struct List(pub Box<Type>);
 
@@ -97,20 +778,1121 @@ pub struct List(pub Box<Type>);
 /// program how each field/element should be encoded and acts as a lookup table
 /// when constructing and deconstructing [compressed objects].
 ///
+/// The root of a schema is usually a record or list but may also be a single
+/// non-nested type for payloads which are just one value. Such scalar objects
+/// consist of a single element block.
+///
+/// A schema may carry a version number so that values of older versions can
+/// be [migrated](crate::migrate). In a schema file the root type is then
+/// nested under a `schema` key:
+///
+/// ```yaml
+/// version: 2
+/// schema:
+///   record:
+///     name: ~
+/// ```
+///
+/// The same form lets a schema choose how the lengths of its objects are
+/// encoded, which otherwise default to VIE (see [`LengthEncoding`]):
+///
+/// ```yaml
+/// lengths: gamma
+/// schema:
+///   list:
+///     ~
+/// ```
+///
+/// Lists of enormous numbers of elements may be split into chunks, each of
+/// which starts with the number of bits it takes up, so that decoders can
+/// skip over a whole chunk at a time and encoders can write one out as soon
+/// as it is complete. Only lists with more elements than the chunk size are
+/// split:
+///
+/// ```yaml
+/// chunks: 1024
+/// schema:
+///   list:
+///     ~
+/// ```
+///
+/// Objects whose root is a list may carry sync markers between its elements,
+/// one after roughly every given number of blocks, so that a
+/// [lenient](crate::decode_lenient) decoder can scan past a damaged region
+/// to the next marker and carry on from there:
+///
+/// ```yaml
+/// sync: 4096
+/// schema:
+///   list:
+///     ~
+/// ```
+///
+/// A schema may also bundle the older versions which were used to encode
+/// existing data, so that a single file is enough to read all of it. A bundle
+/// file holds every version keyed by its [fingerprint](Schema::fingerprint)
+/// along with the fingerprint of the current one:
+///
+/// ```yaml
+/// current: 9c3e5e1f0b6a2d47
+/// versions:
+///   9c3e5e1f0b6a2d47:
+///     record:
+///       first: ~
+///       last: ~
+///   41d7c2b8e0f39a16:
+///     record:
+///       name: ~
+/// ```
+///
 /// [compressed objects]: ../data/struct.CompressedObject.html
-#[derive(Debug, Deserialize, Serialize)]
-#[serde(rename_all = "kebab-case")]
-pub struct Schema(CompositeType);
+#[derive(Clone, Debug, Deserialize, Serialize)]
+#[serde(try_from = "SchemaDef", into = "SchemaDef")]
+pub struct Schema {
+  root: Type,
+  version: Option<u32>,
+  lengths: LengthEncoding,
+  chunks: Option<usize>,
+  sync: Option<usize>,
+  epoch: Option<Epoch>,
+  /// Older versions of this schema by fingerprint.
+  history: BTreeMap<u64, Schema>,
+}
 
 impl Schema {
   /// Constructs a new schema.
   pub fn new(root: CompositeType) -> Self {
-    Schema(root)
+    Schema::new_scalar(Type::Nested(root))
+  }
+
+  /// Constructs a new schema whose root is a single value of type `ty`.
+  pub fn new_scalar(ty: Type) -> Self {
+    Schema {
+      root: ty,
+      version: None,
+      lengths: LengthEncoding::Vie,
+      chunks: None,
+      sync: None,
+      epoch: None,
+      history: BTreeMap::new(),
+    }
+  }
+
+  /// Bundles older versions of this schema with it, along with any versions
+  /// they bundle themselves.
+  pub fn with_history<I>(mut self, versions: I) -> Self
+  where
+    I: IntoIterator<Item = Schema>,
+  {
+    let current = self.fingerprint();
+    for mut schema in versions {
+      let history = core::mem::take(&mut schema.history);
+      for schema in core::iter::once(schema).chain(history.into_values()) {
+        let fingerprint = schema.fingerprint();
+        if fingerprint != current {
+          self.history.insert(fingerprint, schema);
+        }
+      }
+    }
+    self
+  }
+
+  /// The older versions bundled with this schema.
+  pub fn history(&self) -> impl Iterator<Item = &Schema> {
+    self.history.values()
+  }
+
+  /// Finds the version of this schema with a given fingerprint, which is
+  /// either this schema or one of the older versions bundled with it.
+  pub fn resolve(&self, fingerprint: u64) -> Option<&Schema> {
+    if self.fingerprint() == fingerprint {
+      return Some(self);
+    }
+    self.history.get(&fingerprint)
+  }
+
+  /// Sets the version number of this schema.
+  pub fn with_version(mut self, version: u32) -> Self {
+    self.version = Some(version);
+    self
+  }
+
+  /// The version number of this schema, if it has one.
+  #[inline]
+  pub fn version(&self) -> Option<u32> {
+    self.version
+  }
+
+  /// Sets how the lengths of objects encoded with this schema are encoded.
+  pub fn with_lengths(mut self, lengths: LengthEncoding) -> Self {
+    self.lengths = lengths;
+    self
+  }
+
+  /// How the lengths of objects encoded with this schema are encoded.
+  #[inline]
+  pub fn lengths(&self) -> LengthEncoding {
+    self.lengths
+  }
+
+  /// Splits lists of more than `size` elements encoded with this schema into
+  /// chunks of `size` elements.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `size` is zero.
+  pub fn with_chunks(mut self, size: usize) -> Self {
+    assert!(size > 0, "chunk size must be greater than 0");
+    self.chunks = Some(size);
+    self
+  }
+
+  /// The number of elements in each chunk of a list, if lists are split
+  /// into chunks.
+  #[inline]
+  pub fn chunks(&self) -> Option<usize> {
+    self.chunks
+  }
+
+  /// Writes a sync marker between the elements of root lists encoded with
+  /// this schema after every `blocks` blocks or so. Markers are only written
+  /// at the start of a chunk in lists which are split into chunks.
+  ///
+  /// # Panics
+  ///
+  /// Panics if `blocks` is zero.
+  pub fn with_sync(mut self, blocks: usize) -> Self {
+    assert!(blocks > 0, "sync interval must be greater than 0");
+    self.sync = Some(blocks);
+    self
+  }
+
+  /// The number of blocks after which a sync marker is written, if objects
+  /// carry sync markers.
+  #[inline]
+  pub fn sync(&self) -> Option<usize> {
+    self.sync
+  }
+
+  /// Sets the epoch which the timestamps of objects encoded with this schema
+  /// are stored relative to (see [`epoch`](crate::epoch)).
+  pub fn with_epoch(mut self, epoch: Epoch) -> Self {
+    self.epoch = Some(epoch);
+    self
+  }
+
+  /// The epoch timestamps are stored relative to, if there is one.
+  #[inline]
+  pub fn epoch(&self) -> Option<Epoch> {
+    self.epoch
   }
 
   /// The root type of this schema.
   #[inline]
-  pub fn root(&self) -> &CompositeType {
-    &self.0
+  pub fn root(&self) -> &Type {
+    &self.root
+  }
+
+  /// Replaces the type of the non-nested values at `path`, changing how they
+  /// are compressed without editing the schema itself.
+  ///
+  /// Every element of a list has the same type, so list indices in `path`
+  /// are ignored. Fails if `path` doesn't refer to a non-nested value of
+  /// this schema or if `ty` is nested.
+  pub fn override_type(&mut self, path: &Path, ty: Type) -> Result<()> {
+    if let Type::Nested(_) = ty {
+      bail!("cannot override {} with a nested type", path);
+    }
+    let mut target = &mut self.root;
+    for segment in path.segments() {
+      target = match (target, segment) {
+        (Type::Nested(CompositeType::Record(record)), Segment::Field(name)) => {
+          record
+            .fields
+            .get_mut(name)
+            .ok_or_else(|| anyhow!("schema has no field {}", path))?
+        }
+        (Type::Nested(CompositeType::List(list)), Segment::Index(_)) => {
+          list.0.as_mut()
+        }
+        _ => bail!("schema has no value at {}", path),
+      };
+    }
+    if let Type::Nested(_) = target {
+      bail!("cannot override the type of nested value {}", path);
+    }
+    *target = ty;
+    Ok(())
+  }
+
+  /// The root type of this schema, which may be changed in place.
+  #[cfg(feature = "std")]
+  pub(crate) fn root_mut(&mut self) -> &mut Type {
+    &mut self.root
+  }
+
+  /// The root type of this schema if it is a record or list.
+  #[inline]
+  pub fn composite_root(&self) -> Option<&CompositeType> {
+    match &self.root {
+      Type::Nested(ct) => Some(ct),
+      _ => None,
+    }
+  }
+
+  /// A hash of the structure of this schema.
+  ///
+  /// Two schemas have the same fingerprint if, barring hash collisions, they
+  /// describe the same encoding, including how lengths are encoded, how
+  /// lists are chunked, whether there are sync markers and the epoch of
+  /// timestamps, and have the same version.
+  /// It doesn't depend on the file format the schema was loaded from or on
+  /// the versions bundled with it.
+  pub fn fingerprint(&self) -> u64 {
+    let mut bytes = Vec::new();
+    if let Some(version) = self.version {
+      bytes.push(b'v');
+      bytes.extend_from_slice(&version.to_le_bytes());
+    }
+    if self.lengths != LengthEncoding::Vie {
+      bytes.push(b'c');
+      bytes.push(self.lengths as u8);
+    }
+    if let Some(size) = self.chunks {
+      bytes.push(b'k');
+      bytes.extend_from_slice(&(size as u64).to_le_bytes());
+    }
+    if let Some(blocks) = self.sync {
+      bytes.push(b'm');
+      bytes.extend_from_slice(&(blocks as u64).to_le_bytes());
+    }
+    match self.epoch {
+      Some(Epoch::Document) => bytes.extend_from_slice(b"od"),
+      Some(Epoch::Fixed(t)) => {
+        bytes.extend_from_slice(b"of");
+        bytes.extend_from_slice(&t.to_le_bytes());
+      }
+      None => {}
+    }
+    write_canonical(&self.root, &mut bytes);
+    math::fnv1a(&bytes)
+  }
+}
+
+/// A schema as written in a schema file.
+#[derive(Deserialize, Serialize)]
+#[serde(untagged)]
+enum SchemaDef {
+  Bundle(BundleDef),
+  Configured(ConfiguredSchema),
+  Plain(Type),
+}
+
+/// A schema with a version number, a length encoding, a chunk size or an
+/// epoch.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct ConfiguredSchema {
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  version: Option<u32>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  lengths: Option<LengthEncoding>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  chunks: Option<usize>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  sync: Option<usize>,
+  #[serde(default, skip_serializing_if = "Option::is_none")]
+  epoch: Option<Epoch>,
+  schema: Type,
+}
+
+/// Several versions of a schema keyed by their fingerprints in hex.
+#[derive(Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct BundleDef {
+  current: String,
+  versions: BTreeMap<String, SchemaDef>,
+}
+
+impl TryFrom<SchemaDef> for Schema {
+  type Error = anyhow::Error;
+
+  fn try_from(def: SchemaDef) -> Result<Self> {
+    let schema = match def {
+      SchemaDef::Bundle(bundle) => return Schema::from_bundle(bundle),
+      SchemaDef::Configured(c) => {
+        let mut schema = Schema::new_scalar(c.schema);
+        schema.version = c.version;
+        schema.lengths = c.lengths.unwrap_or(LengthEncoding::Vie);
+        if c.chunks == Some(0) {
+          bail!("chunk size must be greater than 0");
+        }
+        schema.chunks = c.chunks;
+        if c.sync == Some(0) {
+          bail!("sync interval must be greater than 0");
+        }
+        schema.sync = c.sync;
+        schema.epoch = c.epoch;
+        schema
+      }
+      SchemaDef::Plain(root) => Schema::new_scalar(root),
+    };
+    Ok(schema)
+  }
+}
+
+impl Schema {
+  fn from_bundle(bundle: BundleDef) -> Result<Self> {
+    let BundleDef {
+      current: current_key,
+      versions,
+    } = bundle;
+    let mut current = None;
+    let mut history = Vec::new();
+    for (key, def) in versions {
+      if let SchemaDef::Bundle(_) = def {
+        bail!("version {} is a bundle", key);
+      }
+      let schema = Schema::try_from(def)?;
+      let fingerprint = format!("{:016x}", schema.fingerprint());
+      if key != fingerprint {
+        bail!("version {} has fingerprint {}", key, fingerprint);
+      }
+      if key == current_key {
+        current = Some(schema);
+      } else {
+        history.push(schema);
+      }
+    }
+    let current = current.ok_or_else(|| {
+      anyhow!("current version {} is not in the bundle", current_key)
+    })?;
+    Ok(current.with_history(history))
+  }
+}
+
+impl From<Schema> for SchemaDef {
+  fn from(mut schema: Schema) -> Self {
+    if !schema.history.is_empty() {
+      let history = core::mem::take(&mut schema.history);
+      let current = format!("{:016x}", schema.fingerprint());
+      let mut versions: BTreeMap<String, SchemaDef> = history
+        .into_iter()
+        .map(|(fp, schema)| (format!("{:016x}", fp), schema.into()))
+        .collect();
+      versions.insert(current.clone(), schema.into());
+      return SchemaDef::Bundle(BundleDef { current, versions });
+    }
+    let lengths = Some(schema.lengths).filter(|l| *l != LengthEncoding::Vie);
+    if schema.version.is_none()
+      && lengths.is_none()
+      && schema.chunks.is_none()
+      && schema.sync.is_none()
+      && schema.epoch.is_none()
+    {
+      return SchemaDef::Plain(schema.root);
+    }
+    SchemaDef::Configured(ConfiguredSchema {
+      version: schema.version,
+      lengths,
+      chunks: schema.chunks,
+      sync: schema.sync,
+      epoch: schema.epoch,
+      schema: schema.root,
+    })
+  }
+}
+
+/// Writes an unambiguous byte representation of `ty` to `out`.
+fn write_canonical(ty: &Type, out: &mut Vec<u8>) {
+  fn write_str(s: &str, out: &mut Vec<u8>) {
+    out.extend_from_slice(&(s.len() as u64).to_le_bytes());
+    out.extend_from_slice(s.as_bytes());
+  }
+
+  match ty {
+    Type::PassThrough => out.push(b'p'),
+    Type::Name(name) => {
+      out.push(b'n');
+      write_str(name, out);
+    }
+    Type::Enum {
+      variants,
+      probabilities: None,
+    } => {
+      out.push(b'e');
+      out.extend_from_slice(&(variants.len() as u64).to_le_bytes());
+      for v in variants {
+        write_str(v, out);
+      }
+    }
+    Type::Enum {
+      variants,
+      probabilities: Some(probabilities),
+    } => {
+      out.push(b'E');
+      out.extend_from_slice(&(variants.len() as u64).to_le_bytes());
+      for v in variants {
+        write_str(v, out);
+      }
+      out.extend_from_slice(&(probabilities.len() as u64).to_le_bytes());
+      for (v, p) in probabilities {
+        write_str(v, out);
+        out.extend_from_slice(&p.to_bits().to_le_bytes());
+      }
+    }
+    Type::IntEnum { codes } => {
+      out.push(b'i');
+      out.extend_from_slice(&(codes.len() as u64).to_le_bytes());
+      for c in codes {
+        out.extend_from_slice(&c.to_le_bytes());
+      }
+    }
+    Type::Dictionary { size } => {
+      out.push(b'd');
+      out.extend_from_slice(&(*size as u64).to_le_bytes());
+    }
+    Type::Huffman { huffman } => {
+      out.push(b'h');
+      let bytes = huffman.to_bytes();
+      out.extend_from_slice(&(bytes.len() as u64).to_le_bytes());
+      out.extend_from_slice(&bytes);
+    }
+    Type::Scaled { scale, round } => {
+      out.push(b's');
+      out.extend_from_slice(&scale.to_bits().to_le_bytes());
+      write_str(round.unwrap_or(Rounding::Nearest).name(), out);
+    }
+    Type::Transformed { transform, of } => {
+      out.push(b't');
+      out.extend_from_slice(&(transform.len() as u64).to_le_bytes());
+      for t in transform {
+        write_str(t.name(), out);
+      }
+      write_canonical(of, out);
+    }
+    Type::Nested(CompositeType::Record(record)) => {
+      out.push(b'r');
+      out.extend_from_slice(&(record.fields.len() as u64).to_le_bytes());
+      for (name, ty) in &record.fields {
+        write_str(name, out);
+        out.push(record.is_required(name) as u8);
+        write_canonical(ty, out);
+      }
+      // A declared width only changes the encoding if it is wider than
+      // needed
+      if record.field_width() != record.min_field_width() {
+        out.push(b'w');
+        out.extend_from_slice(&(record.field_width() as u64).to_le_bytes());
+      }
+      // Reserved fields change the identifiers of the other fields
+      if !record.reserved.is_empty() {
+        out.push(b'x');
+        out.extend_from_slice(&(record.reserved.len() as u64).to_le_bytes());
+        for name in &record.reserved {
+          write_str(name, out);
+        }
+      }
+      // Nullable lists store their lengths differently
+      if !record.nullable.is_empty() {
+        out.push(b'N');
+        out.extend_from_slice(&(record.nullable.len() as u64).to_le_bytes());
+        for name in &record.nullable {
+          write_str(name, out);
+        }
+      }
+      // Sparse records start with a layout flag
+      if record.sparse {
+        out.push(b'S');
+      }
+      // A declared order changes the identifiers of the fields
+      if !record.order.is_empty() {
+        out.push(b'o');
+        out.extend_from_slice(&(record.order.len() as u64).to_le_bytes());
+        for name in &record.order {
+          write_str(name, out);
+        }
+      }
+      // Inline records share their parent's field identifiers
+      if !record.inline.is_empty() {
+        out.push(b'I');
+        out.extend_from_slice(&(record.inline.len() as u64).to_le_bytes());
+        for name in &record.inline {
+          write_str(name, out);
+        }
+      }
+    }
+    Type::Nested(CompositeType::List(list)) => {
+      out.push(b'l');
+      write_canonical(&list.0, out);
+    }
+    Type::Union { alternatives } => {
+      out.push(b'u');
+      out.extend_from_slice(&(alternatives.len() as u64).to_le_bytes());
+      for ty in alternatives {
+        write_canonical(ty, out);
+      }
+    }
+  }
+}
+
+/// Constructs a [`Schema`] inline with a syntax like that of a schema file.
+///
+/// The fields of a root record are listed as `name: type`, optionally
+/// preceded by `required`. A root list is given as its element type in
+/// brackets instead. Types are written as:
+///
+/// - `raw` for values which are encoded as they are;
+/// - a name, like `bool` or `timestamp`, or a string literal for names which
+///   aren't identifiers, like `"float(precision=2)"`;
+/// - `lo..hi` or `lo..=hi` for integers in a range, which are encoded as an
+///   [`IntEnum`](Type::IntEnum) of every integer in it. Ranges of more than
+///   [`MAX_RANGE_LEN`](crate::schema::MAX_RANGE_LEN) integers, or none at all,
+///   fail to compile;
+/// - `enum[a, b, c]` for an enum of the given variants;
+/// - `{ ... }` for a record of the fields within the braces;
+/// - `[type]` for a list of the type within the brackets;
+/// - `(expr)` for any other [`Type`] given as an expression.
+///
+/// ```
+/// let schema = chii::schema! {
+///   required name: raw,
+///   age: 0..120,
+///   role: enum[student, teacher],
+///   courses: [{ name: raw, grade: enum[A, B, C] }],
+/// };
+/// let value = serde_json::json!({
+///   "name": "Jeremy",
+///   "age": 27,
+///   "role": "student",
+///   "courses": [{ "name": "Art", "grade": "A" }]
+/// });
+/// let bytes = chii::encode(&schema, &value).unwrap().to_bytes();
+/// assert_eq!(value, chii::decode(&schema, &bytes).unwrap());
+/// ```
+///
+/// Names are checked when values are encoded, as with a schema file.
+///
+/// ```compile_fail
+/// let schema = chii::schema! { id: 0..1_000_000 };
+/// ```
+#[macro_export]
+macro_rules! schema {
+  ([ $($element:tt)+ ]) => {
+    $crate::schema::Schema::new($crate::schema::CompositeType::List(
+      $crate::schema::List($crate::__schema_type!($($element)+).into()),
+    ))
+  };
+  ($($fields:tt)*) => {
+    $crate::schema::Schema::new($crate::schema::CompositeType::Record(
+      $crate::__schema_record!($($fields)*),
+    ))
+  };
+}
+
+/// Constructs the [`Record`] of the fields of a [`schema!`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_record {
+  ($($fields:tt)*) => {{
+    let mut record = $crate::schema::Record::default();
+    $crate::__schema_fields!(record $($fields)*);
+    record
+  }};
+}
+
+/// Adds the fields of a [`schema!`] to `$record`, collecting the tokens of
+/// each type up to the comma after it.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_fields {
+  (@type $record:ident $required:tt $name:ident [$($ty:tt)+] $(, $($rest:tt)*)?) => {
+    $record
+      .fields
+      .insert(stringify!($name).into(), $crate::__schema_type!($($ty)+));
+    if $required {
+      $record.required.insert(stringify!($name).into());
+    }
+    $crate::__schema_fields!($record $($($rest)*)?);
+  };
+  (@type $record:ident $required:tt $name:ident [$($ty:tt)*] $next:tt $($rest:tt)*) => {
+    $crate::__schema_fields!(@type $record $required $name [$($ty)* $next] $($rest)*);
+  };
+  ($record:ident) => {};
+  ($record:ident required $name:ident : $($rest:tt)+) => {
+    $crate::__schema_fields!(@type $record true $name [] $($rest)+);
+  };
+  ($record:ident $name:ident : $($rest:tt)+) => {
+    $crate::__schema_fields!(@type $record false $name [] $($rest)+);
+  };
+}
+
+/// Constructs the [`Type`] of a field or element of a [`schema!`].
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_type {
+  (raw) => {
+    $crate::schema::Type::PassThrough
+  };
+  ($name:ident) => {
+    $crate::schema::Type::Name(stringify!($name).into())
+  };
+  ($name:literal) => {
+    $crate::schema::Type::Name($name.into())
+  };
+  ($lo:literal ..= $hi:literal) => {
+    $crate::__schema_range!($lo, $hi)
+  };
+  ($lo:literal .. $hi:literal) => {
+    $crate::__schema_range!($lo, $hi - 1)
+  };
+  (enum [$($variant:ident),* $(,)?]) => {
+    $crate::schema::Type::Enum {
+      variants: [$(stringify!($variant)),*]
+        .iter()
+        .map(|v| (*v).into())
+        .collect(),
+      probabilities: None,
+    }
+  };
+  ({ $($fields:tt)* }) => {
+    $crate::schema::Type::Nested($crate::schema::CompositeType::Record(
+      $crate::__schema_record!($($fields)*),
+    ))
+  };
+  ([ $($element:tt)+ ]) => {
+    $crate::schema::Type::Nested($crate::schema::CompositeType::List(
+      $crate::schema::List($crate::__schema_type!($($element)+).into()),
+    ))
+  };
+  (( $ty:expr )) => {
+    $ty
+  };
+}
+
+/// Constructs the [`IntEnum`](Type::IntEnum) of the integers from `$lo` to
+/// `$hi` inclusive of a [`schema!`], failing to compile if there are too many.
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __schema_range {
+  ($lo:expr, $hi:expr) => {{
+    const LEN: i128 = ($hi) as i128 - ($lo) as i128 + 1;
+    const _: () = assert!(
+      0 < LEN && LEN <= $crate::schema::MAX_RANGE_LEN,
+      "range must have between 1 and 65536 integers"
+    );
+    $crate::schema::Type::IntEnum {
+      codes: ($lo..=$hi).collect(),
+    }
+  }};
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  fn schema(names: &[&str]) -> Schema {
+    let fields = names
+      .iter()
+      .map(|name| (name.to_string(), Type::PassThrough))
+      .collect();
+    Schema::new(CompositeType::Record(Record::new(fields)))
+  }
+
+  #[test]
+  fn resolve_bundled_versions() {
+    let v1 = schema(&["name"]);
+    let v2 = schema(&["first", "last"]).with_history(vec![v1.clone()]);
+    let v3 = schema(&["first", "last", "email"]).with_history(vec![v2.clone()]);
+
+    assert_eq!(2, v3.history().count());
+    for old in &[&v1, &v2] {
+      let resolved = v3.resolve(old.fingerprint()).unwrap();
+      assert_eq!(old.fingerprint(), resolved.fingerprint());
+    }
+    assert_eq!(
+      v3.fingerprint(),
+      v3.resolve(v3.fingerprint()).unwrap().fingerprint()
+    );
+    assert!(v3.resolve(0).is_none());
+    assert!(v1.resolve(v2.fingerprint()).is_none());
+  }
+
+  #[test]
+  fn bundle_definition_roundtrip() {
+    let v1 = schema(&["name"]);
+    let v2 = schema(&["first", "last"])
+      .with_version(2)
+      .with_history(vec![v1]);
+    let def = SchemaDef::from(v2.clone());
+    let keys: Vec<String> = match &def {
+      SchemaDef::Bundle(bundle) => bundle.versions.keys().cloned().collect(),
+      _ => panic!("expected a bundle"),
+    };
+    assert_eq!(2, keys.len());
+
+    let schema = Schema::try_from(def).unwrap();
+    assert_eq!(v2.fingerprint(), schema.fingerprint());
+    assert_eq!(Some(2), schema.version());
+    assert_eq!(1, schema.history().count());
+  }
+
+  #[test]
+  fn bundle_with_wrong_fingerprint() {
+    let mut versions = BTreeMap::new();
+    versions.insert(
+      "0000000000000000".to_string(),
+      SchemaDef::Plain(Type::PassThrough),
+    );
+    let bundle = BundleDef {
+      current: "0000000000000000".to_string(),
+      versions,
+    };
+    let e = Schema::try_from(SchemaDef::Bundle(bundle)).unwrap_err();
+    assert!(e
+      .to_string()
+      .starts_with("version 0000000000000000 has fingerprint"));
+  }
+
+  #[test]
+  fn length_encoding_definition() {
+    let plain = Schema::new_scalar(Type::PassThrough);
+    let gamma = plain.clone().with_lengths(LengthEncoding::Gamma);
+    assert_ne!(plain.fingerprint(), gamma.fingerprint());
+    assert!(matches!(SchemaDef::from(plain), SchemaDef::Plain(_)));
+
+    let def = SchemaDef::from(gamma.clone());
+    match &def {
+      SchemaDef::Configured(c) => {
+        assert_eq!(Some(LengthEncoding::Gamma), c.lengths);
+        assert_eq!(None, c.version);
+      }
+      _ => panic!("expected a configured schema"),
+    }
+    let schema = Schema::try_from(def).unwrap();
+    assert_eq!(LengthEncoding::Gamma, schema.lengths());
+    assert_eq!(gamma.fingerprint(), schema.fingerprint());
+  }
+
+  #[test]
+  fn chunk_size_definition() {
+    let plain = Schema::new_scalar(Type::PassThrough);
+    let chunked = plain.clone().with_chunks(1024);
+    assert_ne!(plain.fingerprint(), chunked.fingerprint());
+
+    let def = SchemaDef::from(chunked.clone());
+    match &def {
+      SchemaDef::Configured(c) => assert_eq!(Some(1024), c.chunks),
+      _ => panic!("expected a configured schema"),
+    }
+    let schema = Schema::try_from(def).unwrap();
+    assert_eq!(Some(1024), schema.chunks());
+    assert_eq!(chunked.fingerprint(), schema.fingerprint());
+
+    let empty = ConfiguredSchema {
+      version: None,
+      lengths: None,
+      chunks: Some(0),
+      sync: None,
+      epoch: None,
+      schema: Type::PassThrough,
+    };
+    assert!(Schema::try_from(SchemaDef::Configured(empty)).is_err());
+  }
+
+  #[test]
+  fn sync_interval_definition() {
+    let plain = Schema::new_scalar(Type::PassThrough);
+    let synced = plain.clone().with_sync(4096);
+    assert_ne!(plain.fingerprint(), synced.fingerprint());
+
+    let def = SchemaDef::from(synced.clone());
+    match &def {
+      SchemaDef::Configured(c) => assert_eq!(Some(4096), c.sync),
+      _ => panic!("expected a configured schema"),
+    }
+    let schema = Schema::try_from(def).unwrap();
+    assert_eq!(Some(4096), schema.sync());
+    assert_eq!(synced.fingerprint(), schema.fingerprint());
+
+    let never = ConfiguredSchema {
+      version: None,
+      lengths: None,
+      chunks: None,
+      sync: Some(0),
+      epoch: None,
+      schema: Type::PassThrough,
+    };
+    assert!(Schema::try_from(SchemaDef::Configured(never)).is_err());
+  }
+
+  #[test]
+  fn huffman_codebook_is_part_of_the_fingerprint() {
+    let huffman = |samples: &[&str]| {
+      let mut fields = BTreeMap::new();
+      let huffman = Codebook::train(samples);
+      fields.insert("name".to_string(), Type::Huffman { huffman });
+      Schema::new(CompositeType::Record(Record::new(fields)))
+    };
+    let a = huffman(&["Jeremy", "Schwartz"]);
+    let b = huffman(&["Jeremy"]);
+    assert_ne!(a.fingerprint(), b.fingerprint());
+
+    let value = serde_json::json!({ "name": "Schwartz" });
+    let bytes = crate::encode(&a, &value).unwrap().to_bytes();
+    assert_eq!(value, crate::decode(&a, &bytes).unwrap());
+    // `b` has no codes for most of the bytes
+    assert!(crate::encode(&b, &value).is_err());
+  }
+
+  #[test]
+  fn transforms_are_part_of_the_fingerprint() {
+    let transformed = |transform: Vec<TransformKind>| {
+      let mut fields = BTreeMap::new();
+      let of = Box::new(Type::PassThrough);
+      fields.insert("email".to_string(), Type::Transformed { transform, of });
+      Schema::new(CompositeType::Record(Record::new(fields)))
+    };
+    let a = transformed(vec![TransformKind::Trim, TransformKind::Lowercase]);
+    let b = transformed(vec![TransformKind::Lowercase, TransformKind::Trim]);
+    assert_ne!(a.fingerprint(), b.fingerprint());
+    assert_ne!(a.fingerprint(), schema(&["email"]).fingerprint());
+
+    let value = serde_json::json!({ "email": " Jeremy@Example.com\n" });
+    let bytes = crate::encode(&a, &value).unwrap().to_bytes();
+    assert_eq!(
+      serde_json::json!({ "email": "jeremy@example.com" }),
+      crate::decode(&a, &bytes).unwrap()
+    );
+  }
+
+  #[test]
+  fn scaled_numbers() {
+    let scaled = |scale, round| {
+      let mut fields = BTreeMap::new();
+      fields.insert("price".to_string(), Type::Scaled { scale, round });
+      Schema::new(CompositeType::Record(Record::new(fields)))
+    };
+    let cents = scaled(100.0, None);
+    assert_ne!(cents.fingerprint(), scaled(1000.0, None).fingerprint());
+    assert_ne!(
+      cents.fingerprint(),
+      scaled(100.0, Some(Rounding::Floor)).fingerprint()
+    );
+
+    let value = serde_json::json!({ "price": 19.99 });
+    let co = crate::encode(&cents, &value).unwrap();
+    assert_eq!(value, crate::decode(&cents, &co.to_bytes()).unwrap());
+
+    let e = crate::encode(&scaled(0.0, None), &value).unwrap_err();
+    assert!(format!("{:#}", e).contains("scale must be a positive number"));
+  }
+
+  #[test]
+  fn override_types() {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    let list = List(Box::new(Type::Nested(CompositeType::Record(
+      Record::new(course),
+    ))));
+    let mut fields = BTreeMap::new();
+    fields.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(list)),
+    );
+    let mut schema = Schema::new(CompositeType::Record(Record::new(fields)));
+    let before = schema.fingerprint();
+
+    let bool_type = Type::Name("bool".to_string());
+    let path = ".courses[0].name".parse().unwrap();
+    schema.override_type(&path, bool_type.clone()).unwrap();
+    assert_ne!(before, schema.fingerprint());
+    let value = serde_json::json!({ "courses": [{ "name": true }] });
+    let bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+    assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+
+    let e = |path: &str, ty: &Type| {
+      let mut schema = schema.clone();
+      let path = path.parse().unwrap();
+      schema
+        .override_type(&path, ty.clone())
+        .unwrap_err()
+        .to_string()
+    };
+    assert_eq!("schema has no field .grade", e(".grade", &bool_type));
+    assert_eq!(
+      "schema has no value at .courses.name",
+      e(".courses.name", &bool_type)
+    );
+    assert_eq!(
+      "cannot override the type of nested value .courses",
+      e(".courses", &bool_type)
+    );
+    let nested = Type::Nested(CompositeType::Record(Record::default()));
+    assert_eq!(
+      "cannot override .courses[0].name with a nested type",
+      e(".courses[0].name", &nested)
+    );
+  }
+
+  #[test]
+  fn declared_field_width() {
+    let narrow = schema(&["name"]);
+    let mut record = Record::new(
+      vec![("name".to_string(), Type::PassThrough)]
+        .into_iter()
+        .collect(),
+    );
+    record.width = Some(8);
+    assert_eq!(8, record.field_width());
+    let wide = Schema::new(CompositeType::Record(record.clone()));
+    assert_ne!(narrow.fingerprint(), wide.fingerprint());
+
+    let value = serde_json::json!({ "name": "Jeremy" });
+    let bytes = crate::encode(&wide, &value).unwrap().to_bytes();
+    let narrow_bytes = crate::encode(&narrow, &value).unwrap().to_bytes();
+    assert_ne!(narrow_bytes, bytes);
+    assert_eq!(value, crate::decode(&wide, &bytes).unwrap());
+
+    // A width which is too narrow can't be declared
+    let fields = match RecordDef::from(record) {
+      RecordDef::Sized(sized) => sized.fields,
+      _ => panic!("expected a sized record"),
+    };
+    let def = RecordDef::Sized(SizedRecord {
+      width: 0,
+      sparse: false,
+      fields,
+    });
+    let e = Record::try_from(def).unwrap_err();
+    assert_eq!(
+      "width 0 is too narrow for 1 fields, which need 1",
+      e.to_string()
+    );
+  }
+
+  #[test]
+  fn declared_field_order() {
+    let alphabetical = schema(&["age", "name"]);
+    let mut record = Record::new(
+      vec![
+        ("age".to_string(), Type::PassThrough),
+        ("name".to_string(), Type::PassThrough),
+      ]
+      .into_iter()
+      .collect(),
+    );
+    record.reserved.insert("id".to_string());
+    let order = |names: &[&str]| names.iter().map(|n| n.to_string()).collect();
+    record.set_order(order(&["name", "id", "age"])).unwrap();
+    assert_eq!(Some(&FieldId::new(0)), record.field_map().get("name"));
+    assert_eq!(Some(&FieldId::new(2)), record.field_map().get("age"));
+    assert!(record.field_by_id(FieldId::new(1)).is_none());
+    let names: Vec<_> = record.ordered_fields().map(|(k, _)| k).collect();
+    assert_eq!(vec!["name", "age"], names);
+    let ordered = Schema::new(CompositeType::Record(record.clone()));
+    assert_ne!(alphabetical.fingerprint(), ordered.fingerprint());
+
+    let value = serde_json::json!({ "age": "30", "name": "Jeremy" });
+    let bytes = crate::encode(&ordered, &value).unwrap().to_bytes();
+    assert_eq!(value, crate::decode(&ordered, &bytes).unwrap());
+
+    let def = RecordDef::from(record.clone());
+    assert!(matches!(def, RecordDef::Ordered(_)));
+    let roundtrip = Record::try_from(def).unwrap();
+    assert_eq!(record.order, roundtrip.order);
+
+    // Every field must be ordered exactly once
+    let e = |names: &[&str]| {
+      let mut record = record.clone();
+      record.set_order(order(names)).unwrap_err().to_string()
+    };
+    assert_eq!("field id is missing from the order", e(&["name", "age"]));
+    assert_eq!(
+      "field age appears more than once in the order",
+      e(&["name", "age", "id", "age"])
+    );
+    assert_eq!(
+      "ordered field email is not a field",
+      e(&["name", "email", "id", "age"])
+    );
+  }
+
+  #[test]
+  fn field_descriptions() {
+    let fields = vec![("name".to_string(), Type::PassThrough)];
+    let plain = Record::new(fields.into_iter().collect());
+    let mut record = plain.clone();
+    record
+      .descriptions
+      .insert("name".to_string(), "The full name.".to_string());
+
+    let roundtrip = Record::try_from(RecordDef::from(record.clone())).unwrap();
+    assert_eq!(record.descriptions, roundtrip.descriptions);
+    // Descriptions don't change the encoding
+    let fingerprint =
+      |record: Record| Schema::new(CompositeType::Record(record)).fingerprint();
+    assert_eq!(fingerprint(plain), fingerprint(record));
+  }
+
+  #[test]
+  fn schema_macro() {
+    let schema = crate::schema! {
+      required name: raw,
+      active: bool,
+      age: 0..=3,
+      grade: enum[A, B],
+      height: "float(precision=2)",
+      nickname: (Type::PassThrough),
+      courses: [{ title: "smaz" }],
+    };
+
+    let mut course = BTreeMap::new();
+    course.insert("title".to_string(), Type::Name("smaz".to_string()));
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert(
+      "age".to_string(),
+      Type::IntEnum {
+        codes: (0..4).collect(),
+      },
+    );
+    fields.insert(
+      "grade".to_string(),
+      Type::Enum {
+        variants: ["A", "B"].iter().map(|v| v.to_string()).collect(),
+        probabilities: None,
+      },
+    );
+    fields.insert(
+      "height".to_string(),
+      Type::Name("float(precision=2)".to_string()),
+    );
+    fields.insert("nickname".to_string(), Type::PassThrough);
+    fields.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    let mut record = Record::new(fields);
+    record.required.insert("name".to_string());
+    let expected = Schema::new(CompositeType::Record(record));
+    assert_eq!(expected.fingerprint(), schema.fingerprint());
+
+    let list = crate::schema!([-2..2]);
+    match list.root() {
+      Type::Nested(CompositeType::List(List(element))) => match **element {
+        Type::IntEnum { ref codes } => {
+          assert_eq!(
+            vec![-2, -1, 0, 1],
+            codes.iter().copied().collect::<Vec<_>>()
+          )
+        }
+        ref ty => panic!("unexpected element type {:?}", ty),
+      },
+      ty => panic!("unexpected root type {:?}", ty),
+    }
   }
 }
@@ -0,0 +1,1475 @@
+//! A compact text syntax for schemas, which is terser than YAML and easier to
+//! write by hand:
+//!
+//! ```text
+//! version 2;
+//!
+//! record {
+//!   /// The student's full name.
+//!   required name: raw;
+//!   age: 0..120;
+//!   nullable courses: [record { title: smaz; grade: enum[A, B, C] }];
+//!   reserved ssn;
+//! }
+//! ```
+//!
+//! Types are written as:
+//!
+//! - `raw` for values which are encoded as they are;
+//! - a name, like `bool` or `float(precision=2)`, or a quoted name for names
+//!   which aren't made up of letters, digits, `_` and `-` (see [`Type::Name`]);
+//! - `lo..hi` or `lo..=hi` for every integer in a range, or `enum[200, 404]`
+//!   for a few integers (see [`Type::IntEnum`]);
+//! - `enum[a, b, c]` for an enum of the given variants, which may be given
+//!   probabilities like `enum[a = 0.9, b = 0.1]` (see [`Type::Enum`]);
+//! - `dictionary(8)` for a dictionary of up to 8 entries (see
+//!   [`Type::Dictionary`]);
+//! - `huffman(0301010261626364)` for a Huffman code given by its codebook (see
+//!   [`Type::Huffman`]);
+//! - `scaled(100)` or `scaled(1000, floor)` for a scaled number and the way
+//!   it's rounded (see [`Type::Scaled`]);
+//! - `transform[trim, lowercase] type` for the type after it with the given
+//!   transforms (see [`Type::Transformed`]);
+//! - `union[type, ...]` for any of the types within the brackets (see
+//!   [`Type::Union`]);
+//! - `record { ... }` for a record of the fields within the braces, which may
+//!   be preceded by `(width = 6, sparse, ordered)` or any of those settings to
+//!   give the width of its field markers, make it sparse or give its fields
+//!   identifiers in the order they are written in (see [`Record`]);
+//! - `[type]` for a list of the type within the brackets.
+//!
+//! Fields are separated by `;` and written as `name: type`, preceded by any
+//! of `required`, `nullable`, `deprecated` and `inline` and followed by
+//! `= value` for the default value given in JSON, or as `reserved name` for
+//! reserved fields. The lines of `///` comments before a field make up its
+//! description, while other `//` comments are ignored. Names are quoted like
+//! the names of types, escaping `"` and `\` with a `\`.
+//!
+//! The type of the root may be preceded by the `version` of the schema, how
+//! its `lengths` are encoded, the number of elements in each of its `chunks`,
+//! the number of blocks between its `sync` markers and its `epoch`, which is
+//! either `document` or a timestamp, as well as the definitions of named
+//! types. Named types may take type parameters, which stand for the types
+//! given where they are used, so wrappers like pages of results only need to
+//! be written once:
+//!
+//! ```text
+//! type page<T> = record { required items: [T]; next: raw };
+//!
+//! record { students: page<record { name: raw }>; teachers: page<smaz> }
+//! ```
+//!
+//! Named types are expanded where they are used, which is also where their
+//! definitions are checked, so a schema is the same as if they had been
+//! written out in full. [`to_string`] writes them out in full too.
+//!
+//! Only bundles of several versions of a schema can't be written in the
+//! compact syntax, so [`to_string`] fails for them.
+
+use super::{
+  AnnotatedField, CompositeType, FieldDef, List, OrderedRecord, Record,
+  RecordDef, ReservedField, Schema, SizedRecord, SparseRecord, Type,
+  MAX_RANGE_LEN,
+};
+use crate::comp::{Rounding, TransformKind};
+use crate::data::LengthEncoding;
+use crate::epoch::Epoch;
+use crate::prelude::*;
+use alloc::collections::{BTreeMap, BTreeSet};
+use anyhow::{anyhow, bail, Result};
+use core::convert::TryFrom;
+use core::fmt::Write;
+use serde_json::Value;
+
+/// The words which can't be the names of named types or their parameters.
+const KEYWORDS: [&str; 9] = [
+  "raw",
+  "record",
+  "enum",
+  "type",
+  "dictionary",
+  "huffman",
+  "scaled",
+  "transform",
+  "union",
+];
+
+/// The ways lengths can be encoded, which are named by `lengths`.
+const LENGTHS: [LengthEncoding; 4] = [
+  LengthEncoding::Vie,
+  LengthEncoding::Fixed16,
+  LengthEncoding::Fixed32,
+  LengthEncoding::Gamma,
+];
+
+/// The rounding policies of scaled numbers.
+const ROUNDINGS: [Rounding; 4] = [
+  Rounding::Nearest,
+  Rounding::Floor,
+  Rounding::Ceil,
+  Rounding::Exact,
+];
+
+/// The transforms of transformed types.
+const TRANSFORMS: [TransformKind; 3] = [
+  TransformKind::Trim,
+  TransformKind::Lowercase,
+  TransformKind::NfcNormalize,
+];
+
+/// The words which may precede the name of a field.
+const MODIFIERS: [&str; 4] = ["required", "nullable", "deprecated", "inline"];
+
+/// Parses a schema written in the compact syntax.
+///
+/// Errors give the line and column where parsing stopped.
+pub fn parse(text: &str) -> Result<Schema> {
+  let parser = Parser {
+    text,
+    pos: 0,
+    definitions: BTreeMap::new(),
+    expanding: Vec::new(),
+  };
+  parser.schema()
+}
+
+/// Writes `schema` in the compact syntax.
+///
+/// Fails if `schema` bundles older versions of itself, or if it has numbers
+/// which aren't finite, an enum of no integers or enum probabilities which
+/// can't be given next to its variants.
+pub fn to_string(schema: &Schema) -> Result<String> {
+  if !schema.history.is_empty() {
+    bail!("bundles can't be written in the compact syntax");
+  }
+  let mut out = String::new();
+  // Writing to a string can't fail
+  if let Some(version) = schema.version {
+    let _ = writeln!(out, "version {};", version);
+  }
+  if schema.lengths != LengthEncoding::Vie {
+    let _ = writeln!(out, "lengths {};", schema.lengths);
+  }
+  if let Some(size) = schema.chunks {
+    let _ = writeln!(out, "chunks {};", size);
+  }
+  if let Some(blocks) = schema.sync {
+    let _ = writeln!(out, "sync {};", blocks);
+  }
+  match schema.epoch {
+    Some(Epoch::Document) => out.push_str("epoch document;\n"),
+    Some(Epoch::Fixed(t)) => {
+      let _ = writeln!(out, "epoch {};", t);
+    }
+    None => {}
+  }
+  if !out.is_empty() {
+    out.push('\n');
+  }
+  write_type(&mut out, &schema.root, 0, "")?;
+  out.push('\n');
+  Ok(out)
+}
+
+struct Parser<'a> {
+  text: &'a str,
+  /// The byte offset of the next character.
+  pos: usize,
+  /// The named types defined before the root by name.
+  definitions: BTreeMap<&'a str, Definition<'a>>,
+  /// The names of the named types being expanded along with the types
+  /// given for their parameters, innermost last.
+  expanding: Vec<(&'a str, BTreeMap<&'a str, Type>)>,
+}
+
+/// A named type, which may take type parameters.
+struct Definition<'a> {
+  params: Vec<&'a str>,
+  /// The byte offset of the type it stands for.
+  body: usize,
+}
+
+impl<'a> Parser<'a> {
+  fn rest(&self) -> &'a str {
+    &self.text[self.pos..]
+  }
+
+  /// An error at the current position.
+  fn error(&self, message: &str) -> anyhow::Error {
+    let before = &self.text[..self.pos];
+    let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+    anyhow!(
+      "{} at line {}, column {}",
+      message,
+      before.matches('\n').count() + 1,
+      before[line_start..].chars().count() + 1
+    )
+  }
+
+  /// Skips whitespace and comments, returning the lines of any `///`
+  /// comments.
+  fn skip(&mut self) -> Vec<String> {
+    let mut docs = Vec::new();
+    loop {
+      let rest = self.rest();
+      let trimmed = rest.trim_start();
+      self.pos += rest.len() - trimmed.len();
+      if !trimmed.starts_with("//") {
+        return docs;
+      }
+      let line = trimmed.split('\n').next().unwrap_or_default();
+      self.pos += line.len();
+      if let Some(doc) = line.strip_prefix("///") {
+        let doc = doc.strip_prefix(' ').unwrap_or(doc);
+        docs.push(doc.trim_end().to_string());
+      }
+    }
+  }
+
+  /// Consumes `token` if it is next.
+  fn eat(&mut self, token: &str) -> bool {
+    self.skip();
+    if self.rest().starts_with(token) {
+      self.pos += token.len();
+      return true;
+    }
+    false
+  }
+
+  fn expect(&mut self, token: &str) -> Result<()> {
+    if !self.eat(token) {
+      return Err(self.error(&format!("expected `{}`", token)));
+    }
+    Ok(())
+  }
+
+  /// Parses an unquoted name if one is next.
+  fn ident(&mut self) -> Option<&'a str> {
+    self.skip();
+    let rest = self.rest();
+    let len = ident_len(rest);
+    if len == 0 {
+      return None;
+    }
+    self.pos += len;
+    Some(&rest[..len])
+  }
+
+  /// Parses a quoted name if one is next.
+  fn quoted(&mut self) -> Result<Option<String>> {
+    self.skip();
+    if !self.rest().starts_with('"') {
+      return Ok(None);
+    }
+    let start = self.pos;
+    let mut out = String::new();
+    let mut chars = self.rest().char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+      match c {
+        '"' => {
+          self.pos += i + 1;
+          return Ok(Some(out));
+        }
+        '\\' => match chars.next() {
+          Some((_, c @ '"')) | Some((_, c @ '\\')) => out.push(c),
+          _ => {
+            self.pos = start + i;
+            return Err(self.error("expected `\"` or `\\` after `\\`"));
+          }
+        },
+        c => out.push(c),
+      }
+    }
+    Err(self.error("unterminated name"))
+  }
+
+  /// Parses a quoted or unquoted name if one is next.
+  fn name(&mut self) -> Result<Option<String>> {
+    match self.ident() {
+      Some(name) => Ok(Some(name.to_string())),
+      None => self.quoted(),
+    }
+  }
+
+  /// Parses an integer if one is next.
+  fn int(&mut self) -> Result<Option<i64>> {
+    self.skip();
+    let rest = self.rest();
+    let sign = rest.starts_with('-') as usize;
+    let digits = rest[sign..]
+      .find(|c: char| !c.is_ascii_digit())
+      .unwrap_or(rest.len() - sign);
+    if digits == 0 {
+      return Ok(None);
+    }
+    let int = rest[..sign + digits]
+      .parse()
+      .map_err(|_| self.error("integer is out of range"))?;
+    self.pos += sign + digits;
+    Ok(Some(int))
+  }
+
+  /// Parses a finite number if one is next.
+  fn float(&mut self) -> Result<Option<f64>> {
+    self.skip();
+    let rest = self.rest();
+    let len = rest
+      .find(|c: char| !(c.is_ascii_digit() || "+-.eE".contains(c)))
+      .unwrap_or(rest.len());
+    if len == 0 {
+      return Ok(None);
+    }
+    let float = match rest[..len].parse::<f64>() {
+      Ok(float) if float.is_finite() => float,
+      _ => return Err(self.error("expected a number")),
+    };
+    self.pos += len;
+    Ok(Some(float))
+  }
+
+  /// Parses the comma separated arguments in parentheses after a type,
+  /// returning each along with its byte offset.
+  fn args(&mut self) -> Result<Vec<(usize, &'a str)>> {
+    self.expect("(")?;
+    let start = self.pos;
+    let end = match self.rest().find(')') {
+      Some(len) => start + len,
+      None => return Err(self.error("unclosed `(`")),
+    };
+    let mut args = Vec::new();
+    let mut offset = start;
+    for arg in self.text[start..end].split(',') {
+      let trimmed = arg.trim_start();
+      args.push((offset + arg.len() - trimmed.len(), trimmed.trim_end()));
+      offset += arg.len() + 1;
+    }
+    if let [(_, "")] = args[..] {
+      args.clear();
+    }
+    self.pos = end + 1;
+    Ok(args)
+  }
+
+  /// Parses a JSON value, which ends at the `;` or `}` after it.
+  fn json(&mut self) -> Result<Value> {
+    self.skip();
+    let rest = self.rest();
+    let mut depth = 0usize;
+    let mut len = 0;
+    while let Some(c) = rest[len..].chars().next() {
+      match c {
+        ';' | '}' if depth == 0 => break,
+        '[' | '{' => depth += 1,
+        ']' | '}' => depth = depth.saturating_sub(1),
+        '"' => {
+          len += string_len(&rest[len..])
+            .ok_or_else(|| self.error("unterminated string"))?;
+          continue;
+        }
+        _ => {}
+      }
+      len += c.len_utf8();
+    }
+    let value = serde_json::from_str(rest[..len].trim_end())
+      .map_err(|e| self.error(&format!("invalid JSON value: {}", e)))?;
+    self.pos += len;
+    Ok(value)
+  }
+
+  fn schema(mut self) -> Result<Schema> {
+    let mut settings = BTreeMap::new();
+    let mut lengths = None;
+    let mut epoch = None;
+    let root = loop {
+      let start = self.pos;
+      let setting = match self.ident() {
+        Some("type") => {
+          let named = self.ident().is_some();
+          self.pos = start;
+          // Otherwise the root is a type named `type`
+          if !named {
+            break self.ty()?;
+          }
+          self.definition()?;
+          continue;
+        }
+        Some(name) if ["version", "chunks", "sync"].contains(&name) => name,
+        Some("lengths") => {
+          let name = self.ident();
+          match LENGTHS.iter().find(|lengths| Some(lengths.name()) == name) {
+            Some(found) if lengths.replace(*found).is_some() => {
+              return Err(self.error("lengths is given more than once"));
+            }
+            Some(_) => {
+              self.expect(";")?;
+              continue;
+            }
+            // A type named `lengths`
+            None => {
+              self.pos = start;
+              break self.ty()?;
+            }
+          }
+        }
+        Some("epoch") => {
+          let found = match self.int()? {
+            Some(t) => Some(Epoch::Fixed(t)),
+            None => self
+              .ident()
+              .filter(|name| *name == "document")
+              .map(|_| Epoch::Document),
+          };
+          match found {
+            Some(found) if epoch.replace(found).is_some() => {
+              return Err(self.error("epoch is given more than once"));
+            }
+            Some(_) => {
+              self.expect(";")?;
+              continue;
+            }
+            // A type named `epoch`
+            None => {
+              self.pos = start;
+              break self.ty()?;
+            }
+          }
+        }
+        _ => {
+          self.pos = start;
+          break self.ty()?;
+        }
+      };
+      let value = match self.int()? {
+        Some(value) => value,
+        // A type with the same name as a setting
+        None => {
+          self.pos = start;
+          break self.ty()?;
+        }
+      };
+      let min = if setting == "version" { 0 } else { 1 };
+      if value < min || value > i64::from(u32::MAX) {
+        return Err(
+          self.error(&format!("{} {} is out of range", setting, value)),
+        );
+      }
+      if settings.insert(setting, value as u32).is_some() {
+        return Err(
+          self.error(&format!("{} is given more than once", setting)),
+        );
+      }
+      self.expect(";")?;
+    };
+    self.skip();
+    if !self.rest().is_empty() {
+      return Err(self.error("expected the end of the schema"));
+    }
+
+    let mut schema = Schema::new_scalar(root);
+    if let Some(version) = settings.get("version") {
+      schema = schema.with_version(*version);
+    }
+    if let Some(size) = settings.get("chunks") {
+      schema = schema.with_chunks(*size as usize);
+    }
+    if let Some(blocks) = settings.get("sync") {
+      schema = schema.with_sync(*blocks as usize);
+    }
+    if let Some(lengths) = lengths {
+      schema = schema.with_lengths(lengths);
+    }
+    if let Some(epoch) = epoch {
+      schema = schema.with_epoch(epoch);
+    }
+    Ok(schema)
+  }
+
+  fn ty(&mut self) -> Result<Type> {
+    self.skip();
+    let start = self.pos;
+    if self.eat("[") {
+      let element = self.ty()?;
+      self.expect("]")?;
+      return Ok(Type::Nested(CompositeType::List(List(Box::new(element)))));
+    }
+    if let Some(name) = self.quoted()? {
+      return Ok(Type::Name(name));
+    }
+    if let Some(lo) = self.int()? {
+      return self.range(start, lo);
+    }
+    let name = match self.ident() {
+      Some("raw") => return Ok(Type::PassThrough),
+      Some("record") => return self.record(start),
+      Some("enum") => return self.enumeration(),
+      Some("dictionary") => return self.dictionary(),
+      Some("huffman") => return self.huffman(),
+      Some("scaled") => return self.scaled(),
+      Some("transform") => return self.transformed(),
+      Some("union") => return self.union(),
+      Some(name) => name,
+      None => return Err(self.error("expected a type")),
+    };
+    let args = self.expanding.last().map(|(_, args)| args);
+    if let Some(ty) = args.and_then(|args| args.get(name)) {
+      return Ok(ty.clone());
+    }
+    if self.definitions.contains_key(name) {
+      return self.expand(start, name);
+    }
+    if self.rest().starts_with('(') {
+      self.pos +=
+        parens_len(self.rest()).ok_or_else(|| self.error("unclosed `(`"))?;
+      return Ok(Type::Name(self.text[start..self.pos].to_string()));
+    }
+    Ok(Type::Name(name.to_string()))
+  }
+
+  /// Parses the definition of a named type, which starts with `type`.
+  fn definition(&mut self) -> Result<()> {
+    self.expect("type")?;
+    self.skip();
+    let start = self.pos;
+    let name = match self.ident() {
+      Some(name) if !KEYWORDS.contains(&name) => name,
+      _ => {
+        self.pos = start;
+        return Err(self.error("expected the name of a type"));
+      }
+    };
+    let mut params = Vec::new();
+    if self.eat("<") {
+      loop {
+        self.skip();
+        let param_start = self.pos;
+        match self.ident() {
+          Some(param) if !KEYWORDS.contains(&param) => {
+            if params.contains(&param) {
+              self.pos = param_start;
+              let message =
+                format!("parameter {} is given more than once", param);
+              return Err(self.error(&message));
+            }
+            params.push(param);
+          }
+          _ => {
+            self.pos = param_start;
+            return Err(self.error("expected the name of a parameter"));
+          }
+        }
+        if !self.eat(",") {
+          self.expect(">")?;
+          break;
+        }
+      }
+    }
+    self.expect("=")?;
+    self.skip();
+    let body = self.pos;
+    // Parameters only stand for types where the type is used, so that is
+    // where the definition is parsed
+    self.skip_type()?;
+    self.expect(";")?;
+    if self.definitions.contains_key(name) {
+      self.pos = start;
+      let message = format!("type {} is defined more than once", name);
+      return Err(self.error(&message));
+    }
+    self.definitions.insert(name, Definition { params, body });
+    Ok(())
+  }
+
+  /// Skips over a type without parsing it, up to the `;` after it.
+  fn skip_type(&mut self) -> Result<()> {
+    let mut depth = 0usize;
+    loop {
+      self.skip();
+      let c = match self.rest().chars().next() {
+        Some(c) => c,
+        None => return Err(self.error("expected `;`")),
+      };
+      match c {
+        ';' if depth == 0 => return Ok(()),
+        // Strings may be names or parts of the defaults of fields
+        '"' => {
+          self.pos += string_len(self.rest())
+            .ok_or_else(|| self.error("unterminated string"))?;
+          continue;
+        }
+        '[' | '{' | '(' | '<' => depth += 1,
+        ']' | '}' | ')' | '>' => depth = depth.saturating_sub(1),
+        _ => {}
+      }
+      self.pos += c.len_utf8();
+    }
+  }
+
+  /// Expands the named type `name` used at `start`, parsing the types given
+  /// for its parameters.
+  fn expand(&mut self, start: usize, name: &'a str) -> Result<Type> {
+    let mut args = Vec::new();
+    if self.eat("<") {
+      loop {
+        args.push(self.ty()?);
+        if !self.eat(",") {
+          self.expect(">")?;
+          break;
+        }
+      }
+    }
+    let definition = &self.definitions[name];
+    let (params, body) = (definition.params.clone(), definition.body);
+    if args.len() != params.len() {
+      self.pos = start;
+      let message = format!(
+        "type {} takes {} type arguments, not {}",
+        name,
+        params.len(),
+        args.len()
+      );
+      return Err(self.error(&message));
+    }
+    if self
+      .expanding
+      .iter()
+      .any(|(expanding, _)| *expanding == name)
+    {
+      self.pos = start;
+      let message = format!("type {} is defined in terms of itself", name);
+      return Err(self.error(&message));
+    }
+    let end = self.pos;
+    self.pos = body;
+    self
+      .expanding
+      .push((name, params.into_iter().zip(args).collect()));
+    let ty = self.ty()?;
+    self.expanding.pop();
+    self.pos = end;
+    Ok(ty)
+  }
+
+  /// Parses the rest of a range starting at `lo`.
+  fn range(&mut self, start: usize, lo: i64) -> Result<Type> {
+    let inclusive = if self.eat("..=") {
+      true
+    } else if self.eat("..") {
+      false
+    } else {
+      return Err(self.error("expected `..` or `..=`"));
+    };
+    let hi = match self.int()? {
+      Some(hi) if inclusive => i128::from(hi),
+      Some(hi) => i128::from(hi) - 1,
+      None => return Err(self.error("expected the end of the range")),
+    };
+    let len = hi - i128::from(lo) + 1;
+    if len <= 0 || len > MAX_RANGE_LEN {
+      self.pos = start;
+      return Err(self.error(&format!(
+        "range must have between 1 and {} integers",
+        MAX_RANGE_LEN
+      )));
+    }
+    Ok(Type::IntEnum {
+      codes: (lo..=hi as i64).collect(),
+    })
+  }
+
+  /// Parses the variants of an enum after `enum`.
+  fn enumeration(&mut self) -> Result<Type> {
+    self.expect("[")?;
+    let mut variants = BTreeSet::new();
+    let mut probabilities = BTreeMap::new();
+    let mut codes = BTreeSet::new();
+    while !self.eat("]") {
+      let start = self.pos;
+      if let Some(code) = self.int()? {
+        codes.insert(code);
+      } else if let Some(variant) = self.name()? {
+        if self.eat("=") {
+          match self.float()? {
+            Some(p) => probabilities.insert(variant.clone(), p),
+            None => return Err(self.error("expected a probability")),
+          };
+        }
+        variants.insert(variant);
+      } else {
+        return Err(self.error("expected an enum variant"));
+      }
+      if !codes.is_empty() && !variants.is_empty() {
+        self.pos = start;
+        return Err(self.error("enum mixes integers and names"));
+      }
+      if !self.eat(",") {
+        self.expect("]")?;
+        break;
+      }
+    }
+    if codes.is_empty() {
+      return Ok(Type::Enum {
+        variants,
+        probabilities: Some(probabilities).filter(|p| !p.is_empty()),
+      });
+    }
+    Ok(Type::IntEnum { codes })
+  }
+
+  /// Parses the size of a dictionary after `dictionary`.
+  fn dictionary(&mut self) -> Result<Type> {
+    let args = self.args()?;
+    match args[..] {
+      [(start, size)] => match size.parse() {
+        Ok(size) => Ok(Type::Dictionary { size }),
+        Err(_) => {
+          self.pos = start;
+          Err(self.error("expected the size of the dictionary"))
+        }
+      },
+      _ => Err(self.error("expected the size of the dictionary")),
+    }
+  }
+
+  /// Parses the codebook of a Huffman code after `huffman`.
+  fn huffman(&mut self) -> Result<Type> {
+    let args = self.args()?;
+    match args[..] {
+      [(start, codebook)] => match codebook.parse() {
+        Ok(huffman) => Ok(Type::Huffman { huffman }),
+        Err(e) => {
+          self.pos = start;
+          Err(self.error(&e.to_string()))
+        }
+      },
+      _ => Err(self.error("expected a codebook")),
+    }
+  }
+
+  /// Parses the scale and rounding policy of a scaled number after `scaled`.
+  fn scaled(&mut self) -> Result<Type> {
+    let args = self.args()?;
+    let ((start, scale), round) = match args[..] {
+      [scale] => (scale, None),
+      [scale, round] => (scale, Some(round)),
+      _ => return Err(self.error("expected a scale and a rounding policy")),
+    };
+    let scale = match scale.parse::<f64>() {
+      Ok(scale) if scale.is_finite() => scale,
+      _ => {
+        self.pos = start;
+        return Err(self.error("expected a scale"));
+      }
+    };
+    let round = match round {
+      Some((start, name)) => {
+        match ROUNDINGS.iter().find(|round| round.name() == name) {
+          Some(round) => Some(*round),
+          None => {
+            self.pos = start;
+            return Err(self.error("expected a rounding policy"));
+          }
+        }
+      }
+      None => None,
+    };
+    Ok(Type::Scaled { scale, round })
+  }
+
+  /// Parses the transforms and the type after `transform`.
+  fn transformed(&mut self) -> Result<Type> {
+    self.expect("[")?;
+    let mut transform = Vec::new();
+    while !self.eat("]") {
+      let start = self.pos;
+      let name = self.ident();
+      match TRANSFORMS.iter().find(|kind| Some(kind.name()) == name) {
+        Some(kind) => transform.push(*kind),
+        None => {
+          self.pos = start;
+          return Err(self.error("expected a transform"));
+        }
+      }
+      if !self.eat(",") {
+        self.expect("]")?;
+        break;
+      }
+    }
+    let of = Box::new(self.ty()?);
+    Ok(Type::Transformed { transform, of })
+  }
+
+  /// Parses the alternatives of a union after `union`.
+  fn union(&mut self) -> Result<Type> {
+    self.expect("[")?;
+    let mut alternatives = Vec::new();
+    while !self.eat("]") {
+      alternatives.push(self.ty()?);
+      if !self.eat(",") {
+        self.expect("]")?;
+        break;
+      }
+    }
+    Ok(Type::Union { alternatives })
+  }
+
+  /// Parses the settings and fields of a record after `record`, which is at
+  /// `start`.
+  fn record(&mut self, start: usize) -> Result<Type> {
+    let mut width = None;
+    let mut sparse = false;
+    let mut ordered = false;
+    self.skip();
+    if self.rest().starts_with('(') {
+      for (arg_start, arg) in self.args()? {
+        let (setting, value) = match arg.find('=') {
+          Some(i) => (arg[..i].trim_end(), Some(arg[i + 1..].trim_start())),
+          None => (arg, None),
+        };
+        match (setting, value.map(str::parse)) {
+          ("width", Some(Ok(value))) if width.is_none() => width = Some(value),
+          ("sparse", None) if !sparse => sparse = true,
+          ("ordered", None) if !ordered => ordered = true,
+          _ => {
+            self.pos = arg_start;
+            return Err(
+              self.error("expected `width = N`, `sparse` or `ordered`"),
+            );
+          }
+        }
+      }
+    }
+    self.expect("{")?;
+    let mut defs = BTreeMap::new();
+    let mut order = Vec::new();
+    loop {
+      let docs = self.skip();
+      if self.eat("}") {
+        break;
+      }
+      let field_start = self.pos;
+      let (name, def) = self.field(docs)?;
+      order.push(name.clone());
+      if defs.insert(name.clone(), def).is_some() {
+        self.pos = field_start;
+        let message = format!("field {} is defined more than once", name);
+        return Err(self.error(&message));
+      }
+      if !self.eat(";") {
+        self.expect("}")?;
+        break;
+      }
+    }
+    let def = match width {
+      _ if ordered => RecordDef::Ordered(OrderedRecord {
+        order,
+        width,
+        sparse,
+        fields: defs,
+      }),
+      Some(width) => RecordDef::Sized(SizedRecord {
+        width,
+        sparse,
+        fields: defs,
+      }),
+      None if sparse => RecordDef::Sparse(SparseRecord {
+        sparse,
+        fields: defs,
+      }),
+      None => RecordDef::Fields(defs),
+    };
+    let record = Record::try_from(def).map_err(|e| {
+      self.pos = start;
+      self.error(&e.to_string())
+    })?;
+    Ok(Type::Nested(CompositeType::Record(record)))
+  }
+
+  /// Parses a field whose description is made up of the lines `docs`.
+  fn field(&mut self, docs: Vec<String>) -> Result<(String, FieldDef)> {
+    let mut modifiers = Vec::new();
+    let name = loop {
+      self.skip();
+      let quoted = self.rest().starts_with('"');
+      let name = match self.name()? {
+        Some(name) => name,
+        None => return Err(self.error("expected a field")),
+      };
+      if self.eat(":") {
+        break name;
+      }
+      if quoted {
+        return Err(self.error("expected `:`"));
+      }
+      match name.as_str() {
+        "reserved" if modifiers.is_empty() => {
+          let name = match self.name()? {
+            Some(name) => name,
+            None => return Err(self.error("expected a field")),
+          };
+          let def = FieldDef::Reserved(ReservedField { reserved: true });
+          return Ok((name, def));
+        }
+        modifier if MODIFIERS.contains(&modifier) => modifiers.push(name),
+        _ => return Err(self.error("expected `:`")),
+      }
+    };
+    let ty = self.ty()?;
+    let default = match self.eat("=") {
+      true => Some(self.json()?),
+      false => None,
+    };
+    if modifiers.is_empty() && docs.is_empty() && default.is_none() {
+      return Ok((name, FieldDef::Plain(ty)));
+    }
+    let has = |modifier: &str| modifiers.iter().any(|m| m == modifier);
+    let field = AnnotatedField {
+      ty,
+      required: has("required"),
+      default,
+      deprecated: has("deprecated"),
+      nullable: has("nullable"),
+      inline: has("inline"),
+      description: if docs.is_empty() {
+        None
+      } else {
+        Some(docs.join("\n"))
+      },
+    };
+    Ok((name, FieldDef::Annotated(field)))
+  }
+}
+
+/// The length of the unquoted name at the start of `s`, or zero if there is
+/// none.
+fn ident_len(s: &str) -> usize {
+  if !s.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+    return 0;
+  }
+  s.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_' || c == '-'))
+    .unwrap_or(s.len())
+}
+
+/// The length of the quoted string at the start of `s`, skipping over escaped
+/// characters, or `None` if it isn't closed.
+fn string_len(s: &str) -> Option<usize> {
+  let mut chars = s.char_indices().skip(1);
+  while let Some((i, c)) = chars.next() {
+    match c {
+      '"' => return Some(i + 1),
+      '\\' => {
+        chars.next();
+      }
+      _ => {}
+    }
+  }
+  None
+}
+
+/// The length of the parentheses at the start of `s` and everything within
+/// them, or `None` if they aren't closed.
+fn parens_len(s: &str) -> Option<usize> {
+  let mut depth = 0;
+  for (i, c) in s.char_indices() {
+    match c {
+      '(' => depth += 1,
+      ')' if depth == 1 => return Some(i + 1),
+      ')' => depth -= 1,
+      _ => {}
+    }
+  }
+  None
+}
+
+/// An error for something at `path` which can't be written in the compact
+/// syntax.
+fn unsupported(path: &str, what: &str) -> anyhow::Error {
+  let path = if path.is_empty() { "the root" } else { path };
+  anyhow!(
+    "{} can't be written in the compact syntax (at {})",
+    what,
+    path
+  )
+}
+
+/// Writes a name, quoting it unless it is made up of letters, digits, `_`
+/// and `-`.
+fn write_name(out: &mut String, name: &str) {
+  if !name.is_empty() && ident_len(name) == name.len() {
+    out.push_str(name);
+    return;
+  }
+  write_quoted(out, name);
+}
+
+/// Writes a name in quotes.
+fn write_quoted(out: &mut String, name: &str) {
+  out.push('"');
+  for c in name.chars() {
+    if c == '"' || c == '\\' {
+      out.push('\\');
+    }
+    out.push(c);
+  }
+  out.push('"');
+}
+
+/// Writes `ty`, found at `path`, with the lines of any records within it
+/// indented by `indent` levels.
+fn write_type(
+  out: &mut String,
+  ty: &Type,
+  indent: usize,
+  path: &str,
+) -> Result<()> {
+  match ty {
+    Type::PassThrough => out.push_str("raw"),
+    Type::Name(name) => {
+      // Names are written as they are if they would be parsed back the same
+      // way, which keywords wouldn't be
+      let len = ident_len(name);
+      let plain = len > 0
+        && !KEYWORDS.contains(&&name[..len])
+        && (len == name.len()
+          || parens_len(&name[len..]) == Some(name.len() - len));
+      match plain {
+        true => out.push_str(name),
+        false => write_quoted(out, name),
+      }
+    }
+    Type::Nested(CompositeType::List(list)) => {
+      out.push('[');
+      write_type(out, &list.0, indent, &format!("{}[]", path))?;
+      out.push(']');
+    }
+    Type::Nested(CompositeType::Record(record)) => {
+      write_record(out, record, indent, path)?
+    }
+    Type::Enum {
+      variants,
+      probabilities,
+    } => {
+      let probabilities = probabilities.as_ref();
+      if let Some(p) = probabilities {
+        if p.is_empty() {
+          return Err(unsupported(path, "an enum with no probabilities"));
+        }
+        if p.keys().any(|v| !variants.contains(v)) {
+          return Err(unsupported(path, "the probabilities of non-variants"));
+        }
+      }
+      out.push_str("enum[");
+      for (i, variant) in variants.iter().enumerate() {
+        if i > 0 {
+          out.push_str(", ");
+        }
+        write_name(out, variant);
+        if let Some(p) = probabilities.and_then(|p| p.get(variant)) {
+          out.push_str(" = ");
+          write_float(out, *p, path)?;
+        }
+      }
+      out.push(']');
+    }
+    Type::IntEnum { codes } => {
+      let (lo, hi) = match (codes.iter().next(), codes.iter().next_back()) {
+        (Some(lo), Some(hi)) => (*lo, *hi),
+        _ => return Err(unsupported(path, "an enum of no integers")),
+      };
+      if codes.len() > 1
+        && i128::from(hi) - i128::from(lo) + 1 == codes.len() as i128
+      {
+        let _ = write!(out, "{}..={}", lo, hi);
+      } else {
+        let codes: Vec<_> = codes.iter().map(|c| c.to_string()).collect();
+        let _ = write!(out, "enum[{}]", codes.join(", "));
+      }
+    }
+    Type::Dictionary { size } => {
+      let _ = write!(out, "dictionary({})", size);
+    }
+    Type::Huffman { huffman } => {
+      let _ = write!(out, "huffman({})", huffman);
+    }
+    Type::Scaled { scale, round } => {
+      out.push_str("scaled(");
+      write_float(out, *scale, path)?;
+      if let Some(round) = round {
+        let _ = write!(out, ", {}", round);
+      }
+      out.push(')');
+    }
+    Type::Transformed { transform, of } => {
+      let names: Vec<_> = transform.iter().map(|kind| kind.name()).collect();
+      let _ = write!(out, "transform[{}] ", names.join(", "));
+      write_type(out, of, indent, path)?;
+    }
+    Type::Union { alternatives } => {
+      out.push_str("union[");
+      for (i, ty) in alternatives.iter().enumerate() {
+        if i > 0 {
+          out.push_str(", ");
+        }
+        write_type(out, ty, indent, path)?;
+      }
+      out.push(']');
+    }
+  }
+  Ok(())
+}
+
+/// Writes a number found at `path`, failing if it isn't finite.
+fn write_float(out: &mut String, float: f64, path: &str) -> Result<()> {
+  if !float.is_finite() {
+    return Err(unsupported(path, "a number which isn't finite"));
+  }
+  let _ = write!(out, "{}", float);
+  Ok(())
+}
+
+/// Writes `record`, found at `path`, with its fields indented by one level
+/// more than `indent`.
+fn write_record(
+  out: &mut String,
+  record: &Record,
+  indent: usize,
+  path: &str,
+) -> Result<()> {
+  let mut settings = Vec::new();
+  if let Some(width) = record.width {
+    settings.push(format!("width = {}", width));
+  }
+  if record.sparse {
+    settings.push("sparse".to_string());
+  }
+  if !record.order.is_empty() {
+    settings.push("ordered".to_string());
+  }
+  out.push_str("record");
+  if !settings.is_empty() {
+    let _ = write!(out, "({})", settings.join(", "));
+  }
+  out.push_str(" {");
+  if record.fields.is_empty() && record.reserved.is_empty() {
+    out.push('}');
+    return Ok(());
+  }
+  out.push('\n');
+  let pad = (indent + 1) * 2;
+  for (name, _) in record.names() {
+    let ty = match record.fields.get(name) {
+      Some(ty) => ty,
+      None => {
+        let _ = write!(out, "{:pad$}reserved ", "", pad = pad);
+        write_name(out, name);
+        out.push_str(";\n");
+        continue;
+      }
+    };
+    if let Some(description) = record.descriptions.get(name) {
+      for line in description.lines() {
+        let _ = write!(out, "{:pad$}///", "", pad = pad);
+        if !line.is_empty() {
+          let _ = write!(out, " {}", line);
+        }
+        out.push('\n');
+      }
+    }
+    let _ = write!(out, "{:pad$}", "", pad = pad);
+    let sets = [
+      &record.required,
+      &record.nullable,
+      &record.deprecated,
+      &record.inline,
+    ];
+    for (set, modifier) in sets.iter().zip(MODIFIERS.iter()) {
+      if set.contains(name) {
+        let _ = write!(out, "{} ", modifier);
+      }
+    }
+    write_name(out, name);
+    out.push_str(": ");
+    let path = match path {
+      "" => name.to_string(),
+      path => format!("{}.{}", path, name),
+    };
+    write_type(out, ty, indent + 1, &path)?;
+    if let Some(default) = record.defaults.get(name) {
+      let _ = write!(out, " = {}", default);
+    }
+    out.push_str(";\n");
+  }
+  let _ = write!(out, "{:pad$}}}", "", pad = indent * 2);
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use serde_json::json;
+
+  const STUDENTS: &str = r#"version 2;
+
+record {
+  /// The student's full name.
+  required name: raw;
+  age: 0..120; // in years
+  nullable courses: [record { title: smaz; grade: enum[A, B, "C+"] }];
+  height: float(precision=2);
+  reserved ssn;
+}
+"#;
+
+  fn students() -> Schema {
+    let mut course = BTreeMap::new();
+    course.insert("title".to_string(), Type::Name("smaz".to_string()));
+    course.insert(
+      "grade".to_string(),
+      Type::Enum {
+        variants: ["A", "B", "C+"].iter().map(|v| v.to_string()).collect(),
+        probabilities: None,
+      },
+    );
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert(
+      "age".to_string(),
+      Type::IntEnum {
+        codes: (0..120).collect(),
+      },
+    );
+    fields.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    fields.insert(
+      "height".to_string(),
+      Type::Name("float(precision=2)".to_string()),
+    );
+    let mut record = Record::new(fields);
+    record.required.insert("name".to_string());
+    record.nullable.insert("courses".to_string());
+    record.reserved.insert("ssn".to_string());
+    record
+      .descriptions
+      .insert("name".to_string(), "The student's full name.".to_string());
+    Schema::new(CompositeType::Record(record)).with_version(2)
+  }
+
+  #[test]
+  fn parse_and_write_compact_syntax() {
+    let schema = parse(STUDENTS).unwrap();
+    assert_eq!(students().fingerprint(), schema.fingerprint());
+    assert_eq!(Some(2), schema.version());
+    let record = match schema.composite_root() {
+      Some(CompositeType::Record(record)) => record,
+      _ => panic!("root isn't a record"),
+    };
+    assert!(record.is_nullable("courses"));
+    assert_eq!(
+      Some("The student's full name."),
+      record.descriptions.get("name").map(String::as_str)
+    );
+
+    let written = to_string(&schema).unwrap();
+    assert_eq!(
+      concat!(
+        "version 2;\n\n",
+        "record {\n",
+        "  age: 0..=119;\n",
+        "  nullable courses: [record {\n",
+        "    grade: enum[A, B, \"C+\"];\n",
+        "    title: smaz;\n",
+        "  }];\n",
+        "  height: float(precision=2);\n",
+        "  /// The student's full name.\n",
+        "  required name: raw;\n",
+        "  reserved ssn;\n",
+        "}\n",
+      ),
+      written
+    );
+    let reparsed = parse(&written).unwrap();
+    assert_eq!(schema.fingerprint(), reparsed.fingerprint());
+    assert_eq!(written, to_string(&reparsed).unwrap());
+
+    let list = parse("[enum[200, 404]]").unwrap();
+    assert_eq!("[enum[200, 404]]\n", to_string(&list).unwrap());
+  }
+
+  #[test]
+  fn compact_syntax_errors() {
+    let e = |text: &str| parse(text).unwrap_err().to_string();
+    assert_eq!(
+      "expected `:` at line 2, column 8",
+      e("record {\n  name raw\n}")
+    );
+    assert_eq!(
+      "field name is defined more than once at line 1, column 21",
+      e("record { name: raw; name: bool }")
+    );
+    assert_eq!(
+      "field name is nullable but isn't a list at line 1, column 1",
+      e("record { nullable name: raw }")
+    );
+    assert_eq!(
+      "range must have between 1 and 65536 integers at line 1, column 1",
+      e("5..5")
+    );
+    assert_eq!(
+      "enum mixes integers and names at line 1, column 9",
+      e("enum[1, a]")
+    );
+    assert_eq!(
+      "expected the end of the schema at line 1, column 5",
+      e("raw raw")
+    );
+
+    assert_eq!(
+      "expected `width = N`, `sparse` or `ordered` at line 1, column 8",
+      e("record(wide) {}")
+    );
+    assert_eq!(
+      "expected a rounding policy at line 1, column 12",
+      e("scaled(10, up)")
+    );
+    assert_eq!(
+      "expected a transform at line 1, column 11",
+      e("transform[upper] raw")
+    );
+
+    let mut schema = students();
+    schema
+      .override_type(
+        &".courses[0].title".parse().unwrap(),
+        Type::Scaled {
+          scale: f64::NAN,
+          round: None,
+        },
+      )
+      .unwrap();
+    assert_eq!(
+      "a number which isn't finite can't be written in the compact syntax \
+       (at courses[].title)",
+      to_string(&schema).unwrap_err().to_string()
+    );
+  }
+
+  #[test]
+  fn parse_and_write_every_setting() {
+    let codebook = crate::comp::Codebook::train(&["get", "post", "put"]);
+    let text = format!(
+      concat!(
+        "version 3;\n",
+        "lengths gamma;\n",
+        "chunks 64;\n",
+        "sync 128;\n",
+        "epoch 1600000000;\n\n",
+        "record(width = 6, sparse) {{\n",
+        "  email: transform[trim, lowercase] raw;\n",
+        "  events: [union[record(ordered) {{\n",
+        "    y: bool;\n",
+        "    x: bool;\n",
+        "  }}, f16]];\n",
+        "  level: enum[debug = 0.25, info = 0.75] = \"info\";\n",
+        "  method: dictionary(8);\n",
+        "  name: huffman({});\n",
+        "  price: scaled(0.01, floor);\n",
+        "  required tags: [raw] = [\"a;b\",\"}}\"];\n",
+        "}}\n",
+      ),
+      codebook
+    );
+    let schema = parse(&text).unwrap();
+    assert_eq!(text, to_string(&schema).unwrap());
+    assert_eq!(LengthEncoding::Gamma, schema.lengths());
+    assert_eq!(Some(Epoch::Fixed(1_600_000_000)), schema.epoch());
+
+    let record = match schema.root() {
+      Type::Nested(CompositeType::Record(record)) => record,
+      _ => panic!("root isn't a record"),
+    };
+    assert_eq!(Some(6), record.width);
+    assert!(record.sparse);
+    assert_eq!(Some(&json!(["a;b", "}"])), record.defaults.get("tags"));
+    match &record.fields["price"] {
+      Type::Scaled { scale, round } => {
+        assert_eq!(0.01, *scale);
+        assert_eq!(Some(Rounding::Floor), *round);
+      }
+      ty => panic!("unexpected type {:?}", ty),
+    }
+    match &record.fields["events"] {
+      Type::Nested(CompositeType::List(List(element))) => match &**element {
+        Type::Union { alternatives } => match &alternatives[0] {
+          Type::Nested(CompositeType::Record(event)) => {
+            assert_eq!(["y", "x"], event.order[..])
+          }
+          ty => panic!("unexpected type {:?}", ty),
+        },
+        ty => panic!("unexpected type {:?}", ty),
+      },
+      ty => panic!("unexpected type {:?}", ty),
+    }
+
+    let list = parse("epoch document;\n\n[dictionary(2)]").unwrap();
+    assert_eq!(Some(Epoch::Document), list.epoch());
+    // Types named like settings or keywords are still types
+    let named = parse("lengths").unwrap();
+    assert_eq!("lengths\n", to_string(&named).unwrap());
+    for keyword in &["raw", "union"] {
+      let quoted = Schema::new_scalar(Type::Name(keyword.to_string()));
+      let written = to_string(&quoted).unwrap();
+      assert_eq!(format!("\"{}\"\n", keyword), written);
+      assert_eq!(quoted.fingerprint(), parse(&written).unwrap().fingerprint());
+    }
+  }
+
+  #[test]
+  fn generic_named_types() {
+    let schema = parse(concat!(
+      "type page<T> = record { required items: [T]; next: raw };\n",
+      "type grade = enum[A, B]; // defined after its first use\n",
+      "type pair<A, B> = record { first: A; second: B };\n\n",
+      "record {\n",
+      "  students: page<record { name: raw; grade: grade }>;\n",
+      "  scores: page<pair<grade, 0..=100>>;\n",
+      "}\n",
+    ))
+    .unwrap();
+    let expanded = parse(concat!(
+      "record {\n",
+      "  students: record {\n",
+      "    required items: [record { name: raw; grade: enum[A, B] }];\n",
+      "    next: raw\n",
+      "  };\n",
+      "  scores: record {\n",
+      "    required items: [record { first: enum[A, B]; second: 0..=100 }];\n",
+      "    next: raw\n",
+      "  };\n",
+      "}\n",
+    ))
+    .unwrap();
+    assert_eq!(expanded.fingerprint(), schema.fingerprint());
+    assert_eq!(to_string(&expanded).unwrap(), to_string(&schema).unwrap());
+
+    let e = |text: &str| parse(text).unwrap_err().to_string();
+    assert_eq!(
+      "type page takes 1 type arguments, not 0 at line 1, column 21",
+      e("type page<T> = [T]; page")
+    );
+    assert_eq!(
+      "type a is defined in terms of itself at line 1, column 11",
+      e("type a = [a]; a")
+    );
+    assert_eq!(
+      "type a is defined more than once at line 1, column 20",
+      e("type a = raw; type a = bool; a")
+    );
+    assert_eq!(
+      "field items is nullable but isn't a list at line 1, column 16",
+      e("type page<T> = record { nullable items: T }; page<raw>")
+    );
+  }
+}
@@ -0,0 +1,185 @@
+//! Totals up the bits taken up by each field of a compressed object.
+
+use crate::bit::BitVec;
+use crate::data::{Block, CompressedObject, Layout, Visitor};
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use crate::schema::{Schema, Type};
+use anyhow::Result;
+
+impl CompressedObject {
+  /// Returns the number of bits taken up by the values at each path within
+  /// this object, in the order the paths are first found.
+  ///
+  /// List indices in the paths are always zero and stand for every element,
+  /// so the bits of a field are totalled over all of the lists it is within.
+  /// The bits of a record or list include its header and everything nested
+  /// within it, so the root path has the bits of the whole object.
+  ///
+  /// Fails if the blocks of this object don't match the structure of
+  /// `schema`, see [`validate`](CompressedObject::validate).
+  pub fn field_sizes(&self, schema: &Schema) -> Result<Vec<(Path, usize)>> {
+    let mut sizes = Sizes {
+      layout: self.layout,
+      sizes: Vec::new(),
+      stack: Vec::new(),
+      pending: 0,
+    };
+    self.accept(schema, &mut sizes)?;
+    Ok(sizes.sizes)
+  }
+}
+
+/// A visitor which totals up the bits of each path.
+struct Sizes {
+  layout: Layout,
+  sizes: Vec<(Path, usize)>,
+  /// The paths of the open records and lists along with the bits they have
+  /// taken up so far.
+  stack: Vec<(Path, usize)>,
+  /// The bits of the blocks which have been walked but not yet counted
+  /// towards a path.
+  pending: usize,
+}
+
+impl Sizes {
+  /// The path of a value in `field` of the innermost open record, or an
+  /// element of the innermost open list.
+  fn path(&self, field: Option<&str>) -> Path {
+    let mut path = match self.stack.last() {
+      Some((path, _)) => path.clone(),
+      None => return Path::root(),
+    };
+    path.0.push(match field {
+      Some(field) => Segment::Field(field.to_string()),
+      None => Segment::Index(0),
+    });
+    path
+  }
+
+  /// Returns the entry for `path`, adding one if it is new.
+  fn entry(&mut self, path: &Path) -> &mut usize {
+    let i = match self.sizes.iter().position(|(p, _)| p == path) {
+      Some(i) => i,
+      None => {
+        self.sizes.push((path.clone(), 0));
+        self.sizes.len() - 1
+      }
+    };
+    &mut self.sizes[i].1
+  }
+
+  /// Counts `bits` towards `path` and the record or list enclosing it.
+  fn add(&mut self, path: &Path, bits: usize) {
+    *self.entry(path) += bits;
+    if let Some((_, parent)) = self.stack.last_mut() {
+      *parent += bits;
+    }
+  }
+
+  fn open(&mut self, field: Option<&str>) {
+    let path = self.path(field);
+    // Composite values come before their contents in the order of paths
+    self.entry(&path);
+    let bits = core::mem::take(&mut self.pending);
+    self.stack.push((path, bits));
+  }
+
+  fn close(&mut self) {
+    if let Some((path, bits)) = self.stack.pop() {
+      let bits = bits + core::mem::take(&mut self.pending);
+      self.add(&path, bits);
+    }
+  }
+
+  fn value(&mut self, field: Option<&str>) {
+    let path = self.path(field);
+    let bits = core::mem::take(&mut self.pending);
+    self.add(&path, bits);
+  }
+}
+
+impl Visitor for Sizes {
+  fn visit_record(&mut self, field: Option<&str>) {
+    self.open(field);
+  }
+
+  fn visit_record_end(&mut self) {
+    self.close();
+  }
+
+  fn visit_list(&mut self, field: Option<&str>, _: usize) {
+    self.open(field);
+  }
+
+  fn visit_list_end(&mut self) {
+    self.close();
+  }
+
+  fn visit_null(&mut self, field: Option<&str>) {
+    self.value(field);
+  }
+
+  fn visit_data(&mut self, field: Option<&str>, _: &Type, _: &BitVec) {
+    self.value(field);
+  }
+
+  fn visit_block(&mut self, block: &Block) {
+    let bits = block.bit_len_with(self.layout);
+    match (block, self.stack.last_mut()) {
+      // Chunk headers and sync blocks belong to the list rather than the
+      // element after them
+      (Block::ChunkHeader(_), Some((_, list)))
+      | (Block::Sync(_), Some((_, list))) => *list += bits,
+      _ => self.pending += bits,
+    }
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{CompositeType, List, Record};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  #[test]
+  fn field_sizes_are_totalled_over_elements() {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    let mut student = BTreeMap::new();
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    let schema = Schema::new(CompositeType::Record(Record::new(student)));
+
+    let value = json!({
+      "active": true,
+      "courses": [{ "name": "Art" }, { "name": "Math" }]
+    });
+    let co = crate::encode(&schema, &value).unwrap();
+    let sizes: Vec<_> = co
+      .field_sizes(&schema)
+      .unwrap()
+      .into_iter()
+      .map(|(path, bits)| (path.to_string(), bits))
+      .collect();
+    let expected = [
+      (".", co.bit_len()),
+      (".active", 3),
+      (".courses", 86),
+      (".courses[0]", 76),
+      (".courses[0].name", 33 + 41),
+    ];
+    let expected: Vec<_> = expected
+      .iter()
+      .map(|(p, bits)| (p.to_string(), *bits))
+      .collect();
+    assert_eq!(expected, sizes);
+    assert_eq!(3 + 86, co.bit_len());
+  }
+}
@@ -0,0 +1,855 @@
+//! The `spec` module states the structural rules of the binary format as
+//! data, and checks byte streams against them.
+//!
+//! [`verify_bytes`] walks an object using nothing but the schema and the
+//! [`RULES`], independently of the decoder, so it can check the output of
+//! other implementations of the format as well as this one. Only the
+//! structure of the object is checked; the data of non-nested values is
+//! skipped without being decompressed.
+//!
+//! The first broken rule is reported as a [`Violation`], which can be
+//! recovered from the returned error with `downcast_ref`:
+//!
+//! ```ignore
+//! if let Err(e) = chii::spec::verify_bytes(&schema, &bytes) {
+//!   let violation = e.downcast_ref::<Violation>().unwrap();
+//!   println!("{} broken at bit {}", violation.rule.id, violation.offset);
+//! }
+//! ```
+
+use crate::bit::BitReader;
+use crate::comp::{self, EncodedWidth};
+use crate::data::{FieldId, Layout, LengthEncoding, Profile, SYNC_MAGIC};
+use crate::decode::split_dictionaries;
+use crate::math;
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use crate::schema::{CompositeType, List, Record, Schema, Type};
+use crate::vie::CodePoint;
+use alloc::collections::BTreeSet;
+use anyhow::{bail, Result};
+use core::convert::TryFrom;
+use core::fmt;
+
+/// A rule which every object must follow.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Rule {
+  /// A short name which identifies the rule.
+  pub id: &'static str,
+  /// What the rule requires.
+  pub text: &'static str,
+}
+
+pub const COMPLETE_SECTIONS: Rule = Rule {
+  id: "complete-sections",
+  text: "Every layout flag, field marker, union tag, length, data section and \
+         checksum lies entirely within the object.",
+};
+
+pub const VALID_MARKER: Rule = Rule {
+  id: "valid-marker",
+  text: "A field marker is either zero, ending its record, or one more than \
+         the identifier of one of the record's fields. The identifiers of \
+         reserved fields never appear. The fields of an inline record are \
+         marked among its parent's, with identifiers in place of the inline \
+         field's. Under the dense profile, records whose fields are all \
+         required and none deprecated have no markers. If the layout flag of a sparse record is set, each marker \
+         is instead given as its difference from the previous one, as a VIE \
+         code point.",
+};
+
+pub const VALID_TAG: Rule = Rule {
+  id: "valid-tag",
+  text: "A union tag is the index of one of the union's types.",
+};
+
+pub const UNIQUE_FIELDS: Rule = Rule {
+  id: "unique-fields",
+  text: "A field appears at most once in each record, and the fields of an \
+         inline record appear one after another.",
+};
+
+pub const REQUIRED_FIELDS: Rule = Rule {
+  id: "required-fields",
+  text: "Every field which the schema marks as required is present.",
+};
+
+pub const TERMINATED_RECORDS: Rule = Rule {
+  id: "terminated-records",
+  text: "Every record other than the root and inline records ends with a \
+         zero field marker, as does a root record whose markers are given as \
+         differences. Dense records have no terminator.",
+};
+
+pub const ELEMENT_COUNT: Rule = Rule {
+  id: "element-count",
+  text: "A list is followed by exactly as many elements as its length states. \
+         The length of a nullable list field is one more than its number of \
+         elements, and a length of zero means it is null.",
+};
+
+pub const CHUNK_LENGTH: Rule = Rule {
+  id: "chunk-length",
+  text: "If the schema has a chunk size, the elements of a list with more \
+         elements than it are split into chunks of that many, the last of \
+         which may have fewer. Each chunk starts with a length holding the \
+         number of bits its elements take up.",
+};
+
+pub const SYNC_MARKERS: Rule = Rule {
+  id: "sync-markers",
+  text: "If the schema has sync markers, each element of a root list, or each \
+         chunk if it is chunked, starts with a sync flag. If the flag is set \
+         it is followed by the 64 bits of the sync magic and a length holding \
+         the index of the element.",
+};
+
+pub const VALID_CHECKSUM: Rule = Rule {
+  id: "valid-checksum",
+  text: "If the object was encoded with checksums, the data section of each \
+         variable-width value is followed by 8 bits holding the CRC of its \
+         bits, with polynomial 0x07 and an initial value of zero.",
+};
+
+pub const VALID_LENGTH: Rule = Rule {
+  id: "valid-length",
+  text: "A length is encoded as the schema states. A VIE length is a code \
+         point of at most 10 bytes whose value fits in 64 bits, and a gamma \
+         length has at most 63 leading zeros.",
+};
+
+pub const MINIMAL_LENGTH: Rule = Rule {
+  id: "minimal-length",
+  text: "A VIE length is encoded in as few bytes as possible, so its last \
+         byte is only zero if it is the only byte.",
+};
+
+pub const ZERO_PADDING: Rule = Rule {
+  id: "zero-padding",
+  text: "In a byte-aligned object, the padding after each layout flag, field \
+         marker, union tag, length and data section is made up of zero bits.",
+};
+
+pub const TRAILING_BITS: Rule = Rule {
+  id: "trailing-bits",
+  text: "An object ends within a byte of the end of its last block, and any \
+         bits after the block are zero.",
+};
+
+/// Every rule of the format.
+pub const RULES: &[Rule] = &[
+  COMPLETE_SECTIONS,
+  VALID_MARKER,
+  VALID_TAG,
+  UNIQUE_FIELDS,
+  REQUIRED_FIELDS,
+  TERMINATED_RECORDS,
+  ELEMENT_COUNT,
+  CHUNK_LENGTH,
+  SYNC_MARKERS,
+  VALID_CHECKSUM,
+  VALID_LENGTH,
+  MINIMAL_LENGTH,
+  ZERO_PADDING,
+  TRAILING_BITS,
+];
+
+/// A rule broken by an object.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Violation {
+  /// The rule which was broken.
+  pub rule: Rule,
+  /// The path of the value in which the rule was broken.
+  pub path: Path,
+  /// The bit offset at which the rule was broken.
+  pub offset: usize,
+  /// A description of how the rule was broken.
+  pub detail: String,
+}
+
+impl fmt::Display for Violation {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(
+      f,
+      "{}: {} at bit {}",
+      self.rule.id, self.detail, self.offset
+    )?;
+    if !self.path.segments().is_empty() {
+      write!(f, " (at {})", self.path.to_pointer())?;
+    }
+    Ok(())
+  }
+}
+
+impl core::error::Error for Violation {}
+
+/// Checks that `bytes` is a packed object which conforms to the format for
+/// `schema`.
+///
+/// `bytes` must hold the object alone, without an attached index or unknown
+/// fields footer, but with its [dictionaries](crate::dictionary) footer if
+/// `schema` has dictionary fields. Fails with a [`Violation`] for the first
+/// rule which is broken, or with some other error if `schema` uses a type which
+/// the format doesn't define.
+pub fn verify_bytes(schema: &Schema, bytes: &[u8]) -> Result<()> {
+  verify_bytes_with(schema, bytes, Layout::Packed, Profile::Standard, false)
+}
+
+/// Checks that `bytes` is an object laid out using `layout` and encoded with
+/// `profile`, and with checksums if `checksums` is set, which conforms to the
+/// format for `schema`.
+pub fn verify_bytes_with(
+  schema: &Schema,
+  bytes: &[u8],
+  layout: Layout,
+  profile: Profile,
+  checksums: bool,
+) -> Result<()> {
+  let (bytes, _) = split_dictionaries(schema, bytes)?;
+  let mut verifier = Verifier {
+    r: BitReader::new(bytes),
+    layout,
+    profile,
+    checksums,
+    lengths: schema.lengths(),
+    chunks: schema.chunks(),
+    sync: schema.sync().is_some(),
+    path: Vec::new(),
+  };
+  match schema.root() {
+    Type::Nested(CompositeType::Record(record)) => {
+      verifier.record(record, true, None)?
+    }
+    Type::Nested(CompositeType::List(list)) => {
+      verifier.list(list, false, true)?
+    }
+    ty => verifier.data(ty)?,
+  }
+  verifier.trailing_bits()
+}
+
+/// The width of the data of a non-nested value of type `ty`, which is
+/// preceded by a length if it is variable.
+fn data_width(ty: &Type) -> Result<EncodedWidth> {
+  match ty {
+    Type::PassThrough
+    | Type::Huffman { .. }
+    | Type::Scaled { .. }
+    | Type::Dictionary { .. } => Ok(EncodedWidth::Variable),
+    Type::Name(name) if name == "bool" => Ok(EncodedWidth::Fixed(1)),
+    Type::Name(name) if name == "f16" => Ok(EncodedWidth::Fixed(16)),
+    Type::Name(name) if name == "color" => Ok(EncodedWidth::Fixed(25)),
+    #[cfg(feature = "experimental-bwt")]
+    Type::Name(name) if name == "bwt" => Ok(EncodedWidth::Variable),
+    Type::Name(name)
+      if ["any", "lzw", "smaz", "timestamp"].contains(&name.as_str()) =>
+    {
+      Ok(EncodedWidth::Variable)
+    }
+    Type::Name(name) if comp::GeoCompressor::parse(name).is_some() => {
+      Ok(crate::encode::get_compressor_for_type(ty)?.encoded_width())
+    }
+    Type::Name(name) if crate::encode::is_float(name) => {
+      Ok(EncodedWidth::Variable)
+    }
+    Type::Name(name) => bail!("the format doesn't define type '{}'", name),
+    Type::Enum {
+      probabilities: Some(_),
+      ..
+    } => Ok(EncodedWidth::Prefix),
+    Type::Enum { variants, .. } => Ok(EncodedWidth::Fixed(
+      math::required_bit_width(variants.len()),
+    )),
+    Type::IntEnum { codes } => {
+      Ok(EncodedWidth::Fixed(math::required_bit_width(codes.len())))
+    }
+    Type::Transformed { of, .. } => data_width(of),
+    Type::Nested(_) => bail!("composite types have no data"),
+    Type::Union { .. } => bail!("unions have no data"),
+  }
+}
+
+/// Whether every value of type `ty` takes up at least one bit when encoded
+/// with `profile`.
+fn takes_bits(ty: &Type, profile: Profile) -> Result<bool> {
+  match ty {
+    Type::Nested(CompositeType::Record(record))
+      if profile == Profile::Dense && record.is_dense() =>
+    {
+      for ty in record.fields.values() {
+        if takes_bits(ty, profile)? {
+          return Ok(true);
+        }
+      }
+      Ok(false)
+    }
+    Type::Nested(CompositeType::Record(record)) => {
+      Ok(record.sparse || record.field_width() > 0)
+    }
+    Type::Nested(CompositeType::List(_)) => Ok(true),
+    Type::Union { alternatives } if alternatives.len() > 1 => Ok(true),
+    Type::Union { alternatives } => match alternatives.first() {
+      Some(ty) => takes_bits(ty, profile),
+      None => bail!("a union needs at least one type"),
+    },
+    ty => match data_width(ty)? {
+      EncodedWidth::Fixed(width) => Ok(width > 0),
+      EncodedWidth::Variable => Ok(true),
+      // Values take up no bits if the empty sequence is a whole code
+      EncodedWidth::Prefix => Ok(
+        crate::encode::get_compressor_for_type(ty)?
+          .prefix_len(&mut core::iter::empty())
+          .is_err(),
+      ),
+    },
+  }
+}
+
+/// Walks an object checking each section against the rules.
+struct Verifier<'b> {
+  r: BitReader<'b>,
+  layout: Layout,
+  profile: Profile,
+  /// Whether variable-width data sections are followed by checksums.
+  checksums: bool,
+  lengths: LengthEncoding,
+  /// The number of elements in each chunk of a list, if lists are chunked.
+  chunks: Option<usize>,
+  /// Whether the elements of a root list are preceded by sync blocks.
+  sync: bool,
+  /// The path to the value currently being checked.
+  path: Vec<Segment>,
+}
+
+impl<'b> Verifier<'b> {
+  fn violation(
+    &self,
+    rule: Rule,
+    offset: usize,
+    detail: String,
+  ) -> anyhow::Error {
+    anyhow::Error::new(Violation {
+      rule,
+      path: Path(self.path.clone()),
+      offset,
+      detail,
+    })
+  }
+
+  /// Checks that there are at least `n` bits left for a section.
+  fn expect(&self, n: usize, section: &str) -> Result<()> {
+    if self.r.remaining() < n {
+      let detail = format!(
+        "{} needs {} bits but only {} remain",
+        section,
+        n,
+        self.r.remaining()
+      );
+      return Err(self.violation(COMPLETE_SECTIONS, self.r.position(), detail));
+    }
+    Ok(())
+  }
+
+  /// Skips the padding after a marker or data section, checking that it is
+  /// all zeros.
+  fn padding(&mut self) -> Result<()> {
+    let start = self.r.position();
+    let n = self.layout.padded(start) - start;
+    for _ in 0..n {
+      if self.r.read_bit() != Some(false) {
+        let detail = "padding holds a set bit".to_string();
+        return Err(self.violation(ZERO_PADDING, start, detail));
+      }
+    }
+    Ok(())
+  }
+
+  /// Checks a record, which is inline if its `parent`'s marker width and
+  /// the identifier of its first field among them are given.
+  fn record(
+    &mut self,
+    record: &Record,
+    root: bool,
+    parent: Option<(usize, u32)>,
+  ) -> Result<()> {
+    // Dense records are just their fields in schema order
+    if self.profile == Profile::Dense && record.is_dense() && parent.is_none() {
+      for (name, ty) in record.ordered_fields() {
+        self.path.push(Segment::Field(name.clone()));
+        self.value(ty, record.is_nullable(name))?;
+        self.path.pop();
+      }
+      return Ok(());
+    }
+    let width = parent.map_or(record.field_width(), |p| p.0);
+    // Inline records are marked among their parent's fields
+    let sparse = record.sparse && parent.is_none() && {
+      self.expect(1, "layout flag")?;
+      let sparse = self.r.read_bit().unwrap_or_default();
+      self.padding()?;
+      sparse
+    };
+    let mut seen = BTreeSet::new();
+    let mut prev = 0u64;
+    loop {
+      let start = self.r.position();
+      let marker = if sparse {
+        if self.r.remaining() == 0 {
+          let detail = "input ends before the record's terminator".to_string();
+          return Err(self.violation(TERMINATED_RECORDS, start, detail));
+        }
+        match prev.checked_add(self.vie_length()?) {
+          Some(marker) if marker == prev => break,
+          Some(marker) => marker,
+          None => {
+            let detail = "marker overflows 64 bits".to_string();
+            return Err(self.violation(VALID_MARKER, start, detail));
+          }
+        }
+      } else {
+        // The root record ends at the end of the input, leaving any trailing
+        // bits to be checked once it is done. An inline record leaves it to
+        // its parent.
+        if (root || parent.is_some()) && self.r.remaining() < width {
+          break;
+        }
+        if self.r.remaining() < width {
+          let detail = "input ends before the record's terminator".to_string();
+          return Err(self.violation(TERMINATED_RECORDS, start, detail));
+        }
+
+        let marker = self.r.read_rev_be(width).unwrap_or_default();
+        self.padding()?;
+        if marker == 0 {
+          if root || parent.is_some() {
+            self.r.seek(start);
+          }
+          break;
+        }
+        marker
+      };
+      prev = marker;
+
+      // An inline record ends at the first marker which isn't one of its
+      // fields'
+      let id = match parent {
+        Some((_, first)) => match (marker - 1).checked_sub(first as u64) {
+          Some(id) if id < record.id_count() as u64 => id,
+          _ => {
+            self.r.seek(start);
+            break;
+          }
+        },
+        None => marker - 1,
+      };
+      let field = u32::try_from(id)
+        .ok()
+        .and_then(|id| record.field_by_id(FieldId::new(id)));
+      let (name, ty) = match field {
+        Some(field) => field,
+        None => {
+          let detail = format!("marker {} matches no field", marker);
+          return Err(self.violation(VALID_MARKER, start, detail));
+        }
+      };
+      self.path.push(Segment::Field(name.clone()));
+      if !seen.insert(name) {
+        let detail = format!("field {} is repeated", name);
+        return Err(self.violation(UNIQUE_FIELDS, start, detail));
+      }
+      match record.inline_record(name) {
+        Some(inline) => {
+          self.r.seek(start);
+          let first = record.field_map()[name.as_str()].index() as u32;
+          let first = parent.map_or(0, |p| p.1) + first;
+          self.record(inline, root, Some((width, first)))?;
+        }
+        None => self.value(ty, record.is_nullable(name))?,
+      }
+      self.path.pop();
+    }
+
+    self.required(record, &seen)
+  }
+
+  /// Checks that the required fields of `record` which aren't `seen` are
+  /// the fields of inline records, whose own required fields are then
+  /// missing.
+  fn required(
+    &mut self,
+    record: &Record,
+    seen: &BTreeSet<&String>,
+  ) -> Result<()> {
+    for name in record.required.iter().filter(|n| !seen.contains(n)) {
+      self.path.push(Segment::Field(name.clone()));
+      match record.inline_record(name) {
+        Some(inline) => self.required(inline, &BTreeSet::new())?,
+        None => {
+          self.path.pop();
+          let detail = format!("required field {} is missing", name);
+          let offset = self.r.position();
+          return Err(self.violation(REQUIRED_FIELDS, offset, detail));
+        }
+      }
+      self.path.pop();
+    }
+    Ok(())
+  }
+
+  fn list(&mut self, list: &List, nullable: bool, root: bool) -> Result<()> {
+    // The length of a nullable list is one more than its number of elements,
+    // with zero meaning null
+    let len = match self.length()? {
+      0 if nullable => return Ok(()),
+      len => len - nullable as u64,
+    };
+    let ty = list.0.as_ref();
+    // Values which take up no bits are always valid, so there is no need to
+    // walk them, however many there are, though chunks still have headers
+    let takes_bits = takes_bits(ty, self.profile)?;
+    let chunks = self.chunks.map(|size| size as u64).filter(|n| len > *n);
+    // Sync blocks precede every chunk of a root list, or every element if
+    // there are none
+    let sync = root && self.sync;
+    let walk = takes_bits || (sync && chunks.is_none());
+    let size = match chunks {
+      Some(size) => size,
+      None if !walk => return Ok(()),
+      None => len,
+    };
+    let mut start = 0;
+    while start < len {
+      let end = len.min(start.saturating_add(size));
+      if sync && chunks.is_some() {
+        self.sync_block(len, start)?;
+      }
+      let header = match chunks {
+        Some(_) => Some(self.chunk_header(len, start)?),
+        None => None,
+      };
+      let offset = self.r.position();
+      for i in (start..end).filter(|_| walk) {
+        if self.r.remaining() == 0 {
+          let detail = format!("list of {} elements ends after {}", len, i);
+          return Err(self.violation(ELEMENT_COUNT, self.r.position(), detail));
+        }
+        if sync && chunks.is_none() {
+          self.sync_block(len, i)?;
+        }
+        self.path.push(Segment::Index(i as usize));
+        let ty = self.tag(ty)?;
+        self.value(ty, false)?;
+        self.path.pop();
+      }
+      if let Some((at, bits)) = header {
+        let actual = (self.r.position() - offset) as u64;
+        if actual != bits {
+          let detail =
+            format!("chunk of {} bits holds {} bits of elements", bits, actual);
+          return Err(self.violation(CHUNK_LENGTH, at, detail));
+        }
+      }
+      start = end;
+    }
+    Ok(())
+  }
+
+  /// Reads the header of the chunk starting at element `i` of a list of
+  /// `len` elements, returning its offset and the length it holds.
+  fn chunk_header(&mut self, len: u64, i: u64) -> Result<(usize, u64)> {
+    let at = self.r.position();
+    if self.r.remaining() == 0 {
+      let detail = format!("list of {} elements ends after {}", len, i);
+      return Err(self.violation(ELEMENT_COUNT, at, detail));
+    }
+    Ok((at, self.length()?))
+  }
+
+  /// Reads the sync block before element `i` of a root list of `len`
+  /// elements.
+  fn sync_block(&mut self, len: u64, i: u64) -> Result<()> {
+    let start = self.r.position();
+    if self.r.remaining() == 0 {
+      let detail = format!("list of {} elements ends after {}", len, i);
+      return Err(self.violation(ELEMENT_COUNT, start, detail));
+    }
+    let marker = self.r.read_bit().unwrap_or_default();
+    self.padding()?;
+    if !marker {
+      return Ok(());
+    }
+    self.expect(64, "sync marker")?;
+    if self.r.read_be(64) != Some(SYNC_MAGIC) {
+      let detail = "sync marker doesn't start with the sync magic".to_string();
+      return Err(self.violation(SYNC_MARKERS, start, detail));
+    }
+    match self.length()? {
+      n if n == i => Ok(()),
+      n => {
+        let detail =
+          format!("sync marker for element {} found at element {}", n, i);
+        Err(self.violation(SYNC_MARKERS, start, detail))
+      }
+    }
+  }
+
+  /// Reads the tag of a list element if the list's element type `ty` is a
+  /// union, returning the type of the element.
+  fn tag<'t>(&mut self, ty: &'t Type) -> Result<&'t Type> {
+    let alternatives = match ty {
+      Type::Union { alternatives } => alternatives,
+      ty => return Ok(ty),
+    };
+    let width = math::required_bit_width(alternatives.len());
+    let start = self.r.position();
+    self.expect(width, "union tag")?;
+    let tag = self.r.read_rev_be(width).unwrap_or_default();
+    self.padding()?;
+    match alternatives.get(tag as usize) {
+      Some(ty) => Ok(ty),
+      None => {
+        let detail = format!("tag {} matches no type", tag);
+        Err(self.violation(VALID_TAG, start, detail))
+      }
+    }
+  }
+
+  fn value(&mut self, ty: &Type, nullable: bool) -> Result<()> {
+    match ty {
+      Type::Nested(CompositeType::Record(record)) => {
+        self.record(record, false, None)
+      }
+      Type::Nested(CompositeType::List(list)) => {
+        self.list(list, nullable, false)
+      }
+      ty => self.data(ty),
+    }
+  }
+
+  fn data(&mut self, ty: &Type) -> Result<()> {
+    let width = data_width(ty)?;
+    let len = match width {
+      EncodedWidth::Fixed(width) => width as u64,
+      EncodedWidth::Variable => self.length()?,
+      EncodedWidth::Prefix => {
+        let compressor = crate::encode::get_compressor_for_type(ty)?;
+        let mut r = self.r.clone();
+        match compressor.prefix_len(&mut core::iter::from_fn(|| r.read_bit())) {
+          Ok(len) => len as u64,
+          Err(e) => {
+            let offset = self.r.position();
+            return Err(self.violation(
+              COMPLETE_SECTIONS,
+              offset,
+              e.to_string(),
+            ));
+          }
+        }
+      }
+    };
+    if len > self.r.remaining() as u64 {
+      let detail = format!(
+        "data needs {} bits but only {} remain",
+        len,
+        self.r.remaining()
+      );
+      return Err(self.violation(COMPLETE_SECTIONS, self.r.position(), detail));
+    }
+    if !self.checksums || width != EncodedWidth::Variable {
+      self.r.skip(len as usize);
+      return self.padding();
+    }
+
+    let data = self.r.read_bits(len as usize).unwrap_or_default();
+    self.padding()?;
+    let start = self.r.position();
+    self.expect(8, "checksum")?;
+    let crc = self.r.read_be(8).unwrap_or_default() as u8;
+    if crc != crate::bit::crc8(&data) {
+      let detail = format!("checksum {:#04x} does not match the data", crc);
+      return Err(self.violation(VALID_CHECKSUM, start, detail));
+    }
+    self.padding()
+  }
+
+  fn length(&mut self) -> Result<u64> {
+    let len = match self.lengths {
+      LengthEncoding::Vie => self.vie_length()?,
+      LengthEncoding::Fixed16 => {
+        self.expect(16, "length")?;
+        self.r.read_be(16).unwrap_or_default()
+      }
+      LengthEncoding::Fixed32 => {
+        self.expect(32, "length")?;
+        self.r.read_be(32).unwrap_or_default()
+      }
+      LengthEncoding::Gamma => self.gamma_length()?,
+    };
+    self.padding()?;
+    Ok(len)
+  }
+
+  fn vie_length(&mut self) -> Result<u64> {
+    // A u64 needs at most 10 bytes
+    const MAX_BYTES: usize = 10;
+
+    let start = self.r.position();
+    let mut bytes = Vec::new();
+    loop {
+      if bytes.len() == MAX_BYTES {
+        let detail = format!("length is longer than {} bytes", MAX_BYTES);
+        return Err(self.violation(VALID_LENGTH, start, detail));
+      }
+      self.expect(8, "length")?;
+      let byte = self.r.read_byte().unwrap_or_default();
+      bytes.push(byte);
+      if byte & 0x80 == 0 {
+        break;
+      }
+    }
+
+    if bytes.len() > 1 && bytes[bytes.len() - 1] == 0 {
+      let detail = format!("length has {} bytes but needs fewer", bytes.len());
+      return Err(self.violation(MINIMAL_LENGTH, start, detail));
+    }
+    match CodePoint::parse(&bytes).and_then(|cp| cp.decode::<u64>()) {
+      Some(len) => Ok(len),
+      None => {
+        let detail = "length overflows 64 bits".to_string();
+        Err(self.violation(VALID_LENGTH, start, detail))
+      }
+    }
+  }
+
+  fn gamma_length(&mut self) -> Result<u64> {
+    let start = self.r.position();
+    let mut zeros = 0;
+    loop {
+      self.expect(1, "length")?;
+      if self.r.read_bit().unwrap_or_default() {
+        break;
+      }
+      zeros += 1;
+      if zeros == 64 {
+        let detail = "length has more than 63 leading zeros".to_string();
+        return Err(self.violation(VALID_LENGTH, start, detail));
+      }
+    }
+    self.expect(zeros, "length")?;
+    let low = self.r.read_be(zeros).unwrap_or_default();
+    Ok(((1 << zeros) | low) - 1)
+  }
+
+  /// Checks the bits after the end of the root value.
+  fn trailing_bits(&mut self) -> Result<()> {
+    let start = self.r.position();
+    if self.r.remaining() >= 8 {
+      let detail = format!("{} bits follow the last block", self.r.remaining());
+      return Err(self.violation(TRAILING_BITS, start, detail));
+    }
+    while let Some(bit) = self.r.read_bit() {
+      if bit {
+        let detail = "bits after the last block are set".to_string();
+        return Err(self.violation(TRAILING_BITS, start, detail));
+      }
+    }
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test_support::schema_and_value;
+  use proptest::prelude::*;
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut fields = BTreeMap::new();
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert(
+      "tags".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::PassThrough)))),
+    );
+    let mut record = Record::new(fields);
+    record.required.insert("name".to_string());
+    Schema::new(CompositeType::Record(record))
+  }
+
+  fn rule(schema: &Schema, bytes: &[u8]) -> Rule {
+    let e = verify_bytes(schema, bytes).unwrap_err();
+    e.downcast_ref::<Violation>().unwrap().rule
+  }
+
+  proptest! {
+    #[test]
+    fn encoded_objects_conform((schema, value) in schema_and_value()) {
+      let encodings = [
+        LengthEncoding::Vie,
+        LengthEncoding::Fixed16,
+        LengthEncoding::Fixed32,
+        LengthEncoding::Gamma,
+      ];
+      let chunked = schema.clone().with_chunks(2);
+      let synced = schema.clone().with_sync(1);
+      let chunked_synced = chunked.clone().with_sync(3);
+      for schema in &[schema, chunked, synced, chunked_synced] {
+        for lengths in &encodings {
+          let schema = schema.clone().with_lengths(*lengths);
+          for layout in &[Layout::Packed, Layout::ByteAligned] {
+            for profile in &[Profile::Standard, Profile::Dense] {
+              for checksums in &[false, true] {
+                let options = crate::EncodeOptions {
+                  layout: *layout,
+                  profile: *profile,
+                  checksums: *checksums,
+                  ..Default::default()
+                };
+                let bytes = crate::encode_with(&schema, &value, &options)
+                  .unwrap()
+                  .to_bytes();
+                verify_bytes_with(&schema, &bytes, *layout, *profile, *checksums)
+                  .unwrap();
+              }
+            }
+          }
+        }
+      }
+    }
+  }
+
+  #[test]
+  fn broken_rules() {
+    let schema = schema();
+    // A marker one past the last field
+    assert_eq!(VALID_MARKER, rule(&schema, &[0b1100_0000]));
+    assert_eq!(REQUIRED_FIELDS, rule(&schema, &[]));
+    // name = "" twice
+    assert_eq!(UNIQUE_FIELDS, rule(&schema, &[0b1000_0000, 0b0010_0000]));
+    // A list length which continues past the end of the input
+    assert_eq!(
+      COMPLETE_SECTIONS,
+      rule(&schema, &[0b1000_0000, 0b0001_1000, 0])
+    );
+    // name = "" followed by a checksum which isn't the CRC of no bits
+    let (layout, profile) = (Layout::Packed, Profile::Standard);
+    let bytes = [0b1000_0000, 0, 0b0100_0000];
+    let e = verify_bytes_with(&schema, &bytes, layout, profile, true);
+    let e = e.unwrap_err();
+    assert_eq!(VALID_CHECKSUM, e.downcast_ref::<Violation>().unwrap().rule);
+
+    let bytes = crate::encode(&schema, &json!({ "name": "Jeremy" }))
+      .unwrap()
+      .to_bytes();
+    let mut longer = bytes.clone();
+    longer.push(0);
+    assert_eq!(TRAILING_BITS, rule(&schema, &longer));
+
+    let e = verify_bytes(&schema, &[0b1100_0000]).unwrap_err();
+    assert_eq!(
+      "valid-marker: marker 3 matches no field at bit 0",
+      e.to_string()
+    );
+  }
+}
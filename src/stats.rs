@@ -0,0 +1,221 @@
+//! The `stats` module compares the probabilities which a schema declares for
+//! the variants of its enums with how often those variants occur in actual
+//! data.
+//!
+//! Enums with declared probabilities are encoded with a code matched to them,
+//! so the further the data strays from them the more bits are wasted. This is
+//! measured by the [divergence](EnumStats::divergence) of the observed
+//! frequencies from the declared probabilities. The frequencies of enums
+//! without declared probabilities are reported as well, which makes them a
+//! good starting point for declaring some.
+
+use crate::path::{Path, Segment};
+use crate::schema::{CompositeType, Schema, Type};
+use serde_json::Value;
+
+/// How often a single variant of an enum occurs.
+#[derive(Clone, Debug, PartialEq)]
+pub struct VariantStats {
+  /// The variant itself.
+  pub variant: String,
+  /// The probability the schema declares for the variant, normalized so that
+  /// the probabilities of an enum add up to one.
+  pub declared: Option<f64>,
+  /// The number of values which are this variant.
+  pub observed: usize,
+}
+
+/// How often the variants of an enum occur.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EnumStats {
+  /// The path of the enum within the schema, where list indices are always
+  /// zero and stand for every element.
+  pub path: Path,
+  /// The variants of the enum in schema order.
+  pub variants: Vec<VariantStats>,
+}
+
+impl EnumStats {
+  fn new(path: Path, ty: &Type) -> Option<Self> {
+    let (variants, probabilities) = match ty {
+      Type::Enum {
+        variants,
+        probabilities,
+      } => (variants, probabilities.as_ref()),
+      _ => return None,
+    };
+    let total: f64 = probabilities.map_or(0.0, |p| p.values().sum());
+    let variants = variants
+      .iter()
+      .map(|v| VariantStats {
+        variant: v.clone(),
+        declared: probabilities.map(|p| p.get(v).map_or(0.0, |p| p / total)),
+        observed: 0,
+      })
+      .collect();
+    Some(EnumStats { path, variants })
+  }
+
+  /// The total number of values of the enum.
+  pub fn total(&self) -> usize {
+    self.variants.iter().map(|v| v.observed).sum()
+  }
+
+  /// The fraction of values which are the variant at `index`.
+  pub fn frequency(&self, index: usize) -> f64 {
+    match self.total() {
+      0 => 0.0,
+      total => self.variants[index].observed as f64 / total as f64,
+    }
+  }
+
+  /// The Kullback-Leibler divergence of the observed frequencies from the
+  /// declared probabilities in bits, or `None` if the schema doesn't declare
+  /// any.
+  ///
+  /// This is the number of bits per value which an ideal code for the
+  /// declared probabilities wastes compared with one for the observed
+  /// frequencies. It is zero when they match and infinite when a variant
+  /// which is declared impossible occurs.
+  pub fn divergence(&self) -> Option<f64> {
+    let mut divergence = 0.0;
+    for (i, v) in self.variants.iter().enumerate() {
+      let declared = v.declared?;
+      let observed = self.frequency(i);
+      if observed > 0.0 {
+        divergence += observed * (observed / declared).log2();
+      }
+    }
+    Some(divergence)
+  }
+}
+
+/// Counts the variants of every enum in `value`, which must match `schema`.
+///
+/// Enums are returned in schema order, one for each enum in the schema
+/// whether or not it occurs in `value`. Values which aren't variants of their
+/// enum are ignored.
+pub fn enum_stats(schema: &Schema, value: &Value) -> Vec<EnumStats> {
+  let mut stats = Vec::new();
+  collect(schema.root(), &mut Vec::new(), &mut stats);
+  tally(schema.root(), value, 0, &mut stats);
+  stats
+}
+
+/// The number of enums in `ty`.
+fn count(ty: &Type) -> usize {
+  match ty {
+    Type::Enum { .. } => 1,
+    Type::Nested(CompositeType::Record(record)) => {
+      record.fields.values().map(count).sum()
+    }
+    Type::Nested(CompositeType::List(list)) => count(&list.0),
+    _ => 0,
+  }
+}
+
+/// Adds empty stats for every enum in `ty` to `out`.
+fn collect(ty: &Type, path: &mut Vec<Segment>, out: &mut Vec<EnumStats>) {
+  match ty {
+    Type::Nested(CompositeType::Record(record)) => {
+      for (name, ty) in &record.fields {
+        path.push(Segment::Field(name.clone()));
+        collect(ty, path, out);
+        path.pop();
+      }
+    }
+    Type::Nested(CompositeType::List(list)) => {
+      path.push(Segment::Index(0));
+      collect(&list.0, path, out);
+      path.pop();
+    }
+    ty => out.extend(EnumStats::new(Path(path.clone()), ty)),
+  }
+}
+
+/// Counts the variants in `value`, whose first enum is the one with index
+/// `id`.
+fn tally(ty: &Type, value: &Value, id: usize, stats: &mut [EnumStats]) {
+  match (ty, value) {
+    (Type::Enum { .. }, Value::String(s)) => {
+      let variant = stats[id].variants.iter_mut().find(|v| v.variant == *s);
+      if let Some(variant) = variant {
+        variant.observed += 1;
+      }
+    }
+    (Type::Nested(CompositeType::Record(record)), Value::Object(map)) => {
+      let mut id = id;
+      for (name, ty) in &record.fields {
+        if let Some(value) = map.get(name) {
+          tally(ty, value, id, stats);
+        }
+        id += count(ty);
+      }
+    }
+    (Type::Nested(CompositeType::List(list)), Value::Array(arr)) => {
+      for value in arr {
+        tally(&list.0, value, id, stats);
+      }
+    }
+    _ => {}
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{List, Record};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut probabilities = BTreeMap::new();
+    probabilities.insert("error".to_string(), 1.0);
+    probabilities.insert("info".to_string(), 3.0);
+    let mut fields = BTreeMap::new();
+    fields.insert(
+      "level".to_string(),
+      Type::Enum {
+        variants: probabilities.keys().cloned().collect(),
+        probabilities: Some(probabilities),
+      },
+    );
+    fields.insert(
+      "method".to_string(),
+      Type::Enum {
+        variants: ["GET", "POST"].iter().map(|v| v.to_string()).collect(),
+        probabilities: None,
+      },
+    );
+    let list = List(Box::new(Type::Nested(CompositeType::Record(
+      Record::new(fields),
+    ))));
+    Schema::new(CompositeType::List(list))
+  }
+
+  #[test]
+  fn declared_and_observed() {
+    let value = json!([
+      { "level": "info", "method": "GET" },
+      { "level": "error", "method": "GET" },
+      { "level": "info" },
+      { "level": "error", "method": "PUT" }
+    ]);
+    let stats = enum_stats(&schema(), &value);
+    assert_eq!(2, stats.len());
+
+    let level = &stats[0];
+    assert_eq!("[0].level", level.path.to_string());
+    assert_eq!(Some(0.25), level.variants[0].declared);
+    assert_eq!(4, level.total());
+    assert_eq!(0.5, level.frequency(1));
+    // 0.5 * log2(0.5 / 0.25) + 0.5 * log2(0.5 / 0.75)
+    let divergence = level.divergence().unwrap();
+    assert!((divergence - 0.2075).abs() < 1e-4);
+
+    let method = &stats[1];
+    assert_eq!(None, method.divergence());
+    assert_eq!(2, method.total());
+    assert_eq!(1.0, method.frequency(0));
+  }
+}
@@ -0,0 +1,375 @@
+//! The `test_support` module provides [proptest] strategies for schemas and
+//! for values which match them, for property testing code built on chii.
+//!
+//! [`Schema`] and [`Type`] implement [`Arbitrary`], and [`value`] generates
+//! values of a given type. Together they make it easy to check that
+//! `decode(encode(x)) == x` holds across random schemas:
+//!
+//! ```ignore
+//! proptest! {
+//!   #[test]
+//!   fn roundtrip((schema, value) in chii::test_support::schema_and_value()) {
+//!     let bytes = chii::encode(&schema, &value)?.to_bytes();
+//!     prop_assert_eq!(value, chii::decode(&schema, &bytes)?);
+//!   }
+//! }
+//! ```
+//!
+//! The [`golden`] submodule checks encoded output against checked-in files,
+//! so that changes to the binary format can't go unnoticed, and the [`ratio`]
+//! submodule checks that samples still compress as well as they used to.
+//!
+//! [proptest]: https://docs.rs/proptest
+
+pub mod golden;
+pub mod ratio;
+
+use crate::comp::{
+  self, Codebook, GeoCompressor, GeoForm, QuantizedCompressor, Transform,
+};
+use crate::encode::union_alternative;
+use crate::prelude::*;
+use crate::schema::{CompositeType, List, Record, Schema, Type};
+use core::convert::TryFrom;
+use proptest::collection;
+use proptest::prelude::*;
+use proptest::sample;
+use serde_json::{Map, Value};
+
+/// How deeply generated records and lists are nested.
+const MAX_DEPTH: u32 = 4;
+
+/// The names of generated record fields and enum variants.
+const NAME: &str = "[a-z_]{1,8}";
+
+impl Arbitrary for Type {
+  type Parameters = ();
+  type Strategy = BoxedStrategy<Type>;
+
+  fn arbitrary_with(_: ()) -> Self::Strategy {
+    let leaf = prop_oneof![
+      Just(Type::PassThrough),
+      Just(Type::Name("any".to_string())),
+      Just(Type::Name("bool".to_string())),
+      Just(Type::Name("f16".to_string())),
+      Just(Type::Name("color".to_string())),
+      Just(Type::Name("lzw".to_string())),
+      Just(Type::Name("smaz".to_string())),
+      Just(Type::Name("timestamp".to_string())),
+      collection::btree_set(NAME, 1..6)
+        .prop_flat_map(|variants| {
+          let n = variants.len();
+          (
+            Just(variants),
+            proptest::option::of(collection::vec(1..100u32, n)),
+          )
+        })
+        .prop_map(|(variants, weights)| {
+          let probabilities = weights.map(|weights| {
+            let weights = weights.into_iter().map(f64::from);
+            variants.iter().cloned().zip(weights).collect()
+          });
+          Type::Enum {
+            variants,
+            probabilities,
+          }
+        }),
+      collection::btree_set(any::<i64>(), 1..6)
+        .prop_map(|codes| Type::IntEnum { codes }),
+      (1..8usize).prop_map(|size| Type::Dictionary { size }),
+      collection::vec(NAME, 0..4).prop_map(|samples| Type::Huffman {
+        huffman: Codebook::train(&samples)
+      }),
+      (0..4u32).prop_map(|p| Type::Name(format!("float(precision={})", p))),
+      (0..4u32, any::<bool>()).prop_map(|(p, record)| {
+        let form = if record { ", form=record" } else { "" };
+        Type::Name(format!("geo(precision={}{})", p, form))
+      }),
+      sample::select(vec![1.0, 100.0, 1000.0, 0.01])
+        .prop_map(|scale| Type::Scaled { scale, round: None }),
+    ];
+    leaf
+      .prop_recursive(MAX_DEPTH, 32, 4, |inner| {
+        prop_oneof![
+          inner.clone().prop_map(|ty| {
+            Type::Nested(CompositeType::List(List(Box::new(ty))))
+          }),
+          collection::vec(inner.clone(), 1..4).prop_map(|alternatives| {
+            let union = Type::Union { alternatives };
+            Type::Nested(CompositeType::List(List(Box::new(union))))
+          }),
+          record(inner)
+            .prop_map(|record| Type::Nested(CompositeType::Record(record))),
+        ]
+      })
+      .boxed()
+  }
+}
+
+impl Arbitrary for Schema {
+  type Parameters = ();
+  type Strategy = BoxedStrategy<Schema>;
+
+  fn arbitrary_with(_: ()) -> Self::Strategy {
+    any::<Type>()
+      .prop_map(|root| match root {
+        Type::Nested(ct) => Schema::new(ct),
+        ty => Schema::new_scalar(ty),
+      })
+      .boxed()
+  }
+}
+
+/// Generates records with fields of types from `ty`, some of which are
+/// required, some lists of which are nullable and some required records of
+/// which are inline. Some records are sparse and some give their fields
+/// identifiers in reverse order.
+fn record(ty: BoxedStrategy<Type>) -> impl Strategy<Value = Record> {
+  let field = (ty, any::<bool>(), any::<bool>());
+  let fields = collection::btree_map(NAME, field, 0..6);
+  (fields, any::<bool>(), any::<bool>()).prop_map(|(fields, sparse, rev)| {
+    let required = fields
+      .iter()
+      .filter(|(_, (_, required, _))| *required)
+      .map(|(name, _)| name.clone())
+      .collect();
+    let nullable = fields
+      .iter()
+      .filter(|(_, (ty, _, nullable))| {
+        *nullable && matches!(ty, Type::Nested(CompositeType::List(_)))
+      })
+      .map(|(name, _)| name.clone())
+      .collect();
+    // Lists are nullable with the same flag that makes records inline
+    let inline = fields
+      .iter()
+      .filter(|(_, (ty, required, inline))| {
+        *required
+          && *inline
+          && matches!(ty, Type::Nested(CompositeType::Record(_)))
+      })
+      .map(|(name, _)| name.clone())
+      .collect();
+    let order = match rev {
+      true => fields.keys().rev().cloned().collect(),
+      false => Vec::new(),
+    };
+    let fields = fields
+      .into_iter()
+      .map(|(name, (ty, _, _))| (name, ty))
+      .collect();
+    Record {
+      fields,
+      required,
+      nullable,
+      sparse,
+      inline,
+      order,
+      ..Record::default()
+    }
+  })
+}
+
+/// Generates JSON values of any type, which can be encoded as `any`.
+fn any_json() -> BoxedStrategy<Value> {
+  let leaf = prop_oneof![
+    Just(Value::Null),
+    any::<bool>().prop_map(Value::Bool),
+    any::<i64>().prop_map(Value::from),
+    any::<u64>().prop_map(Value::from),
+    // Every finite float survives being encoded, these are just the easiest
+    // ones to generate
+    any::<i32>().prop_map(|i| Value::from(i as f64 / 16.0)),
+    any::<String>().prop_map(Value::String),
+  ];
+  leaf
+    .prop_recursive(3, 16, 4, |inner| {
+      prop_oneof![
+        collection::vec(inner.clone(), 0..4).prop_map(Value::Array),
+        collection::btree_map(NAME, inner, 0..4)
+          .prop_map(|map| Value::Object(map.into_iter().collect())),
+      ]
+    })
+    .boxed()
+}
+
+/// Generates values which can be encoded as type `ty`.
+///
+/// Optional record fields are left out of some values, and lists have up to
+/// four elements.
+///
+/// # Panics
+///
+/// Panics if `ty` is, or contains, an enum or union without any variants or
+/// a named type other than `any`, `bool`, `bwt`, `color`, `f16`,
+/// `float(precision=N)`, `geo(precision=N)`, `lzw`, `smaz` or `timestamp`, as
+/// there are no values of such types.
+pub fn value(ty: &Type) -> BoxedStrategy<Value> {
+  match ty {
+    Type::PassThrough | Type::Dictionary { .. } => {
+      any::<String>().prop_map(Value::String).boxed()
+    }
+    Type::Name(name) if ["bwt", "lzw", "smaz"].contains(&name.as_str()) => {
+      any::<String>().prop_map(Value::String).boxed()
+    }
+    Type::Name(name) if name == "any" => any_json(),
+    Type::Name(name) if name == "bool" => {
+      any::<bool>().prop_map(Value::Bool).boxed()
+    }
+    Type::Name(name) if name == "color" => {
+      // Colors are decoded in lowercase, and short ones stay short
+      let long = (0..=0xff_ffffu32).prop_map(|rgb| format!("#{:06x}", rgb));
+      let short = (0..=0xfffu32).prop_map(|rgb| format!("#{:03x}", rgb));
+      prop_oneof![long, short].prop_map(Value::String).boxed()
+    }
+    Type::Name(name) if name == "timestamp" => {
+      any::<i64>().prop_map(Value::from).boxed()
+    }
+    Type::Name(name) if name == "f16" => {
+      // Eighths smaller than 256 need at most 11 significant bits, so they
+      // survive being stored as f16s
+      (-2048i32..=2048)
+        .prop_map(|i| Value::from(i as f64 / 8.0))
+        .boxed()
+    }
+    Type::Name(name) if crate::encode::is_float(name) => {
+      // Values which are already rounded survive being decoded
+      let precision = QuantizedCompressor::parse_precision(name).unwrap();
+      let scale = (0..precision).fold(1.0, |scale, _| scale * 10.0);
+      any::<i32>()
+        .prop_map(move |i| Value::from(i as f64 / scale))
+        .boxed()
+    }
+    Type::Name(name) if GeoCompressor::parse(name).is_some() => {
+      // Values which are already rounded survive being decoded
+      let (precision, form) = GeoCompressor::parse(name).unwrap();
+      let scale = (0..precision).fold(1i64, |scale, _| scale * 10);
+      let coordinate = |max: i64| {
+        (-max * scale..=max * scale).prop_map(move |i| i as f64 / scale as f64)
+      };
+      (coordinate(90), coordinate(180))
+        .prop_map(move |(lat, lon)| match form {
+          GeoForm::Pair => Value::Array(vec![lat.into(), lon.into()]),
+          GeoForm::Record => {
+            let mut record = serde_json::Map::new();
+            record.insert("lat".to_string(), lat.into());
+            record.insert("lon".to_string(), lon.into());
+            Value::Object(record)
+          }
+        })
+        .boxed()
+    }
+    Type::Name(name) => panic!("no values of type '{}'", name),
+    Type::Enum { variants, .. } => {
+      let variants: Vec<String> = variants.iter().cloned().collect();
+      sample::select(variants).prop_map(Value::String).boxed()
+    }
+    Type::IntEnum { codes } => {
+      let codes: Vec<i64> = codes.iter().cloned().collect();
+      sample::select(codes).prop_map(Value::from).boxed()
+    }
+    Type::Huffman { huffman } => {
+      // Only strings made up of bytes which have codes can be compressed
+      let coded: Vec<char> = (0..128u8)
+        .filter(|b| huffman.code(*b).is_some())
+        .map(char::from)
+        .collect();
+      if coded.is_empty() {
+        return Just(Value::String(String::new())).boxed();
+      }
+      collection::vec(sample::select(coded), 0..8)
+        .prop_map(|chars| Value::String(chars.into_iter().collect()))
+        .boxed()
+    }
+    Type::Scaled { scale, .. } => {
+      // Values which are already whole once scaled survive being decoded
+      let scale = *scale;
+      any::<i32>()
+        .prop_map(move |i| Value::from(i as f64 / scale))
+        .boxed()
+    }
+    Type::Transformed { transform, of } => {
+      // Decoded values are in their transformed form, so only generate
+      // values which are already transformed
+      let transform = transform.clone();
+      value(of)
+        .prop_map(move |v| {
+          let mut v = comp::Value::try_from(&v).expect("a primitive value");
+          for t in &transform {
+            v = t.apply(v).expect("a transformable value");
+          }
+          v.into()
+        })
+        .boxed()
+    }
+    Type::Union { alternatives } => {
+      // Values are encoded as the first type which accepts them, so only
+      // values which are encoded as the type they came from survive
+      let choices = alternatives
+        .iter()
+        .enumerate()
+        .map(|(i, ty)| value(ty).prop_map(move |v| (i, v)));
+      let alternatives = alternatives.clone();
+      prop::strategy::Union::new(choices)
+        .prop_filter("value is encoded as another type", move |(i, v)| {
+          matches!(union_alternative(&alternatives, v), Ok((j, _)) if j == *i)
+        })
+        .prop_map(|(_, v)| v)
+        .boxed()
+    }
+    Type::Nested(CompositeType::List(list)) => {
+      collection::vec(value(&list.0), 0..5)
+        .prop_map(Value::Array)
+        .boxed()
+    }
+    Type::Nested(CompositeType::Record(record)) => {
+      let fields: Vec<BoxedStrategy<Option<(String, Value)>>> = record
+        .fields
+        .iter()
+        .map(|(name, ty)| {
+          let required = record.is_required(name);
+          let name = name.clone();
+          let value = if record.is_nullable(name.as_str()) {
+            proptest::option::of(value(ty))
+              .prop_map(|v| v.unwrap_or(Value::Null))
+              .boxed()
+          } else {
+            value(ty)
+          };
+          let field = value.prop_map(move |v| (name.clone(), v));
+          if required {
+            field.prop_map(Some).boxed()
+          } else {
+            proptest::option::of(field).boxed()
+          }
+        })
+        .collect();
+      fields
+        .prop_map(|fields| {
+          Value::Object(fields.into_iter().flatten().collect::<Map<_, _>>())
+        })
+        .boxed()
+    }
+  }
+}
+
+/// Generates a random schema along with a value which matches it.
+pub fn schema_and_value() -> impl Strategy<Value = (Schema, Value)> {
+  any::<Schema>().prop_flat_map(|schema| {
+    let value = value(schema.root());
+    (Just(schema), value)
+  })
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  proptest! {
+    #[test]
+    fn decode_inverts_encode((schema, value) in schema_and_value()) {
+      let bytes = crate::encode(&schema, &value).unwrap().to_bytes();
+      prop_assert_eq!(value, crate::decode(&schema, &bytes).unwrap());
+    }
+  }
+}
@@ -0,0 +1,147 @@
+//! Golden file tests, which pin down the binary format.
+//!
+//! A golden directory holds one subdirectory per case:
+//!
+//! ```text
+//! tests/golden/
+//!   student/
+//!     schema.yaml   the schema, or schema.json
+//!     input.json    the value to encode
+//!     expected.co   the bytes the value must encode to
+//! ```
+//!
+//! Checking a case encodes its input and compares the result byte for byte
+//! with its expected output, then decodes the expected output and compares it
+//! with the input. Any difference means the format has changed and data
+//! written by an earlier version may no longer decode.
+//!
+//! Running the checks with the [`BLESS_VAR`] environment variable set writes
+//! the actual output of each case to its `expected.co` instead, which is how
+//! new cases are added and intentional format changes are recorded.
+
+use crate::schema::Schema;
+use anyhow::{bail, Context, Result};
+use serde_json::Value;
+use std::env;
+use std::fs;
+use std::path::Path;
+
+/// The environment variable which, when set, makes the checks record the
+/// actual outputs instead of comparing against them.
+pub const BLESS_VAR: &str = "CHII_BLESS";
+
+/// Checks every case in the golden directory `dir`, failing with a list of
+/// all the cases which didn't match.
+pub fn check_dir<P: AsRef<Path>>(dir: P) -> Result<()> {
+  let dir = dir.as_ref();
+  let mut cases = Vec::new();
+  let entries = fs::read_dir(dir)
+    .with_context(|| format!("unable to read {}", dir.display()))?;
+  for entry in entries {
+    let path = entry?.path();
+    if path.is_dir() {
+      cases.push(path);
+    }
+  }
+  cases.sort();
+  if cases.is_empty() {
+    bail!("no golden cases found in {}", dir.display());
+  }
+
+  let failures: Vec<String> = cases
+    .iter()
+    .filter_map(|case| {
+      let e = check_case(case).err()?;
+      Some(format!("{}: {:#}", case.display(), e))
+    })
+    .collect();
+  if !failures.is_empty() {
+    bail!(
+      "{} of {} golden cases failed:\n{}",
+      failures.len(),
+      cases.len(),
+      failures.join("\n")
+    );
+  }
+  Ok(())
+}
+
+/// Checks the single golden case in the directory `dir`.
+pub fn check_case<P: AsRef<Path>>(dir: P) -> Result<()> {
+  let dir = dir.as_ref();
+  let schema = load_schema(dir)?;
+  let input_path = dir.join("input.json");
+  let text = fs::read_to_string(&input_path)
+    .with_context(|| format!("unable to read {}", input_path.display()))?;
+  let input: Value = serde_json::from_str(&text)
+    .with_context(|| format!("unable to load {}", input_path.display()))?;
+  let actual = crate::encode(&schema, &input)?.to_bytes();
+
+  let expected_path = dir.join("expected.co");
+  if env::var_os(BLESS_VAR).is_some() {
+    fs::write(&expected_path, &actual)?;
+    return Ok(());
+  }
+  let expected = fs::read(&expected_path).with_context(|| {
+    format!(
+      "unable to read {}, set {} to create it",
+      expected_path.display(),
+      BLESS_VAR
+    )
+  })?;
+
+  if actual != expected {
+    let offset = actual
+      .iter()
+      .zip(&expected)
+      .position(|(a, b)| a != b)
+      .unwrap_or_else(|| actual.len().min(expected.len()));
+    bail!(
+      "encoded {} bytes but expected {}, first difference at byte {}",
+      actual.len(),
+      expected.len(),
+      offset
+    );
+  }
+
+  let decoded = crate::decode(&schema, &expected)
+    .with_context(|| format!("unable to decode {}", expected_path.display()))?;
+  if decoded != input {
+    bail!(
+      "expected output decodes to {} instead of the input",
+      decoded
+    );
+  }
+  Ok(())
+}
+
+/// Loads `schema.yaml`, or `schema.json` if there is no YAML schema, from
+/// the case directory `dir`.
+fn load_schema(dir: &Path) -> Result<Schema> {
+  let yaml = dir.join("schema.yaml");
+  let json = dir.join("schema.json");
+  let schema = if yaml.exists() {
+    let text = fs::read_to_string(&yaml)?;
+    serde_yaml::from_str(&text)
+      .with_context(|| format!("unable to load {}", yaml.display()))?
+  } else {
+    let text = fs::read_to_string(&json)
+      .with_context(|| format!("no schema found in {}", dir.display()))?;
+    serde_json::from_str(&text)
+      .with_context(|| format!("unable to load {}", json.display()))?
+  };
+  Ok(schema)
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn golden_files() {
+    let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/golden");
+    if let Err(e) = check_dir(dir) {
+      panic!("{:#}", e);
+    }
+  }
+}
@@ -0,0 +1,98 @@
+//! Compression ratio checks, which catch changes that make encoded output
+//! larger.
+//!
+//! The ratio of a sample is the size of its encoded bytes as a fraction of
+//! its size as compact JSON, so smaller is better. [`assert_ratio!`] fails a
+//! test if the ratio of a sample grows past a threshold:
+//!
+//! ```ignore
+//! #[test]
+//! fn students_stay_small() {
+//!   chii::assert_ratio!(schema, sample, <= 0.35);
+//! }
+//! ```
+//!
+//! [`assert_ratio!`]: crate::assert_ratio
+
+use crate::schema::Schema;
+use anyhow::{bail, Result};
+use serde_json::Value;
+
+/// Returns the size of `sample` once encoded using `schema` as a fraction of
+/// its size as compact JSON.
+pub fn ratio(schema: &Schema, sample: &Value) -> Result<f64> {
+  let encoded = crate::encode(schema, sample)?.to_bytes().len();
+  let json = serde_json::to_vec(sample)?.len();
+  Ok(encoded as f64 / json as f64)
+}
+
+/// Fails if the [`ratio`] of `sample` is greater than `max`.
+pub fn check_ratio(schema: &Schema, sample: &Value, max: f64) -> Result<()> {
+  let ratio = ratio(schema, sample)?;
+  if ratio > max {
+    bail!("compression ratio {:.3} is greater than {}", ratio, max);
+  }
+  Ok(())
+}
+
+/// Asserts that a sample compresses to at most a given fraction of its size
+/// as compact JSON, see [`check_ratio`].
+///
+/// ```ignore
+/// chii::assert_ratio!(schema, sample, <= 0.35);
+/// ```
+///
+/// # Panics
+///
+/// Panics if the ratio is greater than the threshold, or if the sample can't
+/// be encoded.
+#[macro_export]
+macro_rules! assert_ratio {
+  ($schema:expr, $sample:expr, <= $max:expr $(,)?) => {
+    if let Err(e) =
+      $crate::test_support::ratio::check_ratio(&$schema, &$sample, $max)
+    {
+      panic!("{:#}", e);
+    }
+  };
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{CompositeType, List, Record, Type};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn students() -> (Schema, Value) {
+    let mut student = BTreeMap::new();
+    student.insert("name".to_string(), Type::PassThrough);
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    let list = List(Box::new(Type::Nested(CompositeType::Record(
+      Record::new(student),
+    ))));
+    let sample = json!([
+      { "name": "Jeremy", "active": true },
+      { "name": "Ada", "active": false }
+    ]);
+    (Schema::new(CompositeType::List(list)), sample)
+  }
+
+  #[test]
+  fn ratio_of_sample() {
+    let (schema, sample) = students();
+    // 14 bytes encoded from 63 bytes of JSON
+    assert_eq!(14.0 / 63.0, ratio(&schema, &sample).unwrap());
+    crate::assert_ratio!(schema, sample, <= 0.35);
+
+    let e = check_ratio(&schema, &sample, 0.1).unwrap_err();
+    assert_eq!("compression ratio 0.222 is greater than 0.1", e.to_string());
+  }
+
+  #[test]
+  #[should_panic(expected = "is greater than 0.1")]
+  fn assert_ratio_panics_past_threshold() {
+    let (schema, sample) = students();
+    crate::assert_ratio!(&schema, &sample, <= 0.1);
+  }
+}
@@ -0,0 +1,237 @@
+//! Renders a compressed object as an indented tree with the names of its
+//! fields resolved from the schema.
+
+use crate::bit::BitVec;
+use crate::comp::EncodedWidth;
+use crate::data::{Block, CompressedObject, Layout, Visitor};
+use crate::encode::get_compressor_for_type;
+use crate::prelude::*;
+use crate::schema::{CompositeType, Schema, Type};
+use core::fmt::{self, Write};
+use serde_json::Value;
+
+impl CompressedObject {
+  /// Displays this object as an indented tree of its records, lists and
+  /// values, using `schema` to name their fields.
+  ///
+  /// Each line gives the name and type of a value and the number of bits its
+  /// blocks take up, not counting anything nested within it. Values with a
+  /// fixed width are decoded as well:
+  ///
+  /// ```text
+  /// record
+  ///   active: bool (3 bits) = true
+  ///   courses: list of 1 (10 bits)
+  ///     [0]: record
+  ///       name: raw (33 bits)
+  ///       end (1 bits)
+  /// ```
+  ///
+  /// Unlike [`Display`](fmt::Display) for [`Block`], which only knows the
+  /// identifiers of fields, this shows what the blocks mean. If the blocks
+  /// don't match `schema` the tree stops where they stop matching, followed
+  /// by the error.
+  pub fn display<'a>(&'a self, schema: &'a Schema) -> impl fmt::Display + 'a {
+    Tree {
+      object: self,
+      schema,
+    }
+  }
+}
+
+struct Tree<'a> {
+  object: &'a CompressedObject,
+  schema: &'a Schema,
+}
+
+impl fmt::Display for Tree<'_> {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let mut printer = Printer {
+      layout: self.object.layout,
+      out: String::new(),
+      stack: Vec::new(),
+      pending: 0,
+    };
+    let result = self.object.accept(self.schema, &mut printer);
+    f.write_str(&printer.out)?;
+    match result {
+      Ok(()) => Ok(()),
+      Err(e) => writeln!(f, "error: {:#}", e),
+    }
+  }
+}
+
+/// A visitor which writes a line for each value.
+struct Printer {
+  layout: Layout,
+  out: String,
+  /// The number of elements visited in each enclosing record or list, or
+  /// `None` for records.
+  stack: Vec<Option<usize>>,
+  /// The bits of the blocks which have been walked since the last line.
+  pending: usize,
+}
+
+impl Printer {
+  /// Writes a line for a value in `field` of the innermost record, or the
+  /// next element of the innermost list.
+  fn line(&mut self, field: Option<&str>, what: &str) {
+    let indent = self.stack.len() * 2;
+    let name = match (field, self.stack.last_mut()) {
+      (Some(field), _) => Some(field.to_string()),
+      (None, Some(Some(elements))) => {
+        *elements += 1;
+        Some(format!("[{}]", *elements - 1))
+      }
+      _ => None,
+    };
+    let bits = core::mem::take(&mut self.pending);
+    let _ = write!(self.out, "{:indent$}", "", indent = indent);
+    if let Some(name) = name {
+      let _ = write!(self.out, "{}: ", name);
+    }
+    let _ = write!(self.out, "{}", what);
+    if bits > 0 {
+      let _ = write!(self.out, " ({} bits)", bits);
+    }
+  }
+
+  /// Writes a line for the end of the innermost record or list, if it has a
+  /// terminator.
+  fn end(&mut self) {
+    self.stack.pop();
+    if self.pending > 0 {
+      let indent = (self.stack.len() + 1) * 2;
+      let _ = writeln!(
+        self.out,
+        "{:indent$}end ({} bits)",
+        "",
+        core::mem::take(&mut self.pending),
+        indent = indent
+      );
+    }
+  }
+}
+
+impl Visitor for Printer {
+  fn visit_record(&mut self, field: Option<&str>) {
+    self.line(field, "record");
+    self.out.push('\n');
+    self.stack.push(None);
+  }
+
+  fn visit_record_end(&mut self) {
+    self.end();
+  }
+
+  fn visit_list(&mut self, field: Option<&str>, len: usize) {
+    self.line(field, &format!("list of {}", len));
+    self.out.push('\n');
+    self.stack.push(Some(0));
+  }
+
+  fn visit_list_end(&mut self) {
+    self.end();
+  }
+
+  fn visit_null(&mut self, field: Option<&str>) {
+    self.line(field, "null");
+    self.out.push('\n');
+  }
+
+  fn visit_data(&mut self, field: Option<&str>, ty: &Type, data: &BitVec) {
+    self.line(field, &type_name(ty));
+    let value = get_compressor_for_type(ty).ok().and_then(|compressor| {
+      if compressor.encoded_width() == EncodedWidth::Variable {
+        return None;
+      }
+      compressor.decompress(data.clone()).ok().map(Value::from)
+    });
+    if let Some(value) = value {
+      let _ = write!(self.out, " = {}", value);
+    }
+    self.out.push('\n');
+  }
+
+  fn visit_block(&mut self, block: &Block) {
+    let bits = block.bit_len_with(self.layout);
+    let what = match block {
+      Block::ChunkHeader(_) => "chunk",
+      Block::Sync(_) => "sync",
+      _ => {
+        self.pending += bits;
+        return;
+      }
+    };
+    // Chunk headers and sync blocks are lines of their own within the list
+    let indent = self.stack.len() * 2;
+    let _ = writeln!(
+      self.out,
+      "{:indent$}{} ({} bits)",
+      "",
+      what,
+      bits,
+      indent = indent
+    );
+  }
+}
+
+/// A short name for a non-nested type.
+pub(crate) fn type_name(ty: &Type) -> String {
+  match ty {
+    Type::PassThrough => "raw".to_string(),
+    Type::Name(name) => name.clone(),
+    Type::Nested(CompositeType::Record(_)) => "record".to_string(),
+    Type::Nested(CompositeType::List(_)) => "list".to_string(),
+    Type::Enum { .. } => "enum".to_string(),
+    Type::IntEnum { .. } => "enum-int".to_string(),
+    Type::Dictionary { .. } => "dictionary".to_string(),
+    Type::Huffman { .. } => "huffman".to_string(),
+    Type::Scaled { .. } => "scaled".to_string(),
+    Type::Transformed { of, .. } => format!("transformed {}", type_name(of)),
+    Type::Union { .. } => "union".to_string(),
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::schema::{List, Record};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  #[test]
+  fn display_tree_with_field_names() {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+    let mut student = BTreeMap::new();
+    student.insert("active".to_string(), Type::Name("bool".to_string()));
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    let schema = Schema::new(CompositeType::Record(Record::new(student)));
+
+    let value = json!({ "active": true, "courses": [{ "name": "Art" }] });
+    let co = crate::encode(&schema, &value).unwrap();
+    assert_eq!(
+      concat!(
+        "record\n",
+        "  active: bool (3 bits) = true\n",
+        "  courses: list of 1 (10 bits)\n",
+        "    [0]: record\n",
+        "      name: raw (33 bits)\n",
+        "      end (1 bits)\n",
+      ),
+      co.display(&schema).to_string()
+    );
+
+    let mut broken = co.clone();
+    broken.blocks.truncate(3);
+    let tree = broken.display(&schema).to_string();
+    assert!(tree
+      .ends_with("    [0]: record\nerror: record is missing a terminator\n"));
+  }
+}
@@ -0,0 +1,246 @@
+//! The `unknown` module implements an optional footer which preserves record
+//! fields that are not defined by the schema.
+//!
+//! When encoding with [`UnknownFieldPolicy::Preserve`], unknown fields are left
+//! out of the compressed object and stored as raw JSON in a side section
+//! instead. The section is laid out after the object's bytes like so:
+//!
+//! ```text
+//! object | fields | fields offset | MAGIC
+//! ```
+//!
+//! The fields are a JSON object mapping the JSON pointer of each record which
+//! had unknown fields to an object holding those fields. The fields offset is
+//! an 8 byte little endian integer holding the byte position of the start of
+//! the fields.
+//!
+//! [`UnknownFieldPolicy::Preserve`]: crate::UnknownFieldPolicy::Preserve
+
+use crate::path::{Path, Segment};
+use crate::prelude::*;
+use crate::schema::{CompositeType, Schema, Type};
+use alloc::collections::BTreeMap;
+use anyhow::{anyhow, bail, Result};
+use serde_json::{Map, Value};
+
+/// Magic bytes found at the very end of an object with preserved fields.
+pub const MAGIC: &[u8; 4] = b"CHIU";
+
+/// Size of the trailer (fields offset + magic) in bytes.
+const TRAILER_LEN: usize = 8 + MAGIC.len();
+
+/// Record fields which are not defined by a schema, grouped by the JSON
+/// pointer of the record they belong to.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct UnknownFields {
+  records: BTreeMap<String, Map<String, Value>>,
+}
+
+impl UnknownFields {
+  /// Collects all of the fields in `value` which are not defined by `schema`.
+  pub fn collect(schema: &Schema, value: &Value) -> Self {
+    let mut unknown = UnknownFields::default();
+    if let Some(ct) = schema.composite_root() {
+      unknown.collect_from(ct, value, &mut Vec::new());
+    }
+    unknown
+  }
+
+  fn collect_from(
+    &mut self,
+    ct: &CompositeType,
+    value: &Value,
+    path: &mut Vec<Segment>,
+  ) {
+    match (ct, value) {
+      (CompositeType::Record(record), Value::Object(map)) => {
+        for (k, v) in map {
+          match record.fields.get(k) {
+            Some(Type::Nested(ct)) => {
+              path.push(Segment::Field(k.clone()));
+              self.collect_from(ct, v, path);
+              path.pop();
+            }
+            Some(_) => {}
+            None => {
+              let pointer = Path(path.clone()).to_pointer();
+              let fields = self.records.entry(pointer).or_default();
+              fields.insert(k.clone(), v.clone());
+            }
+          }
+        }
+      }
+      (CompositeType::List(list), Value::Array(arr)) => {
+        if let Type::Nested(ct) = list.0.as_ref() {
+          for (i, v) in arr.iter().enumerate() {
+            path.push(Segment::Index(i));
+            self.collect_from(ct, v, path);
+            path.pop();
+          }
+        }
+      }
+      _ => {}
+    }
+  }
+
+  /// Returns `true` if there are no unknown fields.
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.records.is_empty()
+  }
+
+  /// Adds the unknown fields back into a decoded `value`.
+  pub fn restore(&self, value: &mut Value) -> Result<()> {
+    for (pointer, fields) in &self.records {
+      let record = value
+        .pointer_mut(pointer)
+        .and_then(Value::as_object_mut)
+        .ok_or_else(|| {
+          anyhow!("no record at '{}' to restore fields into", pointer)
+        })?;
+      for (k, v) in fields {
+        record.insert(k.clone(), v.clone());
+      }
+    }
+    Ok(())
+  }
+
+  /// Serializes these fields to bytes.
+  pub fn to_bytes(&self) -> Vec<u8> {
+    let records: Map<String, Value> = self
+      .records
+      .iter()
+      .map(|(k, v)| (k.clone(), Value::Object(v.clone())))
+      .collect();
+    // Serializing a `Value` can't fail
+    serde_json::to_vec(&Value::Object(records)).unwrap()
+  }
+
+  /// Deserializes fields from bytes produced by [`to_bytes`].
+  ///
+  /// [`to_bytes`]: UnknownFields::to_bytes
+  pub fn from_bytes(bytes: &[u8]) -> Result<Self> {
+    let records = match serde_json::from_slice(bytes)? {
+      Value::Object(records) => records,
+      _ => bail!("unknown fields must be an object"),
+    };
+
+    let mut unknown = UnknownFields::default();
+    for (pointer, fields) in records {
+      match fields {
+        Value::Object(fields) => unknown.records.insert(pointer, fields),
+        _ => bail!("unknown fields of '{}' must be an object", pointer),
+      };
+    }
+    Ok(unknown)
+  }
+
+  /// Appends these fields as a footer to the bytes of a compressed object.
+  pub fn append_to(&self, object: &mut Vec<u8>) {
+    let footer = self.footer(object.len());
+    object.extend_from_slice(&footer);
+  }
+
+  /// The footer for an object which is `len` bytes long.
+  pub(crate) fn footer(&self, len: usize) -> Vec<u8> {
+    let mut footer = self.to_bytes();
+    footer.extend_from_slice(&(len as u64).to_le_bytes());
+    footer.extend_from_slice(MAGIC);
+    footer
+  }
+
+  /// Splits the bytes of a compressed object from the unknown fields footer
+  /// at their end.
+  ///
+  /// Like an [index](crate::index::Index::split), the footer can't be told
+  /// apart from the end of an object, so this is only called for objects known
+  /// to have one.
+  pub fn split(bytes: &[u8]) -> Result<(&[u8], UnknownFields)> {
+    if bytes.len() < TRAILER_LEN || !bytes.ends_with(MAGIC) {
+      bail!("missing unknown fields footer");
+    }
+
+    let trailer = &bytes[bytes.len() - TRAILER_LEN..];
+    let mut start = [0u8; 8];
+    start.copy_from_slice(&trailer[..8]);
+    let start = u64::from_le_bytes(start) as usize;
+    if start > bytes.len() - TRAILER_LEN {
+      bail!("unknown fields offset is out of bounds");
+    }
+
+    let fields = &bytes[start..bytes.len() - TRAILER_LEN];
+    let unknown = UnknownFields::from_bytes(fields)?;
+    Ok((&bytes[..start], unknown))
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::bit::BitVec;
+  use crate::schema::{List, Record};
+  use crate::{EncodeOptions, UnknownFieldPolicy};
+  use serde_json::json;
+
+  fn schema() -> Schema {
+    let mut course = BTreeMap::new();
+    course.insert("name".to_string(), Type::PassThrough);
+
+    let mut student = BTreeMap::new();
+    student.insert("name".to_string(), Type::PassThrough);
+    student.insert(
+      "courses".to_string(),
+      Type::Nested(CompositeType::List(List(Box::new(Type::Nested(
+        CompositeType::Record(Record::new(course)),
+      ))))),
+    );
+    Schema::new(CompositeType::Record(Record::new(student)))
+  }
+
+  #[test]
+  fn preserve_roundtrip() {
+    let schema = schema();
+    let value = json!({
+      "name": "Jeremy",
+      "age": 24,
+      "courses": [{ "name": "Math" }, { "name": "Art", "room": [1, 2] }]
+    });
+
+    let options = EncodeOptions {
+      unknown_fields: UnknownFieldPolicy::Preserve,
+      ..Default::default()
+    };
+    let mut bytes = Vec::new();
+    crate::encode_to_with(&schema, &value, &mut bytes, &options).unwrap();
+
+    let (body, unknown) = UnknownFields::split(&bytes).unwrap();
+    assert_eq!(UnknownFields::collect(&schema, &value), unknown);
+
+    let mut decoded = crate::decode(&schema, body).unwrap();
+    assert_eq!(
+      json!({ "name": "Jeremy", "courses": [{ "name": "Math" }, { "name": "Art" }] }),
+      decoded
+    );
+    unknown.restore(&mut decoded).unwrap();
+    assert_eq!(value, decoded);
+  }
+
+  #[test]
+  fn ignore_unknown_fields() {
+    let schema = schema();
+    let value = json!({ "name": "Jeremy", "age": 24 });
+
+    let options = EncodeOptions {
+      unknown_fields: UnknownFieldPolicy::Ignore,
+      ..Default::default()
+    };
+    let co = crate::encode_with(&schema, &value, &options).unwrap();
+    let bits: BitVec = co.into();
+    let bytes = bits.to_bytes();
+    assert!(UnknownFields::split(&bytes).is_err());
+    assert_eq!(
+      json!({ "name": "Jeremy" }),
+      crate::decode(&schema, &bytes).unwrap()
+    );
+  }
+}
@@ -0,0 +1,233 @@
+//! The `update` module implements in-place updates of fixed-width fields.
+//!
+//! The width of a fixed-width field is determined entirely by the schema so a
+//! new value can be written directly over the old one without shifting any of
+//! the data which comes after it. This makes it possible to, for example, flip
+//! a status enum or toggle a flag in an existing compressed object without
+//! re-encoding the whole document.
+
+use core::convert::TryFrom;
+use core::ops::Range;
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde_json::Value;
+
+use crate::bit;
+use crate::comp::{self, EncodedWidth};
+use crate::decode::{reader, seek_root, DecodeOptions, Target};
+use crate::encode::get_compressor_for_type;
+use crate::math;
+use crate::path::Path;
+use crate::prelude::*;
+use crate::schema::{Schema, Type};
+
+/// The location of a fixed-width value's data within a compressed object.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Location {
+  /// Bit offset of the first bit of data.
+  pub offset: usize,
+  /// The number of data bits.
+  pub width: usize,
+}
+
+impl Location {
+  /// The range of bytes which contain this location's bits.
+  pub fn byte_range(&self) -> Range<usize> {
+    if self.width == 0 {
+      return self.offset / 8..self.offset / 8;
+    }
+    self.offset / 8..math::div_ceil(self.offset + self.width, 8)
+  }
+}
+
+/// Finds the location of the fixed-width value at `path` within the bytes of
+/// a compressed object.
+pub fn locate(schema: &Schema, bytes: &[u8], path: &Path) -> Result<Location> {
+  locate_with(schema, bytes, path, DecodeOptions::default())
+}
+
+/// Finds the location of the fixed-width value at `path` within the bytes of
+/// a compressed object which was encoded in the way described by `options`.
+pub fn locate_with(
+  schema: &Schema,
+  bytes: &[u8],
+  path: &Path,
+  options: DecodeOptions,
+) -> Result<Location> {
+  locate_with_type(schema, bytes, path, &options).map(|(loc, _)| loc)
+}
+
+/// Overwrites the fixed-width value at `path` with `value`, returning the
+/// location which was updated.
+///
+/// Only the bits belonging to the value are modified, everything else in
+/// `bytes` is left untouched.
+pub fn update(
+  schema: &Schema,
+  bytes: &mut [u8],
+  path: &Path,
+  value: &Value,
+) -> Result<Location> {
+  update_with(schema, bytes, path, value, DecodeOptions::default())
+}
+
+/// Overwrites the fixed-width value at `path` within a compressed object
+/// which was encoded in the way described by `options`.
+pub fn update_with(
+  schema: &Schema,
+  bytes: &mut [u8],
+  path: &Path,
+  value: &Value,
+  options: DecodeOptions,
+) -> Result<Location> {
+  let (loc, ty) = locate_with_type(schema, bytes, path, &options)?;
+  let compressor = get_compressor_for_type(ty)?;
+  let value = comp::Value::try_from(value)?;
+  let bits = compressor
+    .compress(value)
+    .with_context(|| format!("when encoding {}", path))?;
+
+  if bits.len() != loc.width {
+    bail!(
+      "encoded value has width {} but {} has width {}",
+      bits.len(),
+      path,
+      loc.width
+    );
+  }
+
+  // This can't fail as `locate` already checked that the value is in bounds
+  bit::overwrite(bytes, loc.offset, &bits).unwrap();
+  Ok(loc)
+}
+
+fn locate_with_type<'s>(
+  schema: &'s Schema,
+  bytes: &[u8],
+  path: &Path,
+  options: &DecodeOptions,
+) -> Result<(Location, &'s Type)> {
+  let mut r = reader(schema, bytes, options);
+  let target = seek_root(schema, &mut r, path.segments())
+    .with_context(|| format!("when locating {}", path))?
+    .ok_or_else(|| anyhow!("no value found at {}", path))?;
+
+  let ty = match target {
+    Target::Value(ty) => ty,
+    Target::Composite(..)
+    | Target::List(..)
+    | Target::Inline(_)
+    | Target::Null => {
+      bail!("{} is not a fixed-width value", path)
+    }
+  };
+
+  let width = match get_compressor_for_type(ty)?.encoded_width() {
+    EncodedWidth::Fixed(n) => n,
+    EncodedWidth::Variable | EncodedWidth::Prefix => {
+      bail!("{} is not a fixed-width value", path)
+    }
+  };
+
+  let loc = Location {
+    offset: r.position(),
+    width,
+  };
+  if width > r.remaining() {
+    bail!("unexpected end of input");
+  }
+  Ok((loc, ty))
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::bit::BitVec;
+  use crate::data::Layout;
+  use crate::schema::{CompositeType, Record};
+  use serde_json::json;
+  use std::collections::BTreeMap;
+
+  fn schema() -> Schema {
+    let mut fields = BTreeMap::new();
+    fields.insert("active".to_string(), Type::Name("bool".to_string()));
+    fields.insert("name".to_string(), Type::PassThrough);
+    fields.insert(
+      "status".to_string(),
+      Type::Enum {
+        variants: vec!["closed", "open", "pending"]
+          .into_iter()
+          .map(String::from)
+          .collect(),
+        probabilities: None,
+      },
+    );
+    Schema::new(CompositeType::Record(Record::new(fields)))
+  }
+
+  #[test]
+  fn update_enum_in_place() {
+    let schema = schema();
+    let value = json!({ "active": true, "name": "x", "status": "open" });
+    let bits: BitVec = crate::encode(&schema, &value).unwrap().into();
+    let mut bytes = bits.to_bytes();
+
+    let path = ".status".parse().unwrap();
+    update(&schema, &mut bytes, &path, &json!("pending")).unwrap();
+    update(
+      &schema,
+      &mut bytes,
+      &".active".parse().unwrap(),
+      &json!(false),
+    )
+    .unwrap();
+
+    let expected = json!({ "active": false, "name": "x", "status": "pending" });
+    assert_eq!(expected, crate::decode(&schema, &bytes).unwrap());
+  }
+
+  #[test]
+  fn update_byte_aligned_in_place() {
+    let schema = schema();
+    let value = json!({ "active": true, "name": "x", "status": "open" });
+    let options = crate::EncodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+    let co = crate::encode_with(&schema, &value, &options).unwrap();
+    let mut bytes = co.to_bytes();
+    let options = DecodeOptions {
+      layout: Layout::ByteAligned,
+      ..Default::default()
+    };
+
+    let path = ".status".parse().unwrap();
+    let loc =
+      update_with(&schema, &mut bytes, &path, &json!("pending"), options);
+    assert_eq!(0, loc.unwrap().offset % 8);
+
+    let expected = json!({ "active": true, "name": "x", "status": "pending" });
+    let decoded = crate::decode_with(&schema, &bytes, options).unwrap();
+    assert_eq!(expected, decoded);
+  }
+
+  #[test]
+  fn update_rejects_variable_width_values() {
+    let schema = schema();
+    let value = json!({ "name": "x" });
+    let bits: BitVec = crate::encode(&schema, &value).unwrap().into();
+    let mut bytes = bits.to_bytes();
+
+    let path = ".name".parse().unwrap();
+    assert!(update(&schema, &mut bytes, &path, &json!("y")).is_err());
+  }
+
+  #[test]
+  fn locate_missing_field() {
+    let schema = schema();
+    let value = json!({ "name": "x" });
+    let bits: BitVec = crate::encode(&schema, &value).unwrap().into();
+    let bytes = bits.to_bytes();
+    assert!(locate(&schema, &bytes, &".status".parse().unwrap()).is_err());
+  }
+}
@@ -2,11 +2,14 @@
 //! values which allows for (theoretically) unbounded integers to be encoded
 //! in an efficient way optimizing for smaller values.
 //!
-//! > While this encoding theoretically supports unbounded integers, this
-//! > implementation only supports up to 64-bit integer values for simplicity.
+//! Native integers of up to 128 bits can be converted to and from code points
+//! directly. Larger values can be encoded from and decoded to their little
+//! endian byte representation using [`CodePoint::from_le_bytes`] and
+//! [`CodePoint::to_le_bytes`].
 
 use crate::int::{FixedWidthInteger, LittleEndian};
-use crate::math;
+use crate::prelude::*;
+use core::convert::TryFrom;
 use num_traits::PrimInt;
 
 /// A code point in the variable-width integer encoding encodes an integer
@@ -46,6 +49,20 @@ pub struct CodePoint {
 }
 
 impl CodePoint {
+  /// Reads the code point at the start of `bytes`.
+  ///
+  /// Any bytes after the end of the code point are ignored; use [`count`] to
+  /// find out how many bytes were consumed. Returns `None` if `bytes` ends
+  /// before the code point is terminated.
+  ///
+  /// [`count`]: CodePoint::count
+  pub fn parse(bytes: &[u8]) -> Option<Self> {
+    let end = bytes.iter().position(|b| b & 0x80 == 0)?;
+    Some(CodePoint {
+      bytes: bytes[..=end].to_vec(),
+    })
+  }
+
   /// The number of bytes taken up by this code point.
   #[inline]
   pub fn count(&self) -> usize {
@@ -58,6 +75,66 @@ impl CodePoint {
     &self.bytes[..]
   }
 
+  /// Constructs a code point from the little endian byte representation of
+  /// an unsigned integer of any size.
+  pub fn from_le_bytes(le_bytes: &[u8]) -> Self {
+    // Special case for 0 values.
+    if le_bytes.iter().all(|b| *b == 0) {
+      return CodePoint { bytes: vec![0] };
+    }
+
+    let mut u7_vec = u8_to_u7(le_bytes);
+    debug_assert!(!u7_vec.is_empty());
+
+    // To convert a u7 sequence to a code point we need to OR a 1 bit into the
+    // free high bit of each byte except for the last one.
+    let last = u7_vec.len() - 1;
+    for byte in &mut u7_vec[..last] {
+      *byte |= 0x80;
+    }
+
+    CodePoint { bytes: u7_vec }
+  }
+
+  /// The little endian byte representation of the unsigned value of this
+  /// code point.
+  ///
+  /// Trailing zero bytes are trimmed, except for a value of 0 which is
+  /// represented as a single zero byte.
+  pub fn to_le_bytes(&self) -> Vec<u8> {
+    // Prefix bits are ignored by the conversion
+    let mut le_bytes = u7_to_u8(&self.bytes);
+    while le_bytes.len() > 1 && le_bytes.last() == Some(&0) {
+      le_bytes.pop();
+    }
+    le_bytes
+  }
+
+  /// Constructs a code point from a signed integer using zigzag encoding.
+  ///
+  /// Zigzag encoding interleaves negative and positive values (0, -1, 1, -2,
+  /// 2, ...) so that values with a small magnitude produce short code points
+  /// regardless of their sign. Signed values converted using [`From`] are
+  /// encoded as their two's complement bits instead, meaning that negative
+  /// values always use the maximum number of bytes for their type.
+  pub fn from_signed<I: Into<i128>>(x: I) -> Self {
+    let x = x.into();
+    CodePoint::from(((x << 1) ^ (x >> 127)) as u128)
+  }
+
+  /// Decodes a code point constructed by [`from_signed`] into a native signed
+  /// integer type.
+  ///
+  /// Returns `None` if the value of this code point doesn't fit in the
+  /// requested integer.
+  ///
+  /// [`from_signed`]: CodePoint::from_signed
+  pub fn decode_signed<I: TryFrom<i128>>(&self) -> Option<I> {
+    let z = self.decode::<u128>()?;
+    let x = (z >> 1) as i128 ^ -((z & 1) as i128);
+    I::try_from(x).ok()
+  }
+
   /// Decodes this code point into a native integer type.
   ///
   /// Returns `None` if the value of this code point is too large to store in
@@ -67,18 +144,8 @@ impl CodePoint {
   where
     I: FixedWidthInteger + LittleEndian,
   {
-    // Strip prefix bits from code point bytes.
-    let u7_vec = self.bytes.iter().map(|x| x & 0x7f).collect::<Vec<u8>>();
-
-    // Convert u7 slice to u8 little endian vector.
-    let mut le_bytes = u7_to_u8(u7_vec);
-
-    // Trim trailing zero bytes from the little endian representation up until
-    // the byte width of the integer we are trying to create.
+    let mut le_bytes = self.to_le_bytes();
     let byte_width = I::WIDTH / 8;
-    while le_bytes.last() == Some(&0) && le_bytes.len() > byte_width {
-      le_bytes.pop();
-    }
 
     // If the little endian vector is too large for the required integer size
     // return None.
@@ -103,147 +170,179 @@ where
 {
   /// Constructs a code point from an integer value.
   fn from(x: I) -> Self {
-    // Special case for 0 values.
-    if x == I::zero() {
-      return CodePoint { bytes: vec![0] };
-    }
-
-    let le_bytes = x.le_bytes();
-    let mut u7_vec = u8_to_u7(le_bytes.as_slice());
-    // Trim trailing zero bytes from the little endian `u7` vector.
-    while u7_vec.last() == Some(&0) {
-      u7_vec.pop();
-    }
-
-    debug_assert!(!u7_vec.is_empty());
-
-    // To convert a u7 sequence to a code point we need to OR a 1 bit into the
-    // free high bit of each byte except for the last one.
-    for i in 0..u7_vec.len() - 1 {
-      u7_vec[i] |= 0x80;
-    }
-
-    CodePoint { bytes: u7_vec }
+    CodePoint::from_le_bytes(&x.le_bytes())
   }
 }
 
-/// Converts a slice of bytes into a slice of u7 (unsigned 7-bit integers) by
-/// continually masking off the high bit from each byte and shifting it into
-/// the adjacent byte cascading the result of the shift down the slice.
+/// Converts the little endian bytes of an integer into its little endian
+/// sequence of u7 (unsigned 7-bit integers) without any trailing zeros.
+///
+/// Bits are moved through an accumulator 7 at a time in the same way as LEB128
+/// so each input byte is only touched once.
 fn u8_to_u7(bytes: &[u8]) -> Vec<u8> {
-  // TODO: There is probably a more efficient algorithm to do this.
-  debug_assert!(!bytes.is_empty());
-  let mut vec: Vec<u8> = bytes.to_vec();
-  let mut i = 0;
-  while i != vec.len() {
-    // Split off the high bit of the `i`th byte.
-    let (value, mut carry_in) = split_high_bit(vec[i]);
-    // Place back the new value into the vector.
-    vec[i] = value;
-    // Cascade, shift the carry of the previous shift into the next byte.
-    for byte in vec.iter_mut().skip(i + 1) {
-      // Shift this byte to the left by 1 to make room for the carry in.
-      let (shifted, carry_out) = math::shl_with_carry(*byte, 1);
-      // Combine the shifted result with the carry in giving us the new byte
-      // for this position.
-      let shifted = shifted | carry_in;
-      // Place the new shifted value back into the vector.
-      *byte = shifted;
-      // If this shift overflowed, then we carry a 1 to the next byte,
-      // otherwise we carry a zero.
-      carry_in = carry_out;
+  let mut vec = Vec::with_capacity(bytes.len() + bytes.len() / 7 + 1);
+  let mut acc = 0u32;
+  let mut bits = 0;
+  for &b in bytes {
+    acc |= (b as u32) << bits;
+    bits += 8;
+    while bits >= 7 {
+      vec.push((acc & 0x7f) as u8);
+      acc >>= 7;
+      bits -= 7;
     }
+  }
+  if bits > 0 {
+    vec.push(acc as u8);
+  }
 
-    // We've now chopped off the high bit of the byte in the `i`th position
-    // and shifted it into the next byte, cascading the shift throughout the
-    // rest of the bytes in the sequence.
+  while vec.last() == Some(&0) {
+    vec.pop();
+  }
+  vec
+}
 
-    // Carry in now holds the carry out of the last shift, if it is one then
-    // we need add a new byte to the result to hold it.
-    if carry_in == 1 {
-      vec.push(carry_in);
+/// Converts a little endian sequence of u7 integers into little endian bytes,
+/// ignoring the high bit of each input byte.
+fn u7_to_u8(u7s: &[u8]) -> Vec<u8> {
+  let mut vec = Vec::with_capacity(u7s.len());
+  let mut acc = 0u32;
+  let mut bits = 0;
+  for &b in u7s {
+    acc |= ((b & 0x7f) as u32) << bits;
+    bits += 7;
+    if bits >= 8 {
+      vec.push(acc as u8);
+      acc >>= 8;
+      bits -= 8;
     }
-
-    // Now we chop of the highest bit of the next byte, shifting it into the
-    // next byte and so on...
-    i += 1;
   }
-
+  if bits > 0 {
+    vec.push(acc as u8);
+  }
   vec
 }
 
-/// Masks off the high bit of `x` returning it as the lowest bit in the second
-/// tuple element.
-fn split_high_bit(x: u8) -> (u8, u8) {
-  (x & 0x7f, x >> 7)
-}
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::math;
+  use proptest::prelude::*;
 
-/// Converts a vector of `u7` integers into a vector of `u8` integers.
-fn u7_to_u8(mut u7_vec: Vec<u8>) -> Vec<u8> {
-  debug_assert!(!u7_vec.is_empty());
-  let mut vec = Vec::new();
-  let mut i = 0;
-  let mut borrow_amount = 1;
-  loop {
-    // Since we sometimes skip values (given certain circumstances), we check
-    // to make sure we actually have data to work on this iteration.
-    if i == u7_vec.len() {
-      break;
+  // The original cascading-shift conversions which the LEB128 style ones
+  // replaced, kept to check that both produce the same code points.
+
+  /// Converts a slice of bytes into a slice of u7 (unsigned 7-bit integers) by
+  /// continually masking off the high bit from each byte and shifting it into
+  /// the adjacent byte cascading the result of the shift down the slice.
+  fn reference_u8_to_u7(bytes: &[u8]) -> Vec<u8> {
+    debug_assert!(!bytes.is_empty());
+    let mut vec: Vec<u8> = bytes.to_vec();
+    let mut i = 0;
+    while i != vec.len() {
+      // Split off the high bit of the `i`th byte.
+      let (value, mut carry_in) = split_high_bit(vec[i]);
+      // Place back the new value into the vector.
+      vec[i] = value;
+      // Cascade, shift the carry of the previous shift into the next byte.
+      for byte in vec.iter_mut().skip(i + 1) {
+        // Shift this byte to the left by 1 to make room for the carry in.
+        let (shifted, carry_out) = math::shl_with_carry(*byte, 1);
+        // Combine the shifted result with the carry in giving us the new byte
+        // for this position.
+        let shifted = shifted | carry_in;
+        // Place the new shifted value back into the vector.
+        *byte = shifted;
+        // If this shift overflowed, then we carry a 1 to the next byte,
+        // otherwise we carry a zero.
+        carry_in = carry_out;
+      }
+
+      // We've now chopped off the high bit of the byte in the `i`th position
+      // and shifted it into the next byte, cascading the shift throughout the
+      // rest of the bytes in the sequence.
+
+      // Carry in now holds the carry out of the last shift, if it is one then
+      // we need add a new byte to the result to hold it.
+      if carry_in == 1 {
+        vec.push(carry_in);
+      }
+
+      // Now we chop of the highest bit of the next byte, shifting it into the
+      // next byte and so on...
+      i += 1;
     }
 
-    // Get the value for this iteration.
-    let value = u7_vec[i];
-    // If there is no next value, add the current value to the result vector
-    // and break, because we are done.
-    if i + 1 == u7_vec.len() {
-      vec.push(value);
-      break;
-    }
+    vec
+  }
 
-    // Borrow the required number of bits from the next value and OR them into
-    // the top of the current one.
-    let borrowed = if borrow_amount == 7 {
-      // If we need to borrow the entire next value, reset the borrow amount
-      // and add 1 to the index counter so we skip over the next value.
-      borrow_amount = 1;
-      i += 1;
-      u7_vec[i] << 1
-    } else {
-      // Borrow the required amount of bits and shift them to the high part of
-      // the byte so that we can OR them with the current value.
-      let b = borrow_lower(u7_vec[i + 1], borrow_amount);
-      let b = b << (8 - borrow_amount);
-      // Shift the next value to the right so that it is ready for the next
-      // loop iteration.
-      u7_vec[i + 1] >>= borrow_amount;
-      // Increment the borrow_amount because we will need to borrow 1 more bit
-      // in the next iteration.
-      borrow_amount += 1;
-      // Return the shifted borrowed bits.
-      b
-    };
-
-    // OR the borrowed bits into the current value and store it in the result
-    // vector.
-    let value = value | borrowed;
-    vec.push(value);
-
-    i += 1;
+  /// Masks off the high bit of `x` returning it as the lowest bit in the second
+  /// tuple element.
+  fn split_high_bit(x: u8) -> (u8, u8) {
+    (x & 0x7f, x >> 7)
   }
 
-  vec
-}
+  /// Converts a vector of `u7` integers into a vector of `u8` integers.
+  fn reference_u7_to_u8(mut u7_vec: Vec<u8>) -> Vec<u8> {
+    debug_assert!(!u7_vec.is_empty());
+    let mut vec = Vec::new();
+    let mut i = 0;
+    let mut borrow_amount = 1;
+    loop {
+      // Since we sometimes skip values (given certain circumstances), we check
+      // to make sure we actually have data to work on this iteration.
+      if i == u7_vec.len() {
+        break;
+      }
+
+      // Get the value for this iteration.
+      let value = u7_vec[i];
+      // If there is no next value, add the current value to the result vector
+      // and break, because we are done.
+      if i + 1 == u7_vec.len() {
+        vec.push(value);
+        break;
+      }
+
+      // Borrow the required number of bits from the next value and OR them into
+      // the top of the current one.
+      let borrowed = if borrow_amount == 7 {
+        // If we need to borrow the entire next value, reset the borrow amount
+        // and add 1 to the index counter so we skip over the next value.
+        borrow_amount = 1;
+        i += 1;
+        u7_vec[i] << 1
+      } else {
+        // Borrow the required amount of bits and shift them to the high part of
+        // the byte so that we can OR them with the current value.
+        let b = borrow_lower(u7_vec[i + 1], borrow_amount);
+        let b = b << (8 - borrow_amount);
+        // Shift the next value to the right so that it is ready for the next
+        // loop iteration.
+        u7_vec[i + 1] >>= borrow_amount;
+        // Increment the borrow_amount because we will need to borrow 1 more bit
+        // in the next iteration.
+        borrow_amount += 1;
+        // Return the shifted borrowed bits.
+        b
+      };
+
+      // OR the borrowed bits into the current value and store it in the result
+      // vector.
+      let value = value | borrowed;
+      vec.push(value);
 
-/// Returns the lower `n` bits of `x`.
-fn borrow_lower(x: u8, n: u8) -> u8 {
-  debug_assert!(n <= 8);
-  x & (0xff >> (8 - n))
-}
+      i += 1;
+    }
 
-#[cfg(test)]
-mod test {
-  use super::*;
-  use proptest::prelude::*;
+    vec
+  }
+
+  /// Returns the lower `n` bits of `x`.
+  fn borrow_lower(x: u8, n: u8) -> u8 {
+    debug_assert!(n <= 8);
+    x & (0xff >> (8 - n))
+  }
 
   #[test]
   fn code_point_from_u8_no_high_bit() {
@@ -313,6 +412,38 @@ mod test {
     assert_eq!(9, cp.count());
   }
 
+  #[test]
+  fn code_point_zigzag() {
+    for (x, z) in &[(0i64, 0u8), (-1, 1), (1, 2), (-2, 3), (-64, 127)] {
+      let cp = CodePoint::from_signed(*x);
+      assert_eq!(&[*z], cp.bytes());
+      assert_eq!(Some(*x), cp.decode_signed::<i64>());
+    }
+    assert_eq!(None, CodePoint::from_signed(-129).decode_signed::<i8>());
+  }
+
+  #[test]
+  fn code_point_beyond_u128() {
+    let mut bytes = vec![0u8; 32];
+    bytes[31] = 0x80;
+    let cp = CodePoint::from_le_bytes(&bytes);
+    assert_eq!(37, cp.count());
+    assert_eq!(None, cp.decode::<u128>());
+    assert_eq!(bytes, cp.to_le_bytes());
+  }
+
+  #[test]
+  fn code_point_parse_ignores_trailing_bytes() {
+    let cp = CodePoint::parse(&[0x81, 0xe1, 0x01, 0xff]).unwrap();
+    assert_eq!(3, cp.count());
+    assert_eq!(Some(0x7081), cp.decode::<u16>());
+  }
+
+  #[test]
+  fn code_point_parse_unterminated() {
+    assert_eq!(None, CodePoint::parse(&[0x81, 0xe1]));
+  }
+
   #[test]
   fn u8_to_u7_single_byte_no_high_bit() {
     let bytes = [0x7f];
@@ -396,6 +527,58 @@ mod test {
       assert_eq!(Some(x), cp.decode::<i64>());
     }
 
+    #[test]
+    fn prop_code_point_encode_decode_u128(x: u128) {
+      let cp = CodePoint::from(x);
+      assert_eq!(Some(x), cp.decode::<u128>());
+    }
+
+    #[test]
+    fn prop_code_point_encode_decode_i128(x: i128) {
+      let cp = CodePoint::from(x);
+      assert_eq!(Some(x), cp.decode::<i128>());
+    }
+
+    #[test]
+    fn prop_code_point_zigzag_roundtrip_i64(x: i64) {
+      let cp = CodePoint::from_signed(x);
+      assert_eq!(Some(x), cp.decode_signed::<i64>());
+      if x < 0 {
+        assert!(cp.count() <= CodePoint::from(x).count());
+      }
+    }
+
+    #[test]
+    fn prop_code_point_zigzag_roundtrip_i128(x: i128) {
+      let cp = CodePoint::from_signed(x);
+      assert_eq!(Some(x), cp.decode_signed::<i128>());
+    }
+
+    #[test]
+    fn prop_code_point_matches_reference(bytes: Vec<u8>) {
+      prop_assume!(bytes.iter().any(|b| *b != 0));
+      let mut expected = reference_u8_to_u7(&bytes);
+      while expected.last() == Some(&0) {
+        expected.pop();
+      }
+      assert_eq!(expected, u8_to_u7(&bytes));
+
+      let cp = CodePoint::from_le_bytes(&bytes);
+      let stripped: Vec<u8> = cp.bytes().iter().map(|x| x & 0x7f).collect();
+      assert_eq!(reference_u7_to_u8(stripped), u7_to_u8(cp.bytes()));
+    }
+
+    #[test]
+    fn prop_code_point_le_bytes_roundtrip(bytes: Vec<u8>) {
+      prop_assume!(!bytes.is_empty());
+      let cp = CodePoint::from_le_bytes(&bytes);
+      let mut trimmed = bytes.clone();
+      while trimmed.len() > 1 && trimmed.last() == Some(&0) {
+        trimmed.pop();
+      }
+      assert_eq!(trimmed, cp.to_le_bytes());
+    }
+
     #[test]
     fn prop_code_point_bytes_should_never_end_in_a_zero(x: u64) {
       let cp = CodePoint::from(x);